@@ -0,0 +1,38 @@
+//! Embeds build metadata for `src/version.rs`.
+//!
+//! Sets `DEEPTHINK_GIT_SHA` to the short commit hash (`unknown` outside a git
+//! checkout, with a `-dirty` suffix when the tree has uncommitted changes)
+//! and `DEEPTHINK_BUILD_TIMESTAMP` to the build time in Unix seconds.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let sha = git(&["rev-parse", "--short=12", "HEAD"]).filter(|sha| !sha.is_empty());
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|status| !status.is_empty());
+    let sha = match sha {
+        Some(sha) if dirty => format!("{}-dirty", sha),
+        Some(sha) => sha,
+        None => "unknown".to_string(),
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=DEEPTHINK_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=DEEPTHINK_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
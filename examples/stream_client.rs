@@ -0,0 +1,95 @@
+//! Consumes a deepthink chat completion stream with `reqwest-eventsource`.
+//!
+//! Sends a streaming request to the OpenAI-compatible endpoint and prints the
+//! reasoning and the answer as their deltas arrive. The request asks for a
+//! resumable stream, so when the connection drops the event source resends
+//! it with the `Last-Event-ID` of the last frame it saw and the proxy replays
+//! the frames it missed instead of starting over.
+//!
+//! ```text
+//! cargo run --example stream_client -- [base url] [prompt]
+//! ```
+//!
+//! The base URL defaults to `http://127.0.0.1:3000`; set `DEEPTHINK_TOKEN`
+//! to the token configured in `auth.token_mappings`.
+
+use futures::StreamExt;
+use reqwest_eventsource::{retry::ExponentialBackoff, Error, Event, EventSource};
+use serde_json::{json, Value};
+use std::{io::Write, time::Duration};
+
+/// Reconnect attempts before the client gives up on a dropped stream.
+const MAX_RECONNECTS: usize = 5;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let base_url = args.next().unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+    let prompt = args.next().unwrap_or_else(|| "Why is the sky blue? Answer in one sentence.".to_string());
+    let token = std::env::var("DEEPTHINK_TOKEN").unwrap_or_default();
+
+    let request = reqwest::Client::new()
+        .post(format!("{}/v1/chat/completions", base_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .json(&json!({
+            "model": "deepthink",
+            "messages": [{"role": "user", "content": prompt}],
+            "stream": true,
+            "resumable": true,
+            "thinking_format": "reasoning_content",
+        }));
+    let mut source = EventSource::new(request)?;
+    // 服务端的 retry 提示会覆盖起始间隔
+    source.set_retry_policy(Box::new(ExponentialBackoff::new(
+        Duration::from_millis(500),
+        2.0,
+        Some(Duration::from_secs(10)),
+        Some(MAX_RECONNECTS),
+    )));
+
+    let mut phase = "";
+    while let Some(event) = source.next().await {
+        match event {
+            Ok(Event::Open) => eprintln!("[connected]"),
+            Ok(Event::Message(message)) if message.data == "[DONE]" => {
+                source.close();
+            }
+            Ok(Event::Message(message)) if message.event == "metadata" => {
+                eprintln!("\n[metadata] {}", message.data);
+            }
+            Ok(Event::Message(message)) => {
+                let chunk: Value = serde_json::from_str(&message.data)?;
+                if let Some(error) = chunk.get("error") {
+                    eprintln!("\n[error] {}", error);
+                    continue;
+                }
+                let delta = &chunk["choices"][0]["delta"];
+                for (field, label) in [("reasoning_content", "reasoning"), ("content", "answer")] {
+                    let Some(text) = delta[field].as_str().filter(|text| !text.is_empty()) else {
+                        continue;
+                    };
+                    if phase != label {
+                        phase = label;
+                        print!("\n--- {} ---\n", label);
+                    }
+                    print!("{}", text);
+                    std::io::stdout().flush()?;
+                }
+            }
+            // 连接中断: 事件源按重试策略自动重连, 并带上 Last-Event-ID 续传
+            Err(Error::Transport(e)) => eprintln!("\n[disconnected: {}; reconnecting]", e),
+            Err(Error::StreamEnded) => break,
+            Err(Error::InvalidStatusCode(status, response)) => {
+                let body = response.text().await.unwrap_or_default();
+                source.close();
+                return Err(format!("proxy answered HTTP {}: {}", status, body).into());
+            }
+            Err(e) => {
+                source.close();
+                return Err(e.into());
+            }
+        }
+    }
+    println!();
+    Ok(())
+}
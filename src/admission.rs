@@ -0,0 +1,250 @@
+//! Priority-aware admission control for chat requests.
+//!
+//! At most `server.max_concurrent_requests` requests run the pipeline at
+//! once. Requests beyond that wait in an [`AdmissionQueue`]; when a slot
+//! frees up it goes to the waiter with the best effective priority rather
+//! than the oldest one. Lower priority values are served first, and every
+//! `server.priority_aging_secs` spent waiting improves a waiter's priority by
+//! one, so batch traffic is delayed but never starved.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::oneshot, time::Instant};
+
+/// Limits concurrent requests and orders waiters by priority.
+#[derive(Debug)]
+pub struct AdmissionQueue {
+    inner: Mutex<QueueState>,
+    aging: Duration,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    /// Free slots, or `None` when admission is unlimited.
+    available: Option<usize>,
+    waiting: Vec<Waiter>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: u8,
+    enqueued: Instant,
+    seq: u64,
+    admit: oneshot::Sender<AdmissionPermit>,
+}
+
+/// A running request's slot, returned to the queue when dropped.
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    queue: Option<Arc<AdmissionQueue>>,
+}
+
+impl AdmissionQueue {
+    /// Creates a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_concurrent` - Requests allowed to run at once; `None` admits every request immediately
+    /// * `aging` - Waiting time that improves a waiter's priority by one; zero disables aging
+    pub fn new(max_concurrent: Option<usize>, aging: Duration) -> Self {
+        Self {
+            inner: Mutex::new(QueueState {
+                available: max_concurrent,
+                waiting: Vec::new(),
+                next_seq: 0,
+            }),
+            aging,
+        }
+    }
+
+    /// Waits for a slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - The caller's priority class; lower is served first
+    ///
+    /// # Returns
+    ///
+    /// * `AdmissionPermit` - Holds the slot until dropped
+    pub async fn acquire(self: &Arc<Self>, priority: u8) -> AdmissionPermit {
+        let admitted = {
+            let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            match state.available {
+                None => return AdmissionPermit { queue: None },
+                Some(available) if available > 0 && state.waiting.is_empty() => {
+                    state.available = Some(available - 1);
+                    return AdmissionPermit {
+                        queue: Some(self.clone()),
+                    };
+                }
+                Some(_) => {
+                    let (admit, admitted) = oneshot::channel();
+                    let seq = state.next_seq;
+                    state.next_seq += 1;
+                    state.waiting.push(Waiter {
+                        priority,
+                        enqueued: Instant::now(),
+                        seq,
+                        admit,
+                    });
+                    admitted
+                }
+            }
+        };
+
+        // 发送方只会在交出许可后被移除, 因此接收不会失败
+        admitted.await.unwrap_or(AdmissionPermit { queue: None })
+    }
+
+    /// Returns the number of waiting requests per priority class.
+    pub fn depths(&self) -> BTreeMap<u8, usize> {
+        let state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut depths = BTreeMap::new();
+        for waiter in state.waiting.iter().filter(|w| !w.admit.is_closed()) {
+            *depths.entry(waiter.priority).or_insert(0) += 1;
+        }
+        depths
+    }
+
+    /// Hands a freed slot to the best waiter, or returns it to the pool.
+    fn release(self: &Arc<Self>) {
+        let next = {
+            let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            // 已取消的等待者直接移除
+            state.waiting.retain(|w| !w.admit.is_closed());
+            let now = Instant::now();
+            let best = state
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, w)| (self.effective_priority(w, now), w.seq))
+                .map(|(index, _)| index);
+            match best {
+                Some(index) => Some(state.waiting.swap_remove(index)),
+                None => {
+                    state.available = state.available.map(|available| available + 1);
+                    None
+                }
+            }
+        };
+
+        if let Some(waiter) = next {
+            // 等待者在此期间取消时, 许可随返回值被丢弃并再次释放
+            let _ = waiter.admit.send(AdmissionPermit {
+                queue: Some(self.clone()),
+            });
+        }
+    }
+
+    /// Returns a waiter's priority after aging.
+    fn effective_priority(&self, waiter: &Waiter, now: Instant) -> u8 {
+        if self.aging.is_zero() {
+            return waiter.priority;
+        }
+        let waited = now.saturating_duration_since(waiter.enqueued);
+        let boost = (waited.as_millis() / self.aging.as_millis()).min(u8::MAX as u128) as u8;
+        waiter.priority.saturating_sub(boost)
+    }
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{sync::mpsc, task::yield_now, time::advance};
+
+    /// Queues a request of `priority` that reports `label` once admitted and
+    /// then holds its slot until the test drops the returned permit.
+    async fn enqueue(
+        queue: &Arc<AdmissionQueue>,
+        priority: u8,
+        label: &'static str,
+        admitted: &mpsc::UnboundedSender<(&'static str, AdmissionPermit)>,
+    ) {
+        let (queue, admitted) = (queue.clone(), admitted.clone());
+        tokio::spawn(async move {
+            let permit = queue.acquire(priority).await;
+            let _ = admitted.send((label, permit));
+        });
+        yield_now().await;
+    }
+
+    #[tokio::test]
+    async fn unlimited_queues_admit_immediately() {
+        let queue = Arc::new(AdmissionQueue::new(None, Duration::ZERO));
+        let _permits: Vec<_> = futures::future::join_all((0..3).map(|_| queue.acquire(10))).await;
+        assert!(queue.depths().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn freed_slots_go_to_the_best_priority_first() {
+        let queue = Arc::new(AdmissionQueue::new(Some(1), Duration::ZERO));
+        let (tx, mut admitted) = mpsc::unbounded_channel();
+        let running = queue.acquire(10).await;
+        enqueue(&queue, 10, "batch", &tx).await;
+        enqueue(&queue, 1, "interactive", &tx).await;
+        enqueue(&queue, 10, "batch-2", &tx).await;
+        assert_eq!(queue.depths(), BTreeMap::from([(1, 1), (10, 2)]));
+
+        drop(running);
+        let (label, permit) = admitted.recv().await.unwrap();
+        assert_eq!(label, "interactive");
+        drop(permit);
+        // 同一优先级按到达顺序
+        let (label, permit) = admitted.recv().await.unwrap();
+        assert_eq!(label, "batch");
+        drop(permit);
+        let (label, _permit) = admitted.recv().await.unwrap();
+        assert_eq!(label, "batch-2");
+        assert!(queue.depths().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_improves_priority() {
+        let queue = Arc::new(AdmissionQueue::new(Some(1), Duration::from_secs(5)));
+        let (tx, mut admitted) = mpsc::unbounded_channel();
+        let running = queue.acquire(10).await;
+        enqueue(&queue, 10, "batch", &tx).await;
+        // 等待 50 秒后 batch 的有效优先级为 0, 优于新到的 1
+        advance(Duration::from_secs(50)).await;
+        enqueue(&queue, 1, "interactive", &tx).await;
+
+        drop(running);
+        let (label, _permit) = admitted.recv().await.unwrap();
+        assert_eq!(label, "batch");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_waiters_give_up_their_place() {
+        let queue = Arc::new(AdmissionQueue::new(Some(1), Duration::ZERO));
+        let (tx, mut admitted) = mpsc::unbounded_channel();
+        let running = queue.acquire(10).await;
+        let cancelled = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire(1).await })
+        };
+        yield_now().await;
+        enqueue(&queue, 10, "waiting", &tx).await;
+        cancelled.abort();
+        let _ = cancelled.await;
+        assert_eq!(queue.depths(), BTreeMap::from([(10, 1)]));
+
+        drop(running);
+        let (label, permit) = admitted.recv().await.unwrap();
+        assert_eq!(label, "waiting");
+        // 最后一个许可释放后空位回到池中
+        drop(permit);
+        let _permit = queue.acquire(10).await;
+    }
+}
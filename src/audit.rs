@@ -0,0 +1,104 @@
+//! Audit log of chat requests.
+//!
+//! With `logging.audit` enabled, every chat request is written as one event
+//! of the `deepthink::audit` log target once it is answered: its route, the
+//! fingerprint of the caller's token, the status, the duration and the
+//! request body. The body passes through [`redact::json`], so the audit log
+//! honours `logging.log_content` like every other log line.
+
+use crate::{handlers::AppState, redact};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Instant};
+
+/// Largest request body buffered for the audit log, the JSON extractor's default limit.
+const MAX_AUDITED_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Writes the audit event of each chat request.
+pub async fn record(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if !state.config.logging.audit {
+        return next.run(request).await;
+    }
+    let started = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let caller = request
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(fingerprint)
+        .unwrap_or_default();
+
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, MAX_AUDITED_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!(target: "deepthink::audit", route, caller, "Unreadable request body: {}", e);
+            return (StatusCode::PAYLOAD_TOO_LARGE, format!("Request body exceeds {} bytes", MAX_AUDITED_BODY_BYTES)).into_response();
+        }
+    };
+    let body = redact::json(&String::from_utf8_lossy(&bytes));
+    let response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+    tracing::info!(
+        target: "deepthink::audit",
+        route,
+        caller,
+        status = response.status().as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        body,
+        "Chat request"
+    );
+    response
+}
+
+/// Returns a short, stable fingerprint of a token that does not reveal it.
+pub fn fingerprint(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    digest.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, OPENAI_PATH};
+    use serde_json::json;
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn fingerprints_are_stable_and_hide_the_token() {
+        assert_eq!(fingerprint("sk-secret"), fingerprint("sk-secret"));
+        assert_ne!(fingerprint("sk-secret"), fingerprint("sk-other"));
+        assert_eq!(fingerprint("sk-secret").len(), 12);
+        assert!(!fingerprint("sk-secret").contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn audited_requests_reach_the_handler_unchanged() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.logging.audit = true;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi there"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[("Authorization", "Bearer sk-secret")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        assert!(target_calls[0]["messages"].to_string().contains("Hi there"));
+    }
+}
@@ -0,0 +1,397 @@
+//! Daily and monthly spend quotas per auth token.
+//!
+//! The cost of each completed request is priced from its per-phase token
+//! usage with `budget.pricing` and added to the caller's running totals in a
+//! [`SpendLedger`]. Before a pipeline starts, the totals for the current UTC
+//! day and month are compared against the token's `daily_budget_usd` and
+//! `monthly_budget_usd`: an exhausted budget rejects the request, and one past
+//! `budget.warning_ratio` only adds a warning header. Checks only read the
+//! totals in memory; with `budget.store_path` set they are loaded from a
+//! sqlite database at startup and changed totals are flushed to it
+//! periodically, so a restart does not reset the budgets. A store that
+//! cannot be opened or written is logged and the ledger keeps working from
+//! memory.
+
+use crate::{
+    config::{BudgetConfig, TokenConfig},
+    error::{ApiError, Result},
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use rusqlite::{params, Connection};
+use std::{collections::HashMap, sync::Mutex};
+
+/// Response header carrying the soft budget warning.
+pub const BUDGET_WARNING_HEADER: &str = "X-DeepThink-Budget-Warning";
+
+/// Running spend per caller for the current day and month.
+#[derive(Debug, Default)]
+pub struct SpendLedger {
+    spend: Mutex<HashMap<String, Spend>>,
+    /// The sqlite database the totals are flushed to, if configured.
+    store: Option<Mutex<Connection>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Spend {
+    day: Option<NaiveDate>,
+    daily_usd: f64,
+    month: Option<(i32, u32)>,
+    monthly_usd: f64,
+    /// Whether the totals changed since the last flush.
+    dirty: bool,
+    /// Spend on speculative reasoning since the process started, included in the totals above.
+    speculative_usd: f64,
+}
+
+impl Spend {
+    /// Drops totals from windows that have ended.
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let today = now.date_naive();
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.daily_usd = 0.0;
+        }
+        let month = (now.year(), now.month());
+        if self.month != Some(month) {
+            self.month = Some(month);
+            self.monthly_usd = 0.0;
+        }
+    }
+}
+
+impl SpendLedger {
+    /// Opens the ledger persisted in the sqlite database at `path`, creating it if needed.
+    ///
+    /// Falls back to an in-memory ledger, with a warning, if the database
+    /// cannot be opened or read.
+    pub fn open(path: &str) -> Self {
+        match load(path) {
+            Ok((connection, spend)) => {
+                tracing::info!("Loaded the spend of {} callers from {}", spend.len(), path);
+                Self {
+                    spend: Mutex::new(spend),
+                    store: Some(Mutex::new(connection)),
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Keeping spend in memory only, cannot open {}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Writes the totals changed since the last flush to the store.
+    ///
+    /// A failed write is logged and retried on the next flush.
+    pub fn flush(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let changed: Vec<(String, Spend)> = {
+            let mut ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+            ledger
+                .iter_mut()
+                .filter(|(_, spend)| spend.dirty)
+                .map(|(caller, spend)| {
+                    spend.dirty = false;
+                    (caller.clone(), *spend)
+                })
+                .collect()
+        };
+        if changed.is_empty() {
+            return;
+        }
+        let mut connection = store.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = save(&mut connection, &changed) {
+            tracing::warn!("Failed to flush the spend of {} callers: {}", changed.len(), e);
+            // 写入失败的记录留到下次刷新时重试
+            let mut ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+            for (caller, _) in &changed {
+                if let Some(spend) = ledger.get_mut(caller) {
+                    spend.dirty = true;
+                }
+            }
+        }
+    }
+
+    /// Checks the caller's spend against its budgets.
+    ///
+    /// # Arguments
+    ///
+    /// * `caller` - The key spend is recorded under
+    /// * `tokens` - The caller's token configuration holding its budgets
+    /// * `config` - The warning threshold
+    /// * `now` - The current time, which selects the day and month windows
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - A warning to send to the caller once a
+    ///   budget passes the warning threshold, or `None`
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BudgetExceeded` if the daily or monthly budget is used up.
+    pub fn check(
+        &self,
+        caller: &str,
+        tokens: &TokenConfig,
+        config: &BudgetConfig,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>> {
+        if tokens.daily_budget_usd.is_none() && tokens.monthly_budget_usd.is_none() {
+            return Ok(None);
+        }
+        let spend = {
+            let mut ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+            let spend = ledger.entry(caller.to_string()).or_default();
+            spend.roll(now);
+            *spend
+        };
+
+        let windows = [
+            ("daily", tokens.daily_budget_usd, spend.daily_usd, next_day(now)),
+            ("monthly", tokens.monthly_budget_usd, spend.monthly_usd, next_month(now)),
+        ];
+        let mut warning = None;
+        for (window, limit, spent, resets_at) in windows {
+            let Some(limit) = limit else {
+                continue;
+            };
+            if spent >= limit {
+                return Err(ApiError::BudgetExceeded {
+                    window: window.to_string(),
+                    limit_usd: limit,
+                    spent_usd: spent,
+                    resets_at,
+                });
+            }
+            if warning.is_none() && spent >= limit * config.warning_ratio {
+                warning = Some(format!(
+                    "{} budget {:.0}% used: ${:.4} of ${:.2}, resets at {}",
+                    window,
+                    spent / limit * 100.0,
+                    spent,
+                    limit,
+                    resets_at.to_rfc3339()
+                ));
+            }
+        }
+        Ok(warning)
+    }
+
+    /// Adds the cost of a completed request to the caller's totals.
+    pub fn record(&self, caller: &str, cost_usd: f64, now: DateTime<Utc>) {
+        if cost_usd <= 0.0 {
+            return;
+        }
+        let mut ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+        let spend = ledger.entry(caller.to_string()).or_default();
+        spend.roll(now);
+        spend.daily_usd += cost_usd;
+        spend.monthly_usd += cost_usd;
+        spend.dirty = true;
+    }
+
+    /// Adds the cost of a speculative reasoner run to the caller's totals,
+    /// tallying it as speculative spend as well.
+    pub fn record_speculative(&self, caller: &str, cost_usd: f64, now: DateTime<Utc>) {
+        if cost_usd <= 0.0 {
+            return;
+        }
+        self.record(caller, cost_usd, now);
+        let mut ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+        ledger.entry(caller.to_string()).or_default().speculative_usd += cost_usd;
+    }
+
+    /// Returns the speculative spend of all callers since the process started.
+    pub fn speculative_total(&self) -> f64 {
+        let ledger = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+        ledger.values().fold(0.0, |total, spend| total + spend.speculative_usd)
+    }
+}
+
+/// Opens the store at `path` and reads the totals of every caller.
+fn load(path: &str) -> rusqlite::Result<(Connection, HashMap<String, Spend>)> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS spend (
+            caller TEXT PRIMARY KEY,
+            day TEXT,
+            daily_usd REAL NOT NULL,
+            month TEXT,
+            monthly_usd REAL NOT NULL
+        )",
+    )?;
+    let spend = {
+        let mut statement = connection.prepare("SELECT caller, day, daily_usd, month, monthly_usd FROM spend")?;
+        let rows = statement.query_map([], |row| {
+            let day: Option<String> = row.get(1)?;
+            let month: Option<String> = row.get(3)?;
+            Ok((
+                row.get::<_, String>(0)?,
+                Spend {
+                    day: day.and_then(|day| day.parse().ok()),
+                    daily_usd: row.get(2)?,
+                    month: month.and_then(|month| parse_month(&month)),
+                    monthly_usd: row.get(4)?,
+                    dirty: false,
+                    speculative_usd: 0.0,
+                },
+            ))
+        })?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>()?
+    };
+    Ok((connection, spend))
+}
+
+/// Writes the totals of `changed` in one transaction.
+fn save(connection: &mut Connection, changed: &[(String, Spend)]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare(
+            "INSERT OR REPLACE INTO spend (caller, day, daily_usd, month, monthly_usd)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for (caller, spend) in changed {
+            statement.execute(params![
+                caller,
+                spend.day.map(|day| day.to_string()),
+                spend.daily_usd,
+                spend.month.map(|(year, month)| format!("{:04}-{:02}", year, month)),
+                spend.monthly_usd,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+/// Parses a `YYYY-MM` month.
+fn parse_month(month: &str) -> Option<(i32, u32)> {
+    let (year, month) = month.split_once('-')?;
+    Some((year.parse().ok()?, month.parse().ok()?))
+}
+
+/// Returns the start of the next UTC day.
+fn next_day(now: DateTime<Utc>) -> DateTime<Utc> {
+    let tomorrow = now.date_naive() + Duration::days(1);
+    Utc.from_utc_datetime(&tomorrow.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
+
+/// Returns the start of the next UTC month.
+fn next_month(now: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = if now.month() == 12 {
+        (now.year() + 1, 1)
+    } else {
+        (now.year(), now.month() + 1)
+    };
+    let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default();
+    Utc.from_utc_datetime(&first.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(daily: f64) -> TokenConfig {
+        TokenConfig {
+            daily_budget_usd: Some(daily),
+            ..crate::config::Config::default().auth.default_tokens
+        }
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn crossing_the_threshold_mid_day_warns_then_rejects() {
+        let ledger = SpendLedger::default();
+        let config = BudgetConfig::default();
+        let tokens = tokens(1.0);
+        let morning = at("2025-03-10T09:00:00Z");
+        assert_eq!(ledger.check("caller", &tokens, &config, morning).unwrap(), None);
+
+        ledger.record("caller", 0.85, morning);
+        let warning = ledger.check("caller", &tokens, &config, at("2025-03-10T12:00:00Z")).unwrap();
+        assert!(warning.unwrap().starts_with("daily budget 85% used"));
+
+        ledger.record("caller", 0.2, at("2025-03-10T13:00:00Z"));
+        let error = ledger.check("caller", &tokens, &config, at("2025-03-10T14:00:00Z")).unwrap_err();
+        assert!(matches!(error, ApiError::BudgetExceeded { ref window, .. } if window == "daily"));
+
+        // 第二天的预算重新计算
+        assert_eq!(ledger.check("caller", &tokens, &config, at("2025-03-11T00:00:01Z")).unwrap(), None);
+    }
+
+    #[test]
+    fn monthly_budgets_span_days_and_reset_with_the_month() {
+        let ledger = SpendLedger::default();
+        let config = BudgetConfig::default();
+        let tokens = TokenConfig {
+            monthly_budget_usd: Some(1.0),
+            ..crate::config::Config::default().auth.default_tokens
+        };
+        ledger.record("caller", 0.6, at("2025-03-10T09:00:00Z"));
+        ledger.record("caller", 0.6, at("2025-03-20T09:00:00Z"));
+        let error = ledger.check("caller", &tokens, &config, at("2025-03-31T23:59:59Z")).unwrap_err();
+        assert!(matches!(
+            error,
+            ApiError::BudgetExceeded { ref window, resets_at, .. }
+                if window == "monthly" && resets_at == at("2025-04-01T00:00:00Z")
+        ));
+        assert_eq!(ledger.check("caller", &tokens, &config, at("2025-04-01T00:00:00Z")).unwrap(), None);
+    }
+
+    #[test]
+    fn callers_are_charged_separately() {
+        let ledger = SpendLedger::default();
+        let now = at("2025-03-10T09:00:00Z");
+        ledger.record("a", 5.0, now);
+        assert!(ledger.check("a", &tokens(1.0), &BudgetConfig::default(), now).is_err());
+        assert!(ledger.check("b", &tokens(1.0), &BudgetConfig::default(), now).is_ok());
+    }
+
+    #[test]
+    fn flushed_totals_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("deepthink-spend-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let now = at("2025-03-10T09:00:00Z");
+        {
+            let ledger = SpendLedger::open(path);
+            ledger.record("caller", 0.75, now);
+            ledger.flush();
+        }
+        let ledger = SpendLedger::open(path);
+        let warning = ledger.check("caller", &tokens(1.0), &BudgetConfig::default(), now).unwrap();
+        assert!(warning.is_none());
+        let error = ledger.check("caller", &tokens(0.7), &BudgetConfig::default(), now).unwrap_err();
+        assert!(matches!(error, ApiError::BudgetExceeded { spent_usd, .. } if (spent_usd - 0.75).abs() < 1e-9));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn an_unopenable_store_fails_open() {
+        let ledger = SpendLedger::open("/nonexistent-dir/spend.db");
+        let now = at("2025-03-10T09:00:00Z");
+        ledger.record("caller", 0.5, now);
+        ledger.flush();
+        assert!(ledger.check("caller", &tokens(1.0), &BudgetConfig::default(), now).is_ok());
+    }
+
+    #[test]
+    fn speculative_spend_counts_towards_the_budget() {
+        let ledger = SpendLedger::default();
+        let config = BudgetConfig::default();
+        let tokens = tokens(1.0);
+        let now = at("2025-03-10T09:00:00Z");
+        assert_eq!(ledger.speculative_total(), 0.0);
+
+        ledger.record("caller", 0.5, now);
+        ledger.record_speculative("caller", 0.3, now);
+        ledger.record_speculative("other", 0.1, now);
+        ledger.record_speculative("other", 0.0, now);
+        assert!((ledger.speculative_total() - 0.4).abs() < 1e-9);
+        let warning = ledger.check("caller", &tokens, &config, now).unwrap();
+        assert!(warning.unwrap().starts_with("daily budget 80% used"));
+    }
+}
@@ -0,0 +1,238 @@
+//! Target model capabilities and request checks against them.
+//!
+//! Each target model resolves to a [`ModelCapabilities`] declaration: from its
+//! mapping's `capabilities`, else from the longest matching prefix in
+//! `models.capabilities`, else from a small built-in table, else everything
+//! is allowed. A request using a feature its target lacks is rejected with a
+//! 400 naming the feature and model, except for images, which are replaced
+//! by a text placeholder when `models.degrade_images` is set.
+
+use crate::{
+    config::{ModelCapabilities, ModelConfig},
+    error::{ApiError, Result},
+    models::{ContentPart, Message, MessageContent},
+};
+
+/// Response header listing the content that was degraded for the target.
+pub const CONTENT_WARNING_HEADER: &str = "X-DeepThink-Content-Warning";
+
+/// Placeholder that replaces an image the target cannot accept.
+const IMAGE_PLACEHOLDER: &str = "[image omitted: the target model does not accept images]";
+
+/// Built-in declarations for known text-only model families, by name prefix.
+const BUILTIN: &[(&str, ModelCapabilities)] = &[
+    ("qwen2.5", ModelCapabilities::text_only(true, true)),
+    ("deepseek-r1", ModelCapabilities::text_only(false, false)),
+    ("deepseek-reasoner", ModelCapabilities::text_only(false, false)),
+    ("gpt-3.5", ModelCapabilities::text_only(true, true)),
+    ("claude-2", ModelCapabilities::text_only(false, false)),
+];
+
+/// Returns the capabilities of the upstream model `model`.
+///
+/// # Arguments
+///
+/// * `config` - The model configuration holding declared capabilities
+/// * `mapping_capabilities` - The request's mapping declaration, which wins when set
+/// * `model` - The upstream model name
+pub fn resolve(config: &ModelConfig, mapping_capabilities: Option<&ModelCapabilities>, model: &str) -> ModelCapabilities {
+    if let Some(capabilities) = mapping_capabilities {
+        return capabilities.clone();
+    }
+    let configured = config
+        .capabilities
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, capabilities)| capabilities.clone());
+    configured
+        .or_else(|| {
+            BUILTIN
+                .iter()
+                .filter(|(prefix, _)| model.starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, capabilities)| capabilities.clone())
+        })
+        .unwrap_or_default()
+}
+
+/// Checks a request against its target's capabilities.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation; degraded images are replaced in place
+/// * `target_body` - The target's request body, carrying `tools` and `response_format`
+/// * `model` - The target model name, for error messages
+/// * `capabilities` - What the target supports
+/// * `degrade_images` - Replace unsupported images instead of rejecting the request
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - A warning describing the degraded content, if any
+///
+/// # Errors
+///
+/// Returns `ApiError::UnsupportedCapability` for the first feature the target
+/// lacks that cannot be degraded.
+pub fn enforce(
+    messages: &mut [Message],
+    target_body: &serde_json::Value,
+    model: &str,
+    capabilities: &ModelCapabilities,
+    degrade_images: bool,
+) -> Result<Option<String>> {
+    let unsupported = |feature: &str| ApiError::UnsupportedCapability {
+        feature: feature.to_string(),
+        model: model.to_string(),
+    };
+
+    if !capabilities.supports_tools && target_body.get("tools").is_some_and(|tools| !tools.is_null()) {
+        return Err(unsupported("tools"));
+    }
+    let json_mode = target_body
+        .pointer("/response_format/type")
+        .and_then(|t| t.as_str())
+        .is_some_and(|t| t == "json_object" || t == "json_schema");
+    if !capabilities.supports_json_mode && json_mode {
+        return Err(unsupported("json_mode"));
+    }
+
+    let allowed = match (capabilities.supports_images, capabilities.max_images) {
+        (false, _) => 0,
+        (true, Some(max_images)) => max_images as usize,
+        (true, None) => return Ok(None),
+    };
+    let images = messages.iter().flat_map(image_parts).count();
+    if images <= allowed {
+        return Ok(None);
+    }
+    if !degrade_images {
+        return Err(match allowed {
+            0 => unsupported("images"),
+            _ => unsupported(&format!("more than {} images", allowed)),
+        });
+    }
+
+    // 超出数量的图片按出现顺序替换为文本占位符
+    let mut seen = 0;
+    for message in messages.iter_mut() {
+        if let MessageContent::Parts(parts) = &mut message.content {
+            for part in parts.iter_mut().filter(|part| is_image(part)) {
+                seen += 1;
+                if seen > allowed {
+                    *part = ContentPart::Text {
+                        text: IMAGE_PLACEHOLDER.to_string(),
+                    };
+                }
+            }
+        }
+    }
+    Ok(Some(format!(
+        "{} of {} images replaced with text placeholders: {} accepts {}",
+        images - allowed,
+        images,
+        model,
+        match allowed {
+            0 => "no images".to_string(),
+            n => format!("at most {} images", n),
+        }
+    )))
+}
+
+fn image_parts(message: &Message) -> impl Iterator<Item = &ContentPart> {
+    let parts = match &message.content {
+        MessageContent::Parts(parts) => parts.as_slice(),
+        MessageContent::Text(_) => &[],
+    };
+    parts.iter().filter(|part| is_image(part))
+}
+
+fn is_image(part: &ContentPart) -> bool {
+    matches!(part, ContentPart::ImageUrl { .. } | ContentPart::Image { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn image(url: &str) -> serde_json::Value {
+        json!({"type": "image_url", "image_url": {"url": url}})
+    }
+
+    fn messages(parts: serde_json::Value) -> Vec<Message> {
+        serde_json::from_value(json!([{"role": "user", "content": parts}])).unwrap()
+    }
+
+    fn capabilities(value: serde_json::Value) -> ModelCapabilities {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn rejected_feature(result: Result<Option<String>>) -> String {
+        match result {
+            Err(ApiError::UnsupportedCapability { feature, model }) => {
+                assert_eq!(model, "target");
+                feature
+            }
+            other => panic!("expected an unsupported capability, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mappings_win_over_configured_prefixes_and_the_builtin_table() {
+        let mut config = ModelConfig::default();
+        assert!(!resolve(&config, None, "qwen2.5:14b").supports_images);
+        assert_eq!(resolve(&config, None, "unknown-model"), ModelCapabilities::default());
+
+        config.capabilities.insert("qwen".to_string(), capabilities(json!({"supports_tools": false})));
+        config.capabilities.insert("qwen2.5-vl".to_string(), capabilities(json!({"max_images": 2})));
+        assert!(!resolve(&config, None, "qwen2.5:14b").supports_tools);
+        assert_eq!(resolve(&config, None, "qwen2.5-vl:7b").max_images, Some(2));
+
+        let declared = ModelCapabilities::text_only(false, false);
+        assert_eq!(resolve(&config, Some(&declared), "qwen2.5-vl:7b"), declared);
+    }
+
+    #[test]
+    fn each_missing_capability_is_rejected_by_name() {
+        let text_only = ModelCapabilities::text_only(false, false);
+        let mut plain = messages(json!("Hi"));
+
+        let tools = json!({"tools": [{"type": "function", "function": {"name": "lookup"}}]});
+        assert_eq!(rejected_feature(enforce(&mut plain, &tools, "target", &text_only, false)), "tools");
+
+        for format in ["json_object", "json_schema"] {
+            let body = json!({"response_format": {"type": format}});
+            assert_eq!(rejected_feature(enforce(&mut plain, &body, "target", &text_only, false)), "json_mode");
+        }
+        let text_format = json!({"response_format": {"type": "text"}, "tools": null});
+        assert_eq!(enforce(&mut plain, &text_format, "target", &text_only, false).unwrap(), None);
+
+        let mut pictures = messages(json!([{"type": "text", "text": "Compare"}, image("a.png"), image("b.png")]));
+        assert_eq!(rejected_feature(enforce(&mut pictures, &json!({}), "target", &text_only, false)), "images");
+        let two_images = capabilities(json!({"max_images": 1}));
+        assert_eq!(
+            rejected_feature(enforce(&mut pictures, &json!({}), "target", &two_images, false)),
+            "more than 1 images"
+        );
+        assert_eq!(enforce(&mut pictures, &json!({}), "target", &ModelCapabilities::default(), false).unwrap(), None);
+    }
+
+    #[test]
+    fn images_over_the_limit_are_degraded_to_placeholders_in_order() {
+        let mut pictures = messages(json!([{"type": "text", "text": "Compare"}, image("a.png"), image("b.png")]));
+        let one_image = capabilities(json!({"max_images": 1}));
+        let warning = enforce(&mut pictures, &json!({}), "target", &one_image, true).unwrap();
+        assert_eq!(warning.as_deref(), Some("1 of 2 images replaced with text placeholders: target accepts at most 1 images"));
+        let MessageContent::Parts(parts) = &pictures[0].content else {
+            panic!("parts were flattened");
+        };
+        assert_eq!(parts[0], ContentPart::Text { text: "Compare".to_string() });
+        assert!(is_image(&parts[1]));
+        assert_eq!(parts[2], ContentPart::Text { text: IMAGE_PLACEHOLDER.to_string() });
+
+        let warning = enforce(&mut pictures, &json!({}), "target", &ModelCapabilities::text_only(true, true), true).unwrap();
+        assert_eq!(warning.as_deref(), Some("1 of 1 images replaced with text placeholders: target accepts no images"));
+        assert_eq!(pictures.iter().flat_map(image_parts).count(), 0);
+    }
+}
@@ -0,0 +1,437 @@
+//! Circuit breakers for the upstream providers.
+//!
+//! Every `(provider, base_url)` pair has its own breaker. After
+//! `circuit_breaker.failure_threshold` consecutive connection failures
+//! (connect errors and timeouts) its circuit opens, and requests to that
+//! upstream fail fast for `circuit_breaker.cooldown_secs` instead of waiting
+//! out the connect timeout. The first request after the cool-down goes
+//! through as a probe: if it reaches the upstream the circuit closes, if its
+//! connection fails too the circuit opens for another cool-down. Any
+//! response, even an error status, counts as reaching the upstream, except a
+//! rate limit: a `429` opens the circuit at once, so requests back off
+//! instead of adding to the limit. The back-off lasts for the delay the
+//! upstream advertised; without one it starts at the cool-down and doubles
+//! with every further `429` until a request succeeds. A response whose rate
+//! limit headers show an exhausted window backs off the same way, and a
+//! success reported while a back-off runs does not close the circuit early.
+
+use crate::error::{ApiError, Result};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Most times a back-off without an advertised delay doubles the cool-down.
+const MAX_BACK_OFF_DOUBLINGS: u32 = 5;
+
+/// The breakers of every upstream that has failed, by provider and base URL.
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    circuits: Mutex<HashMap<(String, String), Circuit>>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// When the half-open probe was let through; a probe that never
+    /// reports back is replaced after another cool-down.
+    probe_started_at: Option<Instant>,
+    /// Cool-down set by an upstream rate limit instead of the configured one.
+    back_off: Option<Duration>,
+    /// Rate limits without an advertised delay since the last success.
+    unadvertised_rate_limits: u32,
+}
+
+impl Circuit {
+    fn cooldown(&self, default: Duration) -> Duration {
+        self.back_off.unwrap_or(default)
+    }
+
+    /// Returns true while a rate-limit back-off keeps the circuit open.
+    fn backing_off(&self) -> bool {
+        match (self.opened_at, self.back_off) {
+            (Some(opened_at), Some(back_off)) => opened_at.elapsed() < back_off,
+            _ => false,
+        }
+    }
+}
+
+/// State of one circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through.
+    Closed,
+    /// Requests fail fast until the cool-down ends.
+    Open,
+    /// The cool-down has ended; the next request probes the upstream.
+    HalfOpen,
+}
+
+/// Point-in-time state of one circuit, as reported by `/health` and `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub provider: String,
+    pub base_url: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// Seconds until an open circuit lets a probe through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl CircuitBreakers {
+    /// Creates breakers with every circuit closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `failure_threshold` - Consecutive connection failures that open a
+    ///   circuit; 0 disables the breakers
+    /// * `cooldown` - How long an open circuit fails fast
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            circuits: Mutex::new(HashMap::new()),
+            failure_threshold,
+            cooldown,
+        }
+    }
+
+    /// Checks whether a request to `base_url` may be sent.
+    ///
+    /// Once an open circuit's cool-down has ended, the first caller is let
+    /// through as the probe and the others keep failing fast until it reports.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::CircuitOpen` while the circuit is open.
+    pub fn check(&self, provider: &str, base_url: &str) -> Result<()> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut circuits = self.lock();
+        let Some(circuit) = circuits.get_mut(&(provider.to_string(), base_url.to_string())) else {
+            return Ok(());
+        };
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+        let elapsed = opened_at.elapsed();
+        let cooldown = circuit.cooldown(self.cooldown);
+        let probe_pending = circuit
+            .probe_started_at
+            .is_some_and(|started_at| started_at.elapsed() < cooldown);
+        if elapsed >= cooldown && !probe_pending {
+            tracing::info!("Circuit for {} at {} is half-open, probing", provider, base_url);
+            circuit.probe_started_at = Some(Instant::now());
+            return Ok(());
+        }
+        Err(ApiError::CircuitOpen {
+            provider: provider.to_string(),
+            base_url: base_url.to_string(),
+            retry_after_secs: cooldown.saturating_sub(elapsed).as_secs().max(1),
+        })
+    }
+
+    /// Returns true while the circuit of `base_url` is open and its cool-down has not ended.
+    ///
+    /// Unlike [`CircuitBreakers::check`] this never lets a probe through.
+    pub fn is_open(&self, provider: &str, base_url: &str) -> bool {
+        if self.failure_threshold == 0 {
+            return false;
+        }
+        self.lock()
+            .get(&(provider.to_string(), base_url.to_string()))
+            .and_then(|circuit| circuit.opened_at.map(|opened_at| opened_at.elapsed() < circuit.cooldown(self.cooldown)))
+            .unwrap_or(false)
+    }
+
+    /// Records the outcome of a request to `base_url`.
+    pub fn record<T>(&self, provider: &str, base_url: &str, result: &Result<T>) {
+        match result {
+            Ok(_) => self.record_success(provider, base_url),
+            Err(e) => self.record_error(provider, base_url, e),
+        }
+    }
+
+    /// Records a request that reached the upstream, closing its circuit.
+    pub fn record_success(&self, provider: &str, base_url: &str) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut circuits = self.lock();
+        let key = (provider.to_string(), base_url.to_string());
+        // 限流退避期间完成的请求不提前关闭熔断器
+        if circuits.get(&key).is_some_and(Circuit::backing_off) {
+            return;
+        }
+        if let Some(circuit) = circuits.remove(&key) {
+            if circuit.opened_at.is_some() {
+                tracing::info!("Circuit for {} at {} closed", provider, base_url);
+            }
+        }
+    }
+
+    /// Records a failed request; only connection failures count towards
+    /// opening the circuit, and a rate limit opens it for its retry delay.
+    /// Any other error closes it.
+    pub fn record_error(&self, provider: &str, base_url: &str, error: &ApiError) {
+        if let ApiError::UpstreamRateLimited { retry_after_secs, .. } = error {
+            self.back_off(provider, base_url, retry_after_secs.map(Duration::from_secs));
+            return;
+        }
+        if !error.is_connection_failure() {
+            self.record_success(provider, base_url);
+            return;
+        }
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut circuits = self.lock();
+        let circuit = circuits.entry((provider.to_string(), base_url.to_string())).or_default();
+        circuit.consecutive_failures += 1;
+        let probe_failed = circuit.probe_started_at.is_some();
+        if probe_failed || circuit.consecutive_failures >= self.failure_threshold {
+            if circuit.opened_at.is_none() || probe_failed {
+                tracing::warn!(
+                    "Circuit for {} at {} opened after {} consecutive connection failures",
+                    provider,
+                    base_url,
+                    circuit.consecutive_failures
+                );
+            }
+            circuit.opened_at = Some(Instant::now());
+            circuit.probe_started_at = None;
+            circuit.back_off = None;
+        }
+    }
+
+    /// Opens the circuit after the upstream rate-limited a request or
+    /// reported an exhausted window.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The advertised time until the limit resets; `None` backs
+    ///   off exponentially from the configured cool-down
+    pub fn back_off(&self, provider: &str, base_url: &str, delay: Option<Duration>) {
+        if self.failure_threshold == 0 || delay.is_some_and(|delay| delay.is_zero()) {
+            return;
+        }
+        let mut circuits = self.lock();
+        let circuit = circuits.entry((provider.to_string(), base_url.to_string())).or_default();
+        let delay = delay.unwrap_or_else(|| {
+            let doublings = circuit.unadvertised_rate_limits.min(MAX_BACK_OFF_DOUBLINGS);
+            circuit.unadvertised_rate_limits += 1;
+            self.cooldown * 2u32.pow(doublings)
+        });
+        // 已在进行的更长退避不被缩短
+        let running = circuit
+            .opened_at
+            .zip(circuit.back_off)
+            .map(|(opened_at, back_off)| back_off.saturating_sub(opened_at.elapsed()))
+            .unwrap_or_default();
+        if running >= delay {
+            return;
+        }
+        tracing::warn!("Circuit for {} at {} opened for {}s by a rate limit", provider, base_url, delay.as_secs());
+        circuit.opened_at = Some(Instant::now());
+        circuit.probe_started_at = None;
+        circuit.back_off = Some(delay);
+    }
+
+    /// Returns the state of every circuit that has seen a connection failure
+    /// since it last closed.
+    pub fn states(&self) -> Vec<CircuitStatus> {
+        let circuits = self.lock();
+        let mut states: Vec<CircuitStatus> = circuits
+            .iter()
+            .map(|((provider, base_url), circuit)| {
+                let remaining = circuit
+                    .opened_at
+                    .map(|opened_at| circuit.cooldown(self.cooldown).saturating_sub(opened_at.elapsed()));
+                let state = match remaining {
+                    None => CircuitState::Closed,
+                    Some(remaining) if !remaining.is_zero() => CircuitState::Open,
+                    Some(_) => CircuitState::HalfOpen,
+                };
+                CircuitStatus {
+                    provider: provider.clone(),
+                    base_url: base_url.clone(),
+                    state,
+                    consecutive_failures: circuit.consecutive_failures,
+                    retry_after_secs: remaining
+                        .filter(|remaining| !remaining.is_zero())
+                        .map(|remaining| remaining.as_secs().max(1)),
+                }
+            })
+            .collect();
+        states.sort_by(|a, b| (&a.provider, &a.base_url).cmp(&(&b.provider, &b.base_url)));
+        states
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, String), Circuit>> {
+        self.circuits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "http://reasoner.local";
+
+    fn connection_failure() -> ApiError {
+        ApiError::DeepSeekError {
+            message: "error sending request".to_string(),
+            type_: crate::clients::CONNECTION_FAILED_ERROR_TYPE.to_string(),
+            param: None,
+            code: None,
+        }
+    }
+
+    fn state(breakers: &CircuitBreakers) -> Option<CircuitState> {
+        breakers.states().first().map(|status| status.state)
+    }
+
+    #[test]
+    fn opens_after_consecutive_connection_failures() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(60));
+        breakers.record_error("deepseek", URL, &connection_failure());
+        assert!(breakers.check("deepseek", URL).is_ok());
+        assert_eq!(state(&breakers), Some(CircuitState::Closed));
+
+        breakers.record_error("deepseek", URL, &connection_failure());
+        match breakers.check("deepseek", URL) {
+            Err(ApiError::CircuitOpen { provider, base_url, retry_after_secs }) => {
+                assert_eq!((provider.as_str(), base_url.as_str()), ("deepseek", URL));
+                assert!(retry_after_secs > 0 && retry_after_secs <= 60);
+            }
+            other => panic!("expected an open circuit, got {:?}", other),
+        }
+        let status = &breakers.states()[0];
+        assert_eq!((status.state, status.consecutive_failures), (CircuitState::Open, 2));
+        assert!(status.retry_after_secs.is_some());
+
+        // 其他上游不受影响
+        assert!(breakers.check("deepseek", "http://other.local").is_ok());
+        assert!(breakers.check("openai", URL).is_ok());
+    }
+
+    #[test]
+    fn upstream_error_statuses_reset_the_failure_count() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(60));
+        breakers.record_error("deepseek", URL, &connection_failure());
+        let rate_limited = ApiError::UpstreamStatus {
+            provider: "deepseek".to_string(),
+            status: 429,
+            message: "slow down".to_string(),
+        };
+        breakers.record_error("deepseek", URL, &rate_limited);
+        assert!(breakers.states().is_empty());
+
+        breakers.record_error("deepseek", URL, &connection_failure());
+        assert!(breakers.check("deepseek", URL).is_ok());
+    }
+
+    #[test]
+    fn half_open_probe_closes_the_circuit() {
+        let breakers = CircuitBreakers::new(1, Duration::from_millis(50));
+        breakers.record_error("deepseek", URL, &connection_failure());
+        assert!(breakers.check("deepseek", URL).is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(state(&breakers), Some(CircuitState::HalfOpen));
+        assert!(breakers.check("deepseek", URL).is_ok());
+        // 探测请求返回前其余请求继续快速失败
+        assert!(breakers.check("deepseek", URL).is_err());
+
+        breakers.record::<()>("deepseek", URL, &Ok(()));
+        assert!(breakers.states().is_empty());
+        assert!(breakers.check("deepseek", URL).is_ok());
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_circuit() {
+        let breakers = CircuitBreakers::new(3, Duration::from_millis(50));
+        for _ in 0..3 {
+            breakers.record_error("deepseek", URL, &connection_failure());
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breakers.check("deepseek", URL).is_ok());
+
+        breakers.record_error("deepseek", URL, &connection_failure());
+        assert_eq!(state(&breakers), Some(CircuitState::Open));
+        assert!(breakers.check("deepseek", URL).is_err());
+    }
+
+    #[test]
+    fn zero_threshold_disables_the_breakers() {
+        let breakers = CircuitBreakers::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breakers.record_error("deepseek", URL, &connection_failure());
+        }
+        assert!(breakers.check("deepseek", URL).is_ok());
+        assert!(breakers.states().is_empty());
+    }
+
+    #[test]
+    fn rate_limits_open_the_circuit_for_their_retry_delay() {
+        let breakers = CircuitBreakers::new(5, Duration::from_millis(10));
+        let rate_limited = |retry_after_secs| ApiError::UpstreamRateLimited {
+            provider: "deepseek".to_string(),
+            retry_after_secs,
+            message: "slow down".to_string(),
+        };
+        breakers.record_error("deepseek", URL, &rate_limited(Some(30)));
+        // 不等连续失败次数, 且按上游给出的等待时间而不是 cooldown 熔断
+        std::thread::sleep(Duration::from_millis(20));
+        match breakers.check("deepseek", URL) {
+            Err(ApiError::CircuitOpen { retry_after_secs, .. }) => assert!(retry_after_secs > 20 && retry_after_secs <= 30),
+            other => panic!("expected an open circuit, got {:?}", other),
+        }
+        assert_eq!(state(&breakers), Some(CircuitState::Open));
+
+        // 之后的连接失败恢复使用配置的 cooldown
+        breakers.record::<()>("deepseek", URL, &Ok(()));
+        for _ in 0..5 {
+            breakers.record_error("deepseek", URL, &connection_failure());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breakers.check("deepseek", URL).is_ok());
+
+        let breakers = CircuitBreakers::new(0, Duration::from_secs(60));
+        breakers.record_error("deepseek", URL, &rate_limited(Some(30)));
+        assert!(breakers.check("deepseek", URL).is_ok());
+    }
+
+    #[test]
+    fn unadvertised_rate_limits_double_the_back_off_until_a_success() {
+        let breakers = CircuitBreakers::new(5, Duration::from_secs(10));
+        let remaining = |breakers: &CircuitBreakers| breakers.states()[0].retry_after_secs.unwrap();
+        for expected in [10, 20, 40] {
+            breakers.back_off("openai", URL, None);
+            // 新的退避从现在开始计时, 至少覆盖预期的时长
+            assert!(remaining(&breakers) > expected / 2 && remaining(&breakers) <= expected, "{}", expected);
+        }
+        // 退避期间完成的请求不关闭熔断器, 更短的退避也不会缩短它
+        breakers.record::<()>("openai", URL, &Ok(()));
+        breakers.back_off("openai", URL, Some(Duration::from_secs(1)));
+        assert!(remaining(&breakers) > 20);
+        assert!(breakers.check("openai", URL).is_err());
+    }
+
+    #[test]
+    fn open_circuits_are_reported_without_taking_a_probe() {
+        let breakers = CircuitBreakers::new(1, Duration::from_secs(60));
+        assert!(!breakers.is_open("deepseek", URL));
+        breakers.record_error("deepseek", URL, &connection_failure());
+        assert!(breakers.is_open("deepseek", URL));
+        assert!(breakers.is_open("deepseek", URL));
+        assert!(!breakers.is_open("deepseek", "http://other.local"));
+        assert_eq!(state(&breakers), Some(CircuitState::Open));
+    }
+}
@@ -1,22 +1,48 @@
 use crate::{
+    clients::{next_chunk, observe_rate_limit, reject_frame, status_error, signed_body, ClientBuilder, ClientParts, ANTHROPIC_API_URL},
+    compression,
+    config::{ParseStrictness, ProviderDefaults},
+    connection,
+    endpoints,
     error::{ApiError, Result},
-    models::{ApiConfig, Message, Role},
+    models::{ApiConfig, Message, MessageContent, Role, SystemPrompt},
+    ratelimit::UpstreamRateLimits,
+    signing::RequestSigner,
+    trace,
 };
 use futures::Stream;
-use reqwest::{header::{HeaderMap, HeaderValue}, Client};
+use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, pin::Pin};
-use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use serde_json;
 
-pub(crate) const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1/messages";
+/// Built-in default model, used when `[defaults.anthropic]` does not set one.
 const DEFAULT_MODEL: &str = "claude-3-5-sonnet-20241022";
 
+/// Request keys set by the pipeline that `config.body` may not override; the system prompt comes from the request's `system` field.
+/// `model` is not protected: the body is where callers choose the model.
+pub(crate) const PROTECTED_BODY_KEYS: &[&str] = &["messages", "stream", "system"];
+
 #[derive(Debug)]
 pub struct AnthropicClient {
     pub(crate) client: Client,
     api_token: String,
     base_url: String,
+    default_headers: HeaderMap,
+    idle_timeout: Option<Duration>,
+    parse_strictness: ParseStrictness,
+    dropped_frames: Arc<AtomicU64>,
+    rate_limits: Option<Arc<UpstreamRateLimits>>,
+    signer: Option<Arc<dyn RequestSigner>>,
+    defaults: ProviderDefaults,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -30,29 +56,70 @@ pub struct AnthropicResponse {
     pub stop_reason: Option<String>,
     pub stop_sequence: Option<String>,
     pub usage: Usage,
+    /// Status and headers of the response this body came from.
+    #[serde(skip)]
+    pub upstream: super::UpstreamResponse,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ContentBlock {
     #[serde(rename = "type")]
     pub content_type: String,
+    #[serde(default)]
     pub text: String,
+    /// Id of a `tool_use` block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Tool name of a `tool_use` block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Tool input of a `tool_use` block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Token usage of a response.
+///
+/// `message_start` events carry the input and cache counts and
+/// `message_delta` events only the running `output_tokens`, so every field
+/// defaults to zero.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Usage {
+    #[serde(default)]
     pub input_tokens: u32,
+    #[serde(default)]
     pub output_tokens: u32,
+    #[serde(default)]
     pub cache_creation_input_tokens: u32,
+    #[serde(default)]
     pub cache_read_input_tokens: u32,
 }
 
+impl Usage {
+    /// Returns every input token billed, cached or not; Anthropic reports
+    /// cache writes and reads apart from `input_tokens`.
+    pub fn prompt_tokens(&self) -> u32 {
+        self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens
+    }
+}
+
+/// Converts Anthropic usage into the shared usage type, keeping cache reads
+/// apart so they can be priced at the cached rate.
+impl From<&Usage> for crate::models::UsageStats {
+    fn from(usage: &Usage) -> Self {
+        let mut stats = Self::default();
+        stats.add(usage.prompt_tokens(), usage.output_tokens);
+        stats.add_cached(usage.cache_read_input_tokens);
+        stats
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AnthropicRequest {
     messages: Vec<AnthropicMessage>,
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    system: Option<String>,
+    system: Option<SystemPrompt>,
     #[serde(flatten)]
     additional_params: serde_json::Value,
 }
@@ -60,11 +127,11 @@ pub(crate) struct AnthropicRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AnthropicMessage {
     role: String,
-    content: String,
+    content: MessageContent,
 }
 
 // Event types for streaming responses
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum StreamEvent {
     #[serde(rename = "message_start")]
@@ -72,24 +139,20 @@ pub enum StreamEvent {
         message: AnthropicResponse,
     },
     #[serde(rename = "content_block_start")]
-    #[allow(dead_code)]
     ContentBlockStart {
         index: usize,
         content_block: ContentBlock,
     },
     #[serde(rename = "content_block_delta")]
-    #[allow(dead_code)]
     ContentBlockDelta {
         index: usize,
         delta: ContentDelta,
     },
     #[serde(rename = "content_block_stop")]
-    #[allow(dead_code)]
     ContentBlockStop {
         index: usize,
     },
     #[serde(rename = "message_delta")]
-    #[allow(dead_code)]
     MessageDelta {
         delta: MessageDelta,
         usage: Option<Usage>,
@@ -98,22 +161,97 @@ pub enum StreamEvent {
     MessageStop,
     #[serde(rename = "ping")]
     Ping,
+    /// A failure reported mid-stream, e.g. `overloaded_error`.
+    #[serde(rename = "error")]
+    Error {
+        error: AnthropicErrorBody,
+    },
+}
+
+/// Error reported by the Anthropic API, in an error response or an `error` stream event.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicErrorBody {
+    /// Error type such as `invalid_request_error` or `permission_error`.
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+impl From<AnthropicErrorBody> for ApiError {
+    fn from(error: AnthropicErrorBody) -> Self {
+        ApiError::AnthropicError {
+            message: error.message,
+            type_: error.error_type,
+            param: None,
+            code: None,
+        }
+    }
+}
+
+/// Builds the error for an unsuccessful Anthropic response from its body.
+///
+/// The provider's error type and message are kept when the body is the
+/// documented `{"type": "error", "error": {...}}` envelope; any other body
+/// is reported verbatim as an `api_error`.
+pub(crate) fn api_error(body: String) -> ApiError {
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        error: AnthropicErrorBody,
+    }
+    match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(response) => response.error.into(),
+        Err(_) => ApiError::AnthropicError {
+            message: body,
+            type_: "api_error".to_string(),
+            param: None,
+            code: None,
+        },
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ContentDelta {
     #[serde(rename = "type")]
     pub delta_type: String,
+    #[serde(default)]
     pub text: String,
+    /// Fragment of a `tool_use` block's input, for `input_json_delta`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial_json: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[allow(dead_code)]
 pub struct MessageDelta {
     pub stop_reason: Option<String>,
     pub stop_sequence: Option<String>,
 }
 
+/// Maps an Anthropic `stop_reason` onto the OpenAI `finish_reason` vocabulary.
+///
+/// `max_tokens` becomes `length`, `tool_use` becomes `tool_calls` and
+/// refusals become `content_filter`; everything else is a normal `stop`.
+pub(crate) fn finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        _ => "stop",
+    }
+}
+
+#[allow(dead_code)]
+impl ClientBuilder<AnthropicClient> {
+    /// Builds the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if a default header was invalid.
+    pub fn build(self) -> Result<AnthropicClient> {
+        self.try_into_parts(ANTHROPIC_API_URL).map(AnthropicClient::from_parts)
+    }
+}
+
 impl AnthropicClient {
     /// Creates a new Anthropic client instance.
     ///
@@ -124,20 +262,128 @@ impl AnthropicClient {
     /// # Returns
     ///
     /// A new `AnthropicClient` instance configured with the provided API token
+    /// Returns a builder for a client with a custom HTTP client, default headers or user agent.
+    pub fn builder() -> ClientBuilder<Self> {
+        ClientBuilder::default()
+    }
+
+    // 处理器使用共享连接池的 new_with_client
+    #[allow(dead_code)]
     pub fn new(api_token: String) -> Self {
-        Self {
-            client: Client::new(),
-            api_token,
-            base_url: ANTHROPIC_API_URL.to_string(),
-        }
+        Self::from_parts(Self::builder().api_token(api_token).into_parts(ANTHROPIC_API_URL))
     }
 
+    #[allow(dead_code)]
     pub fn new_with_base_url(api_token: String, base_url: String) -> Self {
+        Self::from_parts(Self::builder().api_token(api_token).base_url(base_url).into_parts(ANTHROPIC_API_URL))
+    }
+
+    /// Creates a client for `base_url` that sends its requests through `client`'s connection pool.
+    pub fn new_with_client(api_token: String, base_url: String, client: reqwest::Client) -> Self {
+        Self::from_parts(Self::builder().api_token(api_token).base_url(base_url).client(client).into_parts(ANTHROPIC_API_URL))
+    }
+
+    fn from_parts(parts: ClientParts) -> Self {
         Self {
-            client: Client::new(),
-            api_token,
-            base_url,
+            client: parts.client,
+            api_token: parts.api_token,
+            base_url: endpoints::normalized(&parts.base_url, super::ANTHROPIC_MESSAGES_PATH),
+            default_headers: parts.default_headers,
+            idle_timeout: None,
+            parse_strictness: ParseStrictness::default(),
+            dropped_frames: Arc::new(AtomicU64::new(0)),
+            rate_limits: None,
+            signer: parts.signer,
+            defaults: ProviderDefaults::default(),
+        }
+    }
+
+    /// Sets the longest gap allowed between chunks of a streaming response.
+    ///
+    /// # Arguments
+    ///
+    /// * `idle_timeout` - Maximum idle gap; `None` disables the check
+    ///
+    /// # Returns
+    ///
+    /// The client with the idle timeout applied
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how streaming responses treat frames that cannot be parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `parse_strictness` - Whether to skip, count, or abort on unparseable frames
+    ///
+    /// # Returns
+    ///
+    /// The client with the parse strictness applied
+    pub fn with_parse_strictness(mut self, parse_strictness: ParseStrictness) -> Self {
+        self.parse_strictness = parse_strictness;
+        self
+    }
+
+    /// Sets the configured body defaults, used for fields a request does not set.
+    ///
+    /// # Arguments
+    ///
+    /// * `defaults` - The `[defaults.anthropic]` section
+    ///
+    /// # Returns
+    ///
+    /// The client with the defaults applied
+    pub fn with_defaults(mut self, defaults: ProviderDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Reports the rate limit headers of every response to `rate_limits`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate_limits` - The shared registry of upstream rate limits
+    ///
+    /// # Returns
+    ///
+    /// The client with the registry applied
+    pub fn with_rate_limits(mut self, rate_limits: Arc<UpstreamRateLimits>) -> Self {
+        self.rate_limits = Some(rate_limits);
+        self
+    }
+
+    /// Signs every request with `signer` just before it is sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `signer` - The provider's signer; `None` keeps the builder's
+    ///
+    /// # Returns
+    ///
+    /// The client with the signer applied
+    pub fn with_signer(mut self, signer: Option<Arc<dyn RequestSigner>>) -> Self {
+        if signer.is_some() {
+            self.signer = signer;
         }
+        self
+    }
+
+    /// Returns how many unparseable stream frames this client has dropped under `warn`.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the model a request with this configuration will target.
+    pub(crate) fn resolve_model(&self, config: &ApiConfig) -> String {
+        config
+            .body
+            .get("model")
+            .and_then(|m| m.as_str())
+            .or(self.defaults.model.as_deref())
+            .unwrap_or(DEFAULT_MODEL)
+            .to_string()
     }
 
     /// Builds the HTTP headers required for Anthropic API requests.
@@ -145,6 +391,7 @@ impl AnthropicClient {
     /// # Arguments
     ///
     /// * `custom_headers` - Optional additional headers to include in requests
+    /// * `stream` - Whether the request streams, which picks its `Accept` header
     ///
     /// # Returns
     ///
@@ -154,8 +401,8 @@ impl AnthropicClient {
     ///
     /// Returns `ApiError::Internal` if:
     /// - The API token is invalid
-    /// - Content-Type or Anthropic-Version headers cannot be constructed
-    pub(crate) fn build_headers(&self, custom_headers: Option<&HashMap<String, String>>) -> Result<HeaderMap> {
+    /// - Content-Type, Anthropic-Version or Accept headers cannot be constructed
+    pub(crate) fn build_headers(&self, custom_headers: Option<&HashMap<String, String>>, stream: bool) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
         headers.insert(
             "x-api-key",
@@ -181,9 +428,12 @@ impl AnthropicClient {
                     message: format!("Invalid anthropic version: {}", e) 
                 })?,
         );
+        super::insert_accept(&mut headers, self.defaults.accept.as_deref(), stream)?;
 
+        // 默认请求头在前, 请求级别的 ApiConfig.headers 覆盖同名项
+        super::override_headers(&mut headers, self.default_headers.clone());
         if let Some(custom) = custom_headers {
-            headers.extend(super::build_headers(custom)?);
+            super::override_headers(&mut headers, super::build_headers(custom)?);
         }
 
         Ok(headers)
@@ -194,54 +444,58 @@ impl AnthropicClient {
     /// # Arguments
     ///
     /// * `messages` - Vector of messages to send to the model
-    /// * `system` - Optional system prompt to set context
+    /// * `system` - Optional system prompt, as text or cache-controlled blocks
     /// * `stream` - Whether to enable streaming mode
     /// * `config` - Configuration options for the request
     ///
     /// # Returns
     ///
-    /// An `AnthropicRequest` object configured with the provided parameters and defaults
+    /// * `Result<AnthropicRequest>` - The request with `config.body` merged over the defaults
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if `config.body` sets a key in
+    /// [`PROTECTED_BODY_KEYS`] or does not form a valid request.
     pub(crate) fn build_request(
         &self,
         messages: Vec<Message>,
-        system: Option<String>,
+        system: Option<SystemPrompt>,
         stream: bool,
         config: &ApiConfig,
-    ) -> AnthropicRequest {
-        let filtered_messages = messages
+    ) -> Result<AnthropicRequest> {
+        let filtered_messages: Vec<AnthropicMessage> = messages
             .into_iter()
-            .filter(|msg| msg.role != Role::System)
+            .filter(|msg| !msg.role.is_system())
             .map(|msg| AnthropicMessage {
                 role: match msg.role {
                     Role::User => "user".to_string(),
                     Role::Assistant => "assistant".to_string(),
-                    Role::System => unreachable!(),
+                    // tool 消息已在 convert_messages 中转为 tool_result 块
+                    Role::Tool => "user".to_string(),
+                    Role::System | Role::Developer => unreachable!(),
                 },
                 content: msg.content,
             })
             .collect();
 
         // Create base request with required fields
-        let default_model = serde_json::json!(DEFAULT_MODEL);
-        let model_value = config.body.get("model").unwrap_or(&default_model);
-        
-        let default_max_tokens = if let Some(model_str) = model_value.as_str() {
-            if model_str.contains("claude-3-opus") {
-                4096
-            } else {
-                8192
-            }
+        let model = self.resolve_model(config);
+        let default_max_tokens = if model.contains("claude-3-opus") {
+            4096
         } else {
             8192
         };
-        let default_max_tokens_json = serde_json::json!(default_max_tokens);
 
         let mut request_value = serde_json::json!({
             "messages": filtered_messages,
             "stream": stream,
-            "model": model_value,
-            "max_tokens": config.body.get("max_tokens").unwrap_or(&default_max_tokens_json)
+            "model": model,
+            "max_tokens": default_max_tokens
         });
+        // 配置的默认值覆盖内置默认值, 请求体中的值仍然优先
+        if let Some(body) = request_value.as_object_mut() {
+            body.extend(super::default_fields(&self.defaults, PROTECTED_BODY_KEYS));
+        }
 
         // Add system if present
         if let Some(ref sys) = system {
@@ -251,29 +505,9 @@ impl AnthropicClient {
             }
         }
 
-        // Merge additional configuration from config.body while protecting critical fields
-        if let serde_json::Value::Object(mut map) = request_value {
-            if let serde_json::Value::Object(mut body) = serde_json::to_value(&config.body).unwrap_or_default() {
-                // Remove protected fields from config body
-                body.remove("stream");
-                body.remove("messages");
-                body.remove("system");
-                
-                // Merge remaining fields from config.body
-                for (key, value) in body {
-                    map.insert(key, value);
-                }
-            }
-            request_value = serde_json::Value::Object(map);
-        }
-
-        // Convert the merged JSON value into our request structure
-        serde_json::from_value(request_value).unwrap_or_else(|_| AnthropicRequest {
-            messages: filtered_messages,
-            stream,
-            system,
-            additional_params: config.body.clone(),
-        })
+        // Merge additional configuration from config.body; protected fields are rejected
+        let request_value = super::merge_config_body(request_value, &config.body, PROTECTED_BODY_KEYS, "anthropic")?;
+        super::parse_request(request_value, "anthropic")
     }
 
     /// Sends a non-streaming chat request to the Anthropic API.
@@ -281,7 +515,7 @@ impl AnthropicClient {
     /// # Arguments
     ///
     /// * `messages` - Vector of messages for the conversation
-    /// * `system` - Optional system prompt to set context
+    /// * `system` - Optional system prompt, as text or cache-controlled blocks
     /// * `config` - Configuration options for the request
     ///
     /// # Returns
@@ -297,48 +531,108 @@ impl AnthropicClient {
     pub async fn chat(
         &self,
         messages: Vec<Message>,
-        system: Option<String>,
+        system: Option<SystemPrompt>,
         config: &ApiConfig,
     ) -> Result<AnthropicResponse> {
-        let headers = self.build_headers(Some(&config.headers))?;
-        let request = self.build_request(messages, system, false, config);
+        let mut headers = compression::accept_compressed(self.build_headers(Some(&config.headers), false)?);
+        let request = self.build_request(messages, system, false, config)?;
 
+        let body = signed_body(self.signer.as_deref(), &self.base_url, &mut headers, &request)?;
+        let call = trace::record_request("anthropic", &self.base_url, &body);
+        let started = Instant::now();
         let response = self
             .client
             .post(&self.base_url)
             .headers(headers)
-            .json(&request)
+            .body(body)
             .send()
             .await
             .map_err(|e| ApiError::AnthropicError { 
                 message: format!("Request failed: {}", e),
-                type_: "request_failed".to_string(),
+                type_: super::request_failure_type(&e).to_string(),
                 param: None,
                 code: None
             })?;
+        connection::record_call("anthropic", &self.base_url, started.elapsed());
+        observe_rate_limit(self.rate_limits.as_deref(), "anthropic", &self.base_url, response.headers());
 
+        let status = response.status().as_u16();
         if !response.status().is_success() {
-            let error = response
-                .text()
+            let response_headers = response.headers().clone();
+            let error = compression::read_text(response)
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(ApiError::AnthropicError { 
-                message: error,
-                type_: "api_error".to_string(),
-                param: None,
-                code: None
-            });
+            trace::record_response(call, status, &error);
+            return Err(status_error("anthropic", status, &response_headers, &self.api_token, error, api_error));
         }
 
-        response
-            .json::<AnthropicResponse>()
+        let upstream = super::UpstreamResponse::of(&response);
+        let body = compression::read_text(response)
             .await
+            .map_err(|e| ApiError::AnthropicError { 
+                message: format!("Failed to read response: {}", e),
+                type_: "parse_error".to_string(),
+                param: None,
+                code: None
+            })?;
+        trace::record_response(call, status, &body);
+        let mut response = serde_json::from_str::<AnthropicResponse>(&body)
             .map_err(|e| ApiError::AnthropicError { 
                 message: format!("Failed to parse response: {}", e),
                 type_: "parse_error".to_string(),
                 param: None,
                 code: None
-            })
+            })?;
+        response.upstream = upstream;
+        Ok(response)
+    }
+
+    /// Counts the input tokens of a request with Anthropic's `count_tokens` endpoint.
+    ///
+    /// The request is built as for [`AnthropicClient::chat`], keeping only the
+    /// fields the endpoint accepts.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::AnthropicError` if the call fails or the response
+    /// carries no `input_tokens`.
+    pub async fn count_tokens(
+        &self,
+        messages: Vec<Message>,
+        system: Option<SystemPrompt>,
+        config: &ApiConfig,
+    ) -> Result<u32> {
+        let headers = self.build_headers(Some(&config.headers), false)?;
+        let mut request = serde_json::to_value(self.build_request(messages, system, false, config)?).map_err(|e| ApiError::Internal {
+            message: format!("Failed to serialize request: {}", e),
+        })?;
+        if let Some(body) = request.as_object_mut() {
+            body.retain(|key, _| matches!(key.as_str(), "model" | "messages" | "system" | "tools" | "tool_choice" | "thinking"));
+        }
+        let url = format!("{}/count_tokens", self.base_url.trim_end_matches('/'));
+        let failed = |message: String| ApiError::AnthropicError {
+            message,
+            type_: "count_tokens_failed".to_string(),
+            param: None,
+            code: None,
+        };
+        let response = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| failed(format!("Request failed: {}", e)))?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.map_err(|e| failed(format!("Failed to read response: {}", e)))?;
+        if !status.is_success() {
+            return Err(failed(format!("count_tokens returned {}", status)));
+        }
+        body.get("input_tokens")
+            .and_then(|tokens| tokens.as_u64())
+            .map(|tokens| tokens as u32)
+            .ok_or_else(|| failed("count_tokens response has no input_tokens".to_string()))
     }
 
     /// Sends a streaming chat request to the Anthropic API.
@@ -348,7 +642,7 @@ impl AnthropicClient {
     /// # Arguments
     ///
     /// * `messages` - Vector of messages for the conversation
-    /// * `system` - Optional system prompt to set context
+    /// * `system` - Optional system prompt, as text or cache-controlled blocks
     /// * `config` - Configuration options for the request
     ///
     /// # Returns
@@ -364,42 +658,64 @@ impl AnthropicClient {
     pub fn chat_stream(
         &self,
         messages: Vec<Message>,
-        system: Option<String>,
+        system: Option<SystemPrompt>,
         config: &ApiConfig,
     ) -> Pin<Box<dyn Stream<Item = Result<StreamEvent>> + Send>> {
-        let headers = match self.build_headers(Some(&config.headers)) {
-            Ok(h) => h,
+        let headers = match self.build_headers(Some(&config.headers), true) {
+            Ok(h) => compression::identity(h),
             Err(e) => return Box::pin(futures::stream::once(async move { Err(e) })),
         };
 
         let request = self.build_request(messages, system, true, config);
         let client = self.client.clone();
+        let idle_timeout = self.idle_timeout;
+        let parse_strictness = self.parse_strictness;
+        let dropped_frames = self.dropped_frames.clone();
+        let rate_limits = self.rate_limits.clone();
+        let signer = self.signer.clone();
+        let api_token = self.api_token.clone();
         let base_url = self.base_url.clone();
 
         Box::pin(async_stream::try_stream! {
-            let mut stream = client
+            // 请求体构建失败时作为流的第一个错误返回
+            let request = request?;
+            let mut headers = headers;
+            let body = signed_body(signer.as_deref(), &base_url, &mut headers, &request)?;
+            let call = trace::record_request("anthropic", &base_url, &body);
+            let started = Instant::now();
+            let response = client
                 .post(&base_url)
                 .headers(headers)
-                .json(&request)
+                .body(body)
                 .send()
                 .await
                 .map_err(|e| ApiError::AnthropicError { 
                     message: format!("Request failed: {}", e),
-                    type_: "request_failed".to_string(),
+                    type_: super::request_failure_type(&e).to_string(),
                     param: None,
                     code: None
-                })?
-                .bytes_stream();
+                })?;
+            connection::record_call("anthropic", &base_url, started.elapsed());
+            observe_rate_limit(rate_limits.as_deref(), "anthropic", &base_url, response.headers());
+            let status = response.status().as_u16();
+            // 上游返回错误状态时以状态码中止流, 而不是把错误体当作数据帧解析
+            if !response.status().is_success() {
+                let response_headers = response.headers().clone();
+                let message = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+                trace::record_response(call, status, &message);
+                Err(status_error("anthropic", status, &response_headers, &api_token, message, |message| ApiError::UpstreamStatus {
+                    provider: "anthropic".to_string(),
+                    status,
+                    message,
+                }))?;
+                return;
+            }
+            trace::record_status(call, status);
+            let mut stream = response.bytes_stream();
 
             let mut data = String::new();
             
-            while let Some(chunk) = stream.next().await {
-                let chunk = chunk.map_err(|e| ApiError::AnthropicError { 
-                    message: format!("Stream error: {}", e),
-                    type_: "stream_error".to_string(),
-                    param: None,
-                    code: None
-                })?;
+            while let Some(chunk) = next_chunk(&mut stream, idle_timeout, "anthropic").await? {
                 data.push_str(&String::from_utf8_lossy(&chunk));
 
                 let mut start = 0;
@@ -409,12 +725,20 @@ impl AnthropicClient {
                     start = end + 2;
 
                     if event_data.starts_with("event: ") {
-                        let _event_line = &event_data["event: ".len()..];
                         if let Some(data_line) = event_data.lines().nth(1) {
-                            if data_line.starts_with("data: ") {
-                                let json_data = &data_line["data: ".len()..];
-                                if let Ok(event) = serde_json::from_str::<StreamEvent>(json_data) {
-                                    yield event;
+                            if let Some(json_data) = data_line.strip_prefix("data: ") {
+                                trace::record_frame(call, json_data);
+                                match serde_json::from_str::<StreamEvent>(json_data) {
+                                    // 流中的 error 事件 (如 overloaded_error) 以 Anthropic 错误类型中止流
+                                    Ok(StreamEvent::Error { error }) => {
+                                        Err(ApiError::from(error))?;
+                                    }
+                                    Ok(event) => {
+                                        yield event;
+                                    }
+                                    Err(e) => {
+                                        reject_frame(parse_strictness, "anthropic", json_data, &e, &dropped_frames)?;
+                                    }
                                 }
                             }
                         }
@@ -438,3 +762,99 @@ impl From<ContentBlock> for crate::models::response::ContentBlock {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_reasons_map_onto_openai_finish_reasons() {
+        for (stop_reason, expected) in [
+            ("end_turn", "stop"),
+            ("stop_sequence", "stop"),
+            ("max_tokens", "length"),
+            ("tool_use", "tool_calls"),
+            ("refusal", "content_filter"),
+            ("pause_turn", "stop"),
+            ("", "stop"),
+        ] {
+            assert_eq!(finish_reason(stop_reason), expected, "{}", stop_reason);
+        }
+    }
+
+    #[test]
+    fn request_headers_override_default_headers() {
+        let client = AnthropicClient::builder().api_token("key").default_header("anthropic-beta", "default").build().unwrap();
+        let custom = HashMap::from([("anthropic-beta".to_string(), "request".to_string())]);
+        let headers = client.build_headers(Some(&custom), false).unwrap();
+        assert_eq!(headers["anthropic-beta"], "request");
+        assert_eq!(headers["x-api-key"], "key");
+        assert_eq!(headers["user-agent"], crate::clients::DEFAULT_USER_AGENT);
+    }
+
+    #[test]
+    fn the_accept_header_follows_the_request_mode() {
+        let client = AnthropicClient::new("key".to_string());
+        assert_eq!(client.build_headers(None, true).unwrap()["accept"], "text/event-stream");
+        assert_eq!(client.build_headers(None, false).unwrap()["accept"], "application/json");
+
+        // 空字符串表示不发送 Accept
+        let defaults: ProviderDefaults = serde_json::from_value(serde_json::json!({"accept": ""})).unwrap();
+        let client = AnthropicClient::new("key".to_string()).with_defaults(defaults);
+        assert!(!client.build_headers(None, false).unwrap().contains_key("accept"));
+    }
+
+    #[test]
+    fn error_bodies_keep_the_provider_error_type() {
+        let body = r#"{"type": "error", "error": {"type": "permission_error", "message": "No access"}}"#;
+        let ApiError::AnthropicError { type_, message, .. } = api_error(body.to_string()) else {
+            panic!("expected an Anthropic error");
+        };
+        assert_eq!((type_.as_str(), message.as_str()), ("permission_error", "No access"));
+
+        let ApiError::AnthropicError { type_, message, .. } = api_error("<html>Bad Gateway</html>".to_string()) else {
+            panic!("expected an Anthropic error");
+        };
+        assert_eq!((type_.as_str(), message.as_str()), ("api_error", "<html>Bad Gateway</html>"));
+    }
+
+    #[test]
+    fn stream_usage_counts_cache_reads_and_writes_as_prompt_tokens() {
+        let start: Usage = serde_json::from_value(serde_json::json!({
+            "input_tokens": 25,
+            "cache_creation_input_tokens": 5,
+            "cache_read_input_tokens": 100,
+            "output_tokens": 1,
+        }))
+        .unwrap();
+        let stats = crate::models::UsageStats::from(&start);
+        assert_eq!((stats.prompt_tokens, stats.completion_tokens, stats.total_tokens, stats.cached_prompt_tokens), (130, 1, 131, 100));
+
+        // message_delta 只带输出 token
+        let delta: Usage = serde_json::from_value(serde_json::json!({"output_tokens": 42})).unwrap();
+        assert_eq!((delta.prompt_tokens(), delta.output_tokens), (0, 42));
+    }
+
+    #[test]
+    fn configured_defaults_replace_the_built_in_ones() {
+        let config = ApiConfig { headers: HashMap::new(), body: serde_json::json!({}) };
+        let built = |client: &AnthropicClient| {
+            let messages = vec![Message { role: Role::User, content: "Hi".into(), tool_calls: None, tool_call_id: None }];
+            serde_json::to_value(client.build_request(messages, None, false, &config).unwrap()).unwrap()
+        };
+
+        let request = built(&AnthropicClient::new("key".to_string()));
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64()), (Some(DEFAULT_MODEL), Some(8192)));
+
+        let opus = AnthropicClient::new("key".to_string()).with_defaults(serde_json::from_value(serde_json::json!({"model": "claude-3-opus-20240229"})).unwrap());
+        let request = built(&opus);
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64()), (Some("claude-3-opus-20240229"), Some(4096)));
+        assert!(request.get("temperature").is_none());
+
+        let configured = opus.with_defaults(serde_json::from_value(serde_json::json!({"max_tokens": 1000, "temperature": 0.3, "body": {"system": "ignored", "top_k": 5}})).unwrap());
+        let request = built(&configured);
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64(), request["temperature"].as_f64()), (Some(DEFAULT_MODEL), Some(1000), Some(0.3)));
+        assert_eq!(request["top_k"], 5);
+        assert!(request.get("system").is_none());
+    }
+}
@@ -7,15 +7,41 @@
 //!
 //! Each client handles authentication, request building, and response parsing
 //! specific to its provider's API.
+//!
+//! The target clients are compiled only with their cargo feature, `openai`
+//! or `anthropic`, both on by default. The DeepSeek client is always built:
+//! every request needs the reasoner.
 
+#[cfg(feature = "anthropic")]
 pub mod anthropic;
 pub mod deepseek;
+#[cfg(feature = "openai")]
 pub mod openai;
 
+#[cfg(feature = "anthropic")]
 pub use anthropic::AnthropicClient;
 pub use deepseek::DeepSeekClient;
+#[cfg(feature = "openai")]
 pub use openai::OpenAIClient;
 
+/// Default OpenAI chat completions endpoint.
+pub(crate) const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+
+/// Default Mistral chat completions endpoint.
+pub(crate) const MISTRAL_API_URL: &str = "https://api.mistral.ai/v1/chat/completions";
+
+/// Default Anthropic messages endpoint.
+pub(crate) const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1/messages";
+
+/// Default path of DeepSeek-compatible endpoints given without one, such as Ollama's.
+pub const DEEPSEEK_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+
+/// Default path of OpenAI-compatible endpoints given without one.
+pub const OPENAI_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+
+/// Default path of Anthropic endpoints given without one.
+pub const ANTHROPIC_MESSAGES_PATH: &str = "/v1/messages";
+
 /// Header name for configuring the DeepSeek endpoint URL
 pub const DEEPSEEK_ENDPOINT_URL_HEADER: &str = "X-DeepSeek-Endpoint-URL";
 
@@ -25,14 +51,117 @@ pub const OPENAI_ENDPOINT_URL_HEADER: &str = "X-OpenAI-Endpoint-URL";
 /// Header name for configuring the Anthropic endpoint URL
 pub const ANTHROPIC_ENDPOINT_URL_HEADER: &str = "X-Anthropic-Endpoint-URL";
 
-use crate::error::Result;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use std::collections::HashMap;
+/// Error type of a request that never reached the provider: the connection
+/// could not be established or the request timed out.
+pub const CONNECTION_FAILED_ERROR_TYPE: &str = "connection_failed";
+
+/// User-Agent sent to upstream providers unless the client sets its own.
+pub const DEFAULT_USER_AGENT: &str = concat!("deepthink/", env!("CARGO_PKG_VERSION"), "+", env!("DEEPTHINK_GIT_SHA"));
+
+use crate::{
+    config::{ParseStrictness, ProviderDefaults},
+    connection,
+    error::{ApiError, Result},
+    ratelimit::{UpstreamRateLimit, UpstreamRateLimits},
+    redact,
+    signing::RequestSigner,
+    webhooks,
+};
+use futures::{Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT},
+    Client, Method,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    sync::Arc,
+    sync::Mutex,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Longest payload excerpt quoted in a strict-mode parse error.
+const MAX_PAYLOAD_EXCERPT_CHARS: usize = 200;
+
+/// Account errors of each provider, for alerting on exhausted balances and revoked keys.
+static ACCOUNT_ERRORS: Mutex<BTreeMap<String, AccountErrorStats>> = Mutex::new(BTreeMap::new());
+
+/// Responses of one provider rejecting the account, as reported by `/metrics`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountErrorStats {
+    pub provider: String,
+    /// `402` responses: the account's balance is exhausted.
+    pub insufficient_credit: u64,
+    /// `401` responses: the API key is invalid or revoked.
+    pub unauthorized: u64,
+}
+
+/// Returns the account errors of every provider that had one.
+pub fn account_error_stats() -> Vec<AccountErrorStats> {
+    ACCOUNT_ERRORS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
+
+fn record_account_error(provider: &str, status: u16) {
+    let mut errors = ACCOUNT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    let stats = errors.entry(provider.to_string()).or_insert_with(|| AccountErrorStats {
+        provider: provider.to_string(),
+        ..Default::default()
+    });
+    match status {
+        402 => stats.insufficient_credit += 1,
+        _ => stats.unauthorized += 1,
+    }
+}
+
+/// Returns the `error.message` of an upstream error body, or the body itself.
+///
+/// DeepSeek, OpenAI and Anthropic all nest the message there, e.g.
+/// `{"error": {"message": "Insufficient Balance", ...}}`.
+fn upstream_message(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value.pointer("/error/message").and_then(|m| m.as_str()).map(str::to_string))
+        .unwrap_or_else(|| body.to_string())
+}
+
+/// Derives the URL of another OpenAI API resource from a chat completions URL.
+///
+/// For example `http://host/v1/chat/completions` with `embeddings` yields
+/// `http://host/v1/embeddings`.
+pub(crate) fn sibling_endpoint(chat_url: &str, resource: &str) -> String {
+    let base = chat_url
+        .strip_suffix("chat/completions")
+        .unwrap_or(chat_url)
+        .trim_end_matches('/');
+    format!("{}/{}", base, resource)
+}
+
+/// Headers the caller's `config.headers` may not set: reqwest derives them
+/// from the endpoint URL and the body.
+const DENIED_CUSTOM_HEADERS: &[&str] = &["host", "content-length"];
+
+/// Headers that take a list of values. A comma-separated value is sent as
+/// one header line per item, and spellings differing only in case add their
+/// items instead of replacing each other.
+const MULTI_VALUE_HEADERS: &[&str] = &["anthropic-beta", "openai-beta"];
 
 /// Converts a HashMap of string headers to a reqwest HeaderMap.
 ///
 /// This function is used internally by clients to convert user-provided
-/// header maps into the format required by reqwest.
+/// header maps into the format required by reqwest. Header names are case
+/// insensitive, so keys differing only in case name the same header; they
+/// are applied in byte order of their spelling, which makes the result
+/// independent of the map's iteration order. For a single-value header the
+/// last spelling wins, so `authorization` beats `Authorization`; the values
+/// of [`MULTI_VALUE_HEADERS`] are split on commas and all kept, without
+/// duplicates.
 ///
 /// # Arguments
 ///
@@ -48,22 +177,729 @@ use std::collections::HashMap;
 /// Returns `ApiError::BadRequest` if:
 /// - A header name contains invalid characters
 /// - A header value contains invalid characters
+/// - A header is one of [`DENIED_CUSTOM_HEADERS`]
 pub(crate) fn build_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
     let mut header_map = HeaderMap::new();
-    
-    for (key, value) in headers {
+    let mut keys: Vec<&String> = headers.keys().collect();
+    keys.sort();
+
+    for key in keys {
         let header_name = HeaderName::from_bytes(key.as_bytes())
             .map_err(|e| crate::error::ApiError::BadRequest { 
                 message: format!("Invalid header name: {}", e) 
             })?;
-            
-        let header_value = HeaderValue::from_str(value)
-            .map_err(|e| crate::error::ApiError::BadRequest { 
-                message: format!("Invalid header value: {}", e) 
-            })?;
-            
-        header_map.insert(header_name, header_value);
+        if DENIED_CUSTOM_HEADERS.contains(&header_name.as_str()) {
+            return Err(ApiError::BadRequest {
+                message: format!("Header {} cannot be set in config.headers", key),
+            });
+        }
+
+        let value = &headers[key];
+        if !MULTI_VALUE_HEADERS.contains(&header_name.as_str()) {
+            if header_map.contains_key(&header_name) {
+                tracing::warn!("config.headers sets {} in several spellings; using the value of {}", header_name, key);
+            }
+            header_map.insert(header_name, header_value(value)?);
+            continue;
+        }
+        for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let item = header_value(item)?;
+            // 不同大小写的同名头合并取值, 相同的值只发送一次
+            if !header_map.get_all(&header_name).iter().any(|existing| *existing == item) {
+                header_map.append(header_name.clone(), item);
+            }
+        }
     }
     
     Ok(header_map)
 }
+
+/// Status and headers of the upstream response a parsed body came from,
+/// returned in the `deepseek_response` and `target_response` of verbose
+/// responses.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+}
+
+impl UpstreamResponse {
+    /// Captures a response's status and headers before its body is read.
+    ///
+    /// `Set-Cookie` is left out, and headers that are not valid UTF-8 are skipped.
+    pub(crate) fn of(response: &reqwest::Response) -> Self {
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| *name != reqwest::header::SET_COOKIE)
+            .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Self {
+            status: response.status().as_u16(),
+            headers,
+        }
+    }
+}
+
+/// Parses one header value, rejecting invalid characters with a 400.
+fn header_value(value: &str) -> Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|e| crate::error::ApiError::BadRequest { 
+        message: format!("Invalid header value: {}", e) 
+    })
+}
+
+/// Applies `overrides` on top of `headers`.
+///
+/// Every header in `overrides` replaces all values `headers` had under that
+/// name, and keeps all of its own values, so a multi-value header is
+/// overridden as a whole rather than merged.
+pub(crate) fn override_headers(headers: &mut HeaderMap, overrides: HeaderMap) {
+    for name in overrides.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in overrides.iter() {
+        headers.append(name.clone(), value.clone());
+    }
+}
+
+/// Sets the `Accept` header of a request.
+///
+/// Streaming requests accept `text/event-stream` and the others
+/// `application/json`, unless the provider's defaults configure `accept`;
+/// a configured empty value sends no `Accept` header at all.
+///
+/// # Errors
+///
+/// Returns `ApiError::Internal` if the configured value is not a valid header value.
+pub(crate) fn insert_accept(headers: &mut HeaderMap, accept: Option<&str>, stream: bool) -> Result<()> {
+    let accept = accept.unwrap_or(if stream { "text/event-stream" } else { "application/json" });
+    if accept.is_empty() {
+        return Ok(());
+    }
+    headers.insert(
+        reqwest::header::ACCEPT,
+        accept.parse().map_err(|e| ApiError::Internal {
+            message: format!("Invalid accept header: {}", e),
+        })?,
+    );
+    Ok(())
+}
+
+/// Returns the body fields of a provider's configured defaults.
+///
+/// `model`, `max_tokens` and `temperature` are included when configured,
+/// alongside the extra `body` fields; `protected` keys are left out.
+pub(crate) fn default_fields(defaults: &ProviderDefaults, protected: &[&str]) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields: serde_json::Map<String, serde_json::Value> = defaults
+        .body
+        .iter()
+        .filter(|(key, _)| !protected.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    if let Some(model) = &defaults.model {
+        fields.insert("model".to_string(), serde_json::json!(model));
+    }
+    if let Some(max_tokens) = defaults.max_tokens {
+        fields.insert("max_tokens".to_string(), serde_json::json!(max_tokens));
+    }
+    if let Some(temperature) = defaults.temperature {
+        fields.insert("temperature".to_string(), serde_json::json!(temperature));
+    }
+    fields
+}
+
+/// Merges a request's `config.body` into the base request built by a client.
+///
+/// Keys in `body` override the base request's defaults, except the
+/// `protected` keys the pipeline sets itself, which `body` may not contain.
+///
+/// # Arguments
+///
+/// * `request` - The base request, a JSON object
+/// * `body` - The caller's `config.body`; `null` adds nothing
+/// * `protected` - Keys `body` may not set
+/// * `provider` - Provider name reported in errors
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` naming the offending key if `body` sets a
+/// protected key, or if `body` is not a JSON object.
+pub(crate) fn merge_config_body(
+    mut request: serde_json::Value,
+    body: &serde_json::Value,
+    protected: &[&str],
+    provider: &str,
+) -> Result<serde_json::Value> {
+    let fields = match body {
+        serde_json::Value::Null => return Ok(request),
+        serde_json::Value::Object(fields) => fields,
+        other => {
+            return Err(ApiError::BadRequest {
+                message: format!("{}_config.body must be a JSON object, got {}", provider, json_type(other)),
+            })
+        }
+    };
+    if let Some(key) = protected.iter().find(|key| fields.contains_key(**key)) {
+        return Err(ApiError::BadRequest {
+            message: format!(
+                "{}_config.body may not set '{}'; it is set by the pipeline (protected keys: {})",
+                provider,
+                key,
+                protected.join(", ")
+            ),
+        });
+    }
+    if let serde_json::Value::Object(map) = &mut request {
+        for (key, value) in fields {
+            map.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(request)
+}
+
+/// Converts a merged request body into a client's request type.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` naming the field that failed to deserialize.
+pub(crate) fn parse_request<T: serde::de::DeserializeOwned>(request: serde_json::Value, provider: &str) -> Result<T> {
+    serde_json::from_value(request).map_err(|e| ApiError::BadRequest {
+        message: format!("Invalid {}_config.body: {}", provider, e),
+    })
+}
+
+/// Classifies a failed upstream request as a connection failure or any other
+/// request failure, for the `type_` of the provider error.
+pub(crate) fn request_failure_type(error: &reqwest::Error) -> &'static str {
+    if error.is_connect() || error.is_timeout() {
+        CONNECTION_FAILED_ERROR_TYPE
+    } else {
+        "request_failed"
+    }
+}
+
+/// Reads how long to wait before retrying a rate-limited request.
+///
+/// Uses `Retry-After` in seconds if present, otherwise the reset of the
+/// exhausted rate limit window, otherwise the later of the two windows'
+/// resets. OpenAI and Groq write those like `2m59.56s` or `450ms`,
+/// Anthropic as RFC 3339 timestamps.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after = headers
+        .get("retry-after")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse::<f64>().ok());
+    if let Some(secs) = retry_after {
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+    let limit = UpstreamRateLimit::from_headers(headers)?;
+    limit.exhausted_for().or_else(|| limit.latest_reset())
+}
+
+/// Builds the error for an unsuccessful upstream response.
+///
+/// A `429` becomes `ApiError::UpstreamRateLimited` carrying the advertised
+/// retry delay, which the circuit breakers turn into a back-off. A `402`
+/// becomes `ApiError::InsufficientCredit` and a `401`
+/// `ApiError::Unauthorized`, both naming the fingerprint of `token` so the
+/// operator can tell which configured key failed; they are also counted per
+/// provider. `other` builds any other error.
+pub(crate) fn status_error(
+    provider: &str,
+    status: u16,
+    headers: &HeaderMap,
+    token: &str,
+    message: String,
+    other: impl FnOnce(String) -> ApiError,
+) -> ApiError {
+    // 原生接口的 token 头带有 Bearer 前缀, 指纹按配置中的 token 本身计算
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let token_fingerprint = (!token.is_empty()).then(|| webhooks::fingerprint(token));
+    match status {
+        402 => {
+            record_account_error(provider, status);
+            return ApiError::InsufficientCredit {
+                provider: provider.to_string(),
+                token_fingerprint,
+                message: upstream_message(&message),
+            };
+        }
+        401 => {
+            record_account_error(provider, status);
+            return ApiError::Unauthorized {
+                provider: provider.to_string(),
+                token_fingerprint,
+                message: upstream_message(&message),
+            };
+        }
+        429 => {}
+        _ => return other(message),
+    }
+    ApiError::UpstreamRateLimited {
+        provider: provider.to_string(),
+        retry_after_secs: retry_after(headers).map(|delay| delay.as_secs_f64().ceil() as u64),
+        message,
+    }
+}
+
+/// Serializes a request body and signs the request, if the client has a signer.
+///
+/// The body is serialized here rather than by reqwest so the signer sees the
+/// exact bytes that are sent; the signature headers are added to `headers`.
+///
+/// # Errors
+///
+/// Returns `ApiError::Internal` if the body cannot be serialized, and the
+/// signer's error if it fails.
+pub(crate) fn signed_body<T: Serialize>(
+    signer: Option<&dyn RequestSigner>,
+    url: &str,
+    headers: &mut HeaderMap,
+    request: &T,
+) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(request).map_err(|e| ApiError::Internal {
+        message: format!("Failed to serialize the request: {}", e),
+    })?;
+    headers
+        .entry(CONTENT_TYPE)
+        .or_insert(HeaderValue::from_static("application/json"));
+    if let Some(signer) = signer {
+        for (name, value) in signer.sign(&Method::POST, url, headers, &body)? {
+            headers.insert(name, value);
+        }
+    }
+    Ok(body)
+}
+
+/// Hands a response's rate limit headers to the shared registry, if the client has one.
+pub(crate) fn observe_rate_limit(
+    rate_limits: Option<&UpstreamRateLimits>,
+    provider: &str,
+    base_url: &str,
+    headers: &HeaderMap,
+) {
+    if let Some(rate_limits) = rate_limits {
+        rate_limits.observe(provider, base_url, headers);
+    }
+}
+
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// Builder for the provider clients.
+///
+/// Obtained from `DeepSeekClient::builder()`, `OpenAIClient::builder()` or
+/// `AnthropicClient::builder()`. Default headers are sent with every request
+/// beneath the per-request `ApiConfig` headers, and a `User-Agent` of
+/// [`DEFAULT_USER_AGENT`] is added unless one is set.
+#[derive(Debug)]
+pub struct ClientBuilder<C> {
+    api_token: String,
+    base_url: Option<String>,
+    client: Option<Client>,
+    default_headers: HeaderMap,
+    signer: Option<Arc<dyn RequestSigner>>,
+    error: Option<ApiError>,
+    _client: PhantomData<fn() -> C>,
+}
+
+/// Settings resolved from a [`ClientBuilder`], shared by every client type.
+pub(crate) struct ClientParts {
+    pub(crate) client: Client,
+    pub(crate) api_token: String,
+    pub(crate) base_url: String,
+    pub(crate) default_headers: HeaderMap,
+    pub(crate) signer: Option<Arc<dyn RequestSigner>>,
+}
+
+impl<C> Default for ClientBuilder<C> {
+    fn default() -> Self {
+        Self {
+            api_token: String::new(),
+            base_url: None,
+            client: None,
+            default_headers: HeaderMap::new(),
+            signer: None,
+            error: None,
+            _client: PhantomData,
+        }
+    }
+}
+
+// 构建器的部分方法只供嵌入本 crate 的调用方使用
+#[allow(dead_code)]
+impl<C> ClientBuilder<C> {
+    /// Sets the token used to authenticate with the provider.
+    pub fn api_token(mut self, api_token: impl Into<String>) -> Self {
+        self.api_token = api_token.into();
+        self
+    }
+
+    /// Sets the endpoint URL; defaults to the provider's public API.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Sends requests through `client`, e.g. one configured with a proxy or custom TLS.
+    ///
+    /// Without one a client from [`connection::client`] is used, whose DNS lookups are timed.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Adds a header sent with every request unless the request sets it itself.
+    ///
+    /// An invalid name or value is reported by `build`.
+    pub fn default_header(mut self, name: &str, value: &str) -> Self {
+        let header = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| format!("Invalid default header name {}: {}", name, e))
+            .and_then(|header_name| {
+                HeaderValue::from_str(value)
+                    .map(|value| (header_name, value))
+                    .map_err(|e| format!("Invalid default header value for {}: {}", name, e))
+            });
+        match header {
+            Ok((name, value)) => {
+                self.default_headers.insert(name, value);
+            }
+            Err(message) => {
+                self.error.get_or_insert(ApiError::BadRequest { message });
+            }
+        }
+        self
+    }
+
+    /// Signs every request with `signer` just before it is sent, e.g. for a gateway in front of the provider.
+    pub fn signer(mut self, signer: Arc<dyn RequestSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Sets the `User-Agent` header; defaults to [`DEFAULT_USER_AGENT`].
+    pub fn user_agent(self, user_agent: &str) -> Self {
+        self.default_header(USER_AGENT.as_str(), user_agent)
+    }
+
+    /// Checks the builder and resolves its settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if a default header was invalid.
+    pub(crate) fn try_into_parts(self, default_base_url: &str) -> Result<ClientParts> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        Ok(self.into_parts(default_base_url))
+    }
+
+    /// Resolves the builder's settings, skipping any invalid default header.
+    pub(crate) fn into_parts(self, default_base_url: &str) -> ClientParts {
+        let mut default_headers = self.default_headers;
+        default_headers
+            .entry(USER_AGENT)
+            .or_insert(HeaderValue::from_static(DEFAULT_USER_AGENT));
+        ClientParts {
+            client: self.client.unwrap_or_else(connection::client),
+            api_token: self.api_token,
+            base_url: self.base_url.unwrap_or_else(|| default_base_url.to_string()),
+            default_headers,
+            signer: self.signer,
+        }
+    }
+}
+
+/// Reads the next chunk of an upstream response stream, bounded by an idle timeout.
+///
+/// # Arguments
+///
+/// * `stream` - The upstream byte stream
+/// * `idle_timeout` - Longest gap allowed between chunks; `None` waits forever
+/// * `provider` - Provider name reported in the error
+///
+/// # Returns
+///
+/// * `Result<Option<T>>` - The next chunk, or `None` once the stream has ended
+///
+/// # Errors
+///
+/// Returns `ApiError::StreamIdle` if no chunk arrives within `idle_timeout`,
+/// and `ApiError::StreamAborted` if the connection fails mid-stream.
+pub(crate) async fn next_chunk<S, T, E>(
+    stream: &mut S,
+    idle_timeout: Option<Duration>,
+    provider: &str,
+) -> Result<Option<T>>
+where
+    S: Stream<Item = std::result::Result<T, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let next = match idle_timeout {
+        Some(idle_timeout) => tokio::time::timeout(idle_timeout, stream.next())
+            .await
+            .map_err(|_| ApiError::StreamIdle {
+                provider: provider.to_string(),
+                idle_secs: idle_timeout.as_secs(),
+            })?,
+        None => stream.next().await,
+    };
+
+    next.transpose().map_err(|e| ApiError::StreamAborted {
+        provider: provider.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Handles an upstream stream frame that could not be parsed.
+///
+/// # Arguments
+///
+/// * `strictness` - The provider's configured parse strictness
+/// * `provider` - Provider name reported in logs and errors
+/// * `payload` - The raw frame payload
+/// * `error` - Why the payload failed to parse
+/// * `dropped_frames` - Counter incremented when the frame is skipped under `warn`
+///
+/// # Errors
+///
+/// Returns `ApiError::StreamAborted` naming a truncated excerpt of the payload
+/// when `strictness` is `strict`.
+pub(crate) fn reject_frame(
+    strictness: ParseStrictness,
+    provider: &str,
+    payload: &str,
+    error: &serde_json::Error,
+    dropped_frames: &AtomicU64,
+) -> Result<()> {
+    match strictness {
+        ParseStrictness::Lenient => {
+            tracing::debug!("Skipping unparseable {} frame: {}", provider, redact::json(payload));
+            Ok(())
+        }
+        ParseStrictness::Warn => {
+            dropped_frames.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Dropped unparseable {} frame ({}): {}", provider, error, redact::json(payload));
+            Ok(())
+        }
+        ParseStrictness::Strict => {
+            let mut excerpt: String = payload.chars().take(MAX_PAYLOAD_EXCERPT_CHARS).collect();
+            if excerpt.len() < payload.len() {
+                excerpt.push_str("...");
+            }
+            Err(ApiError::StreamAborted {
+                provider: provider.to_string(),
+                reason: format!("unparseable frame ({}): {}", error, excerpt),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn parse_error() -> serde_json::Error {
+        serde_json::from_str::<serde_json::Value>("{").unwrap_err()
+    }
+
+    #[test]
+    fn unparseable_frames_are_skipped_unless_strict() {
+        let dropped = AtomicU64::new(0);
+        assert!(reject_frame(ParseStrictness::Lenient, "openai", "{", &parse_error(), &dropped).is_ok());
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        assert!(reject_frame(ParseStrictness::Warn, "openai", "{", &parse_error(), &dropped).is_ok());
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn strict_parsers_abort_with_a_truncated_excerpt() {
+        let dropped = AtomicU64::new(0);
+        let payload = "x".repeat(MAX_PAYLOAD_EXCERPT_CHARS + 10);
+        let error = reject_frame(ParseStrictness::Strict, "deepseek", &payload, &parse_error(), &dropped).unwrap_err();
+        let ApiError::StreamAborted { provider, reason } = error else {
+            panic!("expected a stream abort, got {:?}", error);
+        };
+        assert_eq!(provider, "deepseek");
+        let excerpt = format!("{}...", "x".repeat(MAX_PAYLOAD_EXCERPT_CHARS));
+        assert!(reason.starts_with("unparseable frame (") && reason.ends_with(&format!("): {}", excerpt)), "{}", reason);
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_streams_time_out_as_idle() {
+        let mut stalled = stream::pending::<std::result::Result<u8, String>>();
+        let error = next_chunk(&mut stalled, Some(Duration::from_secs(30)), "openai").await.unwrap_err();
+        assert!(matches!(error, ApiError::StreamIdle { ref provider, idle_secs: 30 } if provider == "openai"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_without_an_idle_timeout_are_read_to_the_end() {
+        let mut chunks = stream::iter([Ok::<_, String>(1), Ok(2)]);
+        assert_eq!(next_chunk(&mut chunks, None, "openai").await.unwrap(), Some(1));
+        assert_eq!(next_chunk(&mut chunks, None, "openai").await.unwrap(), Some(2));
+        assert_eq!(next_chunk(&mut chunks, None, "openai").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn connection_failures_abort_the_stream() {
+        let mut broken = stream::iter([Err::<u8, _>("connection reset")]);
+        let error = next_chunk(&mut broken, Some(Duration::from_secs(30)), "anthropic").await.unwrap_err();
+        assert!(matches!(error, ApiError::StreamAborted { ref provider, ref reason }
+            if provider == "anthropic" && reason == "connection reset"));
+    }
+
+    #[test]
+    fn config_bodies_override_the_base_request() {
+        let merged = merge_config_body(
+            serde_json::json!({"model": "default", "stream": true}),
+            &serde_json::json!({"model": "custom", "top_p": 0.5}),
+            &["messages", "stream"],
+            "openai",
+        )
+        .unwrap();
+        assert_eq!(merged, serde_json::json!({"model": "custom", "stream": true, "top_p": 0.5}));
+        let base = serde_json::json!({"model": "default"});
+        assert_eq!(merge_config_body(base.clone(), &serde_json::Value::Null, &[], "openai").unwrap(), base);
+    }
+
+    #[test]
+    fn config_bodies_may_not_set_protected_keys() {
+        let error = merge_config_body(serde_json::json!({}), &serde_json::json!({"stream": false}), &["messages", "stream"], "deepseek")
+            .unwrap_err();
+        let ApiError::BadRequest { message } = error else {
+            panic!("expected a bad request, got {:?}", error);
+        };
+        assert_eq!(
+            message,
+            "deepseek_config.body may not set 'stream'; it is set by the pipeline (protected keys: messages, stream)"
+        );
+    }
+
+    #[test]
+    fn config_bodies_must_be_objects() {
+        let error = merge_config_body(serde_json::json!({}), &serde_json::json!(["oops"]), &[], "openai").unwrap_err();
+        assert!(matches!(error, ApiError::BadRequest { ref message }
+            if message == "openai_config.body must be a JSON object, got an array"));
+    }
+
+    #[test]
+    fn retry_delays_are_read_from_retry_after_or_the_reset_headers() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        assert_eq!(retry_after(&headers(&[("retry-after", "7")])), Some(Duration::from_secs(7)));
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "2"), ("x-ratelimit-reset-tokens", "1m")])),
+            Some(Duration::from_secs(2))
+        );
+        // 两个 reset 头取较晚者
+        let reset = headers(&[("x-ratelimit-reset-requests", "2m59.5s"), ("x-ratelimit-reset-tokens", "450ms")]);
+        assert_eq!(retry_after(&reset), Some(Duration::from_secs_f64(179.5)));
+        assert_eq!(retry_after(&headers(&[("x-ratelimit-reset-tokens", "1h2m3s")])), Some(Duration::from_secs(3723)));
+        assert_eq!(retry_after(&headers(&[("x-ratelimit-reset-tokens", "soon")])), None);
+        assert_eq!(retry_after(&headers(&[("x-ratelimit-reset-tokens", "")])), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    fn custom(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn spellings_of_one_header_resolve_in_byte_order() {
+        // 无论 HashMap 的迭代顺序如何, 小写拼写总是最后应用
+        for _ in 0..16 {
+            let headers = build_headers(&custom(&[("Authorization", "Bearer upper"), ("authorization", "Bearer lower")])).unwrap();
+            let values: Vec<_> = headers.get_all("authorization").iter().collect();
+            assert_eq!(values, ["Bearer lower"]);
+        }
+    }
+
+    #[test]
+    fn multi_value_headers_merge_their_items_across_spellings() {
+        let headers = build_headers(&custom(&[
+            ("anthropic-beta", "tools-2024, prompt-caching"),
+            ("Anthropic-Beta", "prompt-caching,,output-128k"),
+        ]))
+        .unwrap();
+        let values: Vec<_> = headers.get_all("anthropic-beta").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(values, ["prompt-caching", "output-128k", "tools-2024"]);
+    }
+
+    #[test]
+    fn host_and_content_length_cannot_be_set() {
+        for name in ["Host", "content-length"] {
+            let error = build_headers(&custom(&[(name, "1")])).unwrap_err();
+            assert_eq!(
+                error.to_error_response().1.error.message,
+                format!("Header {} cannot be set in config.headers", name)
+            );
+        }
+    }
+
+    #[test]
+    fn overrides_replace_every_value_of_a_header() {
+        let mut headers = build_headers(&custom(&[("anthropic-beta", "a,b"), ("x-kept", "1")])).unwrap();
+        override_headers(&mut headers, build_headers(&custom(&[("Anthropic-Beta", "c")])).unwrap());
+        let values: Vec<_> = headers.get_all("anthropic-beta").iter().collect();
+        assert_eq!(values, ["c"]);
+        assert_eq!(headers["x-kept"], "1");
+    }
+
+    /// DeepSeek's answer to a request from an account without balance.
+    const INSUFFICIENT_BALANCE: &str =
+        r#"{"error":{"message":"Insufficient Balance","type":"unknown_error","param":null,"code":"invalid_request_error"}}"#;
+    /// DeepSeek's answer to a request with a revoked API key.
+    const INVALID_KEY: &str = r#"{"error":{"message":"Authentication Fails, Your api key: ****1234 is invalid","type":"authentication_error","param":null,"code":"invalid_request_error"}}"#;
+
+    #[test]
+    fn account_errors_name_the_provider_and_the_token_fingerprint() {
+        let other = |message| ApiError::Internal { message };
+        let error = status_error("deepseek", 402, &HeaderMap::new(), "sk-live", INSUFFICIENT_BALANCE.to_string(), other);
+        let ApiError::InsufficientCredit { provider, token_fingerprint, message } = error else {
+            panic!("{:?}", error);
+        };
+        assert_eq!(provider, "deepseek");
+        assert_eq!(token_fingerprint, Some(webhooks::fingerprint("sk-live")));
+        let error = status_error("deepseek", 402, &HeaderMap::new(), "Bearer sk-live", INSUFFICIENT_BALANCE.to_string(), other);
+        assert!(matches!(error, ApiError::InsufficientCredit { token_fingerprint: Some(ref f), .. } if *f == webhooks::fingerprint("sk-live")));
+        assert_eq!(message, "Insufficient Balance");
+
+        let error = status_error("deepseek", 401, &HeaderMap::new(), "", INVALID_KEY.to_string(), other);
+        assert!(
+            matches!(error, ApiError::Unauthorized { ref message, token_fingerprint: None, .. } if message == "Authentication Fails, Your api key: ****1234 is invalid"),
+            "{:?}",
+            error
+        );
+
+        // 非 JSON 的错误体原样保留; 其他状态码仍由调用方构造错误
+        let error = status_error("openai", 402, &HeaderMap::new(), "sk-live", "Payment Required".to_string(), other);
+        assert!(matches!(error, ApiError::InsufficientCredit { ref message, .. } if message == "Payment Required"), "{:?}", error);
+        let error = status_error("openai", 500, &HeaderMap::new(), "sk-live", INSUFFICIENT_BALANCE.to_string(), other);
+        assert!(matches!(error, ApiError::Internal { .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn account_errors_are_counted_per_provider() {
+        // 计数是进程级的, 使用其他测试不会用到的 provider 名
+        for status in [402, 402, 401] {
+            status_error("counted", status, &HeaderMap::new(), "", String::new(), |message| ApiError::Internal { message });
+        }
+        let stats = account_error_stats().into_iter().find(|stats| stats.provider == "counted").unwrap();
+        assert_eq!((stats.insufficient_credit, stats.unauthorized), (2, 1));
+    }
+}
@@ -1,16 +1,107 @@
 use crate::{
+    clients::{next_chunk, observe_rate_limit, reject_frame, status_error, signed_body, ClientBuilder, ClientParts, OPENAI_API_URL},
+    compression,
+    config::{ParseStrictness, ProviderDefaults},
+    connection,
+    endpoints,
     error::{ApiError, Result},
-    models::{ApiConfig, Message},
+    models::{ApiConfig, Message, OpenAIDialect, Role, ToolCall},
+    ratelimit::UpstreamRateLimits,
+    redact::{self, Loggable},
+    signing::RequestSigner,
+    trace,
 };
 use futures::Stream;
 use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, pin::Pin};
-use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use serde_json;
 
-pub(crate) const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+/// Built-in request defaults, used when `[defaults.openai]` does not set them.
 const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+const DEFAULT_TEMPERATURE: f64 = 1.0;
+
+/// Request keys set by the pipeline that `config.body` may not override.
+/// `model` is not protected: the body is where callers choose the model.
+pub(crate) const PROTECTED_BODY_KEYS: &[&str] = &["messages", "stream"];
+
+/// Request keys the Mistral API rejects; `n` is only rejected above 1.
+const MISTRAL_UNSUPPORTED_KEYS: &[&str] = &["logprobs", "top_logprobs", "logit_bias"];
+
+/// Length of the alphanumeric tool call ids the Mistral API accepts.
+const MISTRAL_TOOL_CALL_ID_LEN: usize = 9;
+
+/// Returns true for OpenAI's o-series reasoning models (`o1`, `o3-mini`, ...),
+/// which take the system prompt as a `developer` message.
+fn is_o_series(model: &str) -> bool {
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// Rejects request parameters the dialect's API does not support.
+///
+/// Called for each target request, and by the handlers before the reasoner
+/// runs, so an unsupported parameter fails fast instead of after reasoning.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` naming the first unsupported parameter.
+pub(crate) fn check_dialect_params(dialect: OpenAIDialect, body: &serde_json::Value) -> Result<()> {
+    if dialect != OpenAIDialect::Mistral {
+        return Ok(());
+    }
+    if let Some(n) = body.get("n").filter(|n| n.as_u64() != Some(1)) {
+        return Err(ApiError::BadRequest {
+            message: format!("Mistral targets return a single choice and do not support n = {}", n),
+        });
+    }
+    match MISTRAL_UNSUPPORTED_KEYS.iter().find(|key| body.get(**key).is_some_and(|v| !v.is_null())) {
+        Some(key) => Err(ApiError::BadRequest {
+            message: format!("Mistral targets do not support the '{}' parameter", key),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Rewrites tool call ids to the 9-character alphanumeric form Mistral requires.
+///
+/// Ids already in that form are kept. Others are hashed, so an assistant's
+/// `tool_calls` and the `tool` messages answering them still pair up.
+fn normalize_tool_call_ids(messages: &mut [Message]) {
+    for message in messages.iter_mut() {
+        for call in message.tool_calls.iter_mut().flatten() {
+            call.id = mistral_tool_call_id(&call.id);
+        }
+        if let Some(id) = &message.tool_call_id {
+            message.tool_call_id = Some(mistral_tool_call_id(id));
+        }
+    }
+}
+
+fn mistral_tool_call_id(id: &str) -> String {
+    if id.len() == MISTRAL_TOOL_CALL_ID_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return id.to_string();
+    }
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    // FNV-1a: 同一个 id 在请求内外总是映射为同一个结果
+    let mut hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x100000001b3));
+    (0..MISTRAL_TOOL_CALL_ID_LEN)
+        .map(|_| {
+            let c = ALPHABET[(hash % ALPHABET.len() as u64) as usize] as char;
+            hash /= ALPHABET.len() as u64;
+            c
+        })
+        .collect()
+}
 
 /// Client for interacting with OpenAI-compatible API models.
 ///
@@ -21,6 +112,14 @@ pub struct OpenAIClient {
     pub(crate) client: Client,
     api_token: String,
     base_url: String,
+    default_headers: HeaderMap,
+    idle_timeout: Option<Duration>,
+    parse_strictness: ParseStrictness,
+    dropped_frames: Arc<AtomicU64>,
+    rate_limits: Option<Arc<UpstreamRateLimits>>,
+    signer: Option<Arc<dyn RequestSigner>>,
+    dialect: OpenAIDialect,
+    defaults: ProviderDefaults,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -31,12 +130,17 @@ pub struct OpenAIResponse {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
+    /// Status and headers of the response this body came from.
+    #[serde(skip)]
+    pub upstream: super::UpstreamResponse,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Choice {
     pub index: i32,
     pub message: AssistantMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
     pub finish_reason: Option<String>,
 }
 
@@ -44,15 +148,48 @@ pub struct Choice {
 pub struct AssistantMessage {
     pub role: String,
     pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StreamChoice {
     pub index: i32,
     pub delta: StreamDelta,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
     pub finish_reason: Option<String>,
 }
 
+/// Log probability information for a choice's output tokens.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Logprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<Vec<TokenLogprob>>,
+}
+
+/// Log probability of a single output token and its most likely alternatives.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// One alternative token considered at a position.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StreamDelta {
     pub role: Option<String>,
@@ -74,6 +211,32 @@ pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+/// Breakdown of `completion_tokens`; only the predicted output counts are read.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CompletionTokensDetails {
+    /// Tokens of the `prediction` that appeared in the completion.
+    #[serde(default)]
+    pub accepted_prediction_tokens: u32,
+    /// Tokens of the `prediction` that did not; billed as completion tokens.
+    #[serde(default)]
+    pub rejected_prediction_tokens: u32,
+}
+
+/// Converts OpenAI usage into the shared usage type, keeping the predicted
+/// output counts.
+impl From<&Usage> for crate::models::UsageStats {
+    fn from(usage: &Usage) -> Self {
+        let mut stats = Self::default();
+        stats.add(usage.prompt_tokens, usage.completion_tokens);
+        if let Some(details) = &usage.completion_tokens_details {
+            stats.add_prediction(details.accepted_prediction_tokens, details.rejected_prediction_tokens);
+        }
+        stats
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -84,33 +247,157 @@ pub(crate) struct OpenAIRequest {
     additional_params: serde_json::Value,
 }
 
+#[allow(dead_code)]
+impl ClientBuilder<OpenAIClient> {
+    /// Builds the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if a default header was invalid.
+    pub fn build(self) -> Result<OpenAIClient> {
+        self.try_into_parts(OPENAI_API_URL).map(OpenAIClient::from_parts)
+    }
+}
+
 impl OpenAIClient {
+    /// Returns a builder for a client with a custom HTTP client, default headers or user agent.
+    pub fn builder() -> ClientBuilder<Self> {
+        ClientBuilder::default()
+    }
+
+    // 处理器使用共享连接池的 new_with_client
+    #[allow(dead_code)]
     pub fn new(api_token: String) -> Self {
-        Self {
-            client: Client::new(),
-            api_token,
-            base_url: OPENAI_API_URL.to_string(),
-        }
+        Self::from_parts(Self::builder().api_token(api_token).into_parts(OPENAI_API_URL))
     }
 
+    #[allow(dead_code)]
     pub fn new_with_base_url(api_token: String, base_url: String) -> Self {
+        Self::from_parts(Self::builder().api_token(api_token).base_url(base_url).into_parts(OPENAI_API_URL))
+    }
+
+    /// Creates a client for `base_url` that sends its requests through `client`'s connection pool.
+    pub fn new_with_client(api_token: String, base_url: String, client: reqwest::Client) -> Self {
+        Self::from_parts(Self::builder().api_token(api_token).base_url(base_url).client(client).into_parts(OPENAI_API_URL))
+    }
+
+    fn from_parts(parts: ClientParts) -> Self {
         Self {
-            client: Client::new(),
-            api_token,
-            base_url,
+            client: parts.client,
+            api_token: parts.api_token,
+            base_url: endpoints::normalized(&parts.base_url, super::OPENAI_COMPLETIONS_PATH),
+            default_headers: parts.default_headers,
+            idle_timeout: None,
+            parse_strictness: ParseStrictness::default(),
+            dropped_frames: Arc::new(AtomicU64::new(0)),
+            rate_limits: None,
+            signer: parts.signer,
+            dialect: OpenAIDialect::default(),
+            defaults: ProviderDefaults::default(),
+        }
+    }
+
+    /// Sets the longest gap allowed between chunks of a streaming response.
+    ///
+    /// # Arguments
+    ///
+    /// * `idle_timeout` - Maximum idle gap; `None` disables the check
+    ///
+    /// # Returns
+    ///
+    /// The client with the idle timeout applied
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how streaming responses treat frames that cannot be parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `parse_strictness` - Whether to skip, count, or abort on unparseable frames
+    ///
+    /// # Returns
+    ///
+    /// The client with the parse strictness applied
+    pub fn with_parse_strictness(mut self, parse_strictness: ParseStrictness) -> Self {
+        self.parse_strictness = parse_strictness;
+        self
+    }
+
+    /// Sets the API dialect the target speaks.
+    ///
+    /// # Arguments
+    ///
+    /// * `dialect` - The variant of the chat completions API to build requests for
+    ///
+    /// # Returns
+    ///
+    /// The client with the dialect applied
+    pub fn with_dialect(mut self, dialect: OpenAIDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Sets the configured body defaults, used for fields a request does not set.
+    ///
+    /// # Arguments
+    ///
+    /// * `defaults` - The `[defaults.openai]` section
+    ///
+    /// # Returns
+    ///
+    /// The client with the defaults applied
+    pub fn with_defaults(mut self, defaults: ProviderDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Reports the rate limit headers of every response to `rate_limits`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate_limits` - The shared registry of upstream rate limits
+    ///
+    /// # Returns
+    ///
+    /// The client with the registry applied
+    pub fn with_rate_limits(mut self, rate_limits: Arc<UpstreamRateLimits>) -> Self {
+        self.rate_limits = Some(rate_limits);
+        self
+    }
+
+    /// Signs every request with `signer` just before it is sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `signer` - The provider's signer; `None` keeps the builder's
+    ///
+    /// # Returns
+    ///
+    /// The client with the signer applied
+    pub fn with_signer(mut self, signer: Option<Arc<dyn RequestSigner>>) -> Self {
+        if signer.is_some() {
+            self.signer = signer;
         }
+        self
+    }
+
+    /// Returns how many unparseable stream frames this client has dropped under `warn`.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
     }
 
     pub(crate) fn get_base_url(&self, custom_headers: Option<&HashMap<String, String>>) -> String {
         if let Some(headers) = custom_headers {
             if let Some(endpoint_url) = headers.get(super::OPENAI_ENDPOINT_URL_HEADER) {
-                return endpoint_url.clone();
+                return endpoints::normalized(endpoint_url, super::OPENAI_COMPLETIONS_PATH);
             }
         }
         self.base_url.clone()
     }
 
-    pub(crate) fn build_headers(&self, custom_headers: Option<&HashMap<String, String>>) -> Result<HeaderMap> {
+    pub(crate) fn build_headers(&self, custom_headers: Option<&HashMap<String, String>>, stream: bool) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
         headers.insert(
             "Authorization",
@@ -128,48 +415,69 @@ impl OpenAIClient {
                     message: format!("Invalid content type: {}", e) 
                 })?,
         );
-        headers.insert(
-            "Accept",
-            "application/json"
-                .parse()
-                .map_err(|e| ApiError::Internal { 
-                    message: format!("Invalid accept header: {}", e) 
-                })?,
-        );
+        super::insert_accept(&mut headers, self.defaults.accept.as_deref(), stream)?;
 
+        // 默认请求头在前, 请求级别的 ApiConfig.headers 覆盖同名项
+        super::override_headers(&mut headers, self.default_headers.clone());
         if let Some(custom) = custom_headers {
-            headers.extend(super::build_headers(custom)?);
+            super::override_headers(&mut headers, super::build_headers(custom)?);
         }
 
         Ok(headers)
     }
 
-    pub(crate) fn build_request(&self, messages: Vec<Message>, stream: bool, config: &ApiConfig) -> OpenAIRequest {
+    /// Constructs a request object for the OpenAI API.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if `config.body` sets a key in
+    /// [`PROTECTED_BODY_KEYS`], sets a parameter the client's dialect does
+    /// not support, or does not form a valid request.
+    pub(crate) fn build_request(&self, messages: Vec<Message>, stream: bool, config: &ApiConfig) -> Result<OpenAIRequest> {
+        let model = config
+            .body
+            .get("model")
+            .cloned()
+            .unwrap_or_else(|| serde_json::json!(self.defaults.model.as_deref().unwrap_or(DEFAULT_MODEL)));
+        // o 系列模型使用 developer 角色, 其余模型使用 system
+        let system_role = match model.as_str() {
+            Some(model) if self.dialect == OpenAIDialect::OpenAI && is_o_series(model) => Role::Developer,
+            _ => Role::System,
+        };
+        let mut messages: Vec<Message> = messages
+            .into_iter()
+            .map(|msg| {
+                if msg.role.is_system() {
+                    Message { role: system_role.clone(), ..msg }
+                } else {
+                    msg
+                }
+            })
+            .collect();
+        check_dialect_params(self.dialect, &config.body)?;
+        if self.dialect == OpenAIDialect::Mistral {
+            normalize_tool_call_ids(&mut messages);
+        }
         let mut request_value = serde_json::json!({
             "messages": messages,
             "stream": stream,
-            "model": config.body.get("model").unwrap_or(&serde_json::json!(DEFAULT_MODEL)),
-            "max_tokens": config.body.get("max_tokens").unwrap_or(&serde_json::json!(4096)),
-            "temperature": config.body.get("temperature").unwrap_or(&serde_json::json!(1.0)),
+            "max_tokens": DEFAULT_MAX_TOKENS,
+            "temperature": DEFAULT_TEMPERATURE,
         });
+        // 配置的默认值覆盖内置默认值, 请求体中的值仍然优先
+        if let Some(body) = request_value.as_object_mut() {
+            body.extend(super::default_fields(&self.defaults, PROTECTED_BODY_KEYS));
+            body.insert("model".to_string(), model);
+        }
 
-        if let serde_json::Value::Object(mut map) = request_value {
-            if let serde_json::Value::Object(mut body) = serde_json::to_value(&config.body).unwrap_or_default() {
-                body.remove("stream");
-                body.remove("messages");
-                
-                for (key, value) in body {
-                    map.insert(key, value);
-                }
+        let mut request_value = super::merge_config_body(request_value, &config.body, PROTECTED_BODY_KEYS, "openai")?;
+        // safe_prompt 是 Mistral 的扩展参数, OpenAI 会拒绝未知参数
+        if self.dialect != OpenAIDialect::Mistral {
+            if let Some(body) = request_value.as_object_mut() {
+                body.remove("safe_prompt");
             }
-            request_value = serde_json::Value::Object(map);
         }
-
-        serde_json::from_value(request_value).unwrap_or_else(|_| OpenAIRequest {
-            messages,
-            stream,
-            additional_params: config.body.clone(),
-        })
+        super::parse_request(request_value, "openai")
     }
 
     pub async fn chat(
@@ -178,8 +486,8 @@ impl OpenAIClient {
         config: &ApiConfig,
     ) -> Result<OpenAIResponse> {
         tracing::info!("Building headers");
-        let headers = self.build_headers(Some(&config.headers))?;
-        let request = self.build_request(messages, false, config);
+        let mut headers = compression::accept_compressed(self.build_headers(Some(&config.headers), false)?);
+        let request = self.build_request(messages, false, config)?;
         let base_url = self.get_base_url(Some(&config.headers));
 
 
@@ -187,46 +495,63 @@ impl OpenAIClient {
         tracing::info!("OpenAI Request Debug Info:");
         tracing::info!("URL: {}", base_url);
         tracing::info!("Headers: {:#?}", headers);
-        tracing::info!("Body: {}", serde_json::to_string_pretty(&request).unwrap_or_default());
+        tracing::info!("Body: {:#?}", Loggable(&request));
 
         
+        let body = signed_body(self.signer.as_deref(), &base_url, &mut headers, &request)?;
+        let call = trace::record_request("openai", &base_url, &body);
+        let started = Instant::now();
         let response = self
             .client
             .post(&base_url)
             .headers(headers)
-            .json(&request)
+            .body(body)
             .send()
             .await
             .map_err(|e| ApiError::OpenAIError { 
                 message: format!("Request failed: {}", e),
-                type_: "request_failed".to_string(),
+                type_: super::request_failure_type(&e).to_string(),
                 param: None,
                 code: None
             })?;
+        connection::record_call("openai", &base_url, started.elapsed());
+        observe_rate_limit(self.rate_limits.as_deref(), "openai", &base_url, response.headers());
 
+        let status = response.status().as_u16();
         if !response.status().is_success() {
-            let error = response
-                .text()
+            let response_headers = response.headers().clone();
+            let error = compression::read_text(response)
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            tracing::error!("OpenAI API error response: {}", error); // 添加错误日志
-            return Err(ApiError::OpenAIError { 
-                message: error,
+            trace::record_response(call, status, &error);
+            tracing::error!("OpenAI API error response: {}", redact::json(&error)); // 添加错误日志
+            return Err(status_error("openai", status, &response_headers, &self.api_token, error, |message| ApiError::OpenAIError { 
+                message,
                 type_: "api_error".to_string(),
                 param: None,
                 code: None
-            });
+            }));
         }
 
-        response
-            .json::<OpenAIResponse>()
+        let upstream = super::UpstreamResponse::of(&response);
+        let body = compression::read_text(response)
             .await
+            .map_err(|e| ApiError::OpenAIError { 
+                message: format!("Failed to read response: {}", e),
+                type_: "parse_error".to_string(),
+                param: None,
+                code: None
+            })?;
+        trace::record_response(call, status, &body);
+        let mut response = serde_json::from_str::<OpenAIResponse>(&body)
             .map_err(|e| ApiError::OpenAIError { 
                 message: format!("Failed to parse response: {}", e),
                 type_: "parse_error".to_string(),
                 param: None,
                 code: None
-            })
+            })?;
+        response.upstream = upstream;
+        Ok(response)
     }
 
     pub fn chat_stream(
@@ -234,39 +559,61 @@ impl OpenAIClient {
         messages: Vec<Message>,
         config: &ApiConfig,
     ) -> Pin<Box<dyn Stream<Item = Result<StreamResponse>> + Send>> {
-        let headers = match self.build_headers(Some(&config.headers)) {
-            Ok(h) => h,
+        let headers = match self.build_headers(Some(&config.headers), true) {
+            Ok(h) => compression::identity(h),
             Err(e) => return Box::pin(futures::stream::once(async move { Err(e) })),
         };
 
         let request = self.build_request(messages, true, config);
         let client = self.client.clone();
+        let idle_timeout = self.idle_timeout;
+        let parse_strictness = self.parse_strictness;
+        let dropped_frames = self.dropped_frames.clone();
+        let rate_limits = self.rate_limits.clone();
+        let signer = self.signer.clone();
+        let api_token = self.api_token.clone();
         let base_url = self.get_base_url(Some(&config.headers));
 
         Box::pin(async_stream::try_stream! {
-            let mut stream = client
+            // 请求体构建失败时作为流的第一个错误返回
+            let request = request?;
+            let mut headers = headers;
+            let body = signed_body(signer.as_deref(), &base_url, &mut headers, &request)?;
+            let call = trace::record_request("openai", &base_url, &body);
+            let started = Instant::now();
+            let response = client
                 .post(&base_url)
                 .headers(headers)
-                .json(&request)
+                .body(body)
                 .send()
                 .await
                 .map_err(|e| ApiError::OpenAIError { 
                     message: format!("Request failed: {}", e),
-                    type_: "request_failed".to_string(),
+                    type_: super::request_failure_type(&e).to_string(),
                     param: None,
                     code: None
-                })?
-                .bytes_stream();
+                })?;
+            connection::record_call("openai", &base_url, started.elapsed());
+            observe_rate_limit(rate_limits.as_deref(), "openai", &base_url, response.headers());
+            let status = response.status().as_u16();
+            // 上游返回错误状态时以状态码中止流, 而不是把错误体当作数据帧解析
+            if !response.status().is_success() {
+                let response_headers = response.headers().clone();
+                let message = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+                trace::record_response(call, status, &message);
+                Err(status_error("openai", status, &response_headers, &api_token, message, |message| ApiError::UpstreamStatus {
+                    provider: "openai".to_string(),
+                    status,
+                    message,
+                }))?;
+                return;
+            }
+            trace::record_status(call, status);
+            let mut stream = response.bytes_stream();
 
             let mut data = String::new();
             
-            while let Some(chunk) = stream.next().await {
-                let chunk = chunk.map_err(|e| ApiError::OpenAIError { 
-                    message: format!("Stream error: {}", e),
-                    type_: "stream_error".to_string(),
-                    param: None,
-                    code: None
-                })?;
+            while let Some(chunk) = next_chunk(&mut stream, idle_timeout, "openai").await? {
                 data.push_str(&String::from_utf8_lossy(&chunk));
 
                 let mut start = 0;
@@ -275,10 +622,19 @@ impl OpenAIClient {
                     let line = &data[start..end].trim();
                     start = end + 2;
                     
-                    if line.starts_with("data: ") {
-                        let json_data = &line["data: ".len()..];
-                        if let Ok(response) = serde_json::from_str::<StreamResponse>(json_data) {
-                            yield response;
+                    if let Some(json_data) = line.strip_prefix("data: ") {
+                        trace::record_frame(call, json_data);
+                        // 结束标记不是 JSON, 不能算作无法解析的数据块
+                        if json_data == "[DONE]" {
+                            continue;
+                        }
+                        match serde_json::from_str::<StreamResponse>(json_data) {
+                            Ok(response) => {
+                                yield response;
+                            }
+                            Err(e) => {
+                                reject_frame(parse_strictness, "openai", json_data, &e, &dropped_frames)?;
+                            }
                         }
                     }
                 }
@@ -289,4 +645,122 @@ impl OpenAIClient {
             }
         })
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_headers_override_default_headers() {
+        let client = OpenAIClient::builder()
+            .api_token("key")
+            .default_header("OpenAI-Organization", "org-default")
+            .user_agent("embedder/1.0")
+            .build()
+            .unwrap();
+        let custom = HashMap::from([("OpenAI-Organization".to_string(), "org-request".to_string())]);
+        let headers = client.build_headers(Some(&custom), false).unwrap();
+        assert_eq!(headers["openai-organization"], "org-request");
+        assert_eq!(headers["user-agent"], "embedder/1.0");
+        assert_eq!(headers["authorization"], "Bearer key");
+    }
+
+    #[test]
+    fn the_accept_header_follows_the_request_mode() {
+        let client = OpenAIClient::new("key".to_string());
+        assert_eq!(client.build_headers(None, true).unwrap()["accept"], "text/event-stream");
+        assert_eq!(client.build_headers(None, false).unwrap()["accept"], "application/json");
+
+        let defaults: ProviderDefaults = serde_json::from_value(serde_json::json!({"accept": "*/*"})).unwrap();
+        let client = OpenAIClient::new("key".to_string()).with_defaults(defaults);
+        assert_eq!(client.build_headers(None, true).unwrap()["accept"], "*/*");
+        assert_eq!(client.build_headers(None, false).unwrap()["accept"], "*/*");
+    }
+
+    #[test]
+    fn system_messages_take_the_role_the_model_expects() {
+        let client = OpenAIClient::new("key".to_string());
+        let messages = vec![
+            Message { role: Role::Developer, content: "Be brief.".into(), tool_calls: None, tool_call_id: None },
+            Message { role: Role::User, content: "Hi".into(), tool_calls: None, tool_call_id: None },
+        ];
+        for (model, role) in [("o1", "developer"), ("o3-mini", "developer"), ("gpt-4o", "system"), ("omni-7b", "system")] {
+            let config = ApiConfig { headers: HashMap::new(), body: serde_json::json!({"model": model}) };
+            let request = serde_json::to_value(client.build_request(messages.clone(), false, &config).unwrap()).unwrap();
+            assert_eq!(request["messages"][0]["role"], role, "{}", model);
+            assert_eq!(request["messages"][1]["role"], "user", "{}", model);
+        }
+    }
+
+    fn tool_round_trip() -> Vec<Message> {
+        let messages = serde_json::json!([
+            {"role": "assistant", "content": "", "tool_calls": [{"id": "call_abc-123", "type": "function", "function": {"name": "lookup", "arguments": "{}"}}]},
+            {"role": "tool", "content": "42", "tool_call_id": "call_abc-123"},
+            {"role": "assistant", "content": "", "tool_calls": [{"id": "Ab3dE6gH9", "type": "function", "function": {"name": "lookup", "arguments": "{}"}}]},
+        ]);
+        serde_json::from_value(messages).unwrap()
+    }
+
+    #[test]
+    fn mistral_requests_get_nine_character_tool_call_ids_that_still_pair_up() {
+        let client = OpenAIClient::new("key".to_string()).with_dialect(OpenAIDialect::Mistral);
+        let config = ApiConfig { headers: HashMap::new(), body: serde_json::json!({"model": "mistral-large-latest"}) };
+        let request = serde_json::to_value(client.build_request(tool_round_trip(), false, &config).unwrap()).unwrap();
+        let id = request["messages"][0]["tool_calls"][0]["id"].as_str().unwrap();
+        assert!(id.len() == 9 && id.bytes().all(|b| b.is_ascii_alphanumeric()), "{}", id);
+        assert_eq!(request["messages"][1]["tool_call_id"], id);
+        // 已经合规的 id 保持不变
+        assert_eq!(request["messages"][2]["tool_calls"][0]["id"], "Ab3dE6gH9");
+
+        let request = serde_json::to_value(OpenAIClient::new("key".to_string()).build_request(tool_round_trip(), false, &config).unwrap()).unwrap();
+        assert_eq!(request["messages"][0]["tool_calls"][0]["id"], "call_abc-123");
+    }
+
+    #[test]
+    fn safe_prompt_is_only_sent_in_the_mistral_dialect() {
+        let messages = vec![Message { role: Role::System, content: "Be brief.".into(), tool_calls: None, tool_call_id: None }];
+        let config = ApiConfig { headers: HashMap::new(), body: serde_json::json!({"model": "o1", "safe_prompt": true}) };
+        let mistral = OpenAIClient::new("key".to_string()).with_dialect(OpenAIDialect::Mistral);
+        let request = serde_json::to_value(mistral.build_request(messages.clone(), false, &config).unwrap()).unwrap();
+        assert_eq!(request["safe_prompt"], true);
+        assert_eq!(request["messages"][0]["role"], "system");
+
+        let request = serde_json::to_value(OpenAIClient::new("key".to_string()).build_request(messages, false, &config).unwrap()).unwrap();
+        assert!(request.get("safe_prompt").is_none());
+        assert_eq!(request["messages"][0]["role"], "developer");
+    }
+
+    #[test]
+    fn mistral_rejects_parameters_it_does_not_support() {
+        for body in [serde_json::json!({"n": 2}), serde_json::json!({"logprobs": true}), serde_json::json!({"logit_bias": {"1": 5}})] {
+            assert!(check_dialect_params(OpenAIDialect::Mistral, &body).is_err(), "{}", body);
+            assert!(check_dialect_params(OpenAIDialect::OpenAI, &body).is_ok(), "{}", body);
+        }
+        let body = serde_json::json!({"n": 1, "logprobs": null, "temperature": 0.3});
+        assert!(check_dialect_params(OpenAIDialect::Mistral, &body).is_ok());
+    }
+
+    #[test]
+    fn configured_defaults_replace_the_built_in_ones() {
+        let built = |client: &OpenAIClient, body: serde_json::Value| {
+            let messages = vec![Message { role: Role::User, content: "Hi".into(), tool_calls: None, tool_call_id: None }];
+            let config = ApiConfig { headers: HashMap::new(), body };
+            serde_json::to_value(client.build_request(messages, false, &config).unwrap()).unwrap()
+        };
+        let with_defaults = |value: serde_json::Value| OpenAIClient::new("key".to_string()).with_defaults(serde_json::from_value(value).unwrap());
+
+        let request = built(&OpenAIClient::new("key".to_string()), serde_json::json!({}));
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64(), request["temperature"].as_f64()), (Some(DEFAULT_MODEL), Some(DEFAULT_MAX_TOKENS), Some(DEFAULT_TEMPERATURE)));
+
+        let request = built(&with_defaults(serde_json::json!({"max_tokens": 1024})), serde_json::json!({}));
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64(), request["temperature"].as_f64()), (Some(DEFAULT_MODEL), Some(1024), Some(DEFAULT_TEMPERATURE)));
+
+        let configured = with_defaults(serde_json::json!({"model": "qwen2.5:14b", "max_tokens": 1024, "temperature": 0.2, "body": {"seed": 7}}));
+        let request = built(&configured, serde_json::json!({}));
+        assert_eq!((request["model"].as_str(), request["max_tokens"].as_u64(), request["temperature"].as_f64()), (Some("qwen2.5:14b"), Some(1024), Some(0.2)));
+        assert_eq!(request["seed"], 7);
+
+        let request = built(&configured, serde_json::json!({"model": "gpt-4o", "seed": 1}));
+        assert_eq!((request["model"].as_str(), request["seed"].as_u64()), (Some("gpt-4o"), Some(1)));
+    }
+}
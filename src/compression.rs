@@ -0,0 +1,279 @@
+//! Compressed request bodies and compressed upstream responses.
+//!
+//! Long conversations make large request bodies, so callers may send them
+//! with `Content-Encoding: gzip` or `deflate`. They are decompressed by
+//! tower-http before any handler sees them; the decompressed body is capped
+//! at `server.max_body_bytes` like an uncompressed one, so a small compressed
+//! body cannot expand past the limit.
+//!
+//! Non-streaming upstream calls ask for `gzip`, `deflate` or `br` responses
+//! and are decoded here. Streaming calls always ask for an identity response,
+//! since the SSE parsers read the raw frames as they arrive. The bytes saved
+//! in both directions are reported under `compression` on `/metrics`.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header::CONTENT_ENCODING, header::CONTENT_LENGTH, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING};
+use serde::Serialize;
+use std::{
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Encodings the non-streaming upstream calls accept.
+pub const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br";
+
+/// Largest decoded upstream response body.
+const MAX_DECODED_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+
+static STATS: CompressionCounters = CompressionCounters {
+    compressed_requests: AtomicU64::new(0),
+    request_bytes_compressed: AtomicU64::new(0),
+    request_bytes_decompressed: AtomicU64::new(0),
+    compressed_responses: AtomicU64::new(0),
+    response_bytes_compressed: AtomicU64::new(0),
+    response_bytes_decompressed: AtomicU64::new(0),
+};
+
+struct CompressionCounters {
+    compressed_requests: AtomicU64,
+    request_bytes_compressed: AtomicU64,
+    request_bytes_decompressed: AtomicU64,
+    compressed_responses: AtomicU64,
+    response_bytes_compressed: AtomicU64,
+    response_bytes_decompressed: AtomicU64,
+}
+
+/// Compression counters as reported by `/metrics`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompressionStats {
+    /// Compressed request bodies received from callers.
+    pub compressed_requests: u64,
+    /// Bytes saved on the wire by compressed request bodies.
+    pub request_bytes_saved: u64,
+    /// Compressed responses received from upstreams.
+    pub compressed_responses: u64,
+    /// Bytes saved on the wire by compressed upstream responses.
+    pub response_bytes_saved: u64,
+}
+
+/// Returns the current compression counters.
+pub fn stats() -> CompressionStats {
+    let saved = |compressed: &AtomicU64, decompressed: &AtomicU64| {
+        decompressed
+            .load(Ordering::Relaxed)
+            .saturating_sub(compressed.load(Ordering::Relaxed))
+    };
+    CompressionStats {
+        compressed_requests: STATS.compressed_requests.load(Ordering::Relaxed),
+        request_bytes_saved: saved(&STATS.request_bytes_compressed, &STATS.request_bytes_decompressed),
+        compressed_responses: STATS.compressed_responses.load(Ordering::Relaxed),
+        response_bytes_saved: saved(&STATS.response_bytes_compressed, &STATS.response_bytes_decompressed),
+    }
+}
+
+/// Size on the wire of a compressed request body, recorded before decompression.
+#[derive(Debug, Clone, Copy)]
+struct CompressedBody {
+    /// The declared `Content-Length`; chunked bodies have none.
+    length: Option<u64>,
+}
+
+/// Marks compressed request bodies before they are decompressed.
+///
+/// Runs outside the decompression layer, which drops `Content-Encoding`
+/// and `Content-Length` from the requests it decompresses.
+pub async fn mark_compressed(mut request: Request, next: Next) -> Response {
+    if is_compressed(request.headers().get(CONTENT_ENCODING)) {
+        let length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        request.extensions_mut().insert(CompressedBody { length });
+    }
+    next.run(request).await
+}
+
+/// Buffers decompressed request bodies up to `max_body_bytes` and records the bytes saved.
+///
+/// Runs inside the decompression layer. Bodies that decompress past the
+/// limit are rejected with `413` before a handler reads them.
+pub async fn measure_decompressed(State(max_body_bytes): State<usize>, request: Request, next: Next) -> Response {
+    let Some(compressed) = request.extensions().get::<CompressedBody>().copied() else {
+        return next.run(request).await;
+    };
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Rejected compressed request body: {}", e);
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Decompressed request body exceeds {} bytes", max_body_bytes),
+            )
+                .into_response();
+        }
+    };
+    STATS.compressed_requests.fetch_add(1, Ordering::Relaxed);
+    // 分块传输的请求没有 Content-Length, 无法统计节省的字节数
+    if let Some(length) = compressed.length {
+        STATS.request_bytes_compressed.fetch_add(length, Ordering::Relaxed);
+        STATS.request_bytes_decompressed.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+fn is_compressed(encoding: Option<&axum::http::HeaderValue>) -> bool {
+    encoding
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| !value.trim().eq_ignore_ascii_case("identity"))
+}
+
+/// Asks the upstream for a compressed response, unless the headers already choose an encoding.
+pub fn accept_compressed(mut headers: HeaderMap) -> HeaderMap {
+    headers
+        .entry(ACCEPT_ENCODING)
+        .or_insert(HeaderValue::from_static(ACCEPTED_ENCODINGS));
+    headers
+}
+
+/// Asks the upstream for an uncompressed response, for streaming calls.
+pub fn identity(mut headers: HeaderMap) -> HeaderMap {
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    headers
+}
+
+/// Reads an upstream response body as text, decoding its `Content-Encoding`.
+///
+/// Decoding runs on the blocking pool: a large body takes long enough to
+/// stall the other tasks of a runtime worker.
+///
+/// # Errors
+///
+/// Returns a message if the body cannot be read, uses an encoding other than
+/// `gzip`, `deflate` or `br`, is corrupt, or decodes past 64 MiB.
+pub async fn read_text(response: reqwest::Response) -> std::result::Result<String, String> {
+    let encoding = response
+        .headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    let Some(encoding) = encoding.filter(|encoding| encoding != "identity") else {
+        return Ok(String::from_utf8_lossy(&bytes).into_owned());
+    };
+
+    let compressed_len = bytes.len() as u64;
+    let decoded = tokio::task::spawn_blocking(move || decode(&encoding, &bytes))
+        .await
+        .map_err(|e| format!("Failed to decode response: {}", e))??;
+
+    STATS.compressed_responses.fetch_add(1, Ordering::Relaxed);
+    STATS.response_bytes_compressed.fetch_add(compressed_len, Ordering::Relaxed);
+    STATS.response_bytes_decompressed.fetch_add(decoded.len() as u64, Ordering::Relaxed);
+    Ok(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Decodes a body compressed with `encoding`, up to [`MAX_DECODED_RESPONSE_BYTES`].
+fn decode(encoding: &str, bytes: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(flate2::read::GzDecoder::new(bytes)),
+        // HTTP 的 deflate 是 zlib 格式
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(bytes)),
+        "br" => Box::new(brotli_decompressor::Decompressor::new(bytes, 4096)),
+        other => return Err(format!("Unsupported response encoding {}", other)),
+    };
+    let mut decoded = Vec::new();
+    reader
+        .take(MAX_DECODED_RESPONSE_BYTES + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| format!("Failed to decode {} response: {}", encoding, e))?;
+    if decoded.len() as u64 > MAX_DECODED_RESPONSE_BYTES {
+        return Err(format!("Decoded response exceeds {} bytes", MAX_DECODED_RESPONSE_BYTES));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+    use std::io::Write;
+    use tower::ServiceExt;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Reads a response carrying `body` with `Content-Encoding: encoding`.
+    async fn read_encoded(encoding: &str, body: Vec<u8>) -> std::result::Result<String, String> {
+        let upstream = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).insert_header("Content-Encoding", encoding).set_body_raw(body, "application/json"))
+            .mount(&upstream)
+            .await;
+        read_text(reqwest::get(upstream.uri()).await.unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn compressed_upstream_responses_are_decoded() {
+        let body = br#"{"answer": "Paris."}"#;
+        assert_eq!(read_encoded("gzip", gzip(body)).await.unwrap(), r#"{"answer": "Paris."}"#);
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::fast());
+        zlib.write_all(body).unwrap();
+        assert_eq!(read_encoded("deflate", zlib.finish().unwrap()).await.unwrap(), r#"{"answer": "Paris."}"#);
+        assert_eq!(read_encoded("identity", body.to_vec()).await.unwrap(), r#"{"answer": "Paris."}"#);
+
+        assert_eq!(read_encoded("zstd", body.to_vec()).await.unwrap_err(), "Unsupported response encoding zstd");
+        assert!(read_encoded("gzip", body.to_vec()).await.unwrap_err().starts_with("Failed to decode gzip response"));
+    }
+
+    #[tokio::test]
+    async fn upstream_responses_decoding_past_64_mib_are_rejected() {
+        let limit = MAX_DECODED_RESPONSE_BYTES as usize;
+        let error = read_encoded("gzip", gzip(&vec![b' '; limit + 1])).await.unwrap_err();
+        assert_eq!(error, "Decoded response exceeds 67108864 bytes");
+        assert_eq!(read_encoded("gzip", gzip(&vec![b' '; limit])).await.unwrap().len(), limit);
+    }
+
+    /// Posts a gzip-compressed token count request of about `size` bytes.
+    async fn post_compressed(app: &axum::Router, size: usize) -> (StatusCode, String) {
+        let padding = "a".repeat(size);
+        let body = serde_json::json!({"model": "deepthink", "messages": [{"role": "user", "content": padding}]});
+        let request = Request::post("/v1/token_count")
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .body(Body::from(gzip(body.to_string().as_bytes())))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn compressed_request_bodies_are_capped_after_decompression() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        config.server.max_body_bytes = 4096;
+        let (app, _) = testing::app(&config);
+
+        let (status, body) = post_compressed(&app, 1024).await;
+        assert_eq!(status, 200, "{}", body);
+        // 压缩后远小于上限, 解压后超出
+        let (status, body) = post_compressed(&app, 64 * 1024).await;
+        assert_eq!(status, 413, "{}", body);
+        assert_eq!(body, "Decompressed request body exceeds 4096 bytes");
+        assert!(stats().compressed_requests >= 1);
+    }
+}
@@ -4,8 +4,13 @@
 //! and environment variables. It includes endpoint configurations for different
 //! AI model providers and server settings.
 
+use crate::models::{
+    InjectionMode, OpenAIDialect, OptimisticConfig, ReasonerAnswerMode, ReasonerDialect, ReasonerSystemPrompt, ReasonerTranscript,
+    ThinkingFormat, UseReasonerAnswer,
+};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::time::Duration;
 use std::collections::HashMap;
 
 /// Root configuration structure containing all application settings.
@@ -18,6 +23,72 @@ pub struct Config {
     pub endpoints: EndpointConfig,
     pub models: ModelConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub reasoning: ReasoningConfig,
+    #[serde(default)]
+    pub target: TargetConfig,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
+    pub compat: CompatConfig,
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub parameter_policy: ParameterPolicyConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub speculation: SpeculationConfig,
+    /// Request body defaults of each upstream provider.
+    #[serde(default)]
+    pub defaults: DefaultsConfig,
+    /// Named route profiles, each serving the chat routes under its own prefix.
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfig>,
+    /// Rules redacting the reasoning before it is injected, applied in order.
+    #[serde(default)]
+    pub redaction: Vec<RedactionRule>,
+    /// Request signing of each upstream provider.
+    #[serde(default)]
+    pub signing: SigningConfig,
+    /// Usage events posted to external billing systems.
+    #[serde(default)]
+    pub usage_webhooks: UsageWebhookConfig,
+    /// Tenant namespaces, each with its own models, endpoints and auth.
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+    /// Progress updates posted while non-streaming requests run.
+    #[serde(default)]
+    pub progress_callbacks: ProgressCallbackConfig,
+}
+
+/// The sections of one `[tenants.<name>]` table; missing ones fall back to the global sections.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TenantConfig {
+    /// The tenant's model catalog; mappings it does not define fall back to the global ones.
+    #[serde(default)]
+    pub models: Option<ModelConfig>,
+    #[serde(default)]
+    pub endpoints: Option<TenantEndpointConfig>,
+    /// The tenant's tokens; each token may belong to one tenant only.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+/// One `[[redaction]]` rule: every match of `pattern` is replaced by `replacement`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RedactionRule {
+    /// Regular expression to redact.
+    pub pattern: String,
+    /// Replacement text; may refer to capture groups as `$1` or `${name}`.
+    #[serde(default = "default_redaction_replacement")]
+    pub replacement: String,
+}
+
+fn default_redaction_replacement() -> String {
+    "[redacted]".to_string()
 }
 
 /// Server-specific configuration settings.
@@ -28,6 +99,139 @@ pub struct Config {
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Capacity of the channel between the upstream readers and the SSE consumer.
+    #[serde(default = "default_stream_buffer")]
+    pub stream_buffer: usize,
+    /// What the stream does when the SSE consumer falls behind.
+    #[serde(default)]
+    pub stream_overflow: StreamOverflowPolicy,
+    /// Hold streamed reasoning deltas until this many bytes are pending and
+    /// send them as one chunk; 0 disables coalescing. Answer deltas are never held.
+    #[serde(default)]
+    pub max_coalesce_bytes: usize,
+    /// Longest reasoning deltas are held for coalescing, in milliseconds.
+    #[serde(default = "default_max_coalesce_millis")]
+    pub max_coalesce_millis: u64,
+    /// Number of non-streaming responses kept for `Idempotency-Key` replay; 0 disables replay.
+    #[serde(default = "default_idempotency_cache_size")]
+    pub idempotency_cache_size: usize,
+    /// Requests allowed to run the pipeline at once; unset admits every request immediately.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Seconds a queued request waits before its priority improves by one; 0 disables aging.
+    #[serde(default = "default_priority_aging_secs")]
+    pub priority_aging_secs: u64,
+    /// Largest request body accepted, in bytes; compressed bodies are capped after decompression.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Token required in `X-DeepThink-Admin-Token` by the `/admin/*` routes
+    /// and traced requests; when unset the admin routes are open.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Number of completed chat requests kept for `/admin/requests`; 0 disables the journal.
+    #[serde(default = "default_journal_size")]
+    pub journal_size: usize,
+    /// Separate listener for the admin routes; when unset they are served on the public listener.
+    #[serde(default)]
+    pub admin: Option<AdminServerConfig>,
+    /// Report message fields the proxy ignores, such as `function_call`, in the response's warnings.
+    #[serde(default)]
+    pub report_unknown_message_fields: bool,
+    /// Honour `trace: true` on chat requests; requires `admin_token`, which
+    /// traced requests must carry in `X-DeepThink-Admin-Token`.
+    #[serde(default)]
+    pub request_traces: bool,
+    /// Number of request traces kept for `/admin/traces/{id}`.
+    #[serde(default = "default_trace_store_size")]
+    pub trace_store_size: usize,
+}
+
+/// Address of the listener serving admin routes such as `/metrics`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdminServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+fn default_stream_buffer() -> usize {
+    100
+}
+
+fn default_max_coalesce_millis() -> u64 {
+    50
+}
+
+fn default_idempotency_cache_size() -> usize {
+    256
+}
+
+fn default_priority_aging_secs() -> u64 {
+    5
+}
+
+fn default_max_body_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_journal_size() -> usize {
+    200
+}
+
+fn default_trace_store_size() -> usize {
+    20
+}
+
+/// Body parameters callers may set, per pipeline phase.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ParameterPolicyConfig {
+    /// What happens to a parameter the policy does not allow.
+    #[serde(default)]
+    pub mode: ParameterPolicyMode,
+    #[serde(default)]
+    pub reasoner: PhaseParameterPolicy,
+    #[serde(default)]
+    pub target: PhaseParameterPolicy,
+}
+
+/// Handling of a disallowed body parameter.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterPolicyMode {
+    /// Reject the request with a 400 naming the parameter.
+    #[default]
+    Strict,
+    /// Drop the parameter and report it in a warning header.
+    Lenient,
+}
+
+/// Allowed and denied body parameters of one phase.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PhaseParameterPolicy {
+    /// Parameters callers may set; unset allows every parameter not denied.
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    /// Parameters callers may never set.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl PhaseParameterPolicy {
+    /// Returns true if callers may set the body parameter `key`.
+    pub fn permits(&self, key: &str) -> bool {
+        !self.deny.iter().any(|denied| denied == key)
+            && self.allow.as_ref().is_none_or(|allowed| allowed.iter().any(|allowed| allowed == key))
+    }
+}
+
+/// Backpressure policy for the SSE pipeline when its channel is full.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamOverflowPolicy {
+    /// Wait for the consumer to catch up before reading more from upstream.
+    #[default]
+    Block,
+    /// Coalesce reasoning deltas while the channel is full; answer deltas still block.
+    DropReasoning,
 }
 
 /// Endpoint configuration for all supported AI models.
@@ -36,6 +240,66 @@ pub struct EndpointConfig {
     pub deepseek: String,
     pub anthropic: String,
     pub openai: String,
+    /// Honor the `X-*-Endpoint-URL` request headers; when false they are ignored.
+    #[serde(default = "default_true")]
+    pub allow_override: bool,
+    /// Hosts (`host` or `host:port`) an endpoint override may point at; empty allows any host.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Path given to an endpoint that has none, such as `http://localhost:11434`.
+    #[serde(default)]
+    pub completion_paths: CompletionPaths,
+}
+
+/// A tenant's `endpoints` section.
+///
+/// The override policy is inherited from the global `endpoints` section
+/// unless the tenant sets it, so a tenant with its own upstreams keeps the
+/// global `allow_override` and `allowed_hosts`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TenantEndpointConfig {
+    pub deepseek: String,
+    pub anthropic: String,
+    pub openai: String,
+    #[serde(default)]
+    pub allow_override: Option<bool>,
+    #[serde(default)]
+    pub allowed_hosts: Option<Vec<String>>,
+    #[serde(default)]
+    pub completion_paths: Option<CompletionPaths>,
+}
+
+impl TenantEndpointConfig {
+    /// Returns the tenant's endpoints, with the settings it does not set taken from `global`.
+    pub fn resolve(&self, global: &EndpointConfig) -> EndpointConfig {
+        EndpointConfig {
+            deepseek: self.deepseek.clone(),
+            anthropic: self.anthropic.clone(),
+            openai: self.openai.clone(),
+            allow_override: self.allow_override.unwrap_or(global.allow_override),
+            allowed_hosts: self.allowed_hosts.clone().unwrap_or_else(|| global.allowed_hosts.clone()),
+            completion_paths: self.completion_paths.clone().unwrap_or_else(|| global.completion_paths.clone()),
+        }
+    }
+}
+
+/// The completions path of each provider, appended to endpoints without one.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CompletionPaths {
+    pub deepseek: String,
+    pub openai: String,
+    pub anthropic: String,
+}
+
+impl Default for CompletionPaths {
+    fn default() -> Self {
+        Self {
+            deepseek: crate::clients::DEEPSEEK_COMPLETIONS_PATH.to_string(),
+            openai: crate::clients::OPENAI_COMPLETIONS_PATH.to_string(),
+            anthropic: crate::clients::ANTHROPIC_MESSAGES_PATH.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -44,6 +308,135 @@ pub struct ModelConfig {
     pub default_openai: String,
     pub default_anthropic: String,
     pub model_mappings: HashMap<String, ModelMapping>,
+    /// Models proxied straight to the OpenAI endpoint without reasoning.
+    #[serde(default)]
+    pub passthrough_models: Vec<String>,
+    /// Upper bound on the `n` (number of choices) a request may ask for.
+    #[serde(default = "default_max_choices")]
+    pub max_choices: u32,
+    /// Target provider used by native requests without an `X-Target-Model` header.
+    #[serde(default)]
+    pub default_target_provider: TargetProvider,
+    /// Declared target capabilities by model name prefix; the longest matching prefix wins.
+    #[serde(default)]
+    pub capabilities: HashMap<String, ModelCapabilities>,
+    /// Replace images a target cannot accept with text placeholders instead of rejecting the request.
+    #[serde(default)]
+    pub degrade_images: bool,
+    /// Reasoner models a request may select with `reasoner_model`; unset allows any model.
+    #[serde(default)]
+    pub allowed_reasoner_models: Option<Vec<String>>,
+}
+
+/// Request features a target model accepts.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ModelCapabilities {
+    #[serde(default = "default_true")]
+    pub supports_images: bool,
+    #[serde(default = "default_true")]
+    pub supports_tools: bool,
+    /// Accepts `response_format` of type `json_object` or `json_schema`.
+    #[serde(default = "default_true")]
+    pub supports_json_mode: bool,
+    /// Most images accepted in one request; unset means no limit.
+    #[serde(default)]
+    pub max_images: Option<u32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ModelCapabilities {
+    /// Declares a model that accepts no images.
+    pub const fn text_only(supports_tools: bool, supports_json_mode: bool) -> Self {
+        Self {
+            supports_images: false,
+            supports_tools,
+            supports_json_mode,
+            max_images: None,
+        }
+    }
+}
+
+impl Default for ModelCapabilities {
+    fn default() -> Self {
+        Self {
+            supports_images: true,
+            supports_tools: true,
+            supports_json_mode: true,
+            max_images: None,
+        }
+    }
+}
+
+fn default_max_choices() -> u32 {
+    4
+}
+
+/// Upstream provider answering after the reasoner.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetProvider {
+    #[default]
+    Anthropic,
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// mistral.ai, called through the OpenAI client in the Mistral dialect.
+    Mistral,
+}
+
+impl TargetProvider {
+    /// Returns the provider's name as used in `X-Target-Model`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetProvider::Anthropic => "anthropic",
+            TargetProvider::OpenAI => "openai",
+            TargetProvider::Mistral => "mistral",
+        }
+    }
+
+    /// Returns the cargo feature that compiles in the provider's client.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            TargetProvider::Anthropic => "anthropic",
+            // Mistral 走 OpenAI 客户端
+            TargetProvider::OpenAI | TargetProvider::Mistral => "openai",
+        }
+    }
+
+    /// Returns true if the provider's client is compiled into this build.
+    pub fn is_compiled(&self) -> bool {
+        match self {
+            TargetProvider::Anthropic => cfg!(feature = "anthropic"),
+            TargetProvider::OpenAI | TargetProvider::Mistral => cfg!(feature = "openai"),
+        }
+    }
+
+    /// Parses a provider name as used in `X-Target-Model`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "anthropic" => Some(TargetProvider::Anthropic),
+            "openai" => Some(TargetProvider::OpenAI),
+            "mistral" => Some(TargetProvider::Mistral),
+            _ => None,
+        }
+    }
+
+    /// Returns an error unless the provider's client is compiled in.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::ProviderNotCompiled` naming the missing feature.
+    pub fn ensure_compiled(&self) -> crate::error::Result<()> {
+        if self.is_compiled() {
+            return Ok(());
+        }
+        Err(crate::error::ApiError::ProviderNotCompiled {
+            provider: self.as_str().to_string(),
+            feature: self.feature(),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -51,6 +444,174 @@ pub struct ModelMapping {
     pub deepseek_model: String,
     pub target_model: String,
     pub parameters: serde_json::Value,
+    /// Reasoning injection strategy for this mapping.
+    #[serde(default)]
+    pub injection_mode: Option<InjectionMode>,
+    /// Template for the injected reasoning; supports `{reasoning}` interpolation.
+    #[serde(default)]
+    pub injection_template: Option<String>,
+    /// Proxy requests for this mapping straight to `target_model` without reasoning.
+    #[serde(default)]
+    pub passthrough: bool,
+    /// Validate answers against the request's `response_format` JSON schema.
+    #[serde(default)]
+    pub validate_json_schema: bool,
+    /// Sampling defaults for the reasoner only.
+    #[serde(default)]
+    pub reasoner: PhaseParameters,
+    /// Sampling defaults for the target only.
+    #[serde(default)]
+    pub target: PhaseParameters,
+    /// Which phases a client-supplied `temperature` / `top_p` applies to.
+    #[serde(default)]
+    pub client_temperature_applies_to: ClientTemperaturePolicy,
+    /// Reasoning time limit for this mapping, overriding `reasoning.reasoning_timeout_secs`.
+    #[serde(default)]
+    pub reasoning_timeout_secs: Option<u64>,
+    /// Capabilities of this mapping's target, overriding `models.capabilities`.
+    #[serde(default)]
+    pub capabilities: Option<ModelCapabilities>,
+    /// How the conversation is presented to the reasoner.
+    #[serde(default)]
+    pub reasoner_transcript: ReasonerTranscript,
+    /// How the caller's system prompt reaches the reasoner, overriding
+    /// `reasoning.reasoner_sees_system`.
+    #[serde(default)]
+    pub reasoner_sees_system: Option<ReasonerSystemPrompt>,
+    /// Race a draft target call without reasoning against the reasoner.
+    #[serde(default)]
+    pub optimistic: OptimisticConfig,
+    /// API dialect of an OpenAI-compatible target, such as `mistral`.
+    #[serde(default)]
+    pub openai_dialect: OpenAIDialect,
+    /// API dialect of the reasoner; detected from its host when unset.
+    #[serde(default)]
+    pub reasoner_dialect: Option<ReasonerDialect>,
+    /// Most messages sent upstream; older messages past it are dropped.
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+    /// Keep the first user message when `max_messages` trims the conversation.
+    #[serde(default = "default_true")]
+    pub keep_first_user_message: bool,
+    /// Reasoner replicas serving `deepseek_model`; each request is sent to
+    /// one of them, picked by `reasoner_routing`. Empty uses `endpoints.deepseek`.
+    #[serde(default)]
+    pub reasoner_endpoints: Vec<String>,
+    /// How a request picks one of `reasoner_endpoints`.
+    #[serde(default)]
+    pub reasoner_routing: ReasonerRouting,
+    /// When to return the reasoner's own answer and skip the target.
+    #[serde(default)]
+    pub use_reasoner_answer: UseReasonerAnswer,
+    /// Shortest reasoner answer `use_reasoner_answer = "if_confident"` accepts, in characters.
+    #[serde(default = "default_confident_answer_min_chars")]
+    pub confident_answer_min_chars: usize,
+    /// Language the reasoner reasons in when the request does not set `reasoning_language`.
+    #[serde(default)]
+    pub reasoning_language: Option<String>,
+    /// Language the target answers in when the request does not set `answer_language`.
+    #[serde(default)]
+    pub answer_language: Option<String>,
+}
+
+pub(crate) fn default_confident_answer_min_chars() -> usize {
+    200
+}
+
+/// How a request picks one of a mapping's reasoner replicas.
+///
+/// Replicas whose circuit is open are skipped while any other is available.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerRouting {
+    /// Take the replicas in turn.
+    #[default]
+    RoundRobin,
+    /// Take the replica with the fewest requests in flight.
+    LeastBusy,
+    /// Keep each conversation on one replica, keyed by `conversation_id` or
+    /// the first user message, so its prefix cache stays warm.
+    Sticky,
+}
+
+/// Sampling parameters for one phase of a mapping.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PhaseParameters {
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+}
+
+/// The phases of a request that each send their own sampling parameters.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Reasoner,
+    Target,
+}
+
+/// Which phases a client-supplied `temperature` / `top_p` applies to.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientTemperaturePolicy {
+    /// Apply the client's values to the reasoner and the target.
+    #[default]
+    Both,
+    /// Apply the client's values to the target only.
+    Target,
+    /// Ignore the client's values; the mapping decides.
+    Ignored,
+}
+
+/// Temperature used when neither the request nor the mapping sets one.
+const DEFAULT_TEMPERATURE: f64 = 0.7;
+
+impl ModelMapping {
+    /// Resolves the `temperature` and `top_p` sent to one phase.
+    ///
+    /// Each parameter is taken from the first source that sets it:
+    /// the request (when `client_temperature_applies_to` covers this phase),
+    /// the mapping's per-phase section, the mapping's shared `parameters`,
+    /// and finally the hardcoded defaults (temperature 0.7, no `top_p`).
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - The phase whose parameters to resolve
+    /// * `request` - The client's request body
+    ///
+    /// # Returns
+    ///
+    /// * `serde_json::Map<String, serde_json::Value>` - The resolved parameters, to merge into the phase's body
+    pub fn sampling_parameters(&self, phase: Phase, request: &serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        let phase_parameters = match phase {
+            Phase::Reasoner => &self.reasoner,
+            Phase::Target => &self.target,
+        };
+        let client_applies = match self.client_temperature_applies_to {
+            ClientTemperaturePolicy::Both => true,
+            ClientTemperaturePolicy::Target => phase == Phase::Target,
+            ClientTemperaturePolicy::Ignored => false,
+        };
+
+        let mut resolved = serde_json::Map::new();
+        for (key, phase_value, default) in [
+            ("temperature", phase_parameters.temperature, Some(DEFAULT_TEMPERATURE)),
+            ("top_p", phase_parameters.top_p, None),
+        ] {
+            let value = request
+                .get(key)
+                .filter(|value| client_applies && !value.is_null())
+                .cloned()
+                .or_else(|| phase_value.map(serde_json::Value::from))
+                .or_else(|| self.parameters.get(key).cloned())
+                .or_else(|| default.map(serde_json::Value::from));
+            if let Some(value) = value {
+                resolved.insert(key.to_string(), value);
+            }
+        }
+        resolved
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -64,6 +625,917 @@ pub struct TokenConfig {
     pub deepseek_token: String,
     pub openai_token: String,
     pub anthropic_token: String,
+    /// Admission priority of this token's requests; lower values are served first.
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    /// Spend allowed per UTC day, in USD; unset means unlimited.
+    #[serde(default)]
+    pub daily_budget_usd: Option<f64>,
+    /// Spend allowed per UTC month, in USD; unset means unlimited.
+    #[serde(default)]
+    pub monthly_budget_usd: Option<f64>,
+    /// Tenant the token belongs to, for tokens in the global `auth.token_mappings`.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+fn default_priority() -> u8 {
+    10
+}
+
+/// Settings controlling how the reasoner's output is handled.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReasoningConfig {
+    /// What to do when the reasoner returns empty or whitespace-only reasoning.
+    #[serde(default)]
+    pub empty_policy: EmptyReasoningPolicy,
+    /// What to do with the reasoner's own answer; requests may override it.
+    #[serde(default)]
+    pub reasoner_answer: ReasonerAnswerMode,
+    /// Longest the reasoning phase may run, in seconds; unset or 0 disables the limit.
+    #[serde(default)]
+    pub reasoning_timeout_secs: Option<u64>,
+    /// Appended to reasoning that was cut off by the timeout or by the
+    /// reasoner's `max_tokens` before it is injected; empty disables it.
+    #[serde(default = "default_truncation_marker")]
+    pub truncation_marker: String,
+    /// What to do when the reasoner stops with `finish_reason = "length"`.
+    #[serde(default)]
+    pub on_truncated_reasoning: TruncatedReasoningPolicy,
+    /// Factor applied to the reasoner's `max_tokens` by the `retry_larger` policy.
+    #[serde(default = "default_truncated_retry_multiplier")]
+    pub truncated_retry_multiplier: f64,
+    /// How the caller's system prompt reaches the reasoner; mappings and
+    /// native requests may override it.
+    #[serde(default)]
+    pub reasoner_sees_system: ReasonerSystemPrompt,
+    /// How think tags written in user messages reach the reasoner.
+    #[serde(default)]
+    pub user_think_tags: UserThinkTags,
+    /// Drop the thinking block clients send back at the start of assistant
+    /// turns before the conversation reaches either phase.
+    #[serde(default)]
+    pub strip_history_thinking: bool,
+    /// Highest `max_tokens` sent to a Groq reasoner; larger values are clamped.
+    #[serde(default = "default_groq_max_tokens")]
+    pub groq_max_tokens: u64,
+    /// Which turns inject earlier reasoning instead of running the reasoner.
+    #[serde(default)]
+    pub reasoning_reuse: ReasoningReuse,
+    /// Seconds the reasoning stored for a conversation stays reusable.
+    #[serde(default = "default_reuse_ttl_secs")]
+    pub reuse_ttl_secs: u64,
+    /// Which copies of the reasoning the `[[redaction]]` rules apply to.
+    #[serde(default)]
+    pub redaction_scope: RedactionScope,
+    /// Instruction appended to the reasoner's system prompt for a request's
+    /// `reasoning_language`; `{language}` is replaced with the language.
+    #[serde(default = "default_reasoning_language_instruction")]
+    pub language_instruction: String,
+    /// Instructions for specific languages, replacing `language_instruction`.
+    #[serde(default)]
+    pub language_instructions: HashMap<String, String>,
+}
+
+impl ReasoningConfig {
+    /// Returns the reasoner instruction for `language`.
+    pub fn language_instruction(&self, language: &str) -> String {
+        language_instruction(&self.language_instructions, &self.language_instruction, language)
+    }
+}
+
+fn default_reasoning_language_instruction() -> String {
+    "Reason in {language}, regardless of the language of the conversation.".to_string()
+}
+
+/// Returns the instruction of `instructions` for `language`, or `template`
+/// with `{language}` replaced.
+fn language_instruction(instructions: &HashMap<String, String>, template: &str, language: &str) -> String {
+    instructions
+        .get(language)
+        .cloned()
+        .unwrap_or_else(|| template.replace("{language}", language))
+}
+
+fn default_reuse_ttl_secs() -> u64 {
+    1800
+}
+
+fn default_groq_max_tokens() -> u64 {
+    16384
+}
+
+fn default_truncation_marker() -> String {
+    "[reasoning truncated]".to_string()
+}
+
+fn default_truncated_retry_multiplier() -> f64 {
+    2.0
+}
+
+impl Default for ReasoningConfig {
+    fn default() -> Self {
+        Self {
+            empty_policy: EmptyReasoningPolicy::default(),
+            reasoner_answer: ReasonerAnswerMode::default(),
+            reasoning_timeout_secs: None,
+            truncation_marker: default_truncation_marker(),
+            on_truncated_reasoning: TruncatedReasoningPolicy::default(),
+            truncated_retry_multiplier: default_truncated_retry_multiplier(),
+            reasoner_sees_system: ReasonerSystemPrompt::default(),
+            user_think_tags: UserThinkTags::default(),
+            strip_history_thinking: false,
+            groq_max_tokens: default_groq_max_tokens(),
+            reasoning_reuse: ReasoningReuse::default(),
+            reuse_ttl_secs: default_reuse_ttl_secs(),
+            redaction_scope: RedactionScope::default(),
+            language_instruction: default_reasoning_language_instruction(),
+            language_instructions: HashMap::new(),
+        }
+    }
+}
+
+/// Turns that reuse earlier reasoning instead of running the reasoner.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningReuse {
+    /// Every turn with reasoning to reuse.
+    Always,
+    /// Continuation turns of a tool loop: tool results with no new user message.
+    ToolLoop,
+    /// None; every turn runs the reasoner.
+    #[default]
+    Never,
+}
+
+/// Copies of the reasoning the `[[redaction]]` rules apply to.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionScope {
+    /// Only the reasoning injected into the target prompt; clients see it unredacted.
+    #[default]
+    Injected,
+    /// The injected reasoning and the reasoning returned to the client.
+    All,
+}
+
+/// Treatment of `<think>` and `<thinking>` tags written in user messages.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UserThinkTags {
+    /// Escape them as `&lt;think&gt;` in the messages sent to the reasoner,
+    /// so the only tags in its output are its own.
+    #[default]
+    Escape,
+    /// Send them to the reasoner unchanged.
+    Pass,
+}
+
+/// Policy applied when the reasoner produces no reasoning content.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyReasoningPolicy {
+    /// Retry the reasoner once with a nudging user instruction.
+    Retry,
+    /// Continue without injecting any reasoning into the target request.
+    Skip,
+    /// Fail the request with `ApiError::EmptyReasoning`.
+    #[default]
+    Error,
+}
+
+/// Policy applied when the reasoner hits its `max_tokens` mid-reasoning.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncatedReasoningPolicy {
+    /// Inject the truncated reasoning, marked as truncated.
+    #[default]
+    Continue,
+    /// Re-run the reasoner once with `max_tokens` scaled by
+    /// `truncated_retry_multiplier`.
+    RetryLarger,
+    /// Fail the request with `ApiError::ReasoningTruncated`.
+    Error,
+}
+
+/// Settings controlling how the target's output is handled.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TargetConfig {
+    /// What to do when the target returns no answer text and no tool calls.
+    #[serde(default)]
+    pub empty_answer_policy: EmptyAnswerPolicy,
+    /// Connection warm-up per target provider, run while the reasoner works.
+    #[serde(default)]
+    pub warm_up: WarmUpConfig,
+    /// Stream the target's answer for non-streaming requests too, and return
+    /// the text received before a mid-answer failure with
+    /// `finish_reason = "interrupted"` instead of an error.
+    #[serde(default)]
+    pub partial_on_failure: bool,
+    /// What to do with `<think>` and `<thinking>` tags the target writes in its answer.
+    #[serde(default)]
+    pub answer_think_tags: AnswerThinkTags,
+    /// Instruction appended to the target's system prompt for a request's
+    /// `answer_language`; `{language}` is replaced with the language.
+    #[serde(default = "default_answer_language_instruction")]
+    pub answer_language_instruction: String,
+    /// Instructions for specific languages, replacing `answer_language_instruction`.
+    #[serde(default)]
+    pub answer_language_instructions: HashMap<String, String>,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        Self {
+            empty_answer_policy: EmptyAnswerPolicy::default(),
+            warm_up: WarmUpConfig::default(),
+            partial_on_failure: false,
+            answer_think_tags: AnswerThinkTags::default(),
+            answer_language_instruction: default_answer_language_instruction(),
+            answer_language_instructions: HashMap::new(),
+        }
+    }
+}
+
+impl TargetConfig {
+    /// Returns the target instruction for `language`.
+    pub fn answer_language_instruction(&self, language: &str) -> String {
+        language_instruction(&self.answer_language_instructions, &self.answer_language_instruction, language)
+    }
+}
+
+fn default_answer_language_instruction() -> String {
+    "Answer in {language}.".to_string()
+}
+
+/// Treatment of `<think>` and `<thinking>` tags in the target's answer.
+///
+/// A target that saw the injected reasoning sometimes echoes its tags, which
+/// confuses UIs rendering the reasoning and, on the next turn, the stripping
+/// of thinking blocks from the history. The reasoning itself is never touched.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerThinkTags {
+    /// Leave them as the target wrote them.
+    #[default]
+    Pass,
+    /// Escape them as `&lt;think&gt;`.
+    Escape,
+    /// Remove the tags, keeping the text between them.
+    Strip,
+}
+
+/// Warm-up request per target provider.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct WarmUpConfig {
+    #[serde(default)]
+    pub openai: WarmUpMethod,
+    #[serde(default)]
+    pub anthropic: WarmUpMethod,
+}
+
+impl WarmUpConfig {
+    /// Returns the warm-up request for a target provider name.
+    pub fn method(&self, target_model: &str) -> WarmUpMethod {
+        match target_model {
+            "openai" => self.openai,
+            _ => self.anthropic,
+        }
+    }
+}
+
+/// Request that opens a connection to the target endpoint ahead of the real call.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmUpMethod {
+    /// No warm-up.
+    #[default]
+    Off,
+    /// `HEAD` on the endpoint.
+    Head,
+    /// `OPTIONS` on the endpoint, for servers that reject `HEAD`.
+    Options,
+}
+
+/// Policy applied when the target produces an empty answer.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyAnswerPolicy {
+    /// Fail the request with `ApiError::TargetEmptyResponse`.
+    #[default]
+    Error,
+    /// Call the target once more with a raised `max_tokens`.
+    RetryOnce,
+    /// Return the empty answer, flagged with a warning header or metadata field.
+    Pass,
+}
+
+/// Settings controlling what the logs may contain.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LoggingConfig {
+    /// How message content appears in logged requests and responses.
+    #[serde(default)]
+    pub log_content: LogContent,
+    /// Write each chat request to the `deepthink::audit` log target, with
+    /// its body redacted under `log_content`.
+    #[serde(default)]
+    pub audit: bool,
+}
+
+/// How end-user and model content is written to the logs.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogContent {
+    /// Log content verbatim.
+    #[default]
+    Full,
+    /// Replace content with a SHA-256 prefix and its length.
+    Hash,
+    /// Replace content with its length.
+    LengthOnly,
+}
+
+/// Settings for the SSE output pipeline.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamingConfig {
+    /// Maximum answer characters per second sent to the client; unset disables throttling.
+    #[serde(default)]
+    pub max_output_chars_per_second: Option<u32>,
+    /// Maximum reasoning characters per second sent to the client; unset disables throttling.
+    #[serde(default)]
+    pub max_reasoning_chars_per_second: Option<u32>,
+    /// Longest gap in seconds allowed between upstream chunks before a stream is aborted; 0 disables the check.
+    #[serde(default = "default_max_idle_secs")]
+    pub max_idle_secs: u64,
+    /// How each provider's stream parser treats frames it cannot parse.
+    #[serde(default)]
+    pub parse_strictness: ParseStrictnessConfig,
+    /// Text sent between the closing thinking tag and the first answer delta; empty disables it.
+    #[serde(default = "default_answer_separator")]
+    pub answer_separator: String,
+    /// Most frames kept per resumable stream for replay.
+    #[serde(default = "default_resume_buffer_frames")]
+    pub resume_buffer_frames: usize,
+    /// Seconds a finished resumable stream stays available for replay.
+    #[serde(default = "default_resume_ttl_secs")]
+    pub resume_ttl_secs: u64,
+    /// What wins when a request's `stream` flag contradicts its `Accept` header.
+    #[serde(default)]
+    pub accept_precedence: AcceptPrecedence,
+    /// Reconnection delay in milliseconds sent as the SSE `retry` field; 0 omits it.
+    #[serde(default = "default_retry_ms")]
+    pub retry_ms: u64,
+    /// Seconds between SSE keep-alive comments on idle streams; 0 disables them.
+    #[serde(default = "default_heartbeat_secs")]
+    pub heartbeat_secs: u64,
+    /// Wall-clock seconds after which a stream's task is aborted, whatever it waits on; 0 disables the ceiling.
+    #[serde(default = "default_max_stream_secs")]
+    pub max_stream_secs: u64,
+}
+
+fn default_max_stream_secs() -> u64 {
+    900
+}
+
+fn default_retry_ms() -> u64 {
+    3000
+}
+
+fn default_heartbeat_secs() -> u64 {
+    15
+}
+
+/// Resolution of a `stream` flag that contradicts the `Accept` header.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AcceptPrecedence {
+    /// Fail the request with `406 Not Acceptable`.
+    #[default]
+    Reject,
+    /// Answer in the format the `Accept` header names.
+    Accept,
+    /// Answer as the `stream` flag says.
+    Body,
+}
+
+fn default_resume_buffer_frames() -> usize {
+    4096
+}
+
+fn default_resume_ttl_secs() -> u64 {
+    300
+}
+
+fn default_answer_separator() -> String {
+    "\n\n".to_string()
+}
+
+fn default_max_idle_secs() -> u64 {
+    120
+}
+
+impl StreamingConfig {
+    /// Returns the configured upstream idle timeout, or `None` when disabled.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.max_idle_secs > 0).then(|| Duration::from_secs(self.max_idle_secs))
+    }
+
+    /// Returns the SSE reconnection delay, or `None` when disabled.
+    pub fn retry(&self) -> Option<Duration> {
+        (self.retry_ms > 0).then(|| Duration::from_millis(self.retry_ms))
+    }
+
+    /// Returns the SSE keep-alive interval, or `None` when disabled.
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_secs > 0).then(|| Duration::from_secs(self.heartbeat_secs))
+    }
+
+    /// Returns the wall-clock ceiling of a stream, or `None` when disabled.
+    pub fn max_stream_duration(&self) -> Option<Duration> {
+        (self.max_stream_secs > 0).then(|| Duration::from_secs(self.max_stream_secs))
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            max_output_chars_per_second: None,
+            max_reasoning_chars_per_second: None,
+            max_idle_secs: default_max_idle_secs(),
+            parse_strictness: ParseStrictnessConfig::default(),
+            answer_separator: default_answer_separator(),
+            resume_buffer_frames: default_resume_buffer_frames(),
+            resume_ttl_secs: default_resume_ttl_secs(),
+            accept_precedence: AcceptPrecedence::default(),
+            retry_ms: default_retry_ms(),
+            heartbeat_secs: default_heartbeat_secs(),
+            max_stream_secs: default_max_stream_secs(),
+        }
+    }
+}
+
+/// Settings for the per-upstream circuit breakers.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive connection failures that open a circuit; 0 disables the breakers.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Seconds an open circuit fails fast before letting a probe request through.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// What requests do while the reasoner's circuit is open.
+    #[serde(default)]
+    pub reasoner_open: ReasonerCircuitAction,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown_secs() -> u64 {
+    30
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_failure_threshold(),
+            cooldown_secs: default_cooldown_secs(),
+            reasoner_open: ReasonerCircuitAction::default(),
+        }
+    }
+}
+
+/// Request body defaults of each upstream provider.
+///
+/// The clients fall back to their built-in defaults for anything not set
+/// here, and values in a request's `*_config.body` always win.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DefaultsConfig {
+    #[serde(default)]
+    pub deepseek: ProviderDefaults,
+    #[serde(default)]
+    pub openai: ProviderDefaults,
+    #[serde(default)]
+    pub anthropic: ProviderDefaults,
+}
+
+/// Request signing of each upstream provider, for gateways that verify a signature.
+///
+/// Providers without a section send their requests unsigned.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SigningConfig {
+    #[serde(default)]
+    pub deepseek: Option<HmacSigningConfig>,
+    #[serde(default)]
+    pub openai: Option<HmacSigningConfig>,
+    #[serde(default)]
+    pub anthropic: Option<HmacSigningConfig>,
+}
+
+/// HMAC-SHA256 signing of one provider's requests.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HmacSigningConfig {
+    /// Environment variable holding the signing key.
+    pub key_env: String,
+    /// Header carrying the hex signature.
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    /// Header carrying the signed Unix timestamp.
+    #[serde(default = "default_timestamp_header")]
+    pub timestamp_header: String,
+    /// Seconds added to the local clock for the timestamp, to match the gateway's clock.
+    #[serde(default)]
+    pub timestamp_skew_secs: i64,
+}
+
+fn default_signature_header() -> String {
+    "X-Signature".to_string()
+}
+
+fn default_timestamp_header() -> String {
+    "X-Signature-Timestamp".to_string()
+}
+
+/// Usage events posted after each completed request, for external billing systems.
+///
+/// Without endpoints no events are sent.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UsageWebhookConfig {
+    /// HTTPS URLs every event is posted to.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// Environment variable holding the shared secret the events are signed with.
+    #[serde(default)]
+    pub secret_env: String,
+    /// Delivery attempts of an event before it is written to the queue file.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each later one.
+    #[serde(default = "default_webhook_retry_base_ms")]
+    pub retry_base_ms: u64,
+    /// Timeout of one delivery attempt.
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// JSON lines file keeping the undelivered events across restarts.
+    #[serde(default = "default_webhook_queue_path")]
+    pub queue_path: String,
+    /// Events waiting for the dispatcher before new ones go straight to the queue file.
+    #[serde(default = "default_webhook_buffer")]
+    pub buffer: usize,
+}
+
+impl Default for UsageWebhookConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            secret_env: String::new(),
+            max_attempts: default_webhook_max_attempts(),
+            retry_base_ms: default_webhook_retry_base_ms(),
+            timeout_secs: default_webhook_timeout_secs(),
+            queue_path: default_webhook_queue_path(),
+            buffer: default_webhook_buffer(),
+        }
+    }
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_retry_base_ms() -> u64 {
+    500
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+fn default_webhook_queue_path() -> String {
+    "usage_webhooks.jsonl".to_string()
+}
+
+fn default_webhook_buffer() -> usize {
+    1024
+}
+
+/// Settings of the `progress_callback_url` request option.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProgressCallbackConfig {
+    /// Hosts ("host" or "host:port") callbacks may be posted to; empty
+    /// rejects requests asking for progress callbacks.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Environment variable holding the shared secret the updates are signed with.
+    #[serde(default)]
+    pub secret_env: String,
+    /// Seconds between two updates while the phase does not change.
+    #[serde(default = "default_progress_interval_secs")]
+    pub interval_secs: u64,
+    /// Timeout of one delivery; updates are not retried.
+    #[serde(default = "default_progress_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for ProgressCallbackConfig {
+    fn default() -> Self {
+        Self {
+            allowed_hosts: Vec::new(),
+            secret_env: String::new(),
+            interval_secs: default_progress_interval_secs(),
+            timeout_secs: default_progress_timeout_secs(),
+        }
+    }
+}
+
+fn default_progress_interval_secs() -> u64 {
+    10
+}
+
+fn default_progress_timeout_secs() -> u64 {
+    5
+}
+
+/// Body and header defaults of one provider's requests.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProviderDefaults {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// Further body fields sent with every request; `messages` and `stream` are ignored.
+    #[serde(default)]
+    pub body: serde_json::Map<String, serde_json::Value>,
+    /// Leaves out the `response_format` the reasoner is sent by default, for
+    /// servers that reject it. Only the reasoner is sent one.
+    #[serde(default)]
+    pub omit_response_format: bool,
+    /// `Accept` header sent instead of `text/event-stream` for streaming and
+    /// `application/json` for other requests; empty sends none.
+    #[serde(default)]
+    pub accept: Option<String>,
+}
+
+/// Settings for speculative reasoning on conversation continuations.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SpeculationConfig {
+    /// Run the reasoner ahead of the next turn of conversations with a `conversation_id`.
+    #[serde(default)]
+    pub speculative_reasoning: bool,
+    /// Speculative reasoner runs allowed at once across all conversations.
+    #[serde(default = "default_max_concurrent_speculations")]
+    pub max_concurrent: usize,
+    /// Seconds cached speculative reasoning stays usable.
+    #[serde(default = "default_speculation_ttl_secs")]
+    pub ttl_secs: u64,
+    /// How cached reasoning is used when the next turn matches.
+    #[serde(default)]
+    pub reuse: SpeculationReuse,
+    /// `max_tokens` of the reasoner run that tops up cached reasoning.
+    #[serde(default = "default_top_up_max_tokens")]
+    pub top_up_max_tokens: u64,
+}
+
+fn default_max_concurrent_speculations() -> usize {
+    2
+}
+
+fn default_speculation_ttl_secs() -> u64 {
+    600
+}
+
+fn default_top_up_max_tokens() -> u64 {
+    1024
+}
+
+impl Default for SpeculationConfig {
+    fn default() -> Self {
+        Self {
+            speculative_reasoning: false,
+            max_concurrent: default_max_concurrent_speculations(),
+            ttl_secs: default_speculation_ttl_secs(),
+            reuse: SpeculationReuse::default(),
+            top_up_max_tokens: default_top_up_max_tokens(),
+        }
+    }
+}
+
+/// How cached speculative reasoning is used by the turn it anticipated.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeculationReuse {
+    /// Inject the cached reasoning as is, skipping the reasoner.
+    #[default]
+    Reuse,
+    /// Run the reasoner briefly on the real question with the cached
+    /// reasoning as context, and inject both.
+    TopUp,
+}
+
+/// Action taken for a request whose reasoner circuit is open.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerCircuitAction {
+    /// Fail the request with `ApiError::CircuitOpen`.
+    #[default]
+    FailFast,
+    /// Answer with the target alone, without reasoning.
+    SkipReasoning,
+}
+
+/// A named bundle of defaults for the chat routes mounted under `route_prefix`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProfileConfig {
+    /// Path prefix of the profile's routes, e.g. `/internal`.
+    pub route_prefix: String,
+    /// Return the reasoning to clients; when false it is only injected into the target.
+    #[serde(default = "default_true")]
+    pub include_reasoning: bool,
+    /// Default `thinking_format`; requests may override it.
+    #[serde(default)]
+    pub thinking_format: Option<ThinkingFormat>,
+    /// Model mapping used for requested models without a mapping of their own.
+    #[serde(default)]
+    pub default_mapping: Option<String>,
+    /// Models the profile serves; unset allows any model.
+    #[serde(default)]
+    pub allowed_models: Option<Vec<String>>,
+    /// Requests allowed per minute across all callers; unset means unlimited.
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+}
+
+/// Per-provider handling of unparseable upstream stream frames.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ParseStrictnessConfig {
+    #[serde(default)]
+    pub deepseek: ParseStrictness,
+    #[serde(default)]
+    pub openai: ParseStrictness,
+    #[serde(default)]
+    pub anthropic: ParseStrictness,
+}
+
+/// What a stream parser does with a frame it cannot parse.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseStrictness {
+    /// Skip the frame silently.
+    #[default]
+    Lenient,
+    /// Skip the frame, but count it and report the count to the client.
+    Warn,
+    /// Abort the stream with an error naming the offending payload.
+    Strict,
+}
+
+/// Settings for the OpenAI-compatible surface.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CompatConfig {
+    /// Forward `/v1/embeddings` unchanged to the configured OpenAI endpoint
+    /// instead of rejecting it.
+    #[serde(default)]
+    pub proxy_embeddings: bool,
+    /// Reasoning length assumed by `/v1/token_count` when projecting the target prompt.
+    #[serde(default = "default_assumed_reasoning_tokens")]
+    pub assumed_reasoning_tokens: u32,
+    /// Context window per upstream model name, used to report headroom.
+    #[serde(default)]
+    pub context_windows: HashMap<String, u32>,
+    /// Count Anthropic target prompts with Anthropic's `count_tokens` endpoint
+    /// in `/v1/token_count`, using the caller's Anthropic token.
+    #[serde(default)]
+    pub anthropic_token_counting: bool,
+    /// Inbound headers copied onto the upstream requests of the compat routes.
+    #[serde(default)]
+    pub forward_request_headers: Vec<ForwardedHeader>,
+}
+
+/// A `compat.forward_request_headers` entry: a glob pattern of header names,
+/// optionally restricted to one phase.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ForwardedHeader {
+    /// Forward matching headers to the reasoner and the target.
+    Pattern(String),
+    /// Forward matching headers to the phase `to` only.
+    Restricted { pattern: String, to: Phase },
+}
+
+impl ForwardedHeader {
+    /// Returns the glob pattern, e.g. `x-helicone-*`.
+    pub fn pattern(&self) -> &str {
+        match self {
+            ForwardedHeader::Pattern(pattern) | ForwardedHeader::Restricted { pattern, .. } => pattern,
+        }
+    }
+
+    /// Returns true if matching headers are forwarded to `phase`.
+    pub fn applies_to(&self, phase: Phase) -> bool {
+        match self {
+            ForwardedHeader::Pattern(_) => true,
+            ForwardedHeader::Restricted { to, .. } => *to == phase,
+        }
+    }
+}
+
+fn default_assumed_reasoning_tokens() -> u32 {
+    1024
+}
+
+/// Pricing and warning settings for per-token spend budgets.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BudgetConfig {
+    /// Fraction of a budget after which responses carry a warning header.
+    #[serde(default = "default_warning_ratio")]
+    pub warning_ratio: f64,
+    /// Price per upstream model name; unpriced models cost nothing.
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPrice>,
+    /// Path of the sqlite database the spend ledger is persisted to; unset keeps spend in memory.
+    #[serde(default)]
+    pub store_path: Option<String>,
+    /// Seconds between flushes of the spend ledger to `store_path`.
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+}
+
+fn default_flush_interval_secs() -> u64 {
+    10
+}
+
+/// Price of an upstream model, in USD per million tokens.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default)]
+pub struct ModelPrice {
+    #[serde(default)]
+    pub prompt_usd_per_million: f64,
+    #[serde(default)]
+    pub completion_usd_per_million: f64,
+    /// Price of prompt tokens read from the provider's prompt cache; defaults
+    /// to `prompt_usd_per_million`.
+    #[serde(default)]
+    pub cached_prompt_usd_per_million: Option<f64>,
+}
+
+fn default_warning_ratio() -> f64 {
+    0.8
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            warning_ratio: default_warning_ratio(),
+            pricing: HashMap::new(),
+            store_path: None,
+            flush_interval_secs: default_flush_interval_secs(),
+        }
+    }
+}
+
+impl BudgetConfig {
+    /// Returns the cost of one upstream call in USD.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The upstream model name
+    /// * `prompt_tokens` - Tokens sent to the model
+    /// * `completion_tokens` - Tokens generated by the model
+    pub fn cost(&self, model: &str, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        self.cost_with_cache(model, prompt_tokens, 0, completion_tokens)
+    }
+
+    /// Returns the cost of one upstream call in USD, pricing cache reads at
+    /// the model's cached rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The upstream model name
+    /// * `prompt_tokens` - Tokens sent to the model, including cached ones
+    /// * `cached_prompt_tokens` - Prompt tokens read from the provider's cache
+    /// * `completion_tokens` - Tokens generated by the model
+    pub fn cost_with_cache(&self, model: &str, prompt_tokens: u32, cached_prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        let price = self.pricing.get(model).copied().unwrap_or_default();
+        let cached = cached_prompt_tokens.min(prompt_tokens);
+        let cached_price = price.cached_prompt_usd_per_million.unwrap_or(price.prompt_usd_per_million);
+        (f64::from(prompt_tokens - cached) * price.prompt_usd_per_million
+            + f64::from(cached) * cached_price
+            + f64::from(completion_tokens) * price.completion_usd_per_million)
+            / 1_000_000.0
+    }
+}
+
+impl Default for CompatConfig {
+    fn default() -> Self {
+        Self {
+            proxy_embeddings: false,
+            assumed_reasoning_tokens: default_assumed_reasoning_tokens(),
+            context_windows: HashMap::new(),
+            anthropic_token_counting: false,
+            forward_request_headers: Vec::new(),
+        }
+    }
 }
 
 impl Config {
@@ -82,13 +1554,66 @@ impl Config {
     /// - The config file cannot be read
     /// - The TOML content cannot be parsed
     /// - The parsed content doesn't match the expected structure
+    /// - The parsed content fails [`Config::validate`]
     pub fn load() -> anyhow::Result<Self> {
         let config_path = Path::new("./config.toml");
         let config = config::Config::builder()
             .add_source(config::File::from(config_path))
             .build()?;
 
-        Ok(config.try_deserialize()?)
+        let mut config: Self = config.try_deserialize()?;
+        for note in crate::endpoints::normalize_config(&mut config.endpoints).map_err(anyhow::Error::msg)? {
+            tracing::warn!("{}", note);
+        }
+        for (name, tenant) in &mut config.tenants {
+            if let Some(endpoints) = &mut tenant.endpoints {
+                let mut resolved = endpoints.resolve(&config.endpoints);
+                let notes = crate::endpoints::normalize_config(&mut resolved).map_err(|e| anyhow::anyhow!("tenants.{}.{}", name, e))?;
+                for note in notes {
+                    tracing::warn!("tenants.{}.{}", name, note);
+                }
+                endpoints.deepseek = resolved.deepseek;
+                endpoints.openai = resolved.openai;
+                endpoints.anthropic = resolved.anthropic;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings that deserialize but cannot be used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a `[[redaction]]` pattern is not a valid regex, a
+    /// `compat.forward_request_headers` pattern is empty or matches a header
+    /// the proxy sets itself, such as `Authorization`, a `[signing]`
+    /// section names an unset key variable or an invalid header, a
+    /// `[usage_webhooks]` endpoint is not HTTPS or its secret variable is unset,
+    /// an `[endpoints]` URL is not `http(s)`, `server.request_traces` is set
+    /// without `server.admin_token`, a token names an unknown tenant or
+    /// belongs to two tenants, or `[progress_callbacks]` allows hosts without
+    /// a set secret variable.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // trace 含有完整的上游请求和响应, 只对持有管理 token 的请求开放
+        if self.server.request_traces && self.server.admin_token.is_none() {
+            anyhow::bail!("server.request_traces requires server.admin_token");
+        }
+        crate::endpoints::normalize_config(&mut self.endpoints.clone()).map_err(anyhow::Error::msg)?;
+        for (name, tenant) in &self.tenants {
+            if let Some(endpoints) = &tenant.endpoints {
+                crate::endpoints::normalize_config(&mut endpoints.resolve(&self.endpoints)).map_err(|e| anyhow::anyhow!("tenants.{}.{}", name, e))?;
+            }
+        }
+        crate::tenants::Tenants::check(self).map_err(anyhow::Error::msg)?;
+        crate::reasoning_redaction::Redactor::compile(&self.redaction).map_err(anyhow::Error::msg)?;
+        crate::signing::Signers::from_config(&self.signing).map_err(anyhow::Error::msg)?;
+        crate::webhooks::UsageWebhooks::check(&self.usage_webhooks).map_err(anyhow::Error::msg)?;
+        crate::progress::ProgressCallbacks::check(&self.progress_callbacks).map_err(anyhow::Error::msg)?;
+        for rule in &self.compat.forward_request_headers {
+            crate::conversion::check_forwarded_pattern(rule.pattern()).map_err(anyhow::Error::msg)?;
+        }
+        Ok(())
     }
 }
 
@@ -102,26 +1627,69 @@ impl Default for Config {
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 3000,
+                stream_buffer: default_stream_buffer(),
+                stream_overflow: StreamOverflowPolicy::default(),
+                max_coalesce_bytes: 0,
+                max_coalesce_millis: default_max_coalesce_millis(),
+                idempotency_cache_size: default_idempotency_cache_size(),
+                max_concurrent_requests: None,
+                priority_aging_secs: default_priority_aging_secs(),
+                max_body_bytes: default_max_body_bytes(),
+                admin_token: None,
+                journal_size: default_journal_size(),
+                admin: None,
+                report_unknown_message_fields: false,
+                request_traces: false,
+                trace_store_size: default_trace_store_size(),
             },
             endpoints: EndpointConfig {
                 deepseek: "https://api.deepseek.com/v1/chat/completions".to_string(),
                 anthropic: "https://api.anthropic.com/v1/messages".to_string(),
                 openai: "https://api.openai.com/v1/chat/completions".to_string(),
+                allow_override: true,
+                allowed_hosts: Vec::new(),
+                completion_paths: CompletionPaths::default(),
             },
             models: ModelConfig {
                 default_deepseek: "deepseek-r1:14b".to_string(),
                 default_openai: "qwen2.5:14b".to_string(),
                 default_anthropic: "claude-3-sonnet-20240229".to_string(),
                 model_mappings: HashMap::new(),
+                passthrough_models: Vec::new(),
+                max_choices: default_max_choices(),
+                default_target_provider: TargetProvider::default(),
+                capabilities: HashMap::new(),
+                degrade_images: false,
+                allowed_reasoner_models: None,
             },
             auth: AuthConfig {
                 default_tokens: TokenConfig {
                     deepseek_token: "ollama".to_string(),
                     openai_token: "ollama".to_string(),
                     anthropic_token: "ollama".to_string(),
+                    priority: default_priority(),
+                    daily_budget_usd: None,
+                    monthly_budget_usd: None,
+                    tenant: None,
                 },
                 token_mappings: HashMap::new(),
             },
+            reasoning: ReasoningConfig::default(),
+            target: TargetConfig::default(),
+            streaming: StreamingConfig::default(),
+            compat: CompatConfig::default(),
+            budget: BudgetConfig::default(),
+            logging: LoggingConfig::default(),
+            parameter_policy: ParameterPolicyConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            speculation: SpeculationConfig::default(),
+            defaults: DefaultsConfig::default(),
+            profiles: HashMap::new(),
+            redaction: Vec::new(),
+            signing: SigningConfig::default(),
+            usage_webhooks: UsageWebhookConfig::default(),
+            tenants: HashMap::new(),
+            progress_callbacks: ProgressCallbackConfig::default(),
         }
     }
 }
@@ -133,6 +1701,12 @@ impl Default for ModelConfig {
             default_openai: "qwen2.5:14b".to_string(),
             default_anthropic: "claude-3-sonnet-20240229".to_string(),
             model_mappings: HashMap::new(),
+            passthrough_models: Vec::new(),
+            max_choices: default_max_choices(),
+            default_target_provider: TargetProvider::default(),
+            capabilities: HashMap::new(),
+            degrade_images: false,
+            allowed_reasoner_models: None,
         }
     }
 }
@@ -144,8 +1718,165 @@ impl Default for AuthConfig {
                 deepseek_token: "ollama".to_string(),
                 openai_token: "ollama".to_string(),
                 anthropic_token: "ollama".to_string(),
+                priority: default_priority(),
+                daily_budget_usd: None,
+                monthly_budget_usd: None,
+                tenant: None,
             },
             token_mappings: HashMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(overrides: serde_json::Value) -> ModelMapping {
+        let mut mapping = json!({"deepseek_model": "r1", "target_model": "qwen", "parameters": {}});
+        mapping.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+        serde_json::from_value(mapping).unwrap()
+    }
+
+    #[test]
+    fn sampling_defaults_to_the_fallback_temperature() {
+        let resolved = mapping(json!({})).sampling_parameters(Phase::Target, &json!({}));
+        assert_eq!(serde_json::Value::Object(resolved), json!({"temperature": 0.7}));
+    }
+
+    #[test]
+    fn phase_sections_override_the_shared_parameters() {
+        let mapping = mapping(json!({
+            "parameters": {"temperature": 0.5, "top_p": 0.8},
+            "reasoner": {"temperature": 0.6},
+            "target": {"top_p": 0.9},
+        }));
+        let reasoner = mapping.sampling_parameters(Phase::Reasoner, &json!({}));
+        let target = mapping.sampling_parameters(Phase::Target, &json!({}));
+        assert_eq!(serde_json::Value::Object(reasoner), json!({"temperature": 0.6, "top_p": 0.8}));
+        assert_eq!(serde_json::Value::Object(target), json!({"temperature": 0.5, "top_p": 0.9}));
+    }
+
+    #[test]
+    fn client_values_apply_to_the_configured_phases() {
+        let request = json!({"temperature": 0.1, "top_p": null});
+        let resolve = |policy: &str, phase| {
+            let mapping = mapping(json!({"reasoner": {"temperature": 0.6}, "client_temperature_applies_to": policy}));
+            mapping.sampling_parameters(phase, &request)["temperature"].clone()
+        };
+        assert_eq!(resolve("both", Phase::Reasoner), json!(0.1));
+        assert_eq!(resolve("both", Phase::Target), json!(0.1));
+        assert_eq!(resolve("target", Phase::Reasoner), json!(0.6));
+        assert_eq!(resolve("target", Phase::Target), json!(0.1));
+        assert_eq!(resolve("ignored", Phase::Reasoner), json!(0.6));
+        assert_eq!(resolve("ignored", Phase::Target), json!(0.7));
+    }
+
+    #[test]
+    fn the_default_target_provider_stays_anthropic() {
+        assert_eq!(Config::default().models.default_target_provider, TargetProvider::Anthropic);
+        assert_eq!(serde_json::from_value::<TargetProvider>(serde_json::json!("openai")).unwrap(), TargetProvider::OpenAI);
+        assert_eq!(serde_json::from_value::<TargetProvider>(serde_json::json!("anthropic")).unwrap(), TargetProvider::Anthropic);
+        assert_eq!(serde_json::from_value::<TargetProvider>(serde_json::json!("mistral")).unwrap(), TargetProvider::Mistral);
+        assert!(serde_json::from_value::<TargetProvider>(serde_json::json!("cohere")).is_err());
+    }
+
+    #[test]
+    fn providers_name_the_feature_that_compiles_their_client() {
+        for provider in [TargetProvider::Anthropic, TargetProvider::OpenAI, TargetProvider::Mistral] {
+            assert_eq!(TargetProvider::from_name(provider.as_str()), Some(provider));
+        }
+        assert_eq!(TargetProvider::from_name("cohere"), None);
+        assert_eq!(TargetProvider::Mistral.feature(), "openai");
+        assert_eq!(TargetProvider::Anthropic.is_compiled(), cfg!(feature = "anthropic"));
+        assert_eq!(TargetProvider::Mistral.is_compiled(), cfg!(feature = "openai"));
+        assert_eq!(TargetProvider::OpenAI.ensure_compiled().is_ok(), cfg!(feature = "openai"));
+    }
+
+    #[test]
+    fn cached_prompt_tokens_are_priced_at_the_cached_rate() {
+        let budget: BudgetConfig = serde_json::from_value(json!({
+            "pricing": {
+                "claude": {"prompt_usd_per_million": 3.0, "cached_prompt_usd_per_million": 0.3, "completion_usd_per_million": 15.0},
+                "gpt-4o": {"prompt_usd_per_million": 2.5, "completion_usd_per_million": 10.0},
+            },
+        }))
+        .unwrap();
+        let cost = budget.cost_with_cache("claude", 1_000_000, 800_000, 100_000);
+        assert!((cost - (0.2 * 3.0 + 0.8 * 0.3 + 0.1 * 15.0)).abs() < 1e-9, "{}", cost);
+        // 没有缓存价格时按 prompt 价格计费, 缓存数不会超过 prompt 数
+        let cost = budget.cost_with_cache("gpt-4o", 1_000_000, 2_000_000, 0);
+        assert!((cost - 2.5).abs() < 1e-9, "{}", cost);
+        assert_eq!(budget.cost_with_cache("claude", 1_000, 0, 0), budget.cost("claude", 1_000, 0));
+    }
+
+    #[test]
+    fn invalid_redaction_patterns_fail_validation() {
+        let mut config = Config {
+            redaction: serde_json::from_value(json!([{"pattern": "sk-\\w+"}])).unwrap(),
+            ..Config::default()
+        };
+        assert_eq!(config.redaction[0].replacement, "[redacted]");
+        config.validate().unwrap();
+
+        config.redaction.push(serde_json::from_value(json!({"pattern": "[a-", "replacement": "x"})).unwrap());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("redaction rule 2"), "{}", error);
+    }
+
+    #[test]
+    fn forwarded_header_patterns_are_validated() {
+        let mut config = Config {
+            compat: serde_json::from_value(json!({"forward_request_headers": ["x-helicone-*", {"pattern": "x-portkey-*", "to": "reasoner"}]}))
+                .unwrap(),
+            ..Config::default()
+        };
+        config.validate().unwrap();
+        assert!(config.compat.forward_request_headers[0].applies_to(Phase::Target));
+        assert!(!config.compat.forward_request_headers[1].applies_to(Phase::Target));
+
+        config.compat.forward_request_headers.push(ForwardedHeader::Pattern("x-api-*".into()));
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("x-api-key"), "{}", error);
+    }
+
+    #[test]
+    fn request_traces_require_an_admin_token() {
+        let mut config = Config::default();
+        config.server.request_traces = true;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("server.admin_token"), "{}", error);
+        config.server.admin_token = Some("admin-secret".to_string());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn tokens_must_name_a_configured_tenant() {
+        let mut config = Config::default();
+        let tokens = TokenConfig {
+            tenant: Some("team_a".to_string()),
+            ..config.auth.default_tokens.clone()
+        };
+        config.auth.token_mappings.insert("sk-a".to_string(), tokens);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("unknown tenant 'team_a'"), "{}", error);
+        config.tenants.insert("team_a".to_string(), TenantConfig::default());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn language_instructions_prefer_the_per_language_entry() {
+        let mut reasoning = ReasoningConfig::default();
+        assert_eq!(reasoning.language_instruction("English"), "Reason in English, regardless of the language of the conversation.");
+        reasoning.language_instructions.insert("English".to_string(), "Think step by step in English.".to_string());
+        assert_eq!(reasoning.language_instruction("English"), "Think step by step in English.");
+        assert_eq!(reasoning.language_instruction("French"), "Reason in French, regardless of the language of the conversation.");
+
+        let target = TargetConfig {
+            answer_language_instruction: "Antworte auf {language}.".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(target.answer_language_instruction("Deutsch"), "Antworte auf Deutsch.");
+    }
+}
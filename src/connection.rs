@@ -0,0 +1,299 @@
+//! Connection timing of the upstream calls.
+//!
+//! When latency spikes, the time of an upstream call has to be split between
+//! name resolution, connection setup and the provider itself. reqwest only
+//! has a hook for name resolution: the HTTP clients built by [`client`] time
+//! every DNS lookup through [`TimedResolver`], and the provider clients time
+//! each call from sending the request to receiving the response headers
+//! (TTFB, which includes any connection setup and the request write). The
+//! TCP, TLS and request-write components have no hook, so they stay empty in
+//! [`ConnectionTiming`] until one is available.
+//!
+//! Every sample goes into per-host histograms reported under
+//! `upstream_connections` on `/metrics`. The calls made by a request are also
+//! collected while it runs inside [`scope`] and reported in its timings.
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKET_BOUNDS_MS: [u64; 12] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+static HOSTS: Mutex<BTreeMap<String, HostHistograms>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    static REQUEST_CALLS: RefCell<RequestCalls>;
+}
+
+/// Timing of one upstream call, in milliseconds.
+///
+/// Components reqwest gives no hook for are `None`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConnectionTiming {
+    pub provider: String,
+    pub host: String,
+    /// Name resolution, when the call opened a new connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_ms: Option<u64>,
+    /// TCP connection setup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// TLS handshake.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_ms: Option<u64>,
+    /// Writing the request body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_write_ms: Option<u64>,
+    /// From sending the request to receiving the response headers.
+    pub ttfb_ms: u64,
+}
+
+/// Upstream calls of the request running in the current task.
+#[derive(Debug, Default)]
+struct RequestCalls {
+    /// Lookups not yet matched to a call, by host.
+    pending_dns: HashMap<String, u64>,
+    calls: Vec<ConnectionTiming>,
+}
+
+/// Latency histogram with fixed buckets.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, ms: u64) {
+        let bucket = BUCKET_BOUNDS_MS.iter().position(|&bound| ms <= bound).unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+
+    /// Returns the histogram with cumulative bucket counts, or `None` if it is empty.
+    fn snapshot(&self) -> Option<HistogramSnapshot> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut cumulative = 0;
+        let buckets = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(index, count)| {
+                cumulative += count;
+                HistogramBucket {
+                    le_ms: BUCKET_BOUNDS_MS.get(index).copied(),
+                    count: cumulative,
+                }
+            })
+            .collect();
+        Some(HistogramSnapshot {
+            count: self.count,
+            sum_ms: self.sum_ms,
+            buckets,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct HostHistograms {
+    dns: Histogram,
+    connect: Histogram,
+    tls: Histogram,
+    request_write: Histogram,
+    ttfb: Histogram,
+}
+
+/// One cumulative histogram bucket.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket; `None` for the overflow bucket.
+    pub le_ms: Option<u64>,
+    /// Samples at or below the bound.
+    pub count: u64,
+}
+
+/// A latency histogram as reported by `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum_ms: u64,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// Connection timing histograms of one upstream host.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostConnectionStats {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<HistogramSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect: Option<HistogramSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<HistogramSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_write: Option<HistogramSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttfb: Option<HistogramSnapshot>,
+}
+
+/// Returns the histograms of every upstream host, ordered by host.
+pub fn stats() -> Vec<HostConnectionStats> {
+    lock()
+        .iter()
+        .map(|(host, histograms)| HostConnectionStats {
+            host: host.clone(),
+            dns: histograms.dns.snapshot(),
+            connect: histograms.connect.snapshot(),
+            tls: histograms.tls.snapshot(),
+            request_write: histograms.request_write.snapshot(),
+            ttfb: histograms.ttfb.snapshot(),
+        })
+        .collect()
+}
+
+/// Runs `future` with its own list of upstream calls, read by [`calls`].
+pub fn scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    REQUEST_CALLS.scope(RefCell::new(RequestCalls::default()), future)
+}
+
+/// Returns the upstream calls made so far by the request in [`scope`].
+///
+/// Outside a scope, e.g. in background tasks, the list is empty.
+pub fn calls() -> Vec<ConnectionTiming> {
+    REQUEST_CALLS
+        .try_with(|calls| calls.borrow().calls.clone())
+        .unwrap_or_default()
+}
+
+/// Records the TTFB of a call to `url`, along with the DNS lookup it made, if any.
+pub fn record_call(provider: &str, url: &str, ttfb: Duration) {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let ttfb_ms = millis(ttfb);
+    lock().entry(host.clone()).or_default().ttfb.observe(ttfb_ms);
+    // 只在请求自己的任务中解析时才能对应到这次调用; 复用已有连接时没有 DNS 耗时
+    let _ = REQUEST_CALLS.try_with(|calls| {
+        let mut calls = calls.borrow_mut();
+        let dns_ms = calls.pending_dns.remove(&host);
+        calls.calls.push(ConnectionTiming {
+            provider: provider.to_string(),
+            host,
+            dns_ms,
+            connect_ms: None,
+            tls_ms: None,
+            request_write_ms: None,
+            ttfb_ms,
+        });
+    });
+}
+
+fn record_dns(host: &str, elapsed: Duration) {
+    let dns_ms = millis(elapsed);
+    lock().entry(host.to_string()).or_default().dns.observe(dns_ms);
+    let _ = REQUEST_CALLS.try_with(|calls| {
+        calls.borrow_mut().pending_dns.insert(host.to_string(), dns_ms);
+    });
+}
+
+/// DNS resolver that times every lookup.
+#[derive(Debug, Default)]
+pub struct TimedResolver;
+
+impl Resolve for TimedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let started = Instant::now();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            record_dns(&host, started.elapsed());
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Builds an HTTP client whose DNS lookups are timed.
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(TimedResolver))
+        .build()
+        .unwrap_or_default()
+}
+
+fn lock() -> std::sync::MutexGuard<'static, BTreeMap<String, HostHistograms>> {
+    HOSTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_count_cumulatively_per_bucket() {
+        let mut histogram = Histogram::default();
+        assert!(histogram.snapshot().is_none());
+        for ms in [3, 5, 7, 400, 60_000] {
+            histogram.observe(ms);
+        }
+        let snapshot = histogram.snapshot().unwrap();
+        assert_eq!((snapshot.count, snapshot.sum_ms), (5, 60_415));
+        let counts: Vec<(Option<u64>, u64)> = snapshot.buckets.iter().map(|bucket| (bucket.le_ms, bucket.count)).collect();
+        assert_eq!(counts[0], (Some(5), 2));
+        assert_eq!(counts[1], (Some(10), 3));
+        assert_eq!(counts[6], (Some(500), 4));
+        assert_eq!(counts.last(), Some(&(None, 5)));
+    }
+
+    #[tokio::test]
+    async fn calls_in_a_scope_carry_their_dns_lookup() {
+        let host = "calls-in-a-scope.test";
+        let calls = scope(async {
+            record_dns(host, Duration::from_millis(12));
+            record_call("openai", &format!("https://{}/v1/chat/completions", host), Duration::from_millis(80));
+            // 复用连接的调用没有 DNS 耗时
+            record_call("openai", &format!("https://{}/v1/chat/completions", host), Duration::from_millis(40));
+            calls()
+        })
+        .await;
+        let timing = |dns_ms, ttfb_ms| ConnectionTiming {
+            provider: "openai".to_string(),
+            host: host.to_string(),
+            dns_ms,
+            connect_ms: None,
+            tls_ms: None,
+            request_write_ms: None,
+            ttfb_ms,
+        };
+        assert_eq!(calls, [timing(Some(12), 80), timing(None, 40)]);
+
+        let stats = stats().into_iter().find(|stats| stats.host == host).unwrap();
+        assert_eq!(stats.dns.unwrap().count, 1);
+        assert_eq!(stats.ttfb.unwrap().sum_ms, 120);
+        assert!(stats.connect.is_none() && stats.tls.is_none() && stats.request_write.is_none());
+    }
+
+    #[test]
+    fn calls_outside_a_scope_are_only_aggregated() {
+        let host = "outside-a-scope.test";
+        record_call("deepseek", &format!("http://{}/chat/completions", host), Duration::from_millis(9));
+        assert!(calls().is_empty());
+        assert_eq!(stats().into_iter().find(|stats| stats.host == host).unwrap().ttfb.unwrap().count, 1);
+    }
+}
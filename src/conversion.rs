@@ -0,0 +1,1354 @@
+//! Conversions between the OpenAI-compatible wire format and the internal
+//! request and response types.
+//!
+//! Every top-level field of a chat completion request is either consumed
+//! into an `ApiRequest` field, forwarded into the upstream bodies, or dropped
+//! on purpose; [`CONSUMED_FIELDS`], [`FORWARDED_FIELDS`] and
+//! [`DROPPED_FIELDS`] list them. A field in none of the lists is logged as
+//! ignored, so a new parameter that was never wired through shows up instead
+//! of vanishing. The `deepthink` vendor options are parsed separately by
+//! [`crate::vendor`] and passed in.
+
+use crate::{
+    clients::{ANTHROPIC_ENDPOINT_URL_HEADER, DEEPSEEK_ENDPOINT_URL_HEADER, OPENAI_ENDPOINT_URL_HEADER},
+    config::{
+        default_confident_answer_min_chars, ClientTemperaturePolicy, ForwardedHeader, ModelConfig, ModelMapping, Phase,
+        PhaseParameters, ReasonerRouting, TokenConfig,
+    },
+    error::{ApiError, Result},
+    history::{self, TrimReport},
+    models::{
+        anthropic_tool_choice, anthropic_tools, ApiConfig, ApiRequest, ApiResponse, ContentBlock, LanguageInstructions, Message,
+        OpenAIDialect, OptimisticConfig, PipelineResult, ReasonerTranscript, ResponseChoice, StreamFormat, ThinkingFormat, ToolCall, UsageStats,
+        UseReasonerAnswer, REASONER_ANSWER_BLOCK_TYPE, TEXT_BLOCK_TYPE, THINKING_BLOCK_TYPE,
+    },
+    vendor::{self, VendorOptions},
+    tenants::TENANT_HEADER,
+    trace::{RequestTrace, ADMIN_TOKEN_HEADER},
+    warnings::{Warning, WarningCollector},
+};
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Response header listing content block types left out of a converted response.
+pub const BLOCK_WARNING_HEADER: &str = "X-DeepThink-Block-Warning";
+
+/// Separator between consecutive text blocks of one message.
+const TEXT_BLOCK_SEPARATOR: &str = "\n\n";
+
+/// What a content block becomes in the OpenAI-compatible response formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockRole {
+    /// Part of the message content.
+    Text,
+    /// The reasoning, placed according to the `thinking_format`.
+    Thinking,
+}
+
+/// The content block types the compatible formats render. Tool use blocks
+/// never reach them: they become tool calls when the target's response is
+/// read. Blocks of any other type are left out and reported by
+/// [`unknown_block_types`].
+const BLOCK_ROLES: &[(&str, BlockRole)] = &[
+    (TEXT_BLOCK_TYPE, BlockRole::Text),
+    (REASONER_ANSWER_BLOCK_TYPE, BlockRole::Text),
+    (THINKING_BLOCK_TYPE, BlockRole::Thinking),
+];
+
+fn block_role(block: &ContentBlock) -> Option<BlockRole> {
+    BLOCK_ROLES
+        .iter()
+        .find(|(content_type, _)| *content_type == block.content_type)
+        .map(|(_, role)| *role)
+}
+
+/// Joins the non-empty text of the blocks with `role`.
+///
+/// The reasoner's own answer is wrapped in `<reasoner_answer>` tags, as in
+/// streamed output, so it is not mistaken for the target's answer.
+///
+/// # Returns
+///
+/// * `Option<String>` - The joined text, or `None` if no block has `role`
+fn join_blocks(blocks: &[ContentBlock], role: BlockRole) -> Option<String> {
+    let mut blocks = blocks.iter().filter(|block| block_role(block) == Some(role)).peekable();
+    blocks.peek()?;
+    Some(
+        blocks
+            .filter(|block| !block.text.is_empty())
+            .map(|block| match block.content_type.as_str() {
+                REASONER_ANSWER_BLOCK_TYPE => format!("<reasoner_answer>\n{}\n</reasoner_answer>", block.text),
+                _ => block.text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(TEXT_BLOCK_SEPARATOR),
+    )
+}
+
+/// Returns the content block types of `response` that the compatible
+/// formats leave out, sorted and without duplicates.
+pub fn unknown_block_types(response: &ApiResponse) -> Vec<&str> {
+    let mut unknown: Vec<&str> = response
+        .choices
+        .iter()
+        .flat_map(|choice| &choice.content)
+        .filter(|block| block_role(block).is_none())
+        .map(|block| block.content_type.as_str())
+        .collect();
+    unknown.sort_unstable();
+    unknown.dedup();
+    unknown
+}
+
+/// Returns the indexes of the choices the compatible formats would send
+/// without an answer: no non-empty text block and no tool call.
+pub fn empty_choices(response: &ApiResponse) -> Vec<u32> {
+    response.choices.iter().filter(|choice| is_empty_choice(choice)).map(|choice| choice.index).collect()
+}
+
+/// Whether `choice` has no tool call and no answer text. The reasoning laid
+/// out in `<think>` tags and the reasoner's own answer block are text blocks
+/// in front of the answer, so they do not count.
+fn is_empty_choice(choice: &ResponseChoice) -> bool {
+    let is_reasoning = |block: &ContentBlock| block.text.starts_with("<think>") && block.text.ends_with("</think>");
+    choice.tool_calls.is_empty()
+        && choice
+            .content
+            .iter()
+            .filter(|block| block.content_type == TEXT_BLOCK_TYPE && !is_reasoning(block))
+            .all(|block| block.text.trim().is_empty())
+}
+
+/// Request fields read into `ApiRequest` fields; they never reach an upstream body.
+pub const CONSUMED_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "stream",
+    "stream_transport",
+    "n",
+    "metadata",
+    "conversation_id",
+    "injection_mode",
+    "injection_template",
+    "reasoner_answer",
+    "include_reasoning",
+    "thinking_format",
+    "include_timings",
+    "reasoning_timeout_secs",
+    "resumable",
+    "validate_json_schema",
+    "max_output_chars_per_second",
+    "max_reasoning_chars_per_second",
+    "reasoning_language",
+    "answer_language",
+];
+
+/// Request fields copied into the upstream bodies. The sampling parameters
+/// go to the phases `client_temperature_applies_to` selects, the rest to the
+/// target only.
+pub const FORWARDED_FIELDS: &[&str] = &[
+    "max_tokens",
+    "temperature",
+    "top_p",
+    "logprobs",
+    "top_logprobs",
+    "response_format",
+    "tools",
+    "tool_choice",
+    "safe_prompt",
+    "prediction",
+];
+
+/// Request fields deliberately left out of the internal request; the vendor
+/// options inside them are parsed by [`vendor::parse`].
+pub const DROPPED_FIELDS: &[&str] = &[vendor::NAMESPACE, "extra_body"];
+
+/// OpenAI compatible chat completion request format
+#[derive(Debug, Deserialize)]
+pub struct OpenAICompatRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(flatten)]
+    pub extra: serde_json::Value,
+}
+
+impl OpenAICompatRequest {
+    /// Returns the request's fields that no conversion handles, sorted.
+    pub fn ignored_fields(&self) -> Vec<&str> {
+        let mut ignored: Vec<&str> = self
+            .extra
+            .as_object()
+            .into_iter()
+            .flat_map(|extra| extra.keys())
+            .map(String::as_str)
+            .filter(|key| ![CONSUMED_FIELDS, FORWARDED_FIELDS, DROPPED_FIELDS].iter().any(|fields| fields.contains(key)))
+            .collect();
+        ignored.sort_unstable();
+        ignored
+    }
+
+    /// Converts the request into the internal request format.
+    ///
+    /// Merges the mapping's parameters with the request's fields and builds
+    /// the reasoner and target configurations.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_mapping` - The mapping resolved for the requested model
+    /// * `token_config` - The upstream tokens for the caller
+    /// * `options` - The request's `deepthink` vendor options, which take
+    ///   precedence over the top-level fields of the same name
+    /// * `anthropic_model` - The model an Anthropic target is called with
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ApiRequest>` - The internal request
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if a field such as `injection_mode` or
+    /// `tools` is malformed.
+    pub fn to_internal(
+        &self,
+        model_mapping: &ModelMapping,
+        token_config: &TokenConfig,
+        options: &VendorOptions,
+        anthropic_model: &str,
+    ) -> Result<ApiRequest> {
+        let ignored = self.ignored_fields();
+        if !ignored.is_empty() {
+            tracing::info!("Ignoring unsupported chat completion fields: {}", ignored.join(", "));
+        }
+
+        // 请求级别的推理注入策略优先于映射配置
+        let injection_mode = match self.extra.get("injection_mode") {
+            Some(value) => Some(serde_json::from_value(value.clone()).map_err(|e| ApiError::BadRequest {
+                message: format!("Invalid injection_mode: {}", e),
+            })?),
+            None => model_mapping.injection_mode,
+        };
+        let reasoner_answer = match self.extra.get("reasoner_answer") {
+            Some(value) => Some(serde_json::from_value(value.clone()).map_err(|e| ApiError::BadRequest {
+                message: format!("Invalid reasoner_answer: {}", e),
+            })?),
+            None => None,
+        };
+        let metadata = match self.extra.get("metadata") {
+            _ if options.metadata.is_some() => options.metadata.clone().unwrap_or_default(),
+            Some(value) => serde_json::from_value(value.clone()).map_err(|e| ApiError::BadRequest {
+                message: format!("Invalid metadata: expected an object of strings: {}", e),
+            })?,
+            None => HashMap::new(),
+        };
+        let conversation_id = match self.extra.get("conversation_id") {
+            Some(serde_json::Value::String(id)) => Some(id.clone()),
+            Some(other) => {
+                return Err(ApiError::BadRequest {
+                    message: format!("Invalid conversation_id: expected a string, got {}", other),
+                })
+            }
+            None => None,
+        };
+        let include_reasoning = match self.extra.get("include_reasoning") {
+            _ if options.include_reasoning.is_some() => options.include_reasoning,
+            Some(value) => Some(value.as_bool().ok_or_else(|| ApiError::BadRequest {
+                message: format!("Invalid include_reasoning: expected a boolean, got {}", value),
+            })?),
+            None => None,
+        };
+        let thinking_format = match self.extra.get("thinking_format") {
+            _ if options.thinking_format.is_some() => options.thinking_format.unwrap_or_default(),
+            Some(value) => serde_json::from_value(value.clone()).map_err(|e| ApiError::BadRequest {
+                message: format!("Invalid thinking_format: {}", e),
+            })?,
+            None => ThinkingFormat::default(),
+        };
+        // 供应商扩展字段: "deepthink": {"reasoner_model": "..."} 覆盖映射的 deepseek_model
+        let reasoner_model = options.reasoner_model.clone();
+        let injection_template = self
+            .extra
+            .get("injection_template")
+            .and_then(|t| t.as_str())
+            .map(String::from)
+            .or_else(|| model_mapping.injection_template.clone());
+
+        // 三个阶段共用的 max_tokens: 请求 > 映射参数 > 默认值
+        let max_tokens = self
+            .extra
+            .get("max_tokens")
+            .or_else(|| model_mapping.parameters.get("max_tokens"))
+            .cloned()
+            .unwrap_or(serde_json::json!(4096));
+
+        // 采样参数按阶段分别解析: 请求 > 映射分阶段配置 > 映射共享参数 > 默认值
+        let reasoner_sampling = model_mapping.sampling_parameters(Phase::Reasoner, &self.extra);
+        let target_sampling = model_mapping.sampling_parameters(Phase::Target, &self.extra);
+
+        // 透传 logprobs 等参数给 OpenAI 兼容的目标模型; prediction 只发给目标模型, 不进入推理阶段
+        let mut openai_body = serde_json::json!({
+            "model": model_mapping.target_model,
+            "max_tokens": max_tokens
+        });
+        merge_into(&mut openai_body, &target_sampling);
+        for key in ["logprobs", "top_logprobs", "response_format", "tools", "tool_choice", "safe_prompt", "prediction"] {
+            if let Some(value) = self.extra.get(key) {
+                openai_body[key] = value.clone();
+            }
+        }
+
+        // Anthropic 目标需要转换工具定义格式
+        let mut anthropic_body = serde_json::json!({
+            "model": anthropic_model,
+            "max_tokens": max_tokens
+        });
+        merge_into(&mut anthropic_body, &target_sampling);
+        if let Some(tools) = self.extra.get("tools") {
+            anthropic_body["tools"] = anthropic_tools(tools)?;
+        }
+        if let Some(tool_choice) = self.extra.get("tool_choice") {
+            anthropic_body["tool_choice"] = anthropic_tool_choice(tool_choice)?;
+        }
+
+        let mut deepseek_body = serde_json::json!({
+            "model": model_mapping.deepseek_model,
+            "max_tokens": max_tokens
+        });
+        merge_into(&mut deepseek_body, &reasoner_sampling);
+        if let Some(effort) = &options.reasoning_effort {
+            deepseek_body["reasoning_effort"] = serde_json::json!(effort);
+        }
+
+        // 超过映射的 max_messages 时先裁剪对话, 推理和目标模型收到相同的历史
+        let mut messages = self.messages.clone();
+        let trimmed = model_mapping
+            .max_messages
+            .and_then(|max_messages| history::trim(&mut messages, max_messages, model_mapping.keep_first_user_message));
+
+        // 构建内部请求格式
+        Ok(ApiRequest {
+            stream: self.stream,
+            verbose: false,
+            system: None,
+            messages,
+            deepseek_config: ApiConfig {
+                headers: HashMap::from([
+                    ("Authorization".to_string(), format!("Bearer {}", token_config.deepseek_token))
+                ]),
+                body: deepseek_body,
+            },
+            openai_config: ApiConfig {
+                headers: HashMap::from([
+                    ("Authorization".to_string(), format!("Bearer {}", token_config.openai_token))
+                ]),
+                body: openai_body,
+            },
+            anthropic_config: ApiConfig {
+                headers: HashMap::new(),
+                body: anthropic_body,
+            },
+            injection_mode,
+            injection_template,
+            max_output_chars_per_second: self.u32_field("max_output_chars_per_second"),
+            max_reasoning_chars_per_second: self.u32_field("max_reasoning_chars_per_second"),
+            n: self.u32_field("n"),
+            model: Some(self.model.clone()),
+            validate_json_schema: self
+                .extra
+                .get("validate_json_schema")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(model_mapping.validate_json_schema),
+            reasoner_answer,
+            // 非流式响应总是通过 X-DeepThink-Timing-* 头返回耗时, 流式响应按请求参数放入 metadata 帧
+            include_timings: !self.stream
+                || self
+                    .extra
+                    .get("include_timings")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            reasoning_timeout_secs: self
+                .extra
+                .get("reasoning_timeout_secs")
+                .and_then(|v| v.as_u64())
+                .or(model_mapping.reasoning_timeout_secs),
+            reasoner_model,
+            resumable: self
+                .extra
+                .get("resumable")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            thinking_format,
+            reasoner_transcript: model_mapping.reasoner_transcript,
+            reasoner_sees_system: model_mapping.reasoner_sees_system,
+            include_reasoning,
+            conversation_id,
+            metadata,
+            skip_reasoning: options.skip_reasoning,
+            stream_format: StreamFormat::default(),
+            optimistic: model_mapping.optimistic.enabled.then_some(model_mapping.optimistic),
+            openai_dialect: model_mapping.openai_dialect,
+            reasoner_dialect: model_mapping.reasoner_dialect,
+            trimmed,
+            trace: options.trace,
+            progress_callback_url: options.progress_callback_url.clone(),
+            reasoning_language: self.string_field("reasoning_language").or_else(|| model_mapping.reasoning_language.clone()),
+            answer_language: self.string_field("answer_language").or_else(|| model_mapping.answer_language.clone()),
+            language_instructions: LanguageInstructions::default(),
+            warnings: WarningCollector::default(),
+            replica: None,
+            tracer: None,
+            use_reasoner_answer: model_mapping.use_reasoner_answer,
+            confident_answer_min_chars: model_mapping.confident_answer_min_chars,
+            request_id: None,
+        })
+    }
+
+    fn u32_field(&self, key: &str) -> Option<u32> {
+        self.extra.get(key).and_then(|v| v.as_u64()).map(|v| v as u32)
+    }
+
+    fn string_field(&self, key: &str) -> Option<String> {
+        self.extra.get(key).and_then(|v| v.as_str()).map(str::to_string)
+    }
+}
+
+/// Resolves the model mapping a compat request runs with.
+///
+/// Falls back to `default_mapping`, then to a mapping built from the
+/// configured default models.
+pub fn resolve_mapping(model_config: &ModelConfig, model: &str, default_mapping: Option<&str>) -> ModelMapping {
+    model_config
+        .model_mappings
+        .get(model)
+        .or_else(|| default_mapping.and_then(|name| model_config.model_mappings.get(name)))
+        .cloned()
+        .unwrap_or_else(|| ModelMapping {
+            deepseek_model: model_config.default_deepseek.clone(),
+            target_model: model_config.default_openai.clone(),
+            parameters: serde_json::json!({}),
+            injection_mode: None,
+            injection_template: None,
+            passthrough: false,
+            validate_json_schema: false,
+            reasoner: PhaseParameters::default(),
+            target: PhaseParameters::default(),
+            client_temperature_applies_to: ClientTemperaturePolicy::default(),
+            reasoning_timeout_secs: None,
+            capabilities: None,
+            reasoner_transcript: ReasonerTranscript::default(),
+            reasoner_sees_system: None,
+            optimistic: OptimisticConfig::default(),
+            openai_dialect: OpenAIDialect::default(),
+            reasoner_dialect: None,
+            max_messages: None,
+            keep_first_user_message: true,
+            reasoner_endpoints: Vec::new(),
+            reasoner_routing: ReasonerRouting::default(),
+            use_reasoner_answer: UseReasonerAnswer::default(),
+            confident_answer_min_chars: default_confident_answer_min_chars(),
+            reasoning_language: None,
+            answer_language: None,
+        })
+}
+
+/// Headers the proxy sets on upstream requests itself, or uses to route and
+/// authenticate them; they are never forwarded from the caller.
+const PROTECTED_HEADERS: &[&str] = &[
+    "authorization",
+    "x-api-key",
+    "anthropic-version",
+    "host",
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "accept-encoding",
+    "x-deepseek-api-token",
+    "x-openai-api-token",
+    "x-anthropic-api-token",
+    "x-mistral-api-token",
+    "x-target-model",
+    DEEPSEEK_ENDPOINT_URL_HEADER,
+    OPENAI_ENDPOINT_URL_HEADER,
+    ANTHROPIC_ENDPOINT_URL_HEADER,
+    ADMIN_TOKEN_HEADER,
+    TENANT_HEADER,
+];
+
+/// Checks a `compat.forward_request_headers` pattern.
+///
+/// # Errors
+///
+/// Returns a message if the pattern is empty or would match one of the
+/// headers the proxy sets itself.
+pub fn check_forwarded_pattern(pattern: &str) -> std::result::Result<(), String> {
+    if pattern.trim_matches('*').is_empty() {
+        return Err(format!("forward_request_headers pattern {:?} must name a header prefix or suffix", pattern));
+    }
+    match PROTECTED_HEADERS.iter().find(|name| glob_matches(pattern, name)) {
+        Some(name) => Err(format!("forward_request_headers pattern {:?} would forward the {} header", pattern, name)),
+        None => Ok(()),
+    }
+}
+
+/// Copies the inbound headers matching `rules` onto the upstream requests.
+///
+/// Reasoner-bound headers go into `deepseek_config.headers`, target-bound
+/// ones into the OpenAI and Anthropic configs. Headers the proxy sets itself
+/// are skipped even if a rule matches them, and repeated headers are joined
+/// with `, `.
+pub fn forward_headers(request: &mut ApiRequest, inbound: &HeaderMap, rules: &[ForwardedHeader]) {
+    if rules.is_empty() {
+        return;
+    }
+    let mut forwarded: Vec<(&str, String, Vec<Phase>)> = Vec::new();
+    for name in inbound.keys() {
+        let name = name.as_str();
+        if PROTECTED_HEADERS.iter().any(|protected| protected.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let phases: Vec<Phase> = [Phase::Reasoner, Phase::Target]
+            .into_iter()
+            .filter(|&phase| rules.iter().any(|rule| rule.applies_to(phase) && glob_matches(rule.pattern(), name)))
+            .collect();
+        if phases.is_empty() {
+            continue;
+        }
+        let value = inbound
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(", ");
+        forwarded.push((name, value, phases));
+    }
+    for (name, value, phases) in forwarded {
+        if phases.contains(&Phase::Reasoner) {
+            request.deepseek_config.headers.insert(name.to_string(), value.clone());
+        }
+        if phases.contains(&Phase::Target) {
+            request.openai_config.headers.insert(name.to_string(), value.clone());
+            request.anthropic_config.headers.insert(name.to_string(), value);
+        }
+    }
+}
+
+/// Matches a header name against a glob pattern where `*` stands for any
+/// run of characters, ignoring case.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = parts.next().and_then(|prefix| name.strip_prefix(prefix)) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((suffix, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(suffix)
+}
+
+/// Copies every entry of `params` into the JSON object `body`.
+fn merge_into(body: &mut serde_json::Value, params: &serde_json::Map<String, serde_json::Value>) {
+    if let Some(body) = body.as_object_mut() {
+        body.extend(params.clone());
+    }
+}
+
+/// OpenAI compatible chat completion response format
+#[derive(Debug, Serialize)]
+pub struct OpenAICompatResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<OpenAICompatChoice>,
+    pub usage: OpenAICompatUsage,
+    /// The request's `metadata`, echoed back.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// What the mapping's `max_messages` trimmed from the conversation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trimmed: Option<TrimReport>,
+    /// Vendor extension listing the request's warnings.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    /// Vendor extension carrying the request's trace, when one was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<RequestTrace>,
+}
+
+impl OpenAICompatResponse {
+    /// Converts an internal response into a chat completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The internal response
+    /// * `id` - The completion id
+    /// * `created` - Creation time as a Unix timestamp
+    /// * `model` - The model name the client requested
+    /// * `thinking_format` - How the reasoning is laid out in each message
+    pub fn from_response(response: &ApiResponse, id: String, created: i64, model: String, thinking_format: ThinkingFormat) -> Self {
+        Self {
+            id,
+            object: "chat.completion".to_string(),
+            created,
+            model,
+            choices: response.choices.iter()
+                .map(|choice| OpenAICompatChoice {
+                    index: choice.index as i32,
+                    message: OpenAICompatMessage::from_choice(choice, thinking_format),
+                    logprobs: choice.logprobs.clone(),
+                    finish_reason: finish_reason(choice),
+                })
+                .collect(),
+            usage: OpenAICompatUsage::from(&response.usage),
+            metadata: response.metadata.clone(),
+            trimmed: response.trimmed,
+            warnings: response.warnings.clone(),
+            trace: response.trace.clone(),
+        }
+    }
+}
+
+/// Lays a pipeline result out as an internal response.
+///
+/// The reasoning becomes a thinking block in front of the answer, the usage
+/// is summed over both phases, and the timings are always included.
+impl From<PipelineResult> for ApiResponse {
+    fn from(result: PipelineResult) -> Self {
+        let usage = result.usage();
+        let content: Vec<ContentBlock> = Some(&result.reasoning.text)
+            .filter(|reasoning| !reasoning.is_empty())
+            .map(ContentBlock::thinking)
+            .into_iter()
+            .chain(result.answer.blocks)
+            .collect();
+        Self {
+            created: chrono::Utc::now(),
+            choices: vec![ResponseChoice {
+                index: 0,
+                content: content.clone(),
+                logprobs: None,
+                finish_reason: result.answer.finish_reason,
+                tool_calls: Vec::new(),
+            }],
+            content,
+            tool_calls: Vec::new(),
+            usage,
+            deepseek_response: None,
+            target_response: None,
+            timings: Some(result.timings.clone()),
+            reasoner_model: Some(result.reasoning.model),
+            reasoner_endpoint: None,
+            reasoning_truncated: result.reasoning.truncated,
+            empty_answer: false,
+            optimistic_path: None,
+            answered_by: None,
+            reasoning_source: None,
+            redactions: 0,
+            trimmed: None,
+            warnings: result.warnings,
+            metadata: HashMap::new(),
+            trace: None,
+            phase_timings: result.timings,
+            target_provider: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAICompatChoice {
+    pub index: i32,
+    pub message: OpenAICompatMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAICompatMessage {
+    pub role: String,
+    pub content: OpenAICompatContent,
+    /// The reasoning, when the request set `thinking_format: "reasoning_content"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Message content: a plain string, or a parts array for `thinking_format: "content_part"`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum OpenAICompatContent {
+    Text(String),
+    Parts(Vec<OpenAICompatContentPart>),
+}
+
+/// One part of a parts-array message content.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpenAICompatContentPart {
+    Thinking { thinking: String },
+    Text { text: String },
+}
+
+impl OpenAICompatMessage {
+    /// Builds the assistant message for one choice in the requested thinking format.
+    ///
+    /// Thinking blocks only exist for the structured formats; with `tag` the
+    /// reasoning is already inline in the text blocks. Text blocks are joined
+    /// with blank lines, and blocks of types the format cannot carry are left out.
+    fn from_choice(choice: &ResponseChoice, thinking_format: ThinkingFormat) -> Self {
+        let thinking = join_blocks(&choice.content, BlockRole::Thinking);
+        let text = join_blocks(&choice.content, BlockRole::Text).unwrap_or_default();
+        let (content, reasoning_content) = match thinking_format {
+            ThinkingFormat::Tag | ThinkingFormat::ReasoningContent => (OpenAICompatContent::Text(text), thinking),
+            ThinkingFormat::ContentPart => {
+                let parts = thinking
+                    .map(|thinking| OpenAICompatContentPart::Thinking { thinking })
+                    .into_iter()
+                    .chain(Some(OpenAICompatContentPart::Text { text }))
+                    .collect();
+                (OpenAICompatContent::Parts(parts), None)
+            }
+        };
+        Self {
+            role: "assistant".to_string(),
+            content,
+            reasoning_content,
+            tool_calls: choice.tool_calls.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAICompatUsage {
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    pub total_tokens: i32,
+    /// Predicted output counts, present when the request sent a `prediction`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<OpenAICompatCompletionDetails>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAICompatCompletionDetails {
+    pub accepted_prediction_tokens: i32,
+    pub rejected_prediction_tokens: i32,
+}
+
+impl From<&UsageStats> for OpenAICompatUsage {
+    fn from(usage: &UsageStats) -> Self {
+        let predicted = usage.accepted_prediction_tokens + usage.rejected_prediction_tokens > 0;
+        Self {
+            prompt_tokens: usage.prompt_tokens as i32,
+            completion_tokens: usage.completion_tokens as i32,
+            total_tokens: usage.total_tokens as i32,
+            completion_tokens_details: predicted.then_some(OpenAICompatCompletionDetails {
+                accepted_prediction_tokens: usage.accepted_prediction_tokens as i32,
+                rejected_prediction_tokens: usage.rejected_prediction_tokens as i32,
+            }),
+        }
+    }
+}
+
+/// Legacy `/v1/completions` response format
+#[derive(Debug, Serialize)]
+pub struct LegacyCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<LegacyCompletionChoice>,
+    pub usage: OpenAICompatUsage,
+}
+
+impl LegacyCompletionResponse {
+    /// Converts an internal response into a text completion, joining each
+    /// choice's text blocks into its `text`. Thinking blocks have no place in
+    /// a text completion and are left out, like blocks of unknown types.
+    pub fn from_response(response: &ApiResponse, id: String, created: i64, model: String) -> Self {
+        Self {
+            id,
+            object: "text_completion".to_string(),
+            created,
+            model,
+            choices: response.choices.iter()
+                .map(|choice| LegacyCompletionChoice {
+                    text: join_blocks(&choice.content, BlockRole::Text).unwrap_or_default(),
+                    index: choice.index as i32,
+                    logprobs: choice.logprobs.clone(),
+                    finish_reason: finish_reason(choice),
+                })
+                .collect(),
+            usage: OpenAICompatUsage::from(&response.usage),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LegacyCompletionChoice {
+    pub text: String,
+    pub index: i32,
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: String,
+}
+
+/// Returns the finish reason of a choice; an answer-less choice that claims
+/// (or defaults to) `stop` reports `error`, since it did not finish normally.
+fn finish_reason(choice: &ResponseChoice) -> String {
+    match choice.finish_reason.as_deref() {
+        None | Some("stop") if is_empty_choice(choice) => "error".to_string(),
+        _ => choice.finish_reason.clone().unwrap_or_else(|| "stop".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{AnswerOutcome, FunctionCall, InjectionMode, ReasonerAnswerMode, ReasoningOutcome, THINKING_BLOCK_TYPE};
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn request(body: serde_json::Value) -> OpenAICompatRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn mapping() -> ModelMapping {
+        serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1",
+            "target_model": "gpt-4o",
+            "parameters": {"max_tokens": 2048, "temperature": 0.3},
+        }))
+        .unwrap()
+    }
+
+    fn tokens() -> TokenConfig {
+        serde_json::from_value(json!({"deepseek_token": "ds", "openai_token": "oa", "anthropic_token": "an"})).unwrap()
+    }
+
+    fn convert(body: serde_json::Value) -> Result<ApiRequest> {
+        request(body).to_internal(&mapping(), &tokens(), &VendorOptions::default(), "claude-3-5-sonnet")
+    }
+
+    fn response(choices: Vec<ResponseChoice>) -> ApiResponse {
+        ApiResponse {
+            created: Utc::now(),
+            content: choices[0].content.clone(),
+            choices,
+            tool_calls: Vec::new(),
+            usage: UsageStats { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15, ..Default::default() },
+            deepseek_response: None,
+            target_response: None,
+            timings: None,
+            reasoner_model: None,
+            reasoning_truncated: false,
+            empty_answer: false,
+            optimistic_path: None,
+            reasoning_source: None,
+            redactions: 0,
+            answered_by: None,
+            trimmed: None,
+            warnings: Vec::new(),
+            reasoner_endpoint: None,
+            trace: None,
+            metadata: HashMap::from([("trace".to_string(), "t1".to_string())]),
+            phase_timings: Default::default(),
+            target_provider: "anthropic".to_string(),
+        }
+    }
+
+    fn choice(content: Vec<ContentBlock>, finish_reason: Option<&str>) -> ResponseChoice {
+        ResponseChoice { index: 0, content, logprobs: None, finish_reason: finish_reason.map(String::from), tool_calls: Vec::new() }
+    }
+
+    fn block(content_type: &str, text: &str) -> ContentBlock {
+        ContentBlock { content_type: content_type.to_string(), text: text.to_string() }
+    }
+
+    /// A request setting every field the conversion knows about.
+    fn full_request() -> serde_json::Value {
+        json!({
+            "model": "deepthink",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true,
+            "stream_transport": "sse",
+            "n": 2,
+            "metadata": {"trace": "t1"},
+            "conversation_id": "c1",
+            "injection_mode": "user_context",
+            "injection_template": "<r>{reasoning}</r>",
+            "reasoner_answer": "return_as_block",
+            "include_reasoning": false,
+            "thinking_format": "reasoning_content",
+            "include_timings": true,
+            "reasoning_timeout_secs": 30,
+            "resumable": true,
+            "validate_json_schema": true,
+            "max_output_chars_per_second": 100,
+            "max_reasoning_chars_per_second": 50,
+            "reasoning_language": "English",
+            "answer_language": "German",
+            "max_tokens": 512,
+            "temperature": 0.9,
+            "top_p": 0.5,
+            "logprobs": true,
+            "top_logprobs": 3,
+            "response_format": {"type": "json_object"},
+            "tools": [{"type": "function", "function": {"name": "lookup", "parameters": {"type": "object"}}}],
+            "tool_choice": "auto",
+            "safe_prompt": true,
+            "prediction": {"type": "content", "content": "Hi"},
+            "deepthink": {},
+            "extra_body": {},
+        })
+    }
+
+    #[test]
+    fn every_field_is_classified_exactly_once() {
+        let lists = [CONSUMED_FIELDS, FORWARDED_FIELDS, DROPPED_FIELDS];
+        let all: Vec<&str> = lists.concat();
+        let mut unique = all.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), all.len(), "a field is listed twice: {:?}", all);
+
+        // full_request 覆盖每个列出的字段
+        let full = full_request();
+        let listed: Vec<&str> = full.as_object().unwrap().keys().map(String::as_str).collect();
+        for field in &all {
+            assert!(listed.contains(field), "{} missing from full_request", field);
+        }
+        assert!(request(full).ignored_fields().is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_reported_as_ignored() {
+        let request = request(json!({"model": "m", "messages": [], "user": "u1", "frequency_penalty": 1}));
+        assert_eq!(request.ignored_fields(), vec!["frequency_penalty", "user"]);
+    }
+
+    #[test]
+    fn consumed_fields_become_request_fields() {
+        let internal = convert(full_request()).unwrap();
+        assert!(internal.stream);
+        assert_eq!(internal.messages.len(), 1);
+        assert_eq!(internal.n, Some(2));
+        assert_eq!(internal.model.as_deref(), Some("deepthink"));
+        assert_eq!(internal.metadata, HashMap::from([("trace".to_string(), "t1".to_string())]));
+        assert_eq!(internal.conversation_id.as_deref(), Some("c1"));
+        assert_eq!(internal.injection_mode, Some(InjectionMode::UserContext));
+        assert_eq!(internal.injection_template.as_deref(), Some("<r>{reasoning}</r>"));
+        assert_eq!(internal.reasoner_answer, Some(ReasonerAnswerMode::ReturnAsBlock));
+        assert_eq!(internal.include_reasoning, Some(false));
+        assert_eq!(internal.thinking_format, ThinkingFormat::ReasoningContent);
+        assert!(internal.include_timings);
+        assert_eq!(internal.reasoning_timeout_secs, Some(30));
+        assert!(internal.resumable);
+        assert!(internal.validate_json_schema);
+        assert_eq!(internal.max_output_chars_per_second, Some(100));
+        assert_eq!(internal.max_reasoning_chars_per_second, Some(50));
+        assert_eq!(internal.deepseek_config.headers["Authorization"], "Bearer ds");
+        assert_eq!(internal.openai_config.headers["Authorization"], "Bearer oa");
+
+        // 消费的字段不会出现在任何上游请求体中
+        for body in [&internal.deepseek_config.body, &internal.openai_config.body, &internal.anthropic_config.body] {
+            for field in CONSUMED_FIELDS.iter().chain(DROPPED_FIELDS).filter(|field| **field != "model") {
+                assert!(body.get(*field).is_none(), "{} leaked into {}", field, body);
+            }
+        }
+    }
+
+    #[test]
+    fn forwarded_fields_reach_the_target_body() {
+        let full = full_request();
+        let internal = convert(full.clone()).unwrap();
+        let openai = &internal.openai_config.body;
+        assert_eq!(openai["model"], "gpt-4o");
+        for field in FORWARDED_FIELDS {
+            assert_eq!(openai[*field], full[*field], "{}", field);
+        }
+        let anthropic = &internal.anthropic_config.body;
+        assert_eq!(anthropic["model"], "claude-3-5-sonnet");
+        assert_eq!(anthropic["max_tokens"], 512);
+        assert_eq!(anthropic["tools"][0]["name"], "lookup");
+        assert_eq!(anthropic["tool_choice"]["type"], "auto");
+        // 推理模型只收到共用的 max_tokens 和采样参数
+        let deepseek = &internal.deepseek_config.body;
+        assert_eq!(deepseek["model"], "deepseek-r1");
+        assert_eq!(deepseek["max_tokens"], 512);
+        assert!(deepseek.get("tools").is_none() && deepseek.get("logprobs").is_none(), "{}", deepseek);
+    }
+
+    #[test]
+    fn mapping_parameters_fill_in_missing_fields() {
+        let internal = convert(json!({"model": "deepthink", "messages": []})).unwrap();
+        assert!(!internal.stream);
+        // 非流式请求总是返回耗时
+        assert!(internal.include_timings);
+        assert_eq!(internal.openai_config.body["max_tokens"], 2048);
+        assert_eq!(internal.openai_config.body["temperature"], 0.3);
+        assert_eq!(internal.deepseek_config.body["max_tokens"], 2048);
+        assert_eq!(internal.thinking_format, ThinkingFormat::default());
+        assert!(internal.metadata.is_empty());
+    }
+
+    #[test]
+    fn vendor_options_override_top_level_fields() {
+        let options: VendorOptions = serde_json::from_value(json!({
+            "include_reasoning": true,
+            "thinking_format": "content_part",
+            "metadata": {"from": "vendor"},
+            "reasoning_effort": "high",
+        }))
+        .unwrap();
+        let internal = request(full_request()).to_internal(&mapping(), &tokens(), &options, "claude").unwrap();
+        assert_eq!(internal.include_reasoning, Some(true));
+        assert_eq!(internal.thinking_format, ThinkingFormat::ContentPart);
+        assert_eq!(internal.metadata["from"], "vendor");
+        assert_eq!(internal.deepseek_config.body["reasoning_effort"], "high");
+    }
+
+    #[test]
+    fn malformed_fields_are_rejected() {
+        for (field, value) in [
+            ("injection_mode", json!("sideways")),
+            ("reasoner_answer", json!(3)),
+            ("metadata", json!({"n": 1})),
+            ("conversation_id", json!(7)),
+            ("include_reasoning", json!("yes")),
+            ("thinking_format", json!("xml")),
+        ] {
+            let mut body = json!({"model": "deepthink", "messages": []});
+            body[field] = value;
+            let error = convert(body).unwrap_err();
+            assert!(matches!(error, ApiError::BadRequest { ref message } if message.contains(field)), "{}: {:?}", field, error);
+        }
+    }
+
+    #[test]
+    fn unmapped_models_fall_back_to_the_defaults() {
+        let mut config = ModelConfig::default();
+        config.model_mappings.insert("fast".to_string(), mapping());
+        assert_eq!(resolve_mapping(&config, "fast", None).target_model, "gpt-4o");
+        assert_eq!(resolve_mapping(&config, "other", Some("fast")).target_model, "gpt-4o");
+        let fallback = resolve_mapping(&config, "other", None);
+        assert_eq!(fallback.deepseek_model, config.default_deepseek);
+        assert_eq!(fallback.target_model, config.default_openai);
+    }
+
+    #[test]
+    fn responses_lay_out_the_reasoning_in_the_requested_format() {
+        let content = vec![block(THINKING_BLOCK_TYPE, "plan"), block("text", "Paris")];
+        let response = response(vec![choice(content, None)]);
+        let completion = |format| {
+            serde_json::to_value(OpenAICompatResponse::from_response(&response, "id".into(), 1, "deepthink".into(), format)).unwrap()
+        };
+
+        let body = completion(ThinkingFormat::ReasoningContent);
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["model"], "deepthink");
+        assert_eq!(body["choices"][0]["message"], json!({"role": "assistant", "content": "Paris", "reasoning_content": "plan"}));
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"], json!({"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}));
+        assert_eq!(body["metadata"], json!({"trace": "t1"}));
+
+        let body = completion(ThinkingFormat::ContentPart);
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            json!([{"type": "thinking", "thinking": "plan"}, {"type": "text", "text": "Paris"}])
+        );
+        assert!(body["choices"][0]["message"].get("reasoning_content").is_none());
+    }
+
+    #[test]
+    fn block_combinations_become_reasoning_and_content() {
+        // (块, reasoning_content 格式下的 reasoning_content, content)
+        type Case<'a> = (&'a [(&'a str, &'a str)], Option<&'a str>, &'a str);
+        let cases: &[Case] = &[
+            (&[("text", "Paris")], None, "Paris"),
+            (&[("text", "Paris"), ("text", "France")], None, "Paris\n\nFrance"),
+            (&[("text", ""), ("text", "Paris")], None, "Paris"),
+            (&[(THINKING_BLOCK_TYPE, "plan"), ("text", "Paris")], Some("plan"), "Paris"),
+            (&[(THINKING_BLOCK_TYPE, "a"), (THINKING_BLOCK_TYPE, "b")], Some("a\n\nb"), ""),
+            (&[("image", "ignored"), ("text", "Paris")], None, "Paris"),
+            (&[(REASONER_ANSWER_BLOCK_TYPE, "Rome"), ("text", "Paris")], None, "<reasoner_answer>\nRome\n</reasoner_answer>\n\nParis"),
+        ];
+        for (blocks, reasoning, content) in cases {
+            let choice = choice(blocks.iter().map(|(content_type, text)| block(content_type, text)).collect(), None);
+            let message = OpenAICompatMessage::from_choice(&choice, ThinkingFormat::ReasoningContent);
+            let message = serde_json::to_value(message).unwrap();
+            assert_eq!(message["content"], *content, "{:?}", blocks);
+            assert_eq!(message["reasoning_content"].as_str(), *reasoning, "{:?}", blocks);
+        }
+    }
+
+    #[test]
+    fn unknown_block_types_are_listed_once_in_order() {
+        let response = response(vec![
+            choice(vec![block("text", "Paris"), block("image", ""), block("citation", "")], None),
+            choice(vec![block(THINKING_BLOCK_TYPE, "plan"), block("image", "")], None),
+        ]);
+        assert_eq!(unknown_block_types(&response), ["citation", "image"]);
+        assert!(unknown_block_types(&self::response(vec![choice(vec![block("text", "Paris")], None)])).is_empty());
+    }
+
+    #[test]
+    fn reasoner_answers_are_wrapped_in_their_tag() {
+        let content = vec![block("text", "<thinking>plan</thinking>"), block(REASONER_ANSWER_BLOCK_TYPE, "Rome"), block("text", "Paris")];
+        let response = response(vec![choice(content, Some("length"))]);
+        let body = serde_json::to_value(OpenAICompatResponse::from_response(&response, "id".into(), 1, "m".into(), ThinkingFormat::Tag)).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "<thinking>plan</thinking>\n\n<reasoner_answer>\nRome\n</reasoner_answer>\n\nParis");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+    }
+
+    #[test]
+    fn legacy_completions_join_the_content_blocks() {
+        let response = response(vec![choice(vec![block("text", "Hello"), block("text", "world")], None)]);
+        let body = serde_json::to_value(LegacyCompletionResponse::from_response(&response, "id".into(), 1, "m".into())).unwrap();
+        assert_eq!(body["object"], "text_completion");
+        assert_eq!(body["choices"][0]["text"], "Hello\n\nworld");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"]["total_tokens"], 15);
+    }
+
+    #[test]
+    fn header_globs_match_case_insensitively() {
+        assert!(glob_matches("x-helicone-*", "X-Helicone-Auth"));
+        assert!(glob_matches("*-trace-id", "x-gateway-trace-id"));
+        assert!(glob_matches("x-*-id", "x-portkey-trace-id"));
+        assert!(glob_matches("x-exact", "x-exact"));
+        assert!(!glob_matches("x-exact", "x-exact-not"));
+        assert!(!glob_matches("x-helicone-*", "x-portkey-key"));
+    }
+
+    #[test]
+    fn patterns_that_reach_protected_headers_are_rejected() {
+        check_forwarded_pattern("x-helicone-*").unwrap();
+        for pattern in ["*", "**", "authorization", "Authoriz*", "x-*-api-token", "x-*-endpoint-url", "x-api-*"] {
+            assert!(check_forwarded_pattern(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn forwarded_headers_reach_the_phases_their_rule_names() {
+        let rules: Vec<ForwardedHeader> =
+            serde_json::from_value(json!(["x-helicone-*", {"pattern": "x-portkey-*", "to": "target"}, "authorization"])).unwrap();
+        let mut inbound = HeaderMap::new();
+        inbound.insert("x-helicone-auth", "h".parse().unwrap());
+        inbound.append("x-portkey-trace", "a".parse().unwrap());
+        inbound.append("x-portkey-trace", "b".parse().unwrap());
+        inbound.insert("authorization", "Bearer caller".parse().unwrap());
+        inbound.insert("x-other", "o".parse().unwrap());
+        let mut request = convert(json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]})).unwrap();
+        forward_headers(&mut request, &inbound, &rules);
+
+        let reasoner = &request.deepseek_config.headers;
+        assert_eq!(reasoner["x-helicone-auth"], "h");
+        assert!(!reasoner.contains_key("x-portkey-trace"));
+        for target in [&request.openai_config.headers, &request.anthropic_config.headers] {
+            assert_eq!(target["x-helicone-auth"], "h");
+            assert_eq!(target["x-portkey-trace"], "a, b");
+        }
+        for headers in [reasoner, &request.openai_config.headers, &request.anthropic_config.headers] {
+            assert!(!headers.contains_key("authorization") && !headers.contains_key("x-other"));
+        }
+    }
+
+    /// Request bodies as sent by common client libraries, which must all be
+    /// accepted by the compat endpoint.
+    fn sdk_corpus() -> Vec<(&'static str, serde_json::Value)> {
+        vec![
+            ("openai-python", json!({
+                "messages": [
+                    {"role": "system", "content": "You are a helpful assistant."},
+                    {"role": "user", "content": "What's the weather in Paris?"},
+                    {"role": "assistant", "content": null, "function_call": null, "tool_calls": [
+                        {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}},
+                    ]},
+                    {"role": "tool", "tool_call_id": "call_1", "content": "18C, sunny"},
+                ],
+                "model": "deepthink",
+                "temperature": 0.2,
+            })),
+            ("openai-node", json!({
+                "model": "deepthink",
+                "messages": [{"role": "developer", "content": "Answer tersely."}, {"role": "user", "content": [{"type": "text", "text": "Hi"}]}],
+                "stream": true,
+                "stream_options": {"include_usage": true},
+            })),
+            ("langchain", json!({
+                "messages": [
+                    {"content": "You are a helpful assistant.", "role": "system"},
+                    {"content": "Hello", "role": "user", "name": "alice"},
+                    {"content": "Hi Alice!", "role": "assistant", "name": "bot"},
+                    {"content": "Tell me a joke", "role": "user"},
+                ],
+                "model": "deepthink",
+                "stream": false,
+                "n": 1,
+                "temperature": 0.7,
+            })),
+            ("llamaindex", json!({
+                "messages": [
+                    {"role": "system", "content": "Answer using the context."},
+                    {"role": "user", "content": "Context: ...\nQuestion: ...", "additional_kwargs": {}},
+                ],
+                "model": "deepthink",
+                "stream": false,
+                "temperature": 0.1,
+                "max_tokens": 512,
+            })),
+            ("vercel-ai-sdk", json!({
+                "model": "deepthink",
+                "messages": [
+                    {"role": "system", "content": "You are a helpful assistant."},
+                    {"role": "user", "content": [{"type": "text", "text": "Weather?"}]},
+                    {"role": "assistant", "content": "", "tool_calls": [
+                        {"id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{}"}},
+                    ]},
+                    {"role": "tool", "tool_call_id": "call_1", "content": "{\"temperature\":18}"},
+                ],
+                "max_tokens": 1024,
+                "temperature": 0,
+            })),
+            ("capitalized-roles", json!({
+                "model": "deepthink",
+                "messages": [{"Role": "SYSTEM", "content": "Be brief."}, {"role": "User", "content": "Hi"}],
+            })),
+        ]
+    }
+
+    #[test]
+    fn requests_from_common_sdks_are_accepted() {
+        for (client, body) in sdk_corpus() {
+            let request = serde_json::from_value::<OpenAICompatRequest>(body).unwrap_or_else(|e| panic!("{}: {}", client, e));
+            let converted = request
+                .to_internal(&mapping(), &tokens(), &VendorOptions::default(), "claude-3-5-sonnet")
+                .unwrap_or_else(|e| panic!("{}: {}", client, e));
+            let messages = serde_json::to_value(&converted.messages).unwrap();
+            for message in messages.as_array().unwrap() {
+                let role = message["role"].as_str().unwrap();
+                assert_eq!(role, role.to_ascii_lowercase(), "{}", client);
+            }
+        }
+    }
+
+    /// Returns the response of a pipeline whose target answered with `blocks`.
+    fn answered(blocks: Vec<ContentBlock>, finish_reason: Option<&str>) -> ApiResponse {
+        ApiResponse::from(PipelineResult {
+            reasoning: ReasoningOutcome {
+                text: String::new(),
+                model: "deepseek-reasoner".to_string(),
+                usage: UsageStats::default(),
+                truncated: false,
+            },
+            answer: AnswerOutcome {
+                blocks,
+                finish_reason: finish_reason.map(str::to_string),
+                model: "gpt-4o".to_string(),
+                usage: UsageStats::default(),
+            },
+            timings: Default::default(),
+            warnings: Vec::new(),
+        })
+    }
+
+    /// Converts `response` into a chat completion in `thinking_format` and returns it as JSON.
+    fn completion(response: &ApiResponse, thinking_format: ThinkingFormat) -> serde_json::Value {
+        let completion = OpenAICompatResponse::from_response(response, "chatcmpl-1".to_string(), 0, "m".to_string(), thinking_format);
+        serde_json::to_value(completion).unwrap()
+    }
+
+    #[test]
+    fn pipeline_results_put_the_reasoning_in_front_of_the_answer() {
+        let mut usage = UsageStats::default();
+        usage.add(10, 20);
+        let result = PipelineResult {
+            reasoning: ReasoningOutcome {
+                text: "Think first.".to_string(),
+                model: "deepseek-reasoner".to_string(),
+                usage: usage.clone(),
+                truncated: true,
+            },
+            answer: AnswerOutcome {
+                blocks: vec![ContentBlock::text("Answer.")],
+                finish_reason: Some("stop".to_string()),
+                model: "gpt-4o".to_string(),
+                usage,
+            },
+            timings: Default::default(),
+            warnings: Vec::new(),
+        };
+        let response = ApiResponse::from(result);
+        assert_eq!(response.content.len(), 2);
+        assert_eq!(response.content[0].content_type, THINKING_BLOCK_TYPE);
+        assert_eq!(response.content[1].text, "Answer.");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.usage.total_tokens, 60);
+        assert!(response.reasoning_truncated);
+        assert_eq!(response.reasoner_model.as_deref(), Some("deepseek-reasoner"));
+
+        let completion = OpenAICompatResponse::from_response(&response, "chatcmpl-1".to_string(), 0, "m".to_string(), ThinkingFormat::ReasoningContent);
+        assert_eq!(completion.choices[0].message.reasoning_content.as_deref(), Some("Think first."));
+        assert_eq!(completion.usage.total_tokens, 60);
+    }
+
+    #[test]
+    fn empty_answers_report_why_they_stopped() {
+        let response = answered(Vec::new(), None);
+        assert_eq!(empty_choices(&response), vec![0]);
+        let choice = &completion(&response, ThinkingFormat::Tag)["choices"][0];
+        assert_eq!(choice["message"]["content"], "");
+        assert_eq!(choice["finish_reason"], "error");
+        let legacy = LegacyCompletionResponse::from_response(&response, "cmpl-1".to_string(), 0, "m".to_string());
+        assert_eq!(legacy.choices[0].text, "");
+
+        // Tag 格式下推理是回答前的文本块, 不算作回答
+        let mut tagged = answered(vec![ContentBlock::text(" ")], Some("stop"));
+        tagged.choices[0].content.insert(0, ContentBlock::text("<think>\nThink first.\n</think>"));
+        assert_eq!(empty_choices(&tagged), vec![0]);
+
+        // 目标模型给出的非 stop 原因保持不变
+        let response = answered(Vec::new(), Some("length"));
+        assert_eq!(completion(&response, ThinkingFormat::Tag)["choices"][0]["finish_reason"], "length");
+    }
+
+    #[test]
+    fn single_malformed_blocks_convert_to_empty_answers() {
+        let blank = ContentBlock::text("  \n");
+        let unknown = ContentBlock { content_type: "image".to_string(), text: String::new() };
+        let thinking_only = ContentBlock::thinking("Think first.");
+        for block in [blank, unknown, thinking_only] {
+            let response = answered(vec![block.clone()], Some("stop"));
+            assert_eq!(empty_choices(&response), vec![0], "{:?}", block);
+            for thinking_format in [ThinkingFormat::Tag, ThinkingFormat::ReasoningContent, ThinkingFormat::ContentPart] {
+                let choice = &completion(&response, thinking_format)["choices"][0];
+                assert_eq!(choice["finish_reason"], "error", "{:?} in {:?}", block, thinking_format);
+            }
+        }
+    }
+
+    #[test]
+    fn tool_calls_without_text_are_answers() {
+        let mut response = answered(Vec::new(), Some("tool_calls"));
+        response.choices[0].tool_calls.push(ToolCall {
+            id: "call_1".to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall { name: "lookup".to_string(), arguments: "{}".to_string() },
+        });
+        assert!(empty_choices(&response).is_empty());
+        let choice = &completion(&response, ThinkingFormat::Tag)["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["tool_calls"][0]["function"], json!({"name": "lookup", "arguments": "{}"}));
+    }
+}
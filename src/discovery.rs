@@ -0,0 +1,158 @@
+//! Model discovery for the OpenAI-compatible API.
+//!
+//! `GET /v1/models` lists the configured model mappings as OpenAI model
+//! objects, so SDKs and frontends can populate their model pickers. With
+//! `?verbose=true`, and always on `GET /v1/models/{id}`, each object also
+//! carries [`ModelDetails`]: a machine-readable summary of the mapping with
+//! its target's capabilities, parameter defaults, reasoning settings and
+//! context windows. Tokens and upstream URLs are never included.
+
+use crate::{
+    capabilities,
+    config::{CompatConfig, ModelCapabilities, ModelConfig, ModelMapping, PhaseParameters, ReasoningConfig, TargetProvider},
+    models::{InjectionMode, ReasonerTranscript, ThinkingFormat, UseReasonerAnswer},
+};
+use serde::{Deserialize, Serialize};
+
+/// Owner reported for every model.
+const OWNED_BY: &str = "deepthink";
+
+/// Query of `GET /v1/models`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelsQuery {
+    /// Include the details of each mapping.
+    #[serde(default)]
+    pub verbose: bool,
+}
+
+/// Body of `GET /v1/models`.
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+    pub object: &'static str,
+    pub data: Vec<ModelObject>,
+}
+
+/// One model mapping, as an OpenAI model object.
+#[derive(Debug, Serialize)]
+pub struct ModelObject {
+    pub id: String,
+    pub object: &'static str,
+    /// Always 0: mappings come from the config and have no creation time.
+    pub created: i64,
+    pub owned_by: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<ModelDetails>,
+}
+
+/// What a client can expect from a mapping.
+#[derive(Debug, Serialize)]
+pub struct ModelDetails {
+    pub reasoner_model: String,
+    pub target_model: String,
+    /// Provider answering when the request does not pick one with `X-Target-Model`.
+    pub default_target_provider: TargetProvider,
+    /// Requests go straight to the target, without reasoning.
+    pub passthrough: bool,
+    /// What the target accepts, as enforced on requests to this mapping.
+    pub capabilities: ModelCapabilities,
+    /// Body defaults of both phases.
+    pub parameters: serde_json::Value,
+    pub reasoner_parameters: PhaseParameters,
+    pub target_parameters: PhaseParameters,
+    pub reasoning: ReasoningDetails,
+    pub context_windows: ContextWindows,
+    /// Most messages sent upstream before older ones are trimmed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    /// The target answer is checked against the request's JSON schema.
+    pub validate_json_schema: bool,
+}
+
+/// Reasoning defaults of a mapping.
+#[derive(Debug, Serialize)]
+pub struct ReasoningDetails {
+    /// Shape of the reasoning in responses when the request does not set `thinking_format`.
+    pub thinking_format: ThinkingFormat,
+    /// Responses carry the reasoning unless the request sets `include_reasoning: false`.
+    pub include_reasoning: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub injection_mode: Option<InjectionMode>,
+    pub reasoner_transcript: ReasonerTranscript,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    pub use_reasoner_answer: UseReasonerAnswer,
+    /// A draft answer races the reasoner.
+    pub optimistic: bool,
+}
+
+/// Context windows of both phases, from `compat.context_windows`.
+#[derive(Debug, Serialize)]
+pub struct ContextWindows {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoner: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<u32>,
+}
+
+/// Lists the mappings of `models`, sorted by name.
+///
+/// # Arguments
+///
+/// * `models` - The model configuration of the caller's namespace
+/// * `reasoning` - The global reasoning settings the mappings fall back to
+/// * `compat` - The compat settings holding the context windows
+/// * `verbose` - Include the details of each mapping
+pub fn list(models: &ModelConfig, reasoning: &ReasoningConfig, compat: &CompatConfig, verbose: bool) -> ModelList {
+    let mut data: Vec<ModelObject> = models
+        .model_mappings
+        .iter()
+        .map(|(id, mapping)| object(id, verbose.then(|| details(models, reasoning, compat, mapping))))
+        .collect();
+    data.sort_by(|a, b| a.id.cmp(&b.id));
+    ModelList { object: "list", data }
+}
+
+/// Returns mapping `id` of `models` with its details, if it exists.
+pub fn get(models: &ModelConfig, reasoning: &ReasoningConfig, compat: &CompatConfig, id: &str) -> Option<ModelObject> {
+    let mapping = models.model_mappings.get(id)?;
+    Some(object(id, Some(details(models, reasoning, compat, mapping))))
+}
+
+fn object(id: &str, details: Option<ModelDetails>) -> ModelObject {
+    ModelObject {
+        id: id.to_string(),
+        object: "model",
+        created: 0,
+        owned_by: OWNED_BY,
+        details,
+    }
+}
+
+fn details(models: &ModelConfig, reasoning: &ReasoningConfig, compat: &CompatConfig, mapping: &ModelMapping) -> ModelDetails {
+    ModelDetails {
+        reasoner_model: mapping.deepseek_model.clone(),
+        target_model: mapping.target_model.clone(),
+        default_target_provider: models.default_target_provider,
+        passthrough: mapping.passthrough,
+        // 与请求检查使用相同的解析顺序: 映射声明 > models.capabilities 前缀 > 内置表
+        capabilities: capabilities::resolve(models, mapping.capabilities.as_ref(), &mapping.target_model),
+        parameters: mapping.parameters.clone(),
+        reasoner_parameters: mapping.reasoner.clone(),
+        target_parameters: mapping.target.clone(),
+        reasoning: ReasoningDetails {
+            thinking_format: ThinkingFormat::default(),
+            include_reasoning: true,
+            injection_mode: mapping.injection_mode,
+            reasoner_transcript: mapping.reasoner_transcript,
+            timeout_secs: mapping.reasoning_timeout_secs.or(reasoning.reasoning_timeout_secs),
+            use_reasoner_answer: mapping.use_reasoner_answer,
+            optimistic: mapping.optimistic.enabled,
+        },
+        context_windows: ContextWindows {
+            reasoner: compat.context_windows.get(&mapping.deepseek_model).copied(),
+            target: compat.context_windows.get(&mapping.target_model).copied(),
+        },
+        max_messages: mapping.max_messages,
+        validate_json_schema: mapping.validate_json_schema,
+    }
+}
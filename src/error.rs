@@ -11,11 +11,26 @@ use axum::{
     response::{IntoResponse, Response, sse::Event},
     Json,
 };
+use crate::{journal::ErrorMessage, models::StreamEvent, ratelimit};
 use serde::{Deserialize, Serialize};
-use std::convert::Infallible;
+use std::{convert::Infallible, time::Duration};
 use thiserror::Error;
 use tokio_stream::wrappers::ReceiverStream;
 
+/// Routes served by this application, listed in unsupported-endpoint errors.
+pub const SUPPORTED_ROUTES: &[&str] = &[
+    "POST /",
+    "POST /v1/chat/completions",
+    "POST /v1/completions",
+    "POST /v1/embeddings",
+    "POST /v1/token_count",
+    "GET /v1/models",
+    "GET /v1/models/{id}",
+    "GET /v1/streams/{id}",
+    "DELETE /v1/streams/{id}",
+    "GET /version",
+];
+
 /// Response structure for API errors.
 ///
 /// This structure provides a consistent format for error responses
@@ -60,6 +75,54 @@ pub enum ApiError {
         header: String,
     },
 
+    #[error("Missing {header} for target provider {provider}")]
+    MissingProviderToken {
+        header: String,
+        provider: String,
+        /// True if the provider was the configured default rather than requested.
+        by_default: bool,
+    },
+
+    #[error("Target model {model} does not support {feature}")]
+    UnsupportedCapability {
+        feature: String,
+        model: String,
+    },
+
+    #[error("Reasoner model {model} is not allowed")]
+    ReasonerModelNotAllowed {
+        model: String,
+        allowed: Vec<String>,
+    },
+
+    #[error("Parameter {parameter} is not allowed for the {phase} phase")]
+    ParameterNotAllowed {
+        phase: String,
+        parameter: String,
+    },
+
+    #[error("Endpoint override in {header} is not allowed: {reason}")]
+    EndpointNotAllowed {
+        header: String,
+        reason: String,
+    },
+
+    #[error("Accept header {accept} conflicts with stream = {stream}")]
+    NotAcceptable {
+        accept: String,
+        stream: bool,
+    },
+
+    #[error("Stream resumption is not enabled for the stream behind Last-Event-ID {last_event_id}")]
+    ResumeNotEnabled {
+        last_event_id: String,
+    },
+
+    #[error("No resumable stream {id}")]
+    StreamNotFound {
+        id: String,
+    },
+
     #[error("Invalid system prompt configuration")]
     InvalidSystemPrompt,
 
@@ -72,6 +135,7 @@ pub enum ApiError {
     },
 
     #[error("Anthropic API error: {message}")]
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
     AnthropicError {
         message: String,
         type_: String,
@@ -79,6 +143,153 @@ pub enum ApiError {
         code: Option<String>,
     },
 
+    #[error("Reasoner model {model} returned empty reasoning")]
+    EmptyReasoning {
+        model: String,
+    },
+
+    #[error("Target model {model} returned an empty answer")]
+    TargetEmptyResponse {
+        model: String,
+        finish_reason: Option<String>,
+    },
+
+    #[error("Reasoner model {model} hit its limit of {max_tokens} tokens mid-reasoning")]
+    ReasoningTruncated {
+        model: String,
+        max_tokens: u64,
+    },
+
+    #[error("Reasoner model {model} did not finish within {timeout_secs}s")]
+    ReasoningTimeout {
+        model: String,
+        timeout_secs: u64,
+    },
+
+    #[error("{provider} stream sent nothing for {idle_secs}s")]
+    StreamIdle {
+        provider: String,
+        idle_secs: u64,
+    },
+
+    #[error("Stream ran longer than {max_stream_secs}s")]
+    StreamDeadlineExceeded {
+        max_stream_secs: u64,
+    },
+
+    #[error("Stream {id} was killed by an operator")]
+    StreamKilled {
+        id: String,
+    },
+
+    #[error("No running stream {id}")]
+    StreamNotRunning {
+        id: String,
+    },
+
+    #[error("Missing or invalid admin token")]
+    AdminUnauthorized,
+
+    #[error("No journal entry for request {id}")]
+    JournalEntryNotFound {
+        id: String,
+    },
+
+    #[error("Request trace not allowed: {reason}")]
+    TraceNotAllowed {
+        reason: String,
+    },
+
+    #[error("Tenant not allowed: {reason}")]
+    TenantNotAllowed {
+        reason: String,
+    },
+
+    #[error("No trace for request {id}")]
+    TraceNotFound {
+        id: String,
+    },
+
+    #[error("No model mapping {model}")]
+    ModelNotFound {
+        model: String,
+    },
+
+    #[error("{provider} stream aborted: {reason}")]
+    StreamAborted {
+        provider: String,
+        reason: String,
+    },
+
+    #[error("{provider} returned HTTP {status}: {message}")]
+    UpstreamStatus {
+        provider: String,
+        status: u16,
+        message: String,
+    },
+
+    #[error("{provider} rate limit reached: {message}")]
+    UpstreamRateLimited {
+        provider: String,
+        /// The delay the upstream advertised, if any.
+        retry_after_secs: Option<u64>,
+        message: String,
+    },
+
+    #[error("{provider} reports insufficient credit: {message}")]
+    InsufficientCredit {
+        provider: String,
+        /// Fingerprint of the token the request was sent with, as in usage events.
+        token_fingerprint: Option<String>,
+        message: String,
+    },
+
+    #[error("{provider} rejected the API key: {message}")]
+    Unauthorized {
+        provider: String,
+        /// Fingerprint of the token the request was sent with, as in usage events.
+        token_fingerprint: Option<String>,
+        message: String,
+    },
+
+    #[error("Circuit for {provider} at {base_url} is open")]
+    CircuitOpen {
+        provider: String,
+        base_url: String,
+        retry_after_secs: u64,
+    },
+
+    #[error("Answer does not match the requested JSON schema: {}", errors.join("; "))]
+    SchemaValidation {
+        errors: Vec<String>,
+    },
+
+    #[error("Rate limit of {limit} requests per minute for {scope} exceeded")]
+    RateLimited {
+        scope: String,
+        limit: u32,
+        retry_after_secs: u64,
+    },
+
+    #[error("{window} budget of ${limit_usd} exhausted")]
+    BudgetExceeded {
+        window: String,
+        limit_usd: f64,
+        spent_usd: f64,
+        resets_at: chrono::DateTime<chrono::Utc>,
+    },
+
+    #[error("Endpoint not implemented: {endpoint}")]
+    NotImplemented {
+        endpoint: String,
+    },
+
+    #[error("Provider {provider} is not compiled in: build with the {feature} feature")]
+    ProviderNotCompiled {
+        provider: String,
+        feature: &'static str,
+    },
+
     #[error("Internal server error: {message}")]
     Internal {
         message: String,
@@ -98,13 +309,78 @@ pub enum ApiError {
     },
 }
 
-/// Implements conversion of API errors into HTTP responses.
+/// Names the token an upstream rejected by its fingerprint, without revealing it.
+fn token_hint(fingerprint: Option<&str>) -> String {
+    match fingerprint {
+        Some(fingerprint) => format!("the token with fingerprint {}", fingerprint),
+        None => "an empty token".to_string(),
+    }
+}
+
+/// Maps an Anthropic error type onto the status returned to the caller.
 ///
-/// Maps each error variant to an appropriate HTTP status code and
-/// formats the error details into a consistent JSON response structure.
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, error_response) = match &self {
+/// Errors caused by the request or its credentials keep their 4xx meaning,
+/// and capacity errors become retryable statuses. Anthropic's own
+/// `api_error`, failures to reach or read the upstream and unknown types
+/// are the upstream's fault and become a 502.
+fn anthropic_status(error_type: &str) -> StatusCode {
+    match error_type {
+        "invalid_request_error" => StatusCode::BAD_REQUEST,
+        "authentication_error" => StatusCode::UNAUTHORIZED,
+        "permission_error" => StatusCode::FORBIDDEN,
+        "not_found_error" => StatusCode::NOT_FOUND,
+        "request_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
+        "rate_limit_error" => StatusCode::TOO_MANY_REQUESTS,
+        "overloaded_error" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+impl ApiError {
+    /// Returns the HTTP status an upstream provider answered with, if the
+    /// error came from an unsuccessful upstream response.
+    pub fn upstream_status(&self) -> Option<u16> {
+        match self {
+            ApiError::UpstreamStatus { status, .. } => Some(*status),
+            ApiError::UpstreamRateLimited { .. } => Some(429),
+            ApiError::InsufficientCredit { .. } => Some(402),
+            ApiError::Unauthorized { .. } => Some(401),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the request never reached the upstream provider
+    /// because the connection failed or timed out.
+    pub fn is_connection_failure(&self) -> bool {
+        match self {
+            ApiError::DeepSeekError { type_, .. }
+            | ApiError::AnthropicError { type_, .. }
+            | ApiError::OpenAIError { type_, .. } => type_ == crate::clients::CONNECTION_FAILED_ERROR_TYPE,
+            _ => false,
+        }
+    }
+
+    /// Returns the `Retry-After` and rate limit headers of a rejection by our
+    /// own limiter, and `Retry-After` for upstream back-offs.
+    fn rate_limit_headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ApiError::RateLimited { limit, retry_after_secs, .. } => {
+                let mut headers = ratelimit::caller_headers(*limit, 0, Duration::from_secs(*retry_after_secs));
+                headers.push(("retry-after", retry_after_secs.to_string()));
+                headers
+            }
+            ApiError::UpstreamRateLimited { retry_after_secs: Some(secs), .. }
+            | ApiError::CircuitOpen { retry_after_secs: secs, .. } => vec![("retry-after", secs.to_string())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Maps the error to its HTTP status code and structured error body.
+    ///
+    /// Used both for plain HTTP error responses and for error frames sent
+    /// mid-stream, so the two always describe a failure the same way.
+    pub fn to_error_response(&self) -> (StatusCode, ErrorResponse) {
+        match self {
             ApiError::BadRequest { message } => (
                 StatusCode::BAD_REQUEST,
                 ErrorResponse {
@@ -127,6 +403,125 @@ impl IntoResponse for ApiError {
                     },
                 },
             ),
+            ApiError::MissingProviderToken { header, provider, by_default } => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: if *by_default {
+                            format!(
+                                "Missing required header: {} (target provider '{}' was selected by default because X-Target-Model is absent; send X-Target-Model or set models.default_target_provider to use another provider)",
+                                header, provider
+                            )
+                        } else {
+                            format!(
+                                "Missing required header: {} (required by X-Target-Model '{}')",
+                                header, provider
+                            )
+                        },
+                        type_: "missing_header".to_string(),
+                        param: Some(header.clone()),
+                        code: None,
+                    },
+                },
+            ),
+            ApiError::UnsupportedCapability { feature, model } => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Target model '{}' does not support {}; remove it from the request, pick another model, or declare the capability in models.capabilities",
+                            model, feature
+                        ),
+                        type_: "unsupported_capability".to_string(),
+                        param: Some(feature.clone()),
+                        code: Some("unsupported_capability".to_string()),
+                    },
+                },
+            ),
+            ApiError::ReasonerModelNotAllowed { model, allowed } => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Reasoner model '{}' is not allowed on this server; allowed: {}",
+                            model,
+                            allowed.join(", ")
+                        ),
+                        type_: "invalid_request_error".to_string(),
+                        param: Some("reasoner_model".to_string()),
+                        code: Some("reasoner_model_not_allowed".to_string()),
+                    },
+                },
+            ),
+            ApiError::ParameterNotAllowed { phase, parameter } => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Parameter '{}' may not be set for the {} phase on this server",
+                            parameter, phase
+                        ),
+                        type_: "invalid_request_error".to_string(),
+                        param: Some(parameter.clone()),
+                        code: Some("parameter_not_allowed".to_string()),
+                    },
+                },
+            ),
+            ApiError::EndpointNotAllowed { header, reason } => (
+                StatusCode::FORBIDDEN,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("Endpoint override in {} is not allowed: {}", header, reason),
+                        type_: "permission_error".to_string(),
+                        param: Some(header.clone()),
+                        code: Some("endpoint_not_allowed".to_string()),
+                    },
+                },
+            ),
+            ApiError::NotAcceptable { accept, stream } => (
+                StatusCode::NOT_ACCEPTABLE,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "The request sets \"stream\": {} but its Accept header '{}' only accepts {}; make them agree or omit \"stream\" to follow the Accept header",
+                            stream,
+                            accept,
+                            if *stream { "application/json" } else { "text/event-stream" }
+                        ),
+                        type_: "invalid_request_error".to_string(),
+                        param: Some("stream".to_string()),
+                        code: Some("stream_accept_mismatch".to_string()),
+                    },
+                },
+            ),
+            ApiError::ResumeNotEnabled { last_event_id } => (
+                StatusCode::CONFLICT,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Cannot resume after Last-Event-ID '{}': the stream was not started with resumable = true, so its frames were not kept; send the request without Last-Event-ID to start a new stream",
+                            last_event_id
+                        ),
+                        type_: "invalid_request_error".to_string(),
+                        param: Some("Last-Event-ID".to_string()),
+                        code: Some("stream_resumption_disabled".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamNotFound { id } => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "No resumable stream '{}': it was not started with resumable = true, has expired, or was deleted",
+                            id
+                        ),
+                        type_: "not_found_error".to_string(),
+                        param: Some("id".to_string()),
+                        code: Some("stream_not_found".to_string()),
+                    },
+                },
+            ),
             ApiError::InvalidSystemPrompt => (
                 StatusCode::BAD_REQUEST,
                 ErrorResponse {
@@ -150,7 +545,7 @@ impl IntoResponse for ApiError {
                 },
             ),
             ApiError::AnthropicError { message, type_, param, code } => (
-                StatusCode::BAD_REQUEST,
+                anthropic_status(type_),
                 ErrorResponse {
                     error: ErrorDetails {
                         message: format!("Anthropic API Error: {}", message),
@@ -160,6 +555,336 @@ impl IntoResponse for ApiError {
                     },
                 },
             ),
+            ApiError::EmptyReasoning { model } => (
+                StatusCode::BAD_GATEWAY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Reasoner model '{}' returned empty reasoning; check that the DeepSeek endpoint serves a reasoning model",
+                            model
+                        ),
+                        type_: "empty_reasoning".to_string(),
+                        param: Some(model.clone()),
+                        code: None,
+                    },
+                },
+            ),
+            ApiError::TargetEmptyResponse { model, finish_reason } => (
+                StatusCode::BAD_GATEWAY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Target model '{}' returned an empty answer (finish_reason: {})",
+                            model,
+                            finish_reason.as_deref().unwrap_or("none")
+                        ),
+                        type_: "target_empty_response".to_string(),
+                        param: Some(model.clone()),
+                        code: finish_reason.clone(),
+                    },
+                },
+            ),
+            ApiError::ReasoningTruncated { model, max_tokens } => (
+                StatusCode::BAD_GATEWAY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Reasoner model '{}' reached its max_tokens of {} before finishing its reasoning",
+                            model, max_tokens
+                        ),
+                        type_: "reasoning_truncated".to_string(),
+                        param: Some(model.clone()),
+                        code: Some("length".to_string()),
+                    },
+                },
+            ),
+            ApiError::ReasoningTimeout { model, timeout_secs } => (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Reasoner model '{}' did not finish within {} seconds",
+                            model, timeout_secs
+                        ),
+                        type_: "reasoning_timeout".to_string(),
+                        param: Some(model.clone()),
+                        code: Some("reasoning_timeout".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamIdle { provider, idle_secs } => (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "{} stream sent no data for {} seconds and was aborted",
+                            provider, idle_secs
+                        ),
+                        type_: "stream_idle".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("stream_idle".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamDeadlineExceeded { max_stream_secs } => (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Stream did not finish within streaming.max_stream_secs = {} seconds and was aborted",
+                            max_stream_secs
+                        ),
+                        type_: "stream_deadline".to_string(),
+                        param: None,
+                        code: Some("stream_deadline".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamKilled { id } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("Stream '{}' was aborted by an operator", id),
+                        type_: "stream_killed".to_string(),
+                        param: Some("id".to_string()),
+                        code: Some("stream_killed".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamNotRunning { id } => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("No running stream '{}': it has finished or never existed", id),
+                        type_: "not_found_error".to_string(),
+                        param: Some("id".to_string()),
+                        code: Some("stream_not_running".to_string()),
+                    },
+                },
+            ),
+            ApiError::AdminUnauthorized => (
+                StatusCode::UNAUTHORIZED,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: "Admin routes require server.admin_token in X-DeepThink-Admin-Token".to_string(),
+                        type_: "authentication_error".to_string(),
+                        param: None,
+                        code: Some("invalid_admin_token".to_string()),
+                    },
+                },
+            ),
+            ApiError::JournalEntryNotFound { id } => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("No journal entry for request '{}': it was evicted or never existed", id),
+                        type_: "not_found_error".to_string(),
+                        param: Some("id".to_string()),
+                        code: Some("request_not_found".to_string()),
+                    },
+                },
+            ),
+            ApiError::TraceNotAllowed { reason } => (
+                StatusCode::FORBIDDEN,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("Request trace not allowed: {}", reason),
+                        type_: "permission_error".to_string(),
+                        param: Some("trace".to_string()),
+                        code: Some("trace_not_allowed".to_string()),
+                    },
+                },
+            ),
+            ApiError::TenantNotAllowed { reason } => (
+                StatusCode::FORBIDDEN,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("Tenant not allowed: {}", reason),
+                        type_: "permission_error".to_string(),
+                        param: Some("X-DeepThink-Tenant".to_string()),
+                        code: Some("tenant_not_allowed".to_string()),
+                    },
+                },
+            ),
+            ApiError::TraceNotFound { id } => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("No trace for request '{}': it was evicted, never traced or never existed", id),
+                        type_: "not_found_error".to_string(),
+                        param: Some("id".to_string()),
+                        code: Some("trace_not_found".to_string()),
+                    },
+                },
+            ),
+            ApiError::ModelNotFound { model } => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("The model '{}' does not exist", model),
+                        type_: "invalid_request_error".to_string(),
+                        param: Some("model".to_string()),
+                        code: Some("model_not_found".to_string()),
+                    },
+                },
+            ),
+            ApiError::StreamAborted { provider, reason } => (
+                StatusCode::BAD_GATEWAY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("{} stream aborted: {}", provider, reason),
+                        type_: "stream_aborted".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("stream_aborted".to_string()),
+                    },
+                },
+            ),
+            ApiError::UpstreamStatus { provider, status, message } => (
+                StatusCode::BAD_GATEWAY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!("{} returned HTTP {}: {}", provider, status, message),
+                        type_: "upstream_error".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some(format!("upstream_{}", status)),
+                    },
+                },
+            ),
+            ApiError::UpstreamRateLimited { provider, retry_after_secs, message } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: match retry_after_secs {
+                            Some(secs) => format!("{} rate limit reached; retry in {}s: {}", provider, secs, message),
+                            None => format!("{} rate limit reached: {}", provider, message),
+                        },
+                        type_: "rate_limit_error".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("upstream_rate_limited".to_string()),
+                    },
+                },
+            ),
+            ApiError::InsufficientCredit { provider, token_fingerprint, message } => (
+                StatusCode::PAYMENT_REQUIRED,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "{} reports insufficient credit for {}; top up the account: {}",
+                            provider,
+                            token_hint(token_fingerprint.as_deref()),
+                            message
+                        ),
+                        type_: "insufficient_credit".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("insufficient_credit".to_string()),
+                    },
+                },
+            ),
+            ApiError::Unauthorized { provider, token_fingerprint, message } => (
+                StatusCode::UNAUTHORIZED,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "{} rejected {}; check the configured {} token: {}",
+                            provider,
+                            token_hint(token_fingerprint.as_deref()),
+                            provider,
+                            message
+                        ),
+                        type_: "authentication_error".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("upstream_unauthorized".to_string()),
+                    },
+                },
+            ),
+            ApiError::CircuitOpen { provider, base_url, retry_after_secs } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Circuit for {} at {} is open after repeated connection failures or a rate limit; retry in {}s",
+                            provider, base_url, retry_after_secs
+                        ),
+                        type_: "circuit_open".to_string(),
+                        param: Some(provider.clone()),
+                        code: None,
+                    },
+                },
+            ),
+            ApiError::RateLimited { scope, limit, retry_after_secs } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Rate limit of {} requests per minute for {} exceeded; retry in {}s",
+                            limit, scope, retry_after_secs
+                        ),
+                        type_: "rate_limit_error".to_string(),
+                        param: Some(scope.clone()),
+                        code: None,
+                    },
+                },
+            ),
+            ApiError::SchemaValidation { errors } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Target answer does not match the requested JSON schema after one corrective retry: {}",
+                            errors.join("; ")
+                        ),
+                        type_: "json_schema_validation_failed".to_string(),
+                        param: Some("response_format".to_string()),
+                        code: None,
+                    },
+                },
+            ),
+            ApiError::BudgetExceeded { window, limit_usd, spent_usd, resets_at } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "The {} budget of ${:.2} is exhausted (${:.4} spent); it resets at {}",
+                            window,
+                            limit_usd,
+                            spent_usd,
+                            resets_at.to_rfc3339()
+                        ),
+                        type_: "budget_exceeded".to_string(),
+                        param: Some(window.clone()),
+                        code: Some("budget_exceeded".to_string()),
+                    },
+                },
+            ),
+            ApiError::NotImplemented { endpoint } => (
+                StatusCode::NOT_IMPLEMENTED,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "{} is not supported: deepthink only implements chat and text completions. Supported routes: {}",
+                            endpoint,
+                            SUPPORTED_ROUTES.join(", ")
+                        ),
+                        type_: "not_implemented".to_string(),
+                        param: None,
+                        code: Some("unsupported_endpoint".to_string()),
+                    },
+                },
+            ),
+            ApiError::ProviderNotCompiled { provider, feature } => (
+                StatusCode::NOT_IMPLEMENTED,
+                ErrorResponse {
+                    error: ErrorDetails {
+                        message: format!(
+                            "Target provider {} is not available in this build: rebuild deepthink with the `{}` cargo feature",
+                            provider, feature
+                        ),
+                        type_: "not_implemented".to_string(),
+                        param: Some(provider.clone()),
+                        code: Some("provider_not_compiled".to_string()),
+                    },
+                },
+            ),
             ApiError::Internal { message } => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 ErrorResponse {
@@ -193,10 +918,139 @@ impl IntoResponse for ApiError {
                     },
                 },
             ),
-        };
+        }
+    }
+}
+
+/// Implements conversion of API errors into HTTP responses.
+///
+/// Maps each error variant to an appropriate HTTP status code and
+/// formats the error details into a consistent JSON response structure.
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error_response) = self.to_error_response();
+        let mut response = (status, Json(error_response)).into_response();
+        for (name, value) in self.rate_limit_headers() {
+            if let Ok(value) = axum::http::HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response.extensions_mut().insert(ErrorMessage(self.to_string()));
+        response
+    }
+}
+
+/// An error raised after a stream's response headers were sent.
+///
+/// An `ApiError` returned before a stream starts becomes an HTTP error
+/// response through `IntoResponse`. Once the `200` status line is out that is
+/// no longer possible, so a failure mid-stream is wrapped in a `StreamError`
+/// and reported in-band instead: every choice gets a final chunk with
+/// `finish_reason = "error"`, followed by the structured error frame and
+/// `[DONE]`. When output was already streamed, the error frame comes first
+/// and the final chunks are followed by a `metadata` frame with
+/// `partial: true`, so clients can show the answer as interrupted.
+/// `StreamError` deliberately has no `IntoResponse` implementation.
+#[derive(Debug)]
+pub struct StreamError {
+    pub error: ApiError,
+    /// `"reasoning"` or `"answering"`.
+    pub phase: &'static str,
+    /// Upstream provider of the failed phase.
+    pub provider: &'static str,
+    /// True if model output was already streamed to the client.
+    pub partial: bool,
+}
+
+impl StreamError {
+    /// Wraps an error of the reasoning phase.
+    pub fn reasoning(error: ApiError, partial: bool) -> Self {
+        Self { error, phase: "reasoning", provider: "deepseek", partial }
+    }
+
+    /// Wraps an error of the answering phase.
+    pub fn answering(error: ApiError, provider: &'static str, partial: bool) -> Self {
+        Self { error, phase: "answering", provider, partial }
+    }
+
+    /// Builds the events that end the failed stream, in sending order.
+    ///
+    /// # Arguments
+    ///
+    /// * `choice_count` - Number of choices in the stream
+    /// * `finish` - Builds the final chunk of a choice from its index and finish reason
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Event>` - One `finish_reason = "error"` chunk per choice, the
+    ///   error frame and `[DONE]`; for partial output the error frame, the
+    ///   chunks, the `partial` metadata frame and `[DONE]`
+    pub fn events(&self, choice_count: u32, finish: impl Fn(u32, &str) -> Event) -> Vec<Event> {
+        let finishes = (0..choice_count).map(|index| finish(index, "error"));
+        let mut events = Vec::new();
+        if self.partial {
+            events.push(self.error_event());
+            events.extend(finishes);
+            events.push(partial_event());
+        } else {
+            events.extend(finishes);
+            events.push(self.error_event());
+        }
+        events.push(Event::default().data("[DONE]"));
+        events
+    }
 
-        (status, Json(error_response)).into_response()
+    /// Builds the SSE event reporting the failure.
+    ///
+    /// Carries the same structured error body as the equivalent HTTP error
+    /// response, plus the failed phase, its provider, the upstream HTTP status
+    /// when known and whether output was already streamed. The body's `type` is
+    /// prefixed with the phase unless it already names it, and a missing `code`
+    /// names the provider, so clients that only read the error envelope can tell
+    /// the phases apart.
+    pub fn error_event(&self) -> Event {
+        Event::default().data(serde_json::to_string(&self.frame()).unwrap_or_default())
     }
+
+    /// Builds the error frame carried by [`StreamError::error_event`].
+    pub fn frame(&self) -> StreamEvent {
+        let (status, error_response) = self.error.to_error_response();
+        let mut details = error_response.error;
+        if !details.type_.starts_with(&format!("{}_", self.phase)) {
+            details.type_ = format!("{}_{}", self.phase, details.type_);
+        }
+        details.code = details.code.or_else(|| Some(format!("{}_error", self.provider)));
+        StreamEvent::Error {
+            message: self.error.to_string(),
+            code: i32::from(status.as_u16()),
+            error: Some(details),
+            phase: Some(self.phase.to_string()),
+            provider: Some(self.provider.to_string()),
+            upstream_status: self.error.upstream_status(),
+            partial: self.partial,
+        }
+    }
+}
+
+/// Builds the `metadata` frame marking a stream's output as cut off by a failure.
+fn partial_event() -> Event {
+    let event = StreamEvent::Metadata {
+        dropped_frames: Default::default(),
+        upstream_models: Default::default(),
+        reasoner_endpoint: None,
+        timings: None,
+        reasoning_truncated: false,
+        empty_answer: false,
+        usage: None,
+        partial: true,
+        optimistic_path: None,
+        answered_by: None,
+        reasoning_source: None,
+        redactions: 0,
+        trimmed: None,
+        metadata: Default::default(),
+    };
+    Event::default().event("metadata").data(serde_json::to_string(&event).unwrap_or_default())
 }
 
 /// Converts generic errors into API errors.
@@ -232,3 +1086,241 @@ pub type SseStream = ReceiverStream<SseResult>;
 ///
 /// Represents the complete SSE response type used by the API endpoints.
 pub type SseResponse = axum::response::sse::Sse<SseStream>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_failures_map_to_gateway_errors() {
+        let idle = ApiError::StreamIdle { provider: "deepseek".to_string(), idle_secs: 120 };
+        let (status, body) = idle.to_error_response();
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body.error.code.as_deref(), Some("stream_idle"));
+        assert_eq!(body.error.param.as_deref(), Some("deepseek"));
+        assert_eq!(body.error.message, "deepseek stream sent no data for 120 seconds and was aborted");
+
+        let aborted = ApiError::StreamAborted { provider: "openai".to_string(), reason: "reset".to_string() };
+        let (status, body) = aborted.to_error_response();
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body.error.code.as_deref(), Some("stream_aborted"));
+        assert_eq!(body.error.message, "openai stream aborted: reset");
+    }
+
+    #[test]
+    fn account_errors_keep_their_upstream_status() {
+        let credit = ApiError::InsufficientCredit {
+            provider: "deepseek".to_string(),
+            token_fingerprint: Some("0123456789abcdef".to_string()),
+            message: "Insufficient Balance".to_string(),
+        };
+        assert_eq!(credit.upstream_status(), Some(402));
+        let (status, body) = credit.to_error_response();
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body.error.code.as_deref(), Some("insufficient_credit"));
+        assert_eq!(body.error.param.as_deref(), Some("deepseek"));
+        assert_eq!(
+            body.error.message,
+            "deepseek reports insufficient credit for the token with fingerprint 0123456789abcdef; top up the account: Insufficient Balance"
+        );
+
+        let unauthorized = ApiError::Unauthorized {
+            provider: "openai".to_string(),
+            token_fingerprint: None,
+            message: "Incorrect API key provided".to_string(),
+        };
+        let (status, body) = unauthorized.to_error_response();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.error.type_, "authentication_error");
+        assert_eq!(body.error.code.as_deref(), Some("upstream_unauthorized"));
+        assert_eq!(body.error.message, "openai rejected an empty token; check the configured openai token: Incorrect API key provided");
+    }
+
+    #[test]
+    fn reasoning_timeouts_map_to_gateway_timeouts() {
+        let timeout = ApiError::ReasoningTimeout { model: "deepseek-r1:14b".to_string(), timeout_secs: 30 };
+        let (status, body) = timeout.to_error_response();
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body.error.type_, "reasoning_timeout");
+        assert_eq!(body.error.param.as_deref(), Some("deepseek-r1:14b"));
+        assert_eq!(body.error.message, "Reasoner model 'deepseek-r1:14b' did not finish within 30 seconds");
+    }
+
+    #[test]
+    fn anthropic_upstream_failures_are_bad_gateway() {
+        assert_eq!(anthropic_status("invalid_request_error"), StatusCode::BAD_REQUEST);
+        assert_eq!(anthropic_status("permission_error"), StatusCode::FORBIDDEN);
+        assert_eq!(anthropic_status("rate_limit_error"), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(anthropic_status("overloaded_error"), StatusCode::SERVICE_UNAVAILABLE);
+        for error_type in ["api_error", "parse_error", "request_failed", "something_new"] {
+            assert_eq!(anthropic_status(error_type), StatusCode::BAD_GATEWAY, "{}", error_type);
+        }
+    }
+
+    #[test]
+    fn variants_map_to_their_status_type_and_code() {
+        let upstream = |type_: &str| (type_.to_string(), Some("p".to_string()), Some("c".to_string()));
+        let (type_, param, code) = upstream("invalid_request_error");
+        let cases = vec![
+            (ApiError::BadRequest { message: "m".to_string() }, StatusCode::BAD_REQUEST, "bad_request", None),
+            (ApiError::MissingHeader { header: "X-Api-Key".to_string() }, StatusCode::BAD_REQUEST, "missing_header", None),
+            (
+                ApiError::MissingProviderToken { header: "X-Anthropic-API-Token".to_string(), provider: "anthropic".to_string(), by_default: true },
+                StatusCode::BAD_REQUEST,
+                "missing_header",
+                None,
+            ),
+            (
+                ApiError::UnsupportedCapability { feature: "tools".to_string(), model: "m".to_string() },
+                StatusCode::BAD_REQUEST,
+                "unsupported_capability",
+                Some("unsupported_capability"),
+            ),
+            (
+                ApiError::ReasonerModelNotAllowed { model: "m".to_string(), allowed: vec!["a".to_string()] },
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                Some("reasoner_model_not_allowed"),
+            ),
+            (
+                ApiError::ParameterNotAllowed { phase: "reasoning".to_string(), parameter: "top_p".to_string() },
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                Some("parameter_not_allowed"),
+            ),
+            (
+                ApiError::EndpointNotAllowed { header: "X-OpenAI-Endpoint-URL".to_string(), reason: "r".to_string() },
+                StatusCode::FORBIDDEN,
+                "permission_error",
+                Some("endpoint_not_allowed"),
+            ),
+            (
+                ApiError::NotAcceptable { accept: "application/json".to_string(), stream: true },
+                StatusCode::NOT_ACCEPTABLE,
+                "invalid_request_error",
+                Some("stream_accept_mismatch"),
+            ),
+            (ApiError::StreamNotFound { id: "s".to_string() }, StatusCode::NOT_FOUND, "not_found_error", Some("stream_not_found")),
+            (ApiError::InvalidSystemPrompt, StatusCode::BAD_REQUEST, "invalid_system_prompt", None),
+            (
+                ApiError::DeepSeekError { message: "m".to_string(), type_: type_.clone(), param: param.clone(), code: code.clone() },
+                StatusCode::BAD_REQUEST,
+                "deepseek_invalid_request_error",
+                Some("c"),
+            ),
+            (
+                ApiError::AnthropicError { message: "m".to_string(), type_: type_.clone(), param: param.clone(), code: code.clone() },
+                StatusCode::BAD_REQUEST,
+                "anthropic_invalid_request_error",
+                Some("c"),
+            ),
+            (
+                ApiError::OpenAIError { message: "m".to_string(), type_, param, code },
+                StatusCode::BAD_REQUEST,
+                "openai_invalid_request_error",
+                Some("c"),
+            ),
+            (ApiError::EmptyReasoning { model: "m".to_string() }, StatusCode::BAD_GATEWAY, "empty_reasoning", None),
+            (
+                ApiError::TargetEmptyResponse { model: "m".to_string(), finish_reason: Some("stop".to_string()) },
+                StatusCode::BAD_GATEWAY,
+                "target_empty_response",
+                Some("stop"),
+            ),
+            (
+                ApiError::ReasoningTruncated { model: "m".to_string(), max_tokens: 8 },
+                StatusCode::BAD_GATEWAY,
+                "reasoning_truncated",
+                Some("length"),
+            ),
+            (
+                ApiError::UpstreamStatus { provider: "openai".to_string(), status: 500, message: "m".to_string() },
+                StatusCode::BAD_GATEWAY,
+                "upstream_error",
+                Some("upstream_500"),
+            ),
+            (
+                ApiError::CircuitOpen { provider: "deepseek".to_string(), base_url: "u".to_string(), retry_after_secs: 1 },
+                StatusCode::SERVICE_UNAVAILABLE,
+                "circuit_open",
+                None,
+            ),
+            (
+                ApiError::RateLimited { scope: "key".to_string(), limit: 1, retry_after_secs: 1 },
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limit_error",
+                None,
+            ),
+            (
+                ApiError::SchemaValidation { errors: vec!["e".to_string()] },
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "json_schema_validation_failed",
+                None,
+            ),
+            (
+                ApiError::BudgetExceeded { window: "daily".to_string(), limit_usd: 1.0, spent_usd: 1.5, resets_at: chrono::Utc::now() },
+                StatusCode::TOO_MANY_REQUESTS,
+                "budget_exceeded",
+                Some("budget_exceeded"),
+            ),
+            (
+                ApiError::NotImplemented { endpoint: "/v1/embeddings".to_string() },
+                StatusCode::NOT_IMPLEMENTED,
+                "not_implemented",
+                Some("unsupported_endpoint"),
+            ),
+            (ApiError::Internal { message: "m".to_string() }, StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None),
+            (ApiError::Other { message: "m".to_string() }, StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None),
+        ];
+        for (error, status, type_, code) in cases {
+            let (actual_status, body) = error.to_error_response();
+            assert_eq!(actual_status, status, "{:?}", error);
+            assert_eq!(body.error.type_, type_, "{:?}", error);
+            assert_eq!(body.error.code.as_deref(), code, "{:?}", error);
+            assert_eq!(error.into_response().status(), status);
+        }
+    }
+
+    #[test]
+    fn stream_errors_prefix_the_phase_and_name_the_provider() {
+        let error = StreamError::answering(
+            ApiError::UpstreamStatus { provider: "openai".to_string(), status: 503, message: "down".to_string() },
+            "openai",
+            true,
+        );
+        let frame = serde_json::to_value(error.frame()).unwrap();
+        assert_eq!(frame["code"], 502);
+        assert_eq!(frame["error"]["type"], "answering_upstream_error");
+        assert_eq!(frame["error"]["code"], "upstream_503");
+        assert_eq!(frame["phase"], "answering");
+        assert_eq!(frame["provider"], "openai");
+        assert_eq!(frame["upstream_status"], 503);
+        assert_eq!(frame["partial"], true);
+
+        let error = StreamError::reasoning(ApiError::EmptyReasoning { model: "m".to_string() }, false);
+        let frame = serde_json::to_value(error.frame()).unwrap();
+        assert_eq!(frame["error"]["type"], "reasoning_empty_reasoning");
+        assert_eq!(frame["error"]["code"], "deepseek_error");
+        assert_eq!(frame["provider"], "deepseek");
+        assert!(frame.get("upstream_status").is_none());
+        assert_eq!(frame["partial"], false);
+
+        // 已带阶段前缀的类型不再重复添加
+        let error = StreamError::reasoning(ApiError::ReasoningTruncated { model: "m".to_string(), max_tokens: 8 }, true);
+        let frame = serde_json::to_value(error.frame()).unwrap();
+        assert_eq!(frame["error"]["type"], "reasoning_truncated");
+        assert_eq!(frame["error"]["code"], "length");
+    }
+
+    #[test]
+    fn stream_errors_end_every_choice_before_the_error_frame() {
+        let error = StreamError::reasoning(ApiError::EmptyReasoning { model: "m".to_string() }, false);
+        let finished = std::cell::RefCell::new(Vec::new());
+        let events = error.events(3, |index, finish_reason| {
+            finished.borrow_mut().push((index, finish_reason.to_string()));
+            Event::default().data(format!("finish {}", index))
+        });
+        assert_eq!(events.len(), 5);
+        assert_eq!(finished.into_inner(), vec![(0, "error".to_string()), (1, "error".to_string()), (2, "error".to_string())]);
+    }
+}
@@ -6,83 +6,173 @@
 //! usage tracking and cost calculations.
 
 use crate::{
+    admission::AdmissionQueue,
+    audit,
+    budget::{SpendLedger, BUDGET_WARNING_HEADER},
+    circuit::{CircuitBreakers, CircuitState, CircuitStatus},
+    capabilities::{self, CONTENT_WARNING_HEADER},
+    compression,
+    connection,
+    conversion::{self, LegacyCompletionResponse, OpenAICompatRequest, OpenAICompatResponse, BLOCK_WARNING_HEADER},
     clients::{
-        AnthropicClient, DeepSeekClient, OpenAIClient,
+        self, sibling_endpoint, DeepSeekClient, ANTHROPIC_API_URL, MISTRAL_API_URL, OPENAI_API_URL,
         DEEPSEEK_ENDPOINT_URL_HEADER, OPENAI_ENDPOINT_URL_HEADER, ANTHROPIC_ENDPOINT_URL_HEADER,
     },
-    config::{Config, ModelMapping, TokenConfig, EndpointConfig},
-    error::{ApiError, Result, SseResponse},
+    discovery::{self, ModelList, ModelObject, ModelsQuery},
+    endpoints::{self, ENDPOINT_WARNING_HEADER},
+    config::{
+        AnswerThinkTags, Config, EmptyAnswerPolicy, SpeculationConfig, SpeculationReuse, ReasonerCircuitAction, EmptyReasoningPolicy, EndpointConfig, ModelConfig, ModelMapping,
+        ReasoningConfig, ReasoningReuse, RedactionScope, TargetProvider, TokenConfig, TruncatedReasoningPolicy, UserThinkTags,
+    },
+    error::{ApiError, Result, SseResponse, StreamError},
+    history::TrimReport,
+    idempotency::{self, ResponseCache, IDEMPOTENCY_KEY_HEADER},
+    identity::{Clock, IdGenerator},
+    metering,
+    journal::{JournalEntry, JournalQuery, JournalSlot, RequestJournal},
+    metrics::{Metrics, MetricsSnapshot},
+    negotiate::{self, StreamTransport},
     models::{
-        ApiRequest, ApiResponse, ContentBlock,
-        ExternalApiResponse, Message, Role, StreamEvent,
-        ApiConfig,
+        AnswerAuthor, AnswerRole, ApiRequest, ReasonerAnswerMode, ApiResponse, ContentBlock, UseReasonerAnswer,
+        ExternalApiResponse, Message, OpenAIDialect, OptimisticConfig, OptimisticPath, ReasoningSource, Role, StreamEvent,
+        AnswerOutcome, PipelineResult, ReasoningOutcome,
+        ApiConfig, ResponseChoice, StreamFormat, ThinkingFormat, Timings, UsageStats, REASONER_ANSWER_BLOCK_TYPE, TEXT_BLOCK_TYPE,
+        convert_messages, unknown_message_fields, ContentTarget, LanguageInstructions, ToolCall,
     },
+    parameters::{self, PARAMETER_WARNING_HEADER},
+    profiles::{Profile, RouteProfile},
+    progress::{Progress, ProgressCallbacks, ProgressPhase},
+    ratelimit::UpstreamRateLimits,
+    speculation::SpeculationCache,
+    redact::{self, Loggable},
+    reasoning_redaction::Redactor,
+    replicas::{self, ReplicaRouter},
+    schema,
+    signing::Signers,
+    think::{self, AnswerTagFilter},
+    trace::{self, RequestTrace, TraceRecorder, TraceStore, ADMIN_TOKEN_HEADER},
+    resume::{self, StreamBuffer, StreamRegistry},
+    reuse::{self, ReasoningStore},
+    sink::EventSink,
+    tenants::{Namespace, Tenants},
+    throttle::{send_paced, OutputThrottle},
+    timing::{self, PhaseTimer},
+    tokens,
+    vendor::{self, VendorOptions, VENDOR_WARNING_HEADER},
+    version::{self, VersionInfo},
+    warmup::WarmUp,
+    warnings::{Warning, WarningCollector},
+    watchdog::{ActiveStream, PhaseCell, StreamOutput, StreamPhase, StreamWatchdog},
+    webhooks::{UsageEvent, UsageWebhooks},
 };
 
 // 添加 AssistantMessage 导入
-use crate::clients::deepseek::AssistantMessage;
+use crate::clients::deepseek::{AssistantMessage, DeepSeekResponse, DEEPSEEK_API_URL};
+#[cfg(feature = "anthropic")]
+use crate::{
+    clients::{
+        anthropic::{self, AnthropicResponse},
+        AnthropicClient,
+    },
+    models::{SystemBlock, SystemPrompt, ToolCallAccumulator},
+};
+#[cfg(feature = "openai")]
+use crate::clients::{
+    openai::{check_dialect_params, OpenAIResponse},
+    OpenAIClient,
+};
 
 use axum::{
     extract::State,
     response::{sse::Event, IntoResponse},
     Json,
 };
-use chrono::Utc;
-use futures::StreamExt;
-use std::{sync::Arc, collections::HashMap};
+use futures::{future::OptionFuture, StreamExt};
+use tracing::Instrument;
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, collections::HashMap, time::Duration};
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 use axum::http::HeaderValue;
 
+/// Response header naming the reasoner model a compat request ran with.
+const REASONER_MODEL_HEADER: &str = "X-DeepThink-Reasoner-Model";
+
+/// Response header naming the reasoner replica a compat request was routed to.
+const REASONER_ENDPOINT_HEADER: &str = "X-DeepThink-Reasoner-Endpoint";
+
+/// Response header naming the answer an optimistic compat request returned.
+const OPTIMISTIC_PATH_HEADER: &str = "X-DeepThink-Optimistic-Path";
+
+/// Response header naming the phase that wrote the answer, for mappings with `use_reasoner_answer`.
+const ANSWERED_BY_HEADER: &str = "X-DeepThink-Answered-By";
+
+/// Response header saying whether the reasoning was reused or fresh.
+const REASONING_SOURCE_HEADER: &str = "X-DeepThink-Reasoning-Source";
+
+/// Response header counting the replacements the redaction rules made in the injected reasoning.
+const REDACTIONS_HEADER: &str = "X-DeepThink-Redactions";
+
+/// Response header counting the messages the mapping's `max_messages` dropped.
+const TRIMMED_MESSAGES_HEADER: &str = "X-DeepThink-Trimmed-Messages";
+
+/// Response header flagging an empty target answer passed through under the `pass` policy.
+const EMPTY_ANSWER_WARNING_HEADER: &str = "X-DeepThink-Empty-Answer";
+
+/// Most entries allowed in a request's `metadata`.
+const MAX_METADATA_KEYS: usize = 16;
+
+/// Longest `metadata` key allowed, in characters.
+const MAX_METADATA_KEY_CHARS: usize = 64;
+
+/// Longest `metadata` value allowed, in characters.
+const MAX_METADATA_VALUE_CHARS: usize = 512;
+
+/// Least number of tokens `max_tokens` is raised by when retrying an empty answer.
+const EMPTY_ANSWER_RETRY_MIN_TOKENS: u64 = 128;
+
 /// Application state shared across request handlers.
 ///
-/// Contains configuration that needs to be accessible
-/// to all request handlers.
+/// Contains configuration, metrics, and the id/clock sources that need
+/// to be accessible to all request handlers.
 pub struct AppState {
     pub config: Config,
-}
-
-/// Extracts API tokens from request headers.
-///
-/// # Arguments
-///
-/// * `headers` - The HTTP headers containing the API tokens
-///
-/// # Returns
-///
-/// * `Result<(String, String)>` - A tuple of (DeepSeek token, Anthropic token)
-///
-/// # Errors
-///
-/// Returns `ApiError::MissingHeader` if either token is missing
-/// Returns `ApiError::BadRequest` if tokens are malformed
-fn extract_api_tokens(
-    headers: &axum::http::HeaderMap,
-) -> Result<(String, String)> {
-    let deepseek_token = headers
-        .get("X-DeepSeek-API-Token")
-        .ok_or_else(|| ApiError::MissingHeader { 
-            header: "X-DeepSeek-API-Token".to_string() 
-        })?
-        .to_str()
-        .map_err(|_| ApiError::BadRequest { 
-            message: "Invalid DeepSeek API token".to_string() 
-        })?
-        .to_string();
-
-    let anthropic_token = headers
-        .get("X-Anthropic-API-Token")
-        .ok_or_else(|| ApiError::MissingHeader { 
-            header: "X-Anthropic-API-Token".to_string() 
-        })?
-        .to_str()
-        .map_err(|_| ApiError::BadRequest { 
-            message: "Invalid Anthropic API token".to_string() 
-        })?
-        .to_string();
-
-    Ok((deepseek_token, anthropic_token))
+    /// Connection pool of the requests relayed unchanged to the upstream and of
+    /// the target clients, shared with the connection warm-up.
+    pub http: reqwest::Client,
+    pub metrics: Arc<Metrics>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub clock: Arc<dyn Clock>,
+    pub response_cache: ResponseCache,
+    pub admission: Arc<AdmissionQueue>,
+    pub spend: SpendLedger,
+    pub streams: StreamRegistry,
+    pub circuits: Arc<CircuitBreakers>,
+    /// Latest rate limits advertised by the upstreams.
+    pub rate_limits: Arc<UpstreamRateLimits>,
+    /// Route profiles by name.
+    pub profiles: HashMap<String, Arc<Profile>>,
+    pub speculation: Arc<SpeculationCache>,
+    /// Fresh reasoning by conversation id, for reasoning reuse.
+    pub reasoning_store: ReasoningStore,
+    /// Running stream tasks, aborted at their deadline or on request.
+    pub watchdog: StreamWatchdog,
+    /// Summaries of the latest completed chat requests.
+    pub journal: Arc<RequestJournal>,
+    /// Traces of the latest traced requests.
+    pub traces: TraceStore,
+    /// The compiled `[[redaction]]` rules.
+    pub redactor: Redactor,
+    /// Reasoner replica routing and the requests in flight on each replica.
+    pub replicas: ReplicaRouter,
+    /// Request signer of each provider, from `[signing]`.
+    pub signers: Signers,
+    /// Dispatcher of the `[usage_webhooks]` events.
+    pub webhooks: UsageWebhooks,
+    /// The global and per-tenant model, endpoint and auth settings.
+    pub tenants: Tenants,
+    /// Reporters of the `progress_callback_url` request option.
+    pub progress: ProgressCallbacks,
 }
 
 /// Main handler for chat requests.
@@ -94,27 +184,83 @@ fn extract_api_tokens(
 ///
 /// * `state` - Application state containing configuration
 /// * `headers` - HTTP request headers
-/// * `request` - The parsed chat request
+/// * `raw_request` - The request body, parsed into an `ApiRequest`
 ///
 /// # Returns
 ///
 /// * `Result<Response>` - The API response or an error
 pub async fn handle_chat(
     state: State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
-    Json(request): Json<ApiRequest>,
+    journal: JournalSlot,
+    mut headers: axum::http::HeaderMap,
+    Json(raw_request): Json<serde_json::Value>,
 ) -> Result<axum::response::Response> {
+    if let Some(response) = resume_last_event(&state, &headers)? {
+        return Ok(response);
+    }
+    // 手动解析请求体, 使不支持的消息角色等错误返回 400 而不是 422
+    let stream = raw_request.get("stream").and_then(|v| v.as_bool());
+    let transport = negotiate::stream_transport(&headers, raw_request.get("stream_transport")).and_then(negotiate::chunk_transport)?;
+    let unknown_fields = unknown_fields_warning(&state, &raw_request);
+    let traced = check_trace(&state, &headers, raw_request.get("trace").and_then(|v| v.as_bool()).unwrap_or(false))?;
+    let inbound = traced.then(|| raw_request.clone());
+    let mut request: ApiRequest = serde_json::from_value(raw_request).map_err(|e| ApiError::BadRequest {
+        message: format!("Invalid chat request: {}", e),
+    })?;
+    request.stream = negotiate::wants_stream(state.config.streaming.accept_precedence, &headers, stream)?;
+    journal.set_request(None, request.stream);
+    request.request_id = journal.id();
     tracing::info!("Handling chat request");
-    tracing::info!("{:#?}", request);
+    tracing::info!("{:#?}", Loggable(&request));
+    let warnings = request.warnings.clone();
+    warnings.extend(unknown_fields);
+    warnings.extend(check_endpoint_overrides(&state, &mut headers)?);
+    warnings.extend(budget_warnings(&state, &headers)?);
+    warnings.extend(check_parameters(&state, &mut request, None)?);
+    warnings.extend(check_capabilities(&state, &headers, &mut request)?);
+    if let Some(inbound) = &inbound {
+        start_trace(&state, &journal, inbound, None, &mut request);
+    }
+    let tracer = request.tracer.clone();
     if request.stream {
         let stream_response = chat_stream(state, headers, Json(request)).await?;
-        Ok(stream_response.into_response())
+        Ok(with_warnings(negotiate::stream_response(transport, stream_response), &warnings))
     } else {
-        let json_response = chat(state, headers, Json(request)).await?;
-        Ok(json_response.into_response())
+        let body = serde_json::to_value(&request).unwrap_or_default();
+        let cache_key = idempotency_key(&headers, "/", &body);
+        if let Some(body) = cache_key.as_deref().and_then(|key| state.response_cache.get(key)) {
+            return Ok(Json(body).into_response());
+        }
+        let span = request_span(&request);
+        let mut json_response = chat(state.clone(), headers, Json(request)).instrument(span).await?;
+        journal.set_response(&json_response.0);
+        warnings.extend(json_response.0.empty_answer.then(empty_answer_warning));
+        json_response.0.warnings = warnings.list();
+        finish_trace(tracer.as_ref(), &mut json_response.0);
+        if let Some(key) = cache_key {
+            state.response_cache.insert(key, serde_json::to_value(&json_response.0).unwrap_or_default());
+        }
+        Ok(with_warnings(json_response.into_response(), &warnings))
     }
 }
 
+/// Returns the response cache key for a request carrying an `Idempotency-Key` header.
+///
+/// Keys are scoped by `route`, the caller's credentials and the request
+/// body; see [`idempotency::cache_key`].
+fn idempotency_key(headers: &axum::http::HeaderMap, route: &str, body: &serde_json::Value) -> Option<String> {
+    let key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|key| !key.is_empty())?;
+    // 调用方以其携带的全部凭证区分
+    let caller: Vec<&str> = ["Authorization", "X-DeepSeek-API-Token", "X-OpenAI-API-Token", "X-Anthropic-API-Token"]
+        .iter()
+        .map(|name| headers.get(*name).and_then(|h| h.to_str().ok()).unwrap_or(""))
+        .collect();
+    Some(idempotency::cache_key(route, &caller.join("\n"), key, body))
+}
+
 /// Handler for non-streaming chat requests.
 ///
 /// Processes the request through both AI models sequentially,
@@ -130,14 +276,36 @@ pub async fn handle_chat(
 ///
 /// * `Result<Json<ApiResponse>>` - The combined API response or an error
 pub(crate) async fn chat(
-    State(_state): State<Arc<AppState>>,
+    state: State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
+    request: Json<ApiRequest>,
+) -> Result<Json<ApiResponse>> {
+    // 在任务本地收集本请求各次上游调用的连接耗时, 计入 timings; 需要 trace 时同时记录各次上游调用
+    let tracer = request.tracer.clone();
+    // 请求带有 progress_callback_url 时, 处理期间向它报告进度
+    let progress = state.progress.start(request.progress_callback_url.as_deref(), request.request_id.clone(), Instant::now())?;
+    let result = connection::scope(trace::scope(tracer, run_chat(state, headers, request, &progress))).await;
+    progress.finish(result.is_ok());
+    result
+}
+
+async fn run_chat(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
     Json(request): Json<ApiRequest>,
+    progress: &Progress,
 ) -> Result<Json<ApiResponse>> {
+    let received = Instant::now();
+    let namespace = state.tenants.of(&headers);
+    let usage_event = UsageEvent::start(&request, namespace.caller_tokens(&headers).0, state.clock.now());
+    let _permit = state.admission.acquire(request_priority(&namespace, &headers)).await;
+    progress.phase(ProgressPhase::Reasoning);
+
     // Validate system prompt
     if !request.validate_system_prompt() {
         return Err(ApiError::InvalidSystemPrompt);
     }
+    check_metadata(&request.metadata)?;
 
     // Extract API tokens
     let deepseek_token = headers
@@ -151,788 +319,9556 @@ pub(crate) async fn chat(
         })?
         .to_string();
 
-    let (target_model, target_token) = get_target_client(&headers)?;
+    let (target_model, target_token) = get_target_client(&headers, namespace.models.default_target_provider)?;
+    let mut request = request;
+    let target_model = resolve_openai_dialect(target_model, &mut headers, &mut request)?;
+    if target_model != "openai" && requests_logprobs(&request) {
+        return Err(ApiError::BadRequest {
+            message: "logprobs are only supported with OpenAI-compatible target models".to_string(),
+        });
+    }
+    if target_model != "openai" && requests_prediction(&request) {
+        return Err(ApiError::BadRequest {
+            message: "prediction is only supported with OpenAI-compatible target models".to_string(),
+        });
+    }
+
+    // 将内容块转换为目标模型支持的格式, 不支持的组合直接返回 400
+    request.messages = convert_messages(&request.messages, content_target(&target_model))?;
+    strip_client_thinking(&state.config.reasoning, &mut request);
+    resolve_language_instructions(&state.config, &mut request);
+    apply_reasoner_model(&namespace.models, &mut request)?;
 
     // Initialize clients with custom base URLs if provided
     let deepseek_client = match headers.get(DEEPSEEK_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
         Some(base_url) => DeepSeekClient::new_with_base_url(deepseek_token, base_url.to_string()),
         None => DeepSeekClient::new(deepseek_token),
-    };
+    }
+    .with_dialect(request.reasoner_dialect)
+    .with_groq_max_tokens(state.config.reasoning.groq_max_tokens)
+    .with_defaults(state.config.defaults.deepseek.clone())
+    .with_rate_limits(state.rate_limits.clone())
+    .with_signer(state.signers.deepseek.clone());
 
-    let messages = request.get_messages_with_system();
+    let choice_count = resolve_choice_count(&request, namespace.models.max_choices)?;
+    let schema_validator = json_schema_validator(&request, choice_count)?;
+    let messages = reasoner_messages(&state.config.reasoning, &request);
+    let policy = state.config.reasoning.empty_policy;
+    let reasoner_answer_mode = request.reasoner_answer.unwrap_or(state.config.reasoning.reasoner_answer);
+    let mut usage = UsageStats::default();
 
-    // Call DeepSeek API
-    let deepseek_response = deepseek_client.chat(messages.clone(), &request.deepseek_config).await?;
+    // 熔断的上游在开始推理前直接失败, 推理模型熔断时可按配置跳过推理
+    let reasoner_url = upstream_url(&headers, "deepseek");
+    let target_url = upstream_url(&headers, &target_model);
+    let skip_reasoning = request.skip_reasoning || skip_open_reasoner(&state, &reasoner_url, &request.warnings)?;
+    state.circuits.check(&target_model, &target_url)?;
+    // 推理期间预热到目标端点的连接
+    let warm_up = WarmUp::start(&state.http, state.config.target.warm_up.method(&target_model), &target_url);
 
-    // Extract reasoning content and wrap in thinking tags
-    let reasoning_content = deepseek_response
-        .choices
-        .first()
-        .and_then(|c| c.message.reasoning_content.as_ref())
-        .map(|content| content.trim())
-        .ok_or_else(|| ApiError::DeepSeekError { 
-            message: "No reasoning content in response".to_string(),
-            type_: "missing_content".to_string(),
-            param: None,
-            code: None
-        })?;
+    // Call DeepSeek API; 超过推理时限时返回 None
+    let reasoning_timeout = reasoning_timeout(&request, &state.config.reasoning);
+    let mut reasoner_timer = PhaseTimer::start();
+    let deadline = reasoning_timeout.map(|timeout| Instant::now() + timeout);
+    // 工具循环的后续轮次按 reasoning_reuse 重新注入之前的推理, 不再调用推理模型
+    let reuse_policy = state.config.reasoning.reasoning_reuse;
+    let reused = reused_reasoning(&state, &request).filter(|_| !skip_reasoning);
+    // 命中推测推理时直接使用, 或按 reuse 策略带上它做一次简短的补充推理
+    let speculation = &state.config.speculation;
+    let speculative = request
+        .conversation_id
+        .as_deref()
+        .filter(|_| speculation.speculative_reasoning && !skip_reasoning && reused.is_none())
+        .and_then(|conversation_id| state.speculation.take(conversation_id, &request.messages));
+    // 乐观回答: 推理的同时在原始对话上生成草稿, 推理超过 reasoner_wait_ms 时改用草稿
+    let optimistic = request.optimistic.filter(|_| !skip_reasoning && speculative.is_none() && reused.is_none());
+    let draft = optimistic.map(|_| Draft::start(&state, &target_model, &target_token, &headers, &request, choice_count));
+    let deadline = optimistic_deadline(deadline, optimistic);
+    let reasoner = Reasoner { client: &deepseek_client, circuits: &state.circuits, url: &reasoner_url };
+    let Reasoned { response: deepseek_response, content: mut reasoning_content, truncated: reasoning_truncated } = match &reused {
+        _ if skip_reasoning => Reasoned::default(),
+        Some(reasoning) => Reasoned { content: Some(reasoning.clone()), ..Reasoned::default() },
+        None => reason(&state, &reasoner, &request, &messages, speculative.as_deref(), deadline, &mut usage).await?,
+    };
+    reasoner_timer.finish();
+    if reasoning_truncated {
+        request.warnings.push(truncated_reasoning_warning());
+    }
+    let reasoner_usage = usage.clone();
+    progress.reasoned(reasoner_usage.completion_tokens);
 
-    // 只保留推理内容,不添加额外的标记
-    let thinking_content = if reasoning_content.starts_with("<think>") && reasoning_content.ends_with("</think>") {
-        reasoning_content.to_string()
-    } else {
-        format!("<think>\n{}\n</think>", reasoning_content)
+    // 推理按时完成时丢弃草稿, 已完成的草稿同样计入用量
+    let draft = match draft {
+        Some(draft) if deepseek_response.is_none() => Some(draft),
+        Some(draft) => {
+            if let Some(draft_usage) = draft.discard() {
+                usage.merge(&draft_usage);
+            }
+            None
+        }
+        None => None,
     };
+    let optimistic_path = optimistic.map(|_| match draft {
+        Some(_) => OptimisticPath::Draft,
+        None => OptimisticPath::Reasoned,
+    });
 
-    // Add thinking content to messages for target model
-    let mut target_messages = messages;
-    
-    // 移除可能存在的系统消息
-    target_messages.retain(|msg| msg.role != Role::System);
-    
-    // 添加推理内容
-    target_messages.push(Message {
-        role: Role::Assistant,
-        content: thinking_content.clone(),
+    // 非流式调用超时后没有部分推理可用, 按 empty_policy 跳过推理或返回错误
+    if deepseek_response.is_none() && !skip_reasoning && speculative.is_none() && reused.is_none() && draft.is_none() {
+        let timeout_secs = reasoning_timeout.map_or(0, |timeout| timeout.as_secs());
+        if policy != EmptyReasoningPolicy::Skip {
+            return Err(ApiError::ReasoningTimeout {
+                model: deepseek_client.resolve_model(&request.deepseek_config),
+                timeout_secs,
+            });
+        }
+        tracing::warn!("Reasoner did not finish within {}s, continuing without reasoning", timeout_secs);
+    }
+
+    // 推理模型自身的回答满足 use_reasoner_answer 时代替目标模型的回答, 不再调用目标模型
+    let reasoner_answer = deepseek_response.as_ref().and_then(extract_reasoner_answer);
+    let reasoner_finish_reason = deepseek_response
+        .as_ref()
+        .and_then(|response| response.choices.first())
+        .and_then(|choice| choice.finish_reason.clone());
+    let own_answer = reasoner_answer.clone().filter(|answer| {
+        may_use_reasoner_answer(&request, choice_count)
+            && reasoner_answer_stands(
+                request.use_reasoner_answer,
+                request.confident_answer_min_chars,
+                answer,
+                reasoner_finish_reason.as_deref(),
+            )
     });
+    // 否则按配置丢弃、追加到推理内容或单独返回
+    let mut reasoner_answer_block = None;
+    match (reasoner_answer_mode, reasoner_answer.filter(|_| own_answer.is_none())) {
+        (ReasonerAnswerMode::AppendToReasoning, Some(answer)) => {
+            reasoning_content = reasoning_content.map(|reasoning| format!("{}\n\n{}", reasoning, answer));
+        }
+        (ReasonerAnswerMode::ReturnAsBlock, Some(answer)) => {
+            reasoner_answer_block = Some(ContentBlock {
+                content_type: REASONER_ANSWER_BLOCK_TYPE.to_string(),
+                text: answer,
+            });
+        }
+        _ => {}
+    }
 
-    // Call target model API
-    let (target_response, target_status, target_headers) = match target_model.as_str() {
-        "openai" => {
-            let openai_client = match headers.get(OPENAI_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
-                Some(base_url) => OpenAIClient::new_with_base_url(target_token, base_url.to_string()),
-                None => OpenAIClient::new(target_token),
-            };
-            tracing::info!("Calling OpenAI client");
-            tracing::info!("{:#?}", request);
-            tracing::info!("Target messages: {:?}", target_messages);
-            tracing::info!("OpenAI config: {:?}", request.openai_config);
-            let response = openai_client.chat(target_messages, &request.openai_config).await?;
-            (serde_json::to_value(&response)?, 200, HashMap::<String, String>::new())
+    let reasoning_content = match reasoning_content {
+        Some(reasoning) => Some(reasoning),
+        None if skip_reasoning || draft.is_some() => None,
+        None if policy == EmptyReasoningPolicy::Skip => {
+            tracing::warn!("Reasoner returned empty reasoning, continuing without it");
+            None
         }
-        _ => {
-            let anthropic_client = match headers.get(ANTHROPIC_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
-                Some(base_url) => AnthropicClient::new_with_base_url(target_token, base_url.to_string()),
-                None => AnthropicClient::new(target_token),
-            };
-            let response = anthropic_client.chat(
-                target_messages,
-                request.get_system_prompt().map(String::from),
-                &request.anthropic_config
-            ).await?;
-            (serde_json::to_value(&response)?, 200, HashMap::new())
+        None => {
+            return Err(ApiError::EmptyReasoning {
+                model: deepseek_client.resolve_model(&request.deepseek_config),
+            });
         }
     };
 
-    // Combine thinking content with target model's response
-    let mut content = Vec::new();
-    content.push(ContentBlock::text(thinking_content));
-
-    // Add target model's response blocks
-    match target_model.as_str() {
-        "openai" => {
-            if let Some(choice) = target_response.get("choices").and_then(|c| c.as_array()).and_then(|c| c.first()) {
-                if let Some(message) = choice.get("message") {
-                    if let Some(content_str) = message.get("content").and_then(|c| c.as_str()) {
-                        content.push(ContentBlock::text(content_str.to_string()));
-                    }
-                }
-            }
-        }
-        _ => {
-            if let Some(content_array) = target_response.get("content").and_then(|c| c.as_array()) {
-                content.extend(content_array.iter().filter_map(|block| {
-                    Some(ContentBlock {
-                        content_type: block.get("type")?.as_str()?.to_string(),
-                        text: block.get("text")?.as_str()?.to_string(),
-                    })
-                }));
+    let reasoning_source = reasoning_content
+        .as_deref()
+        .filter(|_| reuse_policy != ReasoningReuse::Never)
+        .map(|reasoning| match &reused {
+            Some(_) => ReasoningSource::Reused,
+            None => {
+                store_reasoning(&state, &request, reasoning);
+                ReasoningSource::Fresh
             }
+        });
+
+    // 按 [[redaction]] 规则脱敏注入的推理; redaction_scope = "all" 时返回给客户端的推理也使用脱敏后的内容
+    let redacted = reasoning_content.as_deref().map(|reasoning| state.redactor.apply(reasoning));
+    let redactions = redacted.as_ref().map_or(0, |(_, count)| *count);
+    let redacted_reasoning = redacted.map(|(reasoning, _)| reasoning);
+    let redact_all = state.config.reasoning.redaction_scope == RedactionScope::All;
+    let client_reasoning = if redact_all { redacted_reasoning.clone() } else { reasoning_content };
+
+    // 只保留推理内容,不添加额外的标记; 结构化的推理格式使用单独的 thinking 块
+    let thinking_block = client_reasoning.map(|reasoning_content| match request.thinking_format {
+        ThinkingFormat::Tag if reasoning_content.starts_with("<think>") && reasoning_content.ends_with("</think>") => {
+            ContentBlock::text(reasoning_content)
         }
+        ThinkingFormat::Tag => ContentBlock::text(format!("<think>\n{}\n</think>", reasoning_content)),
+        ThinkingFormat::ReasoningContent | ThinkingFormat::ContentPart => ContentBlock::thinking(reasoning_content),
+    });
+
+    // 按注入策略将推理内容加入目标模型的消息, 被截断的推理追加标记
+    let injected_reasoning = redacted_reasoning
+        .as_deref()
+        .map(|reasoning| mark_truncated(reasoning, reasoning_truncated, &state.config.reasoning.truncation_marker));
+    let target_messages = request.build_target_messages(injected_reasoning.as_deref());
+
+    // Call target model API
+    let target_warm_up = OptionFuture::from(warm_up.map(WarmUp::finish)).await.flatten();
+    let mut target_timer = PhaseTimer::start();
+    let result = if let Some(answer) = &own_answer {
+        tracing::info!("Answering with the reasoner's own answer, skipping the target");
+        Ok(reasoner_outcome(
+            deepseek_client.resolve_model(&request.deepseek_config),
+            answer,
+            reasoner_finish_reason.as_deref(),
+        ))
+    } else if let Some(draft) = draft {
+        tracing::info!("Reasoner did not finish in time, answering with the draft");
+        draft.finish().await
+    } else if state.config.target.partial_on_failure {
+        call_target_streamed(
+            &target_model,
+            &target_token,
+            &headers,
+            &request,
+            target_messages.clone(),
+            choice_count,
+            &state,
+        ).await
+    } else {
+        call_target(
+            &target_model,
+            &target_token,
+            &headers,
+            &request,
+            target_messages.clone(),
+            choice_count,
+            &state,
+        ).await
+    };
+    if own_answer.is_none() {
+        state.circuits.record(&target_model, &target_url, &result);
+    }
+    let mut outcome = result?;
+    // 目标模型返回空回答时按 empty_answer_policy 提高 max_tokens 重试一次、报错或标记后返回
+    let empty_answer_policy = state.config.target.empty_answer_policy;
+    if outcome.is_empty() && empty_answer_policy == EmptyAnswerPolicy::RetryOnce {
+        tracing::warn!("Target returned an empty answer, retrying once with more max_tokens");
+        usage.merge(&outcome.usage);
+        let mut retry_request = request.clone();
+        raise_max_tokens(&mut retry_request.openai_config.body);
+        raise_max_tokens(&mut retry_request.anthropic_config.body);
+        outcome = call_target(
+            &target_model,
+            &target_token,
+            &headers,
+            &retry_request,
+            target_messages.clone(),
+            choice_count,
+            &state,
+        ).await?;
+    }
+    let empty_answer = outcome.is_empty();
+    if empty_answer && empty_answer_policy != EmptyAnswerPolicy::Pass {
+        let finish_reason = outcome.finish_reason();
+        return Err(ApiError::TargetEmptyResponse {
+            model: outcome.model,
+            finish_reason,
+        });
+    }
+    // 校验答案是否符合请求的 JSON schema, 不符合时带错误信息重试一次
+    if let Some(validator) = &schema_validator {
+        outcome = enforce_json_schema(
+            validator,
+            outcome,
+            &mut usage,
+            &target_model,
+            &target_token,
+            &headers,
+            &request,
+            target_messages,
+            &state,
+        ).await?;
+    }
+    target_timer.finish();
+    tracing::info!("Target model {} finished", outcome.model);
+    // 目标模型在回答中复述的 think 标签按 answer_think_tags 转义或删除, 推理块不在 outcome 中, 不受影响
+    let answer_think_tags = state.config.target.answer_think_tags;
+    let mut echoed_tags = 0;
+    for block in outcome
+        .choices
+        .iter_mut()
+        .flat_map(|choice| choice.content.iter_mut())
+        .filter(|block| block.content_type == TEXT_BLOCK_TYPE)
+    {
+        let (text, count) = think::sanitize_answer(&block.text, answer_think_tags);
+        block.text = text;
+        echoed_tags += count;
+    }
+    request.warnings.extend(answer_tags_warning(echoed_tags, answer_think_tags));
+
+    // 第一个 choice 的回答 (不含推理块) 构成 PipelineResult 的 answer
+    let answer_choice = outcome.choices.first().map(|choice| (choice.content.clone(), choice.finish_reason.clone()));
+
+    // Combine thinking content with each of the target model's choices
+    // 不返回推理时, 推理和推理模型自身的回答都只注入目标模型
+    let leading_blocks: Vec<ContentBlock> = thinking_block
+        .into_iter()
+        .chain(reasoner_answer_block)
+        .filter(|_| request.includes_reasoning())
+        .collect();
+    let mut choices: Vec<ResponseChoice> = outcome
+        .choices
+        .into_iter()
+        .map(|mut choice| {
+            choice.content.splice(0..0, leading_blocks.iter().cloned());
+            choice
+        })
+        .collect();
+    usage.merge(&outcome.usage);
+
+    // 按各阶段的模型价格累计调用方的花费; 命中缓存的提示词按缓存价格计费
+    let budget = &state.config.budget;
+    let cost = budget.cost(
+        &deepseek_client.resolve_model(&request.deepseek_config),
+        reasoner_usage.prompt_tokens,
+        reasoner_usage.completion_tokens,
+    ) + budget.cost_with_cache(
+        &outcome.model,
+        usage.prompt_tokens - reasoner_usage.prompt_tokens,
+        usage.cached_prompt_tokens - reasoner_usage.cached_prompt_tokens,
+        usage.completion_tokens - reasoner_usage.completion_tokens,
+    );
+    state.spend.record(&namespace.spend_key(&headers), cost, state.clock.now());
+    // 目标阶段的用量包括空回答重试的那次调用
+    let target_usage = UsageStats {
+        prompt_tokens: usage.prompt_tokens - reasoner_usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens - reasoner_usage.completion_tokens,
+        total_tokens: usage.total_tokens - reasoner_usage.total_tokens,
+        cached_prompt_tokens: usage.cached_prompt_tokens - reasoner_usage.cached_prompt_tokens,
+        accepted_prediction_tokens: usage.accepted_prediction_tokens - reasoner_usage.accepted_prediction_tokens,
+        rejected_prediction_tokens: usage.rejected_prediction_tokens - reasoner_usage.rejected_prediction_tokens,
+    };
+    state.webhooks.send(usage_event.completed(&reasoner_usage, &target_usage, cost, state.clock.now()));
+
+    if choices.is_empty() {
+        choices.push(ResponseChoice {
+            index: 0,
+            content: leading_blocks,
+            logprobs: None,
+            finish_reason: None,
+            tool_calls: Vec::new(),
+        });
     }
+    let content = choices[0].content.clone();
+    let tool_calls = choices[0].tool_calls.clone();
 
-    // Build response
+    // Build response: 先汇总为 PipelineResult, HTTP 响应由它推导, 再按请求补充多 choice、原始响应等字段
+    let phase_timings = timing::timings(received, &reasoner_timer, &target_timer, target_warm_up);
+    let answer_role = if own_answer.is_some() { AnswerRole::Reasoner } else { AnswerRole::Target };
+    let answered_by = answer_author(&request, answer_role, &outcome.model);
+    let has_answer = answer_choice.is_some();
+    let (answer_blocks, answer_finish_reason) = answer_choice.unwrap_or_default();
+    let result = PipelineResult {
+        reasoning: ReasoningOutcome {
+            text: redacted_reasoning.unwrap_or_default(),
+            model: deepseek_client.resolve_model(&request.deepseek_config),
+            usage: reasoner_usage,
+            truncated: reasoning_truncated,
+        },
+        answer: AnswerOutcome {
+            blocks: answer_blocks,
+            finish_reason: answer_finish_reason,
+            model: outcome.model.clone(),
+            usage: target_usage,
+        },
+        timings: phase_timings,
+        warnings: request.warnings.list(),
+    };
+    let answer = has_answer.then(|| result.answer_text());
     let response = ApiResponse {
-        created: Utc::now(),
+        created: state.clock.now(),
         content,
-        // deepseek_response: request.verbose.then(|| ExternalApiResponse {
-        //     status: deepseek_status,
-        //     headers: deepseek_headers,
-        //     body: serde_json::to_value(&deepseek_response).unwrap_or_default(),
-        // }),
-        // anthropic_response: request.verbose.then(|| ExternalApiResponse {
-        //     status: target_status,
-        //     headers: target_headers,
-        //     body: target_response.clone(),
-        // }),
+        choices,
+        tool_calls,
+        // 原始推理响应带有未脱敏的推理, 脱敏范围包括客户端时不返回
+        deepseek_response: deepseek_response
+            .as_ref()
+            .filter(|_| request.verbose && request.includes_reasoning() && !(redact_all && redactions > 0))
+            .map(|response| ExternalApiResponse {
+                status: response.upstream.status,
+                headers: response.upstream.headers.clone(),
+                body: serde_json::to_value(response).unwrap_or_default(),
+            }),
+        target_response: outcome.raw,
+        timings: request.wants_timings().then(|| result.timings.clone()),
+        reasoner_model: reported_reasoner_model(&request),
+        reasoner_endpoint: request.replica.as_ref().map(|lease| lease.endpoint().to_string()),
+        empty_answer,
+        optimistic_path,
+        answered_by,
+        reasoning_source,
+        redactions,
+        trimmed: request.trimmed,
+        metadata: request.metadata.clone(),
+        target_provider: if own_answer.is_some() { "deepseek".to_string() } else { target_model.clone() },
+        ..ApiResponse::from(result)
     };
 
+    if let (Some(conversation_id), Some(answer)) = (request.conversation_id.clone(), answer) {
+        if state.config.speculation.speculative_reasoning {
+            speculate(&state, &headers, deepseek_client, &request, conversation_id, answer);
+        }
+    }
+
     Ok(Json(response))
 }
 
-/// Handler for streaming chat requests.
+/// Starts a background reasoner run anticipating a conversation's next turn.
 ///
-/// Processes the request through both AI models sequentially,
-/// streaming their responses as Server-Sent Events.
+/// The run is skipped while `speculation.max_concurrent` runs are in
+/// progress, while requests are waiting for admission, and for callers whose
+/// budget is exhausted or past its warning threshold. Its cost is charged to
+/// the caller as speculative spend, and its reasoning is cached for the
+/// conversation's next request.
+fn speculate(
+    state: &Arc<AppState>,
+    headers: &axum::http::HeaderMap,
+    deepseek_client: DeepSeekClient,
+    request: &ApiRequest,
+    conversation_id: String,
+    answer: String,
+) {
+    if state.admission.depths().values().sum::<usize>() > 0 {
+        tracing::debug!("Requests are queued, skipping speculative reasoning");
+        return;
+    }
+    let namespace = state.tenants.of(headers);
+    let caller = namespace.spend_key(headers);
+    if !matches!(state.spend.check(&caller, namespace.caller_tokens(headers).1, &state.config.budget, state.clock.now()), Ok(None)) {
+        tracing::debug!("Caller is close to its budget, skipping speculative reasoning");
+        return;
+    }
+    let Some(slot) = state.speculation.try_start() else {
+        tracing::debug!("Speculation limit reached, skipping speculative reasoning");
+        return;
+    };
+
+    // 预测的下一轮对话: 当前对话加上刚返回的回答
+    let mut next = request.clone();
+    next.messages.push(Message {
+        role: Role::Assistant,
+        content: answer.into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    let mut messages = reasoner_messages(&state.config.reasoning, &next);
+    messages.push(Message {
+        role: Role::User,
+        content: SPECULATION_INSTRUCTION.into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    let state = state.clone();
+    tokio::spawn(async move {
+        let _slot = slot;
+        match deepseek_client.chat(messages, &next.deepseek_config).await {
+            Ok(response) => {
+                let cost = state.config.budget.cost(
+                    &deepseek_client.resolve_model(&next.deepseek_config),
+                    response.usage.prompt_tokens,
+                    response.usage.completion_tokens,
+                );
+                state.spend.record_speculative(&caller, cost, state.clock.now());
+                if let Some(reasoning) = extract_reasoning(&response) {
+                    state.speculation.store(&conversation_id, &next.messages, reasoning);
+                }
+            }
+            Err(e) => tracing::warn!("Speculative reasoning failed: {}", e),
+        }
+    });
+}
+
+/// Calls the target model once with the final message list.
 ///
 /// # Arguments
 ///
-/// * `state` - Application state containing configuration
-/// * `headers` - HTTP request headers
-/// * `request` - The parsed chat request
+/// * `target_model` - `"openai"` or any other value for Anthropic
+/// * `target_token` - API token for the target
+/// * `headers` - Request headers, consulted for a custom endpoint URL
+/// * `request` - The chat request, providing the target configs
+/// * `target_messages` - Messages with the reasoning already injected
+/// * `choice_count` - Number of choices to generate
+/// * `state` - Application state, providing the connection pool and the ids
+///   given to Anthropic tool calls
 ///
 /// # Returns
 ///
-/// * `Result<SseResponse>` - A stream of Server-Sent Events or an error
-pub(crate) async fn chat_stream(
-    State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
-    Json(request): Json<ApiRequest>,
-) -> Result<SseResponse> {
-    // Validate system prompt
-    if !request.validate_system_prompt() {
-        return Err(ApiError::InvalidSystemPrompt);
-    }
-
-    // Extract API tokens
-    let deepseek_token = headers
-        .get("X-DeepSeek-API-Token")
-        .ok_or_else(|| ApiError::MissingHeader { 
-            header: "X-DeepSeek-API-Token".to_string() 
-        })?
-        .to_str()
-        .map_err(|_| ApiError::BadRequest { 
-            message: "Invalid DeepSeek API token".to_string() 
-        })?
-        .to_string();
-
-    let (target_model, target_token) = get_target_client(&headers)?;
-
-    // Initialize clients with custom base URLs if provided
-    let deepseek_client = match headers.get(DEEPSEEK_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
-        Some(base_url) => DeepSeekClient::new_with_base_url(deepseek_token, base_url.to_string()),
-        None => DeepSeekClient::new(deepseek_token),
+/// * `Result<TargetOutcome>` - The target's choices and usage
+///
+/// # Errors
+///
+/// Returns the target client's error if the call fails.
+async fn call_target(
+    target_model: &str,
+    target_token: &str,
+    headers: &axum::http::HeaderMap,
+    request: &ApiRequest,
+    target_messages: Vec<Message>,
+    choice_count: u32,
+    state: &AppState,
+) -> Result<TargetOutcome> {
+    let outcome = match target_model {
+        #[cfg(feature = "openai")]
+        "openai" => {
+            let openai_client = OpenAIClient::new_with_client(
+                target_token.to_string(),
+                upstream_url(headers, "openai"),
+                state.http.clone(),
+            )
+            .with_dialect(request.openai_dialect)
+            .with_defaults(state.config.defaults.openai.clone())
+            .with_rate_limits(state.rate_limits.clone())
+            .with_signer(state.signers.openai.clone());
+            let mut openai_config = request.openai_config.clone();
+            if choice_count > 1 {
+                openai_config.body["n"] = serde_json::json!(choice_count);
+            }
+            tracing::info!("Calling OpenAI client");
+            tracing::info!("{:#?}", Loggable(request));
+            tracing::info!("Target messages: {:?}", Loggable(&target_messages));
+            tracing::info!("OpenAI config: {:?}", Loggable(&openai_config));
+            let response = openai_client.chat(target_messages, &openai_config).await?;
+            TargetOutcome::from_openai(response, request.verbose)
+        }
+        #[cfg(feature = "anthropic")]
+        "anthropic" => {
+            let anthropic_client = AnthropicClient::new_with_client(
+                target_token.to_string(),
+                upstream_url(headers, "anthropic"),
+                state.http.clone(),
+            )
+            .with_defaults(state.config.defaults.anthropic.clone())
+            .with_rate_limits(state.rate_limits.clone())
+            .with_signer(state.signers.anthropic.clone());
+            let (system, target_messages) = anthropic_target_messages(request, target_messages);
+            // Anthropic 没有 n 参数, 每个 choice 单独调用一次
+            let responses = futures::future::try_join_all((0..choice_count).map(|_| {
+                anthropic_client.chat(
+                    target_messages.clone(),
+                    system.clone(),
+                    &request.anthropic_config
+                )
+            })).await?;
+            TargetOutcome::from_anthropic(responses, request.verbose, state.ids.as_ref())
+        }
+        other => return Err(target_not_compiled(other)),
     };
 
-    let messages = request.get_messages_with_system();
-
-    // Create channel for stream events
-    let (tx, rx) = tokio::sync::mpsc::channel(100);
-    let tx = Arc::new(tx);
-
-    // Spawn task to handle streaming
-    let config = state.config.clone();
-    let request_clone = request.clone();
-    tokio::spawn(async move {
-        let tx = tx.clone();
-
-        // // Start event
-        // let _ = tx
-        //     .send(Ok(Event::default().event("start").data(
-        //         serde_json::to_string(&StreamEvent::Start {
-        //             created: Utc::now(),
-        //         })
-        //         .unwrap_or_default(),
-        //     )))
-        //     .await;
-
-        // Stream from DeepSeek
-        let mut complete_reasoning = String::new();
-        let mut current_chunk = String::new();
-        let mut deepseek_stream = deepseek_client.chat_stream(messages.clone(), &request_clone.deepseek_config);
-        
-        // Send initial thinking tag
-        let stream_response = serde_json::json!({
-            "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-            "object": "chat.completion.chunk",
-            "created": chrono::Utc::now().timestamp(),
-            "model": request_clone.deepseek_config.body.get("model").unwrap_or(&serde_json::json!("deepseek-chat")),
-            "choices": [{
-                "index": 0,
-                "delta": {
-                    "content": "<thinking>\n"
-                },
-                "finish_reason": null
-            }],
-            "usage": {
-                "prompt_tokens":0,
-                "completion_tokens":0,
-                "total_tokens":0,
-            }
-        });
-        let _ = tx
-            .send(Ok(Event::default().data(
-                serde_json::to_string(&stream_response).unwrap_or_default(),
-            )))
-            .await;
-        
-        while let Some(chunk) = deepseek_stream.next().await {
-            match chunk {
-                Ok(response) => {
-                    if let Some(choice) = response.choices.first() {
-                        tracing::info!("Stream Response: {:?}", response);
-                        
-                        // 处理 delta 如果存在
-                        if let Some(delta) = &choice.delta {
-                            // 处理 content
-                            if let Some(content) = &delta.content {
-                                tracing::info!("Found delta content: {}", content);
-                                if response.system_fingerprint == "fp_ollama" {
-                                    // 直接发送 content 作为流式输出
-                                    if !content.is_empty() {
-                           
-                                    }
-                                    tracing::info!("Processing ollama delta content");
-                                    current_chunk.push_str(content);
-                                    tracing::info!("Updated current_chunk: {}", current_chunk);
-                                    if current_chunk.contains("<think>") && !current_chunk.contains("</think>"){
-                                        if content != "<think>" {
-                                        let stream_response = serde_json::json!({
-                                            "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-                                            "object": "chat.completion.chunk",
-                                            "created": chrono::Utc::now().timestamp(),
-                                            "model": request_clone.deepseek_config.body.get("model").unwrap_or(&serde_json::json!("deepseek-chat")),
-                                            "choices": [{
-                                                "index": 0,
-                                                "delta": {
-                                                    "content": content
-                                                },
-                                                "finish_reason": null
-                                            }],
-                                            "usage": {
-                                                "prompt_tokens":0,
-                                                "completion_tokens":0,
-                                                "total_tokens":0,
-                                            }
-                                        });
-                                        let _ = tx
-                                            .send(Ok(Event::default().data(
-                                                serde_json::to_string(&stream_response).unwrap_or_default(),
-                                            )))
-                                            .await;
-                                        }
-                                    }
-                                    if current_chunk.contains("<think>") && current_chunk.contains("</think>") {
-                                        tracing::info!("Found complete think tags in delta");
-                                        if let Some((reasoning, _)) = AssistantMessage::extract_think_content(&current_chunk) {
-                                            tracing::info!("Extracted reasoning from delta: {}", reasoning);
-                                            complete_reasoning.push_str(&reasoning);
-                                            tracing::info!("Updated complete_reasoning from delta think tags: {}", complete_reasoning);
-                                            current_chunk.clear();
-                                        }
-                                    }
-                                }
-                            }
+    Ok(outcome)
+}
 
-                            // 处理 reasoning_content
-                            if let Some(reasoning) = &delta.reasoning_content {
-                                tracing::info!("Found delta reasoning_content: {}", reasoning);
-                                if !reasoning.is_empty() {
-                                    let stream_response = serde_json::json!({
-                                        "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-                                        "object": "chat.completion.chunk",
-                                        "created": chrono::Utc::now().timestamp(),
-                                        "model": request_clone.deepseek_config.body.get("model").unwrap_or(&serde_json::json!("deepseek-chat")),
-                                        "choices": [{
-                                            "index": 0,
-                                            "delta": {
-                                                "content": reasoning
-                                            },
-                                            "finish_reason": null
-                                        }],
-                                        "usage": {
-                                            "prompt_tokens":0,
-                                            "completion_tokens":0,
-                                            "total_tokens":0,
-                                        }
-                                    });
-                                    let _ = tx
-                                        .send(Ok(Event::default().data(
-                                            serde_json::to_string(&stream_response).unwrap_or_default(),
-                                        )))
-                                        .await;
-
-                                    complete_reasoning.push_str(reasoning);
-                                    tracing::info!("Updated complete_reasoning from delta: {}", complete_reasoning);
-                                }
-                            }
-                        }
-                        
-                        // 处理 message 如果存在
-                        if let Some(message) = &choice.message {
-                            if let Some(content) = &message.content {
-                                if response.system_fingerprint == "fp_ollama" {
-                                    tracing::info!("Processing ollama message content");
-                                    if let Some((reasoning, _)) = AssistantMessage::extract_think_content(content) {
-                                        complete_reasoning.push_str(&reasoning);
-                                        tracing::info!("Updated complete_reasoning from message think tags: {}", complete_reasoning);
-                                    }
-                                }
-                            }
+/// Calls the target model through its streaming API and collects the answer.
+///
+/// Used instead of [`call_target`] when `target.partial_on_failure` is set.
+/// If the stream fails after answer text or tool calls arrived, the output
+/// received so far is returned, and choices that had not finished get
+/// `finish_reason = "interrupted"`.
+///
+/// # Errors
+///
+/// Returns the target client's error if the call fails before any output.
+async fn call_target_streamed(
+    target_model: &str,
+    target_token: &str,
+    headers: &axum::http::HeaderMap,
+    request: &ApiRequest,
+    target_messages: Vec<Message>,
+    choice_count: u32,
+    state: &AppState,
+) -> Result<TargetOutcome> {
+    let idle_timeout = state.config.streaming.idle_timeout();
+    let parse_strictness = &state.config.streaming.parse_strictness;
+    let mut texts = vec![String::new(); choice_count as usize];
+    let mut choices: Vec<ResponseChoice> = (0..choice_count)
+        .map(|index| ResponseChoice {
+            index,
+            content: Vec::new(),
+            logprobs: None,
+            finish_reason: None,
+            tool_calls: Vec::new(),
+        })
+        .collect();
+    let mut usage = UsageStats::default();
+    let mut failure = None;
 
-                            if let Some(reasoning) = &message.reasoning_content {
-                                tracing::info!("Found message reasoning_content: {}", reasoning);
-                                if !reasoning.is_empty() {
-                                    complete_reasoning.push_str(reasoning);
-                                    tracing::info!("Updated complete_reasoning from message: {}", complete_reasoning);
-                                }
-                            }
-                        }
+    let model = match target_model {
+        #[cfg(feature = "openai")]
+        "openai" => {
+            let openai_client = OpenAIClient::new_with_client(
+                target_token.to_string(),
+                upstream_url(headers, "openai"),
+                state.http.clone(),
+            )
+            .with_idle_timeout(idle_timeout)
+            .with_parse_strictness(parse_strictness.openai)
+            .with_dialect(request.openai_dialect)
+            .with_defaults(state.config.defaults.openai.clone())
+            .with_rate_limits(state.rate_limits.clone())
+            .with_signer(state.signers.openai.clone());
+            let mut openai_config = request.openai_config.clone();
+            if choice_count > 1 {
+                openai_config.body["n"] = serde_json::json!(choice_count);
+            }
+            let mut model = openai_config.body.get("model").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+            let mut stream = openai_client.chat_stream(target_messages, &openai_config);
+            while let Some(chunk) = stream.next().await {
+                let response = match chunk {
+                    Ok(response) => response,
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
                     }
+                };
+                model = response.model;
+                if let Some(stream_usage) = &response.usage {
+                    usage.merge(&UsageStats::from(stream_usage));
                 }
-                Err(e) => {
-                    let _ = tx
-                        .send(Ok(Event::default().data(
-                            serde_json::to_string(&StreamEvent::Error {
-                                message: e.to_string(),
-                                code: 500,
-                            })
-                            .unwrap_or_default(),
-                        )))
-                        .await;
-                    return;
+                for choice in response.choices {
+                    let index = choice.index as usize;
+                    if index >= texts.len() {
+                        continue;
+                    }
+                    if let Some(content) = &choice.delta.content {
+                        texts[index].push_str(content);
+                    }
+                    if choice.finish_reason.is_some() {
+                        choices[index].finish_reason = choice.finish_reason;
+                    }
                 }
             }
+            model
         }
-        
-        // Send closing thinking tag
-        let stream_response = serde_json::json!({
-            "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-            "object": "chat.completion.chunk",
-            "created": chrono::Utc::now().timestamp(),
-            "model": request_clone.deepseek_config.body.get("model").unwrap_or(&serde_json::json!("deepseek-chat")),
-            "choices": [{
-                "index": 0,
-                "delta": {
-                    "content": "\n</thinking>"
-                },
-                "finish_reason": null
-            }],
-            "usage": {
-                "prompt_tokens":0,
-                "completion_tokens":0,
-                "total_tokens":0,
-            }
-        });
-        let _ = tx
-            .send(Ok(Event::default().data(
-                serde_json::to_string(&stream_response).unwrap_or_default(),
-            )))
-            .await;
-
-        tracing::info!("Stream completed. Final complete_reasoning: {}", complete_reasoning);
-        // Add complete thinking content to messages for target model
-        let mut target_messages = messages;
-        target_messages.push(Message {
-            role: Role::Assistant,
-            content: format!("<thinking>\n{}\n</thinking>", complete_reasoning),
-        });
-
-        // Stream from target model
-        match target_model.as_str() {
-            "openai" => {
-                tracing::info!("Starting OpenAI stream");
-                let openai_client = match headers.get(OPENAI_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
-                    Some(base_url) => OpenAIClient::new_with_base_url(target_token, base_url.to_string()),
-                    None => OpenAIClient::new(target_token),
+        #[cfg(feature = "anthropic")]
+        "anthropic" => {
+            let anthropic_client = AnthropicClient::new_with_client(
+                target_token.to_string(),
+                upstream_url(headers, "anthropic"),
+                state.http.clone(),
+            )
+            .with_idle_timeout(idle_timeout)
+            .with_parse_strictness(parse_strictness.anthropic)
+            .with_defaults(state.config.defaults.anthropic.clone())
+            .with_rate_limits(state.rate_limits.clone())
+            .with_signer(state.signers.anthropic.clone());
+            let (system, target_messages) = anthropic_target_messages(request, target_messages);
+            let mut model = anthropic_client.resolve_model(&request.anthropic_config);
+            // Anthropic 没有 n 参数, 每个 choice 单独发起一个流并合并
+            let mut stream = futures::stream::select_all((0..choice_count).map(|index| {
+                anthropic_client
+                    .chat_stream(target_messages.clone(), system.clone(), &request.anthropic_config)
+                    .map(move |chunk| (index as usize, chunk))
+            }));
+            let mut tool_calls: HashMap<usize, ToolCallAccumulator> = HashMap::new();
+            let mut stream_usage: HashMap<usize, anthropic::Usage> = HashMap::new();
+            while let Some((index, chunk)) = stream.next().await {
+                let event = match chunk {
+                    Ok(event) => event,
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
                 };
-                let mut openai_stream = openai_client.chat_stream(target_messages.clone(), &request_clone.openai_config);
-                tracing::info!("OpenAI messages: {:?}", target_messages);
-
-                while let Some(chunk) = openai_stream.next().await {
-                    match chunk {
-                        Ok(response) => {
-                            tracing::info!("OpenAI response chunk: {:?}", response);
-                            if let Some(choice) = response.choices.first() {
-                                if let Some(content) = &choice.delta.content {
-                                    if !content.is_empty() {
-                                        tracing::info!("OpenAI content chunk: {}", content);
-                                        let stream_response = serde_json::json!({
-                                            "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-                                            "object": "chat.completion.chunk",
-                                            "created": chrono::Utc::now().timestamp(),
-                                            "model": request_clone.openai_config.body.get("model").unwrap_or(&serde_json::json!("gpt-3.5-turbo")),
-                                            "choices": [{
-                                                "index": 0,
-                                                "delta": {
-                                                    "content": content
-                                                },
-                                                "finish_reason": null
-                                            }],
-                                            "usage": {
-                                                "prompt_tokens":0,
-                                                "completion_tokens":0,
-                                                "total_tokens":0,
-                                            }
-                                        });
-                                        let _ = tx
-                                            .send(Ok(Event::default().data(
-                                                serde_json::to_string(&stream_response).unwrap_or_default(),
-                                            )))
-                                            .await;
-                                    }
-                                }
-                            }
+                match event {
+                    anthropic::StreamEvent::MessageStart { message } => {
+                        model = message.model.clone();
+                        stream_usage.insert(index, message.usage.clone());
+                        for block in &message.content {
+                            texts[index].push_str(&block.text);
                         }
-                        Err(e) => {
-                            tracing::error!("OpenAI stream error: {}", e);
-                            let _ = tx
-                                .send(Ok(Event::default().event("error").data(
-                                    serde_json::to_string(&StreamEvent::Error {
-                                        message: e.to_string(),
-                                        code: 500,
-                                    })
-                                    .unwrap_or_default(),
-                                )))
-                                .await;
-                            return;
+                    }
+                    anthropic::StreamEvent::ContentBlockStart { index: block_index, content_block } if content_block.content_type == "tool_use" => {
+                        tool_calls
+                            .entry(index)
+                            .or_default()
+                            .start(block_index, content_block.name.unwrap_or_default());
+                    }
+                    anthropic::StreamEvent::ContentBlockDelta { index: block_index, delta } => match &delta.partial_json {
+                        Some(partial_json) => tool_calls.entry(index).or_default().push(block_index, partial_json),
+                        None => texts[index].push_str(&delta.text),
+                    },
+                    anthropic::StreamEvent::ContentBlockStop { index: block_index } => {
+                        let finished = tool_calls
+                            .get_mut(&index)
+                            .and_then(|accumulator| accumulator.finish(block_index, || state.ids.tool_call_id()));
+                        if let Some((_, call)) = finished {
+                            choices[index].tool_calls.push(call);
                         }
                     }
-                }
-                tracing::info!("OpenAI stream completed");
-            }
-            _ => {
-                tracing::info!("Starting Anthropic stream");
-                let anthropic_client = match headers.get(ANTHROPIC_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
-                    Some(base_url) => AnthropicClient::new_with_base_url(target_token, base_url.to_string()),
-                    None => AnthropicClient::new(target_token),
-                };
-                tracing::info!("Anthropic messages: {:?}", target_messages);
-                let mut anthropic_stream = anthropic_client.chat_stream(
-                    target_messages.clone(),
-                    request_clone.get_system_prompt().map(String::from),
-                    &request_clone.anthropic_config,
-                );
-
-                while let Some(chunk) = anthropic_stream.next().await {
-                    match chunk {
-                        Ok(event) => {
-                            tracing::info!("Anthropic event: {:?}", event);
-                            match event {
-                                crate::clients::anthropic::StreamEvent::MessageStart { message } => {
-                                    tracing::info!("Anthropic message start: {:?}", message);
-                                    // Only send content event if there's actual content to send
-                                    if !message.content.is_empty() {
-                                        let _ = tx
-                                            .send(Ok(Event::default().data(
-                                                serde_json::to_string(&message.content).unwrap_or_default(),
-                                            )))
-                                            .await;
-                                    }
-                                }
-                                crate::clients::anthropic::StreamEvent::ContentBlockDelta { delta, .. } => {
-                                    tracing::info!("Anthropic content delta: {:?}", delta);
-                                    // Send content update
-                                    let _ = tx
-                                        .send(Ok(Event::default().data(
-                                            serde_json::to_string(&delta).unwrap_or_default(),
-                                        )))
-                                        .await;
-                                }
-                                _ => {
-                                    tracing::info!("Anthropic other event: {:?}", event);
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            tracing::error!("Anthropic stream error: {}", e);
-                            let _ = tx
-                                .send(Ok(Event::default().data(
-                                    serde_json::to_string(&StreamEvent::Error {
-                                        message: e.to_string(),
-                                        code: 500,
-                                    })
-                                    .unwrap_or_default(),
-                                )))
-                                .await;
-                            return;
+                    anthropic::StreamEvent::MessageDelta { delta, usage: delta_usage } => {
+                        if let Some(stop_reason) = &delta.stop_reason {
+                            choices[index].finish_reason = Some(anthropic::finish_reason(stop_reason).to_string());
+                        }
+                        if let Some(delta_usage) = delta_usage {
+                            stream_usage.entry(index).or_default().output_tokens = delta_usage.output_tokens;
                         }
                     }
+                    _ => {}
                 }
-                tracing::info!("Anthropic stream completed");
             }
+            for stream_usage in stream_usage.values() {
+                usage.merge(&UsageStats::from(stream_usage));
+            }
+            model
         }
+        other => return Err(target_not_compiled(other)),
+    };
 
-        // Send done event
-        let _ = tx
-            .send(Ok(Event::default().data("[DONE]")))
-            .await;
-    });
+    for (choice, text) in choices.iter_mut().zip(texts) {
+        if !text.is_empty() {
+            choice.content.push(ContentBlock::text(text));
+        }
+    }
+    let outcome = TargetOutcome {
+        model,
+        choices,
+        usage,
+        raw: None,
+    };
+    if let Some(e) = failure {
+        // 还没有收到任何回答时按普通失败处理
+        if outcome.is_empty() {
+            return Err(e);
+        }
+        tracing::warn!("Target stream failed mid-answer, returning the partial answer: {}", e);
+        return Ok(outcome.interrupted());
+    }
+    Ok(outcome)
+}
 
-    // Convert receiver into stream
-    let stream = ReceiverStream::new(rx);
-    Ok(SseResponse::new(stream))
+/// A draft target call on the raw conversation for optimistic answering,
+/// cancelled when dropped.
+struct Draft {
+    task: tokio::task::JoinHandle<Result<TargetOutcome>>,
 }
 
-/// 获取目标模型的客户端
-fn get_target_client(headers: &axum::http::HeaderMap) -> Result<(String, String)> {
-    let target_model = headers
-        .get("X-Target-Model")
-        .map(|h| h.to_str().unwrap_or("anthropic"))
-        .unwrap_or("anthropic");
+impl Draft {
+    /// Starts calling the target on the request's messages, without reasoning.
+    fn start(
+        state: &Arc<AppState>,
+        target_model: &str,
+        target_token: &str,
+        headers: &axum::http::HeaderMap,
+        request: &ApiRequest,
+        choice_count: u32,
+    ) -> Self {
+        let state = state.clone();
+        let target_model = target_model.to_string();
+        let target_token = target_token.to_string();
+        let headers = headers.clone();
+        let request = request.clone();
+        let task = tokio::spawn(async move {
+            let messages = request.build_target_messages(None);
+            call_target(&target_model, &target_token, &headers, &request, messages, choice_count, &state).await
+        });
+        Self { task }
+    }
 
-    match target_model {
-        "openai" => {
-            let openai_token = headers
-                .get("X-OpenAI-API-Token")
-                .ok_or_else(|| ApiError::MissingHeader { 
-                    header: "X-OpenAI-API-Token".to_string() 
-                })?
-                .to_str()
-                .map_err(|_| ApiError::BadRequest { 
-                    message: "Invalid OpenAI API token".to_string() 
-                })?
-                .to_string();
-            Ok(("openai".to_string(), openai_token))
-        }
-        _ => {
-            let anthropic_token = headers
-                .get("X-Anthropic-API-Token")
-                .ok_or_else(|| ApiError::MissingHeader { 
-                    header: "X-Anthropic-API-Token".to_string() 
-                })?
-                .to_str()
-                .map_err(|_| ApiError::BadRequest { 
-                    message: "Invalid Anthropic API token".to_string() 
-                })?
-                .to_string();
-            Ok(("anthropic".to_string(), anthropic_token))
-        }
+    /// Waits for the draft's outcome.
+    async fn finish(mut self) -> Result<TargetOutcome> {
+        (&mut self.task).await.unwrap_or_else(|e| {
+            Err(ApiError::Internal {
+                message: format!("Draft target call failed: {}", e),
+            })
+        })
     }
-}
 
-impl From<serde_json::Error> for ApiError {
-    fn from(err: serde_json::Error) -> Self {
-        ApiError::Internal {
-            message: format!("JSON error: {}", err),
+    /// Cancels the draft, returning its usage if it had already finished.
+    fn discard(mut self) -> Option<UsageStats> {
+        match futures::FutureExt::now_or_never(&mut self.task) {
+            Some(Ok(Ok(outcome))) => Some(outcome.usage),
+            _ => None,
         }
     }
 }
 
-/// OpenAI compatible chat completion request format
-#[derive(Debug, Deserialize)]
-pub struct OpenAICompatRequest {
-    pub model: String,
-    pub messages: Vec<Message>,
-    #[serde(default)]
-    pub stream: bool,
-    #[serde(flatten)]
-    pub extra: serde_json::Value,
+impl Drop for Draft {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
 }
 
-/// OpenAI compatible chat completion response format
-#[derive(Debug, Serialize)]
-pub struct OpenAICompatResponse {
-    pub id: String,
-    pub object: String,
-    pub created: i64,
-    pub model: String,
-    pub choices: Vec<OpenAICompatChoice>,
-    pub usage: OpenAICompatUsage,
+/// Returns the reasoning deadline, brought forward to the optimistic
+/// reasoner wait when a draft is racing the reasoner.
+fn optimistic_deadline(
+    deadline: Option<tokio::time::Instant>,
+    optimistic: Option<OptimisticConfig>,
+) -> Option<tokio::time::Instant> {
+    let Some(optimistic) = optimistic else {
+        return deadline;
+    };
+    let wait = tokio::time::Instant::now() + optimistic.reasoner_wait();
+    Some(deadline.map_or(wait, |deadline| deadline.min(wait)))
 }
 
-#[derive(Debug, Serialize)]
-pub struct OpenAICompatChoice {
-    pub index: i32,
-    pub message: OpenAICompatMessage,
-    pub finish_reason: String,
-}
+/// Instruction appended when the answer failed schema validation; `{errors}` lists the failures.
+const SCHEMA_CORRECTION_PROMPT: &str = "Your previous answer does not match the required JSON schema:\n{errors}\nReply again with only the corrected JSON document, without any prose or code fences.";
 
-#[derive(Debug, Serialize)]
-pub struct OpenAICompatMessage {
-    pub role: String,
-    pub content: String,
+/// Makes the first choice's answer conform to the requested JSON schema.
+///
+/// An answer wrapped in prose or code fences is unwrapped when that makes it
+/// valid. Otherwise the target is asked once more, with the validation
+/// errors appended as a user message; its usage is added to `usage`.
+///
+/// # Returns
+///
+/// * `Result<TargetOutcome>` - The outcome whose first choice holds the conforming JSON
+///
+/// # Errors
+///
+/// Returns `ApiError::SchemaValidation` if the retried answer still fails,
+/// or the target client's error if the retry call fails.
+#[allow(clippy::too_many_arguments)]
+async fn enforce_json_schema(
+    validator: &jsonschema::Validator,
+    outcome: TargetOutcome,
+    usage: &mut UsageStats,
+    target_model: &str,
+    target_token: &str,
+    headers: &axum::http::HeaderMap,
+    request: &ApiRequest,
+    mut target_messages: Vec<Message>,
+    state: &AppState,
+) -> Result<TargetOutcome> {
+    let answer = outcome.answer_text();
+    let errors = match schema::conform(validator, &answer) {
+        Ok(json) => return Ok(outcome.with_answer(json)),
+        Err(errors) => errors,
+    };
+
+    // 校验错误中带有回答的片段, 按日志隐私模式处理
+    let logged_errors: Vec<String> = errors.iter().map(|error| redact::text(error)).collect();
+    tracing::warn!("Answer failed JSON schema validation, retrying once: {:?}", logged_errors);
+    usage.merge(&outcome.usage);
+    target_messages.push(Message {
+        role: Role::Assistant,
+        content: answer.into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    target_messages.push(Message {
+        role: Role::User,
+        content: SCHEMA_CORRECTION_PROMPT.replace("{errors}", &errors.join("\n")).into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+
+    let retried = call_target(target_model, target_token, headers, request, target_messages, 1, state).await?;
+    match schema::conform(validator, &retried.answer_text()) {
+        Ok(json) => Ok(retried.with_answer(json)),
+        Err(errors) => Err(ApiError::SchemaValidation { errors }),
+    }
 }
 
-#[derive(Debug, Serialize)]
-pub struct OpenAICompatUsage {
-    pub prompt_tokens: i32,
-    pub completion_tokens: i32,
-    pub total_tokens: i32,
+/// The target model's result for a request, independent of provider.
+///
+/// Keeps the typed fields needed to build both native and compat responses;
+/// the raw upstream response is only retained for verbose requests.
+struct TargetOutcome {
+    model: String,
+    choices: Vec<ResponseChoice>,
+    usage: UsageStats,
+    raw: Option<ExternalApiResponse>,
 }
 
-/// 从headers中提取token和目标模型
-fn get_auth_info(headers: &axum::http::HeaderMap) -> Result<(String, String, String)> {
-    let auth_token = headers
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|h| h.strip_prefix("Bearer "))
-        .unwrap_or("")
-        .to_string();
+impl TargetOutcome {
+    /// Returns the text of the first choice.
+    fn answer_text(&self) -> String {
+        self.choices
+            .first()
+            .map(|choice| choice.content.iter().map(|block| block.text.as_str()).collect())
+            .unwrap_or_default()
+    }
 
-    let target_model = headers
-        .get("X-Target-Model")
-        .map(|h| h.to_str().unwrap_or("openai"))
-        .unwrap_or("openai");
+    /// Returns true if no choice has answer text or tool calls.
+    fn is_empty(&self) -> bool {
+        self.choices.iter().all(|choice| {
+            choice.tool_calls.is_empty() && choice.content.iter().all(|block| block.text.trim().is_empty())
+        })
+    }
 
-    Ok((auth_token, target_model.to_string(), target_model.to_string()))
-}
+    /// Returns the first choice's finish reason.
+    fn finish_reason(&self) -> Option<String> {
+        self.choices.first().and_then(|choice| choice.finish_reason.clone())
+    }
 
-/// 构建内部请求的headers
-fn build_internal_headers(
-    original_headers: axum::http::HeaderMap,
-    token_config: &TokenConfig,
-    endpoints: &EndpointConfig,
-) -> Result<axum::http::HeaderMap> {
-    let mut headers = original_headers.clone();
-    
-    // 对于Ollama，我们需要使用特殊的认证方式
-    headers.insert(
-        "X-DeepSeek-API-Token",  // 使用标准Authorization header
-        HeaderValue::from_str(&format!("Bearer {}", token_config.deepseek_token))
-            .map_err(|e| ApiError::Internal {
-                message: format!("Invalid header value: {}", e)
-            })?
-    );
+    /// Marks every choice without a finish reason as cut off by a failure.
+    fn interrupted(mut self) -> Self {
+        for choice in self.choices.iter_mut().filter(|choice| choice.finish_reason.is_none()) {
+            choice.finish_reason = Some("interrupted".to_string());
+        }
+        self
+    }
 
-    headers.insert(
-        "X-OpenAI-API-Token",
-        HeaderValue::from_str(&token_config.openai_token)
-            .map_err(|e| ApiError::Internal {
-                message: format!("Invalid header value: {}", e)
-            })?
-    );
-    
-    headers.insert(
-        "X-Anthropic-API-Token",
-        HeaderValue::from_str(&token_config.anthropic_token)
-            .map_err(|e| ApiError::Internal {
-                message: format!("Invalid header value: {}", e)
-            })?
-    );
-    
-    
-    // 设置其他必要的headers
-    headers.insert(
-        "X-Target-Model",
-        HeaderValue::from_static("openai")
-    );
-    
-    headers.insert(
-        DEEPSEEK_ENDPOINT_URL_HEADER,
-        HeaderValue::from_str(&endpoints.deepseek)
-            .map_err(|e| ApiError::Internal {
-                message: format!("Invalid header value: {}", e)
-            })?
-    );
-    
-    headers.insert(
-        OPENAI_ENDPOINT_URL_HEADER,
-        HeaderValue::from_str(&endpoints.openai)
-            .map_err(|e| ApiError::Internal {
-                message: format!("Invalid header value: {}", e)
-            })?
-    );
+    /// Replaces the first choice's content with `answer`.
+    fn with_answer(mut self, answer: String) -> Self {
+        if let Some(choice) = self.choices.first_mut() {
+            choice.content = vec![ContentBlock::text(answer)];
+        }
+        self
+    }
 
-    Ok(headers)
+    #[cfg(feature = "openai")]
+    fn from_openai(response: OpenAIResponse, verbose: bool) -> Self {
+        let raw = verbose.then(|| ExternalApiResponse {
+            status: response.upstream.status,
+            headers: response.upstream.headers.clone(),
+            body: serde_json::to_value(&response).unwrap_or_default(),
+        });
+        let usage = UsageStats::from(&response.usage);
+
+        let choices = response
+            .choices
+            .into_iter()
+            .map(|choice| ResponseChoice {
+                index: choice.index as u32,
+                content: choice.message.content.map(ContentBlock::text).into_iter().collect(),
+                logprobs: choice.logprobs.and_then(|l| serde_json::to_value(l).ok()),
+                finish_reason: choice.finish_reason,
+                tool_calls: choice.message.tool_calls.unwrap_or_default(),
+            })
+            .collect();
+
+        Self {
+            model: response.model,
+            choices,
+            usage,
+            raw,
+        }
+    }
+
+    /// Builds an outcome from one Anthropic response per requested choice.
+    ///
+    /// `tool_use` blocks become OpenAI tool calls with ids from `ids`; all
+    /// other blocks are kept as content.
+    #[cfg(feature = "anthropic")]
+    fn from_anthropic(responses: Vec<AnthropicResponse>, verbose: bool, ids: &dyn IdGenerator) -> Self {
+        // 每个 choice 单独调用一次, 状态码和响应头取第一次调用的
+        let raw = verbose.then(|| {
+            let upstream = responses.first().map(|r| r.upstream.clone()).unwrap_or_default();
+            ExternalApiResponse {
+                status: upstream.status,
+                headers: upstream.headers,
+                body: serde_json::to_value(&responses).unwrap_or_default(),
+            }
+        });
+        let model = responses.first().map(|r| r.model.clone()).unwrap_or_default();
+        let mut usage = UsageStats::default();
+
+        let choices = responses
+            .into_iter()
+            .enumerate()
+            .map(|(index, response)| {
+                usage.merge(&UsageStats::from(&response.usage));
+                let (tool_uses, blocks): (Vec<_>, Vec<_>) = response
+                    .content
+                    .into_iter()
+                    .partition(|block| block.content_type == "tool_use");
+                ResponseChoice {
+                    index: index as u32,
+                    content: blocks.into_iter().map(ContentBlock::from).collect(),
+                    logprobs: None,
+                    finish_reason: response
+                        .stop_reason
+                        .as_deref()
+                        .map(|r| anthropic::finish_reason(r).to_string()),
+                    tool_calls: tool_uses
+                        .into_iter()
+                        .map(|block| {
+                            ToolCall::from_tool_use(ids.tool_call_id(), block.name.unwrap_or_default(), block.input.as_ref())
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Self {
+            model,
+            choices,
+            usage,
+            raw,
+        }
+    }
 }
 
-/// Handler for OpenAI compatible chat completions endpoint
-pub async fn handle_openai_chat(
+/// Handler for streaming chat requests.
+///
+/// Processes the request through both AI models sequentially,
+/// streaming their responses as Server-Sent Events.
+///
+/// # Arguments
+///
+/// * `state` - Application state containing configuration
+/// * `headers` - HTTP request headers
+/// * `request` - The parsed chat request
+///
+/// # Returns
+///
+/// * `Result<axum::response::Response>` - A stream of Server-Sent Events or an error
+pub(crate) async fn chat_stream(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
-    Json(openai_request): Json<OpenAICompatRequest>,
+    mut headers: axum::http::HeaderMap,
+    Json(request): Json<ApiRequest>,
 ) -> Result<axum::response::Response> {
-    // 获取认证信息
-    let (auth_token, _, _) = get_auth_info(&headers)?;
+    let received = Instant::now();
+    let namespace = state.tenants.of(&headers);
+    let usage_event = UsageEvent::start(&request, namespace.caller_tokens(&headers).0, state.clock.now());
+    let permit = state.admission.acquire(request_priority(&namespace, &headers)).await;
 
-    // 获取token配置
-    let token_config = state.config.auth.token_mappings
-        .get(&auth_token)
-        .unwrap_or(&state.config.auth.default_tokens);
+    // Validate system prompt
+    if !request.validate_system_prompt() {
+        return Err(ApiError::InvalidSystemPrompt);
+    }
+    check_metadata(&request.metadata)?;
 
-    // 获取模型配置
-    let model_config = &state.config.models;
-    
-    // 查找模型映射
-    let model_mapping = model_config.model_mappings
-        .get(&openai_request.model)
-        .cloned()
-        .unwrap_or_else(|| ModelMapping {
-            deepseek_model: model_config.default_deepseek.clone(),
-            target_model: model_config.default_openai.clone(),
-            parameters: serde_json::json!({}),
-        });
-
-    // 合并配置参数
-    let mut model_params = model_mapping.parameters.clone();
-    if let Some(extra) = openai_request.extra.as_object() {
-        for (key, value) in extra {
-            model_params[key] = value.clone();
-        }
-    }
-
-    // 构建内部请求格式
-    let internal_request = ApiRequest {
-        stream: openai_request.stream,
-        verbose: false,
-        system: None,
-        messages: openai_request.messages,
-        deepseek_config: ApiConfig {
-            headers: HashMap::from([
-                ("Authorization".to_string(), format!("Bearer {}", token_config.deepseek_token))
-            ]),
-            body: serde_json::json!({
-                "model": model_mapping.deepseek_model,
-                "temperature": model_params.get("temperature").unwrap_or(&serde_json::json!(0.7)),
-                "max_tokens": model_params.get("max_tokens").unwrap_or(&serde_json::json!(4096))
-            }),
-        },
-        openai_config: ApiConfig {
-            headers: HashMap::from([
-                ("Authorization".to_string(), format!("Bearer {}", token_config.openai_token))
-            ]),
-            body: serde_json::json!({
-                "model": model_mapping.target_model,
-                "temperature": model_params.get("temperature").unwrap_or(&serde_json::json!(0.7)),
-                "max_tokens": model_params.get("max_tokens").unwrap_or(&serde_json::json!(4096))
-            }),
-        },
-        anthropic_config: ApiConfig::default(),
+    // Extract API tokens
+    let deepseek_token = headers
+        .get("X-DeepSeek-API-Token")
+        .ok_or_else(|| ApiError::MissingHeader { 
+            header: "X-DeepSeek-API-Token".to_string() 
+        })?
+        .to_str()
+        .map_err(|_| ApiError::BadRequest { 
+            message: "Invalid DeepSeek API token".to_string() 
+        })?
+        .to_string();
+
+    let (target_model, target_token) = get_target_client(&headers, namespace.models.default_target_provider)?;
+    let mut request = request;
+    let target_model = resolve_openai_dialect(target_model, &mut headers, &mut request)?;
+    if target_model != "openai" && requests_logprobs(&request) {
+        return Err(ApiError::BadRequest {
+            message: "logprobs are only supported with OpenAI-compatible target models".to_string(),
+        });
+    }
+    if target_model != "openai" && requests_prediction(&request) {
+        return Err(ApiError::BadRequest {
+            message: "prediction is only supported with OpenAI-compatible target models".to_string(),
+        });
+    }
+    if request.validate_json_schema {
+        return Err(ApiError::BadRequest {
+            message: "validate_json_schema is not supported for streaming requests".to_string(),
+        });
+    }
+
+    // 将内容块转换为目标模型支持的格式, 不支持的组合直接返回 400
+    request.messages = convert_messages(&request.messages, content_target(&target_model))?;
+    strip_client_thinking(&state.config.reasoning, &mut request);
+    resolve_language_instructions(&state.config, &mut request);
+    apply_reasoner_model(&namespace.models, &mut request)?;
+
+    // Initialize clients with custom base URLs if provided
+    let idle_timeout = state.config.streaming.idle_timeout();
+    let parse_strictness = state.config.streaming.parse_strictness.clone();
+    let deepseek_client = match headers.get(DEEPSEEK_ENDPOINT_URL_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(base_url) => DeepSeekClient::new_with_base_url(deepseek_token, base_url.to_string()),
+        None => DeepSeekClient::new(deepseek_token),
+    }
+    .with_idle_timeout(idle_timeout)
+    .with_parse_strictness(parse_strictness.deepseek)
+    .with_dialect(request.reasoner_dialect)
+    .with_groq_max_tokens(state.config.reasoning.groq_max_tokens)
+    .with_defaults(state.config.defaults.deepseek.clone())
+    .with_rate_limits(state.rate_limits.clone())
+    .with_signer(state.signers.deepseek.clone());
+
+    let messages = reasoner_messages(&state.config.reasoning, &request);
+
+    let choice_count = resolve_choice_count(&request, namespace.models.max_choices)?;
+    let reasoning_config = state.config.reasoning.clone();
+    let reasoner_answer = request.reasoner_answer.unwrap_or(reasoning_config.reasoner_answer);
+    let reasoning_timeout = reasoning_timeout(&request, &reasoning_config);
+    let empty_answer_policy = state.config.target.empty_answer_policy;
+    let answer_think_tags = state.config.target.answer_think_tags;
+    let reasoner_model = deepseek_client.resolve_model(&request.deepseek_config);
+
+    // 熔断的上游在开始推流前直接失败, 推理模型熔断时可按配置跳过推理
+    let reasoner_url = upstream_url(&headers, "deepseek");
+    let target_url = upstream_url(&headers, &target_model);
+    let skip_reasoning = request.skip_reasoning || skip_open_reasoner(&state, &reasoner_url, &request.warnings)?;
+    state.circuits.check(&target_model, &target_url)?;
+    let circuits = state.circuits.clone();
+    // 推理期间预热到目标端点的连接, 随流任务结束而取消
+    let warm_up = WarmUp::start(&state.http, state.config.target.warm_up.method(&target_model), &target_url);
+    let http = state.http.clone();
+    let rate_limits = state.rate_limits.clone();
+    let signers = state.signers.clone();
+    let defaults = state.config.defaults.clone();
+
+    // 输出限速: 请求参数优先, 未设置时不做任何限速
+    let mut answer_throttle = request
+        .max_output_chars_per_second
+        .or(state.config.streaming.max_output_chars_per_second)
+        .map(OutputThrottle::new);
+    let mut reasoning_throttle = request
+        .max_reasoning_chars_per_second
+        .or(state.config.streaming.max_reasoning_chars_per_second)
+        .map(OutputThrottle::new);
+
+    // Create channel for stream events
+    let (tx, rx) = tokio::sync::mpsc::channel(state.config.server.stream_buffer.max(1));
+    let mut sink = EventSink::new(tx.clone(), state.config.server.stream_overflow, state.metrics.clone())
+        .with_coalescing(
+            state.config.server.max_coalesce_bytes,
+            Duration::from_millis(state.config.server.max_coalesce_millis),
+        )
+        .with_retry(state.config.streaming.retry());
+    let heartbeat = state.config.streaming.heartbeat();
+    // 旧版 completions 接口的 id 使用 cmpl- 前缀
+    let stream_id = match request.stream_format {
+        StreamFormat::ChatCompletion => state.ids.completion_id(),
+        StreamFormat::TextCompletion => state.ids.completion_id().replacen("chatcmpl-", "cmpl-", 1),
+    };
+    // 可恢复的流以 stream_id 登记重放缓冲, 客户端断开后继续生成
+    let resume = request.resumable.then(|| state.streams.create(&stream_id, stream_owner(&headers)));
+    if let Some(buffer) = &resume {
+        sink = sink.with_resume(buffer.clone());
+    }
+    let stream_format = request.stream_format;
+    // 推理内联在 content 中时, 在 </thinking> 与回答之间插入分隔符
+    let mut answer_separator = Some(state.config.streaming.answer_separator.clone())
+        .filter(|s| !s.is_empty() && request.thinking_format == ThinkingFormat::Tag);
+    let created = state.clock.now().timestamp();
+    let metrics = state.metrics.clone();
+    #[cfg(feature = "anthropic")]
+    let ids = state.ids.clone();
+    // 流结束后按各阶段上游报告的用量累计调用方的花费; 流失败或中断时以错误状态码发送用量事件
+    let usage_report = StreamUsage {
+        state: state.clone(),
+        caller: namespace.spend_key(&headers),
+        event: usage_event,
+        failure: Mutex::new(None),
+        reported: AtomicBool::new(false),
     };
+    let spend_state = state.clone();
+    // 工具循环的后续轮次按 reasoning_reuse 重新注入之前的推理, 不再调用推理模型
+    let reuse_policy = state.config.reasoning.reasoning_reuse;
+    let redaction_scope = state.config.reasoning.redaction_scope;
+    let reused = reused_reasoning(&state, &request).filter(|_| !skip_reasoning);
+    // 乐观回答: 推理的同时在原始对话上生成草稿, 做出选择前不输出任何内容
+    let optimistic = request.optimistic.filter(|_| !skip_reasoning && reused.is_none());
+    let draft = optimistic.map(|_| Draft::start(&state, &target_model, &target_token, &headers, &request, choice_count));
 
-    // 构建新的headers
-    let new_headers = build_internal_headers(headers, token_config, &state.config.endpoints)?;
+    // Spawn task to handle streaming
+    // 客户端断开后 sink 返回 false, 直接退出任务以丢弃上游连接
+    let request_clone = request.clone();
+    let span = request_span(&request);
+    // 看门狗在任务超过总时长上限或被管理接口终止时中止它, 并释放其并发许可
+    let phase = PhaseCell::default();
+    let task_phase = phase.clone();
+    let watched_id = stream_id.clone();
+    let target_provider = TargetProvider::from_name(&target_model).unwrap_or_default().as_str();
+    let tracer = request.tracer.clone();
+    let task = tokio::spawn(connection::scope(trace::scope(tracer, async move {
+        let _permit = permit;
 
-    // 根据stream参数选择处理方式
-    if openai_request.stream {
-        let stream_response = chat_stream(
-            State(state),
-            new_headers,
-            Json(internal_request),
-        ).await?;
-        Ok(stream_response.into_response())
-    } else {
-        let response = chat(
-            State(state),
-            new_headers,
-            Json(internal_request),
-        ).await?;
-        
-        // 转换为OpenAI格式响应
-        let openai_response = OpenAICompatResponse {
-            id: format!("chatcmpl-{}", Uuid::new_v4()),
-            object: "chat.completion".to_string(),
-            created: Utc::now().timestamp(),
-            model: openai_request.model,
-            choices: vec![OpenAICompatChoice {
-                index: 0,
-                message: OpenAICompatMessage {
-                    role: "assistant".to_string(),
-                    content: response.0.content.iter()
-                        .map(|block| block.text.clone())
-                        .collect::<Vec<_>>()
-                        .join(""),
-                },
-                finish_reason: "stop".to_string(),
-            }],
-            usage: OpenAICompatUsage {
-                prompt_tokens: 0,
-                completion_tokens: 0,
-                total_tokens: 0,
+        // // Start event
+        // let _ = tx
+        //     .send(Ok(Event::default().event("start").data(
+        //         serde_json::to_string(&StreamEvent::Start {
+        //             created: Utc::now(),
+        //         })
+        //         .unwrap_or_default(),
+        //     )))
+        //     .await;
+
+        // 对外展示的模型名: 请求方指定时所有 chunk 都使用该名称, 避免推理/回答阶段模型名不一致
+        let display_model = request_clone.model.clone().map(|model| serde_json::json!(model));
+        let upstream_reasoning_model = request_clone.deepseek_config.body.get("model").cloned().unwrap_or(serde_json::json!("deepseek-chat"));
+        let reasoning_model = ChunkHeader {
+            id: stream_id.clone(),
+            created,
+            model: display_model.clone().unwrap_or_else(|| upstream_reasoning_model.clone()),
+            format: stream_format,
+            thinking: request_clone.thinking_format,
+        };
+
+        // Stream from DeepSeek; <thinking> 标签在第一段推理到达时才发送, 超过推理时限时停止读取, 使用已生成的推理继续
+        let mut thinking_open = false;
+        let mut reasoner_timer = PhaseTimer::start();
+        let deadline = optimistic_deadline(reasoning_timeout.map(|timeout| Instant::now() + timeout), optimistic);
+        if draft.is_some() {
+            sink.hold();
+        }
+        // redaction_scope = "all" 时推理无法边生成边脱敏, 推理结束后一次性发送脱敏后的内容
+        let client_redactor = Some(&spend_state.redactor)
+            .filter(|redactor| redaction_scope == RedactionScope::All && !redactor.is_empty());
+        let mut reasoning_phase = ReasoningPhase {
+            header: &reasoning_model,
+            thinking_open: &mut thinking_open,
+            throttle: &mut reasoning_throttle,
+            reasoner_answer,
+            timer: &mut reasoner_timer,
+            deadline,
+            include_reasoning: request_clone.includes_reasoning(),
+            redactor: client_redactor,
+            usage: &usage_report,
+            use_reasoner_answer: if may_use_reasoner_answer(&request_clone, choice_count) {
+                request_clone.use_reasoner_answer
+            } else {
+                UseReasonerAnswer::Never
             },
+            confident_answer_min_chars: request_clone.confident_answer_min_chars,
+        };
+        let reasoner = Reasoner { client: &deepseek_client, circuits: &circuits, url: &reasoner_url };
+        let streamed = match &reused {
+            _ if skip_reasoning => Some(StreamedReasoning::default()),
+            Some(reasoning) => send_reused_reasoning(&mut sink, &mut reasoning_phase, reasoning).await,
+            None => {
+                stream_reasoner_phase(&reasoner, &reasoning_config, &metrics, &request_clone.deepseek_config, &messages, &mut sink, &mut reasoning_phase, choice_count).await
+            }
+        };
+        let Some(streamed) = streamed else {
+            return;
         };
+        let reasoner_usage = streamed.usage.clone();
+        // 推理在等待时间内完成时发出缓存的推理并丢弃草稿 (已完成的草稿计入用量), 否则丢弃推理改发草稿
+        let draft_usage = match draft {
+            Some(draft) if streamed.truncated => {
+                task_phase.set(StreamPhase::Answering);
+                let Some(outcome) = send_draft(&sink, draft, &circuits, &target_model, &target_url, &reasoning_model, display_model.as_ref(), &usage_report, choice_count).await else {
+                    return;
+                };
+                usage_report.complete(&reasoner_model, &reasoner_usage, &outcome.model, &outcome.usage);
+                let mut usage = reasoner_usage.clone();
+                usage.merge(&outcome.usage);
+                trace::finish(request_clone.warnings.list(), None, &usage);
+                let answered_by = answer_author(&request_clone, AnswerRole::Target, &outcome.model);
+                if !send_stream_metadata(&sink, &metrics, &[], &[], None, None, false, outcome.is_empty(), &usage, Some(OptimisticPath::Draft), answered_by, None, 0, request_clone.trimmed, &request_clone.metadata).await {
+                    return;
+                }
+                sink.send(Event::default().data("[DONE]")).await;
+                return;
+            }
+            Some(draft) => {
+                let draft_usage = draft.discard().unwrap_or_default();
+                if !sink.release().await {
+                    return;
+                }
+                draft_usage
+            }
+            None => UsageStats::default(),
+        };
+        let optimistic_path = optimistic.map(|_| OptimisticPath::Reasoned);
+        reasoner_timer.finish();
+        let StreamedReasoning { text: complete_reasoning, truncated: reasoning_timed_out, hit_max_tokens, answer: own_answer, finish_reason: reasoner_finish_reason, .. } = streamed;
+        let reasoning_truncated = reasoning_timed_out || hit_max_tokens;
+        if reasoning_truncated {
+            request_clone.warnings.push(truncated_reasoning_warning());
+        }
+
+        // 只有发送过 <thinking> 时才发送闭合标签
+        if !close_thinking(&mut sink, &reasoning_model, thinking_open).await {
+            return;
+        }
+        // 没有 thinking 块时回答前也不需要分隔符
+        if !thinking_open {
+            answer_separator = None;
+        }
+
+        tracing::info!("Stream completed. Final complete_reasoning: {}", redact::text(&complete_reasoning));
+        // Add complete thinking content to messages for target model
+        let reasoning = complete_reasoning.trim();
+        let reasoning_source = Some(reasoning)
+            .filter(|reasoning| !reasoning.is_empty() && reuse_policy != ReasoningReuse::Never)
+            .map(|reasoning| match &reused {
+                Some(_) => ReasoningSource::Reused,
+                None => {
+                    store_reasoning(&spend_state, &request_clone, reasoning);
+                    ReasoningSource::Fresh
+                }
+            });
+        if reasoning.is_empty() && !skip_reasoning {
+            if reasoning_config.empty_policy == EmptyReasoningPolicy::Skip {
+                tracing::warn!("Reasoner returned empty reasoning, continuing without it");
+            } else {
+                let e = match reasoning_timeout.filter(|_| reasoning_timed_out) {
+                    Some(timeout) => ApiError::ReasoningTimeout { model: reasoner_model, timeout_secs: timeout.as_secs() },
+                    None => ApiError::EmptyReasoning { model: reasoner_model },
+                };
+                abort_stream(&sink, &usage_report, &reasoning_model, choice_count, StreamError::reasoning(e, reasoner_timer.has_output())).await;
+                return;
+            }
+        }
+        // 推理模型自身的回答满足 use_reasoner_answer 时直接作为回答发送, 不再调用目标模型
+        if let Some(answer) = own_answer {
+            task_phase.set(StreamPhase::Answering);
+            tracing::info!("Answering with the reasoner's own answer, skipping the target");
+            let answer_model = ChunkHeader {
+                model: display_model.clone().unwrap_or_else(|| upstream_reasoning_model.clone()),
+                ..reasoning_model.clone()
+            };
+            let (answer, echoed_tags) = think::sanitize_answer(&answer, answer_think_tags);
+            request_clone.warnings.extend(answer_tags_warning(echoed_tags, answer_think_tags));
+            if !send_answer_separator(&sink, &answer_model, &mut answer_separator, 0, &answer).await {
+                return;
+            }
+            let outcome = reasoner_outcome(reasoner_model.clone(), &answer, reasoner_finish_reason.as_deref());
+            if !send_outcome(&sink, &answer_model, &outcome).await {
+                return;
+            }
+            let dropped_frames = [("deepseek", deepseek_client.dropped_frames())];
+            let upstream_models = [("reasoner", &upstream_reasoning_model)];
+            let upstream_models: &[(&str, &serde_json::Value)] = if request_clone.verbose || request_clone.reasoner_model.is_some() {
+                &upstream_models
+            } else {
+                &[]
+            };
+            let reasoner_endpoint = request_clone.replica.as_ref().map(|lease| lease.endpoint());
+            let mut target_timer = PhaseTimer::start();
+            target_timer.finish();
+            let phase_timings = timing::timings(received, &reasoner_timer, &target_timer, None);
+            let timings = request_clone.wants_timings().then(|| phase_timings.clone());
+            usage_report.complete(&reasoner_model, &reasoner_usage, &reasoner_model, &UsageStats::default());
+            trace::finish(request_clone.warnings.list(), Some(phase_timings), &reasoner_usage);
+            if !send_warnings(&sink, &request_clone.warnings).await {
+                return;
+            }
+            let answered_by = answer_author(&request_clone, AnswerRole::Reasoner, &reasoner_model);
+            if !send_stream_metadata(&sink, &metrics, &dropped_frames, upstream_models, reasoner_endpoint, timings, reasoning_truncated, false, &reasoner_usage, optimistic_path, answered_by, reasoning_source, 0, request_clone.trimmed, &request_clone.metadata).await {
+                return;
+            }
+            sink.send(Event::default().data("[DONE]")).await;
+            return;
+        }
+
+        // 按 [[redaction]] 规则脱敏注入的推理; 被截断的推理在注入时追加标记, 提示目标模型推理不完整
+        let (reasoning, redactions) = spend_state.redactor.apply(reasoning);
+        let reasoning = mark_truncated(&reasoning, reasoning_truncated, &reasoning_config.truncation_marker);
+        let target_messages = request_clone.build_target_messages(Some(reasoning.as_str()).filter(|r| !r.is_empty()));
+
+        // Stream from target model
+        task_phase.set(StreamPhase::Answering);
+        let target_warm_up = OptionFuture::from(warm_up.map(WarmUp::finish)).await.flatten();
+        let mut target_timer = PhaseTimer::start();
+        match target_model.as_str() {
+            #[cfg(feature = "openai")]
+            "openai" => {
+                tracing::info!("Starting OpenAI stream");
+                let openai_client = OpenAIClient::new_with_client(target_token, upstream_url(&headers, "openai"), http)
+                    .with_idle_timeout(idle_timeout)
+                    .with_parse_strictness(parse_strictness.openai)
+                    .with_dialect(request_clone.openai_dialect)
+                    .with_defaults(defaults.openai)
+                    .with_rate_limits(rate_limits)
+                    .with_signer(signers.openai);
+                let mut openai_config = request_clone.openai_config.clone();
+                if choice_count > 1 {
+                    openai_config.body["n"] = serde_json::json!(choice_count);
+                }
+                let mut openai_stream = openai_client.chat_stream(target_messages.clone(), &openai_config);
+                let upstream_answer_model = request_clone.openai_config.body.get("model").cloned().unwrap_or(serde_json::json!("gpt-3.5-turbo"));
+                let answer_model = ChunkHeader {
+                    model: display_model.clone().unwrap_or_else(|| upstream_answer_model.clone()),
+                    ..reasoning_model.clone()
+                };
+                tracing::info!("OpenAI messages: {:?}", Loggable(&target_messages));
+                let mut finish_reasons = HashMap::new();
+                let mut answered = false;
+                let mut retried = false;
+                let mut answer_tags = AnswerTagFilter::new(answer_think_tags);
+                // 用量只出现在最后一个 chunk 中 (Mistral 总是返回, OpenAI 需 stream_options.include_usage)
+                let mut target_usage = draft_usage;
+
+                loop {
+                    while let Some(chunk) = openai_stream.next().await {
+                        match chunk {
+                            Ok(response) => {
+                                tracing::info!("OpenAI response chunk: {:?}", Loggable(&response));
+                                if let Some(stream_usage) = &response.usage {
+                                    target_usage.merge(&UsageStats::from(stream_usage));
+                                }
+                                for choice in &response.choices {
+                                    if let Some(finish_reason) = &choice.finish_reason {
+                                        finish_reasons.insert(choice.index as u32, finish_reason.clone());
+                                    }
+                                    if let Some(content) = &choice.delta.content {
+                                        if !content.is_empty() {
+                                            target_timer.first_token();
+                                            answered = true;
+                                            tracing::info!("OpenAI content chunk: {}", redact::text(content));
+                                            let index = choice.index as u32;
+                                            if !send_answer_separator(&sink, &answer_model, &mut answer_separator, index, content).await {
+                                                return;
+                                            }
+                                            // 可能是标签开头的结尾部分留到下一个 chunk 再判断
+                                            let content = answer_tags.push(index, content);
+                                            if content.is_empty() {
+                                                continue;
+                                            }
+                                            let content = content.as_str();
+                                            match &choice.logprobs {
+                                                // logprobs 对应整段内容, 不做限速拆分
+                                                Some(logprobs) => {
+                                                    let logprobs = serde_json::to_value(logprobs).unwrap_or_default();
+                                                    if !sink.send(chunk_event_with_logprobs(&answer_model, index, content, Some(&logprobs))).await {
+                                                        return;
+                                                    }
+                                                }
+                                                None => {
+                                                    if !send_paced(&sink, &mut answer_throttle, content, |piece| chunk_event(&answer_model, index, piece)).await {
+                                                        return;
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("OpenAI stream error: {}", e);
+                                circuits.record_error(&target_model, &target_url, &e);
+                                let partial = reasoner_timer.has_output() || target_timer.has_output();
+                                abort_stream(&sink, &usage_report, &answer_model, choice_count, StreamError::answering(e, "openai", partial)).await;
+                                return;
+                            }
+                        }
+                    }
+                    // 还没有发送过任何回答, 可以提高 max_tokens 后直接重新发起目标流
+                    if answered || retried || empty_answer_policy != EmptyAnswerPolicy::RetryOnce {
+                        break;
+                    }
+                    tracing::warn!("Target returned an empty answer, retrying once with more max_tokens");
+                    retried = true;
+                    finish_reasons.clear();
+                    raise_max_tokens(&mut openai_config.body);
+                    openai_stream = openai_client.chat_stream(target_messages.clone(), &openai_config);
+                }
+                circuits.record_success(&target_model, &target_url);
+                let empty_answer = !answered;
+                if empty_answer && empty_answer_policy != EmptyAnswerPolicy::Pass {
+                    let e = ApiError::TargetEmptyResponse {
+                        model: upstream_answer_model.as_str().unwrap_or_default().to_string(),
+                        finish_reason: finish_reasons.get(&0).cloned(),
+                    };
+                    abort_stream(&sink, &usage_report, &answer_model, choice_count, StreamError::answering(e, "openai", reasoner_timer.has_output())).await;
+                    return;
+                }
+                if !flush_answer_tags(&sink, &answer_model, &mut answer_tags).await {
+                    return;
+                }
+                request_clone.warnings.extend(answer_tags_warning(answer_tags.count(), answer_think_tags));
+                for index in 0..choice_count {
+                    let finish_reason = finish_reasons.get(&index).map(String::as_str).unwrap_or("stop");
+                    if !sink.send(finish_event(&answer_model, index, finish_reason)).await {
+                        return;
+                    }
+                }
+                let dropped_frames = [("deepseek", deepseek_client.dropped_frames()), ("openai", openai_client.dropped_frames())];
+                let upstream_models = [("reasoner", &upstream_reasoning_model), ("target", &upstream_answer_model)];
+                // 覆盖了推理模型的请求即使不是 verbose 也返回实际使用的推理模型
+                let upstream_models: &[(&str, &serde_json::Value)] = match (request_clone.verbose, request_clone.reasoner_model.is_some()) {
+                    (true, _) => &upstream_models,
+                    (false, true) => &upstream_models[..1],
+                    (false, false) => &[],
+                };
+                let reasoner_endpoint = request_clone.replica.as_ref().map(|lease| lease.endpoint());
+                target_timer.finish();
+                let phase_timings = timing::timings(received, &reasoner_timer, &target_timer, target_warm_up);
+                let timings = request_clone.wants_timings().then(|| phase_timings.clone());
+                usage_report.complete(&reasoner_model, &reasoner_usage, upstream_answer_model.as_str().unwrap_or_default(), &target_usage);
+                let mut usage = reasoner_usage.clone();
+                usage.merge(&target_usage);
+                if empty_answer {
+                    request_clone.warnings.push(empty_answer_warning());
+                }
+                trace::finish(request_clone.warnings.list(), Some(phase_timings), &usage);
+                if !send_warnings(&sink, &request_clone.warnings).await {
+                    return;
+                }
+                let answered_by = answer_author(&request_clone, AnswerRole::Target, upstream_answer_model.as_str().unwrap_or_default());
+                if !send_stream_metadata(&sink, &metrics, &dropped_frames, upstream_models, reasoner_endpoint, timings, reasoning_truncated, empty_answer, &usage, optimistic_path, answered_by, reasoning_source, redactions, request_clone.trimmed, &request_clone.metadata).await {
+                    return;
+                }
+                tracing::info!("OpenAI stream completed");
+            }
+            #[cfg(feature = "anthropic")]
+            "anthropic" => {
+                tracing::info!("Starting Anthropic stream");
+                let anthropic_client = AnthropicClient::new_with_client(target_token, upstream_url(&headers, "anthropic"), http)
+                    .with_idle_timeout(idle_timeout)
+                    .with_parse_strictness(parse_strictness.anthropic)
+                    .with_defaults(defaults.anthropic)
+                    .with_rate_limits(rate_limits)
+                    .with_signer(signers.anthropic);
+                let (system, target_messages) = anthropic_target_messages(&request_clone, target_messages.clone());
+                tracing::info!("Anthropic messages: {:?}", Loggable(&target_messages));
+                let upstream_answer_model = serde_json::json!(anthropic_client.resolve_model(&request_clone.anthropic_config));
+                let answer_model = ChunkHeader {
+                    model: display_model.clone().unwrap_or_else(|| upstream_answer_model.clone()),
+                    ..reasoning_model.clone()
+                };
+                // Anthropic 没有 n 参数, 每个 choice 单独发起一个流并合并
+                let start_streams = |anthropic_config: &ApiConfig| {
+                    futures::stream::select_all((0..choice_count).map(|index| {
+                        anthropic_client
+                            .chat_stream(
+                                target_messages.clone(),
+                                system.clone(),
+                                anthropic_config,
+                            )
+                            .map(move |chunk| (index, chunk))
+                    }))
+                };
+                let mut anthropic_config = request_clone.anthropic_config.clone();
+                let mut anthropic_stream = start_streams(&anthropic_config);
+
+                let mut finish_reasons = HashMap::new();
+                // 每个 choice 的 tool_use 输入分片在块结束前缓存, 结束后整体发出
+                let mut tool_calls: HashMap<u32, ToolCallAccumulator> = HashMap::new();
+                // 每个 choice 的用量: message_start 带输入和缓存 token, message_delta 带累计的输出 token
+                let mut stream_usage: HashMap<u32, anthropic::Usage> = HashMap::new();
+                let mut target_usage = draft_usage;
+                let mut answered = false;
+                let mut retried = false;
+                let mut answer_tags = AnswerTagFilter::new(answer_think_tags);
+
+                loop {
+                    while let Some((index, chunk)) = anthropic_stream.next().await {
+                        match chunk {
+                            Ok(event) => {
+                                tracing::info!("Anthropic event: {:?}", Loggable(&event));
+                                match event {
+                                    crate::clients::anthropic::StreamEvent::MessageStart { message } => {
+                                        tracing::info!("Anthropic message start: {:?}", Loggable(&message));
+                                        stream_usage.insert(index, message.usage.clone());
+                                        // Only send content event if there's actual content to send
+                                        for block in message.content.iter().filter(|block| !block.text.is_empty()) {
+                                            answered = true;
+                                            if !send_answer_separator(&sink, &answer_model, &mut answer_separator, index, &block.text).await {
+                                                return;
+                                            }
+                                            let text = answer_tags.push(index, &block.text);
+                                            if !text.is_empty() && !sink.send(chunk_event(&answer_model, index, &text)).await {
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    crate::clients::anthropic::StreamEvent::ContentBlockStart { index: block_index, content_block } => {
+                                        if content_block.content_type == "tool_use" {
+                                            tool_calls
+                                                .entry(index)
+                                                .or_default()
+                                                .start(block_index, content_block.name.unwrap_or_default());
+                                        }
+                                    }
+                                    crate::clients::anthropic::StreamEvent::ContentBlockDelta { index: block_index, delta } => {
+                                        tracing::info!("Anthropic content delta: {:?}", Loggable(&delta));
+                                        target_timer.first_token();
+                                        if let Some(partial_json) = &delta.partial_json {
+                                            tool_calls.entry(index).or_default().push(block_index, partial_json);
+                                        } else {
+                                            // Send content update
+                                            answered |= !delta.text.is_empty();
+                                            if !send_answer_separator(&sink, &answer_model, &mut answer_separator, index, &delta.text).await {
+                                                return;
+                                            }
+                                            let text = answer_tags.push(index, &delta.text);
+                                            if !text.is_empty() && !send_paced(&sink, &mut answer_throttle, &text, |piece| chunk_event(&answer_model, index, piece)).await {
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    crate::clients::anthropic::StreamEvent::ContentBlockStop { index: block_index } => {
+                                        let finished = tool_calls
+                                            .get_mut(&index)
+                                            .and_then(|accumulator| accumulator.finish(block_index, || ids.tool_call_id()));
+                                        if let Some((position, call)) = finished {
+                                            answered = true;
+                                            if !sink.send(tool_call_event(&answer_model, index, position, &call)).await {
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    crate::clients::anthropic::StreamEvent::MessageDelta { delta, usage } => {
+                                        if let Some(stop_reason) = &delta.stop_reason {
+                                            finish_reasons.insert(index, anthropic::finish_reason(stop_reason));
+                                        }
+                                        if let Some(usage) = usage {
+                                            stream_usage.entry(index).or_default().output_tokens = usage.output_tokens;
+                                        }
+                                    }
+                                    _ => {
+                                        tracing::info!("Anthropic other event: {:?}", Loggable(&event));
+                                    }
+                                }
+                            },
+                            Err(e) => {
+                                tracing::error!("Anthropic stream error: {}", e);
+                                circuits.record_error("anthropic", &target_url, &e);
+                                let partial = reasoner_timer.has_output() || target_timer.has_output();
+                                abort_stream(&sink, &usage_report, &answer_model, choice_count, StreamError::answering(e, "anthropic", partial)).await;
+                                return;
+                            }
+                        }
+                    }
+                    // 空回答重试前的那次调用同样计费
+                    for (_, usage) in stream_usage.drain() {
+                        target_usage.merge(&UsageStats::from(&usage));
+                    }
+                    // 还没有发送过任何回答, 可以提高 max_tokens 后直接重新发起目标流
+                    if answered || retried || empty_answer_policy != EmptyAnswerPolicy::RetryOnce {
+                        break;
+                    }
+                    tracing::warn!("Target returned an empty answer, retrying once with more max_tokens");
+                    retried = true;
+                    finish_reasons.clear();
+                    tool_calls.clear();
+                    raise_max_tokens(&mut anthropic_config.body);
+                    anthropic_stream = start_streams(&anthropic_config);
+                }
+                circuits.record_success(&target_model, &target_url);
+                let empty_answer = !answered;
+                if empty_answer && empty_answer_policy != EmptyAnswerPolicy::Pass {
+                    let e = ApiError::TargetEmptyResponse {
+                        model: upstream_answer_model.as_str().unwrap_or_default().to_string(),
+                        finish_reason: finish_reasons.get(&0).map(|reason| reason.to_string()),
+                    };
+                    abort_stream(&sink, &usage_report, &answer_model, choice_count, StreamError::answering(e, "anthropic", reasoner_timer.has_output())).await;
+                    return;
+                }
+                if !flush_answer_tags(&sink, &answer_model, &mut answer_tags).await {
+                    return;
+                }
+                request_clone.warnings.extend(answer_tags_warning(answer_tags.count(), answer_think_tags));
+                for index in 0..choice_count {
+                    let finish_reason = finish_reasons.get(&index).copied().unwrap_or("stop");
+                    if !sink.send(finish_event(&answer_model, index, finish_reason)).await {
+                        return;
+                    }
+                }
+                let dropped_frames = [("deepseek", deepseek_client.dropped_frames()), ("anthropic", anthropic_client.dropped_frames())];
+                let upstream_models = [("reasoner", &upstream_reasoning_model), ("target", &upstream_answer_model)];
+                // 覆盖了推理模型的请求即使不是 verbose 也返回实际使用的推理模型
+                let upstream_models: &[(&str, &serde_json::Value)] = match (request_clone.verbose, request_clone.reasoner_model.is_some()) {
+                    (true, _) => &upstream_models,
+                    (false, true) => &upstream_models[..1],
+                    (false, false) => &[],
+                };
+                let reasoner_endpoint = request_clone.replica.as_ref().map(|lease| lease.endpoint());
+                target_timer.finish();
+                let phase_timings = timing::timings(received, &reasoner_timer, &target_timer, target_warm_up);
+                let timings = request_clone.wants_timings().then(|| phase_timings.clone());
+                usage_report.complete(&reasoner_model, &reasoner_usage, upstream_answer_model.as_str().unwrap_or_default(), &target_usage);
+                let mut usage = reasoner_usage.clone();
+                usage.merge(&target_usage);
+                if empty_answer {
+                    request_clone.warnings.push(empty_answer_warning());
+                }
+                trace::finish(request_clone.warnings.list(), Some(phase_timings), &usage);
+                if !send_warnings(&sink, &request_clone.warnings).await {
+                    return;
+                }
+                let answered_by = answer_author(&request_clone, AnswerRole::Target, upstream_answer_model.as_str().unwrap_or_default());
+                if !send_stream_metadata(&sink, &metrics, &dropped_frames, upstream_models, reasoner_endpoint, timings, reasoning_truncated, empty_answer, &usage, optimistic_path, answered_by, reasoning_source, redactions, request_clone.trimmed, &request_clone.metadata).await {
+                    return;
+                }
+                tracing::info!("Anthropic stream completed");
+            }
+            other => {
+                let provider = TargetProvider::from_name(other).unwrap_or_default().as_str();
+                abort_stream(&sink, &usage_report, &reasoning_model, choice_count, StreamError::answering(target_not_compiled(other), provider, reasoner_timer.has_output())).await;
+                return;
+            }
+        }
+
+        // Send done event
+        sink.send(Event::default().data("[DONE]")).await;
+    }.instrument(span))));
+    state.watchdog.watch(watched_id, phase, task, StreamOutput { tx, resume, target_provider });
+
+    // Convert receiver into stream
+    let stream = ReceiverStream::new(rx);
+    Ok(with_heartbeat(SseResponse::new(stream), heartbeat))
+}
+
+/// Adds keep-alive comments every `heartbeat` to an SSE response.
+fn with_heartbeat<S, E>(sse: axum::response::sse::Sse<S>, heartbeat: Option<Duration>) -> axum::response::Response
+where
+    S: futures::Stream<Item = std::result::Result<Event, E>> + Send + 'static,
+    E: Into<axum::BoxError>,
+{
+    // keep_alive 会改变流的类型, 两个分支都转换为 Response
+    match heartbeat {
+        Some(interval) => sse.keep_alive(axum::response::sse::KeepAlive::new().interval(interval)).into_response(),
+        None => sse.into_response(),
+    }
+}
+
+/// Returns true if the request asks the target for log probabilities.
+fn requests_logprobs(request: &ApiRequest) -> bool {
+    [&request.openai_config, &request.anthropic_config].iter().any(|config| {
+        config.body.get("logprobs").and_then(|l| l.as_bool()).unwrap_or(false)
+            || config.body.get("top_logprobs").is_some()
+    })
+}
+
+/// Returns true if the request sends the target a predicted output.
+fn requests_prediction(request: &ApiRequest) -> bool {
+    [&request.openai_config, &request.anthropic_config]
+        .iter()
+        .any(|config| config.body.get("prediction").is_some_and(|p| !p.is_null()))
+}
+
+/// Returns true if the reasoner's own answer may stand in for the target's.
+///
+/// Requests with tools, a structured response format, log probabilities, a
+/// predicted output, several choices or optimistic answering always go to the target.
+fn may_use_reasoner_answer(request: &ApiRequest, choice_count: u32) -> bool {
+    let structured = [&request.openai_config, &request.anthropic_config].iter().any(|config| {
+        config.body.get("tools").is_some_and(|tools| !tools.is_null())
+            || config
+                .body
+                .get("response_format")
+                .is_some_and(|format| !format.is_null() && format.get("type").and_then(|t| t.as_str()) != Some("text"))
+    });
+    request.use_reasoner_answer != UseReasonerAnswer::Never
+        && choice_count == 1
+        && request.optimistic.is_none()
+        && !request.validate_json_schema
+        && !structured
+        && !requests_logprobs(request)
+        && !requests_prediction(request)
+}
+
+/// Returns true if the reasoner's `answer`, which ended with `finish_reason`,
+/// replaces the target's under `mode`.
+fn reasoner_answer_stands(mode: UseReasonerAnswer, min_chars: usize, answer: &str, finish_reason: Option<&str>) -> bool {
+    let answer = answer.trim();
+    match mode {
+        UseReasonerAnswer::Never => false,
+        UseReasonerAnswer::Always => !answer.is_empty(),
+        UseReasonerAnswer::IfConfident => {
+            finish_reason == Some("stop") && !answer.is_empty() && answer.chars().count() >= min_chars
+        }
+    }
+}
+
+/// Builds the outcome of a request answered by the reasoner's own answer.
+///
+/// Carries no usage: the reasoner's usage is already counted.
+fn reasoner_outcome(model: String, answer: &str, finish_reason: Option<&str>) -> TargetOutcome {
+    TargetOutcome {
+        model,
+        choices: vec![ResponseChoice {
+            index: 0,
+            content: vec![ContentBlock::text(answer.trim())],
+            logprobs: None,
+            finish_reason: Some(finish_reason.unwrap_or("stop").to_string()),
+            tool_calls: Vec::new(),
+        }],
+        usage: UsageStats::default(),
+        raw: None,
+    }
+}
+
+/// Names the model that wrote the answer, for requests whose mapping sets `use_reasoner_answer`.
+fn answer_author(request: &ApiRequest, role: AnswerRole, model: &str) -> Option<AnswerAuthor> {
+    (request.use_reasoner_answer != UseReasonerAnswer::Never).then(|| AnswerAuthor {
+        role,
+        model: model.to_string(),
+    })
+}
+
+/// Resolves how many target choices a request asks for.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if `n` is zero or exceeds `max_choices`.
+fn resolve_choice_count(request: &ApiRequest, max_choices: u32) -> Result<u32> {
+    let n = request.n.unwrap_or(1);
+    if n == 0 || n > max_choices {
+        return Err(ApiError::BadRequest {
+            message: format!("n must be between 1 and {}, got {}", max_choices, n),
+        });
+    }
+    Ok(n)
+}
+
+/// User instruction appended when retrying a reasoner that returned no reasoning.
+const EMPTY_REASONING_NUDGE: &str = "请先完整地写出你的推理过程，再给出结论。";
+
+/// User instruction of a speculative reasoner run.
+const SPECULATION_INSTRUCTION: &str = "请预测用户接下来最可能提出的追问, 并提前完成回答这些追问所需的推理。";
+
+/// Introduces cached speculative reasoning in a top-up reasoner run.
+const SPECULATION_TOP_UP_PREFIX: &str = "以下是预先完成的推理, 请在此基础上针对接下来的实际问题做简短的补充推理:";
+
+/// Extracts the trimmed reasoning from a DeepSeek response.
+///
+/// Returns `None` when the first choice carries no reasoning or only whitespace.
+fn extract_reasoning(response: &DeepSeekResponse) -> Option<String> {
+    response
+        .choices
+        .first()
+        .and_then(|c| c.message.reasoning_content.as_deref())
+        .map(str::trim)
+        .filter(|reasoning| !reasoning.is_empty())
+        .map(String::from)
+}
+
+/// Returns true if the reasoner stopped because it reached its `max_tokens`.
+fn hit_max_tokens(response: &DeepSeekResponse) -> bool {
+    response
+        .choices
+        .first()
+        .and_then(|c| c.finish_reason.as_deref())
+        == Some("length")
+}
+
+/// Returns a copy of a reasoner configuration with its `max_tokens` scaled by
+/// `multiplier`; multipliers below 1 leave it unchanged.
+fn scale_max_tokens(client: &DeepSeekClient, config: &ApiConfig, multiplier: f64) -> ApiConfig {
+    let mut config = config.clone();
+    let max_tokens = client.resolve_max_tokens(&config);
+    config.body["max_tokens"] = serde_json::json!((max_tokens as f64 * multiplier.max(1.0)).ceil() as u64);
+    config
+}
+
+/// Appends the truncation marker to reasoning that was cut off, unless the
+/// marker is empty.
+fn mark_truncated(reasoning: &str, truncated: bool, marker: &str) -> String {
+    if truncated && !reasoning.is_empty() && !marker.is_empty() {
+        format!("{}\n{}", reasoning, marker)
+    } else {
+        reasoning.to_string()
+    }
+}
+
+/// Returns earlier reasoning the request reuses under `reasoning.reasoning_reuse`.
+fn reused_reasoning(state: &AppState, request: &ApiRequest) -> Option<String> {
+    let reasoning = reuse::reusable_reasoning(
+        state.config.reasoning.reasoning_reuse,
+        &state.reasoning_store,
+        request.conversation_id.as_deref(),
+        &request.messages,
+    )?;
+    tracing::info!("Reusing earlier reasoning instead of calling the reasoner");
+    Some(reasoning)
+}
+
+/// Stores a turn's fresh reasoning for the later turns of its conversation.
+fn store_reasoning(state: &AppState, request: &ApiRequest, reasoning: &str) {
+    if let Some(conversation_id) = &request.conversation_id {
+        state.reasoning_store.store(conversation_id, reasoning);
+    }
+}
+
+/// Resolves the reasoning time limit: the request's, then the server default.
+///
+/// A limit of 0 seconds, or none at all, means the reasoner may run indefinitely.
+fn reasoning_timeout(request: &ApiRequest, config: &ReasoningConfig) -> Option<Duration> {
+    request
+        .reasoning_timeout_secs
+        .or(config.reasoning_timeout_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// A reasoner whose call results are recorded in its circuit breaker.
+struct Reasoner<'a> {
+    client: &'a DeepSeekClient,
+    circuits: &'a CircuitBreakers,
+    url: &'a str,
+}
+
+impl Reasoner<'_> {
+    /// Calls the reasoner without streaming, giving up at `deadline`.
+    async fn chat(&self, deadline: Option<Instant>, messages: Vec<Message>, config: &ApiConfig) -> Result<Option<DeepSeekResponse>> {
+        let result = reason_before(deadline, self.client.chat(messages, config)).await;
+        self.circuits.record("deepseek", self.url, &result);
+        result
+    }
+
+    /// Streams one reasoner attempt to the client, see [`stream_reasoning`].
+    ///
+    /// A failure closes the thinking block and ends the stream with the
+    /// error. Returns `None` once the stream has ended.
+    async fn stream(
+        &self,
+        messages: Vec<Message>,
+        config: &ApiConfig,
+        sink: &mut EventSink,
+        phase: &mut ReasoningPhase<'_>,
+        choice_count: u32,
+    ) -> Option<StreamedReasoning> {
+        let result = stream_reasoning(self.client, messages, config, sink, phase).await;
+        self.circuits.record("deepseek", self.url, &result);
+        match result {
+            Ok(streamed) => streamed,
+            Err(e) => {
+                let failure = StreamError::reasoning(e, phase.timer.has_output());
+                abort_reasoning(sink, phase, choice_count, failure).await;
+                None
+            }
+        }
+    }
+}
+
+/// Reasoning gathered by the reasoner phase of a non-streaming request.
+#[derive(Default)]
+struct Reasoned {
+    /// The last reasoner response; `None` when the deadline passed first or
+    /// a speculative or earlier reasoning was reused as is.
+    response: Option<DeepSeekResponse>,
+    /// The reasoning, after the speculative reasoning it tops up if any.
+    content: Option<String>,
+    /// True if the reasoning stopped at the reasoner's `max_tokens`.
+    truncated: bool,
+}
+
+/// Runs the reasoner phase of a non-streaming request.
+///
+/// Empty reasoning is retried once under the `retry` empty policy, and
+/// reasoning cut off at `max_tokens` is handled by
+/// `reasoning.on_truncated_reasoning`. The usage of every call is added to
+/// `usage`.
+///
+/// # Errors
+///
+/// Returns the reasoner's error, or `ApiError::ReasoningTruncated` under the
+/// `error` truncation policy.
+async fn reason(
+    state: &AppState,
+    reasoner: &Reasoner<'_>,
+    request: &ApiRequest,
+    messages: &[Message],
+    speculative: Option<&str>,
+    deadline: Option<Instant>,
+    usage: &mut UsageStats,
+) -> Result<Reasoned> {
+    let mut response = first_reasoning(&state.config.speculation, reasoner, request, messages, speculative, deadline).await?;
+    if let Some(response) = &response {
+        usage.add(response.usage.prompt_tokens, response.usage.completion_tokens);
+    }
+    let mut content = match speculative {
+        Some(reasoning) => match response.as_ref().and_then(extract_reasoning) {
+            Some(top_up) => Some(format!("{}\n\n{}", reasoning, top_up)),
+            None => Some(reasoning.to_string()),
+        },
+        None => response.as_ref().and_then(extract_reasoning),
+    };
+
+    if response.is_some() && content.is_none() && state.config.reasoning.empty_policy == EmptyReasoningPolicy::Retry {
+        tracing::warn!("Reasoner returned empty reasoning, retrying once");
+        response = reasoner.chat(deadline, with_reasoning_nudge(messages), &request.deepseek_config).await?;
+        if let Some(response) = &response {
+            usage.add(response.usage.prompt_tokens, response.usage.completion_tokens);
+        }
+        content = response.as_ref().and_then(extract_reasoning);
+    }
+
+    let mut reasoned = Reasoned {
+        truncated: speculative.is_none() && response.as_ref().is_some_and(hit_max_tokens),
+        response,
+        content,
+    };
+    if reasoned.truncated {
+        state.metrics.record_truncated_reasoning();
+        apply_truncated_policy(state, reasoner, request, messages, deadline, &mut reasoned, usage).await?;
+    }
+    Ok(reasoned)
+}
+
+/// Makes the first reasoner call of a non-streaming request.
+///
+/// A speculative reasoning cached for the conversation is reused without a
+/// call, or topped up with a short call, as `speculation.reuse` says;
+/// otherwise the reasoner is called on the conversation.
+async fn first_reasoning(
+    speculation: &SpeculationConfig,
+    reasoner: &Reasoner<'_>,
+    request: &ApiRequest,
+    messages: &[Message],
+    speculative: Option<&str>,
+    deadline: Option<Instant>,
+) -> Result<Option<DeepSeekResponse>> {
+    match speculative {
+        Some(_) if speculation.reuse == SpeculationReuse::Reuse => Ok(None),
+        Some(reasoning) => {
+            let mut top_up_config = request.deepseek_config.clone();
+            top_up_config.body["max_tokens"] = serde_json::json!(speculation.top_up_max_tokens);
+            reasoner.chat(deadline, with_speculative_reasoning(messages, reasoning), &top_up_config).await
+        }
+        None => reasoner.chat(deadline, messages.to_vec(), &request.deepseek_config).await,
+    }
+}
+
+/// Applies `reasoning.on_truncated_reasoning` to non-streamed reasoning that
+/// stopped at the reasoner's `max_tokens`: keeps it, fails, or retries once
+/// with a larger `max_tokens`. A retry that times out keeps the first reasoning.
+async fn apply_truncated_policy(
+    state: &AppState,
+    reasoner: &Reasoner<'_>,
+    request: &ApiRequest,
+    messages: &[Message],
+    deadline: Option<Instant>,
+    reasoned: &mut Reasoned,
+    usage: &mut UsageStats,
+) -> Result<()> {
+    match state.config.reasoning.on_truncated_reasoning {
+        TruncatedReasoningPolicy::Continue => Ok(()),
+        TruncatedReasoningPolicy::Error => Err(ApiError::ReasoningTruncated {
+            model: reasoner.client.resolve_model(&request.deepseek_config),
+            max_tokens: reasoner.client.resolve_max_tokens(&request.deepseek_config),
+        }),
+        TruncatedReasoningPolicy::RetryLarger => {
+            let retry_config = scale_max_tokens(reasoner.client, &request.deepseek_config, state.config.reasoning.truncated_retry_multiplier);
+            tracing::warn!(
+                "Reasoner stopped at max_tokens, retrying once with max_tokens = {}",
+                reasoner.client.resolve_max_tokens(&retry_config)
+            );
+            if let Some(response) = reasoner.chat(deadline, messages.to_vec(), &retry_config).await? {
+                usage.add(response.usage.prompt_tokens, response.usage.completion_tokens);
+                reasoned.truncated = hit_max_tokens(&response);
+                if reasoned.truncated {
+                    state.metrics.record_truncated_reasoning();
+                }
+                reasoned.content = extract_reasoning(&response);
+                reasoned.response = Some(response);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Awaits a non-streaming reasoner call, giving up at `deadline`.
+///
+/// # Returns
+///
+/// * `Result<Option<T>>` - The call's result, or `None` if the deadline passed first
+async fn reason_before<T>(
+    deadline: Option<Instant>,
+    call: impl std::future::Future<Output = Result<T>>,
+) -> Result<Option<T>> {
+    match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, call).await {
+            Ok(result) => result.map(Some),
+            Err(_) => Ok(None),
+        },
+        None => call.await.map(Some),
+    }
+}
+
+/// Extracts the trimmed answer a DeepSeek response carries besides its reasoning.
+///
+/// For ollama reasoners this is the text outside the `<think>` tags.
+fn extract_reasoner_answer(response: &DeepSeekResponse) -> Option<String> {
+    response
+        .choices
+        .first()
+        .and_then(|c| c.message.content.as_deref())
+        .map(str::trim)
+        .filter(|answer| !answer.is_empty())
+        .map(String::from)
+}
+
+/// Returns a copy of `messages` with cached speculative reasoning to top up
+/// appended before the real question.
+fn with_speculative_reasoning(messages: &[Message], reasoning: &str) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    let question = messages.pop();
+    messages.push(Message {
+        role: Role::User,
+        content: format!("{}\n\n{}", SPECULATION_TOP_UP_PREFIX, reasoning).into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    messages.extend(question);
+    messages
+}
+
+/// Returns a copy of `messages` with the empty-reasoning nudge appended.
+fn with_reasoning_nudge(messages: &[Message]) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    messages.push(Message {
+        role: Role::User,
+        content: EMPTY_REASONING_NUDGE.into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    messages
+}
+
+/// Returns the messages sent to the reasoner, with think tags in user
+/// messages escaped unless `reasoning.user_think_tags` passes them through.
+fn reasoner_messages(config: &ReasoningConfig, request: &ApiRequest) -> Vec<Message> {
+    let mut messages = request.reasoner_messages(config.reasoner_sees_system);
+    if config.user_think_tags == UserThinkTags::Escape {
+        think::escape_user_tags(&mut messages);
+    }
+    messages
+}
+
+/// Resolves the system prompt instructions for the request's
+/// `reasoning_language` and `answer_language`.
+fn resolve_language_instructions(config: &Config, request: &mut ApiRequest) {
+    request.language_instructions = LanguageInstructions {
+        reasoner: request.reasoning_language.as_deref().map(|language| config.reasoning.language_instruction(language)),
+        target: request.answer_language.as_deref().map(|language| config.target.answer_language_instruction(language)),
+    };
+}
+
+/// Drops the thinking blocks clients sent back in assistant turns when
+/// `reasoning.strip_history_thinking` is set.
+fn strip_client_thinking(config: &ReasoningConfig, request: &mut ApiRequest) {
+    if config.strip_history_thinking {
+        think::strip_history_thinking(&mut request.messages);
+    }
+}
+
+/// Raises a target body's `max_tokens` or `max_completion_tokens` for a retry.
+///
+/// Each limit set by the caller grows by half, and by at least
+/// [`EMPTY_ANSWER_RETRY_MIN_TOKENS`]; unset limits keep the client defaults.
+fn raise_max_tokens(body: &mut serde_json::Value) {
+    for key in ["max_tokens", "max_completion_tokens"] {
+        if let Some(limit) = body.get(key).and_then(|limit| limit.as_u64()) {
+            body[key] = serde_json::json!(limit + (limit / 2).max(EMPTY_ANSWER_RETRY_MIN_TOKENS));
+        }
+    }
+}
+
+/// Returns the warning that the target's empty answer was passed through.
+fn empty_answer_warning() -> Warning {
+    Warning::in_header("empty_answer", EMPTY_ANSWER_WARNING_HEADER, "target returned an empty answer".to_string())
+}
+
+/// Returns the warning counting the think tags sanitized in the target's answer, if any.
+fn answer_tags_warning(count: u32, mode: AnswerThinkTags) -> Option<Warning> {
+    let action = if mode == AnswerThinkTags::Strip { "removed" } else { "escaped" };
+    (count > 0).then(|| {
+        Warning::new("think_tags_in_answer", format!("{} {} think tags the target wrote in its answer", action, count))
+            .with_detail(serde_json::json!({ "count": count }))
+    })
+}
+
+/// Sends the answer text the tag filter still holds back, once the target is done.
+///
+/// Returns `false` once the client has disconnected.
+async fn flush_answer_tags(sink: &EventSink, header: &ChunkHeader, filter: &mut AnswerTagFilter) -> bool {
+    for (index, text) in filter.finish() {
+        if !sink.send(chunk_event(header, index, &text)).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns the warning that the reasoning was cut off before the reasoner finished.
+fn truncated_reasoning_warning() -> Warning {
+    Warning::new("reasoning_truncated", "the reasoning was cut off, the target saw incomplete reasoning")
+}
+
+/// Returns the warning counting the messages trimmed from a request's conversation.
+///
+/// Its header carries the number of dropped messages.
+fn trim_warning(request: &ApiRequest) -> Option<Warning> {
+    request.trimmed.map(|trimmed| {
+        Warning::new("messages_trimmed", format!("dropped {} messages from the conversation", trimmed.dropped_messages))
+            .with_detail(serde_json::to_value(trimmed).unwrap_or_default())
+            .with_header(TRIMMED_MESSAGES_HEADER, trimmed.dropped_messages.to_string())
+    })
+}
+
+/// Returns the warning listing the message fields of a request body that the
+/// proxy ignores, when `server.report_unknown_message_fields` is set.
+fn unknown_fields_warning(state: &AppState, body: &serde_json::Value) -> Option<Warning> {
+    if !state.config.server.report_unknown_message_fields {
+        return None;
+    }
+    let fields = unknown_message_fields(body);
+    (!fields.is_empty()).then(|| {
+        Warning::new("message_fields_ignored", format!("ignored message fields {}", fields.join(", ")))
+            .with_detail(serde_json::json!({ "fields": fields }))
+    })
+}
+
+/// Checks whether a request that asked for a trace may have one.
+///
+/// # Returns
+///
+/// * `Result<bool>` - Whether the request is traced
+///
+/// # Errors
+///
+/// Returns `ApiError::TraceNotAllowed` if `server.request_traces` is off, or
+/// the request does not carry `server.admin_token` in `X-DeepThink-Admin-Token`.
+fn check_trace(state: &AppState, headers: &axum::http::HeaderMap, requested: bool) -> Result<bool> {
+    if !requested {
+        return Ok(false);
+    }
+    if !state.config.server.request_traces {
+        return Err(ApiError::TraceNotAllowed {
+            reason: "server.request_traces is disabled".to_string(),
+        });
+    }
+    // Config::validate 要求开启 trace 时配置 admin_token, 没有配置时同样拒绝
+    let supplied = headers.get(ADMIN_TOKEN_HEADER).and_then(|h| h.to_str().ok());
+    if state.config.server.admin_token.is_none() || supplied != state.config.server.admin_token.as_deref() {
+        return Err(ApiError::TraceNotAllowed {
+            reason: format!("traced requests must carry server.admin_token in {}", ADMIN_TOKEN_HEADER),
+        });
+    }
+    Ok(true)
+}
+
+/// Starts a request's trace under its journal id and keeps it in the trace store.
+///
+/// Runs after the parameter and capability checks, so the trace records
+/// the parameters the request is sent with.
+fn start_trace(
+    state: &AppState,
+    journal: &JournalSlot,
+    inbound: &serde_json::Value,
+    mapping: Option<&ModelMapping>,
+    request: &mut ApiRequest,
+) {
+    let id = journal.id().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let recorder = TraceRecorder::new(id, state.clock.now(), inbound);
+    recorder.set_request(mapping, request);
+    state.traces.insert(recorder.clone());
+    request.tracer = Some(recorder);
+}
+
+/// Completes a non-streaming request's trace and attaches it to the response.
+fn finish_trace(tracer: Option<&TraceRecorder>, response: &mut ApiResponse) {
+    if let Some(tracer) = tracer {
+        tracer.finish(response.warnings.clone(), Some(response.phase_timings.clone()), &response.usage);
+        response.trace = Some(tracer.snapshot());
+    }
+}
+
+/// Applies the empty answer policy to choices the compatible formats would send without an answer.
+///
+/// The pipeline already handles a target that returned nothing; this catches
+/// answers that only turn empty in the conversion, such as ones made only of
+/// thinking or unknown blocks. Retrying is no longer possible here, so
+/// `retry_once` fails like `error`.
+///
+/// # Returns
+///
+/// * `Result<Option<Warning>>` - The empty answer warning under the `pass` policy
+///
+/// # Errors
+///
+/// Returns `ApiError::TargetEmptyResponse` unless the policy is `pass`.
+fn check_empty_choices(state: &AppState, response: &ApiResponse, model: &str) -> Result<Option<Warning>> {
+    // 流水线已标记的空回答已有警告
+    let empty = conversion::empty_choices(response);
+    if empty.is_empty() || response.empty_answer {
+        return Ok(None);
+    }
+    if state.config.target.empty_answer_policy != EmptyAnswerPolicy::Pass {
+        let finish_reason = response
+            .choices
+            .iter()
+            .find(|choice| choice.index == empty[0])
+            .and_then(|choice| choice.finish_reason.clone());
+        return Err(ApiError::TargetEmptyResponse {
+            model: model.to_string(),
+            finish_reason,
+        });
+    }
+    let indexes: Vec<String> = empty.iter().map(u32::to_string).collect();
+    Ok(Some(
+        Warning::in_header(
+            "empty_answer",
+            EMPTY_ANSWER_WARNING_HEADER,
+            format!("no answer text in choice {}", indexes.join(", ")),
+        )
+        .with_detail(serde_json::json!({ "choices": empty })),
+    ))
+}
+
+/// Returns the block warning for a response with content blocks the
+/// OpenAI-compatible formats leave out.
+fn block_warning(response: &ApiResponse) -> Option<Warning> {
+    let unknown = conversion::unknown_block_types(response);
+    (!unknown.is_empty()).then(|| {
+        Warning::in_header("content_blocks_omitted", BLOCK_WARNING_HEADER, format!("left out content blocks of type {}", unknown.join(", ")))
+            .with_detail(serde_json::json!({ "types": unknown }))
+    })
+}
+
+/// Returns the system prompt carried in a target message list, if any.
+#[cfg(feature = "anthropic")]
+fn system_prompt_of(messages: &[Message]) -> Option<String> {
+    messages
+        .iter()
+        .find(|msg| msg.role.is_system())
+        .map(|msg| msg.content.to_text())
+}
+
+/// Compiles the JSON schema a request asks its answer to be validated against.
+///
+/// # Returns
+///
+/// * `Result<Option<jsonschema::Validator>>` - The validator, or `None` when
+///   validation is off or no `response_format.json_schema` was given
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the schema is invalid or more than one
+/// choice was requested.
+fn json_schema_validator(request: &ApiRequest, choice_count: u32) -> Result<Option<jsonschema::Validator>> {
+    if !request.validate_json_schema {
+        return Ok(None);
+    }
+    if choice_count > 1 {
+        return Err(ApiError::BadRequest {
+            message: "validate_json_schema supports a single choice only".to_string(),
+        });
+    }
+    schema::compile(request.openai_config.body.get("response_format"))
+}
+
+/// Maps a target name from `get_target_client` to the content format it accepts.
+fn content_target(target_model: &str) -> ContentTarget {
+    match target_model {
+        "openai" => ContentTarget::OpenAI,
+        _ => ContentTarget::Anthropic,
+    }
+}
+
+/// Splits target messages into the `system` parameter and the messages sent
+/// to an Anthropic target.
+///
+/// Anthropic takes the system prompt as a separate parameter and strict
+/// versions reject system-role messages, so both the streaming and
+/// non-streaming paths send the messages through here after the reasoning
+/// has been injected.
+///
+/// # Returns
+///
+/// * `(Option<SystemPrompt>, Vec<Message>)` - The system prompt and the
+///   messages without any system-role entries
+#[cfg(feature = "anthropic")]
+fn anthropic_target_messages(request: &ApiRequest, mut target_messages: Vec<Message>) -> (Option<SystemPrompt>, Vec<Message>) {
+    let system = anthropic_system_prompt(request, &target_messages);
+    target_messages.retain(|msg| !msg.role.is_system());
+    (system, target_messages)
+}
+
+/// Returns the system prompt to send to an Anthropic target.
+///
+/// Block-form prompts from the request are passed through with their
+/// `cache_control` markers intact; text appended by the injection strategy
+/// becomes an extra trailing block.
+#[cfg(feature = "anthropic")]
+fn anthropic_system_prompt(request: &ApiRequest, target_messages: &[Message]) -> Option<SystemPrompt> {
+    let text = system_prompt_of(target_messages)?;
+    if let Some(SystemPrompt::Blocks(blocks)) = &request.system {
+        let original = SystemPrompt::Blocks(blocks.clone()).to_text();
+        if let Some(appended) = text.strip_prefix(&original) {
+            let mut blocks = blocks.clone();
+            let appended = appended.trim();
+            if !appended.is_empty() {
+                blocks.push(SystemBlock::text(appended));
+            }
+            return Some(SystemPrompt::Blocks(blocks));
+        }
+    }
+    Some(SystemPrompt::Text(text))
+}
+
+/// Fields shared by every chunk of one streamed completion.
+///
+/// Like OpenAI's, all chunks of a stream carry the same `id` and `created`;
+/// only `model` may differ between the reasoning and answer phases.
+#[derive(Debug, Clone)]
+struct ChunkHeader {
+    id: String,
+    created: i64,
+    model: serde_json::Value,
+    format: StreamFormat,
+    /// How reasoning deltas are carried.
+    thinking: ThinkingFormat,
+}
+
+/// Builds the JSON body of one chunk for the choice at `index`.
+///
+/// Chat chunks carry `delta` as is; legacy `text_completion` chunks carry
+/// its `content` as `text` and drop everything else.
+fn chunk_body(
+    header: &ChunkHeader,
+    index: u32,
+    delta: serde_json::Value,
+    finish_reason: Option<&str>,
+) -> serde_json::Value {
+    let choice = match header.format {
+        StreamFormat::ChatCompletion => serde_json::json!({
+            "index": index,
+            "delta": delta,
+            "finish_reason": finish_reason
+        }),
+        StreamFormat::TextCompletion => serde_json::json!({
+            "index": index,
+            "text": delta.get("content").and_then(|c| c.as_str()).unwrap_or_default(),
+            "logprobs": null,
+            "finish_reason": finish_reason
+        }),
+    };
+    let object = match header.format {
+        StreamFormat::ChatCompletion => "chat.completion.chunk",
+        StreamFormat::TextCompletion => "text_completion",
+    };
+    serde_json::json!({
+        "id": header.id,
+        "object": object,
+        "created": header.created,
+        "model": header.model,
+        "choices": [choice],
+        "usage": {
+            "prompt_tokens":0,
+            "completion_tokens":0,
+            "total_tokens":0,
+        }
+    })
+}
+
+/// Builds an OpenAI-style chunk event carrying `content` for the choice at `index`.
+fn chunk_event(header: &ChunkHeader, index: u32, content: &str) -> Event {
+    chunk_event_with_logprobs(header, index, content, None)
+}
+
+/// Builds a chunk event like [`chunk_event`], attaching the target's logprobs when present.
+fn chunk_event_with_logprobs(
+    header: &ChunkHeader,
+    index: u32,
+    content: &str,
+    logprobs: Option<&serde_json::Value>,
+) -> Event {
+    let mut stream_response = chunk_body(header, index, content_delta(header, content), None);
+    if let Some(logprobs) = logprobs {
+        stream_response["choices"][0]["logprobs"] = logprobs.clone();
+    }
+    Event::default().data(serde_json::to_string(&stream_response).unwrap_or_default())
+}
+
+/// Builds the delta carrying answer text; a one-part `text` array in the
+/// `content_part` thinking format, a plain string otherwise.
+fn content_delta(header: &ChunkHeader, content: &str) -> serde_json::Value {
+    match header.thinking {
+        ThinkingFormat::ContentPart => serde_json::json!({"content": [{"type": "text", "text": content}]}),
+        ThinkingFormat::Tag | ThinkingFormat::ReasoningContent => serde_json::json!({"content": content}),
+    }
+}
+
+/// Builds a chunk event carrying one reasoning delta in the stream's thinking format.
+///
+/// With `tag` the reasoning is plain content between the `<thinking>` tags;
+/// otherwise it goes in `delta.reasoning_content` or in a `thinking` part.
+fn reasoning_event(header: &ChunkHeader, content: &str) -> Event {
+    let delta = match header.thinking {
+        ThinkingFormat::Tag => content_delta(header, content),
+        ThinkingFormat::ReasoningContent => serde_json::json!({"reasoning_content": content}),
+        ThinkingFormat::ContentPart => serde_json::json!({"content": [{"type": "thinking", "thinking": content}]}),
+    };
+    let stream_response = chunk_body(header, 0, delta, None);
+    Event::default().data(serde_json::to_string(&stream_response).unwrap_or_default())
+}
+
+/// Builds a chunk event carrying one complete tool call for the choice at
+/// `index`; `position` is the call's index among that choice's tool calls.
+fn tool_call_event(header: &ChunkHeader, index: u32, position: usize, call: &ToolCall) -> Event {
+    let delta = serde_json::json!({
+        "tool_calls": [{
+            "index": position,
+            "id": call.id,
+            "type": call.call_type,
+            "function": call.function,
+        }]
+    });
+    let stream_response = chunk_body(header, index, delta, None);
+    Event::default().data(serde_json::to_string(&stream_response).unwrap_or_default())
+}
+
+/// Builds the final chunk for the choice at `index`, carrying its finish reason.
+fn finish_event(header: &ChunkHeader, index: u32, finish_reason: &str) -> Event {
+    let stream_response = chunk_body(header, index, serde_json::json!({}), Some(finish_reason));
+    Event::default().data(serde_json::to_string(&stream_response).unwrap_or_default())
+}
+
+/// Sends the separator between the closing thinking tag and the first answer delta.
+///
+/// Only the first non-empty delta of choice 0 (the one following the inline
+/// reasoning) triggers it, and only when that delta doesn't already start
+/// with whitespace. Targets that produce no content never emit a separator.
+/// Returns `false` once the client has disconnected.
+async fn send_answer_separator(
+    sink: &EventSink,
+    header: &ChunkHeader,
+    separator: &mut Option<String>,
+    index: u32,
+    content: &str,
+) -> bool {
+    if index != 0 || content.is_empty() {
+        return true;
+    }
+    let Some(separator) = separator.take() else {
+        return true;
+    };
+    if content.starts_with(char::is_whitespace) {
+        return true;
+    }
+    sink.send(chunk_event(header, 0, &separator)).await
+}
+
+/// Sends each of the request's warnings as a `warning` event.
+///
+/// Returns `false` once the client has disconnected.
+async fn send_warnings(sink: &EventSink, warnings: &WarningCollector) -> bool {
+    for warning in warnings.list() {
+        let event = StreamEvent::Warning(warning);
+        if !sink.send(Event::default().event("warning").data(serde_json::to_string(&event).unwrap_or_default())).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Records frames the stream parsers dropped and sends the stream's metadata event.
+///
+/// The `metadata` event lists dropped frames per provider, so the client
+/// knows the answer may be incomplete, the real upstream model names
+/// when `upstream_models` is non-empty, the reasoner replica the request
+/// was routed to, the per-phase `timings` when
+/// given, whether the reasoning was cut off by the reasoning timeout,
+/// whether the target's empty answer was passed through, the token usage
+/// the upstreams reported, which optimistic answer was streamed, whether
+/// the reasoning was reused, how many redactions the injected reasoning got,
+/// what trimming dropped from the conversation, and the caller's request metadata. Nothing is
+/// sent when there is nothing to report. Returns `false` once the client
+/// has disconnected.
+#[allow(clippy::too_many_arguments)]
+async fn send_stream_metadata(
+    sink: &EventSink,
+    metrics: &Metrics,
+    dropped_frames: &[(&str, u64)],
+    upstream_models: &[(&str, &serde_json::Value)],
+    reasoner_endpoint: Option<&str>,
+    timings: Option<Timings>,
+    reasoning_truncated: bool,
+    empty_answer: bool,
+    usage: &UsageStats,
+    optimistic_path: Option<OptimisticPath>,
+    answered_by: Option<AnswerAuthor>,
+    reasoning_source: Option<ReasoningSource>,
+    redactions: u32,
+    trimmed: Option<TrimReport>,
+    metadata: &HashMap<String, String>,
+) -> bool {
+    let total: u64 = dropped_frames.iter().map(|(_, count)| count).sum();
+    metrics.record_dropped_frames(total);
+    if total == 0
+        && upstream_models.is_empty()
+        && reasoner_endpoint.is_none()
+        && timings.is_none()
+        && !reasoning_truncated
+        && !empty_answer
+        && usage.total_tokens == 0
+        && optimistic_path.is_none()
+        && answered_by.is_none()
+        && reasoning_source.is_none()
+        && redactions == 0
+        && trimmed.is_none()
+        && metadata.is_empty()
+    {
+        return true;
+    }
+
+    let event = StreamEvent::Metadata {
+        dropped_frames: dropped_frames
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(provider, count)| (provider.to_string(), *count))
+            .collect(),
+        upstream_models: upstream_models
+            .iter()
+            .map(|(role, model)| (role.to_string(), (*model).clone()))
+            .collect(),
+        reasoner_endpoint: reasoner_endpoint.map(str::to_string),
+        timings,
+        reasoning_truncated,
+        empty_answer,
+        usage: (usage.total_tokens > 0).then(|| usage.clone()),
+        partial: false,
+        optimistic_path,
+        answered_by,
+        reasoning_source,
+        redactions,
+        trimmed,
+        metadata: metadata.clone(),
+    };
+    sink.send(Event::default().event("metadata").data(serde_json::to_string(&event).unwrap_or_default())).await
+}
+
+/// Charges the caller of a stream and sends its usage event once it ends.
+///
+/// A stream dropped before [`StreamUsage::complete`], because it failed or
+/// its client went away, sends its event with the status of the error that
+/// ended it, or 499 if there was none.
+struct StreamUsage {
+    state: Arc<AppState>,
+    /// The key the caller's spend is recorded under.
+    caller: String,
+    event: UsageEvent,
+    /// Status of the error the stream was aborted with.
+    failure: Mutex<Option<u16>>,
+    reported: AtomicBool,
+}
+
+/// Streams the draft of an optimistic request whose reasoner did not finish
+/// in time, in place of the held reasoning.
+///
+/// # Returns
+///
+/// * `Option<TargetOutcome>` - The streamed draft, or `None` once the stream
+///   has ended because the draft failed or the client disconnected
+#[allow(clippy::too_many_arguments)]
+async fn send_draft(
+    sink: &EventSink,
+    draft: Draft,
+    circuits: &CircuitBreakers,
+    target_model: &str,
+    target_url: &str,
+    header: &ChunkHeader,
+    display_model: Option<&serde_json::Value>,
+    usage: &StreamUsage,
+    choice_count: u32,
+) -> Option<TargetOutcome> {
+    sink.discard_held();
+    let result = draft.finish().await;
+    circuits.record(target_model, target_url, &result);
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let provider = if target_model == "openai" { "openai" } else { "anthropic" };
+            abort_stream(sink, usage, header, choice_count, StreamError::answering(e, provider, false)).await;
+            return None;
+        }
+    };
+    tracing::info!("Reasoner did not finish in time, streaming the draft");
+    let answer_model = ChunkHeader {
+        model: display_model.cloned().unwrap_or_else(|| serde_json::json!(outcome.model)),
+        ..header.clone()
+    };
+    send_outcome(sink, &answer_model, &outcome).await.then_some(outcome)
+}
+
+impl StreamUsage {
+    /// Charges the caller for the completed stream, pricing each phase's
+    /// usage with its upstream model, and sends the usage event.
+    fn complete(&self, reasoner_model: &str, reasoner_usage: &UsageStats, target_model: &str, target_usage: &UsageStats) {
+        let state = &self.state;
+        let budget = &state.config.budget;
+        let cost = budget.cost_with_cache(
+            reasoner_model,
+            reasoner_usage.prompt_tokens,
+            reasoner_usage.cached_prompt_tokens,
+            reasoner_usage.completion_tokens,
+        ) + budget.cost_with_cache(
+            target_model,
+            target_usage.prompt_tokens,
+            target_usage.cached_prompt_tokens,
+            target_usage.completion_tokens,
+        );
+        state.spend.record(&self.caller, cost, state.clock.now());
+        state.webhooks.send(self.event.completed(reasoner_usage, target_usage, cost, state.clock.now()));
+        self.reported.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the error the stream is aborted with.
+    fn fail(&self, error: &StreamError) {
+        *self.failure.lock().unwrap_or_else(|e| e.into_inner()) = Some(error.error.to_error_response().0.as_u16());
+    }
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        if !*self.reported.get_mut() {
+            let status = self.failure.get_mut().unwrap_or_else(|e| e.into_inner()).unwrap_or(CLIENT_CLOSED_REQUEST);
+            self.state.webhooks.send(self.event.failed(status, self.state.clock.now()));
+        }
+    }
+}
+
+/// Status reported for a stream whose client went away, as in nginx.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Sends a finished target outcome as one chunk per choice, followed by the
+/// choice's tool calls and finish chunk.
+///
+/// # Returns
+///
+/// `false` if the consumer has disconnected and the stream should stop
+async fn send_outcome(sink: &EventSink, header: &ChunkHeader, outcome: &TargetOutcome) -> bool {
+    for choice in &outcome.choices {
+        let text: String = choice.content.iter().map(|block| block.text.as_str()).collect();
+        if !text.is_empty() && !sink.send(chunk_event(header, choice.index, &text)).await {
+            return false;
+        }
+        for (position, call) in choice.tool_calls.iter().enumerate() {
+            if !sink.send(tool_call_event(header, choice.index, position, call)).await {
+                return false;
+            }
+        }
+        let finish_reason = choice.finish_reason.as_deref().unwrap_or("stop");
+        if !sink.send(finish_event(header, choice.index, finish_reason)).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Ends a stream that failed mid-way with the events of `error`, so clients
+/// always see the stream terminate instead of hanging.
+///
+/// Events still held for an optimistic decision are dropped first.
+async fn abort_stream(sink: &EventSink, usage: &StreamUsage, header: &ChunkHeader, choice_count: u32, error: StreamError) {
+    usage.fail(&error);
+    sink.discard_held();
+    for event in error.events(choice_count, |index, finish_reason| finish_event(header, index, finish_reason)) {
+        if !sink.send(event).await {
+            return;
+        }
+    }
+}
+
+/// Runs the reasoner phase of a stream: the first attempt, the retry of an
+/// empty reasoning under the `retry` empty policy, and the handling of
+/// reasoning cut off at `max_tokens` under `reasoning.on_truncated_reasoning`.
+///
+/// A larger retry first marks where the reasoning was cut off in the
+/// reasoning stream. A failure closes the thinking block and ends the stream
+/// with the error.
+///
+/// # Returns
+///
+/// * `Option<StreamedReasoning>` - The reasoning to inject, with the usage
+///   of every attempt, or `None` once the stream has ended
+#[allow(clippy::too_many_arguments)]
+async fn stream_reasoner_phase(
+    reasoner: &Reasoner<'_>,
+    config: &ReasoningConfig,
+    metrics: &Metrics,
+    deepseek_config: &ApiConfig,
+    messages: &[Message],
+    sink: &mut EventSink,
+    phase: &mut ReasoningPhase<'_>,
+    choice_count: u32,
+) -> Option<StreamedReasoning> {
+    let mut streamed = reasoner.stream(messages.to_vec(), deepseek_config, sink, phase, choice_count).await?;
+    let mut usage = streamed.usage.clone();
+
+    if streamed.text.trim().is_empty() && !streamed.truncated && config.empty_policy == EmptyReasoningPolicy::Retry {
+        tracing::warn!("Reasoner returned empty reasoning, retrying once");
+        streamed = reasoner.stream(with_reasoning_nudge(messages), deepseek_config, sink, phase, choice_count).await?;
+        usage.merge(&streamed.usage);
+    }
+
+    if streamed.hit_max_tokens {
+        metrics.record_truncated_reasoning();
+        match config.on_truncated_reasoning {
+            TruncatedReasoningPolicy::Error => {
+                let e = ApiError::ReasoningTruncated {
+                    model: reasoner.client.resolve_model(deepseek_config),
+                    max_tokens: reasoner.client.resolve_max_tokens(deepseek_config),
+                };
+                let failure = StreamError::reasoning(e, phase.timer.has_output());
+                abort_reasoning(sink, phase, choice_count, failure).await;
+                return None;
+            }
+            TruncatedReasoningPolicy::RetryLarger if !streamed.truncated => {
+                let retry_config = scale_max_tokens(reasoner.client, deepseek_config, config.truncated_retry_multiplier);
+                tracing::warn!(
+                    "Reasoner stopped at max_tokens, retrying once with max_tokens = {}",
+                    reasoner.client.resolve_max_tokens(&retry_config)
+                );
+                let restart = format!("\n{}\n\n", config.truncation_marker);
+                if phase.include_reasoning && !send_reasoning_delta(sink, phase.throttle, phase.thinking_open, phase.header, &restart).await {
+                    return None;
+                }
+                streamed = reasoner.stream(messages.to_vec(), &retry_config, sink, phase, choice_count).await?;
+                usage.merge(&streamed.usage);
+                if streamed.hit_max_tokens {
+                    metrics.record_truncated_reasoning();
+                }
+            }
+            _ => {}
+        }
+    }
+    streamed.usage = usage;
+    Some(streamed)
+}
+
+/// Sends reasoning reused from an earlier turn to the client as if the
+/// reasoner had just streamed it, redacted when the phase has a `redactor`.
+///
+/// # Returns
+///
+/// * `Option<StreamedReasoning>` - The reused reasoning, or `None` once the stream has ended
+async fn send_reused_reasoning(sink: &mut EventSink, phase: &mut ReasoningPhase<'_>, reasoning: &str) -> Option<StreamedReasoning> {
+    let shown = match phase.redactor {
+        Some(redactor) => redactor.apply(reasoning).0,
+        None => reasoning.to_string(),
+    };
+    if phase.include_reasoning && !send_reasoning_delta(sink, phase.throttle, phase.thinking_open, phase.header, &shown).await {
+        return None;
+    }
+    Some(StreamedReasoning { text: reasoning.to_string(), ..StreamedReasoning::default() })
+}
+
+/// Closes the thinking block of a failed reasoning phase and ends the stream
+/// with `error`.
+async fn abort_reasoning(sink: &mut EventSink, phase: &ReasoningPhase<'_>, choice_count: u32, error: StreamError) {
+    if close_thinking(sink, phase.header, *phase.thinking_open).await {
+        abort_stream(sink, phase.usage, phase.header, choice_count, error).await;
+    }
+}
+
+/// Settings and state of the reasoning phase of one stream, shared by its
+/// first attempt and the empty-reasoning retry.
+struct ReasoningPhase<'a> {
+    /// Header of the reasoning chunks.
+    header: &'a ChunkHeader,
+    /// Whether the opening `<thinking>` tag has been sent.
+    thinking_open: &'a mut bool,
+    /// Paces reasoning output when set.
+    throttle: &'a mut Option<OutputThrottle>,
+    /// What to do with content outside the reasoning.
+    reasoner_answer: ReasonerAnswerMode,
+    /// Records the first streamed text.
+    timer: &'a mut PhaseTimer,
+    /// When to stop reading the reasoner and keep what has arrived.
+    deadline: Option<Instant>,
+    /// Forward the reasoning to the client; when false it is only collected.
+    include_reasoning: bool,
+    /// Redacts the reasoning forwarded to the client. When set the reasoning
+    /// is held back and sent redacted once the reasoner finishes.
+    redactor: Option<&'a Redactor>,
+    /// Records the status of the error a failed phase ends the stream with.
+    usage: &'a StreamUsage,
+    /// When content outside the reasoning replaces the target's answer;
+    /// `never` for requests that must go to the target.
+    use_reasoner_answer: UseReasonerAnswer,
+    /// Shortest answer `use_reasoner_answer = "if_confident"` accepts, in characters.
+    confident_answer_min_chars: usize,
+}
+
+/// Reasoning collected from one reasoner stream.
+#[derive(Default)]
+struct StreamedReasoning {
+    text: String,
+    /// True if the stream was cut off at the phase deadline.
+    truncated: bool,
+    /// True if the reasoner stopped at its `max_tokens` (`finish_reason = "length"`).
+    hit_max_tokens: bool,
+    /// Usage reported in the stream's final frame; zero when the reasoner sends none.
+    usage: UsageStats,
+    /// The reasoner's own answer, when it replaces the target's.
+    answer: Option<String>,
+    /// The reasoner's final `finish_reason`.
+    finish_reason: Option<String>,
+}
+
+/// Streams the reasoner's output to the client and collects the full reasoning.
+///
+/// Reasoning deltas (and ollama `<think>` content) are forwarded as chunk
+/// events as they arrive unless the phase leaves reasoning out, paced by the
+/// phase's throttle when one is set; with a phase `redactor` the reasoning is
+/// instead sent redacted, in one delta, once the stream ends. The
+/// opening `<thinking>` tag is sent before the first of them, and the phase's
+/// `thinking_open` records that it was, so a reasoner that produces nothing
+/// leaves no empty thinking block in the stream. Content outside the
+/// reasoning is collected and returned as the answer when the phase's
+/// `use_reasoner_answer` accepts it, otherwise handled according to the
+/// phase's `reasoner_answer`; the first streamed text is recorded on its timer.
+/// Once the phase deadline passes the stream is dropped and the reasoning so
+/// far is returned as truncated; a final `finish_reason` of `"length"` is
+/// reported as `hit_max_tokens`.
+///
+/// # Returns
+///
+/// * `Result<Option<StreamedReasoning>>` - The accumulated reasoning,
+///   possibly empty, or `None` if the client disconnected mid-stream
+///
+/// # Errors
+///
+/// Returns the first error yielded by the DeepSeek stream.
+async fn stream_reasoning(
+    deepseek_client: &DeepSeekClient,
+    messages: Vec<Message>,
+    config: &ApiConfig,
+    sink: &mut EventSink,
+    phase: &mut ReasoningPhase<'_>,
+) -> Result<Option<StreamedReasoning>> {
+    let header = phase.header;
+    let reasoner_answer = phase.reasoner_answer;
+    let thinking_open = &mut *phase.thinking_open;
+    let throttle = &mut *phase.throttle;
+    let timer = &mut *phase.timer;
+    let include_reasoning = phase.include_reasoning;
+    let redactor = phase.redactor;
+    // 需要脱敏时推理先收集, 结束后再发送
+    let forward_reasoning = include_reasoning && redactor.is_none();
+    let mut complete_reasoning = String::new();
+    let mut current_chunk = String::new();
+    let mut answer = String::new();
+    let mut think_closed = false;
+    let mut truncated = false;
+    let mut hit_max_tokens = false;
+    let mut finish_reason = None;
+    let mut usage = UsageStats::default();
+    let mut deepseek_stream = deepseek_client.chat_stream(messages, config);
+
+    loop {
+        let chunk = match phase.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, deepseek_stream.next()).await {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    tracing::warn!("Reasoning timed out, continuing with the reasoning received so far");
+                    truncated = true;
+                    break;
+                }
+            },
+            None => deepseek_stream.next().await,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let response = chunk?;
+        if let Some(reported) = &response.usage {
+            usage = UsageStats::default();
+            usage.add(reported.prompt_tokens, reported.completion_tokens);
+        }
+        if let Some(choice) = response.choices.first() {
+            tracing::info!("Stream Response: {:?}", Loggable(&response));
+            if choice.finish_reason.as_deref() == Some("length") {
+                hit_max_tokens = true;
+            }
+            if choice.finish_reason.is_some() {
+                finish_reason = choice.finish_reason.clone();
+            }
+            let has_text = choice.delta.as_ref().is_some_and(|delta| {
+                [&delta.content, &delta.reasoning_content]
+                    .iter()
+                    .any(|text| text.as_deref().is_some_and(|text| !text.is_empty()))
+            });
+            if has_text {
+                timer.first_token();
+            }
+
+            // 处理 delta 如果存在
+            if let Some(delta) = &choice.delta {
+                // 处理 content
+                if let Some(content) = &delta.content {
+                    tracing::info!("Found delta content: {}", redact::text(content));
+                    if response.system_fingerprint != "fp_ollama" {
+                        answer.push_str(content);
+                    } else if think_closed {
+                        // </think> 之后的内容是推理模型自身的回答
+                        answer.push_str(content);
+                    } else {
+                        tracing::info!("Processing ollama delta content");
+                        current_chunk.push_str(content);
+                        tracing::info!("Updated current_chunk: {}", redact::text(&current_chunk));
+                        // 只有推理模型输出开头的 <think> 才开启推理, 引用的标签按普通文本处理
+                        if think::opens(&current_chunk) && !current_chunk.contains(think::THINK_CLOSE) && content != think::THINK_OPEN
+                            && forward_reasoning
+                            && !send_reasoning_delta(sink, throttle, thinking_open, header, content).await
+                        {
+                            return Ok(None);
+                        }
+                        if think::opens(&current_chunk) && current_chunk.contains(think::THINK_CLOSE) {
+                            tracing::info!("Found complete think tags in delta");
+                            if let Some((reasoning, rest)) = AssistantMessage::extract_think_content(&current_chunk) {
+                                tracing::info!("Extracted reasoning from delta: {}", redact::text(&reasoning));
+                                complete_reasoning.push_str(&reasoning);
+                                tracing::info!("Updated complete_reasoning from delta think tags: {}", redact::text(&complete_reasoning));
+                                answer.push_str(&rest);
+                                think_closed = true;
+                                current_chunk.clear();
+                            }
+                        }
+                    }
+                }
+
+                // 处理 reasoning_content
+                if let Some(reasoning) = &delta.reasoning_content {
+                    tracing::info!("Found delta reasoning_content: {}", redact::text(reasoning));
+                    if !reasoning.is_empty() {
+                        if forward_reasoning && !send_reasoning_delta(sink, throttle, thinking_open, header, reasoning).await {
+                            return Ok(None);
+                        }
+                        complete_reasoning.push_str(reasoning);
+                        tracing::info!("Updated complete_reasoning from delta: {}", redact::text(&complete_reasoning));
+                    }
+                }
+            }
+
+            // 处理 message 如果存在
+            if let Some(message) = &choice.message {
+                if let Some(content) = &message.content {
+                    if response.system_fingerprint == "fp_ollama" {
+                        tracing::info!("Processing ollama message content");
+                        if let Some((reasoning, rest)) = AssistantMessage::extract_think_content(content) {
+                            complete_reasoning.push_str(&reasoning);
+                            tracing::info!("Updated complete_reasoning from message think tags: {}", redact::text(&complete_reasoning));
+                            answer.push_str(&rest);
+                        }
+                    } else {
+                        answer.push_str(content);
+                    }
+                }
+
+                if let Some(reasoning) = &message.reasoning_content {
+                    tracing::info!("Found message reasoning_content: {}", redact::text(reasoning));
+                    if !reasoning.is_empty() {
+                        complete_reasoning.push_str(reasoning);
+                        tracing::info!("Updated complete_reasoning from message: {}", redact::text(&complete_reasoning));
+                    }
+                }
+            }
+        }
+    }
+
+    let answer = answer.trim();
+    // 回答在推理结束前一直缓存, 此时才决定它是代替目标模型的回答还是推理的一部分; 被推理时限截断的回答不采用
+    let own_answer = (!truncated
+        && reasoner_answer_stands(phase.use_reasoner_answer, phase.confident_answer_min_chars, answer, finish_reason.as_deref()))
+    .then(|| answer.to_string());
+    let reasoner_answer = if own_answer.is_some() { ReasonerAnswerMode::Discard } else { reasoner_answer };
+    // 与非流式一致: 只有推理内容非空时才追加
+    if !answer.is_empty() && !complete_reasoning.trim().is_empty() && reasoner_answer == ReasonerAnswerMode::AppendToReasoning {
+        let appended = format!("\n\n{}", answer);
+        if forward_reasoning && !send_reasoning_delta(sink, throttle, thinking_open, header, &appended).await {
+            return Ok(None);
+        }
+        complete_reasoning.push_str(&appended);
+    }
+
+    if let Some(redactor) = redactor.filter(|_| include_reasoning && !complete_reasoning.is_empty()) {
+        let (redacted, _) = redactor.apply(&complete_reasoning);
+        if !send_reasoning_delta(sink, throttle, thinking_open, header, &redacted).await {
+            return Ok(None);
+        }
+    }
+
+    if !sink.flush_reasoning(|piece| reasoning_event(header, piece)).await {
+        return Ok(None);
+    }
+
+    if include_reasoning && !answer.is_empty() && reasoner_answer == ReasonerAnswerMode::ReturnAsBlock {
+        if !*thinking_open {
+            if !sink.send(chunk_event(header, 0, "<thinking>\n")).await {
+                return Ok(None);
+            }
+            *thinking_open = true;
+        }
+        let section = format!("\n<reasoner_answer>\n{}\n</reasoner_answer>", answer);
+        if !sink.send(chunk_event(header, 0, &section)).await {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(StreamedReasoning {
+        text: complete_reasoning,
+        truncated,
+        hit_max_tokens,
+        usage,
+        answer: own_answer,
+        finish_reason,
+    }))
+}
+
+/// Sends one reasoning delta, paced by `throttle` when one is set.
+///
+/// With the `tag` thinking format the opening `<thinking>` tag is sent first
+/// unless `thinking_open` says it already was; whitespace ahead of the first
+/// real reasoning is dropped, so whitespace-only reasoning never opens a
+/// thinking block. Unthrottled deltas go through the sink's overflow policy,
+/// so they may be coalesced while the client is behind. Returns `false` once
+/// the client has disconnected.
+async fn send_reasoning_delta(
+    sink: &mut EventSink,
+    throttle: &mut Option<OutputThrottle>,
+    thinking_open: &mut bool,
+    header: &ChunkHeader,
+    content: &str,
+) -> bool {
+    if !*thinking_open {
+        if content.trim().is_empty() {
+            return true;
+        }
+        if header.thinking == ThinkingFormat::Tag && !sink.send(chunk_event(header, 0, "<thinking>\n")).await {
+            return false;
+        }
+        *thinking_open = true;
+    }
+    match throttle {
+        Some(_) => send_paced(sink, throttle, content, |piece| reasoning_event(header, piece)).await,
+        None => sink.send_reasoning(content, |piece| reasoning_event(header, piece)).await,
+    }
+}
+
+/// Sends any held-back reasoning, then the closing `</thinking>` tag if the
+/// opening tag was sent.
+///
+/// Structured thinking formats have no tags, so no tag is sent for them.
+/// Returns `false` once the client has disconnected.
+async fn close_thinking(sink: &mut EventSink, header: &ChunkHeader, thinking_open: bool) -> bool {
+    sink.flush_reasoning(|piece| reasoning_event(header, piece)).await
+        && (!thinking_open || header.thinking != ThinkingFormat::Tag || sink.send(chunk_event(header, 0, "\n</thinking>")).await)
+}
+
+/// 获取目标模型的客户端
+///
+/// Requests without an `X-Target-Model` header use `default_provider`.
+fn get_target_client(headers: &axum::http::HeaderMap, default_provider: TargetProvider) -> Result<(String, String)> {
+    let requested = headers.get("X-Target-Model").and_then(|h| h.to_str().ok());
+    let target_model = requested.unwrap_or(default_provider.as_str());
+    // 未编译进来的 provider 在检查 token 之前就拒绝, 未知名称按 anthropic 处理
+    TargetProvider::from_name(target_model).unwrap_or_default().ensure_compiled()?;
+    let missing = |header: &str| ApiError::MissingProviderToken {
+        header: header.to_string(),
+        provider: target_model.to_string(),
+        by_default: requested.is_none(),
+    };
+
+    match target_model {
+        "openai" => {
+            let openai_token = headers
+                .get("X-OpenAI-API-Token")
+                .ok_or_else(|| missing("X-OpenAI-API-Token"))?
+                .to_str()
+                .map_err(|_| ApiError::BadRequest { 
+                    message: "Invalid OpenAI API token".to_string() 
+                })?
+                .to_string();
+            Ok(("openai".to_string(), openai_token))
+        }
+        "mistral" => {
+            let mistral_token = headers
+                .get("X-Mistral-API-Token")
+                .ok_or_else(|| missing("X-Mistral-API-Token"))?
+                .to_str()
+                .map_err(|_| ApiError::BadRequest { 
+                    message: "Invalid Mistral API token".to_string() 
+                })?
+                .to_string();
+            Ok(("mistral".to_string(), mistral_token))
+        }
+        _ => {
+            let anthropic_token = headers
+                .get("X-Anthropic-API-Token")
+                .ok_or_else(|| missing("X-Anthropic-API-Token"))?
+                .to_str()
+                .map_err(|_| ApiError::BadRequest { 
+                    message: "Invalid Anthropic API token".to_string() 
+                })?
+                .to_string();
+            Ok(("anthropic".to_string(), anthropic_token))
+        }
+    }
+}
+
+/// Returns the error for a target whose client is not compiled into this build.
+///
+/// [`get_target_client`] already rejects such targets; the pipeline's
+/// provider matches fall back to this only to stay exhaustive.
+fn target_not_compiled(target_model: &str) -> ApiError {
+    let provider = TargetProvider::from_name(target_model).unwrap_or_default();
+    ApiError::ProviderNotCompiled {
+        provider: provider.as_str().to_string(),
+        feature: provider.feature(),
+    }
+}
+
+/// Resolves the `mistral` target to the OpenAI client in the Mistral dialect.
+///
+/// A `mistral` target without `X-OpenAI-Endpoint-URL` is sent to Mistral's
+/// API. For OpenAI targets the request's parameters are checked against its
+/// dialect, so an unsupported one fails before the reasoner runs.
+///
+/// # Returns
+///
+/// * `Result<String>` - The target name the rest of the pipeline uses
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the dialect does not support a parameter
+/// of the request, such as `n` above 1 for Mistral.
+fn resolve_openai_dialect(
+    target_model: String,
+    headers: &mut axum::http::HeaderMap,
+    request: &mut ApiRequest,
+) -> Result<String> {
+    let target_model = match target_model.as_str() {
+        "mistral" => {
+            request.openai_dialect = OpenAIDialect::Mistral;
+            if !headers.contains_key(OPENAI_ENDPOINT_URL_HEADER) {
+                headers.insert(OPENAI_ENDPOINT_URL_HEADER, HeaderValue::from_static(MISTRAL_API_URL));
+            }
+            "openai".to_string()
+        }
+        _ => target_model,
+    };
+    #[cfg(feature = "openai")]
+    if target_model == "openai" {
+        let mut body = request.openai_config.body.clone();
+        if let Some(n) = request.n {
+            body["n"] = serde_json::json!(n);
+        }
+        check_dialect_params(request.openai_dialect, &body)?;
+    }
+    Ok(target_model)
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::Internal {
+            message: format!("JSON error: {}", err),
+        }
+    }
+}
+
+/// 从headers中提取token和目标模型
+fn get_auth_info(headers: &axum::http::HeaderMap) -> Result<(String, String, String)> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .to_string();
+
+    let target_model = headers
+        .get("X-Target-Model")
+        .map(|h| h.to_str().unwrap_or("openai"))
+        .unwrap_or("openai");
+
+    Ok((auth_token, target_model.to_string(), target_model.to_string()))
+}
+
+/// Returns the admission priority of the caller's token.
+fn request_priority(namespace: &Namespace, headers: &axum::http::HeaderMap) -> u8 {
+    namespace.caller_tokens(headers).1.priority
+}
+
+/// Checks the caller's spend budgets before a pipeline starts.
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - The warning to return in the
+///   `X-DeepThink-Budget-Warning` header, if the caller is close to a limit
+///
+/// # Errors
+///
+/// Returns `ApiError::BudgetExceeded` if a budget is used up.
+fn check_budget(state: &AppState, headers: &axum::http::HeaderMap) -> Result<Option<String>> {
+    let namespace = state.tenants.of(headers);
+    state.spend.check(&namespace.spend_key(headers), namespace.caller_tokens(headers).1, &state.config.budget, state.clock.now())
+}
+
+/// Returns the budget warning, if any.
+fn budget_warnings(state: &AppState, headers: &axum::http::HeaderMap) -> Result<Option<Warning>> {
+    Ok(check_budget(state, headers)?.map(|warning| Warning::in_header("budget_near_limit", BUDGET_WARNING_HEADER, warning)))
+}
+
+/// Checks the caller's request metadata against the size limits.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if there are more than
+/// [`MAX_METADATA_KEYS`] entries or a key or value is too long.
+fn check_metadata(metadata: &HashMap<String, String>) -> Result<()> {
+    if metadata.len() > MAX_METADATA_KEYS {
+        return Err(ApiError::BadRequest {
+            message: format!("metadata has {} keys, at most {} are allowed", metadata.len(), MAX_METADATA_KEYS),
+        });
+    }
+    for (key, value) in metadata {
+        if key.chars().count() > MAX_METADATA_KEY_CHARS {
+            return Err(ApiError::BadRequest {
+                message: format!("metadata key '{}' is longer than {} characters", key, MAX_METADATA_KEY_CHARS),
+            });
+        }
+        if value.chars().count() > MAX_METADATA_VALUE_CHARS {
+            return Err(ApiError::BadRequest {
+                message: format!("metadata value of '{}' is longer than {} characters", key, MAX_METADATA_VALUE_CHARS),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the tracing span of one request, carrying the caller's metadata.
+fn request_span(request: &ApiRequest) -> tracing::Span {
+    tracing::info_span!("request", metadata = ?request.metadata)
+}
+
+/// Returns the URL a provider's requests go to, which keys its circuit breaker.
+fn upstream_url(headers: &axum::http::HeaderMap, provider: &str) -> String {
+    let (header, default_url) = match provider {
+        "deepseek" => (DEEPSEEK_ENDPOINT_URL_HEADER, DEEPSEEK_API_URL),
+        "openai" => (OPENAI_ENDPOINT_URL_HEADER, OPENAI_API_URL),
+        _ => (ANTHROPIC_ENDPOINT_URL_HEADER, ANTHROPIC_API_URL),
+    };
+    headers
+        .get(header)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(default_url)
+        .to_string()
+}
+
+/// Checks the reasoner's circuit before a request starts.
+///
+/// Skipping the reasoning is reported in `warnings`.
+///
+/// # Returns
+///
+/// * `Result<bool>` - True if the circuit is open and `circuit_breaker.reasoner_open`
+///   says to answer without reasoning
+///
+/// # Errors
+///
+/// Returns `ApiError::CircuitOpen` if the circuit is open and the request should fail fast.
+fn skip_open_reasoner(state: &AppState, reasoner_url: &str, warnings: &WarningCollector) -> Result<bool> {
+    match state.circuits.check("deepseek", reasoner_url) {
+        Ok(()) => Ok(false),
+        Err(e) if state.config.circuit_breaker.reasoner_open == ReasonerCircuitAction::SkipReasoning => {
+            tracing::warn!("{}, continuing without reasoning", e);
+            warnings.push(Warning::new("reasoning_skipped", format!("{}, answered without reasoning", e)));
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the endpoint override policy to the caller's `X-*-Endpoint-URL`
+/// headers and normalizes the overrides it lets through.
+///
+/// Must run before [`build_internal_headers`] fills in the configured endpoints.
+///
+/// # Returns
+///
+/// * `Result<Vec<Warning>>` - The endpoint warning if overrides were ignored,
+///   and one for every override that was normalized
+///
+/// # Errors
+///
+/// Returns `ApiError::EndpointNotAllowed` for a rejected override.
+fn check_endpoint_overrides(state: &AppState, headers: &mut axum::http::HeaderMap) -> Result<Vec<Warning>> {
+    let namespace = state.tenants.resolve(headers)?;
+    let ignored = endpoints::check_overrides(&namespace.endpoints, headers)?;
+    let mut warnings: Vec<Warning> = ignored
+        .map(|warning| Warning::in_header("endpoint_override_ignored", ENDPOINT_WARNING_HEADER, warning))
+        .into_iter()
+        .collect();
+    for note in endpoints::normalize_overrides(&namespace.endpoints, headers) {
+        tracing::warn!("{}", note);
+        warnings.push(Warning::new("endpoint_normalized", note));
+    }
+    Ok(warnings)
+}
+
+/// Applies `parameter_policy` to the parameters the caller supplied.
+///
+/// `compat_fields` holds a compat request's extra fields; `None` marks a
+/// native request, whose bodies come entirely from the caller.
+///
+/// # Returns
+///
+/// * `Result<Option<Warning>>` - The parameter warning listing dropped
+///   parameters, if any
+///
+/// # Errors
+///
+/// Returns `ApiError::ParameterNotAllowed` in strict mode.
+fn check_parameters(
+    state: &AppState,
+    request: &mut ApiRequest,
+    compat_fields: Option<&serde_json::Value>,
+) -> Result<Option<Warning>> {
+    let warning = parameters::enforce(&state.config.parameter_policy, request, compat_fields)?;
+    Ok(warning.map(|warning| Warning::in_header("parameters_dropped", PARAMETER_WARNING_HEADER, warning)))
+}
+
+/// Checks the request against its target model's capabilities.
+///
+/// Images the target cannot accept are replaced with placeholders when
+/// `models.degrade_images` is set.
+///
+/// # Returns
+///
+/// * `Result<Option<Warning>>` - The content warning describing degraded
+///   content, if any
+///
+/// # Errors
+///
+/// Returns `ApiError::UnsupportedCapability` naming the first feature the
+/// target does not support.
+fn check_capabilities(
+    state: &AppState,
+    headers: &axum::http::HeaderMap,
+    request: &mut ApiRequest,
+) -> Result<Option<Warning>> {
+    let namespace = state.tenants.of(headers);
+    let models = &namespace.models;
+    let provider = headers
+        .get("X-Target-Model")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(models.default_target_provider.as_str());
+    let (body, default_model) = if matches!(provider, "openai" | "mistral") {
+        (&request.openai_config.body, &models.default_openai)
+    } else {
+        (&request.anthropic_config.body, &models.default_anthropic)
+    };
+    let model = body.get("model").and_then(|m| m.as_str()).unwrap_or(default_model);
+    let mapping_capabilities = request
+        .model
+        .as_ref()
+        .and_then(|name| models.model_mappings.get(name))
+        .and_then(|mapping| mapping.capabilities.as_ref());
+    let capabilities = capabilities::resolve(models, mapping_capabilities, model);
+
+    let warning = capabilities::enforce(&mut request.messages, body, model, &capabilities, models.degrade_images)?;
+    Ok(warning.map(|warning| Warning::in_header("content_degraded", CONTENT_WARNING_HEADER, warning)))
+}
+
+/// Adds the headers of the collected warnings to a response.
+fn with_warnings(response: axum::response::Response, warnings: &WarningCollector) -> axum::response::Response {
+    with_headers(response, warnings.headers())
+}
+
+/// Adds headers to a response, skipping values that are not valid header values.
+fn with_headers(mut response: axum::response::Response, headers: Vec<(&'static str, String)>) -> axum::response::Response {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Applies a request's `reasoner_model` override to its reasoner body.
+///
+/// The override wins over `deepseek_config.body.model` and, on the compat
+/// endpoint, over the mapping's `deepseek_model`.
+///
+/// # Errors
+///
+/// Returns `ApiError::ReasonerModelNotAllowed` if `models.allowed_reasoner_models`
+/// is set and does not list the requested model. A model set directly in
+/// `deepseek_config.body.model` must be listed too, unless it is one of the
+/// configured reasoner models.
+fn apply_reasoner_model(config: &ModelConfig, request: &mut ApiRequest) -> Result<()> {
+    let Some(model) = request.reasoner_model.as_deref() else {
+        let body_model = request.deepseek_config.body.get("model").and_then(|m| m.as_str());
+        return match (&config.allowed_reasoner_models, body_model) {
+            // 配置中的推理模型 (默认模型和映射的 deepseek_model) 总是允许, 兼容接口的请求体由映射填写
+            (Some(allowed), Some(model))
+                if !allowed.iter().any(|m| m == model)
+                    && model != config.default_deepseek
+                    && !config.model_mappings.values().any(|mapping| mapping.deepseek_model == model) =>
+            {
+                Err(ApiError::ReasonerModelNotAllowed {
+                    model: model.to_string(),
+                    allowed: allowed.clone(),
+                })
+            }
+            _ => Ok(()),
+        };
+    };
+    if let Some(allowed) = &config.allowed_reasoner_models {
+        if !allowed.iter().any(|m| m == model) {
+            return Err(ApiError::ReasonerModelNotAllowed {
+                model: model.to_string(),
+                allowed: allowed.clone(),
+            });
+        }
+    }
+    let body = &mut request.deepseek_config.body;
+    if body.is_null() {
+        *body = serde_json::json!({});
+    }
+    if let Some(body) = body.as_object_mut() {
+        body.insert("model".to_string(), serde_json::json!(model));
+    }
+    Ok(())
+}
+
+/// Returns the reasoner model to report in a non-streaming response.
+///
+/// Reported for verbose requests and whenever the request chose its reasoner.
+fn reported_reasoner_model(request: &ApiRequest) -> Option<String> {
+    if !request.verbose && request.reasoner_model.is_none() {
+        return None;
+    }
+    request
+        .deepseek_config
+        .body
+        .get("model")
+        .and_then(|model| model.as_str())
+        .map(String::from)
+}
+
+/// Returns the upstream model name when `model` is configured for passthrough.
+///
+/// A mapping marked `passthrough` renames the model to its `target_model`;
+/// models listed in `passthrough_models` keep their name.
+fn passthrough_target(config: &ModelConfig, model: &str) -> Option<String> {
+    match config.model_mappings.get(model) {
+        Some(mapping) if mapping.passthrough => Some(mapping.target_model.clone()),
+        _ if config.passthrough_models.iter().any(|m| m == model) => Some(model.to_string()),
+        _ => None,
+    }
+}
+
+/// 构建内部请求的headers
+///
+/// Tokens come from the caller's `TokenConfig`, falling back to token headers
+/// sent with the original request. An explicit `X-Target-Model` and endpoint
+/// override headers are preserved; configured values are only used when absent.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if no token is available for the resolved
+/// target provider.
+fn build_internal_headers(
+    original_headers: axum::http::HeaderMap,
+    token_config: &TokenConfig,
+    endpoints: &EndpointConfig,
+) -> Result<axum::http::HeaderMap> {
+    let mut headers = original_headers.clone();
+    
+    // 对于Ollama，我们需要使用特殊的认证方式
+    if !token_config.deepseek_token.is_empty() {
+        insert_header(&mut headers, "X-DeepSeek-API-Token", &format!("Bearer {}", token_config.deepseek_token))?;
+    }
+    if !token_config.openai_token.is_empty() {
+        insert_header(&mut headers, "X-OpenAI-API-Token", &token_config.openai_token)?;
+    }
+    if !token_config.anthropic_token.is_empty() {
+        insert_header(&mut headers, "X-Anthropic-API-Token", &token_config.anthropic_token)?;
+    }
+
+    // 显式指定的目标模型和端点优先, 缺省时使用配置
+    if !headers.contains_key("X-Target-Model") {
+        headers.insert("X-Target-Model", HeaderValue::from_static("openai"));
+    }
+    for (header, endpoint) in [
+        (DEEPSEEK_ENDPOINT_URL_HEADER, &endpoints.deepseek),
+        (OPENAI_ENDPOINT_URL_HEADER, &endpoints.openai),
+        (ANTHROPIC_ENDPOINT_URL_HEADER, &endpoints.anthropic),
+    ] {
+        if !headers.contains_key(header) {
+            insert_header(&mut headers, header, endpoint)?;
+        }
+    }
+
+    // 校验目标模型所需的 token 是否可用
+    let target_model = headers
+        .get("X-Target-Model")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("openai");
+    let token_header = match target_model {
+        "openai" => "X-OpenAI-API-Token",
+        "mistral" => "X-Mistral-API-Token",
+        _ => "X-Anthropic-API-Token",
+    };
+    let has_token = headers
+        .get(token_header)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|t| !t.is_empty());
+    if !has_token {
+        return Err(ApiError::BadRequest {
+            message: format!(
+                "No API token available for target model '{}': configure it in auth.token_mappings or send the {} header",
+                target_model, token_header
+            ),
+        });
+    }
+
+    Ok(headers)
+}
+
+/// Inserts a header, reporting invalid values as internal errors.
+fn insert_header(headers: &mut axum::http::HeaderMap, name: &'static str, value: &str) -> Result<()> {
+    headers.insert(
+        name,
+        HeaderValue::from_str(value).map_err(|e| ApiError::Internal {
+            message: format!("Invalid header value: {}", e),
+        })?,
+    );
+    Ok(())
+}
+
+/// Handler for OpenAI compatible chat completions endpoint
+pub async fn handle_openai_chat(
+    State(state): State<Arc<AppState>>,
+    RouteProfile(profile): RouteProfile,
+    journal: JournalSlot,
+    mut headers: axum::http::HeaderMap,
+    Json(raw_request): Json<serde_json::Value>,
+) -> Result<axum::response::Response> {
+    if let Some(response) = resume_last_event(&state, &headers)? {
+        return Ok(response);
+    }
+    let mut openai_request: OpenAICompatRequest = serde_json::from_value(raw_request.clone())
+        .map_err(|e| ApiError::BadRequest {
+            message: format!("Invalid chat completion request: {}", e),
+        })?;
+    let stream = raw_request.get("stream").and_then(|v| v.as_bool());
+    let transport = negotiate::stream_transport(&headers, raw_request.get("stream_transport"))?;
+    openai_request.stream = negotiate::wants_stream(state.config.streaming.accept_precedence, &headers, stream)?;
+    journal.set_request(Some(&openai_request.model), openai_request.stream);
+
+    // 获取认证信息
+    get_auth_info(&headers)?;
+    let namespace = state.tenants.resolve(&headers)?;
+
+    // 获取token配置
+    let token_config = namespace.caller_tokens(&headers).1;
+
+    // 获取模型配置
+    let model_config = &namespace.models;
+
+    // profile 路由先检查限流和允许的模型
+    let mut rate_limit_headers = Vec::new();
+    if let Some(profile) = &profile {
+        rate_limit_headers = profile.admit()?;
+        profile.check_model(&openai_request.model)?;
+    }
+
+    // 无需推理的模型直接透传到目标服务, 同样检查预算并按上游返回的用量计费
+    if let Some(upstream_model) = passthrough_target(model_config, &openai_request.model) {
+        tracing::info!("Passing {} through to {}", openai_request.model, upstream_model);
+        let warnings = WarningCollector::default();
+        warnings.extend(budget_warnings(&state, &headers)?);
+        let mut body = raw_request;
+        body["model"] = serde_json::json!(upstream_model);
+        // Accept 头改变了流式模式时才改写 stream 字段
+        if openai_request.stream != stream.unwrap_or(false) {
+            body["stream"] = serde_json::json!(openai_request.stream);
+        }
+        // 流式透传时请求上游在最后一帧返回用量
+        if openai_request.stream && body.get("stream_options").is_none() {
+            body["stream_options"] = serde_json::json!({"include_usage": true});
+        }
+        vendor::strip(&mut body);
+        if let Some(body) = body.as_object_mut() {
+            body.remove("stream_transport");
+        }
+        let body = serde_json::to_vec(&body)?;
+        let meter = Meter::new(&state, &headers, &journal, Some(&openai_request.model), openai_request.stream);
+        let response = forward_upstream(&state.http, &namespace.endpoints.openai, &token_config.openai_token, body, Some(meter)).await?;
+        let response = with_warnings(response, &warnings);
+        return Ok(with_headers(response, rate_limit_headers));
+    }
+    
+    let default_mapping = profile.as_ref().and_then(|profile| profile.config.default_mapping.as_deref());
+    let (options, vendor_warning) = vendor::parse(&openai_request.extra)?;
+    let mut internal_request = compat_request(&openai_request, &options, model_config, token_config, default_mapping)?;
+    internal_request.request_id = journal.id();
+    if let Some(profile) = &profile {
+        let thinking_format_set = options.thinking_format.is_some() || openai_request.extra.get("thinking_format").is_some();
+        profile.apply(&mut internal_request, thinking_format_set);
+    }
+    // Vercel AI 数据流需要从单独的字段中区分推理和回答
+    if transport == StreamTransport::VercelAi {
+        internal_request.thinking_format = ThinkingFormat::ReasoningContent;
+    }
+    conversion::forward_headers(&mut internal_request, &headers, &state.config.compat.forward_request_headers);
+    let traced = check_trace(&state, &headers, internal_request.trace)?;
+    let warnings = internal_request.warnings.clone();
+    warnings.extend(unknown_fields_warning(&state, &raw_request));
+    warnings.extend(check_endpoint_overrides(&state, &mut headers)?);
+    warnings.extend(budget_warnings(&state, &headers)?);
+    warnings.extend(vendor_warning.map(|warning| Warning::in_header("vendor_options_ignored", VENDOR_WARNING_HEADER, warning)));
+    warnings.extend(trim_warning(&internal_request));
+    route_reasoner(&state, &openai_request.model, default_mapping, &mut headers, &mut internal_request)?;
+
+    // 构建新的headers; no_cache 时跳过幂等缓存
+    let cache_key = idempotency_key(&headers, "/v1/chat/completions", &raw_request).filter(|_| !options.no_cache);
+    let new_headers = build_internal_headers(headers, token_config, &namespace.endpoints)?;
+    warnings.extend(check_parameters(&state, &mut internal_request, Some(&options.supplied_fields(&openai_request.extra)))?);
+    warnings.extend(check_capabilities(&state, &new_headers, &mut internal_request)?);
+    if traced {
+        let mapping = conversion::resolve_mapping(model_config, &openai_request.model, default_mapping);
+        start_trace(&state, &journal, &raw_request, Some(&mapping), &mut internal_request);
+    }
+    let tracer = internal_request.tracer.clone();
+
+    // 根据stream参数选择处理方式
+    if openai_request.stream {
+        let stream_response = chat_stream(
+            State(state),
+            new_headers,
+            Json(internal_request),
+        ).await?;
+        let response = with_warnings(negotiate::stream_response(transport, stream_response), &warnings);
+        Ok(with_headers(response, rate_limit_headers))
+    } else {
+        if let Some(body) = cache_key.as_deref().and_then(|key| state.response_cache.get(key)) {
+            return Ok(Json(body).into_response());
+        }
+        let thinking_format = internal_request.thinking_format;
+        let span = request_span(&internal_request);
+        let mut response = chat(
+            State(state.clone()),
+            new_headers,
+            Json(internal_request),
+        ).instrument(span).await?;
+        journal.set_response(&response.0);
+        warnings.extend(response.0.empty_answer.then(empty_answer_warning));
+        warnings.extend(check_empty_choices(&state, &response.0, &openai_request.model)?);
+        warnings.extend(block_warning(&response.0));
+        response.0.warnings = warnings.list();
+        finish_trace(tracer.as_ref(), &mut response.0);
+        
+        // 转换为OpenAI格式响应
+        let openai_response = OpenAICompatResponse::from_response(
+            &response.0,
+            state.ids.completion_id(),
+            state.clock.now().timestamp(),
+            openai_request.model,
+            thinking_format,
+        );
+        if let Some(key) = cache_key {
+            state.response_cache.insert(key, serde_json::to_value(&openai_response).unwrap_or_default());
+        }
+
+        let mut response_headers = axum::http::HeaderMap::new();
+        for (name, ms) in response.0.timings.iter().flat_map(Timings::headers) {
+            insert_header(&mut response_headers, name, &ms.to_string())?;
+        }
+        if let Some(reasoner_model) = &response.0.reasoner_model {
+            insert_header(&mut response_headers, REASONER_MODEL_HEADER, reasoner_model)?;
+        }
+        if let Some(endpoint) = &response.0.reasoner_endpoint {
+            insert_header(&mut response_headers, REASONER_ENDPOINT_HEADER, endpoint)?;
+        }
+        if let Some(path) = response.0.optimistic_path {
+            insert_header(&mut response_headers, OPTIMISTIC_PATH_HEADER, path.as_str())?;
+        }
+        if let Some(author) = &response.0.answered_by {
+            insert_header(&mut response_headers, ANSWERED_BY_HEADER, author.role.as_str())?;
+        }
+        if let Some(source) = response.0.reasoning_source {
+            insert_header(&mut response_headers, REASONING_SOURCE_HEADER, source.as_str())?;
+        }
+        if response.0.redactions > 0 {
+            insert_header(&mut response_headers, REDACTIONS_HEADER, &response.0.redactions.to_string())?;
+        }
+        let response = with_warnings((response_headers, Json(openai_response)).into_response(), &warnings);
+        Ok(with_headers(response, rate_limit_headers))
+    }
+}
+
+/// Converts an OpenAI-compatible request into the internal request format.
+///
+/// Resolves the model mapping, falling back to `default_mapping` and then
+/// to the configured defaults, and converts the request with it.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if a field such as `injection_mode` or
+/// `tools` is malformed.
+fn compat_request(
+    openai_request: &OpenAICompatRequest,
+    options: &VendorOptions,
+    model_config: &ModelConfig,
+    token_config: &TokenConfig,
+    default_mapping: Option<&str>,
+) -> Result<ApiRequest> {
+    let model_mapping = conversion::resolve_mapping(model_config, &openai_request.model, default_mapping);
+    openai_request.to_internal(&model_mapping, token_config, options, &model_config.default_anthropic)
+}
+
+/// Routes a compat request to one of its mapping's reasoner replicas.
+///
+/// A request whose caller named the reasoner endpoint keeps it. The lease on
+/// the chosen replica is kept in the request, so the request counts as in
+/// flight on the replica until it is done.
+fn route_reasoner(
+    state: &AppState,
+    model: &str,
+    default_mapping: Option<&str>,
+    headers: &mut axum::http::HeaderMap,
+    request: &mut ApiRequest,
+) -> Result<()> {
+    let mapping = conversion::resolve_mapping(&state.tenants.of(headers).models, model, default_mapping);
+    if mapping.reasoner_endpoints.is_empty() || headers.contains_key(DEEPSEEK_ENDPOINT_URL_HEADER) {
+        return Ok(());
+    }
+    let affinity = replicas::affinity_key(request);
+    let lease = state.replicas.select(
+        model,
+        &mapping.reasoner_endpoints,
+        mapping.reasoner_routing,
+        affinity.as_deref(),
+        |endpoint| state.circuits.is_open("deepseek", endpoint),
+    );
+    if let Some(lease) = lease {
+        tracing::debug!("Routing the reasoner of {} to {}", model, lease.endpoint());
+        insert_header(headers, DEEPSEEK_ENDPOINT_URL_HEADER, lease.endpoint())?;
+        request.replica = Some(Arc::new(lease));
+    }
+    Ok(())
+}
+
+/// Estimated prompt size of one pipeline phase.
+#[derive(Debug, Serialize)]
+pub struct PhaseTokenCount {
+    pub model: String,
+    pub prompt_tokens: u32,
+    /// How `prompt_tokens` was counted.
+    pub counting: tokens::Counting,
+    /// The configured context window, if known for this model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    /// Tokens left for the phase's output; negative if the prompt does not fit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headroom: Option<i64>,
+}
+
+/// Response of the `/v1/token_count` endpoint.
+#[derive(Debug, Serialize)]
+pub struct TokenCountResponse {
+    pub object: String,
+    pub model: String,
+    pub reasoner: PhaseTokenCount,
+    pub target: PhaseTokenCount,
+    /// Reasoning length assumed in the target prompt.
+    pub assumed_reasoning_tokens: u32,
+}
+
+/// Handler for the `/v1/token_count` endpoint.
+///
+/// Accepts a chat completion request and counts the prompt sent to the
+/// reasoner and the prompt the target would receive with a placeholder
+/// reasoning of `compat.assumed_reasoning_tokens`, along with the headroom
+/// left in each model's configured context window. Models tiktoken knows are
+/// counted with their BPE and others estimated; with
+/// `compat.anthropic_token_counting` an Anthropic target prompt is counted by
+/// Anthropic's `count_tokens` endpoint, the only upstream call made.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the body is not a valid chat completion
+/// request.
+pub async fn handle_token_count(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    Json(raw_request): Json<serde_json::Value>,
+) -> Result<Json<TokenCountResponse>> {
+    let openai_request: OpenAICompatRequest = serde_json::from_value(raw_request)
+        .map_err(|e| ApiError::BadRequest {
+            message: format!("Invalid chat completion request: {}", e),
+        })?;
+
+    get_auth_info(&headers)?;
+    let namespace = state.tenants.resolve(&headers)?;
+    let token_config = namespace.caller_tokens(&headers).1;
+    let (options, _) = vendor::parse(&openai_request.extra)?;
+    let mut request = compat_request(&openai_request, &options, &namespace.models, token_config, None)?;
+    resolve_language_instructions(&state.config, &mut request);
+    apply_reasoner_model(&namespace.models, &mut request)?;
+
+    // 没有 X-Target-Model 时与兼容接口一样使用 openai
+    let anthropic_target = headers
+        .get("X-Target-Model")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|target| !matches!(target, "openai" | "mistral"));
+    let target_config = if anthropic_target { &request.anthropic_config } else { &request.openai_config };
+    let model_of = |config: &ApiConfig| config.body.get("model").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+
+    // 推理内容用占位符代替, 按假定长度计入目标模型的提示词
+    let assumed_reasoning_tokens = state.config.compat.assumed_reasoning_tokens;
+    let reasoner_model = model_of(&request.deepseek_config);
+    let (reasoner_tokens, reasoner_counting) = tokens::count_messages(&reasoner_model, &reasoner_messages(&state.config.reasoning, &request));
+    let target_model = model_of(target_config);
+    let target_messages = request.build_target_messages(Some(""));
+    #[cfg_attr(not(feature = "anthropic"), allow(unused_mut))]
+    let mut placeholder = None;
+    #[cfg(feature = "anthropic")]
+    if anthropic_target && state.config.compat.anthropic_token_counting {
+        let client = AnthropicClient::new_with_base_url(token_config.anthropic_token.clone(), state.config.endpoints.anthropic.clone());
+        let system = anthropic_system_prompt(&request, &target_messages);
+        match client.count_tokens(target_messages.clone(), system, &request.anthropic_config).await {
+            Ok(count) => placeholder = Some((count, tokens::Counting::Anthropic)),
+            Err(e) => tracing::warn!("Estimating the target prompt, Anthropic token counting failed: {}", e),
+        }
+    }
+    let (placeholder_tokens, target_counting) = placeholder.unwrap_or_else(|| tokens::count_messages(&target_model, &target_messages));
+    let target_tokens = placeholder_tokens + assumed_reasoning_tokens;
+
+    let phase_count = |model: String, prompt_tokens: u32, counting: tokens::Counting| {
+        let context_window = state.config.compat.context_windows.get(&model).copied();
+        PhaseTokenCount {
+            headroom: context_window.map(|window| i64::from(window) - i64::from(prompt_tokens)),
+            model,
+            prompt_tokens,
+            counting,
+            context_window,
+        }
+    };
+
+    Ok(Json(TokenCountResponse {
+        object: "token_count".to_string(),
+        reasoner: phase_count(reasoner_model, reasoner_tokens, reasoner_counting),
+        target: phase_count(target_model, target_tokens, target_counting),
+        model: openai_request.model,
+        assumed_reasoning_tokens,
+    }))
+}
+
+/// Handler for the legacy `/v1/completions` endpoint.
+///
+/// Turns the `prompt` into a single user message and runs the request
+/// through the same pipeline as `/v1/chat/completions`, then returns the
+/// output as `text_completion` objects: one response with `choices[].text`,
+/// or a stream of `text_completion` chunks when `stream` is set.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if `prompt` is missing, is not a string or
+/// array of strings, or holds more than one prompt.
+pub async fn handle_completions(
+    State(state): State<Arc<AppState>>,
+    journal: JournalSlot,
+    mut headers: axum::http::HeaderMap,
+    Json(raw_request): Json<serde_json::Value>,
+) -> Result<axum::response::Response> {
+    let prompt = match raw_request.get("prompt") {
+        Some(serde_json::Value::String(prompt)) => prompt.clone(),
+        Some(serde_json::Value::Array(prompts)) if prompts.len() > 1 => {
+            return Err(ApiError::BadRequest {
+                message: format!("Only a single prompt is supported, got {}", prompts.len()),
+            });
+        }
+        Some(serde_json::Value::Array(prompts)) => prompts
+            .first()
+            .and_then(|prompt| prompt.as_str())
+            .map(String::from)
+            .ok_or_else(|| ApiError::BadRequest {
+                message: "prompt must be a string or an array of one string".to_string(),
+            })?,
+        _ => {
+            return Err(ApiError::BadRequest {
+                message: "prompt must be a string or an array of one string".to_string(),
+            });
+        }
+    };
+
+    let transport = negotiate::stream_transport(&headers, raw_request.get("stream_transport")).and_then(negotiate::chunk_transport)?;
+
+    // 将 prompt 转换为单条 user 消息, 其余参数按 chat completions 处理
+    let mut chat_request = raw_request;
+    if let Some(body) = chat_request.as_object_mut() {
+        body.remove("prompt");
+        body.insert("messages".to_string(), serde_json::json!([{"role": "user", "content": prompt}]));
+    }
+    let openai_request: OpenAICompatRequest = serde_json::from_value(chat_request)
+        .map_err(|e| ApiError::BadRequest {
+            message: format!("Invalid completion request: {}", e),
+        })?;
+    journal.set_request(Some(&openai_request.model), openai_request.stream);
+
+    get_auth_info(&headers)?;
+    let namespace = state.tenants.resolve(&headers)?;
+    let token_config = namespace.caller_tokens(&headers).1;
+    let (options, vendor_warning) = vendor::parse(&openai_request.extra)?;
+    let mut internal_request = compat_request(&openai_request, &options, &namespace.models, token_config, None)?;
+    internal_request.request_id = journal.id();
+    internal_request.stream_format = StreamFormat::TextCompletion;
+    conversion::forward_headers(&mut internal_request, &headers, &state.config.compat.forward_request_headers);
+    // 旧版 completions 只有纯文本的 text 字段, 无法携带结构化推理
+    if internal_request.thinking_format != ThinkingFormat::Tag {
+        return Err(ApiError::BadRequest {
+            message: "thinking_format is not supported for /v1/completions".to_string(),
+        });
+    }
+    let warnings = internal_request.warnings.clone();
+    warnings.extend(check_endpoint_overrides(&state, &mut headers)?);
+    warnings.extend(budget_warnings(&state, &headers)?);
+    warnings.extend(vendor_warning.map(|warning| Warning::in_header("vendor_options_ignored", VENDOR_WARNING_HEADER, warning)));
+    warnings.extend(trim_warning(&internal_request));
+    route_reasoner(&state, &openai_request.model, None, &mut headers, &mut internal_request)?;
+    let new_headers = build_internal_headers(headers, token_config, &namespace.endpoints)?;
+    warnings.extend(check_parameters(&state, &mut internal_request, Some(&options.supplied_fields(&openai_request.extra)))?);
+    warnings.extend(check_capabilities(&state, &new_headers, &mut internal_request)?);
+
+    if openai_request.stream {
+        let stream_response = chat_stream(State(state), new_headers, Json(internal_request)).await?;
+        return Ok(with_warnings(negotiate::stream_response(transport, stream_response), &warnings));
+    }
+
+    let span = request_span(&internal_request);
+    let response = chat(State(state.clone()), new_headers, Json(internal_request)).instrument(span).await?;
+    journal.set_response(&response.0);
+    warnings.extend(response.0.empty_answer.then(empty_answer_warning));
+    warnings.extend(check_empty_choices(&state, &response.0, &openai_request.model)?);
+    warnings.extend(block_warning(&response.0));
+    let completion = LegacyCompletionResponse::from_response(
+        &response.0,
+        state.ids.completion_id().replacen("chatcmpl-", "cmpl-", 1),
+        state.clock.now().timestamp(),
+        openai_request.model,
+    );
+    Ok(with_warnings(Json(completion).into_response(), &warnings))
+}
+
+/// Handler for well-known OpenAI endpoints this server does not implement.
+///
+/// Returns a 501 error naming the requested path and listing the supported
+/// routes, instead of a bare 404.
+pub async fn handle_unsupported(uri: axum::http::Uri) -> ApiError {
+    ApiError::NotImplemented {
+        endpoint: uri.path().to_string(),
+    }
+}
+
+/// Handler for the `/metrics` endpoint.
+///
+/// Returns a JSON snapshot of the streaming pipeline counters, the
+/// admission queue depth per priority, the upstream circuit states and the
+/// rate limits the upstreams last advertised.
+pub async fn handle_metrics(State(state): State<Arc<AppState>>) -> Json<MetricsSnapshot> {
+    let mut snapshot = state.metrics.snapshot();
+    snapshot.queue_depth = state.admission.depths();
+    snapshot.circuits = state.circuits.states();
+    snapshot.upstream_rate_limits = state.rate_limits.gauges();
+    snapshot.speculation = state.speculation.stats();
+    snapshot.speculative_spend_usd = state.spend.speculative_total();
+    snapshot.compression = compression::stats();
+    snapshot.upstream_connections = connection::stats();
+    snapshot.upstream_account_errors = clients::account_error_stats();
+    snapshot.reasoner_replicas = state.replicas.gauges();
+    snapshot.usage_webhooks = state.webhooks.stats();
+    Json(snapshot)
+}
+
+/// Body of the `/health` endpoint.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// `"ok"`, or `"degraded"` while any upstream circuit is not closed.
+    pub status: &'static str,
+    /// Crate version and git commit of the running build.
+    pub version: &'static str,
+    /// Upstream circuits that have seen connection failures.
+    pub circuits: Vec<CircuitStatus>,
+}
+
+/// Handler for the `/health` endpoint.
+///
+/// Always answers 200 while the server runs; upstream circuits that are
+/// open or half-open turn the status to `degraded`.
+pub async fn handle_health(State(state): State<Arc<AppState>>) -> Json<HealthReport> {
+    let circuits = state.circuits.states();
+    let degraded = circuits.iter().any(|circuit| circuit.state != CircuitState::Closed);
+    Json(HealthReport {
+        status: if degraded { "degraded" } else { "ok" },
+        version: version::FULL_VERSION,
+        circuits,
+    })
+}
+
+/// Handles `GET /v1/models`, listing the caller's model mappings.
+///
+/// The plain list is OpenAI-compatible; `?verbose=true` adds each mapping's
+/// details.
+pub async fn handle_models(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(query): axum::extract::Query<ModelsQuery>,
+) -> Result<Json<ModelList>> {
+    let namespace = state.tenants.resolve(&headers)?;
+    Ok(Json(discovery::list(&namespace.models, &state.config.reasoning, &state.config.compat, query.verbose)))
+}
+
+/// Handles `GET /v1/models/{id}` with the details of one model mapping.
+///
+/// # Errors
+///
+/// Returns `ApiError::ModelNotFound` if the caller's namespace has no mapping `id`.
+pub async fn handle_model(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<ModelObject>> {
+    let namespace = state.tenants.resolve(&headers)?;
+    discovery::get(&namespace.models, &state.config.reasoning, &state.config.compat, &id)
+        .map(Json)
+        .ok_or(ApiError::ModelNotFound { model: id })
+}
+
+/// Handles `GET /version` with the build metadata of the running instance.
+pub async fn handle_version() -> Json<VersionInfo> {
+    Json(version::info())
+}
+
+/// Adds the `X-DeepThink-Version` header to a response.
+pub async fn add_version_header(mut response: axum::response::Response) -> axum::response::Response {
+    response
+        .headers_mut()
+        .insert(version::VERSION_HEADER, HeaderValue::from_static(version::FULL_VERSION));
+    response
+}
+
+/// Query of the stream resumption endpoint.
+#[derive(Debug, Deserialize)]
+pub struct ResumeQuery {
+    /// Index of the first frame to replay.
+    pub from: Option<u64>,
+}
+
+/// Handler for `GET /v1/streams/{id}`.
+///
+/// Replays the buffered frames of a resumable stream starting at `from`, or
+/// after the `Last-Event-ID` the client last saw, then follows the stream
+/// live until it ends. Without either, the whole buffer is replayed.
+///
+/// # Errors
+///
+/// Returns `ApiError::MissingHeader` if the request carries no credentials,
+/// or `ApiError::StreamNotFound` if the caller has no buffer registered under `id`.
+pub async fn handle_stream_resume(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<ResumeQuery>,
+    headers: axum::http::HeaderMap,
+) -> Result<axum::response::Response> {
+    let owner = required_stream_owner(&headers)?;
+    let buffer = state
+        .streams
+        .get(&id, &owner)
+        .ok_or_else(|| ApiError::StreamNotFound { id: id.clone() })?;
+    let from = query.from.unwrap_or_else(|| {
+        last_event_id(&headers)
+            .and_then(resume::parse_event_id)
+            .map_or(0, |(_, last)| last + 1)
+    });
+    tracing::info!("Resuming stream {} from frame {}", id, from);
+    Ok(replay_response(&state, buffer, from))
+}
+
+/// Returns the request's `Last-Event-ID` header.
+fn last_event_id(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers.get("last-event-id").and_then(|v| v.to_str().ok())
+}
+
+/// Answers a chat request resent by a reconnecting `EventSource` from the
+/// replay buffer of the stream named in its `Last-Event-ID`.
+///
+/// Only the caller that created the stream can replay it, as with
+/// `GET /v1/streams/{id}`.
+///
+/// # Returns
+///
+/// * `Result<Option<Response>>` - The replay, or `None` for requests without `Last-Event-ID`
+///
+/// # Errors
+///
+/// Returns `ApiError::MissingHeader` if the request carries no credentials,
+/// `ApiError::ResumeNotEnabled` if the id belongs to a stream that was not
+/// resumable, or `ApiError::StreamNotFound` if its buffer is gone or belongs
+/// to another caller.
+fn resume_last_event(state: &AppState, headers: &axum::http::HeaderMap) -> Result<Option<axum::response::Response>> {
+    let Some(last_event_id) = last_event_id(headers) else {
+        return Ok(None);
+    };
+    let owner = required_stream_owner(headers)?;
+    let Some((Some(id), last)) = resume::parse_event_id(last_event_id) else {
+        return Err(ApiError::ResumeNotEnabled {
+            last_event_id: last_event_id.to_string(),
+        });
+    };
+    let buffer = state
+        .streams
+        .get(id, &owner)
+        .ok_or_else(|| ApiError::StreamNotFound { id: id.to_string() })?;
+    tracing::info!("Resuming stream {} after Last-Event-ID {}", id, last);
+    Ok(Some(replay_response(state, buffer, last + 1)))
+}
+
+/// Replays a resumable stream from frame `from` as an SSE response.
+fn replay_response(state: &AppState, buffer: Arc<StreamBuffer>, from: u64) -> axum::response::Response {
+    let sse = axum::response::sse::Sse::new(buffer.replay(from));
+    negotiate::sse_response(with_heartbeat(sse, state.config.streaming.heartbeat()))
+}
+
+/// Identifies the caller owning a resumable stream.
+///
+/// The owner is a fingerprint of the caller's bearer token, or of its
+/// reasoner token on the native route. Returns `None` for a caller without
+/// either token.
+fn stream_owner(headers: &axum::http::HeaderMap) -> Option<String> {
+    ["Authorization", "X-DeepSeek-API-Token"]
+        .iter()
+        .filter_map(|name| headers.get(*name).and_then(|h| h.to_str().ok()))
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value))
+        .find(|value| !value.is_empty())
+        .map(audit::fingerprint)
+}
+
+/// Authenticates a request to a resumable stream and returns its owner key.
+///
+/// # Errors
+///
+/// Returns `ApiError::MissingHeader` if the request carries no credentials.
+fn required_stream_owner(headers: &axum::http::HeaderMap) -> Result<String> {
+    stream_owner(headers).ok_or_else(|| ApiError::MissingHeader {
+        header: "Authorization".to_string(),
+    })
+}
+
+/// Handler for `DELETE /v1/streams/{id}`.
+///
+/// Drops the replay buffer of a resumable stream. A stream still running
+/// keeps producing for any connected client but can no longer be resumed.
+///
+/// # Errors
+///
+/// Returns `ApiError::MissingHeader` if the request carries no credentials,
+/// or `ApiError::StreamNotFound` if the caller has no buffer registered under `id`.
+pub async fn handle_stream_delete(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Result<axum::http::StatusCode> {
+    let owner = required_stream_owner(&headers)?;
+    if !state.streams.remove(&id, &owner) {
+        return Err(ApiError::StreamNotFound { id });
+    }
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Handler for `GET /admin/streams`.
+///
+/// Lists the running stream tasks with their phase and age, oldest first.
+pub async fn handle_active_streams(State(state): State<Arc<AppState>>) -> Json<Vec<ActiveStream>> {
+    Json(state.watchdog.active())
+}
+
+/// Handler for `DELETE /admin/streams/{id}`.
+///
+/// Aborts a running stream task: its admission permit is released and a
+/// still connected client receives an error frame and `[DONE]`.
+///
+/// # Errors
+///
+/// Returns `ApiError::StreamNotRunning` if no stream with that id is running.
+pub async fn handle_stream_kill(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<axum::http::StatusCode> {
+    if !state.watchdog.kill(&id) {
+        return Err(ApiError::StreamNotRunning { id });
+    }
+    Ok(axum::http::StatusCode::ACCEPTED)
+}
+
+/// Handler for `GET /admin/requests`.
+///
+/// Lists the journaled requests, newest first, filtered by `min_status`,
+/// `mapping` and `since` and capped at `limit`.
+pub async fn handle_journal(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<JournalQuery>,
+) -> Json<Vec<JournalEntry>> {
+    Json(state.journal.list(&query))
+}
+
+/// Handler for `GET /admin/requests/{id}`.
+///
+/// # Errors
+///
+/// Returns `ApiError::JournalEntryNotFound` if the request was evicted from
+/// the journal or never existed.
+pub async fn handle_journal_entry(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<JournalEntry>> {
+    state.journal.get(&id).map(Json).ok_or(ApiError::JournalEntryNotFound { id })
+}
+
+/// Handler for `GET /admin/traces/{id}`.
+///
+/// A streamed request's trace can be read while the stream is running; its
+/// `complete` flag is set once the stream has finished.
+///
+/// # Errors
+///
+/// Returns `ApiError::TraceNotFound` if the trace was evicted, or the
+/// request was not traced or never existed.
+pub async fn handle_trace(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<RequestTrace>> {
+    state.traces.get(&id).map(Json).ok_or(ApiError::TraceNotFound { id })
+}
+
+/// Rejects admin requests without the configured `server.admin_token` in
+/// `X-DeepThink-Admin-Token`, the header traced requests use too.
+///
+/// Admin routes are open when no token is configured.
+pub async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<axum::response::Response> {
+    if let Some(expected) = &state.config.server.admin_token {
+        let supplied = request.headers().get(ADMIN_TOKEN_HEADER).and_then(|h| h.to_str().ok());
+        if supplied != Some(expected.as_str()) {
+            return Err(ApiError::AdminUnauthorized);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Sends the usage event of each chat request answered with an error status.
+///
+/// Must be added inside [`journal::record`], whose slot provides the
+/// request id and the model the handler read. Streams that fail after their
+/// response started report their own events.
+pub async fn report_failures(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let started_at = state.clock.now();
+    let caller = state.tenants.of(request.headers()).caller_tokens(request.headers()).0.to_string();
+    let slot = request.extensions().get::<JournalSlot>().cloned().unwrap_or_default();
+    let response = next.run(request).await;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        let (mapping, stream) = slot.request();
+        let event = UsageEvent::rejected(slot.id(), &caller, mapping, stream, status.as_u16(), started_at, state.clock.now());
+        state.webhooks.send(event);
+    }
+    response
+}
+
+/// Handler for the `/v1/embeddings` endpoint.
+///
+/// Embeddings need no reasoning, so when `compat.proxy_embeddings` is enabled
+/// the request body is forwarded unchanged to the configured OpenAI endpoint
+/// and the upstream response is returned as-is. Otherwise the endpoint is
+/// reported as not implemented.
+pub async fn handle_embeddings(
+    State(state): State<Arc<AppState>>,
+    journal: JournalSlot,
+    headers: axum::http::HeaderMap,
+    uri: axum::http::Uri,
+    body: axum::body::Bytes,
+) -> Result<axum::response::Response> {
+    if !state.config.compat.proxy_embeddings {
+        return Err(ApiError::NotImplemented {
+            endpoint: uri.path().to_string(),
+        });
+    }
+
+    get_auth_info(&headers)?;
+    let namespace = state.tenants.resolve(&headers)?;
+    let token_config = namespace.caller_tokens(&headers).1;
+    let url = sibling_endpoint(&namespace.endpoints.openai, "embeddings");
+    let warnings = WarningCollector::default();
+    warnings.extend(budget_warnings(&state, &headers)?);
+
+    let model = serde_json::from_slice::<serde_json::Value>(&body).ok().and_then(|body| body.get("model")?.as_str().map(str::to_string));
+    let meter = Meter::new(&state, &headers, &journal, model.as_deref(), false);
+    let response = forward_upstream(&state.http, &url, &token_config.openai_token, body, Some(meter)).await?;
+    Ok(with_warnings(response, &warnings))
+}
+
+/// Accounts for a response relayed by [`forward_upstream`].
+///
+/// The usage the upstream reported is charged to the caller, sent as a usage
+/// event and recorded in the request's journal entry. A meter dropped before
+/// its response was relayed to the end sends the event with status 499, as
+/// a stream whose client went away.
+struct Meter {
+    state: Arc<AppState>,
+    /// The key the caller's spend is recorded under.
+    caller: String,
+    journal: JournalSlot,
+    event: UsageEvent,
+    reported: bool,
+}
+
+impl Meter {
+    fn new(state: &Arc<AppState>, headers: &axum::http::HeaderMap, journal: &JournalSlot, model: Option<&str>, stream: bool) -> Self {
+        let namespace = state.tenants.of(headers);
+        Self {
+            state: state.clone(),
+            caller: namespace.spend_key(headers),
+            journal: journal.clone(),
+            event: UsageEvent::relayed(journal.id(), namespace.caller_tokens(headers).0, model.map(str::to_string), stream, state.clock.now()),
+            reported: false,
+        }
+    }
+
+    /// Returns the meter if the upstream answered successfully.
+    ///
+    /// Error responses are not charged; [`report_failures`] reports those
+    /// of the chat routes.
+    fn for_status(mut self, status: reqwest::StatusCode) -> Option<Self> {
+        if status.is_success() {
+            return Some(self);
+        }
+        self.reported = true;
+        None
+    }
+
+    /// Charges the caller for the model and usage the upstream reported;
+    /// responses without usage are counted as unmetered.
+    fn charge(&mut self, found: Option<(String, UsageStats)>) {
+        let state = &self.state;
+        state.metrics.record_relayed_response(found.is_some());
+        let (model, usage) = found.unwrap_or_default();
+        let cost = state.config.budget.cost(&model, usage.prompt_tokens, usage.completion_tokens);
+        state.spend.record(&self.caller, cost, state.clock.now());
+        self.journal.set_usage("openai", &usage);
+        state.webhooks.send(self.event.completed(&UsageStats::default(), &usage, cost, state.clock.now()));
+        self.reported = true;
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        if !self.reported {
+            self.state.webhooks.send(self.event.failed(CLIENT_CLOSED_REQUEST, self.state.clock.now()));
+        }
+    }
+}
+
+/// Forwards a request body unchanged to an OpenAI-compatible upstream.
+///
+/// The upstream status, content type and body bytes are relayed to the
+/// client unchanged, so both JSON and SSE responses (including upstream
+/// errors) pass through without re-parsing. SSE bodies are relayed as they
+/// arrive; other bodies are read whole first, so their usage is in the
+/// request's journal entry.
+///
+/// # Arguments
+///
+/// * `http` - The shared connection pool
+/// * `url` - The upstream URL to POST to
+/// * `token` - Bearer token for the upstream
+/// * `body` - The JSON request body to forward
+/// * `meter` - The caller charged for the usage of a successful response,
+///   once its body has been relayed
+///
+/// # Errors
+///
+/// Returns `ApiError::OpenAIError` if the upstream cannot be reached.
+async fn forward_upstream(
+    http: &reqwest::Client,
+    url: &str,
+    token: &str,
+    body: impl Into<reqwest::Body>,
+    meter: Option<Meter>,
+) -> Result<axum::response::Response> {
+    let response = http
+        .post(url)
+        .bearer_auth(token)
+        .header(axum::http::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| ApiError::OpenAIError {
+            message: format!("Request failed: {}", e),
+            type_: "request_failed".to_string(),
+            param: None,
+            code: None,
+        })?;
+
+    let mut builder = axum::response::Response::builder().status(response.status());
+    if let Some(content_type) = response.headers().get(axum::http::header::CONTENT_TYPE) {
+        builder = builder.header(axum::http::header::CONTENT_TYPE, content_type.clone());
+    }
+    let meter = meter.and_then(|meter| meter.for_status(response.status()));
+    let sse = response
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    if !sse {
+        let bytes = response.bytes().await.map_err(|e| ApiError::OpenAIError {
+            message: format!("Failed to read response: {}", e),
+            type_: "request_failed".to_string(),
+            param: None,
+            code: None,
+        })?;
+        if let Some(mut meter) = meter {
+            let mut scanner = metering::UsageScanner::default();
+            scanner.feed(&bytes);
+            meter.charge(scanner.finish());
+        }
+        return builder.body(axum::body::Body::from(bytes)).map_err(|e| ApiError::Internal {
+            message: format!("Failed to build response: {}", e),
+        });
+    }
+
+    let upstream = response.bytes_stream();
+    let body = async_stream::stream! {
+        let mut meter = meter;
+        let mut scanner = metering::UsageScanner::default();
+        futures::pin_mut!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            if let (Some(_), Ok(bytes)) = (&meter, &chunk) {
+                scanner.feed(bytes);
+            }
+            yield chunk;
+        }
+        // 客户端提前断开时流在此之前被丢弃, 上游的用量还未到达, 无法计费, 用量事件以 499 发送
+        if let Some(meter) = &mut meter {
+            meter.charge(scanner.finish());
+        }
+    };
+    builder
+        .body(axum::body::Body::from_stream(body))
+        .map_err(|e| ApiError::Internal {
+            message: format!("Failed to build response: {}", e),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AcceptPrecedence, ReasoningReuse, ModelMapping, ParseStrictness, UserThinkTags, WarmUpMethod};
+    use crate::models::ReasonerSystemPrompt;
+    use crate::testing::{self, OPENAI_PATH, REASONER_PATH, REASONING};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    /// Answers one compat request whose reasoner first returns whitespace
+    /// and then, when called again, [`REASONING`].
+    ///
+    /// Returns the status and body of the response and the bodies the
+    /// reasoner and the target received.
+    async fn answer_empty_reasoning(
+        policy: EmptyReasoningPolicy,
+        stream: bool,
+    ) -> (u16, String, Vec<serde_json::Value>, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        let reasoner = |reasoning: &str| match stream {
+            true => ResponseTemplate::new(200).set_body_raw(testing::reasoner_stream(reasoning), "text/event-stream"),
+            false => ResponseTemplate::new(200).set_body_json(testing::reasoner_completion(reasoning)),
+        };
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(reasoner("  \n "))
+            .up_to_n_times(1)
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(reasoner(REASONING))
+            .mount(&upstream)
+            .await;
+        if stream {
+            testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        } else {
+            Mock::given(method("POST"))
+                .and(path(OPENAI_PATH))
+                .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                    json!({"role": "assistant", "content": "Paris."}),
+                    "stop",
+                )))
+                .mount(&upstream)
+                .await;
+        }
+        let mut config = testing::config(&upstream);
+        config.reasoning.empty_policy = policy;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let reasoner_calls = testing::received(&upstream, REASONER_PATH).await;
+        (status.as_u16(), body, reasoner_calls, testing::received(&upstream, OPENAI_PATH).await)
+    }
+
+    /// Returns the concatenated content deltas of a streamed response.
+    fn streamed_content(body: &str) -> String {
+        testing::stream_deltas(body).iter().filter_map(|delta| delta["content"].as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn empty_reasoning_fails_under_the_error_policy() {
+        let (status, body, reasoner_calls, target_calls) = answer_empty_reasoning(EmptyReasoningPolicy::Error, false).await;
+        assert_eq!(status, 502, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "empty_reasoning");
+        assert_eq!(body["error"]["param"], "deepseek-r1:14b");
+        assert_eq!(reasoner_calls.len(), 1);
+        assert!(target_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_reasoning_is_retried_once_with_a_nudge() {
+        let (status, body, reasoner_calls, target_calls) = answer_empty_reasoning(EmptyReasoningPolicy::Retry, false).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(reasoner_calls.len(), 2);
+        let nudge = reasoner_calls[1]["messages"].as_array().unwrap().last().unwrap();
+        assert_eq!(nudge["role"], "user");
+        assert_eq!(nudge["content"], EMPTY_REASONING_NUDGE);
+        let injected = target_calls[0]["messages"].as_array().unwrap().last().unwrap();
+        assert_eq!(injected["content"], format!("<think>\n{}\n</think>", REASONING));
+    }
+
+    #[tokio::test]
+    async fn empty_reasoning_is_left_out_under_the_skip_policy() {
+        let (status, body, reasoner_calls, target_calls) = answer_empty_reasoning(EmptyReasoningPolicy::Skip, false).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(reasoner_calls.len(), 1);
+        assert_eq!(target_calls[0]["messages"], json!([{"role": "user", "content": "Capital of France?"}]));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Paris.");
+    }
+
+    #[tokio::test]
+    async fn streamed_empty_reasoning_fails_without_thinking_tags() {
+        let (status, body, reasoner_calls, target_calls) = answer_empty_reasoning(EmptyReasoningPolicy::Error, true).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("returned empty reasoning"), "{}", body);
+        assert!(!body.contains("thinking>"), "{}", body);
+        assert_eq!(reasoner_calls.len(), 1);
+        assert!(target_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streamed_empty_reasoning_is_retried_inside_one_thinking_block() {
+        let (status, body, reasoner_calls, _) = answer_empty_reasoning(EmptyReasoningPolicy::Retry, true).await;
+        assert_eq!(status, 200);
+        assert_eq!(reasoner_calls.len(), 2);
+        assert_eq!(streamed_content(&body), format!("<thinking>\n{}\n</thinking>\n\nParis.", REASONING));
+    }
+
+    #[tokio::test]
+    async fn streamed_empty_reasoning_opens_no_thinking_block_under_the_skip_policy() {
+        let (status, body, _, target_calls) = answer_empty_reasoning(EmptyReasoningPolicy::Skip, true).await;
+        assert_eq!(status, 200);
+        assert_eq!(streamed_content(&body), "Paris.");
+        assert_eq!(target_calls[0]["messages"], json!([{"role": "user", "content": "Capital of France?"}]));
+    }
+
+    #[tokio::test]
+    async fn compat_injection_mode_places_the_reasoning_for_the_target() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::reasoner_completion(REASONING)))
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                json!({"role": "assistant", "content": "Paris."}),
+                "stop",
+            )))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "injection_mode": "system_append",
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        assert_eq!(target_calls[0]["messages"], json!([
+            {"role": "system", "content": format!("Here is an expert's analysis of the conversation:\n{}", REASONING)},
+            {"role": "user", "content": "Capital of France?"},
+        ]));
+
+        let request = json!({
+            "model": "deepthink",
+            "injection_mode": "sideways",
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 400, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn unsupported_endpoints_answer_501_with_the_supported_routes() {
+        let (app, _) = testing::app(&Config::default());
+        for uri in ["/v1/moderations", "/v1/images/generations"] {
+            let (status, _, body) = testing::post(&app, uri, &[], json!({"prompt": "Hi"})).await;
+            assert_eq!(status, 501, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["type"], "not_implemented");
+            assert_eq!(body["error"]["code"], "unsupported_endpoint");
+            assert_eq!(
+                body["error"]["message"],
+                format!(
+                    "{} is not supported: deepthink only implements chat and text completions. Supported routes: POST /, POST /v1/chat/completions, POST /v1/completions, POST /v1/embeddings, POST /v1/token_count, GET /v1/models, GET /v1/models/{{id}}, GET /v1/streams/{{id}}, DELETE /v1/streams/{{id}}, GET /version",
+                    uri
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn embeddings_answer_501_unless_proxied() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let (status, _, body) = testing::post(&app, "/v1/embeddings", &[], json!({"input": "Hi"})).await;
+        assert_eq!(status, 501, "{}", body);
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn embeddings_are_proxied_unchanged_to_the_openai_endpoint() {
+        let upstream = MockServer::start().await;
+        let embeddings = json!({"object": "list", "data": [{"object": "embedding", "index": 0, "embedding": [0.5, -0.5]}]});
+        Mock::given(method("POST"))
+            .and(path("/openai/v1/embeddings"))
+            .and(wiremock::matchers::header("authorization", "Bearer ollama"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(embeddings.clone()))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.compat.proxy_embeddings = true;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "text-embedding-3-small", "input": "Hi"});
+        let (status, headers, body) = testing::post(&app, "/v1/embeddings", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), embeddings);
+        assert_eq!(testing::received(&upstream, "/openai/v1/embeddings").await, vec![request]);
+    }
+
+    #[tokio::test]
+    async fn proxied_embeddings_keep_the_upstream_status() {
+        let upstream = MockServer::start().await;
+        let error = json!({"error": {"message": "Invalid model", "type": "invalid_request_error"}});
+        Mock::given(method("POST"))
+            .and(path("/openai/v1/embeddings"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(error.clone()))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.compat.proxy_embeddings = true;
+        let (app, _) = testing::app(&config);
+
+        let (status, _, body) = testing::post(&app, "/v1/embeddings", &[], json!({"input": "Hi"})).await;
+        assert_eq!(status, 400);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), error);
+    }
+
+    /// Serves the routes with `gpt-4o-mini` listed for passthrough and
+    /// `fast` mapped to a passthrough of `qwen2.5:7b`; the reasoner must
+    /// never be called.
+    async fn passthrough_app(upstream: &MockServer) -> axum::Router {
+        Mock::given(path(REASONER_PATH)).respond_with(ResponseTemplate::new(500)).expect(0).mount(upstream).await;
+        let mut config = testing::config(upstream);
+        config.models.passthrough_models = vec!["gpt-4o-mini".to_string()];
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:7b",
+            "parameters": {},
+            "passthrough": true,
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("fast".to_string(), mapping);
+        testing::app(&config).0
+    }
+
+    #[tokio::test]
+    async fn passthrough_models_are_forwarded_unchanged() {
+        let upstream = MockServer::start().await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+            .mount(&upstream)
+            .await;
+        let app = passthrough_app(&upstream).await;
+
+        let request = json!({"model": "gpt-4o-mini", "messages": [{"role": "user", "content": "Hi"}], "seed": 7});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), answer);
+
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], json!({"model": "fast", "messages": []})).await;
+        assert_eq!(status, 200);
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        assert_eq!(target_calls, vec![request, json!({"model": "qwen2.5:7b", "messages": []})]);
+    }
+
+    #[tokio::test]
+    async fn passthrough_streams_and_errors_are_relayed_byte_for_byte() {
+        let upstream = MockServer::start().await;
+        let stream = testing::sse(&[json!({"id": "c1", "choices": [{"index": 0, "delta": {"content": "Hi"}}]})]);
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .and(wiremock::matchers::body_partial_json(json!({"stream": true})))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream.clone(), "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(429).set_body_raw("slow down", "text/plain"))
+            .mount(&upstream)
+            .await;
+        let app = passthrough_app(&upstream).await;
+
+        let request = json!({"model": "gpt-4o-mini", "stream": true, "messages": []});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert_eq!(headers["content-type"], "text/event-stream");
+        assert_eq!(body, stream);
+
+        let request = json!({"model": "gpt-4o-mini", "messages": []});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 429);
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(body, "slow down");
+    }
+
+    #[tokio::test]
+    async fn choice_counts_outside_the_limit_are_rejected() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        for (n, stream) in [(0, false), (5, false), (0, true), (5, true)] {
+            let request = json!({"model": "deepthink", "n": n, "stream": stream, "messages": [{"role": "user", "content": "Hi"}]});
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            assert_eq!(status, 400, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["message"], format!("n must be between 1 and 4, got {}", n));
+        }
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn openai_choices_share_one_reasoning() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop");
+        answer["choices"].as_array_mut().unwrap().push(json!({"index": 1, "message": {"role": "assistant", "content": "It is Paris."}, "finish_reason": "stop"}));
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "n": 2, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let thinking = format!("<think>\n{}\n</think>", REASONING);
+        assert_eq!(body["choices"][0]["index"], 0);
+        assert_eq!(body["choices"][0]["message"]["content"], format!("{}\n\nParis.", thinking));
+        assert_eq!(body["choices"][1]["index"], 1);
+        assert_eq!(body["choices"][1]["message"]["content"], format!("{}\n\nIt is Paris.", thinking));
+        // 推理与两个 choice 的用量合计: 推理 12 + 8, 目标 30 + 5
+        assert_eq!(body["usage"]["prompt_tokens"], 42);
+        assert_eq!(body["usage"]["completion_tokens"], 13);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await[0]["n"], 2);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_choices_are_separate_calls_sharing_one_reasoning() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::anthropic_message(json!([{"type": "text", "text": "Paris."}]), "end_turn");
+        answer["usage"]["cache_creation_input_tokens"] = json!(0);
+        answer["usage"]["cache_read_input_tokens"] = json!(0);
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Anthropic-API-Token", "anthropic-token"),
+            ("X-Target-Model", "anthropic"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (ANTHROPIC_ENDPOINT_URL_HEADER, config.endpoints.anthropic.as_str()),
+        ];
+        let request = json!({"n": 3, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choices = body["choices"].as_array().unwrap();
+        assert_eq!(choices.iter().map(|choice| choice["index"].clone()).collect::<Vec<_>>(), vec![json!(0), json!(1), json!(2)]);
+        for choice in choices {
+            assert_eq!(choice["content"][1]["text"], "Paris.");
+        }
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+        assert_eq!(testing::received(&upstream, testing::ANTHROPIC_PATH).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn streamed_choices_keep_their_index_when_interleaved() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let chunk = |index: u32, content: &str| {
+            json!({"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+                   "choices": [{"index": index, "delta": {"content": content}, "finish_reason": null}]})
+        };
+        let stream = testing::sse(&[chunk(0, "Pa"), chunk(1, "It is "), chunk(0, "ris."), chunk(1, "Paris.")]);
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "n": 2, "stream": true, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let answer: Vec<(u64, String)> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+            .filter_map(|chunk| {
+                let choice = chunk.pointer("/choices/0")?;
+                Some((choice["index"].as_u64()?, choice["delta"]["content"].as_str()?.to_string()))
+            })
+            .skip_while(|(_, content)| content != "\n</thinking>")
+            .skip(1)
+            .collect();
+        assert_eq!(answer, vec![
+            (0, "\n\n".to_string()),
+            (0, "Pa".to_string()),
+            (1, "It is ".to_string()),
+            (0, "ris.".to_string()),
+            (1, "Paris.".to_string()),
+        ]);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await[0]["n"], 2);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn logprobs_are_rejected_for_anthropic_targets() {
+        let upstream = MockServer::start().await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Anthropic-API-Token", "anthropic-token"),
+            ("X-Target-Model", "anthropic"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (ANTHROPIC_ENDPOINT_URL_HEADER, config.endpoints.anthropic.as_str()),
+        ];
+        let bodies = [
+            json!({"logprobs": true}),
+            json!({"top_logprobs": 2}),
+        ];
+        for (body, stream) in bodies.iter().flat_map(|body| [(body, false), (body, true)]) {
+            for config in ["openai_config", "anthropic_config"] {
+                let mut request = json!({"stream": stream, "messages": [{"role": "user", "content": "Hi"}]});
+                request[config] = json!({"body": body});
+                let (status, _, response) = testing::post(&app, "/", &headers, request).await;
+                assert_eq!(status, 400, "{}", response);
+                let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+                assert_eq!(
+                    response["error"]["message"],
+                    "logprobs are only supported with OpenAI-compatible target models"
+                );
+            }
+        }
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn predictions_are_rejected_for_anthropic_targets() {
+        let upstream = MockServer::start().await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Anthropic-API-Token", "anthropic-token"),
+            ("X-Target-Model", "anthropic"),
+        ];
+        for stream in [false, true] {
+            let request = json!({
+                "stream": stream,
+                "messages": [{"role": "user", "content": "Hi"}],
+                "anthropic_config": {"body": {"prediction": {"type": "content", "content": "Hello"}}},
+            });
+            let (status, _, response) = testing::post(&app, "/", &headers, request).await;
+            assert_eq!(status, 400, "{}", response);
+            let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+            assert_eq!(response["error"]["message"], "prediction is only supported with OpenAI-compatible target models");
+        }
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn predicted_outputs_reach_only_the_target_and_report_their_tokens() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::openai_completion(json!({"role": "assistant", "content": "fn main() {}"}), "stop");
+        answer["usage"]["completion_tokens_details"] = json!({"accepted_prediction_tokens": 4, "rejected_prediction_tokens": 2});
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let prediction = json!({"type": "content", "content": "fn main() { }"});
+        let request = json!({"model": "deepthink", "prediction": prediction, "messages": [{"role": "user", "content": "Fix it"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["usage"]["completion_tokens_details"],
+            json!({"accepted_prediction_tokens": 4, "rejected_prediction_tokens": 2})
+        );
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await[0]["prediction"], prediction);
+        assert!(testing::received(&upstream, REASONER_PATH).await[0].get("prediction").is_none());
+
+        // 没有 prediction 的请求不返回明细
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        upstream.reset().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["usage"].get("completion_tokens_details").is_none(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn target_logprobs_are_requested_and_returned() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let logprobs = json!({"content": [{"token": "Paris", "logprob": -0.01, "bytes": [80, 97, 114, 105, 115], "top_logprobs": []}]});
+        let mut answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris"}), "stop");
+        answer["choices"][0]["logprobs"] = logprobs.clone();
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "logprobs": true, "top_logprobs": 1, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["logprobs"], logprobs);
+        let target_call = &testing::received(&upstream, OPENAI_PATH).await[0];
+        assert_eq!((&target_call["logprobs"], &target_call["top_logprobs"]), (&json!(true), &json!(1)));
+
+        upstream.reset().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let chunk = json!({"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+                           "choices": [{"index": 0, "delta": {"content": "Paris"}, "logprobs": logprobs, "finish_reason": null}]});
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(testing::sse(&[chunk]), "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let mut request = request;
+        request["stream"] = json!(true);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let answer = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+            .find(|chunk| chunk["choices"][0]["delta"]["content"] == "Paris")
+            .unwrap();
+        assert_eq!(answer["choices"][0]["logprobs"], logprobs);
+    }
+
+    fn internal_headers(caller: &[(&'static str, &str)], tokens: (&str, &str, &str)) -> Result<axum::http::HeaderMap> {
+        let mut headers = axum::http::HeaderMap::new();
+        for (name, value) in caller {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        let token_config = TokenConfig {
+            deepseek_token: tokens.0.to_string(),
+            openai_token: tokens.1.to_string(),
+            anthropic_token: tokens.2.to_string(),
+            priority: 10,
+            daily_budget_usd: None,
+            monthly_budget_usd: None,
+            tenant: None,
+        };
+        let endpoints = EndpointConfig {
+            deepseek: "http://reasoner.test/v1/chat/completions".to_string(),
+            anthropic: "http://anthropic.test/v1/messages".to_string(),
+            openai: "http://openai.test/v1/chat/completions".to_string(),
+            allow_override: true,
+            allowed_hosts: Vec::new(),
+            completion_paths: Default::default(),
+        };
+        build_internal_headers(headers, &token_config, &endpoints)
+    }
+
+    #[test]
+    fn internal_headers_default_to_the_configured_target_and_endpoints() {
+        let headers = internal_headers(&[], ("r", "o", "a")).unwrap();
+        assert_eq!(headers["X-Target-Model"], "openai");
+        assert_eq!(headers["X-DeepSeek-API-Token"], "Bearer r");
+        assert_eq!(headers["X-OpenAI-API-Token"], "o");
+        assert_eq!(headers["X-Anthropic-API-Token"], "a");
+        assert_eq!(headers[DEEPSEEK_ENDPOINT_URL_HEADER], "http://reasoner.test/v1/chat/completions");
+        assert_eq!(headers[OPENAI_ENDPOINT_URL_HEADER], "http://openai.test/v1/chat/completions");
+        assert_eq!(headers[ANTHROPIC_ENDPOINT_URL_HEADER], "http://anthropic.test/v1/messages");
+    }
+
+    #[test]
+    fn internal_headers_keep_the_callers_target_and_endpoints() {
+        let caller = [
+            ("X-Target-Model", "anthropic"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, "http://my-reasoner.test"),
+            (OPENAI_ENDPOINT_URL_HEADER, "http://my-openai.test"),
+            (ANTHROPIC_ENDPOINT_URL_HEADER, "http://my-anthropic.test"),
+        ];
+        let headers = internal_headers(&caller, ("r", "o", "a")).unwrap();
+        assert_eq!(headers["X-Target-Model"], "anthropic");
+        assert_eq!(headers[DEEPSEEK_ENDPOINT_URL_HEADER], "http://my-reasoner.test");
+        assert_eq!(headers[OPENAI_ENDPOINT_URL_HEADER], "http://my-openai.test");
+        assert_eq!(headers[ANTHROPIC_ENDPOINT_URL_HEADER], "http://my-anthropic.test");
+    }
+
+    #[test]
+    fn internal_headers_require_a_token_for_the_target() {
+        let error = internal_headers(&[("X-Target-Model", "anthropic")], ("r", "o", "")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid request: No API token available for target model 'anthropic': configure it in auth.token_mappings or send the X-Anthropic-API-Token header"
+        );
+        assert!(internal_headers(&[], ("r", "", "a")).is_err());
+
+        // 配置中没有 token 时使用调用方发送的 token 头
+        let caller = [("X-Target-Model", "anthropic"), ("X-Anthropic-API-Token", "callers")];
+        let headers = internal_headers(&caller, ("r", "o", "")).unwrap();
+        assert_eq!(headers["X-Anthropic-API-Token"], "callers");
+        // 配置的 token 优先
+        let headers = internal_headers(&caller, ("r", "o", "configured")).unwrap();
+        assert_eq!(headers["X-Anthropic-API-Token"], "configured");
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn compat_requests_can_target_anthropic() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::anthropic_message(json!([{"type": "text", "text": "Paris."}]), "end_turn");
+        answer["usage"]["cache_creation_input_tokens"] = json!(0);
+        answer["usage"]["cache_read_input_tokens"] = json!(0);
+        Mock::given(method("POST"))
+            .and(path("/elsewhere/v1/messages"))
+            .and(wiremock::matchers::header("x-api-key", "ollama"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let endpoint = format!("{}/elsewhere/v1/messages", upstream.uri());
+        let headers = [("X-Target-Model", "anthropic"), (ANTHROPIC_ENDPOINT_URL_HEADER, endpoint.as_str())];
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], format!("<think>\n{}\n</think>\n\nParis.", REASONING));
+        let target_call = &testing::received(&upstream, "/elsewhere/v1/messages").await[0];
+        assert_eq!(target_call["model"], "claude-3-sonnet-20240229");
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[test]
+    fn anthropic_system_prompts_keep_the_callers_blocks() {
+        let blocks = json!([{"type": "text", "text": "Be brief.", "cache_control": {"type": "ephemeral"}}]);
+        let mut request: ApiRequest = serde_json::from_value(json!({
+            "system": blocks,
+            "messages": [{"role": "user", "content": "Hi"}],
+        }))
+        .unwrap();
+
+        // 推理未注入系统提示词时, 原样透传调用方的块
+        let messages = request.build_target_messages(Some("Think."));
+        assert_eq!(serde_json::to_value(anthropic_system_prompt(&request, &messages)).unwrap(), blocks);
+
+        // 追加到系统提示词的推理成为末尾的新块
+        request.injection_mode = Some(crate::models::InjectionMode::SystemAppend);
+        let messages = request.build_target_messages(Some("Think."));
+        assert_eq!(serde_json::to_value(anthropic_system_prompt(&request, &messages)).unwrap(), json!([
+            {"type": "text", "text": "Be brief.", "cache_control": {"type": "ephemeral"}},
+            {"type": "text", "text": "Here is an expert's analysis of the conversation:\nThink."},
+        ]));
+
+        request.system = Some(SystemPrompt::Text("Be brief.".to_string()));
+        let messages = request.build_target_messages(Some("Think."));
+        assert_eq!(
+            anthropic_system_prompt(&request, &messages),
+            Some(SystemPrompt::Text("Be brief.\n\nHere is an expert's analysis of the conversation:\nThink.".to_string()))
+        );
+
+        request.system = None;
+        request.injection_mode = None;
+        assert_eq!(anthropic_system_prompt(&request, &request.build_target_messages(None)), None);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_stop_reasons_become_finish_reasons() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::anthropic_message(json!([{"type": "text", "text": "Par"}]), "max_tokens");
+        answer["usage"]["cache_creation_input_tokens"] = json!(0);
+        answer["usage"]["cache_read_input_tokens"] = json!(0);
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[("X-Target-Model", "anthropic")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+    }
+
+    #[tokio::test]
+    async fn verbose_responses_carry_the_real_upstream_status_and_headers() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(testing::reasoner_completion(REASONING))
+                    .insert_header("x-request-id", "reasoner-1"),
+            )
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(
+                ResponseTemplate::new(203)
+                    .set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop"))
+                    .insert_header("x-request-id", "target-1")
+                    .insert_header("set-cookie", "session=secret"),
+            )
+            .mount(&upstream)
+            .await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+        let request = json!({"verbose": true, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["deepseek_response"]["status"], 200);
+        assert_eq!(body["deepseek_response"]["headers"]["x-request-id"], "reasoner-1");
+        assert_eq!(body["deepseek_response"]["body"]["id"], "reasoning-1");
+        assert_eq!(body["target_response"]["status"], 203);
+        assert_eq!(body["target_response"]["headers"]["x-request-id"], "target-1");
+        assert!(body["target_response"]["headers"].get("set-cookie").is_none());
+        assert_eq!(body["target_response"]["body"]["id"], "chatcmpl-1");
+        assert!(body["target_response"]["body"].get("upstream").is_none());
+
+        let request = json!({"messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (_, _, body) = testing::post(&app, "/", &headers, request).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body.get("deepseek_response").is_none() && body.get("target_response").is_none());
+    }
+
+    #[tokio::test]
+    async fn mistral_targets_use_the_openai_client_in_the_mistral_dialect() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Mistral-API-Token", "mistral-token"),
+            ("X-Target-Model", "mistral"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+
+        let request = json!({"messages": [{"role": "user", "content": "Hi"}], "openai_config": {"body": {"safe_prompt": true}}});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let requests = upstream.received_requests().await.unwrap();
+        let target = requests.iter().find(|request| request.url.path() == OPENAI_PATH).unwrap();
+        assert_eq!(target.headers["authorization"], "Bearer mistral-token");
+        let target: serde_json::Value = serde_json::from_slice(&target.body).unwrap();
+        assert_eq!(target["safe_prompt"], true);
+
+        // 不支持的参数在推理之前就被拒绝
+        let request = json!({"n": 2, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 400, "{}", body);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+    }
+
+    /// Returns the `/metrics` body without the process-wide compression
+    /// counters, which tests running in parallel change.
+    async fn local_metrics(app: &axum::Router) -> String {
+        let (_, body) = testing::get(app, "/metrics", &[]).await;
+        let (local, _) = body.split_once(",\"compression\":").unwrap();
+        format!("{}}}", local)
+    }
+
+    #[tokio::test]
+    async fn streams_stop_when_the_consumer_disconnects() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let mut config = testing::config(&upstream);
+        config.server.stream_buffer = 1;
+        let (app, state) = testing::app(&config);
+
+        assert_eq!(local_metrics(&app).await, r#"{"coalesced_reasoning_frames":0,"consumer_disconnects":0,"dropped_stream_frames":0,"relayed_responses":0,"unmetered_responses":0,"truncated_reasonings":0,"aborted_streams":0,"queue_depth":{},"circuits":[],"upstream_rate_limits":[],"speculation":{"runs":0,"hits":0,"misses":0,"running":0,"cached":0},"speculative_spend_usd":0.0}"#);
+
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(
+                json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]})
+                    .to_string(),
+            ))
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(app.clone(), request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        drop(response);
+
+        for _ in 0..100 {
+            if state.metrics.snapshot().consumer_disconnects == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(local_metrics(&app).await, r#"{"coalesced_reasoning_frames":0,"consumer_disconnects":1,"dropped_stream_frames":0,"relayed_responses":0,"unmetered_responses":0,"truncated_reasonings":0,"aborted_streams":0,"queue_depth":{},"circuits":[],"upstream_rate_limits":[],"speculation":{"runs":0,"hits":0,"misses":0,"running":0,"cached":0},"speculative_spend_usd":0.0}"#);
+        assert!(testing::received(&upstream, OPENAI_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_streams_end_with_an_error_chunk_frame_and_done() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        // 推理端口无人监听, 连接失败, 尚未发送任何输出
+        config.endpoints.deepseek = "http://127.0.0.1:1/v1/chat/completions".to_string();
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "n": 2, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let frames: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+        let (done, frames) = frames.split_last().unwrap();
+        assert_eq!(*done, "[DONE]");
+        let (error, frames) = frames.split_last().unwrap();
+        let error: serde_json::Value = serde_json::from_str(error).unwrap();
+        assert!(error["error"]["message"].is_string(), "{}", body);
+        let finishes: Vec<serde_json::Value> = frames[frames.len() - 2..]
+            .iter()
+            .map(|frame| serde_json::from_str::<serde_json::Value>(frame).unwrap()["choices"][0].clone())
+            .collect();
+        assert_eq!(finishes[0]["index"], 0);
+        assert_eq!(finishes[1]["index"], 1);
+        assert!(finishes.iter().all(|choice| choice["finish_reason"] == "error"));
+    }
+
+    /// Streams a compat request whose target sends one unparseable frame
+    /// between its answer deltas, with the target parsed under `strictness`.
+    async fn stream_with_garbled_frame(strictness: ParseStrictness) -> (String, Arc<AppState>) {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let chunk = |content: &str| {
+            json!({"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]})
+        };
+        let body = format!("data: {}\n\ndata: {{not json\n\ndata: {}\n\ndata: [DONE]\n\n", chunk("Par"), chunk("is."));
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.streaming.parse_strictness.openai = strictness;
+        let (app, state) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        (body, state)
+    }
+
+    #[tokio::test]
+    async fn lenient_parsers_skip_unparseable_frames_silently() {
+        let (body, state) = stream_with_garbled_frame(ParseStrictness::Lenient).await;
+        assert!(body.contains(r#""content":"Par""#) && body.contains(r#""content":"is.""#));
+        assert!(!body.contains("dropped_frames"));
+        assert_eq!(state.metrics.snapshot().dropped_stream_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn warning_parsers_report_dropped_frames() {
+        let (body, state) = stream_with_garbled_frame(ParseStrictness::Warn).await;
+        assert!(body.contains(r#""content":"Par""#) && body.contains(r#""content":"is.""#));
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+        assert_eq!(metadata["dropped_frames"], json!({"openai": 1}));
+        assert_eq!(state.metrics.snapshot().dropped_stream_frames, 1);
+    }
+
+    #[tokio::test]
+    async fn strict_parsers_abort_on_unparseable_frames() {
+        let (body, _) = stream_with_garbled_frame(ParseStrictness::Strict).await;
+        assert!(body.contains(r#""content":"Par""#) && !body.contains(r#""content":"is.""#));
+        assert!(body.contains(r#""code":"stream_aborted""#), "{}", body);
+        assert!(body.contains("{not json"));
+        assert_eq!(sse_frames(&body).last().unwrap().1, "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn strict_parsers_accept_the_done_marker() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let mut config = testing::config(&upstream);
+        config.streaming.parse_strictness.deepseek = ParseStrictness::Strict;
+        config.streaming.parse_strictness.openai = ParseStrictness::Strict;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(body.contains(r#""content":"Paris.""#));
+        assert!(!body.contains("error"), "{}", body);
+    }
+
+    /// Returns the `model` of every chunk of an SSE response body.
+    fn chunk_models(body: &str) -> Vec<serde_json::Value> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+            .filter_map(|chunk| chunk.get("model").cloned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streamed_chunks_report_the_requested_model() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let models = chunk_models(&body);
+        assert!(models.len() > 3);
+        assert!(models.iter().all(|model| model == "deepthink"), "{:?}", models);
+        assert!(!body.contains("upstream_models"));
+    }
+
+    #[tokio::test]
+    async fn native_streams_report_the_upstream_models() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+        let mut request = json!({
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "deepseek_config": {"body": {"model": "deepseek-reasoner"}},
+            "openai_config": {"body": {"model": "gpt-4o"}},
+        });
+        let (_, _, body) = testing::post(&app, "/", &headers, request.clone()).await;
+        let models = chunk_models(&body);
+        assert_eq!(models.first().unwrap(), "deepseek-reasoner");
+        assert_eq!(models.last().unwrap(), "gpt-4o");
+
+        // 指定 model 时 chunk 统一使用该名称, verbose 时在 metadata 事件中给出真实模型
+        request["model"] = json!("my-model");
+        request["verbose"] = json!(true);
+        let (_, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert!(chunk_models(&body).iter().all(|model| model == "my-model"));
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+        assert_eq!(metadata["upstream_models"], json!({"reasoner": "deepseek-reasoner", "target": "gpt-4o"}));
+    }
+
+    #[tokio::test]
+    async fn seeded_ids_and_a_fixed_clock_make_responses_reproducible() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let config = testing::config(&upstream);
+        let run = || async {
+            let mut state = Arc::try_unwrap(crate::app_state(&config)).ok().unwrap();
+            state.ids = Arc::new(crate::identity::SeededIds::new("test"));
+            state.clock = Arc::new(crate::identity::FixedClock(chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap()));
+            let (app, _) = crate::routers(&config, Arc::new(state));
+            let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Capital of France?"}]});
+            testing::post(&app, "/v1/chat/completions", &[], request).await.2
+        };
+
+        let body = run().await;
+        assert_eq!(body, run().await);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["id"], "chatcmpl-test-0");
+        assert_eq!(body["created"], 1_700_000_000);
+    }
+
+    #[tokio::test]
+    async fn idempotent_retries_replay_only_the_same_callers_request() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let call = |authorization: &'static str, request: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let headers = [("Authorization", authorization), ("Idempotency-Key", "retry-1")];
+                let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+                assert_eq!(status, 200, "{}", body);
+                serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"].clone()
+            }
+        };
+
+        let first = call("Bearer caller", request.clone()).await;
+        assert_eq!(call("Bearer caller", request.clone()).await, first);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 1);
+
+        // 其他调用方或不同的请求体使用同一个 key 时不会拿到缓存的响应
+        assert_ne!(call("Bearer other-caller", request.clone()).await, first);
+        let mut changed = request.clone();
+        changed["messages"][0]["content"] = json!("Capital of Spain?");
+        assert_ne!(call("Bearer caller", changed).await, first);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 3);
+    }
+
+    /// Streams one compat answer whose target streams `deltas`, and returns
+    /// the deltas the client received.
+    async fn stream_answer(thinking_format: &str, deltas: &[&str]) -> Vec<serde_json::Value> {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, deltas).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": true,
+            "thinking_format": thinking_format,
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) =
+            testing::post(&app, "/v1/chat/completions", &[("X-Target-Model", "openai")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        testing::stream_deltas(&body)
+    }
+
+    #[tokio::test]
+    async fn inline_reasoning_is_separated_from_the_streamed_answer() {
+        insta::assert_json_snapshot!(stream_answer("tag", &["The answer", " is Paris."]).await);
+    }
+
+    #[tokio::test]
+    async fn answers_starting_with_whitespace_get_no_separator() {
+        insta::assert_json_snapshot!(stream_answer("tag", &["\nParis."]).await);
+    }
+
+    #[tokio::test]
+    async fn empty_answers_get_no_separator() {
+        insta::assert_json_snapshot!(stream_answer("tag", &[]).await);
+    }
+
+    #[tokio::test]
+    async fn separate_reasoning_gets_no_separator() {
+        insta::assert_json_snapshot!(stream_answer("reasoning_content", &["The answer", " is Paris."]).await);
+    }
+
+    #[tokio::test]
+    async fn content_part_thinking_is_a_part_of_the_message() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                json!({"role": "assistant", "content": "Paris."}),
+                "stop",
+            )))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "thinking_format": "content_part",
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) =
+            testing::post(&app, "/v1/chat/completions", &[("X-Target-Model", "openai")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        insta::assert_json_snapshot!(body["choices"][0]["message"]);
+    }
+
+    #[tokio::test]
+    async fn content_part_thinking_streams_as_parts() {
+        insta::assert_json_snapshot!(stream_answer("content_part", &["The answer", " is Paris."]).await);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn image_parts_are_converted_for_the_target_and_flattened_for_the_reasoner() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let mut answer = testing::anthropic_message(json!([{"type": "text", "text": "A cat."}]), "end_turn");
+        answer["usage"]["cache_creation_input_tokens"] = json!(0);
+        answer["usage"]["cache_read_input_tokens"] = json!(0);
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+        ]}]});
+        let headers = [("X-Target-Model", "anthropic")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let reasoner_call = &testing::received(&upstream, REASONER_PATH).await[0];
+        assert_eq!(reasoner_call["messages"].as_array().unwrap().last().unwrap()["content"], "What is this?\n[image]");
+        let target_call = &testing::received(&upstream, testing::ANTHROPIC_PATH).await[0];
+        assert_eq!(target_call["messages"][0]["content"][1], json!({"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}}));
+
+        let mut request = request;
+        request["messages"][0]["content"][1] = json!({"type": "input_audio", "input_audio": {}});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 400);
+        assert!(body.contains("messages[0].content[1] of type 'input_audio'"), "{}", body);
+    }
+
+    /// Answers a compat request validated against a JSON schema, with the
+    /// target answering `answers` in turn.
+    ///
+    /// Returns the status and body of the response and the bodies the target received.
+    async fn answer_with_schema(answers: &[&str]) -> (u16, serde_json::Value, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        for answer in answers {
+            Mock::given(method("POST"))
+                .and(path(OPENAI_PATH))
+                .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": answer}), "stop")))
+                .up_to_n_times(1)
+                .mount(&upstream)
+                .await;
+        }
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "validate_json_schema": true,
+            "response_format": {"type": "json_schema", "json_schema": {"name": "city", "schema": {
+                "type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"],
+            }}},
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        (status.as_u16(), serde_json::from_str(&body).unwrap(), testing::received(&upstream, OPENAI_PATH).await)
+    }
+
+    #[tokio::test]
+    async fn fenced_json_answers_are_unwrapped_without_a_retry() {
+        let (status, body, target_calls) = answer_with_schema(&["```json\n{\"city\": \"Paris\"}\n```"]).await;
+        assert_eq!(status, 200, "{}", body);
+        let content = body["choices"][0]["message"]["content"].as_str().unwrap();
+        assert!(content.ends_with("</think>\n\n{\"city\": \"Paris\"}"), "{}", content);
+        assert_eq!(target_calls.len(), 1);
+        assert!(target_calls[0]["response_format"]["json_schema"].is_object());
+    }
+
+    #[tokio::test]
+    async fn nonconforming_answers_are_retried_once_with_the_errors() {
+        let (status, body, target_calls) = answer_with_schema(&["Paris.", "{\"city\": \"Paris\"}"]).await;
+        assert_eq!(status, 200, "{}", body);
+        let content = body["choices"][0]["message"]["content"].as_str().unwrap();
+        assert!(content.ends_with("</think>\n\n{\"city\": \"Paris\"}"), "{}", content);
+        assert_eq!(body["usage"]["completion_tokens"], 8 + 5 + 5);
+        assert_eq!(target_calls.len(), 2);
+        let retry = target_calls[1]["messages"].as_array().unwrap();
+        assert_eq!(retry[retry.len() - 2], json!({"role": "assistant", "content": "Paris."}));
+        assert!(retry[retry.len() - 1]["content"].as_str().unwrap().contains("answer is not valid JSON"));
+    }
+
+    #[tokio::test]
+    async fn answers_failing_the_retry_are_unprocessable() {
+        let (status, body, target_calls) = answer_with_schema(&["Paris.", "{\"town\": \"Paris\"}"]).await;
+        assert_eq!(status, 422);
+        assert_eq!(body["error"]["type"], "json_schema_validation_failed");
+        assert_eq!(target_calls.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn schema_validation_needs_one_non_streamed_choice() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let request = json!({
+            "model": "deepthink",
+            "validate_json_schema": true,
+            "response_format": {"type": "json_schema", "json_schema": {"name": "city", "schema": {"type": "object"}}},
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+
+        let mut streamed = request.clone();
+        streamed["stream"] = json!(true);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], streamed).await;
+        assert_eq!(status, 400);
+        assert!(body.contains("not supported for streaming requests"), "{}", body);
+
+        let mut choices = request;
+        choices["n"] = json!(2);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], choices).await;
+        assert_eq!(status, 400);
+        assert!(body.contains("supports a single choice only"), "{}", body);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_tool_loop_round_trips_tool_calls() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let anthropic_message = |content: serde_json::Value, stop_reason: &str| {
+            let mut message = testing::anthropic_message(content, stop_reason);
+            message["usage"]["cache_creation_input_tokens"] = json!(0);
+            message["usage"]["cache_read_input_tokens"] = json!(0);
+            message
+        };
+        // 第二轮带有工具结果, 先挂载以优先匹配
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .and(wiremock::matchers::body_string_contains("tool_result"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(anthropic_message(
+                json!([{"type": "text", "text": "It is 18C and sunny in Paris."}]),
+                "end_turn",
+            )))
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(anthropic_message(
+                json!([
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+                ]),
+                "tool_use",
+            )))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let headers = [("X-Target-Model", "anthropic")];
+        let tools = json!([{"type": "function", "function": {
+            "name": "get_weather",
+            "parameters": {"type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"]}
+        }}]);
+        let mut messages = vec![json!({"role": "user", "content": "What is the weather in Paris?"})];
+
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, json!({"model": "deepthink", "messages": messages, "tools": tools})).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choice = &body["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        let call = &choice["message"]["tool_calls"][0];
+        assert_eq!(call["function"]["name"], "get_weather");
+        let arguments: serde_json::Value = serde_json::from_str(call["function"]["arguments"].as_str().unwrap()).unwrap();
+        assert_eq!(arguments, json!({"city": "Paris"}));
+        let call_id = call["id"].as_str().unwrap().to_string();
+
+        messages.push(json!({"role": "assistant", "content": null, "tool_calls": [call]}));
+        messages.push(json!({"role": "tool", "tool_call_id": call_id, "content": "18C, sunny"}));
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, json!({"model": "deepthink", "messages": messages, "tools": tools})).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["choices"][0]["message"]["content"].as_str().unwrap().contains("sunny"));
+
+        let calls = testing::received(&upstream, testing::ANTHROPIC_PATH).await;
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0]["tools"][0]["input_schema"]["required"], json!(["city"]));
+        let blocks: Vec<serde_json::Value> = calls[1]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|turn| turn["content"].as_array().cloned().unwrap_or_default())
+            .collect();
+        let tool_use = blocks.iter().find(|block| block["type"] == "tool_use").unwrap();
+        let tool_result = blocks.iter().find(|block| block["type"] == "tool_result").unwrap();
+        assert_eq!(tool_use["id"], call_id.as_str());
+        assert_eq!(tool_use["input"], json!({"city": "Paris"}));
+        assert_eq!(tool_result["tool_use_id"], call_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn sampling_parameters_are_resolved_per_phase() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {"temperature": 0.5},
+            "reasoner": {"temperature": 0.6},
+            "target": {"top_p": 0.9},
+            "client_temperature_applies_to": "target",
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "temperature": 0.1, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let reasoner_call = &testing::received(&upstream, REASONER_PATH).await[0];
+        assert_eq!(reasoner_call["temperature"], 0.6);
+        assert!(reasoner_call.get("top_p").is_none());
+        let target_call = &testing::received(&upstream, OPENAI_PATH).await[0];
+        assert_eq!(target_call["temperature"], 0.1);
+        assert_eq!(target_call["top_p"], 0.9);
+    }
+
+    /// Answers one compat request whose reasoner also writes a draft answer,
+    /// handling it according to `mode`.
+    ///
+    /// Returns the answer content and the messages the target received.
+    async fn answer_with_reasoner_answer(mode: &str, stream: bool) -> (String, serde_json::Value) {
+        let upstream = MockServer::start().await;
+        let reasoner = match stream {
+            true => ResponseTemplate::new(200).set_body_raw(
+                testing::reasoner_stream(REASONING).replace(r#"{"content":""}"#, r#"{"content":" Draft: Paris. "}"#),
+                "text/event-stream",
+            ),
+            false => {
+                let mut completion = testing::reasoner_completion(REASONING);
+                completion["choices"][0]["message"]["content"] = json!(" Draft: Paris. ");
+                ResponseTemplate::new(200).set_body_json(completion)
+            }
+        };
+        Mock::given(method("POST")).and(path(REASONER_PATH)).respond_with(reasoner).mount(&upstream).await;
+        if stream {
+            testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        } else {
+            Mock::given(method("POST"))
+                .and(path(OPENAI_PATH))
+                .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                    json!({"role": "assistant", "content": "Paris."}),
+                    "stop",
+                )))
+                .mount(&upstream)
+                .await;
+        }
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "reasoner_answer": mode,
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let content = match stream {
+            true => streamed_content(&body),
+            false => serde_json::from_str::<serde_json::Value>(&body).unwrap()["choices"][0]["message"]["content"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        };
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        (content, target_calls[0]["messages"].clone())
+    }
+
+    #[tokio::test]
+    async fn reasoner_answers_are_discarded_by_default() {
+        for stream in [false, true] {
+            let (content, target_messages) = answer_with_reasoner_answer("discard", stream).await;
+            assert!(!content.contains("Draft"), "{}", content);
+            assert!(!target_messages.to_string().contains("Draft"));
+        }
+    }
+
+    #[tokio::test]
+    async fn reasoner_answers_can_be_appended_to_the_reasoning() {
+        let appended = format!("{}\n\nDraft: Paris.", REASONING);
+        let (content, target_messages) = answer_with_reasoner_answer("append_to_reasoning", false).await;
+        assert_eq!(content, format!("<think>\n{}\n</think>\n\nParis.", appended));
+        assert!(target_messages.to_string().contains("Draft: Paris."));
+        let (content, target_messages) = answer_with_reasoner_answer("append_to_reasoning", true).await;
+        assert_eq!(content, format!("<thinking>\n{}\n</thinking>\n\nParis.", appended));
+        assert!(target_messages.to_string().contains("Draft: Paris."));
+    }
+
+    #[tokio::test]
+    async fn reasoner_answers_can_be_returned_separately() {
+        let section = "\n<reasoner_answer>\nDraft: Paris.\n</reasoner_answer>";
+        let (content, target_messages) = answer_with_reasoner_answer("return_as_block", false).await;
+        // 非流式响应中各文本块以空行分隔
+        assert_eq!(content, format!("<think>\n{}\n</think>\n{}\n\nParis.", REASONING, section));
+        assert!(!target_messages.to_string().contains("Draft"));
+        let (content, target_messages) = answer_with_reasoner_answer("return_as_block", true).await;
+        assert_eq!(content, format!("<thinking>\n{}{}\n</thinking>\n\nParis.", REASONING, section));
+        assert!(!target_messages.to_string().contains("Draft"));
+    }
+
+    #[tokio::test]
+    async fn unknown_reasoner_answer_modes_are_rejected() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let request = json!({"model": "deepthink", "reasoner_answer": "keep", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 400, "{}", body);
+        assert!(body.contains("Invalid reasoner_answer"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn compat_responses_report_timings_in_headers() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        for name in ["x-deepthink-timing-queue-ms", "x-deepthink-timing-reasoner-total-ms", "x-deepthink-timing-target-total-ms"] {
+            assert!(headers[name].to_str().unwrap().parse::<u64>().is_ok(), "{}", name);
+        }
+        // 非流式阶段没有首个 token 的时间
+        assert!(!headers.contains_key("x-deepthink-timing-reasoner-ttft-ms"));
+        assert!(!body.contains("timings"));
+    }
+
+    #[tokio::test]
+    async fn streams_report_timings_in_the_metadata_frame_on_request() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let mut request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert!(!body.contains("timings"), "{}", body);
+
+        request["include_timings"] = json!(true);
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let timings: Timings = serde_json::from_value(serde_json::from_str::<serde_json::Value>(metadata).unwrap()["timings"].clone()).unwrap();
+        assert!(timings.reasoner_first_token_ms.is_some());
+        assert!(timings.target_first_token_ms.is_some());
+        assert!(timings.reasoner_first_token_ms.unwrap() <= timings.reasoner_total_ms);
+    }
+
+    #[tokio::test]
+    async fn timings_break_down_each_upstream_call() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let answer = testing::sse(&[json!({"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": {"content": "Paris."}, "finish_reason": "stop"}]})]);
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "text/event-stream").set_delay(Duration::from_millis(300)))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "include_timings": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let timings: Timings = serde_json::from_value(serde_json::from_str::<serde_json::Value>(metadata).unwrap()["timings"].clone()).unwrap();
+        let providers: Vec<&str> = timings.connections.iter().map(|call| call.provider.as_str()).collect();
+        assert_eq!(providers, ["deepseek", "openai"]);
+        let target_call = &timings.connections[1];
+        assert_eq!(target_call.host, "127.0.0.1");
+        assert!(target_call.ttfb_ms >= 300, "{:?}", target_call);
+        // reqwest 没有连接和 TLS 的钩子, 这些分项为空
+        assert_eq!((target_call.connect_ms, target_call.tls_ms, target_call.request_write_ms), (None, None, None));
+
+        let (_, body) = testing::get(&app, "/metrics", &[]).await;
+        let metrics: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let host = metrics["upstream_connections"].as_array().unwrap().iter().find(|host| host["host"] == "127.0.0.1").unwrap();
+        assert!(host["ttfb"]["count"].as_u64().unwrap() >= 2);
+        // 最后一个桶是累计总数
+        assert_eq!(host["ttfb"]["buckets"].as_array().unwrap().last().unwrap()["count"], host["ttfb"]["count"]);
+    }
+
+    #[tokio::test]
+    async fn streamed_reasoning_is_truncated_at_the_timeout() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.deepseek = testing::mock_stalled_reasoner(REASONING).await;
+        config.reasoning.reasoning_timeout_secs = Some(1);
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let (head, _) = REASONING.split_at(REASONING.len() / 2);
+        assert_eq!(streamed_content(&body), format!("<thinking>\n{}\n</thinking>\n\nParis.", head));
+        assert!(body.contains(r#""reasoning_truncated":true"#), "{}", body);
+        let injected = &testing::received(&upstream, OPENAI_PATH).await[0]["messages"];
+        let injected = injected.as_array().unwrap().last().unwrap()["content"].as_str().unwrap().to_string();
+        assert!(injected.contains(&format!("{}\n[reasoning truncated]", head.trim())), "{}", injected);
+    }
+
+    /// Answers one non-streamed compat request whose reasoner takes longer
+    /// than the request's one-second reasoning timeout.
+    async fn answer_slow_reasoner(policy: EmptyReasoningPolicy) -> (u16, String, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(testing::reasoner_completion(REASONING))
+                    .set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.reasoning.empty_policy = policy;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "reasoning_timeout_secs": 1, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        (status.as_u16(), body, testing::received(&upstream, OPENAI_PATH).await)
+    }
+
+    #[tokio::test]
+    async fn reasoning_timeouts_fail_non_streamed_requests() {
+        let (status, body, target_calls) = answer_slow_reasoner(EmptyReasoningPolicy::Error).await;
+        assert_eq!(status, 504, "{}", body);
+        assert!(body.contains("reasoning_timeout"), "{}", body);
+        assert!(target_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reasoning_timeouts_are_skipped_under_the_skip_policy() {
+        let (status, body, target_calls) = answer_slow_reasoner(EmptyReasoningPolicy::Skip).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(target_calls[0]["messages"], json!([{"role": "user", "content": "Hi"}]));
+    }
+
+    /// Posts a token count request for a one-message conversation.
+    async fn count_tokens(config: &Config, headers: &[(&str, &str)]) -> serde_json::Value {
+        let (app, _) = testing::app(config);
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "tiktoken is great!"}]});
+        let (status, _, body) = testing::post(&app, "/v1/token_count", headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_counts_use_tiktoken_for_openai_models_and_report_headroom() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        let mapping = serde_json::from_value(json!({"deepseek_model": "deepseek-r1:14b", "target_model": "gpt-4o", "parameters": {}})).unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        config.compat.context_windows.insert("gpt-4o".to_string(), 2048);
+        let counts = count_tokens(&config, &[]).await;
+
+        assert_eq!(counts["reasoner"]["model"], "deepseek-r1:14b");
+        assert_eq!(counts["reasoner"]["counting"], "estimate");
+        assert!(counts["reasoner"].get("headroom").is_none());
+        assert_eq!(counts["target"]["model"], "gpt-4o");
+        assert_eq!(counts["target"]["counting"], "tiktoken");
+        let target_tokens = counts["target"]["prompt_tokens"].as_i64().unwrap();
+        assert!(target_tokens > 1024);
+        assert_eq!(counts["target"]["headroom"], 2048 - target_tokens);
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_targets_are_counted_by_count_tokens_when_enabled() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}/count_tokens", testing::ANTHROPIC_PATH)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"input_tokens": 42})))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let headers = [("X-Target-Model", "anthropic")];
+
+        // 未开启时不调用上游, 按字符估算
+        let counts = count_tokens(&config, &headers).await;
+        assert_eq!(counts["target"]["counting"], "estimate");
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+
+        config.compat.anthropic_token_counting = true;
+        let counts = count_tokens(&config, &headers).await;
+        assert_eq!(counts["target"]["counting"], "anthropic");
+        assert_eq!(counts["target"]["prompt_tokens"], 42 + 1024);
+        let counted = &testing::received(&upstream, &format!("{}/count_tokens", testing::ANTHROPIC_PATH)).await[0];
+        let mut fields: Vec<&String> = counted.as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["messages", "model"]);
+    }
+
+    #[tokio::test]
+    async fn failed_anthropic_counts_fall_back_to_the_estimate() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}/count_tokens", testing::ANTHROPIC_PATH)))
+            .respond_with(ResponseTemplate::new(500).set_body_json(json!({"error": "overloaded"})))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.compat.anthropic_token_counting = true;
+        let counts = count_tokens(&config, &[("X-Target-Model", "anthropic")]).await;
+        assert_eq!(counts["target"]["counting"], "estimate");
+        assert!(counts["target"]["prompt_tokens"].as_u64().unwrap() > 1024);
+    }
+
+    #[tokio::test]
+    async fn language_instructions_reach_only_the_configured_phase() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "gpt-4o",
+            "parameters": {},
+            "reasoning_language": "English",
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("english-reasoning".to_string(), mapping);
+        let (app, _) = testing::app(&config);
+        // 推理模型的请求在客户端注入的系统提示之后才是请求自己的系统消息
+        let system_prompt = |call: &serde_json::Value| {
+            let messages = call["messages"].as_array().unwrap();
+            let system = messages.iter().take_while(|message| message["role"] == "system");
+            system.map(|message| message["content"].as_str().unwrap()).collect::<Vec<_>>().join("\n\n")
+        };
+        let reason_in_english = "Reason in English, regardless of the language of the conversation.";
+
+        let messages = json!([{"role": "system", "content": "Sei knapp."}, {"role": "user", "content": "Hauptstadt von Frankreich?"}]);
+        for request in [
+            json!({"model": "deepthink", "messages": messages}),
+            json!({"model": "english-reasoning", "messages": messages, "answer_language": "German"}),
+            json!({"model": "deepthink", "messages": messages, "reasoning_language": "English"}),
+        ] {
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            assert_eq!(status, 200, "{}", body);
+        }
+        let reasoner_calls = testing::received(&upstream, REASONER_PATH).await;
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+
+        // 未设置时提示词不变
+        assert!(!system_prompt(&reasoner_calls[0]).contains(reason_in_english));
+        assert!(!system_prompt(&target_calls[0]).contains("Answer in"));
+        // 映射的 reasoning_language 和请求的 answer_language
+        assert!(system_prompt(&reasoner_calls[1]).ends_with(reason_in_english), "{}", reasoner_calls[1]);
+        assert!(!system_prompt(&reasoner_calls[1]).contains("Answer in German."));
+        assert!(system_prompt(&target_calls[1]).ends_with("Sei knapp.\n\nAnswer in German."), "{}", target_calls[1]);
+        assert!(!system_prompt(&target_calls[1]).contains(reason_in_english));
+        // 请求的 reasoning_language
+        assert!(system_prompt(&reasoner_calls[2]).ends_with(reason_in_english), "{}", reasoner_calls[2]);
+        assert!(!system_prompt(&target_calls[2]).contains("Answer in"));
+    }
+
+    /// Serves a config whose `deepthink` mapping is `mapping` on top of a
+    /// `gpt-4o` target with a known context window.
+    fn discovery_app(mapping: serde_json::Value) -> axum::Router {
+        let mut config = Config::default();
+        let mut fields = json!({"deepseek_model": "deepseek-r1:14b", "target_model": "gpt-4o", "parameters": {"temperature": 0.5}});
+        fields.as_object_mut().unwrap().extend(mapping.as_object().unwrap().clone());
+        config.models.model_mappings = HashMap::from([("deepthink".to_string(), serde_json::from_value(fields).unwrap())]);
+        config.compat.context_windows.insert("gpt-4o".to_string(), 128000);
+        testing::app(&config).0
+    }
+
+    async fn model_details(app: &axum::Router, uri: &str) -> serde_json::Value {
+        let (status, body) = testing::get(app, uri, &[]).await;
+        assert_eq!(status, 200, "{}", body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn model_discovery_keeps_its_schema() {
+        let app = discovery_app(json!({}));
+        insta::assert_json_snapshot!(model_details(&app, "/v1/models/deepthink").await);
+
+        // 普通列表保持 OpenAI 兼容, 只有 verbose 时带有 details
+        let list = model_details(&app, "/v1/models").await;
+        assert_eq!(list, json!({"object": "list", "data": [{"id": "deepthink", "object": "model", "created": 0, "owned_by": "deepthink"}]}));
+        let verbose = model_details(&app, "/v1/models?verbose=true").await;
+        assert_eq!(verbose["data"][0], model_details(&app, "/v1/models/deepthink").await);
+
+        let (status, body) = testing::get(&app, "/v1/models/unknown", &[]).await;
+        assert_eq!(status, 404, "{}", body);
+        assert!(body.contains("model_not_found"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn model_details_follow_the_mapping_config() {
+        let default = model_details(&discovery_app(json!({})), "/v1/models/deepthink").await;
+        assert_eq!(default["details"]["capabilities"]["supports_tools"], true);
+        assert_eq!(default["details"]["reasoning"]["optimistic"], false);
+
+        let app = discovery_app(json!({
+            "target_model": "llama3.1:8b",
+            "capabilities": {"supports_tools": false, "supports_images": false, "supports_json_mode": true},
+            "optimistic": {"enabled": true},
+            "reasoning_timeout_secs": 30,
+        }));
+        let changed = model_details(&app, "/v1/models/deepthink").await;
+        let details = &changed["details"];
+        assert_eq!(details["target_model"], "llama3.1:8b");
+        assert_eq!(details["capabilities"]["supports_tools"], false);
+        assert_eq!(details["capabilities"]["supports_images"], false);
+        assert_eq!(details["reasoning"]["optimistic"], true);
+        assert_eq!(details["reasoning"]["timeout_secs"], 30);
+        // 上下文窗口按新的目标模型查找
+        assert!(details["context_windows"].get("target").is_none(), "{}", details);
+    }
+
+    #[tokio::test]
+    async fn progress_callbacks_are_refused_unless_the_host_is_allowed() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let messages = json!([{"role": "user", "content": "Hi"}]);
+        let request = json!({"model": "deepthink", "messages": messages, "deepthink": {"progress_callback_url": "https://hooks.example.com/progress"}});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 400, "{}", body);
+        assert!(body.contains("progress callbacks are disabled"), "{}", body);
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tenants_resolve_models_only_within_their_namespace() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop")))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.server.admin_token = Some("admin".to_string());
+        let tokens = |tenant: &str| TokenConfig {
+            tenant: Some(tenant.to_string()),
+            ..config.auth.default_tokens.clone()
+        };
+        config.auth.token_mappings.insert("sk-a".to_string(), tokens("team_a"));
+        let mut team_a = crate::config::TenantConfig {
+            models: Some(config.models.clone()),
+            ..Default::default()
+        };
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "team-a-model",
+            "parameters": {},
+        }))
+        .unwrap();
+        team_a.models.as_mut().unwrap().model_mappings.insert("team-a".to_string(), mapping);
+        let mut team_b = crate::config::TenantConfig {
+            auth: Some(config.auth.clone()),
+            ..Default::default()
+        };
+        let auth = team_b.auth.as_mut().unwrap();
+        auth.token_mappings = HashMap::from([("sk-b".to_string(), auth.default_tokens.clone())]);
+        config.tenants.insert("team_a".to_string(), team_a);
+        config.tenants.insert("team_b".to_string(), team_b);
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "team-a", "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[("Authorization", "Bearer sk-a")], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[("Authorization", "Bearer sk-b")], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let models: Vec<serde_json::Value> = testing::received(&upstream, OPENAI_PATH).await.iter().map(|call| call["model"].clone()).collect();
+        assert_eq!(models[0], "team-a-model");
+        // team_b 的 token 看不到 team_a 的映射, 使用全局的默认映射
+        assert_ne!(models[1], "team-a-model");
+
+        // 只有管理员可以用请求头选择租户
+        let headers = [("Authorization", "Bearer sk-b"), (crate::tenants::TENANT_HEADER, "team_a")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request.clone()).await;
+        assert_eq!(status, 403, "{}", body);
+        assert!(body.contains("tenant_not_allowed"), "{}", body);
+        let headers = [(crate::tenants::TENANT_HEADER, "team_a"), (crate::trace::ADMIN_TOKEN_HEADER, "admin")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await[2]["model"], "team-a-model");
+    }
+
+    #[test]
+    fn request_priority_follows_the_callers_token() {
+        let mut auth = crate::config::AuthConfig::default();
+        let mut interactive = auth.default_tokens.clone();
+        interactive.priority = 1;
+        auth.token_mappings.insert("sk-interactive".to_string(), interactive);
+        let namespace = crate::tenants::Namespace {
+            tenant: None,
+            models: Default::default(),
+            endpoints: Config::default().endpoints,
+            auth,
+        };
+
+        let headers = |token: &str| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+            headers
+        };
+        assert_eq!(request_priority(&namespace, &headers("sk-interactive")), 1);
+        assert_eq!(request_priority(&namespace, &headers("sk-unknown")), 10);
+        assert_eq!(request_priority(&namespace, &axum::http::HeaderMap::new()), 10);
+    }
+
+    /// Returns a configuration where `sk-metered` may spend $0.40 a day and
+    /// every priced upstream call in these tests costs $0.35.
+    fn metered_config(upstream: &MockServer) -> Config {
+        let mut config = testing::config(upstream);
+        let mut tokens = config.auth.default_tokens.clone();
+        tokens.daily_budget_usd = Some(0.4);
+        config.auth.token_mappings.insert("sk-metered".to_string(), tokens);
+        let price = |prompt: f64, completion: f64| crate::config::ModelPrice {
+            prompt_usd_per_million: prompt,
+            completion_usd_per_million: completion,
+            cached_prompt_usd_per_million: None,
+        };
+        config.budget.pricing.insert("gpt-4o".to_string(), price(10_000.0, 10_000.0));
+        config.budget.pricing.insert("text-embedding-3-small".to_string(), price(10_000.0, 0.0));
+        config
+    }
+
+    /// Sends `request` three times as `sk-metered`: the first call is within
+    /// budget, the second passes the warning threshold and the third is
+    /// rejected without reaching the upstream at `upstream_path`.
+    async fn assert_budget_enforced(app: &axum::Router, upstream: &MockServer, uri: &str, upstream_path: &str, request: serde_json::Value) {
+        let caller = [("Authorization", "Bearer sk-metered")];
+        let (status, headers, body) = testing::post(app, uri, &caller, request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(headers.get(BUDGET_WARNING_HEADER).is_none());
+
+        let (status, headers, body) = testing::post(app, uri, &caller, request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let warning = headers[BUDGET_WARNING_HEADER].to_str().unwrap();
+        assert!(warning.starts_with("daily budget 87% used: $0.3500 of $0.40"), "{}", warning);
+
+        let (status, _, body) = testing::post(app, uri, &caller, request.clone()).await;
+        assert_eq!(status, 429, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "budget_exceeded");
+        assert_eq!(body["error"]["param"], "daily");
+        assert_eq!(testing::received(upstream, upstream_path).await.len(), 2);
+
+        // 其他调用方不受影响
+        let (status, _, body) = testing::post(app, uri, &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn reasoned_requests_are_charged_against_the_callers_budget() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&metered_config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        assert_budget_enforced(&app, &upstream, "/v1/chat/completions", OPENAI_PATH, request).await;
+    }
+
+    #[tokio::test]
+    async fn passthrough_responses_are_charged_from_their_usage() {
+        let upstream = MockServer::start().await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+            .mount(&upstream)
+            .await;
+        let mut config = metered_config(&upstream);
+        config.models.passthrough_models = vec!["gpt-4o".to_string()];
+        let (app, state) = testing::app(&config);
+
+        let request = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]});
+        assert_budget_enforced(&app, &upstream, "/v1/chat/completions", OPENAI_PATH, request).await;
+        let metrics = state.metrics.snapshot();
+        assert_eq!((metrics.relayed_responses, metrics.unmetered_responses), (3, 0));
+    }
+
+    #[tokio::test]
+    async fn streamed_passthrough_asks_for_and_charges_the_final_usage() {
+        let upstream = MockServer::start().await;
+        let stream = testing::sse(&[
+            json!({"model": "gpt-4o", "choices": [{"index": 0, "delta": {"content": "Hi"}}]}),
+            json!({"model": "gpt-4o", "choices": [], "usage": {"prompt_tokens": 30, "completion_tokens": 5, "total_tokens": 35}}),
+        ]);
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream.clone(), "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let mut config = metered_config(&upstream);
+        config.models.passthrough_models = vec!["gpt-4o".to_string()];
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "gpt-4o", "stream": true, "messages": []});
+        assert_budget_enforced(&app, &upstream, "/v1/chat/completions", OPENAI_PATH, request).await;
+        let sent = testing::received(&upstream, OPENAI_PATH).await;
+        assert_eq!(sent[0]["stream_options"], json!({"include_usage": true}));
+    }
+
+    #[tokio::test]
+    async fn relayed_responses_without_usage_are_counted_as_unmetered() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"model": "gpt-4o", "choices": []})))
+            .mount(&upstream)
+            .await;
+        let mut config = metered_config(&upstream);
+        config.models.passthrough_models = vec!["gpt-4o".to_string()];
+        let (app, state) = testing::app(&config);
+
+        let caller = [("Authorization", "Bearer sk-metered")];
+        for _ in 0..3 {
+            let (status, _, _) = testing::post(&app, "/v1/chat/completions", &caller, json!({"model": "gpt-4o", "messages": []})).await;
+            assert_eq!(status, 200);
+        }
+        let metrics = state.metrics.snapshot();
+        assert_eq!((metrics.relayed_responses, metrics.unmetered_responses), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn proxied_embeddings_are_charged_against_the_callers_budget() {
+        let upstream = MockServer::start().await;
+        let embeddings = json!({
+            "object": "list",
+            "model": "text-embedding-3-small",
+            "data": [{"object": "embedding", "index": 0, "embedding": [0.5, -0.5]}],
+            "usage": {"prompt_tokens": 35, "total_tokens": 35},
+        });
+        Mock::given(method("POST"))
+            .and(path("/openai/v1/embeddings"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(embeddings))
+            .mount(&upstream)
+            .await;
+        let mut config = metered_config(&upstream);
+        config.compat.proxy_embeddings = true;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "text-embedding-3-small", "input": "Hi"});
+        assert_budget_enforced(&app, &upstream, "/v1/embeddings", "/openai/v1/embeddings", request).await;
+    }
+
+    #[tokio::test]
+    async fn legacy_completions_answer_with_text_completion_objects() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "prompt": ["Capital of France?"], "max_tokens": 16});
+        let (status, _, body) = testing::post(&app, "/v1/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["object"], "text_completion");
+        assert!(body["id"].as_str().unwrap().starts_with("cmpl-"));
+        assert_eq!(body["model"], "deepthink");
+        assert_eq!(body["choices"][0]["index"], 0);
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert!(body["choices"][0]["text"].as_str().unwrap().ends_with("Paris."), "{}", body);
+        assert_eq!(body["usage"], json!({"prompt_tokens": 42, "completion_tokens": 13, "total_tokens": 55}));
+
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        let messages = target_calls[0]["messages"].to_string();
+        assert!(messages.contains("Capital of France?"), "{}", messages);
+    }
+
+    #[tokio::test]
+    async fn streamed_legacy_completions_send_text_completion_chunks() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Par", "is."]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "prompt": "Capital of France?", "stream": true});
+        let (status, _, body) = testing::post(&app, "/v1/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str(data).ok())
+            .filter(|chunk: &serde_json::Value| chunk.get("choices").is_some())
+            .collect();
+        assert!(!chunks.is_empty());
+        for chunk in &chunks {
+            assert_eq!(chunk["object"], "text_completion", "{}", chunk);
+            assert!(chunk["id"].as_str().unwrap().starts_with("cmpl-"));
+            assert!(chunk["choices"][0].get("delta").is_none());
+        }
+        let text: String = chunks.iter().filter_map(|chunk| chunk["choices"][0]["text"].as_str()).collect();
+        assert!(text.contains(REASONING.trim()) && text.ends_with("Paris."), "{}", text);
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+        assert_eq!(sse_frames(&body).last().unwrap().1, "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn legacy_completions_take_a_single_prompt() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        for (prompt, message) in [
+            (json!(["a", "b"]), "Only a single prompt is supported, got 2"),
+            (json!([1]), "prompt must be a string or an array of one string"),
+            (json!(null), "prompt must be a string or an array of one string"),
+        ] {
+            let request = json!({"model": "deepthink", "prompt": prompt});
+            let (status, _, body) = testing::post(&app, "/v1/completions", &[], request).await;
+            assert_eq!(status, 400, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["message"], message);
+        }
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn native_requests_without_a_target_use_the_configured_default_provider() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let request = json!({"messages": [{"role": "user", "content": "Capital of France?"}]});
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+
+        // 默认仍为 anthropic, 缺少其 token 时错误说明目标是默认选择的
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/", &headers, request.clone()).await;
+        assert_eq!(status, 400, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["param"], "X-Anthropic-API-Token");
+        assert_eq!(
+            body["error"]["message"],
+            "Missing required header: X-Anthropic-API-Token (target provider 'anthropic' was selected by default because X-Target-Model is absent; send X-Target-Model or set models.default_target_provider to use another provider)"
+        );
+
+        config.models.default_target_provider = TargetProvider::OpenAI;
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 1);
+    }
+
+    #[test]
+    fn explicit_targets_name_the_header_that_required_the_token() {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("X-Target-Model", HeaderValue::from_static("openai"));
+        let error = get_target_client(&headers, TargetProvider::Anthropic).unwrap_err();
+        assert_eq!(
+            error.to_error_response().1.error.message,
+            "Missing required header: X-OpenAI-API-Token (required by X-Target-Model 'openai')"
+        );
+
+        headers.insert("X-OpenAI-API-Token", HeaderValue::from_static("openai-token"));
+        assert_eq!(
+            get_target_client(&headers, TargetProvider::Anthropic).unwrap(),
+            ("openai".to_string(), "openai-token".to_string())
+        );
+    }
+
+    #[cfg(not(feature = "anthropic"))]
+    #[tokio::test]
+    async fn targets_left_out_of_the_build_are_rejected_before_the_reasoner_runs() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let request = json!({"messages": [{"role": "user", "content": "Capital of France?"}]});
+
+        // 显式指定与按默认选中的 anthropic 都在检查 token 之前被拒绝
+        for headers in [&[("X-Target-Model", "anthropic")][..], &[]] {
+            let mut headers = headers.to_vec();
+            headers.push(("X-DeepSeek-API-Token", "reasoner-token"));
+            let (status, _, body) = testing::post(&app, "/", &headers, request.clone()).await;
+            assert_eq!(status, 501, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["code"], "provider_not_compiled");
+            assert_eq!(body["error"]["param"], "anthropic");
+            assert_eq!(
+                body["error"]["message"],
+                "Target provider anthropic is not available in this build: rebuild deepthink with the `anthropic` cargo feature"
+            );
+        }
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn images_for_text_only_targets_are_rejected_or_degraded_for_both_phases() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "A cat."}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+        ]}]});
+
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 400, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "unsupported_capability");
+        assert_eq!(body["error"]["param"], "images");
+        assert!(body["error"]["message"].as_str().unwrap().starts_with("Target model 'qwen2.5:14b' does not support images"));
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+
+        config.models.degrade_images = true;
+        let (app, _) = testing::app(&config);
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(
+            headers[CONTENT_WARNING_HEADER],
+            "1 of 1 images replaced with text placeholders: qwen2.5:14b accepts no images"
+        );
+        for upstream_path in [REASONER_PATH, OPENAI_PATH] {
+            let sent = testing::received(&upstream, upstream_path).await[0]["messages"].to_string();
+            assert!(!sent.contains("cat.png"), "{} got the image: {}", upstream_path, sent);
+            assert!(sent.contains("image omitted"), "{}", sent);
+        }
+    }
+
+    /// Splits an SSE body into `(id, data)` frames.
+    fn sse_frames(body: &str) -> Vec<(Option<u64>, String)> {
+        body.split("\n\n")
+            .filter(|frame| !frame.trim().is_empty())
+            .map(|frame| {
+                let field = |name: &str| frame.lines().find_map(|line| line.strip_prefix(name)).map(str::to_string);
+                let index = field("id: ").and_then(|id| resume::parse_event_id(&id).map(|(_, index)| index));
+                (index, field("data: ").unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// Starts a resumable compat stream as `caller`, reads its first frame
+    /// and drops the connection, as a client losing connectivity would.
+    ///
+    /// Returns the stream id and the index of the frame that was received.
+    async fn start_and_drop_resumable_stream(app: &axum::Router, caller: &str) -> (String, u64) {
+        use futures::StreamExt;
+        use tower::ServiceExt;
+
+        let request = json!({"model": "deepthink", "stream": true, "resumable": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .header("Authorization", format!("Bearer {}", caller))
+            .body(axum::body::Body::from(request.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        drop(body);
+        let (index, data) = sse_frames(&received).remove(0);
+        let chunk: serde_json::Value = serde_json::from_str(&data).unwrap();
+        (chunk["id"].as_str().unwrap().to_string(), index.unwrap())
+    }
+
+    #[tokio::test]
+    async fn dropped_resumable_streams_are_replayed_from_the_missed_frame() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Hel", "lo", "!"]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let owner = [("Authorization", "Bearer sk-owner")];
+
+        let (id, first) = start_and_drop_resumable_stream(&app, "sk-owner").await;
+        assert_eq!(first, 0);
+        let (status, body) = testing::get(&app, &format!("/v1/streams/{}?from=1", id), &owner).await;
+        assert_eq!(status, 200, "{}", body);
+        let frames = sse_frames(&body);
+        let indexes: Vec<u64> = frames.iter().filter_map(|(index, _)| *index).collect();
+        assert_eq!(indexes, (1..=indexes.len() as u64).collect::<Vec<_>>());
+        assert!(streamed_content(&body).ends_with("Hello!"), "{}", body);
+
+        // 只有发起请求的调用方能重放或删除该流
+        for other in [("Authorization", "Bearer sk-other"), ("X-DeepSeek-API-Token", "sk-other")] {
+            let (status, body) = testing::get(&app, &format!("/v1/streams/{}", id), &[other]).await;
+            assert_eq!(status, 404, "{}", body);
+        }
+        let (status, _) = testing::get(&app, &format!("/v1/streams/{}", id), &[]).await;
+        assert_eq!(status, 400);
+        let delete = |caller: &'static str| {
+            let app = app.clone();
+            let uri = format!("/v1/streams/{}", id);
+            async move {
+                use tower::ServiceExt;
+                let request = axum::http::Request::delete(uri).header("Authorization", caller);
+                app.oneshot(request.body(axum::body::Body::empty()).unwrap()).await.unwrap().status()
+            }
+        };
+        assert_eq!(delete("Bearer sk-other").await, 404);
+        assert_eq!(delete("Bearer sk-owner").await, 204);
+        let (status, _) = testing::get(&app, &format!("/v1/streams/{}", id), &owner).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn streams_are_only_buffered_when_resumable() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Hi"]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let caller = [("Authorization", "Bearer sk-owner")];
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &caller, request).await;
+        assert_eq!(status, 200);
+        // 帧仍带序号 id, 但不指向可恢复的流
+        let ids: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("id: ")).collect();
+        assert!(!ids.is_empty() && ids.iter().all(|id| resume::parse_event_id(id).unwrap().0.is_none()));
+        let chunk: serde_json::Value = serde_json::from_str(&sse_frames(&body)[0].1).unwrap();
+        let (status, _) = testing::get(&app, &format!("/v1/streams/{}", chunk["id"].as_str().unwrap()), &caller).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_last_event_id_are_replayed_for_their_owner_only() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Hel", "lo", "!"]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let (id, first) = start_and_drop_resumable_stream(&app, "sk-owner").await;
+        let last_event_id = format!("{}:{}", id, first);
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+
+        let headers = [("Authorization", "Bearer sk-owner"), ("Last-Event-ID", last_event_id.as_str())];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let indexes: Vec<u64> = sse_frames(&body).iter().filter_map(|(index, _)| *index).collect();
+        assert_eq!(indexes.first(), Some(&(first + 1)));
+        assert!(streamed_content(&body).ends_with("Hello!"), "{}", body);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+
+        // 重放同样需要认证, 且只属于创建流的调用方
+        let headers = [("Authorization", "Bearer sk-other"), ("Last-Event-ID", last_event_id.as_str())];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request.clone()).await;
+        assert_eq!(status, 404, "{}", body);
+        let headers = [("Last-Event-ID", last_event_id.as_str())];
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &headers, request.clone()).await;
+        assert_eq!(status, 400);
+        let headers = [("Authorization", "Bearer sk-owner"), ("Last-Event-ID", "3")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 409);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "stream_resumption_disabled");
+    }
+
+    #[tokio::test]
+    async fn streams_send_a_retry_hint_and_heartbeats_while_idle() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(testing::reasoner_stream(REASONING), "text/event-stream")
+                    .set_delay(Duration::from_millis(1500)),
+            )
+            .mount(&upstream)
+            .await;
+        testing::mock_streaming_openai(&upstream, &["Hi"]).await;
+        let mut config = testing::config(&upstream);
+        config.streaming.heartbeat_secs = 1;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200);
+        let frames: Vec<&str> = body.split("\n\n").collect();
+        assert!(frames[0].lines().all(|line| line.starts_with(':')), "{}", body);
+        assert_eq!(frames.iter().filter(|frame| frame.lines().any(|line| line == "retry:3000")).count(), 1, "{}", body);
+
+        config.streaming.retry_ms = 0;
+        config.streaming.heartbeat_secs = 0;
+        let (app, _) = testing::app(&config);
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(!body.contains("retry:") && !body.lines().any(|line| line.starts_with(':')), "{}", body);
+    }
+
+    async fn mock_openai_answer(upstream: &MockServer) {
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(upstream)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn compat_requests_choose_their_reasoner_through_the_vendor_extension() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "deepthink": {"reasoner_model": "deepseek-r1:32b"}, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[REASONER_MODEL_HEADER], "deepseek-r1:32b");
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await[0]["model"], "deepseek-r1:32b");
+        assert!(!testing::received(&upstream, OPENAI_PATH).await[0].as_object().unwrap().contains_key("deepthink"));
+
+        // 未覆盖时使用映射的推理模型, 且不返回该头
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, _) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert!(!headers.contains_key(REASONER_MODEL_HEADER));
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await[1]["model"], "deepseek-r1:14b");
+    }
+
+    #[tokio::test]
+    async fn compat_requests_forward_only_the_allowlisted_headers() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.compat.forward_request_headers =
+            serde_json::from_value(json!(["x-helicone-*", {"pattern": "x-portkey-*", "to": "target"}])).unwrap();
+        let (app, _) = testing::app(&config);
+
+        let headers = [("X-Helicone-Auth", "helicone-key"), ("X-Portkey-Trace-Id", "trace-1"), ("X-Gateway-Secret", "secret")];
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let requests = upstream.received_requests().await.unwrap();
+        let forwarded = |path: &str| {
+            let request = requests.iter().find(|request| request.url.path() == path).unwrap();
+            let mut names: Vec<&str> = ["x-helicone-auth", "x-portkey-trace-id", "x-gateway-secret"]
+                .into_iter()
+                .filter(|name| request.headers.contains_key(*name))
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(forwarded(REASONER_PATH), ["x-helicone-auth"]);
+        assert_eq!(forwarded(OPENAI_PATH), ["x-helicone-auth", "x-portkey-trace-id"]);
+        let target = requests.iter().find(|request| request.url.path() == OPENAI_PATH).unwrap();
+        assert_eq!(target.headers["x-helicone-auth"], "helicone-key");
+        assert_eq!(target.headers["x-portkey-trace-id"], "trace-1");
+    }
+
+    #[tokio::test]
+    async fn reasoner_models_outside_the_allow_list_are_rejected() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.models.allowed_reasoner_models = Some(vec!["deepseek-r1:32b".to_string()]);
+        let (app, _) = testing::app(&config);
+        let native = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+        let messages = json!([{"role": "user", "content": "Hi"}]);
+
+        let rejected = [
+            ("/v1/chat/completions", &[][..], json!({"model": "deepthink", "deepthink": {"reasoner_model": "r1-huge"}, "messages": messages})),
+            ("/", &native[..], json!({"reasoner_model": "r1-huge", "messages": messages})),
+            // 直接写在推理请求体中的模型同样要在列表中
+            ("/", &native[..], json!({"deepseek_config": {"body": {"model": "r1-huge"}}, "messages": messages})),
+        ];
+        for (uri, headers, request) in rejected {
+            let (status, _, body) = testing::post(&app, uri, headers, request).await;
+            assert_eq!(status, 400, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["code"], "reasoner_model_not_allowed");
+            assert_eq!(body["error"]["message"], "Reasoner model 'r1-huge' is not allowed on this server; allowed: deepseek-r1:32b");
+        }
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+
+        let allowed = [
+            json!({"reasoner_model": "deepseek-r1:32b", "messages": messages}),
+            // 配置中的推理模型不受列表限制
+            json!({"deepseek_config": {"body": {"model": config.models.default_deepseek}}, "messages": messages}),
+        ];
+        let mut reported = Vec::new();
+        for request in allowed {
+            let (status, _, body) = testing::post(&app, "/", &native, request).await;
+            assert_eq!(status, 200, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            reported.push(body.get("reasoner_model").cloned());
+        }
+        assert_eq!(reported, vec![Some(json!("deepseek-r1:32b")), None]);
+        let models: Vec<_> = testing::received(&upstream, REASONER_PATH).await.into_iter().map(|call| call["model"].clone()).collect();
+        assert_eq!(models, vec![json!("deepseek-r1:32b"), json!(config.models.default_deepseek)]);
+    }
+
+    /// Returns the error frame of a failed stream.
+    fn error_frame(body: &str) -> serde_json::Value {
+        let frames: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+        assert_eq!(frames.last(), Some(&"[DONE]"), "{}", body);
+        frames
+            .iter()
+            .filter_map(|frame| serde_json::from_str::<serde_json::Value>(frame).ok())
+            .find(|frame| frame.get("error").is_some())
+            .unwrap_or_else(|| panic!("no error frame in {}", body))
+    }
+
+    /// Starts an app whose reasoner takes a minute to answer, with streams
+    /// aborted after `max_stream_secs`.
+    async fn stuck_reasoner_app(upstream: &MockServer, max_stream_secs: u64) -> (axum::Router, Arc<AppState>) {
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(testing::reasoner_stream(REASONING), "text/event-stream")
+                    .set_delay(Duration::from_secs(60)),
+            )
+            .mount(upstream)
+            .await;
+        let mut config = testing::config(upstream);
+        config.streaming.max_stream_secs = max_stream_secs;
+        testing::app(&config)
+    }
+
+    #[tokio::test]
+    async fn streams_past_their_deadline_are_aborted_with_an_error_frame() {
+        let upstream = MockServer::start().await;
+        let (app, state) = stuck_reasoner_app(&upstream, 1).await;
+
+        let started = std::time::Instant::now();
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "reasoning");
+        assert_eq!(error["provider"], "deepseek");
+        assert_eq!(error["partial"], false);
+        assert_eq!(error["error"]["type"], "reasoning_stream_deadline");
+        assert_eq!(error["error"]["code"], "stream_deadline");
+        assert_eq!(
+            error["error"]["message"],
+            "Stream did not finish within streaming.max_stream_secs = 1 seconds and was aborted"
+        );
+        assert!(state.watchdog.active().is_empty());
+        assert_eq!(state.metrics.snapshot().aborted_streams, 1);
+    }
+
+    #[tokio::test]
+    async fn operators_list_and_kill_running_streams() {
+        let upstream = MockServer::start().await;
+        let (app, state) = stuck_reasoner_app(&upstream, 0).await;
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let stream = tokio::spawn({
+            let app = app.clone();
+            async move { testing::post(&app, "/v1/chat/completions", &[], request).await }
+        });
+        let active = loop {
+            let (_, body) = testing::get(&app, "/admin/streams", &[]).await;
+            let active: serde_json::Value = serde_json::from_str(&body).unwrap();
+            if !active.as_array().unwrap().is_empty() {
+                break active;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(active[0]["phase"], "reasoning");
+        let id = active[0]["id"].as_str().unwrap().to_string();
+        assert!(id.starts_with("chatcmpl-"), "{}", id);
+
+        let kill = |id: String| {
+            let app = app.clone();
+            async move {
+                use tower::ServiceExt;
+                let request = axum::http::Request::delete(format!("/admin/streams/{}", id)).body(axum::body::Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+        assert_eq!(kill(id.clone()).await, 202);
+        let (status, _, body) = stream.await.unwrap();
+        assert_eq!(status, 200);
+        let error = error_frame(&body);
+        assert_eq!(error["error"]["type"], "reasoning_stream_killed");
+        assert_eq!(error["error"]["code"], "stream_killed");
+        assert_eq!(error["error"]["message"], format!("Stream '{}' was aborted by an operator", id));
+
+        // 已结束的流不能再次终止
+        assert_eq!(kill(id).await, 404);
+        assert!(state.watchdog.active().is_empty());
+        assert_eq!(state.metrics.snapshot().aborted_streams, 1);
+    }
+
+    #[tokio::test]
+    async fn reasoner_failures_are_reported_as_the_reasoning_phase() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(503).set_body_string("overloaded"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "reasoning");
+        assert_eq!(error["provider"], "deepseek");
+        assert_eq!(error["upstream_status"], 503);
+        assert_eq!(error["partial"], false);
+        assert_eq!(error["code"], 502);
+        assert_eq!(error["error"]["type"], "reasoning_upstream_error");
+        assert_eq!(error["error"]["code"], "upstream_503");
+        assert_eq!(error["error"]["message"], "deepseek returned HTTP 503: overloaded");
+        assert!(testing::received(&upstream, OPENAI_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reasoner_account_errors_keep_their_status() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(402).set_body_json(json!({
+                "error": {"message": "Insufficient Balance", "type": "unknown_error", "param": null, "code": "invalid_request_error"}
+            })))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let fingerprint = crate::webhooks::fingerprint(&Config::default().auth.default_tokens.deepseek_token);
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 402, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "insufficient_credit");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains(&fingerprint) && message.ends_with("Insufficient Balance"), "{}", message);
+
+        // 流式请求以同样的错误帧结束
+        let mut request = request;
+        request["stream"] = json!(true);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "reasoning");
+        assert_eq!(error["upstream_status"], 402);
+        assert_eq!(error["code"], 402);
+        assert_eq!(error["error"]["code"], "insufficient_credit");
+
+        let (_, metrics) = testing::get(&app, "/metrics", &[]).await;
+        let metrics: serde_json::Value = serde_json::from_str(&metrics).unwrap();
+        let deepseek = metrics["upstream_account_errors"].as_array().unwrap().iter().find(|stats| stats["provider"] == "deepseek").unwrap();
+        assert!(deepseek["insufficient_credit"].as_u64().unwrap() >= 2, "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn target_failures_are_reported_as_the_answering_phase() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(429).set_body_string("slow down"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "answering");
+        assert_eq!(error["provider"], "openai");
+        assert_eq!(error["upstream_status"], 429);
+        // 推理已经发送给客户端
+        assert_eq!(error["partial"], true);
+        // 429 按限流错误报告
+        assert_eq!(error["error"]["type"], "answering_rate_limit_error");
+        assert_eq!(error["error"]["code"], "upstream_rate_limited");
+    }
+
+    #[tokio::test]
+    async fn connection_failures_name_the_provider_in_the_error_code() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        // 推理端口无人监听, 连接失败, 没有上游状态码
+        config.endpoints.deepseek = "http://127.0.0.1:1/v1/chat/completions".to_string();
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "reasoning");
+        assert!(error.get("upstream_status").is_none(), "{}", error);
+        assert_eq!(error["partial"], false);
+        assert!(error["error"]["type"].as_str().unwrap().starts_with("reasoning_"), "{}", error);
+        assert_eq!(error["error"]["code"], "deepseek_error", "{}", error);
+    }
+
+    /// How one scripted reasoning phase ended, and the chunk content the
+    /// client saw once the task closed the thinking block.
+    struct ReasoningRun {
+        outcome: Result<Option<StreamedReasoning>>,
+        thinking_open: bool,
+        content: String,
+    }
+
+    /// Runs the reasoning phase of a stream against the reasoner at `url`,
+    /// then closes the thinking block the way the stream task does.
+    async fn run_reasoning_phase(url: String, deadline: Option<Instant>) -> ReasoningRun {
+        use axum::response::IntoResponse;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let mut sink = EventSink::new(tx, crate::config::StreamOverflowPolicy::Block, Arc::new(Metrics::default()));
+        let header = ChunkHeader { id: "chatcmpl-1".to_string(), created: 0, model: json!("deepseek-r1:14b"), format: StreamFormat::default(), thinking: ThinkingFormat::default() };
+        let mut thinking_open = false;
+        let mut timer = PhaseTimer::start();
+        let (_, state) = testing::app(&crate::config::Config::default());
+        let now = chrono::Utc::now();
+        let usage = StreamUsage {
+            state,
+            caller: String::new(),
+            event: UsageEvent::rejected(None, "", None, true, 200, now, now),
+            failure: Mutex::new(None),
+            reported: AtomicBool::new(false),
+        };
+        let mut phase = ReasoningPhase {
+            header: &header,
+            thinking_open: &mut thinking_open,
+            throttle: &mut None,
+            reasoner_answer: ReasonerAnswerMode::default(),
+            timer: &mut timer,
+            deadline,
+            include_reasoning: true,
+            redactor: None,
+            usage: &usage,
+            use_reasoner_answer: UseReasonerAnswer::Never,
+            confident_answer_min_chars: 0,
+        };
+        let client = DeepSeekClient::new_with_base_url("token".to_string(), url);
+        let messages = vec![Message { role: Role::User, content: "Hi".into(), tool_calls: None, tool_call_id: None }];
+        let outcome = stream_reasoning(&client, messages, &ApiConfig::default(), &mut sink, &mut phase).await;
+        if outcome.is_ok() {
+            assert!(close_thinking(&mut sink, &header, thinking_open).await);
+        }
+        drop(sink);
+        let response = axum::response::sse::Sse::new(ReceiverStream::new(rx)).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let content = streamed_content(std::str::from_utf8(&body).unwrap());
+        ReasoningRun { outcome, thinking_open, content }
+    }
+
+    async fn scripted_reasoner(upstream: &MockServer, response: ResponseTemplate) -> String {
+        Mock::given(method("POST")).and(path(REASONER_PATH)).respond_with(response).mount(upstream).await;
+        format!("{}{}", upstream.uri(), REASONER_PATH)
+    }
+
+    #[tokio::test]
+    async fn reasoners_failing_immediately_open_no_thinking_block() {
+        let upstream = MockServer::start().await;
+        let url = scripted_reasoner(&upstream, ResponseTemplate::new(500).set_body_string("boom")).await;
+        let run = run_reasoning_phase(url, None).await;
+        assert!(run.outcome.is_err());
+        assert!(!run.thinking_open);
+        assert_eq!(run.content, "");
+    }
+
+    #[tokio::test]
+    async fn empty_reasoner_streams_open_no_thinking_block() {
+        let upstream = MockServer::start().await;
+        let stream = testing::reasoner_stream("");
+        let url = scripted_reasoner(&upstream, ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream")).await;
+        let run = run_reasoning_phase(url, None).await;
+        let streamed = run.outcome.unwrap().unwrap();
+        assert_eq!(streamed.text, "");
+        assert!(!streamed.truncated);
+        assert!(!run.thinking_open);
+        assert_eq!(run.content, "");
+    }
+
+    #[tokio::test]
+    async fn complete_reasoner_streams_are_wrapped_in_one_thinking_block() {
+        let upstream = MockServer::start().await;
+        let stream = testing::reasoner_stream(REASONING);
+        let url = scripted_reasoner(&upstream, ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream")).await;
+        let run = run_reasoning_phase(url, None).await;
+        let streamed = run.outcome.unwrap().unwrap();
+        assert_eq!(streamed.text, REASONING);
+        assert!(run.thinking_open);
+        assert_eq!(run.content, format!("<thinking>\n{}\n</thinking>", REASONING));
+    }
+
+    #[tokio::test]
+    async fn truncated_reasoner_streams_still_close_their_thinking_block() {
+        let url = testing::mock_stalled_reasoner(REASONING).await;
+        let run = run_reasoning_phase(url, Some(Instant::now() + Duration::from_millis(200))).await;
+        let streamed = run.outcome.unwrap().unwrap();
+        let head = &REASONING[..REASONING.len() / 2];
+        assert!(streamed.truncated);
+        assert_eq!(streamed.text, head);
+        assert_eq!(run.content, format!("<thinking>\n{}\n</thinking>", head));
+    }
+
+    #[tokio::test]
+    async fn streams_failing_before_any_reasoning_send_only_the_error() {
+        let upstream = MockServer::start().await;
+        scripted_reasoner(&upstream, ResponseTemplate::new(500).set_body_string("boom")).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(!body.contains("thinking>"), "{}", body);
+        assert_eq!(error_frame(&body)["phase"], "reasoning");
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_error_types_keep_their_status_and_message() {
+        let cases = [
+            (403, "permission_error", "Your API key does not have permission to use the specified resource.", 403),
+            (400, "invalid_request_error", "messages: roles must alternate between \"user\" and \"assistant\"", 400),
+            (500, "api_error", "An unexpected error has occurred internal to Anthropic's systems.", 502),
+        ];
+        for (upstream_status, error_type, message, expected) in cases {
+            let upstream = MockServer::start().await;
+            testing::mock_reasoner(&upstream).await;
+            let error = json!({"type": "error", "error": {"type": error_type, "message": message}});
+            Mock::given(method("POST"))
+                .and(path(testing::ANTHROPIC_PATH))
+                .respond_with(ResponseTemplate::new(upstream_status).set_body_json(error))
+                .mount(&upstream)
+                .await;
+            let config = testing::config(&upstream);
+            let (app, _) = testing::app(&config);
+            let headers = [
+                ("X-DeepSeek-API-Token", "reasoner-token"),
+                ("X-Anthropic-API-Token", "anthropic-token"),
+                ("X-Target-Model", "anthropic"),
+                (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+                (ANTHROPIC_ENDPOINT_URL_HEADER, config.endpoints.anthropic.as_str()),
+            ];
+
+            let request = json!({"messages": [{"role": "user", "content": "Hi"}]});
+            let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+            assert_eq!(status.as_u16(), expected, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["type"], format!("anthropic_{}", error_type));
+            assert_eq!(body["error"]["message"], format!("Anthropic API Error: {}", message));
+        }
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_error_events_end_the_stream_with_their_type() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let events = [
+            ("message_start", json!({"type": "message_start", "message": {"id": "msg_1", "type": "message", "role": "assistant", "model": "claude-test", "content": [], "stop_reason": null, "stop_sequence": null, "usage": {"input_tokens": 30, "output_tokens": 1}}})),
+            ("error", json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}})),
+        ];
+        let stream: String = events.iter().map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data)).collect();
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Anthropic-API-Token", "anthropic-token"),
+            ("X-Target-Model", "anthropic"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (ANTHROPIC_ENDPOINT_URL_HEADER, config.endpoints.anthropic.as_str()),
+        ];
+
+        let request = json!({"stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200);
+        let error = error_frame(&body);
+        assert_eq!(error["code"], 503);
+        assert_eq!(error["phase"], "answering");
+        assert_eq!(error["provider"], "anthropic");
+        assert_eq!(error["error"]["type"], "answering_anthropic_overloaded_error");
+        assert_eq!(error["error"]["message"], "Anthropic API Error: Overloaded");
+    }
+
+    #[tokio::test]
+    async fn openai_content_filter_finish_reasons_are_passed_through() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "I can't help with that."}), "content_filter");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["finish_reason"], "content_filter");
+    }
+
+    #[tokio::test]
+    async fn compat_parameters_outside_the_policy_are_dropped_in_lenient_mode() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.parameter_policy.mode = crate::config::ParameterPolicyMode::Lenient;
+        config.parameter_policy.target.allow = Some(vec!["temperature".to_string()]);
+        let (app, _) = testing::app(&config);
+
+        let tools = json!([{"type": "function", "function": {"name": "lookup", "parameters": {"type": "object"}}}]);
+        let request = json!({"model": "deepthink", "temperature": 0.2, "tools": tools, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[PARAMETER_WARNING_HEADER], "dropped parameters not allowed by policy: target.tools");
+        let target = &testing::received(&upstream, OPENAI_PATH).await[0];
+        assert_eq!(target["temperature"], 0.2);
+        assert!(target.get("tools").is_none(), "{}", target);
+        // 映射固定的模型不受允许列表限制
+        assert_eq!(target["model"], "qwen2.5:14b");
+
+        // 没有被丢弃的参数时不返回该头
+        let request = json!({"model": "deepthink", "temperature": 0.2, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, headers, _) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(!headers.contains_key(PARAMETER_WARNING_HEADER));
+    }
+
+    #[tokio::test]
+    async fn native_parameters_outside_the_policy_are_rejected_in_strict_mode() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        config.parameter_policy.reasoner.deny = vec!["model".to_string()];
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+
+        let request = json!({"deepseek_config": {"body": {"model": "r1-huge"}}, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 400, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "parameter_not_allowed");
+        assert_eq!(body["error"]["message"], "Parameter 'model' may not be set for the reasoner phase on this server");
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    /// Returns the target providers of [`answer_empty_target`] compiled
+    /// into this build.
+    fn compiled_providers() -> impl Iterator<Item = &'static str> {
+        ["openai", "anthropic"].into_iter().filter(|name| TargetProvider::from_name(name).unwrap().is_compiled())
+    }
+
+    /// Answers one compat request whose target first returns an empty
+    /// answer and then, when called again, a full one.
+    ///
+    /// Returns the status, headers and body of the response and the bodies
+    /// the target received.
+    async fn answer_empty_target(
+        provider: &str,
+        policy: EmptyAnswerPolicy,
+    ) -> (u16, axum::http::HeaderMap, serde_json::Value, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let (target_path, mut empty, mut full) = match provider {
+            "openai" => (
+                OPENAI_PATH,
+                testing::openai_completion(json!({"role": "assistant", "content": ""}), "length"),
+                testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop"),
+            ),
+            _ => (
+                testing::ANTHROPIC_PATH,
+                testing::anthropic_message(json!([]), "max_tokens"),
+                testing::anthropic_message(json!([{"type": "text", "text": "Paris."}]), "end_turn"),
+            ),
+        };
+        for message in [&mut empty, &mut full].into_iter().filter(|message| message["type"] == "message") {
+            message["usage"]["cache_creation_input_tokens"] = json!(0);
+            message["usage"]["cache_read_input_tokens"] = json!(0);
+        }
+        Mock::given(method("POST"))
+            .and(path(target_path))
+            .respond_with(ResponseTemplate::new(200).set_body_json(empty))
+            .up_to_n_times(1)
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(target_path))
+            .respond_with(ResponseTemplate::new(200).set_body_json(full))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.target.empty_answer_policy = policy;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "max_tokens": 200,
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, headers, body) =
+            testing::post(&app, "/v1/chat/completions", &[("X-Target-Model", provider)], request).await;
+        let body = serde_json::from_str(&body).unwrap_or_default();
+        (status.as_u16(), headers, body, testing::received(&upstream, target_path).await)
+    }
+
+    #[tokio::test]
+    async fn empty_answers_fail_under_the_error_policy() {
+        for provider in compiled_providers() {
+            let (status, _, body, calls) = answer_empty_target(provider, EmptyAnswerPolicy::Error).await;
+            assert_eq!(status, 502, "{}: {}", provider, body);
+            assert_eq!(body["error"]["type"], "target_empty_response");
+            assert_eq!(body["error"]["code"], "length", "{}", provider);
+            assert_eq!(calls.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_answers_are_retried_once_with_more_max_tokens() {
+        for provider in compiled_providers() {
+            let (status, _, body, calls) = answer_empty_target(provider, EmptyAnswerPolicy::RetryOnce).await;
+            assert_eq!(status, 200, "{}: {}", provider, body);
+            let content = body["choices"][0]["message"]["content"].as_str().unwrap();
+            assert!(content.ends_with("Paris."), "{}: {}", provider, content);
+            assert_eq!(calls.len(), 2);
+            let max_tokens = |call: &serde_json::Value| call["max_tokens"].as_u64().unwrap();
+            assert_eq!((max_tokens(&calls[0]), max_tokens(&calls[1])), (200, 328), "{}", provider);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_answers_pass_with_a_warning() {
+        for provider in compiled_providers() {
+            let (status, headers, body, calls) = answer_empty_target(provider, EmptyAnswerPolicy::Pass).await;
+            assert_eq!(status, 200, "{}: {}", provider, body);
+            assert_eq!(headers[EMPTY_ANSWER_WARNING_HEADER], "target returned an empty answer", "{}", provider);
+            let warnings = body["warnings"].as_array().unwrap();
+            assert_eq!(warnings.iter().filter(|warning| warning["code"] == "empty_answer").count(), 1, "{}", provider);
+            assert_eq!(body["choices"][0]["finish_reason"], "length", "{}", provider);
+            assert_eq!(calls.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_that_convert_to_no_content_follow_the_empty_answer_policy() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                json!({"role": "assistant", "content": " \n "}),
+                "stop",
+            )))
+            .mount(&upstream)
+            .await;
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        for policy in [EmptyAnswerPolicy::Error, EmptyAnswerPolicy::RetryOnce, EmptyAnswerPolicy::Pass] {
+            let mut config = testing::config(&upstream);
+            config.target.empty_answer_policy = policy;
+            let (app, _) = testing::app(&config);
+            let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            if policy == EmptyAnswerPolicy::Pass {
+                assert_eq!(status, 200, "{}", body);
+                assert!(headers.contains_key(EMPTY_ANSWER_WARNING_HEADER));
+                assert_eq!(body["choices"][0]["finish_reason"], "error", "{}", body);
+                assert!(body["warnings"].as_array().unwrap().iter().any(|warning| warning["code"] == "empty_answer"));
+            } else {
+                assert_eq!(status, 502, "{:?}: {}", policy, body);
+                assert_eq!(body["error"]["type"], "target_empty_response");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_streamed_answers_end_with_an_error_frame_after_the_reasoning() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &[]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert!(streamed_content(&body).ends_with("</thinking>"), "{}", body);
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "answering");
+        assert_eq!(error["error"]["type"], "answering_target_empty_response");
+    }
+
+    #[tokio::test]
+    async fn open_reasoner_circuits_fail_fast_and_show_in_health() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.deepseek = "http://127.0.0.1:1/v1/chat/completions".to_string();
+        config.circuit_breaker.failure_threshold = 2;
+        let (app, _) = testing::app(&config);
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+
+        for _ in 0..2 {
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST, "{}", body);
+            assert!(body.contains("deepseek_connection_failed"), "{}", body);
+        }
+        let (_, health) = testing::get(&app, "/health", &[]).await;
+        let health: serde_json::Value = serde_json::from_str(&health).unwrap();
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["circuits"][0]["provider"], "deepseek");
+        assert_eq!(health["circuits"][0]["base_url"], config.endpoints.deepseek);
+        assert_eq!(health["circuits"][0]["state"], "open");
+
+        // 熔断期间不再连接推理模型, 直接返回 503
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "circuit_open");
+        assert_eq!(body["error"]["param"], "deepseek");
+        assert!(body["error"]["message"].as_str().unwrap().contains("127.0.0.1:1"), "{}", body);
+
+        let stream_request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], stream_request).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_reasoners_back_off_for_the_advertised_delay() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(429).insert_header("x-ratelimit-reset-tokens", "29.2s").set_body_string("slow down"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, axum::http::StatusCode::TOO_MANY_REQUESTS, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "upstream_rate_limited");
+        assert!(body["error"]["message"].as_str().unwrap().contains("retry in 30s"), "{}", body);
+
+        // 一次限流即熔断, 等待期间不再请求推理模型
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn exhausted_upstream_windows_back_off_before_the_next_429() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop"))
+                    .insert_header("x-ratelimit-limit-requests", "100")
+                    .insert_header("x-ratelimit-remaining-requests", "0")
+                    .insert_header("x-ratelimit-reset-requests", "20s")
+                    .insert_header("x-ratelimit-remaining-tokens", "5000"),
+            )
+            .mount(&upstream)
+            .await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        let (_, metrics) = testing::get(&app, "/metrics", &[]).await;
+        let metrics: serde_json::Value = serde_json::from_str(&metrics).unwrap();
+        let gauge = &metrics["upstream_rate_limits"][0];
+        assert_eq!((gauge["provider"].as_str(), gauge["base_url"].as_str()), (Some("openai"), Some(config.endpoints.openai.as_str())));
+        assert_eq!((gauge["limit_requests"].as_u64(), gauge["remaining_requests"].as_u64()), (Some(100), Some(0)));
+        assert_eq!(gauge["remaining_tokens"], 5000);
+        assert!(gauge["reset_requests_secs"].as_f64().is_some_and(|secs| secs > 19.0 && secs <= 20.0));
+
+        // 窗口用尽后熔断到重置时间, 不再把请求发给上游
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+        assert!(headers["retry-after"].to_str().unwrap().parse::<u64>().is_ok_and(|secs| (19..=20).contains(&secs)));
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn open_reasoner_circuits_can_answer_without_reasoning() {
+        let upstream = MockServer::start().await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.deepseek = "http://127.0.0.1:1/v1/chat/completions".to_string();
+        config.circuit_breaker.failure_threshold = 1;
+        config.circuit_breaker.reasoner_open = crate::config::ReasonerCircuitAction::SkipReasoning;
+        let (app, _) = testing::app(&config);
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, axum::http::StatusCode::OK, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hi");
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rendered_transcripts_reach_only_the_reasoner() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+            "reasoner_transcript": "rendered",
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        let (app, _) = testing::app(&config);
+
+        let messages = json!([
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1", "type": "function",
+                "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "18°C"}
+        ]);
+        let tools = json!([{"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object", "properties": {"city": {}}}}}]);
+        let request = json!({"model": "deepthink", "messages": messages, "tools": tools});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+
+        let reasoner_call = &testing::received(&upstream, REASONER_PATH).await[0];
+        let reasoner_messages = reasoner_call["messages"].as_array().unwrap();
+        // 推理提示词之后只剩一条对话记录
+        assert_eq!(reasoner_messages.len(), 2, "{}", reasoner_call);
+        assert_eq!(reasoner_messages[0]["role"], "system");
+        assert_eq!(reasoner_messages[1]["role"], "user");
+        let transcript = reasoner_messages[1]["content"].as_str().unwrap();
+        assert!(transcript.starts_with("Available tools:\n- get_weather(city)"), "{}", transcript);
+        assert!(transcript.contains("Tool get_weather returned: 18°C"), "{}", transcript);
+        assert!(reasoner_call.get("tools").is_none());
+
+        // 目标模型仍收到结构化的工具调用
+        let target_call = &testing::received(&upstream, OPENAI_PATH).await[0];
+        let target_messages = target_call["messages"].as_array().unwrap();
+        assert!(target_messages.iter().any(|m| m["role"] == "tool" && m["tool_call_id"] == "call_1"), "{}", target_call);
+        assert_eq!(target_call["tools"], tools);
+    }
+
+    #[test]
+    fn metadata_limits_are_enforced() {
+        let within: HashMap<String, String> = (0..MAX_METADATA_KEYS)
+            .map(|i| (format!("{}{:02}", "k".repeat(MAX_METADATA_KEY_CHARS - 2), i), "v".repeat(MAX_METADATA_VALUE_CHARS)))
+            .collect();
+        assert_eq!(within.len(), MAX_METADATA_KEYS);
+        assert!(check_metadata(&within).is_ok());
+
+        let mut too_many = within.clone();
+        too_many.insert("extra".to_string(), String::new());
+        let too_long_key = HashMap::from([("k".repeat(MAX_METADATA_KEY_CHARS + 1), String::new())]);
+        let too_long_value = HashMap::from([("team".to_string(), "é".repeat(MAX_METADATA_VALUE_CHARS + 1))]);
+        for metadata in [too_many, too_long_key, too_long_value] {
+            assert!(matches!(check_metadata(&metadata), Err(ApiError::BadRequest { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn request_metadata_is_echoed_in_responses_and_stream_metadata() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let streaming_upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&streaming_upstream).await;
+        testing::mock_streaming_openai(&streaming_upstream, &["Paris."]).await;
+        let (streaming_app, _) = testing::app(&testing::config(&streaming_upstream));
+        let metadata = json!({"team": "search", "feature": "summarize"});
+
+        let request = json!({"model": "deepthink", "metadata": metadata, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["metadata"], metadata);
+
+        let request = json!({"model": "deepthink", "stream": true, "metadata": metadata, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&streaming_app, "/v1/chat/completions", &[], request).await;
+        let frame = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let frame: serde_json::Value = serde_json::from_str(frame).unwrap();
+        assert_eq!(frame["metadata"], metadata);
+
+        // 没有元数据时不回显
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(!body.contains("\"metadata\""), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn invalid_request_metadata_is_rejected() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let oversized: serde_json::Map<String, serde_json::Value> =
+            (0..=MAX_METADATA_KEYS).map(|i| (format!("key{}", i), json!("v"))).collect();
+        for metadata in [json!(oversized), json!({"team": 5}), json!({"team": "x".repeat(MAX_METADATA_VALUE_CHARS + 1)})] {
+            let request = json!({"model": "deepthink", "metadata": metadata, "messages": [{"role": "user", "content": "Hi"}]});
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            assert_eq!(status, 400, "{}", body);
+            assert!(body.contains("metadata"), "{}", body);
+        }
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    /// Answers one compat request whose reasoner stops at its `max_tokens`
+    /// on the first call and finishes when retried with a larger limit.
+    ///
+    /// Returns the status, the response body, the reasoner calls and the
+    /// target calls.
+    async fn answer_truncated_reasoning(
+        policy: &str,
+        stream: bool,
+    ) -> (u16, String, Vec<serde_json::Value>, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        let (truncated, finished) = if stream {
+            let finished = testing::reasoner_stream("Finished reasoning.");
+            let truncated = testing::reasoner_stream(REASONING).replace(r#""finish_reason":"stop""#, r#""finish_reason":"length""#);
+            (
+                ResponseTemplate::new(200).set_body_raw(truncated, "text/event-stream"),
+                ResponseTemplate::new(200).set_body_raw(finished, "text/event-stream"),
+            )
+        } else {
+            let mut truncated = testing::reasoner_completion(REASONING);
+            truncated["choices"][0]["finish_reason"] = json!("length");
+            (
+                ResponseTemplate::new(200).set_body_json(truncated),
+                ResponseTemplate::new(200).set_body_json(testing::reasoner_completion("Finished reasoning.")),
+            )
+        };
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .and(wiremock::matchers::body_partial_json(json!({"max_tokens": 3000})))
+            .respond_with(finished)
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST")).and(path(REASONER_PATH)).respond_with(truncated).mount(&upstream).await;
+        if stream {
+            testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        } else {
+            mock_openai_answer(&upstream).await;
+        }
+        let mut config = testing::config(&upstream);
+        config.reasoning.on_truncated_reasoning = serde_json::from_value(json!(policy)).unwrap();
+        config.reasoning.truncated_retry_multiplier = 1.5;
+        let (app, state) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "max_tokens": 2000,
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        let reasoner_calls = testing::received(&upstream, REASONER_PATH).await;
+        assert_eq!(state.metrics.snapshot().truncated_reasonings, 1);
+        (status.as_u16(), body, reasoner_calls, testing::received(&upstream, OPENAI_PATH).await)
+    }
+
+    #[tokio::test]
+    async fn truncated_reasoning_continues_with_a_marker() {
+        for stream in [false, true] {
+            let (status, body, reasoner_calls, target_calls) = answer_truncated_reasoning("continue", stream).await;
+            assert_eq!(status, 200, "{}", body);
+            assert_eq!(reasoner_calls.len(), 1);
+            let injected = target_calls[0]["messages"].to_string();
+            assert!(injected.contains(&format!("{}\\n[reasoning truncated]", REASONING)), "{}", injected);
+            if stream {
+                let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+                assert!(metadata.contains(r#""reasoning_truncated":true"#), "{}", metadata);
+            }
+        }
+
+        // 非 compat 路由的响应体带有 reasoning_truncated 标记
+        let upstream = MockServer::start().await;
+        let mut truncated = testing::reasoner_completion(REASONING);
+        truncated["choices"][0]["finish_reason"] = json!("length");
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(truncated))
+            .mount(&upstream)
+            .await;
+        mock_openai_answer(&upstream).await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "sk-reasoner"),
+            ("X-OpenAI-API-Token", "sk-target"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+        let request = json!({"messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (_, _, body) = testing::post(&app, "/", &headers, request).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["reasoning_truncated"], true, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn truncated_reasoning_is_retried_with_a_larger_limit() {
+        for stream in [false, true] {
+            let (status, body, reasoner_calls, target_calls) = answer_truncated_reasoning("retry_larger", stream).await;
+            assert_eq!(status, 200, "{}", body);
+            assert_eq!(reasoner_calls.len(), 2);
+            assert_eq!(reasoner_calls[0]["max_tokens"], 2000);
+            assert_eq!(reasoner_calls[1]["max_tokens"], 3000);
+            let injected = target_calls[0]["messages"].to_string();
+            assert!(injected.contains("Finished reasoning."), "{}", injected);
+            assert!(!injected.contains("[reasoning truncated]"), "{}", injected);
+            if stream {
+                // 流中已发送的截断推理后跟截断标记, 再接重试的推理
+                let content = streamed_content(&body);
+                assert!(content.contains(&format!("{}\n[reasoning truncated]\n\nFinished reasoning.", REASONING)), "{}", content);
+                assert!(!body.contains("reasoning_truncated"), "{}", body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_reasoning_fails_under_the_error_policy() {
+        let (status, body, _, target_calls) = answer_truncated_reasoning("error", false).await;
+        assert_eq!(status, 502);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "reasoning_truncated");
+        assert_eq!(body["error"]["code"], "length");
+        assert!(target_calls.is_empty());
+
+        let (_, body, _, target_calls) = answer_truncated_reasoning("error", true).await;
+        let error = error_frame(&body);
+        assert_eq!(error["phase"], "reasoning");
+        assert_eq!(error["error"]["type"], "reasoning_truncated", "{}", error);
+        assert!(target_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn profiles_serve_the_same_upstreams_with_their_own_defaults() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        config.profiles.insert(
+            "internal".to_string(),
+            serde_json::from_value(json!({"route_prefix": "/internal", "thinking_format": "reasoning_content"})).unwrap(),
+        );
+        config.profiles.insert(
+            "external".to_string(),
+            serde_json::from_value(json!({
+                "route_prefix": "/external/",
+                "include_reasoning": false,
+                "default_mapping": "deepthink",
+                "allowed_models": ["deepthink", "assistant"],
+                "requests_per_minute": 3,
+            }))
+            .unwrap(),
+        );
+        let (app, _) = testing::app(&config);
+        let request = |model: &str| json!({"model": model, "messages": [{"role": "user", "content": "Capital of France?"}]});
+
+        let (status, headers, body) = testing::post(&app, "/internal/v1/chat/completions", &[], request("deepthink")).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(headers.get("x-ratelimit-limit-requests").is_none());
+        let internal: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(internal["choices"][0]["message"]["reasoning_content"], REASONING);
+        assert_eq!(internal["choices"][0]["message"]["content"], "Hi");
+
+        let (status, headers, body) = testing::post(&app, "/external/v1/chat/completions", &[], request("deepthink")).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!((&headers["x-ratelimit-limit-requests"], &headers["x-ratelimit-remaining-requests"]), (&"3".parse().unwrap(), &"2".parse().unwrap()));
+        assert!(headers["x-ratelimit-reset-requests"].to_str().unwrap().ends_with('s'));
+        assert!(!body.contains(REASONING), "{}", body);
+        let external: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(external["choices"][0]["message"]["content"], "Hi");
+        // 不返回给客户端的推理仍然注入目标模型
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        assert!(target_calls[1]["messages"].to_string().contains(REASONING));
+
+        // 没有映射的模型使用 default_mapping 的推理模型
+        let (status, _, body) = testing::post(&app, "/external/v1/chat/completions", &[], request("assistant")).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await[2]["model"], "deepseek-r1:14b");
+
+        let (status, _, body) = testing::post(&app, "/external/v1/chat/completions", &[], request("gpt-4o")).await;
+        assert_eq!(status, 400, "{}", body);
+        assert!(body.contains("not available on this route"), "{}", body);
+
+        // 第四个请求超过每分钟 3 个的限制; 其他路由不受影响
+        let (status, headers, body) = testing::post(&app, "/external/v1/chat/completions", &[], request("deepthink")).await;
+        assert_eq!(status, 429, "{}", body);
+        assert!(body.contains("profile external"), "{}", body);
+        assert_eq!(headers["x-ratelimit-remaining-requests"], "0");
+        assert!(headers["retry-after"].to_str().unwrap().parse::<u64>().is_ok_and(|secs| (1..=60).contains(&secs)));
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], request("deepthink")).await;
+        assert_eq!(status, 200);
+    }
+
+    #[tokio::test]
+    async fn profiles_without_reasoning_stream_only_the_answer() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let mut config = testing::config(&upstream);
+        config.profiles.insert(
+            "external".to_string(),
+            serde_json::from_value(json!({"route_prefix": "/external", "include_reasoning": false})).unwrap(),
+        );
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert!(streamed_content(&body).contains(REASONING), "{}", body);
+        let (status, _, body) = testing::post(&app, "/external/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert_eq!(streamed_content(&body), "Paris.");
+    }
+
+    /// Serves compat requests with speculative reasoning enabled. The
+    /// speculative run reasons "Anticipated follow-ups." and a top-up run
+    /// "Top-up reasoning."; any other reasoner call returns [`REASONING`].
+    async fn speculative_app(upstream: &MockServer, config: &mut Config, reuse: &str) -> (axum::Router, Arc<AppState>) {
+        use wiremock::matchers::body_string_contains;
+
+        let reasoning = |text: &str| ResponseTemplate::new(200).set_body_json(testing::reasoner_completion(text));
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .and(body_string_contains(SPECULATION_INSTRUCTION))
+            .respond_with(reasoning("Anticipated follow-ups."))
+            .mount(upstream)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .and(body_string_contains(SPECULATION_TOP_UP_PREFIX))
+            .respond_with(reasoning("Top-up reasoning."))
+            .mount(upstream)
+            .await;
+        testing::mock_reasoner(upstream).await;
+        mock_openai_answer(upstream).await;
+        config.speculation.speculative_reasoning = true;
+        config.speculation.reuse = serde_json::from_value(json!(reuse)).unwrap();
+        testing::app(config)
+    }
+
+    /// Waits for the background speculative run of a conversation to be cached.
+    async fn speculation_cached(state: &AppState) {
+        for _ in 0..200 {
+            if state.speculation.stats().cached > 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("speculative reasoning was never cached");
+    }
+
+    fn conversation(turns: &[(&str, &str)]) -> serde_json::Value {
+        let messages: Vec<_> = turns.iter().map(|(role, content)| json!({"role": role, "content": content})).collect();
+        json!({"model": "deepthink", "conversation_id": "conv-1", "messages": messages})
+    }
+
+    /// A tool loop: the user's question, then a continuation turn carrying
+    /// the tool result unless `continued` is false.
+    fn tool_loop(conversation_id: Option<&str>, assistant: &str, continued: bool) -> serde_json::Value {
+        let mut messages = vec![json!({"role": "user", "content": "Weather in Paris?"})];
+        if continued {
+            let call = json!({"id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{}"}});
+            messages.push(json!({"role": "assistant", "content": assistant, "tool_calls": [call]}));
+            messages.push(json!({"role": "tool", "content": "Sunny", "tool_call_id": "call_1"}));
+        }
+        let mut request = json!({"model": "deepthink", "messages": messages});
+        if let Some(conversation_id) = conversation_id {
+            request["conversation_id"] = json!(conversation_id);
+        }
+        request
+    }
+
+    fn reuse_config(upstream: &MockServer, policy: ReasoningReuse) -> Config {
+        let mut config = testing::config(upstream);
+        config.reasoning.reasoning_reuse = policy;
+        config
+    }
+
+    #[tokio::test]
+    async fn tool_loop_continuations_reuse_the_stored_reasoning() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&reuse_config(&upstream, ReasoningReuse::ToolLoop));
+
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], tool_loop(Some("conv-1"), "", false)).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[REASONING_SOURCE_HEADER], "fresh");
+
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], tool_loop(Some("conv-1"), "", true)).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[REASONING_SOURCE_HEADER], "reused");
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+        let target = testing::received(&upstream, OPENAI_PATH).await.pop().unwrap();
+        assert!(target["messages"].to_string().contains(REASONING.trim()), "{}", target);
+
+        // 其他会话和 never 策略都重新推理
+        let (_, headers, _) = testing::post(&app, "/v1/chat/completions", &[], tool_loop(Some("conv-2"), "", true)).await;
+        assert_eq!(headers[REASONING_SOURCE_HEADER], "fresh");
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 2);
+        let (app, _) = testing::app(&reuse_config(&upstream, ReasoningReuse::Never));
+        let (_, headers, _) = testing::post(&app, "/v1/chat/completions", &[], tool_loop(Some("conv-1"), "", true)).await;
+        assert!(headers.get(REASONING_SOURCE_HEADER).is_none());
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn tool_loops_without_a_conversation_reuse_the_history_thinking_block() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Sunny."]).await;
+        let (app, _) = testing::app(&reuse_config(&upstream, ReasoningReuse::ToolLoop));
+
+        let mut request = tool_loop(None, "<think>Check the forecast.</think>", true);
+        request["stream"] = json!(true);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+        assert!(streamed_content(&body).contains("Check the forecast."), "{}", body);
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+        assert_eq!(metadata["reasoning_source"], "reused");
+    }
+
+    #[tokio::test]
+    async fn speculative_reasoning_is_reused_by_the_anticipated_turn() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        let (app, state) = speculative_app(&upstream, &mut config, "reuse").await;
+
+        let first = conversation(&[("user", "Capital of France?")]);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], first).await;
+        assert_eq!(status, 200, "{}", body);
+        speculation_cached(&state).await;
+        let speculative_call = &testing::received(&upstream, REASONER_PATH).await[1];
+        let speculative_messages = speculative_call["messages"].to_string();
+        assert!(speculative_messages.contains("Hi") && speculative_messages.contains(SPECULATION_INSTRUCTION));
+
+        let next = conversation(&[("user", "Capital of France?"), ("assistant", "Hi"), ("user", "And its population?")]);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], next).await;
+        assert_eq!(status, 200, "{}", body);
+        // 命中时不再调用推理模型, 目标模型收到推测推理
+        speculation_cached(&state).await;
+        let reasoner_calls = testing::received(&upstream, REASONER_PATH).await;
+        assert_eq!(reasoner_calls.len(), 3, "the second answer starts its own speculation");
+        assert!(reasoner_calls[2]["messages"].to_string().contains(SPECULATION_INSTRUCTION));
+        let target_call = &testing::received(&upstream, OPENAI_PATH).await[1];
+        assert!(target_call["messages"].to_string().contains("Anticipated follow-ups."), "{}", target_call);
+        let stats = state.speculation.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn speculative_reasoning_can_be_topped_up() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        let (app, state) = speculative_app(&upstream, &mut config, "top_up").await;
+
+        testing::post(&app, "/v1/chat/completions", &[], conversation(&[("user", "Capital of France?")])).await;
+        speculation_cached(&state).await;
+        let next = conversation(&[("user", "Capital of France?"), ("assistant", "Hi"), ("user", "And its population?")]);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], next).await;
+        assert_eq!(status, 200, "{}", body);
+
+        let top_up_call = &testing::received(&upstream, REASONER_PATH).await[2];
+        assert_eq!(top_up_call["max_tokens"], 1024);
+        let top_up_messages = top_up_call["messages"].as_array().unwrap();
+        assert_eq!(top_up_messages.last().unwrap()["content"], "And its population?");
+        assert!(top_up_call["messages"].to_string().contains("Anticipated follow-ups."));
+        let injected = testing::received(&upstream, OPENAI_PATH).await[1]["messages"].to_string();
+        assert!(injected.contains("Anticipated follow-ups.\\n\\nTop-up reasoning."), "{}", injected);
+    }
+
+    #[tokio::test]
+    async fn unanticipated_turns_miss_and_reason_from_scratch() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        let (app, state) = speculative_app(&upstream, &mut config, "reuse").await;
+
+        testing::post(&app, "/v1/chat/completions", &[], conversation(&[("user", "Capital of France?")])).await;
+        speculation_cached(&state).await;
+        // 用户改写了上一轮的问题
+        let edited = conversation(&[("user", "Capital of Spain?"), ("assistant", "Hi"), ("user", "And its population?")]);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], edited).await;
+        assert_eq!(status, 200, "{}", body);
+
+        let reasoner_calls = testing::received(&upstream, REASONER_PATH).await;
+        assert_eq!(reasoner_calls[2]["messages"].as_array().unwrap().last().unwrap()["content"], "And its population?");
+        let injected = testing::received(&upstream, OPENAI_PATH).await[1]["messages"].to_string();
+        assert!(injected.contains(REASONING) && !injected.contains("Anticipated"), "{}", injected);
+        let stats = state.speculation.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn speculation_stops_near_the_budget_and_is_charged_as_speculative() {
+        let upstream = MockServer::start().await;
+        let mut config = testing::config(&upstream);
+        let mut tokens = config.auth.default_tokens.clone();
+        tokens.daily_budget_usd = Some(1.0);
+        config.auth.token_mappings.insert("sk-metered".to_string(), tokens.clone());
+        config.auth.token_mappings.insert("sk-roomy".to_string(), TokenConfig { daily_budget_usd: Some(100.0), ..tokens });
+        // 每次推理调用 20 个 token, 花费 $0.20; 目标调用 $0.70
+        config.budget.pricing.insert(
+            "deepseek-r1:14b".to_string(),
+            crate::config::ModelPrice { prompt_usd_per_million: 10_000.0, completion_usd_per_million: 10_000.0, cached_prompt_usd_per_million: None },
+        );
+        config.budget.pricing.insert(
+            "gpt-4o".to_string(),
+            crate::config::ModelPrice { prompt_usd_per_million: 35_000.0, completion_usd_per_million: 35_000.0, cached_prompt_usd_per_million: None },
+        );
+        let (app, state) = speculative_app(&upstream, &mut config, "reuse").await;
+
+        // 第一次请求后已用掉 90% 的预算, 不再推测
+        let caller = [("Authorization", "Bearer sk-metered")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &caller, conversation(&[("user", "Capital of France?")])).await;
+        assert_eq!(status, 200, "{}", body);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(state.speculation.stats().runs, 0);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+
+        let caller = [("Authorization", "Bearer sk-roomy")];
+        testing::post(&app, "/v1/chat/completions", &caller, conversation(&[("user", "Capital of France?")])).await;
+        speculation_cached(&state).await;
+        assert_eq!(state.speculation.stats().runs, 1);
+        assert!((state.spend.speculative_total() - 0.2).abs() < 1e-9);
+    }
+
+    /// An Anthropic target that rejects system-role messages like strict API
+    /// versions do, and otherwise streams the answer "Paris.".
+    #[cfg(feature = "anthropic")]
+    struct StrictAnthropic;
+
+    #[cfg(feature = "anthropic")]
+    impl wiremock::Respond for StrictAnthropic {
+        fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            if body["messages"].as_array().unwrap().iter().any(|message| message["role"] == "system") {
+                let error = json!({"type": "error", "error": {"type": "invalid_request_error", "message": "messages: Unexpected role \"system\""}});
+                return ResponseTemplate::new(400).set_body_json(error);
+            }
+            let events = [
+                ("message_start", json!({"type": "message_start", "message": {"id": "msg_1", "type": "message", "role": "assistant", "model": "claude-test", "content": [], "stop_reason": null, "stop_sequence": null, "usage": {"input_tokens": 30, "output_tokens": 1}}})),
+                ("content_block_start", json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}})),
+                ("content_block_delta", json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Paris."}})),
+                ("content_block_stop", json!({"type": "content_block_stop", "index": 0})),
+                ("message_delta", json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}, "usage": {"output_tokens": 3}})),
+                ("message_stop", json!({"type": "message_stop"})),
+            ];
+            let stream: String = events.iter().map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data)).collect();
+            ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream")
+        }
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn streamed_anthropic_answers_send_system_messages_as_the_system_parameter() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        Mock::given(method("POST")).and(path(testing::ANTHROPIC_PATH)).respond_with(StrictAnthropic).mount(&upstream).await;
+        let config = testing::config(&upstream);
+        let (app, _) = testing::app(&config);
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-Anthropic-API-Token", "anthropic-token"),
+            ("X-Target-Model", "anthropic"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (ANTHROPIC_ENDPOINT_URL_HEADER, config.endpoints.anthropic.as_str()),
+        ];
+
+        let request = json!({
+            "stream": true,
+            "messages": [
+                {"role": "system", "content": "Answer in one word."},
+                {"role": "user", "content": "Capital of France?"}
+            ],
+        });
+        let (status, _, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200);
+        assert!(!body.contains("\"type\":\"error\""), "{}", body);
+        assert!(streamed_content(&body).ends_with("Paris."), "{}", body);
+
+        let target_call = &testing::received(&upstream, testing::ANTHROPIC_PATH).await[0];
+        assert!(target_call["system"].to_string().contains("Answer in one word."), "{}", target_call);
+        assert!(target_call["messages"].as_array().unwrap().iter().all(|message| message["role"] != "system"));
+    }
+
+    #[tokio::test]
+    async fn every_response_carries_the_build_version() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let (status, body) = testing::get(&app, "/version", &[]).await;
+        assert_eq!(status, 200);
+        let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["git_sha"], env!("DEEPTHINK_GIT_SHA"));
+        assert!(info["build_timestamp"].as_str().is_some_and(|time| time.parse::<chrono::DateTime<chrono::Utc>>().is_ok()), "{}", info);
+        assert_eq!(version::FULL_VERSION, format!("{}+{}", info["version"].as_str().unwrap(), info["git_sha"].as_str().unwrap()));
+
+        let (_, body) = testing::get(&app, "/health", &[]).await;
+        let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(health["version"], version::FULL_VERSION);
+
+        // 错误响应同样带有版本头
+        let (status, headers, _) = testing::post(&app, "/v1/chat/completions", &[], json!({"model": "deepthink"})).await;
+        assert!(status.is_client_error());
+        assert_eq!(headers[version::VERSION_HEADER], version::FULL_VERSION);
+        assert!(crate::clients::DEFAULT_USER_AGENT.ends_with(version::FULL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn vendor_options_are_read_from_the_body_and_never_forwarded() {
+        let upstream = MockServer::start().await;
+        Mock::given(path(REASONER_PATH)).respond_with(ResponseTemplate::new(500)).expect(0).mount(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "deepthink": {"skip_reasoning": true, "colour": "red"},
+        });
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[VENDOR_WARNING_HEADER], "ignored unknown deepthink options: colour");
+        let target_call = &testing::received(&upstream, OPENAI_PATH).await[0];
+        assert!(target_call.get("deepthink").is_none(), "{}", target_call);
+        assert!(target_call.get("colour").is_none(), "{}", target_call);
+    }
+
+    #[tokio::test]
+    async fn vendor_options_are_read_from_extra_body_and_never_forwarded() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "extra_body": {"deepthink": {"reasoning_effort": "high", "include_reasoning": false, "metadata": {"team": "search"}}},
+        });
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(headers.get(VENDOR_WARNING_HEADER).is_none());
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hi");
+        assert_eq!(body["metadata"], json!({"team": "search"}));
+
+        let reasoner_call = &testing::received(&upstream, REASONER_PATH).await[0];
+        assert_eq!(reasoner_call["reasoning_effort"], "high");
+        for call in [reasoner_call, &testing::received(&upstream, OPENAI_PATH).await[0]] {
+            assert!(call.get("extra_body").is_none(), "{}", call);
+            assert!(call.get("deepthink").is_none(), "{}", call);
+        }
+    }
+
+    #[tokio::test]
+    async fn passthrough_bodies_lose_the_vendor_namespace() {
+        let upstream = MockServer::start().await;
+        mock_openai_answer(&upstream).await;
+        let app = passthrough_app(&upstream).await;
+
+        let request = json!({
+            "model": "gpt-4o-mini",
+            "messages": [],
+            "deepthink": {"no_cache": true},
+            "extra_body": {"deepthink": {"no_cache": true}, "top_k": 5},
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await;
+        assert_eq!(target_calls, vec![json!({"model": "gpt-4o-mini", "messages": [], "extra_body": {"top_k": 5}})]);
+    }
+
+    /// Streams a reasoning of 1000 single-token deltas through a compat
+    /// request and returns the SSE body.
+    async fn stream_token_by_token(max_coalesce_bytes: usize) -> String {
+        let upstream = MockServer::start().await;
+        let mut chunks: Vec<serde_json::Value> = (0..1000)
+            .map(|i| json!({"id": "r1", "object": "chat.completion.chunk", "created": 0, "model": "deepseek-reasoner", "choices": [{"index": 0, "delta": {"reasoning_content": format!("t{:03} ", i)}, "finish_reason": null}]}))
+            .collect();
+        chunks.push(json!({"id": "r1", "object": "chat.completion.chunk", "created": 0, "model": "deepseek-reasoner", "choices": [{"index": 0, "delta": {"content": ""}, "finish_reason": "stop"}]}));
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(testing::sse(&chunks), "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        testing::mock_streaming_openai(&upstream, &["Done."]).await;
+        let mut config = testing::config(&upstream);
+        config.server.max_coalesce_bytes = max_coalesce_bytes;
+        config.server.max_coalesce_millis = 60_000;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Count"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        body
+    }
+
+    #[tokio::test]
+    async fn coalescing_cuts_the_reasoning_frame_count() {
+        let reasoning: String = (0..1000).map(|i| format!("t{:03} ", i)).collect();
+        let expected = format!("<thinking>\n{}\n</thinking>\n\nDone.", reasoning);
+
+        let plain = stream_token_by_token(0).await;
+        assert_eq!(streamed_content(&plain), expected);
+        let plain_frames = testing::stream_deltas(&plain).len();
+        assert!(plain_frames > 1000, "{}", plain_frames);
+
+        // 每帧 5 字节, 攒够 500 字节才发送: 1000 个片段合并为 10 帧
+        let coalesced = stream_token_by_token(500).await;
+        assert_eq!(streamed_content(&coalesced), expected);
+        let coalesced_frames = testing::stream_deltas(&coalesced).len();
+        assert_eq!(plain_frames - coalesced_frames, 990, "{} vs {}", plain_frames, coalesced_frames);
+    }
+
+    /// Sends a compat request with a system message and returns the messages
+    /// the reasoner received after DeepThink's own reasoning prompt.
+    async fn reasoner_messages_for(mode: ReasonerSystemPrompt, mapping_mode: Option<&str>, stream: bool) -> serde_json::Value {
+        let upstream = MockServer::start().await;
+        match stream {
+            true => {
+                testing::mock_streaming_reasoner(&upstream).await;
+                testing::mock_streaming_openai(&upstream, &["Rome."]).await;
+            }
+            false => {
+                testing::mock_reasoner(&upstream).await;
+                mock_openai_answer(&upstream).await;
+            }
+        }
+        let mut config = testing::config(&upstream);
+        config.reasoning.reasoner_sees_system = mode;
+        if let Some(mapping_mode) = mapping_mode {
+            let mapping: ModelMapping = serde_json::from_value(json!({
+                "deepseek_model": "deepseek-r1:14b",
+                "target_model": "gpt-4o",
+                "parameters": {},
+                "reasoner_sees_system": mapping_mode,
+            }))
+            .unwrap();
+            config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        }
+        let (app, _) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "messages": [{"role": "system", "content": "Answer in French."}, {"role": "user", "content": "Capital of Italy?"}],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let messages = testing::received(&upstream, REASONER_PATH).await[0]["messages"].clone();
+        assert_eq!(messages[0]["role"], "system");
+        json!(messages.as_array().unwrap()[1..])
+    }
+
+    #[tokio::test]
+    async fn reasoner_sees_the_callers_system_prompt_as_configured() {
+        let user = json!({"role": "user", "content": "Capital of Italy?"});
+        for stream in [false, true] {
+            assert_eq!(reasoner_messages_for(ReasonerSystemPrompt::None, None, stream).await, json!([user]));
+            assert_eq!(
+                reasoner_messages_for(ReasonerSystemPrompt::AsSystem, None, stream).await,
+                json!([{"role": "system", "content": "Answer in French."}, user])
+            );
+            assert_eq!(
+                reasoner_messages_for(ReasonerSystemPrompt::AsContext, None, stream).await,
+                json!([{"role": "user", "content": "Task context:\nAnswer in French."}, user])
+            );
+        }
+        // 映射中的设置优先于全局配置
+        assert_eq!(
+            reasoner_messages_for(ReasonerSystemPrompt::AsSystem, Some("none"), false).await,
+            json!([user])
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoint_overrides_outside_the_allow_list_are_forbidden() {
+        let upstream = MockServer::start().await;
+        Mock::given(path(REASONER_PATH)).respond_with(ResponseTemplate::new(500)).expect(0).mount(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.allowed_hosts = vec!["gpu-box".to_string()];
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let headers = [(DEEPSEEK_ENDPOINT_URL_HEADER, "http://169.254.169.254/latest/meta-data")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 403, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "endpoint_not_allowed");
+        assert_eq!(body["error"]["param"], DEEPSEEK_ENDPOINT_URL_HEADER);
+        assert_eq!(body["error"]["message"], "Endpoint override in X-DeepSeek-Endpoint-URL is not allowed: host '169.254.169.254' is not in endpoints.allowed_hosts");
+    }
+
+    #[tokio::test]
+    async fn disabled_endpoint_overrides_fall_back_to_the_configured_endpoints() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.allow_override = false;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let headers = [(OPENAI_ENDPOINT_URL_HEADER, "http://attacker.example/v1/chat/completions")];
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[ENDPOINT_WARNING_HEADER], "endpoint overrides are disabled, ignored: X-OpenAI-Endpoint-URL");
+        assert_eq!(testing::received(&upstream, OPENAI_PATH).await.len(), 1);
+    }
+
+    /// Serves an OpenAI target that counts the connections it accepts and the
+    /// `HEAD` and `POST` requests it answers.
+    ///
+    /// Returns the endpoint URL and the three counters.
+    async fn counting_target() -> (String, [Arc<std::sync::atomic::AtomicUsize>; 3]) {
+        use axum::serve::ListenerExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let counters: [Arc<AtomicUsize>; 3] = Default::default();
+        let [connections, heads, posts] = counters.clone();
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop");
+        let router = axum::Router::new().route(
+            OPENAI_PATH,
+            axum::routing::head(move || async move {
+                heads.fetch_add(1, Ordering::SeqCst);
+            })
+            .post(move || async move {
+                posts.fetch_add(1, Ordering::SeqCst);
+                Json(answer)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = listener.tap_io(move |_| {
+            connections.fetch_add(1, Ordering::SeqCst);
+        });
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (format!("http://{}{}", address, OPENAI_PATH), counters)
+    }
+
+    #[tokio::test]
+    async fn the_target_call_reuses_the_warmed_up_connection() {
+        use std::sync::atomic::Ordering;
+
+        for (method, warm_ups) in [(WarmUpMethod::Head, 1), (WarmUpMethod::Off, 0)] {
+            let upstream = MockServer::start().await;
+            testing::mock_reasoner(&upstream).await;
+            let (target, [connections, heads, posts]) = counting_target().await;
+            let mut config = testing::config(&upstream);
+            config.endpoints.openai = target;
+            config.target.warm_up.openai = method;
+            let (app, _) = testing::app(&config);
+
+            let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Capital of France?"}]});
+            let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            assert_eq!(status, 200, "{}", body);
+            assert_eq!(heads.load(Ordering::SeqCst), warm_ups, "{:?}", method);
+            assert_eq!(posts.load(Ordering::SeqCst), 1, "{:?}", method);
+            // 预热建立的连接被目标调用复用, 总共只有一个连接
+            assert_eq!(connections.load(Ordering::SeqCst), 1, "{:?}", method);
+            assert_eq!(headers.contains_key("x-deepthink-timing-target-warm-up-ms"), warm_ups == 1, "{:?}", method);
+        }
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[test]
+    fn developer_messages_become_the_anthropic_system_parameter() {
+        let request: ApiRequest = serde_json::from_value(json!({
+            "messages": [{"role": "developer", "content": "Be brief."}, {"role": "user", "content": "Hi"}],
+        }))
+        .unwrap();
+        let (system, messages) = anthropic_target_messages(&request, request.build_target_messages(None));
+        assert_eq!(serde_json::to_value(system).unwrap(), json!("Be brief."));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, Role::User);
+    }
+
+    #[tokio::test]
+    async fn unknown_roles_are_bad_requests_on_both_endpoints() {
+        let upstream = MockServer::start().await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let messages = json!([{"role": "critic", "content": "Hi"}]);
+        for (uri, request) in [
+            ("/", json!({"messages": messages})),
+            ("/v1/chat/completions", json!({"model": "deepthink", "messages": messages})),
+        ] {
+            let (status, _, body) = testing::post(&app, uri, &[], request).await;
+            assert_eq!(status, 400, "{}: {}", uri, body);
+            assert!(body.contains("accepted roles: system, developer, user, assistant, tool"), "{}: {}", uri, body);
+        }
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_stream_usage_is_aggregated_and_charged() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        // 录制的 Claude 流: message_start 带输入和缓存 token, message_delta 带累计的输出 token
+        let events = [
+            ("message_start", json!({"type": "message_start", "message": {"id": "msg_1", "type": "message", "role": "assistant", "model": "claude-test", "content": [], "stop_reason": null, "stop_sequence": null, "usage": {"input_tokens": 25, "cache_creation_input_tokens": 5, "cache_read_input_tokens": 100, "output_tokens": 1}}})),
+            ("content_block_start", json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}})),
+            ("ping", json!({"type": "ping"})),
+            ("content_block_delta", json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Paris."}})),
+            ("content_block_stop", json!({"type": "content_block_stop", "index": 0})),
+            ("message_delta", json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}, "usage": {"output_tokens": 42}})),
+            ("message_stop", json!({"type": "message_stop"})),
+        ];
+        let stream: String = events.iter().map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data)).collect();
+        Mock::given(method("POST"))
+            .and(path(testing::ANTHROPIC_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        // 每个 prompt token $0.01, 缓存命中 $0.001, 每个输出 token $0.1
+        config.budget.pricing.insert(
+            config.models.default_anthropic.clone(),
+            crate::config::ModelPrice {
+                prompt_usd_per_million: 10_000.0,
+                completion_usd_per_million: 100_000.0,
+                cached_prompt_usd_per_million: Some(1_000.0),
+            },
+        );
+        let (app, state) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[("X-Target-Model", "anthropic")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+        // 推理流报告 12 + 8 个 token
+        assert_eq!(
+            metadata["usage"],
+            json!({"prompt_tokens": 142, "completion_tokens": 50, "total_tokens": 192, "cached_prompt_tokens": 100})
+        );
+
+        let tokens = TokenConfig { daily_budget_usd: Some(0.0001), ..config.auth.default_tokens.clone() };
+        match state.spend.check("", &tokens, &config.budget, state.clock.now()) {
+            Err(ApiError::BudgetExceeded { spent_usd, .. }) => assert!((spent_usd - 4.6).abs() < 1e-9, "{}", spent_usd),
+            other => panic!("expected the stream to be charged, got {:?}", other),
+        }
+    }
+
+    /// Sends a conversation with think tags in its user and assistant turns.
+    ///
+    /// Returns the streamed or returned answer and the messages the reasoner
+    /// and the target received.
+    async fn think_tag_calls(config: impl FnOnce(&mut Config), stream: bool) -> (String, serde_json::Value, serde_json::Value) {
+        let upstream = MockServer::start().await;
+        match stream {
+            true => {
+                testing::mock_streaming_reasoner(&upstream).await;
+                testing::mock_streaming_openai(&upstream, &["Tags ", "are ", "markup."]).await;
+            }
+            false => {
+                testing::mock_reasoner(&upstream).await;
+                mock_openai_answer(&upstream).await;
+            }
+        }
+        let mut app_config = testing::config(&upstream);
+        config(&mut app_config);
+        let (app, _) = testing::app(&app_config);
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "<think>old plan</think>Hello"},
+                {"role": "user", "content": "What does </think> mean?"},
+            ],
+        });
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let answer = match stream {
+            true => streamed_content(&body),
+            false => serde_json::from_str::<serde_json::Value>(&body).unwrap()["choices"][0]["message"]["content"].as_str().unwrap().to_string(),
+        };
+        let reasoner = testing::received(&upstream, REASONER_PATH).await[0]["messages"].clone();
+        let target = testing::received(&upstream, OPENAI_PATH).await[0]["messages"].clone();
+        (answer, reasoner, target)
+    }
+
+    #[tokio::test]
+    async fn user_think_tags_are_escaped_for_the_reasoner_only() {
+        for stream in [false, true] {
+            let (answer, reasoner, target) = think_tag_calls(|_| {}, stream).await;
+            assert!(answer.ends_with(if stream { "Tags are markup." } else { "Hi" }), "{}", answer);
+            let last = reasoner.as_array().unwrap().last().unwrap();
+            assert_eq!(last["content"], "What does &lt;/think&gt; mean?", "{}", reasoner);
+            // 历史中的助手回合不转义
+            assert!(reasoner.to_string().contains("<think>old plan</think>Hello"), "{}", reasoner);
+            assert!(target.to_string().contains("What does </think> mean?"), "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_think_tags_pass_through_when_configured() {
+        let (_, reasoner, _) = think_tag_calls(|config| config.reasoning.user_think_tags = UserThinkTags::Pass, false).await;
+        assert_eq!(reasoner.as_array().unwrap().last().unwrap()["content"], "What does </think> mean?");
+    }
+
+    #[tokio::test]
+    async fn history_thinking_is_stripped_for_both_phases() {
+        for stream in [false, true] {
+            let (_, reasoner, target) = think_tag_calls(|config| config.reasoning.strip_history_thinking = true, stream).await;
+            for messages in [&reasoner, &target] {
+                assert!(!messages.to_string().contains("old plan"), "{}", messages);
+                assert!(messages.to_string().contains("Hello"), "{}", messages);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_cut_off_mid_answer_are_marked_partial() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.openai = testing::mock_dying_openai(&["The answer ", "is"]).await;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200);
+        assert!(streamed_content(&body).ends_with("The answer is"), "{}", body);
+        // 错误帧之后依次是结束块、partial 元数据帧和 [DONE]
+        let frames: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+        let [error, finish, metadata, done] = frames[frames.len() - 4..] else { unreachable!() };
+        assert_eq!(done, "[DONE]");
+        let error: serde_json::Value = serde_json::from_str(error).unwrap();
+        assert_eq!(error["phase"], "answering");
+        assert_eq!(error["partial"], true);
+        let finish: serde_json::Value = serde_json::from_str(finish).unwrap();
+        assert_eq!(finish["choices"][0]["finish_reason"], "error");
+        let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+        assert_eq!(metadata["partial"], true);
+    }
+
+    #[tokio::test]
+    async fn partial_answers_are_returned_when_configured() {
+        for partial_on_failure in [false, true] {
+            let upstream = MockServer::start().await;
+            testing::mock_reasoner(&upstream).await;
+            let mut config = testing::config(&upstream);
+            config.endpoints.openai = testing::mock_dying_openai(&["The answer ", "is"]).await;
+            config.target.partial_on_failure = partial_on_failure;
+            let (app, _) = testing::app(&config);
+
+            let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            if !partial_on_failure {
+                assert!(!status.is_success(), "{}", body);
+                continue;
+            }
+            assert_eq!(status, 200, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["choices"][0]["finish_reason"], "interrupted");
+            assert!(body["choices"][0]["message"]["content"].as_str().unwrap().ends_with("The answer is"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_headers_choose_the_response_format() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Rome."]).await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[("Accept", "text/event-stream")], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers["content-type"], "text/event-stream");
+        assert_eq!(headers["cache-control"], "no-store");
+        assert_eq!(headers["x-accel-buffering"], "no");
+        assert!(streamed_content(&body).ends_with("Rome."), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn stream_flags_contradicting_the_accept_header_are_resolved_by_precedence() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let accept = [("Accept", "application/json")];
+
+        let (app, _) = testing::app(&testing::config(&upstream));
+        for uri in ["/v1/chat/completions", "/"] {
+            let (status, _, body) = testing::post(&app, uri, &accept, request.clone()).await;
+            assert_eq!(status, 406, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["code"], "stream_accept_mismatch");
+            assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+        }
+
+        let mut config = testing::config(&upstream);
+        config.streaming.accept_precedence = AcceptPrecedence::Accept;
+        let (app, _) = testing::app(&config);
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &accept, request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["choices"][0]["message"]["content"].as_str().unwrap().ends_with("Hi"), "{}", body);
+    }
+
+    /// Races a draft against a reasoner that answers after `reasoner_delay`
+    /// under an optimistic mapping waiting 300ms for it.
+    ///
+    /// Returns the status, headers and body of the response and the bodies
+    /// the target received.
+    async fn optimistic_race(
+        reasoner_delay: Duration,
+        stream: bool,
+    ) -> (u16, axum::http::HeaderMap, String, Vec<serde_json::Value>) {
+        let upstream = MockServer::start().await;
+        let reasoner = match stream {
+            true => ResponseTemplate::new(200).set_body_raw(testing::reasoner_stream(REASONING), "text/event-stream"),
+            false => ResponseTemplate::new(200).set_body_json(testing::reasoner_completion(REASONING)),
+        };
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(reasoner.set_delay(reasoner_delay))
+            .mount(&upstream)
+            .await;
+        // 草稿总是非流式调用, 推理后的流式回答走流式接口
+        testing::mock_streaming_openai_when_streamed(&upstream, &["Reasoned ", "answer."]).await;
+        let draft = testing::openai_completion(json!({"role": "assistant", "content": "Draft answer."}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(draft))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1",
+            "target_model": "gpt-4o",
+            "parameters": {},
+            "optimistic": {"enabled": true, "reasoner_wait_ms": 300},
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": stream, "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        (status.as_u16(), headers, body, testing::received(&upstream, OPENAI_PATH).await)
+    }
+
+    #[tokio::test]
+    async fn slow_reasoners_lose_the_race_to_the_draft() {
+        let (status, headers, body, target_calls) = optimistic_race(Duration::from_secs(2), false).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[OPTIMISTIC_PATH_HEADER], "draft");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Draft answer.");
+        // 草稿不带推理, 推理调用被取消后不再调用目标模型
+        assert_eq!(target_calls.len(), 1);
+        assert!(!target_calls[0].to_string().contains(REASONING), "{}", target_calls[0]);
+        assert_eq!(body["usage"]["total_tokens"], 35);
+
+        let (status, _, body, target_calls) = optimistic_race(Duration::from_secs(2), true).await;
+        assert_eq!(status, 200);
+        // 做出选择前不输出推理, 之后只发送草稿
+        let content = streamed_content(&body);
+        assert_eq!(content, "Draft answer.", "{}", body);
+        assert_eq!(target_calls.len(), 1);
+        let metadata = sse_frames(&body)
+            .into_iter()
+            .filter_map(|(_, data)| serde_json::from_str::<serde_json::Value>(&data).ok())
+            .find(|frame| frame.get("optimistic_path").is_some())
+            .unwrap();
+        assert_eq!(metadata["optimistic_path"], "draft");
+        assert_eq!(metadata["usage"]["total_tokens"], 35);
+    }
+
+    #[tokio::test]
+    async fn fast_reasoners_win_the_race_and_count_the_draft() {
+        let (status, headers, body, target_calls) = optimistic_race(Duration::from_millis(100), false).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[OPTIMISTIC_PATH_HEADER], "reasoned");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["choices"][0]["message"]["content"].as_str().unwrap().contains(REASONING), "{}", body);
+        assert_eq!(target_calls.len(), 2);
+        assert!(target_calls[1].to_string().contains(REASONING), "{}", target_calls[1]);
+        // 推理 20 + 已完成的草稿 35 + 推理后的回答 35
+        assert_eq!(body["usage"]["total_tokens"], 90);
+
+        let (status, _, body, target_calls) = optimistic_race(Duration::from_millis(100), true).await;
+        assert_eq!(status, 200);
+        let content = streamed_content(&body);
+        assert!(content.contains(REASONING) && content.ends_with("Reasoned answer."), "{}", body);
+        assert_eq!(target_calls.len(), 2);
+        let metadata = sse_frames(&body)
+            .into_iter()
+            .filter_map(|(_, data)| serde_json::from_str::<serde_json::Value>(&data).ok())
+            .find(|frame| frame.get("optimistic_path").is_some())
+            .unwrap();
+        assert_eq!(metadata["optimistic_path"], "reasoned");
+        // 推理 20 + 已完成的草稿 35
+        assert_eq!(metadata["usage"]["total_tokens"], 55);
+    }
+
+    #[tokio::test]
+    async fn finished_requests_are_journaled_with_their_usage() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let answer = testing::openai_completion(json!({"role": "assistant", "content": "Hi"}), "stop");
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.models.passthrough_models = vec!["gpt-4o".to_string()];
+        let (app, _) = testing::app(&config);
+
+        let (status, headers, _) = testing::post(&app, "/v1/chat/completions", &[], json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]})).await;
+        assert_eq!(status, 200);
+        let reasoned = headers[crate::journal::REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        let (status, headers, _) = testing::post(&app, "/v1/chat/completions", &[], json!({"model": "gpt-4o", "messages": []})).await;
+        assert_eq!(status, 200);
+        let relayed = headers[crate::journal::REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        let (status, _, _) = testing::post(&app, "/v1/chat/completions", &[], json!({"model": "deepthink"})).await;
+        assert!(status.is_client_error());
+
+        let (status, body) = testing::get(&app, &format!("/admin/requests/{}", relayed), &[]).await;
+        assert_eq!(status, 200);
+        let entry: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((entry["mapping"].as_str(), entry["target_provider"].as_str()), (Some("gpt-4o"), Some("openai")));
+        assert_eq!(entry["usage"]["total_tokens"], 35);
+
+        let (_, body) = testing::get(&app, "/admin/requests?mapping=deepthink", &[]).await;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["id"], reasoned.as_str());
+        // 推理 20 + 目标 35
+        assert_eq!(entries[0]["usage"]["total_tokens"], 55);
+        assert!(entries[0]["timings"].is_object());
+
+        let (_, body) = testing::get(&app, "/admin/requests?min_status=400", &[]).await;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0]["error"].is_string(), "{}", body);
+
+        let (status, _) = testing::get(&app, "/admin/requests/unknown", &[]).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn streams_can_be_sent_as_ndjson() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let chunk = |content: &str| json!({"model": "gpt-4o", "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]});
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(testing::sse(&[chunk("Par"), chunk("is.")]), "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let request = json!({"model": "deepthink", "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+
+        // 未指定传输格式时 SSE 输出不变
+        let (status, headers, sse) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(headers[axum::http::header::CONTENT_TYPE], "text/event-stream");
+        assert!(sse.starts_with("data: ") && sse.contains("data: [DONE]"), "{}", sse);
+
+        let mut by_field = request.clone();
+        by_field["stream_transport"] = json!("ndjson");
+        for (headers, request) in [(&[("accept", "application/x-ndjson")][..], request), (&[][..], by_field)] {
+            let (status, response_headers, body) = testing::post(&app, "/v1/chat/completions", headers, request).await;
+            assert_eq!(status, 200);
+            assert_eq!(response_headers[axum::http::header::CONTENT_TYPE], "application/x-ndjson");
+            assert!(body.ends_with("{\"done\":true}\n"), "{}", body);
+            let lines: Vec<serde_json::Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+            let content: String = lines.iter().filter_map(|chunk| chunk.pointer("/choices/0/delta/content")?.as_str()).collect();
+            assert_eq!(content, streamed_content(&sse));
+            // 每行与对应 SSE 帧的 data 结构相同
+            assert_eq!(lines.len(), sse.matches("data: ").count());
+        }
+    }
+
+    /// Reasoning mentioning an email address and a phone number.
+    const PII_REASONING: &str = "Mail ann@example.com or call 555-123-4567.";
+
+    /// Answers a compat request whose reasoning is [`PII_REASONING`], under
+    /// `[[redaction]]` rules for emails and phone numbers and `scope`.
+    ///
+    /// Returns the response headers and body and the body the target received.
+    async fn answer_with_redaction(scope: RedactionScope, stream: bool) -> (axum::http::HeaderMap, String, serde_json::Value) {
+        let upstream = MockServer::start().await;
+        let reasoner = match stream {
+            true => ResponseTemplate::new(200).set_body_raw(testing::reasoner_stream(PII_REASONING), "text/event-stream"),
+            false => ResponseTemplate::new(200).set_body_json(testing::reasoner_completion(PII_REASONING)),
+        };
+        Mock::given(method("POST")).and(path(REASONER_PATH)).respond_with(reasoner).mount(&upstream).await;
+        if stream {
+            testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        } else {
+            let answer = testing::openai_completion(json!({"role": "assistant", "content": "Paris."}), "stop");
+            Mock::given(method("POST")).and(path(OPENAI_PATH)).respond_with(ResponseTemplate::new(200).set_body_json(answer)).mount(&upstream).await;
+        }
+        let mut config = testing::config(&upstream);
+        config.redaction = serde_json::from_value(json!([
+            {"pattern": "[\\w.+-]+@[\\w-]+\\.[\\w.]+", "replacement": "[email]"},
+            {"pattern": "\\d{3}-\\d{3}-\\d{4}", "replacement": "[phone]"},
+        ]))
+        .unwrap();
+        config.reasoning.redaction_scope = scope;
+        let (app, _) = testing::app(&config);
+
+        let request = json!({"model": "deepthink", "stream": stream, "messages": [{"role": "user", "content": "Capital of France?"}]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let target_call = testing::received(&upstream, OPENAI_PATH).await.remove(0);
+        (headers, body, target_call)
+    }
+
+    #[tokio::test]
+    async fn redaction_rules_apply_to_the_injected_reasoning() {
+        const REDACTED: &str = "Mail [email] or call [phone].";
+        for stream in [false, true] {
+            for scope in [RedactionScope::Injected, RedactionScope::All] {
+                let (headers, body, target_call) = answer_with_redaction(scope, stream).await;
+                let injected = target_call["messages"].to_string();
+                assert!(injected.contains(REDACTED) && !injected.contains("ann@example.com"), "{}", injected);
+
+                let shown = if scope == RedactionScope::All { REDACTED } else { PII_REASONING };
+                let content = match stream {
+                    true => streamed_content(&body),
+                    false => serde_json::from_str::<serde_json::Value>(&body).unwrap()["choices"][0]["message"]["content"].as_str().unwrap().to_string(),
+                };
+                if stream {
+                    assert_eq!(content, format!("<thinking>\n{}\n</thinking>\n\nParis.", shown), "{:?}", scope);
+                    let metadata = body.split("event: metadata\ndata: ").nth(1).unwrap().lines().next().unwrap();
+                    let metadata: serde_json::Value = serde_json::from_str(metadata).unwrap();
+                    assert_eq!(metadata["redactions"], 2);
+                    // 脱敏不改变用量
+                    assert_eq!(metadata["usage"]["prompt_tokens"], 12);
+                } else {
+                    assert_eq!(content, format!("<think>\n{}\n</think>\n\nParis.", shown), "{:?}", scope);
+                    assert_eq!(headers[REDACTIONS_HEADER], "2");
+                    let usage = &serde_json::from_str::<serde_json::Value>(&body).unwrap()["usage"];
+                    // 推理 20 + 目标 35
+                    assert_eq!(usage["total_tokens"], 55);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn long_conversations_are_trimmed_for_both_phases_and_reported() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+            "max_messages": 3,
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        let (app, _) = testing::app(&config);
+
+        let turn = |role: &str, content: &str| json!({"role": role, "content": content});
+        let request = json!({"model": "deepthink", "messages": [
+            turn("user", "first"), turn("assistant", "a1"), turn("user", "second"), turn("assistant", "a2"), turn("user", "latest"),
+        ]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers[TRIMMED_MESSAGES_HEADER], "2");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["trimmed"]["dropped_messages"], 2);
+        assert!(body["trimmed"]["estimated_tokens_saved"].as_u64().unwrap() > 0);
+
+        let kept = ["first", "a2", "latest"];
+        let reasoner_call = testing::received(&upstream, REASONER_PATH).await.remove(0).to_string();
+        let target_call = testing::received(&upstream, OPENAI_PATH).await.remove(0);
+        let target_contents: Vec<&str> = target_call["messages"].as_array().unwrap().iter().filter_map(|message| message["content"].as_str()).collect();
+        for content in kept {
+            assert!(reasoner_call.contains(content) && target_contents.iter().any(|text| text.contains(content)), "{}", content);
+        }
+        for dropped in ["a1", "second"] {
+            assert!(!reasoner_call.contains(dropped) && !target_contents.iter().any(|text| text.contains(dropped)), "{}", dropped);
+        }
+    }
+
+    /// Returns a config whose parameter policy drops the target's `tools`
+    /// and whose `deepthink` mapping keeps the last two messages.
+    fn warning_config(upstream: &MockServer) -> crate::config::Config {
+        let mut config = testing::config(upstream);
+        config.parameter_policy.mode = crate::config::ParameterPolicyMode::Lenient;
+        config.parameter_policy.target.allow = Some(vec!["temperature".to_string()]);
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+            "max_messages": 2,
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        config
+    }
+
+    #[tokio::test]
+    async fn compat_responses_list_the_warnings_of_every_stage() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let (app, _) = testing::app(&warning_config(&upstream));
+
+        let tools = json!([{"type": "function", "function": {"name": "lookup", "parameters": {"type": "object"}}}]);
+        let request = json!({"model": "deepthink", "tools": tools, "messages": [
+            {"role": "user", "content": "first"}, {"role": "assistant", "content": "a1"}, {"role": "user", "content": "latest"},
+        ]});
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let warnings = body["warnings"].as_array().unwrap();
+        let codes: Vec<&str> = warnings.iter().filter_map(|warning| warning["code"].as_str()).collect();
+        assert_eq!(codes, ["messages_trimmed", "parameters_dropped"]);
+        assert_eq!(warnings[0]["detail"]["dropped_messages"], 1);
+        assert_eq!(warnings[1]["message"], "dropped parameters not allowed by policy: target.tools");
+        // 原有的警告头由同一组警告生成
+        assert_eq!(headers[TRIMMED_MESSAGES_HEADER], "1");
+        assert_eq!(headers[PARAMETER_WARNING_HEADER], "dropped parameters not allowed by policy: target.tools");
+
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (_, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert!(serde_json::from_str::<serde_json::Value>(&body).unwrap().get("warnings").is_none());
+        assert!(!headers.contains_key(PARAMETER_WARNING_HEADER));
+    }
+
+    #[tokio::test]
+    async fn native_responses_list_their_warnings() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let config = warning_config(&upstream);
+        let (app, _) = testing::app(&config);
+
+        let headers = [
+            ("X-DeepSeek-API-Token", "reasoner-token"),
+            ("X-OpenAI-API-Token", "openai-token"),
+            ("X-Target-Model", "openai"),
+            (DEEPSEEK_ENDPOINT_URL_HEADER, config.endpoints.deepseek.as_str()),
+            (OPENAI_ENDPOINT_URL_HEADER, config.endpoints.openai.as_str()),
+        ];
+        let request = json!({"messages": [{"role": "user", "content": "Hi"}], "openai_config": {"body": {"tools": []}}});
+        let (status, headers, body) = testing::post(&app, "/", &headers, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["warnings"],
+            json!([{"code": "parameters_dropped", "message": "dropped parameters not allowed by policy: target.tools"}])
+        );
+        assert_eq!(headers[PARAMETER_WARNING_HEADER], "dropped parameters not allowed by policy: target.tools");
+    }
+
+    #[tokio::test]
+    async fn streams_send_their_warnings_before_the_metadata() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let (app, _) = testing::app(&warning_config(&upstream));
+
+        let request = json!({"model": "deepthink", "stream": true, "messages": [
+            {"role": "user", "content": "first"}, {"role": "assistant", "content": "a1"}, {"role": "user", "content": "latest"},
+        ]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let warning = body.split("event: warning\ndata: ").nth(1).unwrap().lines().next().unwrap();
+        let warning: serde_json::Value = serde_json::from_str(warning).unwrap();
+        assert_eq!(warning["type"], "warning");
+        assert_eq!(warning["code"], "messages_trimmed");
+        assert!(body.find("event: warning").unwrap() < body.find("event: metadata").unwrap());
+    }
+
+    #[tokio::test]
+    async fn sticky_conversations_stay_on_one_replica_until_it_is_unhealthy() {
+        let upstream = MockServer::start().await;
+        mock_openai_answer(&upstream).await;
+        let mut replicas = Vec::new();
+        for _ in 0..3 {
+            let replica = MockServer::start().await;
+            testing::mock_reasoner(&replica).await;
+            replicas.push(replica);
+        }
+        let endpoints: Vec<String> = replicas.iter().map(|replica| format!("{}{}", replica.uri(), REASONER_PATH)).collect();
+        let mut config = testing::config(&upstream);
+        config.circuit_breaker.failure_threshold = 1;
+        let mapping: ModelMapping = serde_json::from_value(json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+            "reasoner_endpoints": endpoints,
+            "reasoner_routing": "sticky",
+        }))
+        .unwrap();
+        config.models.model_mappings.insert("deepthink".to_string(), mapping);
+        let (app, state) = testing::app(&config);
+        let request = json!({"model": "deepthink", "conversation_id": "conv-1", "messages": [{"role": "user", "content": "Hi"}]});
+        let calls = || async { futures::future::join_all(replicas.iter().map(|replica| testing::received(replica, REASONER_PATH))).await };
+
+        let mut home = None;
+        for _ in 0..3 {
+            let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+            assert_eq!(status, 200, "{}", body);
+            let endpoint = headers[REASONER_ENDPOINT_HEADER].to_str().unwrap().to_string();
+            assert_eq!(*home.get_or_insert(endpoint.clone()), endpoint);
+        }
+        let home = home.unwrap();
+        let counts: Vec<usize> = calls().await.iter().map(Vec::len).collect();
+        let index = endpoints.iter().position(|endpoint| *endpoint == home).unwrap();
+        assert_eq!(counts.iter().sum::<usize>(), 3);
+        assert_eq!(counts[index], 3);
+
+        // 原副本熔断后会话转到其余副本
+        let failure = ApiError::DeepSeekError {
+            message: "error sending request".to_string(),
+            type_: crate::clients::CONNECTION_FAILED_ERROR_TYPE.to_string(),
+            param: None,
+            code: None,
+        };
+        state.circuits.record_error("deepseek", &home, &failure);
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_ne!(headers[REASONER_ENDPOINT_HEADER].to_str().unwrap(), home);
+        assert_eq!(calls().await[index].len(), 3);
+
+        let (_, metrics) = testing::get(&app, "/metrics", &[]).await;
+        let metrics: serde_json::Value = serde_json::from_str(&metrics).unwrap();
+        let gauges = metrics["reasoner_replicas"].as_array().unwrap();
+        assert_eq!(gauges.len(), 2);
+        assert!(gauges.iter().all(|gauge| gauge["in_flight"] == 0), "{:?}", gauges);
+    }
+
+    #[tokio::test]
+    async fn ignored_message_fields_are_reported_when_configured() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let request = json!({"model": "deepthink", "messages": [
+            {"Role": "USER", "content": "Hi", "name": "alice"},
+            {"role": "assistant", "content": null, "function_call": null},
+            {"role": "user", "content": "Again"},
+        ]});
+
+        let (app, _) = testing::app(&testing::config(&upstream));
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(!body.contains("message_fields_ignored"), "{}", body);
+        let target = &testing::received(&upstream, OPENAI_PATH).await[0];
+        assert_eq!(target["messages"][0]["role"], "user");
+
+        let mut config = testing::config(&upstream);
+        config.server.report_unknown_message_fields = true;
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["warnings"][0]["code"], "message_fields_ignored");
+        assert_eq!(body["warnings"][0]["detail"]["fields"], json!(["function_call", "name"]));
+    }
+
+    #[tokio::test]
+    async fn echoed_answer_tags_are_stripped_without_touching_the_reasoning() {
+        for stream in [false, true] {
+            let upstream = MockServer::start().await;
+            if stream {
+                testing::mock_streaming_reasoner(&upstream).await;
+                testing::mock_streaming_openai(&upstream, &["Echo <thi", "nk>x</think", "> done"]).await;
+            } else {
+                testing::mock_reasoner(&upstream).await;
+                let answer = testing::openai_completion(json!({"role": "assistant", "content": "Echo <think>x</think> done"}), "stop");
+                Mock::given(method("POST")).and(path(OPENAI_PATH)).respond_with(ResponseTemplate::new(200).set_body_json(answer)).mount(&upstream).await;
+            }
+            let mut config = testing::config(&upstream);
+            config.target.answer_think_tags = AnswerThinkTags::Strip;
+            let (app, _) = testing::app(&config);
+
+            let request = json!({"model": "deepthink", "stream": stream, "thinking_format": "tag", "messages": [{"role": "user", "content": "Hi"}]});
+            let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+            assert_eq!(status, 200, "{}", body);
+            let (content, warning) = if stream {
+                let warning = body.split("event: warning\ndata: ").nth(1).unwrap().lines().next().unwrap();
+                (streamed_content(&body), serde_json::from_str::<serde_json::Value>(warning).unwrap())
+            } else {
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                (body["choices"][0]["message"]["content"].as_str().unwrap().to_string(), body["warnings"][0].clone())
+            };
+            // 推理阶段真正的 think 标签保留, 回答中复述的被删除
+            let (reasoning, answer) = content.split_once("\n\n").unwrap();
+            assert!(reasoning.starts_with("<think") && reasoning.contains(testing::REASONING), "{}", content);
+            assert!(reasoning.ends_with("</think>") || reasoning.ends_with("</thinking>"), "{}", content);
+            assert_eq!(answer, "Echo x done");
+            assert_eq!(warning["code"], "think_tags_in_answer");
+            assert_eq!(warning["detail"]["count"], 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_traces_keep_their_schema() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(
+                json!({"role": "assistant", "content": "Paris."}),
+                "stop",
+            )))
+            .mount(&upstream)
+            .await;
+        let mut config = testing::config(&upstream);
+        config.server.request_traces = true;
+        config.server.admin_token = Some("admin-secret".to_string());
+        let (app, _) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "deepthink": {"trace": true},
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let admin = [("X-Target-Model", "openai"), (ADMIN_TOKEN_HEADER, "admin-secret")];
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &admin, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let trace = &body["trace"];
+        insta::assert_json_snapshot!(trace, {
+            ".request_id" => "[request_id]",
+            ".received_at" => "[received_at]",
+            ".upstream_calls[].url" => "[url]",
+            ".timings" => "[timings]",
+        });
+
+        // 管理路由返回同一份 trace
+        let uri = format!("/admin/traces/{}", trace["request_id"].as_str().unwrap());
+        let (status, stored) = testing::get(&app, &uri, &admin[1..]).await;
+        assert_eq!(status, 200, "{}", stored);
+        assert_eq!(&serde_json::from_str::<serde_json::Value>(&stored).unwrap(), trace);
+    }
+
+    #[tokio::test]
+    async fn traces_need_the_admin_token_and_record_streams() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        let mut config = testing::config(&upstream);
+        let request = json!({"model": "deepthink", "stream": true, "deepthink": {"trace": true}, "messages": [{"role": "user", "content": "Hi"}]});
+
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 403, "{}", body);
+        assert!(body.contains("server.request_traces is disabled"), "{}", body);
+
+        config.server.request_traces = true;
+        config.server.admin_token = Some("admin-secret".to_string());
+        let (app, _) = testing::app(&config);
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[(ADMIN_TOKEN_HEADER, "wrong")], request.clone()).await;
+        assert_eq!(status, 403, "{}", body);
+        assert!(testing::received(&upstream, REASONER_PATH).await.is_empty());
+
+        let admin = [(ADMIN_TOKEN_HEADER, "admin-secret")];
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &admin, request).await;
+        assert_eq!(status, 200, "{}", body);
+        let uri = format!("/admin/traces/{}", headers[crate::journal::REQUEST_ID_HEADER].to_str().unwrap());
+        let (status, trace) = testing::get(&app, &uri, &admin).await;
+        assert_eq!(status, 200, "{}", trace);
+        let trace: serde_json::Value = serde_json::from_str(&trace).unwrap();
+        assert_eq!(trace["complete"], true);
+        let calls = trace["upstream_calls"].as_array().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0]["status"], 200);
+        assert!(!calls[0]["stream"].as_array().unwrap().is_empty());
+        assert!(calls[1]["stream"].to_string().contains("Paris."));
+
+        let (status, _) = testing::get(&app, "/admin/traces/unknown", &admin).await;
+        assert_eq!(status, 404);
+        let (status, _) = testing::get(&app, &uri, &[]).await;
+        assert_eq!(status, 401);
+    }
+
+    /// Answers one compat request whose reasoner writes `Draft: Paris.` after
+    /// its reasoning, under a mapping with `use_reasoner_answer = mode`.
+    ///
+    /// Returns the answer content, the `answered_by` of the metadata event (for
+    /// a plain response, its role from `X-DeepThink-Answered-By`), the total
+    /// usage and the number of target calls.
+    async fn answer_with_use_reasoner_answer(mode: &str, min_chars: usize, stream: bool) -> (String, serde_json::Value, serde_json::Value, usize) {
+        let upstream = MockServer::start().await;
+        let reasoner = match stream {
+            true => ResponseTemplate::new(200).set_body_raw(
+                testing::reasoner_stream(REASONING).replace(r#"{"content":""}"#, r#"{"content":" Draft: Paris. "}"#),
+                "text/event-stream",
+            ),
+            false => {
+                let mut completion = testing::reasoner_completion(REASONING);
+                completion["choices"][0]["message"]["content"] = json!(" Draft: Paris. ");
+                ResponseTemplate::new(200).set_body_json(completion)
+            }
+        };
+        Mock::given(method("POST")).and(path(REASONER_PATH)).respond_with(reasoner).mount(&upstream).await;
+        if stream {
+            testing::mock_streaming_openai(&upstream, &["Paris."]).await;
+        } else {
+            mock_openai_answer(&upstream).await;
+        }
+        let mut config = testing::config(&upstream);
+        let mapping = json!({
+            "deepseek_model": "deepseek-r1:14b",
+            "target_model": "qwen2.5:14b",
+            "parameters": {},
+            "use_reasoner_answer": mode,
+            "confident_answer_min_chars": min_chars,
+        });
+        config.models.model_mappings.insert("deepthink".to_string(), serde_json::from_value::<ModelMapping>(mapping).unwrap());
+        let (app, _) = testing::app(&config);
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": stream,
+            "stream_options": {"include_usage": true},
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request).await;
+        assert_eq!(status, 200, "{}", body);
+        let target_calls = testing::received(&upstream, OPENAI_PATH).await.len();
+        if stream {
+            let metadata: serde_json::Value = body
+                .split("event: metadata\ndata: ")
+                .nth(1)
+                .and_then(|frame| frame.lines().next())
+                .map(|line| serde_json::from_str(line).unwrap())
+                .unwrap_or_default();
+            return (streamed_content(&body), metadata["answered_by"].clone(), metadata["usage"].clone(), target_calls);
+        }
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let answered_by = headers
+            .get(ANSWERED_BY_HEADER)
+            .map(|role| json!({"role": role.to_str().unwrap()}))
+            .unwrap_or_default();
+        let content = body["choices"][0]["message"]["content"].as_str().unwrap().to_string();
+        (content, answered_by, body["usage"].clone(), target_calls)
+    }
+
+    #[tokio::test]
+    async fn mappings_can_answer_with_the_reasoners_own_answer() {
+        for stream in [false, true] {
+            let (content, answered_by, _, target_calls) = answer_with_use_reasoner_answer("never", 0, stream).await;
+            assert!(!content.contains("Draft"), "{}", content);
+            assert!(answered_by.is_null(), "{}", answered_by);
+            assert_eq!(target_calls, 1);
+
+            let (content, answered_by, usage, target_calls) = answer_with_use_reasoner_answer("always", 0, stream).await;
+            assert!(content.ends_with("Draft: Paris."), "{}", content);
+            assert_eq!(answered_by["role"], "reasoner", "{}", answered_by);
+            assert_eq!(usage["total_tokens"], 20, "{}", usage);
+            assert_eq!(target_calls, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn confident_reasoner_answers_need_the_configured_length() {
+        for stream in [false, true] {
+            let (content, answered_by, _, target_calls) = answer_with_use_reasoner_answer("if_confident", 10, stream).await;
+            assert!(content.ends_with("Draft: Paris."), "{}", content);
+            assert_eq!(answered_by["role"], "reasoner", "{}", answered_by);
+            assert_eq!(target_calls, 0);
+
+            let (content, answered_by, _, target_calls) = answer_with_use_reasoner_answer("if_confident", 200, stream).await;
+            assert!(!content.contains("Draft"), "{}", content);
+            assert_eq!(answered_by["role"], "target", "{}", answered_by);
+            assert_eq!(target_calls, 1);
+        }
+    }
+
+    #[test]
+    fn confident_answers_must_stop_and_be_long_enough() {
+        assert!(!reasoner_answer_stands(UseReasonerAnswer::Never, 0, "Paris.", Some("stop")));
+        assert!(reasoner_answer_stands(UseReasonerAnswer::Always, 0, "Paris.", Some("length")));
+        assert!(!reasoner_answer_stands(UseReasonerAnswer::Always, 0, "  ", Some("stop")));
+        assert!(reasoner_answer_stands(UseReasonerAnswer::IfConfident, 6, " Paris. ", Some("stop")));
+        assert!(!reasoner_answer_stands(UseReasonerAnswer::IfConfident, 7, "Paris.", Some("stop")));
+        assert!(!reasoner_answer_stands(UseReasonerAnswer::IfConfident, 0, "Paris.", Some("length")));
+    }
+
+    #[tokio::test]
+    async fn bare_endpoint_overrides_get_the_completions_path_and_a_warning() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_openai_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.endpoints.completion_paths.deepseek = REASONER_PATH.to_string();
+        let (app, _) = testing::app(&config);
+
+        let bare = format!("{}/", upstream.uri());
+        let request = json!({"model": "deepthink", "messages": [{"role": "user", "content": "Hi"}]});
+        let (status, _, body) = testing::post(&app, "/v1/chat/completions", &[(DEEPSEEK_ENDPOINT_URL_HEADER, &bare)], request).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(testing::received(&upstream, REASONER_PATH).await.len(), 1);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let warning = &body["warnings"][0];
+        assert_eq!(warning["code"], "endpoint_normalized", "{}", body);
+        assert!(warning["message"].as_str().unwrap().contains(REASONER_PATH), "{}", warning);
+    }
+
+    #[tokio::test]
+    async fn vercel_ai_streams_keep_their_wire_format() {
+        let upstream = MockServer::start().await;
+        testing::mock_streaming_reasoner(&upstream).await;
+        let chunk = |choices: serde_json::Value, usage: serde_json::Value| {
+            json!({"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o", "choices": choices, "usage": usage})
+        };
+        let target = testing::sse(&[
+            chunk(json!([{"index": 0, "delta": {"role": "assistant", "content": "Par"}, "finish_reason": null}]), json!(null)),
+            chunk(json!([{"index": 0, "delta": {"content": "is."}, "finish_reason": "stop"}]), json!(null)),
+            chunk(json!([]), json!({"prompt_tokens": 30, "completion_tokens": 2, "total_tokens": 32})),
+        ]);
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(target, "text/event-stream"))
+            .mount(&upstream)
+            .await;
+        let (app, _) = testing::app(&testing::config(&upstream));
+
+        let request = json!({
+            "model": "deepthink",
+            "stream": true,
+            "stream_transport": "vercel-ai",
+            "stream_options": {"include_usage": true},
+            "messages": [{"role": "user", "content": "Capital of France?"}],
+        });
+        let (status, headers, body) = testing::post(&app, "/v1/chat/completions", &[], request.clone()).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(headers["x-vercel-ai-data-stream"], "v1");
+        insta::assert_snapshot!(body);
 
-        Ok(Json(openai_response).into_response())
+        let (status, _, body) = testing::post(&app, "/", &[], request).await;
+        assert_eq!(status, 400, "{}", body);
     }
 }
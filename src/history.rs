@@ -0,0 +1,150 @@
+//! Message-count caps on long conversations.
+//!
+//! Agents that accumulate hundreds of turns send ever larger requests. A
+//! mapping's `max_messages` caps the conversation before it reaches either
+//! phase: the system prompt is kept, the first user message too unless
+//! `keep_first_user_message` is off, and the rest of the budget goes to the
+//! most recent messages. What was dropped is reported back in a
+//! [`TrimReport`] so clients learn to trim on their side.
+
+use crate::{
+    models::{Message, Role},
+    tokens,
+};
+use serde::{Deserialize, Serialize};
+
+/// What trimming removed from a conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrimReport {
+    /// Messages dropped from the middle of the conversation.
+    pub dropped_messages: u32,
+    /// Estimated prompt tokens of the dropped messages.
+    pub estimated_tokens_saved: u32,
+}
+
+/// Trims `messages` to at most `max_messages`, keeping their order.
+///
+/// System messages are always kept, and so is the first user message when
+/// `keep_first_user` is set; if those alone exceed the cap they are all kept
+/// and only the other messages are dropped. Tool results whose assistant
+/// tool call was dropped are dropped as well, so no upstream sees an
+/// orphaned tool result.
+///
+/// # Returns
+///
+/// * `Option<TrimReport>` - What was dropped, or `None` if nothing was
+pub fn trim(messages: &mut Vec<Message>, max_messages: usize, keep_first_user: bool) -> Option<TrimReport> {
+    if messages.len() <= max_messages {
+        return None;
+    }
+    let first_user = messages.iter().position(|message| message.role == Role::User).filter(|_| keep_first_user);
+    let pinned = |index: usize, message: &Message| message.role.is_system() || Some(index) == first_user;
+    let pinned_count = messages.iter().enumerate().filter(|(index, message)| pinned(*index, message)).count();
+
+    // 从末尾向前保留最近的消息, 直到用完 max_messages 中除固定消息以外的名额
+    let mut recent_budget = max_messages.saturating_sub(pinned_count);
+    let mut keep = vec![false; messages.len()];
+    for index in (0..messages.len()).rev() {
+        if pinned(index, &messages[index]) {
+            keep[index] = true;
+        } else if recent_budget > 0 {
+            keep[index] = true;
+            recent_budget -= 1;
+        }
+    }
+    // 保留区开头的工具结果对应的工具调用已被丢弃, 一并丢弃
+    if let Some(start) = (0..messages.len()).find(|&index| keep[index] && !pinned(index, &messages[index])) {
+        for index in start..messages.len() {
+            if messages[index].role != Role::Tool || pinned(index, &messages[index]) {
+                break;
+            }
+            keep[index] = false;
+        }
+    }
+
+    let mut dropped = Vec::new();
+    let mut kept = Vec::with_capacity(max_messages);
+    for (message, keep) in messages.drain(..).zip(keep) {
+        if keep {
+            kept.push(message);
+        } else {
+            dropped.push(message);
+        }
+    }
+    *messages = kept;
+    if dropped.is_empty() {
+        return None;
+    }
+    Some(TrimReport {
+        dropped_messages: dropped.len() as u32,
+        // 不计入回复前缀, 只统计被丢弃消息本身
+        estimated_tokens_saved: tokens::estimate_messages(&dropped).saturating_sub(tokens::estimate_messages(&[])),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message { role, content: content.into(), tool_calls: None, tool_call_id: None }
+    }
+
+    fn contents(messages: &[Message]) -> Vec<String> {
+        messages.iter().map(|message| message.content.to_text()).collect()
+    }
+
+    /// A system prompt followed by `turns` user/assistant pairs.
+    fn conversation(turns: usize) -> Vec<Message> {
+        let mut messages = vec![message(Role::System, "sys")];
+        for turn in 0..turns {
+            messages.push(message(Role::User, &format!("u{}", turn)));
+            messages.push(message(Role::Assistant, &format!("a{}", turn)));
+        }
+        messages
+    }
+
+    #[test]
+    fn short_conversations_are_left_alone() {
+        let mut messages = conversation(2);
+        assert_eq!(trim(&mut messages, 5, true), None);
+        assert_eq!(messages.len(), 5);
+    }
+
+    #[test]
+    fn the_system_prompt_first_user_message_and_latest_turns_are_kept_in_order() {
+        let mut messages = conversation(5);
+        let dropped = messages[2..8].to_vec();
+        let report = trim(&mut messages, 5, true).unwrap();
+        assert_eq!(contents(&messages), ["sys", "u0", "a3", "u4", "a4"]);
+        assert_eq!(report.dropped_messages, 6);
+        assert_eq!(report.estimated_tokens_saved, tokens::estimate_messages(&dropped) - tokens::estimate_messages(&[]));
+
+        let mut messages = conversation(5);
+        let report = trim(&mut messages, 5, false).unwrap();
+        assert_eq!(contents(&messages), ["sys", "u3", "a3", "u4", "a4"]);
+        assert_eq!(report.dropped_messages, 6);
+    }
+
+    #[test]
+    fn pinned_messages_are_kept_past_the_cap() {
+        let mut messages = vec![message(Role::System, "sys"), message(Role::Developer, "dev")];
+        messages.extend(conversation(2).into_iter().skip(1));
+        let report = trim(&mut messages, 2, true).unwrap();
+        assert_eq!(contents(&messages), ["sys", "dev", "u0"]);
+        assert_eq!(report.dropped_messages, 3);
+    }
+
+    #[test]
+    fn tool_results_are_not_kept_without_their_call() {
+        let mut messages = conversation(1);
+        messages.push(message(Role::Assistant, "calling"));
+        messages.push(message(Role::Tool, "result 1"));
+        messages.push(message(Role::Tool, "result 2"));
+        messages.push(message(Role::Assistant, "done"));
+        let report = trim(&mut messages, 5, true).unwrap();
+        // 保留区从两个工具结果开始, 它们对应的调用已被丢弃
+        assert_eq!(contents(&messages), ["sys", "u0", "done"]);
+        assert_eq!(report.dropped_messages, 4);
+    }
+}
@@ -0,0 +1,113 @@
+//! Replay of non-streaming responses by client-supplied idempotency key.
+//!
+//! A client retrying a request with the same `Idempotency-Key` header gets
+//! the response generated the first time, including its original id, instead
+//! of paying for a second run through both models. Entries are scoped to
+//! the caller and the request body, so one caller can never read another's
+//! response, and reusing a key with a different body runs the new request.
+
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+/// Header carrying the client's idempotency key.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Returns the cache key of a request sent with idempotency key `key`.
+///
+/// The key combines the route, since the native and compat routes return
+/// differently shaped bodies, with a hash of the caller's credentials and
+/// of the request body.
+pub fn cache_key(route: &str, caller: &str, key: &str, body: &serde_json::Value) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_bytes());
+    hasher.update([0]);
+    hasher.update(body.to_string().as_bytes());
+    let digest: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}:{}:{}", route, key, digest)
+}
+
+/// Bounded in-memory store of response bodies keyed by idempotency key.
+///
+/// When full, the oldest entry is evicted first.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    bodies: HashMap<String, serde_json::Value>,
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    /// Creates a cache holding at most `capacity` responses; 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Returns the response stored under `key`, if any.
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.bodies.get(key).cloned()
+    }
+
+    /// Stores `body` under `key`, evicting the oldest entry when full.
+    pub fn insert(&self, key: String, body: serde_json::Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.bodies.insert(key.clone(), body).is_none() {
+            entries.order.push_back(key);
+        }
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.bodies.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn evicts_the_oldest_entry_when_full() {
+        let cache = ResponseCache::new(2);
+        cache.insert("a".to_string(), json!(1));
+        cache.insert("b".to_string(), json!(2));
+        cache.insert("c".to_string(), json!(3));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(json!(2)));
+        assert_eq!(cache.get("c"), Some(json!(3)));
+    }
+
+    #[test]
+    fn zero_capacity_disables_caching() {
+        let cache = ResponseCache::new(0);
+        cache.insert("a".to_string(), json!(1));
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_caller_and_body() {
+        let body = json!({"model": "m", "messages": []});
+        let key = cache_key("/", "token-a", "retry-1", &body);
+        assert_eq!(key, cache_key("/", "token-a", "retry-1", &body));
+        assert_ne!(key, cache_key("/", "token-b", "retry-1", &body));
+        assert_ne!(key, cache_key("/", "token-a", "retry-2", &body));
+        assert_ne!(key, cache_key("/v1/chat/completions", "token-a", "retry-1", &body));
+        assert_ne!(key, cache_key("/", "token-a", "retry-1", &json!({"model": "other", "messages": []})));
+        assert!(!key.contains("token-a"));
+    }
+}
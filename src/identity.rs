@@ -0,0 +1,116 @@
+//! Sources of response ids and timestamps.
+//!
+//! Handlers never call `Uuid::new_v4` or `Utc::now` directly; they go through
+//! the [`IdGenerator`] and [`Clock`] held in `AppState`. Production uses the
+//! random and system implementations, while tests can install the seeded and
+//! fixed ones to get byte-identical output across runs.
+
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Produces completion ids.
+pub trait IdGenerator: Send + Sync {
+    /// Returns a new id of the form `chatcmpl-...`.
+    fn completion_id(&self) -> String;
+
+    /// Returns a new tool call id of the form `call_...`.
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+    fn tool_call_id(&self) -> String;
+}
+
+/// Produces the timestamps stamped onto responses.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Generates random UUID-based ids.
+#[derive(Debug, Default)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn completion_id(&self) -> String {
+        format!("chatcmpl-{}", Uuid::new_v4())
+    }
+
+    fn tool_call_id(&self) -> String {
+        format!("call_{}", Uuid::new_v4().simple())
+    }
+}
+
+/// Generates `chatcmpl-{seed}-{n}` and `call_{seed}-{n}` ids from a shared
+/// counter, for reproducible runs.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct SeededIds {
+    seed: String,
+    next: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl SeededIds {
+    /// Creates a generator whose ids start at `chatcmpl-{seed}-0`.
+    pub fn new(seed: impl Into<String>) -> Self {
+        Self {
+            seed: seed.into(),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IdGenerator for SeededIds {
+    fn completion_id(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("chatcmpl-{}-{}", self.seed, n)
+    }
+
+    fn tool_call_id(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("call_{}-{}", self.seed, n)
+    }
+}
+
+/// Reads the system clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Always returns the same instant.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_runs_produce_identical_ids() {
+        let run = || {
+            let ids = SeededIds::new("test");
+            vec![ids.completion_id(), ids.tool_call_id(), ids.completion_id()]
+        };
+        assert_eq!(run(), run());
+        assert_eq!(run(), vec!["chatcmpl-test-0", "call_test-1", "chatcmpl-test-2"]);
+    }
+
+    #[test]
+    fn fixed_clock_does_not_advance() {
+        let instant = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = FixedClock(instant);
+        assert_eq!(clock.now(), clock.now());
+        assert_eq!(clock.now().timestamp(), 1_700_000_000);
+    }
+}
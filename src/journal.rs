@@ -0,0 +1,303 @@
+//! In-memory journal of recently completed chat requests.
+//!
+//! Each chat request gets an id, returned in the `X-DeepThink-Request-Id`
+//! response header, and once its response is ready a summary of it is kept
+//! in a ring buffer of `server.journal_size` entries: route, model mapping,
+//! target provider, status, phase timings, token totals and a truncated
+//! error message. Message content is never recorded. `GET /admin/requests`
+//! lists the newest entries and `GET /admin/requests/{id}` returns one, so
+//! an operator can see how the last requests fared without the logs.
+//!
+//! Streamed requests are recorded when their response starts, so their
+//! entries carry neither timings nor token totals.
+
+use crate::models::{ApiResponse, Timings, UsageStats};
+use axum::{
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use uuid::Uuid;
+
+/// Response header carrying the id of the request's journal entry.
+pub const REQUEST_ID_HEADER: &str = "X-DeepThink-Request-Id";
+
+/// Longest error message kept in an entry, in characters.
+const MAX_ERROR_CHARS: usize = 300;
+
+/// Entries returned by `GET /admin/requests` unless `limit` is given.
+const DEFAULT_LIST_LIMIT: usize = 50;
+
+/// Summary of one completed request.
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    pub id: String,
+    /// When the request was received.
+    pub received_at: DateTime<Utc>,
+    pub route: String,
+    /// Model mapping the request named, for the OpenAI-compatible routes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_provider: Option<String>,
+    pub stream: bool,
+    pub status: u16,
+    /// Time until the response started, in milliseconds.
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageStats>,
+    /// The error the request failed with, truncated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a handler learned about its request, filled in while it runs.
+#[derive(Debug, Default)]
+struct Details {
+    id: Option<String>,
+    mapping: Option<String>,
+    target_provider: Option<String>,
+    stream: bool,
+    timings: Option<Timings>,
+    usage: Option<UsageStats>,
+}
+
+/// Handle through which a handler adds details to its request's entry.
+///
+/// Inserted into the request extensions by [`record`]. As an extractor it
+/// yields an empty slot on routes without the middleware.
+#[derive(Debug, Clone, Default)]
+pub struct JournalSlot(Arc<Mutex<Details>>);
+
+impl<S: Send + Sync> FromRequestParts<S> for JournalSlot {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<JournalSlot>().cloned().unwrap_or_default())
+    }
+}
+
+impl JournalSlot {
+    /// Returns the id of the request, or `None` outside the journaled routes.
+    pub fn id(&self) -> Option<String> {
+        self.lock().id.clone()
+    }
+
+    /// Records the request's model mapping and whether it streams.
+    pub fn set_request(&self, mapping: Option<&str>, stream: bool) {
+        let mut details = self.lock();
+        details.mapping = mapping.map(str::to_string);
+        details.stream = stream;
+    }
+
+    /// Returns the model mapping and stream flag recorded by [`JournalSlot::set_request`].
+    pub fn request(&self) -> (Option<String>, bool) {
+        let details = self.lock();
+        (details.mapping.clone(), details.stream)
+    }
+
+    /// Records the timings, usage and target provider of a finished response.
+    pub fn set_response(&self, response: &ApiResponse) {
+        let mut details = self.lock();
+        details.target_provider = Some(response.target_provider.clone());
+        details.timings = Some(response.phase_timings.clone());
+        details.usage = Some(response.usage.clone());
+    }
+
+    /// Records the target provider and usage of a response relayed unparsed.
+    pub fn set_usage(&self, target_provider: &str, usage: &UsageStats) {
+        let mut details = self.lock();
+        details.target_provider = Some(target_provider.to_string());
+        details.usage = Some(usage.clone());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Details> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The message of an error response, attached to it for the journal.
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
+/// Filters of `GET /admin/requests`.
+#[derive(Debug, Default, Deserialize)]
+pub struct JournalQuery {
+    /// Only entries with at least this status, e.g. `500`.
+    pub min_status: Option<u16>,
+    /// Only entries of this model mapping.
+    pub mapping: Option<String>,
+    /// Only entries received at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Most entries returned; defaults to 50.
+    pub limit: Option<usize>,
+}
+
+/// Bounded ring buffer of the latest [`JournalEntry`]s.
+#[derive(Debug)]
+pub struct RequestJournal {
+    entries: Mutex<VecDeque<JournalEntry>>,
+    capacity: usize,
+}
+
+impl RequestJournal {
+    /// Creates a journal keeping the latest `capacity` entries; 0 disables it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Adds an entry, evicting the oldest once the journal is full.
+    pub fn insert(&self, entry: JournalEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the entries matching `query`, newest first.
+    pub fn list(&self, query: &JournalQuery) -> Vec<JournalEntry> {
+        self.lock()
+            .iter()
+            .rev()
+            .filter(|entry| query.min_status.is_none_or(|status| entry.status >= status))
+            .filter(|entry| query.mapping.is_none() || entry.mapping == query.mapping)
+            .filter(|entry| query.since.is_none_or(|since| entry.received_at >= since))
+            .take(query.limit.unwrap_or(DEFAULT_LIST_LIMIT))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the entry of request `id`, if it is still in the journal.
+    pub fn get(&self, id: &str) -> Option<JournalEntry> {
+        self.lock().iter().find(|entry| entry.id == id).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<JournalEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Middleware recording each request of the routes it wraps in the journal.
+///
+/// Must be added with `route_layer`, so the matched route is known.
+pub async fn record(State(journal): State<Arc<RequestJournal>>, mut request: Request, next: Next) -> Response {
+    let id = Uuid::new_v4().to_string();
+    let received_at = Utc::now();
+    let started = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let slot = JournalSlot::default();
+    slot.lock().id = Some(id.clone());
+    request.extensions_mut().insert(slot.clone());
+
+    let mut response = next.run(request).await;
+    let details = std::mem::take(&mut *slot.lock());
+    let error = response
+        .extensions()
+        .get::<ErrorMessage>()
+        .map(|ErrorMessage(message)| truncate(message));
+    journal.insert(JournalEntry {
+        id: id.clone(),
+        received_at,
+        route,
+        mapping: details.mapping,
+        target_provider: details.target_provider,
+        stream: details.stream,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        timings: details.timings,
+        usage: details.usage,
+        error,
+    });
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+fn truncate(message: &str) -> String {
+    match message.char_indices().nth(MAX_ERROR_CHARS) {
+        Some((end, _)) => format!("{}...", &message[..end]),
+        None => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(n: usize, status: u16, mapping: &str) -> JournalEntry {
+        JournalEntry {
+            id: format!("req-{}", n),
+            received_at: Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, n as u32).unwrap(),
+            route: "/v1/chat/completions".to_string(),
+            mapping: Some(mapping.to_string()),
+            target_provider: Some("openai".to_string()),
+            stream: false,
+            status,
+            duration_ms: 10,
+            timings: None,
+            usage: None,
+            error: None,
+        }
+    }
+
+    fn ids(entries: &[JournalEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.id.as_str()).collect()
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries_past_capacity() {
+        let journal = RequestJournal::new(3);
+        for n in 0..5 {
+            journal.insert(entry(n, 200, "deepthink"));
+        }
+        assert_eq!(ids(&journal.list(&JournalQuery::default())), ["req-4", "req-3", "req-2"]);
+        assert!(journal.get("req-1").is_none());
+        assert_eq!(journal.get("req-2").unwrap().id, "req-2");
+    }
+
+    #[test]
+    fn filters_by_status_mapping_and_time() {
+        let journal = RequestJournal::new(10);
+        journal.insert(entry(0, 200, "deepthink"));
+        journal.insert(entry(1, 502, "deepthink"));
+        journal.insert(entry(2, 500, "fast"));
+        journal.insert(entry(3, 400, "deepthink"));
+
+        let failed = journal.list(&JournalQuery { min_status: Some(500), ..Default::default() });
+        assert_eq!(ids(&failed), ["req-2", "req-1"]);
+        let mapping = journal.list(&JournalQuery { mapping: Some("deepthink".to_string()), limit: Some(2), ..Default::default() });
+        assert_eq!(ids(&mapping), ["req-3", "req-1"]);
+        let since = journal.list(&JournalQuery { since: Some(Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 2).unwrap()), ..Default::default() });
+        assert_eq!(ids(&since), ["req-3", "req-2"]);
+    }
+
+    #[test]
+    fn a_zero_capacity_journal_keeps_nothing() {
+        let journal = RequestJournal::new(0);
+        journal.insert(entry(0, 200, "deepthink"));
+        assert!(journal.list(&JournalQuery::default()).is_empty());
+    }
+}
@@ -8,17 +8,95 @@
 //! The API requires authentication tokens for both services and
 //! supports custom configuration through a TOML config file.
 
+mod admission;
+mod audit;
+mod budget;
+mod capabilities;
+mod circuit;
 mod clients;
+mod compression;
 mod config;
+mod connection;
+mod conversion;
+mod discovery;
+mod endpoints;
 mod error;
 mod handlers;
+mod history;
+mod idempotency;
+mod identity;
+mod metering;
+mod journal;
+mod metrics;
 mod models;
+mod negotiate;
+mod parameters;
+mod profiles;
+mod progress;
+mod ratelimit;
+mod redact;
+mod reasoning_redaction;
+mod replicas;
+mod resume;
+mod reuse;
+mod schema;
+mod signing;
+mod sink;
+mod smoke;
+#[cfg(test)]
+mod testing;
+mod speculation;
+mod tenants;
+mod think;
+mod throttle;
+mod timing;
+mod tokens;
+mod trace;
+mod vendor;
+mod version;
+mod warmup;
+mod warnings;
+mod watchdog;
+mod webhooks;
 
-use crate::{config::Config, handlers::AppState};
-use axum::routing::{post, Router};
-use std::{net::SocketAddr, sync::Arc};
+#[cfg(not(any(feature = "openai", feature = "anthropic")))]
+compile_error!("deepthink needs a target provider: enable the `openai` or `anthropic` feature");
+
+use crate::{
+    admission::AdmissionQueue,
+    budget::SpendLedger,
+    circuit::CircuitBreakers,
+    config::Config,
+    handlers::AppState,
+    idempotency::ResponseCache,
+    identity::{RandomIds, SystemClock},
+    journal::RequestJournal,
+    metrics::Metrics,
+    profiles::Profile,
+    progress::ProgressCallbacks,
+    ratelimit::UpstreamRateLimits,
+    reasoning_redaction::Redactor,
+    replicas::ReplicaRouter,
+    resume::StreamRegistry,
+    reuse::ReasoningStore,
+    signing::Signers,
+    speculation::SpeculationCache,
+    tenants::Tenants,
+    trace::TraceStore,
+    watchdog::StreamWatchdog,
+    webhooks::UsageWebhooks,
+};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{any, delete, get, post, Router},
+    Extension,
+};
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, sync::watch};
 use tower_http::{
     cors::{Any, CorsLayer},
+    decompression::RequestDecompressionLayer,
     trace::TraceLayer,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -26,7 +104,10 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 /// Application entry point.
 ///
 /// Sets up logging, loads configuration, and starts the HTTP server
-/// with the configured routes and middleware.
+/// with the configured routes and middleware. When `server.admin` is set,
+/// the admin routes are served by a second server on that address instead
+/// of the public one; both share the application state and stop together
+/// on Ctrl-C. `deepthink smoke` runs the smoke test instead of serving.
 ///
 /// # Returns
 ///
@@ -40,11 +121,15 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 /// - Server encounters a fatal error while running
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize logging
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let smoke = args.first().is_some_and(|command| command == "smoke");
+
+    // Initialize logging; 冒烟测试只输出警告, 避免淹没结果
+    let default_filter = if smoke { "deepthink=warn" } else { "deepthink=debug,tower_http=debug" };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "deepthink=debug,tower_http=debug".into()),
+                .unwrap_or_else(|_| default_filter.into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
@@ -55,38 +140,334 @@ async fn main() -> anyhow::Result<()> {
         Config::default()
     });
 
+    redact::init(config.logging.log_content);
+
+    let default_provider = config.models.default_target_provider;
+    if !default_provider.is_compiled() {
+        tracing::warn!(
+            "models.default_target_provider = \"{}\" is not compiled in; requests without X-Target-Model will fail until deepthink is built with the `{}` feature",
+            default_provider.as_str(),
+            default_provider.feature()
+        );
+    }
+
+    if smoke {
+        return smoke::run(config, &args[1..]).await;
+    }
+
+    let state = app_state(&config);
+    let spend_state = state.clone();
+    let (app, admin_app) = routers(&config, state);
+
+    // Get host and port from config
+    let addr: SocketAddr = format!("{}:{}", config.server.host, config.server.port)
+        .parse()
+        .expect("Invalid host/port configuration");
+
+    tracing::info!("Starting deepthink {} on {}", version::FULL_VERSION, addr);
+
+    // Ctrl-C 时通知所有监听同时退出
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("Shutdown signal received");
+        }
+        let _ = shutdown_tx.send(true);
+    });
+    // 定期把花费记录写入 sqlite, 服务退出后再写一次
+    if config.budget.store_path.is_some() {
+        let spend_state = spend_state.clone();
+        let mut shutdown = shutdown_rx.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(config.budget.flush_interval_secs.max(1)));
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                }
+                let state = spend_state.clone();
+                let _ = tokio::task::spawn_blocking(move || state.spend.flush()).await;
+            }
+        });
+    }
+
+    let admin = match (admin_app, &config.server.admin) {
+        (Some(admin_app), Some(admin)) => {
+            let admin_addr: SocketAddr = format!("{}:{}", admin.host, admin.port)
+                .parse()
+                .expect("Invalid admin host/port configuration");
+            tracing::info!("Starting admin server on {}", admin_addr);
+            Some((TcpListener::bind(&admin_addr).await?, admin_app))
+        }
+        _ => None,
+    };
+
+    // Start server
+    serve(TcpListener::bind(&addr).await?, app, admin, shutdown_rx).await?;
+    let _ = tokio::task::spawn_blocking(move || spend_state.spend.flush()).await;
+
+    Ok(())
+}
+
+/// Serves `app` on `public` and, when given, the admin router on its own
+/// listener, until `shutdown` is set.
+///
+/// # Errors
+///
+/// Returns the first error either server stops with.
+async fn serve(
+    public: TcpListener,
+    app: Router,
+    admin: Option<(TcpListener, Router)>,
+    shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let public = axum::serve(public, app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal(shutdown.clone()));
+    match admin {
+        Some((listener, admin_app)) => {
+            let admin = axum::serve(listener, admin_app.into_make_service())
+                .with_graceful_shutdown(shutdown_signal(shutdown));
+            tokio::try_join!(public.into_future(), admin.into_future())?;
+            Ok(())
+        }
+        None => public.await,
+    }
+}
+
+/// Resolves once the shutdown flag is set or its sender is dropped.
+async fn shutdown_signal(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// Builds the state shared by every request handler.
+fn app_state(config: &Config) -> Arc<AppState> {
     // Create application state
     // Clone config for AppState
     let config_clone = config.clone();
-    let state = Arc::new(AppState { config: config_clone });
+    let circuits = Arc::new(CircuitBreakers::new(
+        config.circuit_breaker.failure_threshold,
+        Duration::from_secs(config.circuit_breaker.cooldown_secs),
+    ));
+    let metrics = Arc::new(Metrics::default());
+    Arc::new(AppState {
+        config: config_clone,
+        http: connection::client(),
+        metrics: metrics.clone(),
+        ids: Arc::new(RandomIds),
+        clock: Arc::new(SystemClock),
+        response_cache: ResponseCache::new(config.server.idempotency_cache_size),
+        spend: config.budget.store_path.as_deref().map(SpendLedger::open).unwrap_or_default(),
+        admission: Arc::new(AdmissionQueue::new(
+            config.server.max_concurrent_requests,
+            Duration::from_secs(config.server.priority_aging_secs),
+        )),
+        streams: StreamRegistry::new(
+            config.streaming.resume_buffer_frames,
+            Duration::from_secs(config.streaming.resume_ttl_secs),
+        ),
+        rate_limits: Arc::new(UpstreamRateLimits::new(circuits.clone())),
+        circuits,
+        profiles: config
+            .profiles
+            .iter()
+            .map(|(name, profile)| (name.clone(), Arc::new(Profile::new(name.clone(), profile.clone()))))
+            .collect(),
+        speculation: Arc::new(SpeculationCache::new(
+            config.speculation.max_concurrent,
+            Duration::from_secs(config.speculation.ttl_secs),
+        )),
+        reasoning_store: ReasoningStore::new(Duration::from_secs(config.reasoning.reuse_ttl_secs)),
+        watchdog: StreamWatchdog::new(config.streaming.max_stream_duration(), metrics),
+        journal: Arc::new(RequestJournal::new(config.server.journal_size)),
+        traces: TraceStore::new(config.server.trace_store_size),
+        // Config::load 已校验过规则, 这里只会遇到代码中构造的配置
+        redactor: Redactor::compile(&config.redaction).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring the redaction rules: {}", e);
+            Redactor::default()
+        }),
+        replicas: ReplicaRouter::default(),
+        tenants: Tenants::new(config),
+        // 同样已由 Config::load 校验
+        signers: Signers::from_config(&config.signing).unwrap_or_else(|e| {
+            tracing::warn!("Sending the upstream requests unsigned: {}", e);
+            Signers::default()
+        }),
+        // 同样已由 Config::load 校验
+        webhooks: UsageWebhooks::start(&config.usage_webhooks, connection::client()).unwrap_or_else(|e| {
+            tracing::warn!("Not sending usage events: {}", e);
+            UsageWebhooks::default()
+        }),
+        progress: ProgressCallbacks::new(&config.progress_callbacks, connection::client()).unwrap_or_else(|e| {
+            tracing::warn!("Not posting progress callbacks: {}", e);
+            ProgressCallbacks::default()
+        }),
+    })
+}
 
+/// Builds the public router and, when `server.admin` is set, the separate admin router.
+fn routers(config: &Config, state: Arc<AppState>) -> (Router, Option<Router>) {
     // Set up CORS
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_headers(Any)
         .allow_origin(Any);
 
-    // Build router
-    let app = Router::new()
+    // 管理路由: 配置了 server.admin 时由独立的监听地址提供
+    // /admin/* 路由配置了 server.admin_token 时需要认证
+    let admin_routes = Router::new()
+        .route("/admin/streams", get(handlers::handle_active_streams))
+        .route("/admin/streams/{id}", delete(handlers::handle_stream_kill))
+        .route("/admin/requests", get(handlers::handle_journal))
+        .route("/admin/requests/{id}", get(handlers::handle_journal_entry))
+        .route("/admin/traces/{id}", get(handlers::handle_trace))
+        .route_layer(middleware::from_fn_with_state(state.clone(), handlers::require_admin_token))
+        .route("/metrics", get(handlers::handle_metrics))
+        .route("/health", get(handlers::handle_health));
+
+    // chat 路由的请求写入审计日志并记录到请求日志中; 失败的请求在日志中间件之内发送用量事件
+    let record = middleware::from_fn_with_state(state.journal.clone(), journal::record);
+    let audit = middleware::from_fn_with_state(state.clone(), audit::record);
+    let report_failures = middleware::from_fn_with_state(state.clone(), handlers::report_failures);
+    let chat_routes = Router::new()
         .route("/", post(handlers::handle_chat))
         .route("/v1/chat/completions", post(handlers::handle_openai_chat))
+        .route("/v1/completions", post(handlers::handle_completions))
+        .route_layer(audit.clone())
+        .route_layer(report_failures.clone())
+        .route_layer(record.clone());
+
+    // Build router
+    let mut app = chat_routes
+        .route("/version", get(handlers::handle_version))
+        .route("/v1/embeddings", post(handlers::handle_embeddings))
+        .route("/v1/token_count", post(handlers::handle_token_count))
+        .route("/v1/models", get(handlers::handle_models))
+        .route("/v1/models/{*id}", get(handlers::handle_model))
+        .route(
+            "/v1/streams/{id}",
+            get(handlers::handle_stream_resume).delete(handlers::handle_stream_delete),
+        )
+        .route("/v1/audio/transcriptions", any(handlers::handle_unsupported))
+        .route("/v1/audio/translations", any(handlers::handle_unsupported))
+        .route("/v1/audio/speech", any(handlers::handle_unsupported))
+        .route("/v1/images/generations", any(handlers::handle_unsupported))
+        .route("/v1/images/edits", any(handlers::handle_unsupported))
+        .route("/v1/images/variations", any(handlers::handle_unsupported))
+        .route("/v1/moderations", any(handlers::handle_unsupported));
+
+    // 每个 profile 在自己的路由前缀下再挂载一份 chat 路由
+    for profile in state.profiles.values() {
+        let prefix = profile.config.route_prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            tracing::warn!("Skipping profile {}: route_prefix must start with '/' and not be the root", profile.name);
+            continue;
+        }
+        tracing::info!("Serving profile {} under {}", profile.name, prefix);
+        app = app.nest(
+            prefix,
+            Router::new()
+                .route("/v1/chat/completions", post(handlers::handle_openai_chat))
+                .route_layer(audit.clone())
+                .route_layer(report_failures.clone())
+                .route_layer(record.clone())
+                .layer(Extension(profile.clone())),
+        );
+    }
+    let admin_app = match &config.server.admin {
+        Some(_) => Some(
+            admin_routes
+                .layer(middleware::map_response(handlers::add_version_header))
+                .layer(TraceLayer::new_for_http())
+                .with_state(state.clone()),
+        ),
+        None => {
+            app = app.merge(admin_routes);
+            None
+        }
+    };
+    // 压缩的请求体先解压, 再按解压后的大小限制
+    let max_body_bytes = config.server.max_body_bytes;
+    let app = app
+        .layer(middleware::from_fn_with_state(max_body_bytes, compression::measure_decompressed))
+        .layer(RequestDecompressionLayer::new())
+        .layer(middleware::from_fn(compression::mark_compressed))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(middleware::map_response(handlers::add_version_header))
         .layer(TraceLayer::new_for_http())
         .layer(cors)
         .with_state(state);
+    (app, admin_app)
+}
 
-    // Get host and port from config
-    let addr: SocketAddr = format!("{}:{}", config.server.host, config.server.port)
-        .parse()
-        .expect("Invalid host/port configuration");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AdminServerConfig;
 
-    tracing::info!("Starting server on {}", addr);
+    /// Binds a listener on an ephemeral port and returns it with its base URL.
+    async fn ephemeral_listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
 
-    // Start server
-    axum::serve(
-        tokio::net::TcpListener::bind(&addr).await?,
-        app.into_make_service(),
-    )
-    .await?;
+    #[tokio::test]
+    async fn admin_routes_move_to_their_own_listener_and_stop_with_the_public_one() {
+        let mut config = Config::default();
+        config.server.admin = Some(AdminServerConfig { host: "127.0.0.1".to_string(), port: 0 });
+        let (app, admin_app) = routers(&config, app_state(&config));
+        let (public, public_url) = ephemeral_listener().await;
+        let (admin, admin_url) = ephemeral_listener().await;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(public, app, Some((admin, admin_app.unwrap())), shutdown_rx));
 
-    Ok(())
+        let client = reqwest::Client::new();
+        let status = |url: String| {
+            let client = client.clone();
+            async move { client.get(url).send().await.unwrap().status() }
+        };
+        assert_eq!(status(format!("{}/metrics", admin_url)).await, 200);
+        assert_eq!(status(format!("{}/metrics", public_url)).await, 404);
+        assert_eq!(status(format!("{}/v1/moderations", public_url)).await, 501);
+        assert_eq!(status(format!("{}/v1/moderations", admin_url)).await, 404);
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("both servers stop on shutdown")
+            .unwrap()
+            .unwrap();
+        assert!(client.get(format!("{}/metrics", admin_url)).send().await.is_err());
+    }
+
+    #[test]
+    fn admin_routes_stay_public_without_an_admin_listener() {
+        let config = Config::default();
+        let (_, admin_app) = routers(&config, app_state(&config));
+        assert!(admin_app.is_none());
+    }
+
+    #[tokio::test]
+    async fn every_supported_route_is_served() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let config = Config::default();
+        let app = routers(&config, app_state(&config)).0;
+        for route in error::SUPPORTED_ROUTES {
+            let (method, path) = route.split_once(' ').unwrap();
+            let request = Request::builder()
+                .method(method)
+                .uri(path.replace("{id}", "unknown"))
+                .header("content-type", "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            // 未注册的路由返回空的 404 或 405, 处理函数的 404 带有错误体
+            assert_ne!(status, axum::http::StatusCode::METHOD_NOT_ALLOWED, "{}", route);
+            assert!(status != axum::http::StatusCode::NOT_FOUND || !body.is_empty(), "{} is not routed", route);
+        }
+    }
 }
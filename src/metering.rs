@@ -0,0 +1,118 @@
+//! Usage of upstream responses relayed to the client unparsed.
+//!
+//! Passthrough chat requests and embeddings are forwarded byte for byte, so
+//! their usage is read off the body as it passes through: the last `usage`
+//! object of an SSE stream, or the `usage` of a JSON body.
+
+use crate::models::UsageStats;
+use serde_json::Value;
+
+/// Largest JSON body buffered for its usage; larger bodies are not charged.
+const MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// Collects the usage of a relayed response from its body chunks.
+#[derive(Debug, Default)]
+pub struct UsageScanner {
+    /// The incomplete SSE line at the end of the last chunk.
+    line: Vec<u8>,
+    /// The whole body, while it might still be a JSON document.
+    body: Vec<u8>,
+    /// Whether a `data:` line was seen, making the body an SSE stream.
+    sse: bool,
+    /// The model and usage of the last chunk carrying a usage object.
+    found: Option<(String, UsageStats)>,
+}
+
+impl UsageScanner {
+    /// Scans the next chunk of the body.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if !self.sse && self.body.len() + bytes.len() <= MAX_BUFFERED_BYTES {
+            self.body.extend_from_slice(bytes);
+        }
+        for part in bytes.split_inclusive(|b| *b == b'\n') {
+            self.line.extend_from_slice(part);
+            if part.ends_with(b"\n") {
+                let line = std::mem::take(&mut self.line);
+                self.scan_line(&line);
+            }
+        }
+    }
+
+    /// Returns the upstream model and usage of the body, if it reported any.
+    pub fn finish(mut self) -> Option<(String, UsageStats)> {
+        let line = std::mem::take(&mut self.line);
+        self.scan_line(&line);
+        if self.sse {
+            return self.found;
+        }
+        serde_json::from_slice(&self.body).ok().and_then(|body: Value| usage_of(&body))
+    }
+
+    fn scan_line(&mut self, line: &[u8]) {
+        let Some(data) = std::str::from_utf8(line).ok().and_then(|line| line.trim_end().strip_prefix("data:")) else {
+            return;
+        };
+        self.sse = true;
+        self.body = Vec::new();
+        // 只解析带用量的数据帧, 通常是流的最后一帧
+        if !data.contains("\"usage\"") {
+            return;
+        }
+        if let Some(found) = serde_json::from_str(data.trim_start()).ok().and_then(|chunk: Value| usage_of(&chunk)) {
+            self.found = Some(found);
+        }
+    }
+}
+
+/// Reads the model and OpenAI-style usage object of a response or chunk.
+fn usage_of(body: &Value) -> Option<(String, UsageStats)> {
+    let usage = body.get("usage").filter(|usage| usage.is_object())?;
+    let count = |value: Option<&Value>| value.and_then(Value::as_u64).unwrap_or(0) as u32;
+    let mut stats = UsageStats::default();
+    stats.add(count(usage.get("prompt_tokens")), count(usage.get("completion_tokens")));
+    let model = body.get("model").and_then(Value::as_str).unwrap_or_default();
+    Some((model.to_string(), stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(chunks: &[&str]) -> Option<(String, UsageStats)> {
+        let mut scanner = UsageScanner::default();
+        for chunk in chunks {
+            scanner.feed(chunk.as_bytes());
+        }
+        scanner.finish()
+    }
+
+    #[test]
+    fn reads_the_usage_of_a_json_body_split_across_chunks() {
+        let (model, usage) = scan(&[
+            r#"{"model":"gpt-4o","choices":[],"usage":{"prompt_tokens":12,"#,
+            r#""completion_tokens":5}}"#,
+        ])
+        .unwrap();
+        assert_eq!(model, "gpt-4o");
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 5));
+    }
+
+    #[test]
+    fn reads_the_final_usage_chunk_of_a_stream() {
+        let (model, usage) = scan(&[
+            "data: {\"model\":\"gpt-4o\",\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n",
+            "data: {\"model\":\"gpt-4o\",\"choices\":[],\"usage\":{\"prompt_",
+            "tokens\":7,\"completion_tokens\":3}}\n\ndata: [DONE]\n\n",
+        ])
+        .unwrap();
+        assert_eq!(model, "gpt-4o");
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (7, 3));
+    }
+
+    #[test]
+    fn bodies_without_usage_are_not_charged() {
+        assert!(scan(&["data: {\"choices\":[]}\n\ndata: [DONE]\n\n"]).is_none());
+        assert!(scan(&["{\"error\":{\"message\":\"bad\"}}"]).is_none());
+        assert!(scan(&["{\"usage\":null}"]).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Process-wide counters for the streaming pipeline.
+//!
+//! Counters are plain atomics updated from the stream tasks and exposed
+//! as a JSON snapshot on the `/metrics` route.
+
+use crate::{
+    circuit::CircuitStatus, clients::AccountErrorStats, compression::CompressionStats, connection::HostConnectionStats, ratelimit::RateLimitGauge,
+    replicas::ReplicaGauge, speculation::SpeculationStats, webhooks::WebhookStats,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters shared by all request handlers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    coalesced_reasoning_frames: AtomicU64,
+    consumer_disconnects: AtomicU64,
+    dropped_stream_frames: AtomicU64,
+    relayed_responses: AtomicU64,
+    unmetered_responses: AtomicU64,
+    truncated_reasonings: AtomicU64,
+    aborted_streams: AtomicU64,
+}
+
+/// Point-in-time copy of the counters in [`Metrics`].
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub coalesced_reasoning_frames: u64,
+    pub consumer_disconnects: u64,
+    pub dropped_stream_frames: u64,
+    /// Successful passthrough and embeddings responses relayed unparsed.
+    pub relayed_responses: u64,
+    /// Relayed responses that reported no usage and were not charged.
+    pub unmetered_responses: u64,
+    /// Reasoner responses that stopped at their `max_tokens`.
+    pub truncated_reasonings: u64,
+    /// Stream tasks aborted by the watchdog, at their deadline or by an operator.
+    pub aborted_streams: u64,
+    /// Requests waiting for admission, per priority class.
+    pub queue_depth: BTreeMap<u8, usize>,
+    /// Upstream circuits that have seen connection failures.
+    pub circuits: Vec<CircuitStatus>,
+    /// Rate limits the upstreams advertised in their latest response headers.
+    pub upstream_rate_limits: Vec<RateLimitGauge>,
+    /// Speculative reasoning runs and cache use.
+    pub speculation: SpeculationStats,
+    /// Spend on speculative reasoning, in USD.
+    pub speculative_spend_usd: f64,
+    /// Compressed request bodies and upstream responses, and the bytes they saved.
+    pub compression: CompressionStats,
+    /// DNS and TTFB histograms of each upstream host.
+    pub upstream_connections: Vec<HostConnectionStats>,
+    /// Requests in flight on each reasoner replica.
+    pub reasoner_replicas: Vec<ReplicaGauge>,
+    /// Usage webhook deliveries, failures and queued events.
+    pub usage_webhooks: WebhookStats,
+    /// Insufficient credit and invalid key responses of each provider.
+    pub upstream_account_errors: Vec<AccountErrorStats>,
+}
+
+impl Metrics {
+    /// Records a reasoning delta that was merged into a later frame under backpressure.
+    pub fn record_coalesced_frame(&self) {
+        self.coalesced_reasoning_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a stream that stopped because its SSE consumer went away.
+    pub fn record_consumer_disconnect(&self) {
+        self.consumer_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records upstream stream frames skipped because they could not be parsed.
+    pub fn record_dropped_frames(&self, count: u64) {
+        self.dropped_stream_frames.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records a successful upstream response relayed unparsed.
+    pub fn record_relayed_response(&self, metered: bool) {
+        self.relayed_responses.fetch_add(1, Ordering::Relaxed);
+        if !metered {
+            self.unmetered_responses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a reasoner response that stopped at its `max_tokens`.
+    pub fn record_truncated_reasoning(&self) {
+        self.truncated_reasonings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a stream task aborted by the watchdog.
+    pub fn record_aborted_stream(&self) {
+        self.aborted_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of every counter.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            coalesced_reasoning_frames: self.coalesced_reasoning_frames.load(Ordering::Relaxed),
+            consumer_disconnects: self.consumer_disconnects.load(Ordering::Relaxed),
+            dropped_stream_frames: self.dropped_stream_frames.load(Ordering::Relaxed),
+            relayed_responses: self.relayed_responses.load(Ordering::Relaxed),
+            unmetered_responses: self.unmetered_responses.load(Ordering::Relaxed),
+            truncated_reasonings: self.truncated_reasonings.load(Ordering::Relaxed),
+            aborted_streams: self.aborted_streams.load(Ordering::Relaxed),
+            queue_depth: BTreeMap::new(),
+            circuits: Vec::new(),
+            upstream_rate_limits: Vec::new(),
+            speculation: SpeculationStats::default(),
+            speculative_spend_usd: 0.0,
+            compression: CompressionStats::default(),
+            upstream_connections: Vec::new(),
+            reasoner_replicas: Vec::new(),
+            usage_webhooks: WebhookStats::default(),
+            upstream_account_errors: Vec::new(),
+        }
+    }
+}
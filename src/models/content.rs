@@ -0,0 +1,397 @@
+//! Message content models and per-provider conversion.
+//!
+//! Message content arrives either as a plain string or as an array of typed
+//! parts, in OpenAI (`image_url`) or Anthropic (`image`, `tool_result`) form.
+//! Before a request reaches a target the parts are converted into the form
+//! that provider accepts; the reasoner always receives a text flattening.
+
+use super::request::{Message, Role};
+use super::tools::to_anthropic_messages;
+use crate::error::{ApiError, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The content of a message: plain text or a list of typed parts.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// One typed part of a message's content.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    /// Plain text, in both OpenAI and Anthropic form.
+    Text { text: String },
+    /// An OpenAI image part.
+    ImageUrl { image_url: ImageUrl },
+    /// An Anthropic image block.
+    Image { source: ImageSource },
+    /// An Anthropic tool use block, in an assistant turn.
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    /// An Anthropic tool result block.
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+        content: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+    /// Any part type this server does not understand, kept verbatim so it can
+    /// be reported by type.
+    #[serde(untagged)]
+    Unsupported(serde_json::Value),
+}
+
+/// The `image_url` payload of an OpenAI image part.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// The `source` of an Anthropic image block.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ImageSource {
+    /// `base64` or `url`.
+    #[serde(rename = "type")]
+    pub source_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Provider whose content format a message list is converted into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentTarget {
+    OpenAI,
+    Anthropic,
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        MessageContent::Text(String::new())
+    }
+}
+
+/// Deserializes message content, treating `null` (as sent for assistant
+/// turns that only carry `tool_calls`) as empty text.
+pub(crate) fn deserialize_nullable_content<'de, D>(deserializer: D) -> std::result::Result<MessageContent, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<MessageContent>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::Text(text.to_string())
+    }
+}
+
+impl MessageContent {
+    /// Flattens the content to text, replacing non-text parts with placeholders
+    /// such as `[image]`.
+    pub fn to_text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(ContentPart::to_text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl ContentPart {
+    /// Returns the part's `type` tag, as it appeared in the request.
+    pub fn type_name(&self) -> String {
+        match self {
+            ContentPart::Text { .. } => "text".to_string(),
+            ContentPart::ImageUrl { .. } => "image_url".to_string(),
+            ContentPart::Image { .. } => "image".to_string(),
+            ContentPart::ToolUse { .. } => "tool_use".to_string(),
+            ContentPart::ToolResult { .. } => "tool_result".to_string(),
+            ContentPart::Unsupported(value) => value
+                .get("type")
+                .and_then(|t| t.as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+        }
+    }
+
+    /// Flattens the part to text, using a placeholder for non-text parts.
+    pub fn to_text(&self) -> String {
+        match self {
+            ContentPart::Text { text } => text.clone(),
+            ContentPart::ImageUrl { .. } | ContentPart::Image { .. } => "[image]".to_string(),
+            ContentPart::ToolUse { id, name, input } => format!("[tool_call {} {}]\n{}", id, name, input),
+            ContentPart::ToolResult { tool_use_id, content, .. } => {
+                format!("[tool_result {}]\n{}", tool_use_id, tool_result_text(content))
+            }
+            ContentPart::Unsupported(_) => format!("[{}]", self.type_name()),
+        }
+    }
+
+    /// Converts the part into the form `target` accepts, or `None` if it has
+    /// no representation there (unknown types, tool results outside a user
+    /// turn, or tool use outside an Anthropic assistant turn).
+    fn convert(self, role: &Role, target: ContentTarget) -> Option<Self> {
+        match (self, target) {
+            (ContentPart::Unsupported(_), _) => None,
+            (ContentPart::ToolResult { .. }, _) if *role != Role::User => None,
+            (part @ ContentPart::ToolUse { .. }, ContentTarget::Anthropic) if *role == Role::Assistant => Some(part),
+            (ContentPart::ToolUse { .. }, _) => None,
+            (part @ ContentPart::Text { .. }, _) => Some(part),
+
+            (part @ ContentPart::ImageUrl { .. }, ContentTarget::OpenAI) => Some(part),
+            (ContentPart::Image { source }, ContentTarget::OpenAI) => {
+                let url = match source.source_type.as_str() {
+                    "base64" => format!(
+                        "data:{};base64,{}",
+                        source.media_type.as_deref().unwrap_or("image/png"),
+                        source.data?
+                    ),
+                    "url" => source.url?,
+                    _ => return None,
+                };
+                Some(ContentPart::ImageUrl {
+                    image_url: ImageUrl { url, detail: None },
+                })
+            }
+            // OpenAI 目标没有与之配对的 tool_calls, 以文本上下文形式传递工具结果
+            (part @ ContentPart::ToolResult { .. }, ContentTarget::OpenAI) => Some(ContentPart::Text {
+                text: part.to_text(),
+            }),
+
+            (part @ ContentPart::Image { .. }, ContentTarget::Anthropic) => Some(part),
+            (part @ ContentPart::ToolResult { .. }, ContentTarget::Anthropic) => Some(part),
+            (ContentPart::ImageUrl { image_url }, ContentTarget::Anthropic) => {
+                let url = image_url.url;
+                let source = match url.strip_prefix("data:") {
+                    Some(data_url) => {
+                        let (media_type, data) = data_url.split_once(";base64,")?;
+                        ImageSource {
+                            source_type: "base64".to_string(),
+                            media_type: Some(media_type.to_string()),
+                            data: Some(data.to_string()),
+                            url: None,
+                        }
+                    }
+                    None => ImageSource {
+                        source_type: "url".to_string(),
+                        media_type: None,
+                        data: None,
+                        url: Some(url.clone()),
+                    },
+                };
+                Some(ContentPart::Image { source })
+            }
+        }
+    }
+}
+
+impl Message {
+    /// Returns a copy of the message with its content flattened to text.
+    ///
+    /// Tool calls are appended to the text and `tool` messages become user
+    /// turns, so the result can be sent to models without tool support.
+    pub fn flattened(&self) -> Message {
+        let mut text = self.content.to_text();
+        for call in self.tool_calls.iter().flatten() {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&call.to_text());
+        }
+        let (role, text) = match (&self.role, &self.tool_call_id) {
+            (Role::Tool, Some(id)) => (Role::User, format!("[tool_result {}]\n{}", id, text)),
+            (Role::Tool, None) => (Role::User, text),
+            (role, _) => (role.clone(), text),
+        };
+        Message {
+            role,
+            content: MessageContent::Text(text),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+}
+
+/// Converts every message's content parts into the form `target` accepts.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation as received from the client
+/// * `target` - The provider the messages will be sent to
+///
+/// # Returns
+///
+/// * `Result<Vec<Message>>` - The converted messages; plain-text content is
+///   unchanged, and for Anthropic targets tool turns become content blocks
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` naming the message index, block index and
+/// block type of the first part that cannot be sent to `target`, or if a
+/// tool turn cannot be converted.
+pub fn convert_messages(messages: &[Message], target: ContentTarget) -> Result<Vec<Message>> {
+    let tool_converted;
+    let messages = match target {
+        ContentTarget::Anthropic => {
+            tool_converted = to_anthropic_messages(messages)?;
+            &tool_converted[..]
+        }
+        ContentTarget::OpenAI => messages,
+    };
+    messages
+        .iter()
+        .enumerate()
+        .map(|(message_index, message)| {
+            let parts = match &message.content {
+                MessageContent::Text(_) => return Ok(message.clone()),
+                MessageContent::Parts(parts) => parts,
+            };
+            let converted = parts
+                .iter()
+                .enumerate()
+                .map(|(block_index, part)| {
+                    part.clone().convert(&message.role, target).ok_or_else(|| ApiError::BadRequest {
+                        message: format!(
+                            "Content block messages[{}].content[{}] of type '{}' is not supported in a {:?} message for {:?} targets",
+                            message_index,
+                            block_index,
+                            part.type_name(),
+                            message.role,
+                            target
+                        ),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Message {
+                content: MessageContent::Parts(converted),
+                ..message.clone()
+            })
+        })
+        .collect()
+}
+
+/// Flattens the `content` of a tool result, which is either a string or a list of blocks.
+pub(super) fn tool_result_text(content: &serde_json::Value) -> String {
+    match content {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Array(blocks) => blocks
+            .iter()
+            .map(|block| match block.get("type").and_then(|t| t.as_str()) {
+                Some("text") => block.get("text").and_then(|t| t.as_str()).unwrap_or_default().to_string(),
+                Some("image") => "[image]".to_string(),
+                Some(other) => format!("[{}]", other),
+                None => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(role: Role, content: serde_json::Value) -> Message {
+        Message {
+            role,
+            content: serde_json::from_value(content).unwrap(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    fn converted(content: serde_json::Value, target: ContentTarget) -> serde_json::Value {
+        let messages = convert_messages(&[message(Role::User, content)], target).unwrap();
+        serde_json::to_value(&messages[0].content).unwrap()
+    }
+
+    #[test]
+    fn plain_text_is_left_unchanged() {
+        assert_eq!(converted(json!("Hi"), ContentTarget::Anthropic), json!("Hi"));
+        assert_eq!(converted(json!("Hi"), ContentTarget::OpenAI), json!("Hi"));
+    }
+
+    #[test]
+    fn image_urls_become_anthropic_image_sources() {
+        let parts = json!([
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,AAAA"}},
+            {"type": "image_url", "image_url": {"url": "https://example.com/cat.png", "detail": "high"}},
+        ]);
+        assert_eq!(converted(parts, ContentTarget::Anthropic), json!([
+            {"type": "text", "text": "What is this?"},
+            {"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "AAAA"}},
+            {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.png"}},
+        ]));
+    }
+
+    #[test]
+    fn anthropic_images_become_openai_image_urls() {
+        let parts = json!([
+            {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}},
+            {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.png"}},
+        ]);
+        assert_eq!(converted(parts, ContentTarget::OpenAI), json!([
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+            {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+        ]));
+    }
+
+    #[test]
+    fn tool_results_are_kept_for_anthropic_and_inlined_for_openai() {
+        let parts = json!([{"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "42"}]}]);
+        assert_eq!(converted(parts.clone(), ContentTarget::Anthropic), parts);
+        assert_eq!(converted(parts, ContentTarget::OpenAI), json!([{"type": "text", "text": "[tool_result toolu_1]\n42"}]));
+    }
+
+    #[test]
+    fn unconvertible_parts_are_rejected_by_position_and_type() {
+        let messages = [
+            message(Role::User, json!("Hi")),
+            message(Role::User, json!([{"type": "text", "text": "Listen"}, {"type": "input_audio", "input_audio": {}}])),
+        ];
+        let error = convert_messages(&messages, ContentTarget::OpenAI).unwrap_err();
+        let ApiError::BadRequest { message: reason } = error else {
+            panic!("expected a bad request, got {:?}", error);
+        };
+        assert!(reason.starts_with("Content block messages[1].content[1] of type 'input_audio'"), "{}", reason);
+
+        let tool_result = [message(Role::Assistant, json!([{"type": "tool_result", "tool_use_id": "toolu_1"}]))];
+        assert!(convert_messages(&tool_result, ContentTarget::Anthropic).is_err());
+    }
+
+    #[test]
+    fn flattening_replaces_non_text_parts_with_placeholders() {
+        let message = message(Role::User, json!([
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+            {"type": "input_audio", "input_audio": {}},
+        ]));
+        assert_eq!(message.flattened().content, MessageContent::Text("What is this?\n[image]\n[input_audio]".to_string()));
+    }
+}
@@ -1,5 +1,13 @@
+pub mod content;
+pub mod pipeline;
 pub mod request;
 pub mod response;
+pub mod tools;
+pub mod transcript;
 
+pub use content::*;
+pub use pipeline::*;
 pub use request::*;
 pub use response::*;
+pub use tools::*;
+pub use transcript::*;
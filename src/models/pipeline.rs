@@ -0,0 +1,145 @@
+//! Typed result of one reasoning-then-answer run.
+//!
+//! [`ApiResponse`](super::ApiResponse) is shaped for the HTTP API: the
+//! reasoning is a content block in front of the answer and the usage is
+//! summed over both phases. A [`PipelineResult`] keeps the two phases apart,
+//! each with its own model and usage, for code that uses the pipeline as a
+//! library. The non-streaming handler builds one for every run and derives
+//! its [`ApiResponse`](super::ApiResponse) from it with the conversion
+//! module, adding only what is specific to HTTP (extra choices, raw upstream
+//! bodies, the requested reasoning layout); the compat response is in turn
+//! built from that `ApiResponse`.
+
+use super::{ContentBlock, Timings, UsageStats, TEXT_BLOCK_TYPE};
+use crate::warnings::Warning;
+use serde::Serialize;
+
+/// The outcome of a run: the reasoning, the answer, and what happened on the way.
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineResult {
+    pub reasoning: ReasoningOutcome,
+    pub answer: AnswerOutcome,
+    pub timings: Timings,
+    /// Everything that went differently than the request asked.
+    pub warnings: Vec<Warning>,
+}
+
+/// What the reasoner produced.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReasoningOutcome {
+    /// The reasoning, as injected into the target's prompt.
+    pub text: String,
+    pub model: String,
+    pub usage: UsageStats,
+    /// Set when the reasoner stopped at its `max_tokens`.
+    pub truncated: bool,
+}
+
+/// What the target produced.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AnswerOutcome {
+    /// The answer's content blocks, without the reasoning.
+    pub blocks: Vec<ContentBlock>,
+    /// Why the target stopped, in OpenAI `finish_reason` terms (`stop`, `length`, ...).
+    pub finish_reason: Option<String>,
+    pub model: String,
+    pub usage: UsageStats,
+}
+
+impl PipelineResult {
+    /// Returns the text of the answer's text blocks, joined by newlines.
+    pub fn answer_text(&self) -> String {
+        self.answer
+            .blocks
+            .iter()
+            .filter(|block| block.content_type == TEXT_BLOCK_TYPE)
+            .map(|block| block.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the reasoning in a collapsed `<details>` section followed by the answer.
+    ///
+    /// Without reasoning, only the answer is returned.
+    #[allow(dead_code)]
+    pub fn combined_markdown(&self) -> String {
+        let answer = self.answer_text();
+        if self.reasoning.text.trim().is_empty() {
+            return answer;
+        }
+        format!(
+            "<details>\n<summary>Reasoning ({})</summary>\n\n{}\n\n</details>\n\n{}",
+            self.reasoning.model,
+            self.reasoning.text.trim(),
+            answer
+        )
+    }
+
+    /// Returns the token usage of both phases together.
+    pub fn usage(&self) -> UsageStats {
+        let mut usage = self.reasoning.usage.clone();
+        usage.merge(&self.answer.usage);
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(reasoning: &str) -> PipelineResult {
+        let mut reasoner_usage = UsageStats::default();
+        reasoner_usage.add(10, 20);
+        let mut answer_usage = UsageStats::default();
+        answer_usage.add(30, 5);
+        PipelineResult {
+            reasoning: ReasoningOutcome {
+                text: reasoning.to_string(),
+                model: "deepseek-reasoner".to_string(),
+                usage: reasoner_usage,
+                truncated: false,
+            },
+            answer: AnswerOutcome {
+                blocks: vec![ContentBlock::text("Paris."), ContentBlock::thinking("ignored"), ContentBlock::text("Done.")],
+                finish_reason: Some("stop".to_string()),
+                model: "gpt-4o".to_string(),
+                usage: answer_usage,
+            },
+            timings: Timings::default(),
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn answer_text_joins_the_text_blocks() {
+        assert_eq!(result("").answer_text(), "Paris.\nDone.");
+    }
+
+    #[test]
+    fn combined_markdown_collapses_the_reasoning() {
+        assert_eq!(
+            result(" The capital is Paris. ").combined_markdown(),
+            "<details>\n<summary>Reasoning (deepseek-reasoner)</summary>\n\nThe capital is Paris.\n\n</details>\n\nParis.\nDone."
+        );
+        assert_eq!(result("  ").combined_markdown(), "Paris.\nDone.");
+    }
+
+    #[test]
+    fn library_users_read_the_reasoning_and_answer_apart() {
+        let mut result = result("The capital of France is Paris.");
+        result.reasoning.truncated = true;
+        assert_eq!(result.reasoning.model, "deepseek-reasoner");
+        assert_eq!(result.reasoning.text, "The capital of France is Paris.");
+        assert!(result.reasoning.truncated);
+        assert_eq!(result.answer.model, "gpt-4o");
+        assert_eq!(result.answer.finish_reason.as_deref(), Some("stop"));
+        assert!(!result.answer_text().contains("capital"));
+        assert_eq!(result.answer.usage.total_tokens, 35);
+    }
+
+    #[test]
+    fn usage_sums_both_phases() {
+        let usage = result("").usage();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (40, 25, 65));
+    }
+}
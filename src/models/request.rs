@@ -3,8 +3,16 @@
 //! This module defines the structures used to represent incoming API requests,
 //! including chat messages, configuration options, and request parameters.
 
+use super::content::{deserialize_nullable_content, MessageContent};
+use super::tools::ToolCall;
+use super::transcript::{render_transcript, ReasonerSystemPrompt, ReasonerTranscript, TASK_CONTEXT_PREFIX};
+use crate::history::TrimReport;
+use crate::replicas::ReplicaLease;
+use crate::trace::TraceRecorder;
+use crate::warnings::WarningCollector;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Primary request structure for chat API endpoints.
 ///
@@ -18,7 +26,7 @@ pub struct ApiRequest {
     #[serde(default)]
     pub verbose: bool,
     
-    pub system: Option<String>,
+    pub system: Option<SystemPrompt>,
     pub messages: Vec<Message>,
     
     #[serde(default)]
@@ -29,28 +37,439 @@ pub struct ApiRequest {
     
     #[serde(default)]
     pub openai_config: ApiConfig,
+
+    /// How the reasoning is presented to the target model.
+    pub injection_mode: Option<InjectionMode>,
+
+    /// Template for the injected reasoning; `{reasoning}` is replaced with the reasoning text.
+    pub injection_template: Option<String>,
+
+    /// Caps the answer output rate when streaming, overriding the server default.
+    pub max_output_chars_per_second: Option<u32>,
+
+    /// Caps the reasoning output rate when streaming, overriding the server default.
+    pub max_reasoning_chars_per_second: Option<u32>,
+
+    /// Number of target choices to generate; the reasoning is shared across them.
+    pub n: Option<u32>,
+
+    /// Model name reported in every streamed chunk, e.g. the mapping name a
+    /// compat client asked for. Defaults to the upstream model names.
+    pub model: Option<String>,
+
+    /// Validate the answer against `openai_config.body.response_format`'s JSON
+    /// schema, retrying the target once with the errors before failing.
+    #[serde(default)]
+    pub validate_json_schema: bool,
+
+    /// What to do with the reasoner's own answer, overriding the server default.
+    pub reasoner_answer: Option<ReasonerAnswerMode>,
+
+    /// Report per-phase timings even when the request is not verbose.
+    #[serde(default)]
+    pub include_timings: bool,
+
+    /// Longest the reasoning phase may run, in seconds, overriding the server
+    /// default; 0 disables the limit.
+    pub reasoning_timeout_secs: Option<u64>,
+
+    /// Reasoner model for this request, overriding `deepseek_config.body.model`
+    /// and the compat mapping's `deepseek_model`; checked against
+    /// `models.allowed_reasoner_models`.
+    pub reasoner_model: Option<String>,
+
+    /// Buffer the stream's frames so the client can reconnect through
+    /// `GET /v1/streams/{id}` after a dropped connection.
+    #[serde(default)]
+    pub resumable: bool,
+
+    /// How the reasoning is returned to the client.
+    #[serde(default)]
+    pub thinking_format: ThinkingFormat,
+
+    /// Return the reasoning to the client; when false it is still injected
+    /// into the target but left out of the response. Defaults to true.
+    pub include_reasoning: Option<bool>,
+
+    /// How the conversation is presented to the reasoner.
+    #[serde(default)]
+    pub reasoner_transcript: ReasonerTranscript,
+
+    /// How the caller's system prompt reaches the reasoner, overriding
+    /// `reasoning.reasoner_sees_system`.
+    pub reasoner_sees_system: Option<ReasonerSystemPrompt>,
+
+    /// Identifies the conversation across turns for speculative reasoning.
+    pub conversation_id: Option<String>,
+
+    /// Opaque caller metadata, such as `{"team": "search"}`, attached to the
+    /// request's tracing span and echoed in the response.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+
+    /// Answer with the target alone, without calling the reasoner.
+    #[serde(default)]
+    pub skip_reasoning: bool,
+
+    /// Assemble a trace of the request, when `server.request_traces` allows it.
+    #[serde(default)]
+    pub trace: bool,
+
+    /// HTTPS URL progress updates of a non-streaming request are posted to.
+    #[serde(default)]
+    pub progress_callback_url: Option<String>,
+
+    /// Language the reasoner is told to reason in, whatever the conversation's language.
+    #[serde(default)]
+    pub reasoning_language: Option<String>,
+
+    /// Language the target is told to answer in.
+    #[serde(default)]
+    pub answer_language: Option<String>,
+
+    /// Instructions for `reasoning_language` and `answer_language`, resolved
+    /// from the config by the handler.
+    #[serde(skip)]
+    pub language_instructions: LanguageInstructions,
+
+    /// Shape of the streamed chunks; set by the legacy completions endpoint.
+    #[serde(skip)]
+    pub stream_format: StreamFormat,
+
+    /// Optimistic answering settings; set from the compat mapping when enabled.
+    #[serde(skip)]
+    pub optimistic: Option<OptimisticConfig>,
+
+    /// Dialect of an OpenAI-compatible target; set from the compat mapping
+    /// or by `X-Target-Model: mistral`.
+    #[serde(skip)]
+    pub openai_dialect: OpenAIDialect,
+
+    /// Dialect of the reasoner; set from the compat mapping, otherwise
+    /// detected from the reasoner's host.
+    #[serde(skip)]
+    pub reasoner_dialect: Option<ReasonerDialect>,
+
+    /// What the compat mapping's `max_messages` trimmed from the conversation.
+    #[serde(skip)]
+    pub trimmed: Option<TrimReport>,
+
+    /// Warnings pushed by the stages handling the request.
+    #[serde(skip)]
+    pub warnings: WarningCollector,
+
+    /// The reasoner replica the compat mapping routed the request to.
+    #[serde(skip)]
+    pub replica: Option<Arc<ReplicaLease>>,
+
+    /// Recorder of the request's trace, when one was requested and allowed.
+    #[serde(skip)]
+    pub tracer: Option<TraceRecorder>,
+
+    /// When the reasoner's own answer replaces the target's; set from the compat mapping.
+    #[serde(skip)]
+    pub use_reasoner_answer: UseReasonerAnswer,
+
+    /// Shortest reasoner answer `use_reasoner_answer = "if_confident"` accepts, in characters.
+    #[serde(skip)]
+    pub confident_answer_min_chars: usize,
+
+    /// Id of the request, as in `X-DeepThink-Request-Id`, for its usage event.
+    #[serde(skip)]
+    pub request_id: Option<String>,
+}
+
+/// System prompt instructions for the languages a request asks for.
+#[derive(Debug, Clone, Default)]
+pub struct LanguageInstructions {
+    /// Appended to the reasoner's system prompt.
+    pub reasoner: Option<String>,
+    /// Appended to the target's system prompt.
+    pub target: Option<String>,
+}
+
+/// Variant of the chat completions API the reasoner speaks.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerDialect {
+    /// DeepSeek and compatible servers such as ollama, which return the
+    /// reasoning in `reasoning_content` or inline in `<think>` tags.
+    #[default]
+    #[serde(rename = "deepseek")]
+    DeepSeek,
+    /// Groq: asked for `reasoning_format = "parsed"`, which returns the
+    /// reasoning in a `reasoning` field, with lower `max_tokens` ceilings.
+    Groq,
+}
+
+/// Variant of the OpenAI chat completions API an OpenAI-compatible target speaks.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenAIDialect {
+    /// The OpenAI API as documented.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// mistral.ai: takes `safe_prompt`, has no `n` or logprobs, and only
+    /// accepts 9-character alphanumeric tool call ids.
+    Mistral,
+}
+
+/// Optimistic answering: a draft target call on the raw conversation runs
+/// alongside the reasoner, and is used when the reasoner is slow.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct OptimisticConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Milliseconds the reasoner may take before the draft answer is used instead.
+    #[serde(default = "default_reasoner_wait_ms")]
+    pub reasoner_wait_ms: u64,
+}
+
+fn default_reasoner_wait_ms() -> u64 {
+    3000
+}
+
+impl Default for OptimisticConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            reasoner_wait_ms: default_reasoner_wait_ms(),
+        }
+    }
+}
+
+impl OptimisticConfig {
+    /// Returns how long the reasoner may take before the draft wins.
+    pub fn reasoner_wait(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.reasoner_wait_ms)
+    }
+}
+
+/// Object type of the chunks in a streamed response.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StreamFormat {
+    /// `chat.completion.chunk` objects carrying `choices[].delta`.
+    #[default]
+    ChatCompletion,
+    /// Legacy `text_completion` objects carrying `choices[].text`.
+    TextCompletion,
+}
+
+/// How the reasoning appears in responses.
+///
+/// Many clients expect `content` to be a plain string, so the structured
+/// forms are only used when a request asks for them.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThinkingFormat {
+    /// Inline in `content`, wrapped in `<think>` (non-streaming) or
+    /// `<thinking>` (streaming) tags.
+    #[default]
+    Tag,
+    /// In a separate `reasoning_content` field of the message or delta.
+    ReasoningContent,
+    /// As a `{"type": "thinking"}` part of a `content` parts array, followed
+    /// by `{"type": "text"}` parts for the answer.
+    ContentPart,
+}
+
+/// A system prompt, given either as plain text or as Anthropic-style blocks.
+///
+/// The block form carries per-block `cache_control` markers, which are
+/// passed through unchanged to Anthropic targets.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum SystemPrompt {
+    Text(String),
+    Blocks(Vec<SystemBlock>),
+}
+
+/// One block of an Anthropic-style system prompt.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SystemBlock {
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<serde_json::Value>,
+}
+
+impl SystemPrompt {
+    /// Flattens the prompt to plain text, joining blocks with newlines.
+    pub fn to_text(&self) -> String {
+        match self {
+            SystemPrompt::Text(text) => text.clone(),
+            SystemPrompt::Blocks(blocks) => blocks
+                .iter()
+                .map(|block| block.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Returns true if the prompt carries no text.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            SystemPrompt::Text(text) => text.is_empty(),
+            SystemPrompt::Blocks(blocks) => blocks.iter().all(|block| block.text.is_empty()),
+        }
+    }
+}
+
+impl SystemBlock {
+    /// Creates a plain text block without cache control.
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            block_type: "text".to_string(),
+            text: text.into(),
+            cache_control: None,
+        }
+    }
 }
 
 /// A single message in a chat conversation.
 ///
 /// Represents one message in the conversation history, including
-/// its role (system, user, or assistant) and content.
+/// its role (system, user, assistant, or tool) and content, given either as
+/// text or as typed content parts. Tool turns use the OpenAI fields.
+/// Deserialization is lenient, since SDKs differ in what they send: the role
+/// is case-insensitive, a `null` content is empty, and fields not listed here,
+/// such as `function_call` or `name`, are ignored.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
+    #[serde(alias = "Role")]
     pub role: Role,
-    pub content: String,
+    #[serde(default, deserialize_with = "deserialize_nullable_content")]
+    pub content: MessageContent,
+    /// Tool invocations made by an assistant turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// Fields of a message that [`Message`] keeps.
+const MESSAGE_FIELDS: [&str; 5] = ["role", "Role", "content", "tool_calls", "tool_call_id"];
+
+/// Returns the message fields of a raw request body that deserializing it
+/// ignores, sorted and without duplicates.
+pub fn unknown_message_fields(body: &serde_json::Value) -> Vec<String> {
+    let mut fields: Vec<String> = body
+        .get("messages")
+        .and_then(|messages| messages.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.as_object())
+        .flat_map(|message| message.keys())
+        .filter(|field| !MESSAGE_FIELDS.contains(&field.as_str()))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
 }
 
 /// Possible roles for a message in a chat conversation.
 ///
 /// Each message must be associated with one of these roles to
-/// properly structure the conversation flow.
+/// properly structure the conversation flow. Role names are matched
+/// case-insensitively and always serialized in lowercase. Any other role is
+/// rejected with a message listing the accepted ones.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", try_from = "String")]
 pub enum Role {
     System,
+    /// OpenAI's name for the system role on newer models; treated as `system`
+    /// and sent to each target under the name it expects.
+    Developer,
     User,
     Assistant,
+    Tool,
+}
+
+/// Role names accepted in requests.
+const ACCEPTED_ROLES: &str = "system, developer, user, assistant, tool";
+
+impl Role {
+    /// Returns true for the roles that carry the system prompt.
+    pub fn is_system(&self) -> bool {
+        matches!(self, Role::System | Role::Developer)
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = String;
+
+    fn try_from(role: String) -> std::result::Result<Self, Self::Error> {
+        match role.to_ascii_lowercase().as_str() {
+            "system" => Ok(Role::System),
+            "developer" => Ok(Role::Developer),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            _ => Err(format!("unsupported message role '{}'; accepted roles: {}", role, ACCEPTED_ROLES)),
+        }
+    }
+}
+
+/// Strategies for presenting the reasoner's output to the target model.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionMode {
+    /// Append an assistant turn containing a thinking block.
+    ///
+    /// The default template wraps the reasoning in `<think>` tags on both the
+    /// streaming and non-streaming paths; the streaming path used to send
+    /// `<thinking>` tags to the target model.
+    #[default]
+    AssistantThinking,
+    /// Append the reasoning to the system prompt, creating one if absent.
+    SystemAppend,
+    /// Insert the reasoning as a user message before the final user turn.
+    UserContext,
+}
+
+/// What to do with the content a reasoner produces outside its reasoning,
+/// such as the draft answer an ollama model writes after `</think>`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerAnswerMode {
+    /// Drop it.
+    #[default]
+    Discard,
+    /// Append it to the reasoning, so the client and the target both see it.
+    AppendToReasoning,
+    /// Return it separately: a `reasoner_answer` content block in non-streaming
+    /// responses, a `<reasoner_answer>` section in the thinking stream.
+    ReturnAsBlock,
+}
+
+/// When a mapping returns the reasoner's own answer instead of calling the target.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UseReasonerAnswer {
+    /// Always call the target.
+    #[default]
+    Never,
+    /// Use the reasoner's answer when it finished with `stop` and is at least
+    /// `confident_answer_min_chars` long.
+    IfConfident,
+    /// Use the reasoner's answer whenever it wrote one.
+    Always,
+}
+
+impl InjectionMode {
+    /// Returns the template used when no custom template is configured.
+    pub fn default_template(&self) -> &'static str {
+        match self {
+            InjectionMode::AssistantThinking => "<think>\n{reasoning}\n</think>",
+            InjectionMode::SystemAppend => "Here is an expert's analysis of the conversation:\n{reasoning}",
+            InjectionMode::UserContext => "Here is an expert's analysis:\n{reasoning}",
+        }
+    }
 }
 
 /// Configuration options for external API requests.
@@ -67,6 +486,16 @@ pub struct ApiConfig {
 }
 
 impl ApiRequest {
+    /// Returns whether the reasoning is returned to the client.
+    pub fn includes_reasoning(&self) -> bool {
+        self.include_reasoning != Some(false)
+    }
+
+    /// Returns true if the response should carry per-phase timings.
+    pub fn wants_timings(&self) -> bool {
+        self.verbose || self.include_timings
+    }
+
     /// Validates that system prompts are not duplicated.
     ///
     /// Checks that a system prompt is not provided in both the root level
@@ -76,16 +505,21 @@ impl ApiRequest {
     ///
     /// * `bool` - True if system prompt validation passes (no duplicates), false otherwise
     pub fn validate_system_prompt(&self) -> bool {
-        let system_in_messages = self.messages.iter().any(|msg| matches!(msg.role, Role::System));
+        let system_in_messages = self.messages.iter().any(|msg| msg.role.is_system());
         
+        let system_in_root = self.system.as_ref().is_some_and(|system| !system.is_empty());
+
         // Only invalid if system prompt is provided in both places
-        !(self.system.is_some() && system_in_messages)
+        !(system_in_root && system_in_messages)
     }
 
     /// Returns messages with the system prompt in the correct position.
     ///
     /// Ensures the system prompt (if present) is the first message,
-    /// followed by the conversation messages in order.
+    /// followed by the conversation messages in order. The prompt comes from
+    /// the root `system` field or from the `system` and `developer` messages,
+    /// which are moved to the front, and is always returned as a `system`
+    /// message; clients normalize the role for their provider.
     ///
     /// # Returns
     ///
@@ -94,33 +528,473 @@ impl ApiRequest {
         let mut messages = Vec::new();
 
         // Add system message first
-        if let Some(system) = &self.system {
+        if let Some(system) = self.get_system_prompt() {
             messages.push(Message {
                 role: Role::System,
-                content: system.clone(),
+                content: system.into(),
+                tool_calls: None,
+                tool_call_id: None,
             });
         }
 
         // Add remaining messages
-        messages.extend(self.messages.iter().filter(|msg| !matches!(msg.role, Role::System)).cloned());
+        messages.extend(self.messages.iter().filter(|msg| !msg.role.is_system()).cloned());
+
+        messages
+    }
 
+    /// Returns the messages sent to the reasoner.
+    ///
+    /// With `reasoner_transcript = "rendered"` the conversation becomes a
+    /// plain-text transcript summarizing the target's tools; otherwise it is
+    /// the conversation without system messages. The caller's system prompt
+    /// is then added according to the request's `reasoner_sees_system`, or
+    /// `default_system` if the request does not set it. The target never
+    /// sees these forms. The `reasoning_language` instruction, if any, ends
+    /// the system prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `default_system` - The configured `reasoner_sees_system`
+    pub fn reasoner_messages(&self, default_system: ReasonerSystemPrompt) -> Vec<Message> {
+        let mut messages = self.reasoner_conversation(default_system);
+        append_system_instruction(&mut messages, self.language_instructions.reasoner.as_deref());
+        messages
+    }
+
+    fn reasoner_conversation(&self, default_system: ReasonerSystemPrompt) -> Vec<Message> {
+        let conversation: Vec<Message> = self
+            .messages
+            .iter()
+            .filter(|msg| !msg.role.is_system())
+            .cloned()
+            .collect();
+        let conversation = match self.reasoner_transcript {
+            ReasonerTranscript::Raw => conversation,
+            ReasonerTranscript::Rendered => {
+                let tools = self
+                    .openai_config
+                    .body
+                    .get("tools")
+                    .or_else(|| self.anthropic_config.body.get("tools"));
+                render_transcript(&conversation, tools)
+            }
+        };
+
+        let system = self.get_system_prompt().filter(|system| !system.trim().is_empty());
+        let system = match (self.reasoner_sees_system.unwrap_or(default_system), system) {
+            (ReasonerSystemPrompt::None, _) | (_, None) => return conversation,
+            (ReasonerSystemPrompt::AsSystem, Some(system)) => (Role::System, system),
+            (ReasonerSystemPrompt::AsContext, Some(system)) => (Role::User, format!("{}\n{}", TASK_CONTEXT_PREFIX, system)),
+        };
+        let mut messages = vec![Message {
+            role: system.0,
+            content: system.1.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }];
+        messages.extend(conversation);
         messages
     }
 
     /// Retrieves the system prompt if one is present.
     ///
     /// Checks both the root level system field and the messages array
-    /// for a system prompt.
+    /// for a system prompt. Block-form prompts are flattened to text, and
+    /// several `system` or `developer` messages are joined in order.
     ///
     /// # Returns
     ///
-    /// * `Option<&str>` - The system prompt if found, None otherwise
-    pub fn get_system_prompt(&self) -> Option<&str> {
-        self.system.as_deref().or_else(|| {
-            self.messages
-                .iter()
-                .find(|msg| matches!(msg.role, Role::System))
-                .map(|msg| msg.content.as_str())
-        })
+    /// * `Option<String>` - The system prompt if found, None otherwise
+    pub fn get_system_prompt(&self) -> Option<String> {
+        self.system
+            .as_ref()
+            .filter(|system| !system.is_empty())
+            .map(SystemPrompt::to_text)
+            .or_else(|| {
+                let parts: Vec<String> = self
+                    .messages
+                    .iter()
+                    .filter(|msg| msg.role.is_system())
+                    .map(|msg| msg.content.to_text())
+                    .collect();
+                (!parts.is_empty()).then(|| parts.join("\n\n"))
+            })
+    }
+
+    /// Builds the message list sent to the target model.
+    ///
+    /// Injects the reasoning according to the request's injection mode and
+    /// template. Without reasoning the conversation is returned unchanged,
+    /// apart from the `answer_language` instruction, which always ends the
+    /// system prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `reasoning` - The reasoner's output, if any
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Message>` - Messages for the target model, system prompt first
+    pub fn build_target_messages(&self, reasoning: Option<&str>) -> Vec<Message> {
+        let mut messages = self.target_conversation(reasoning);
+        append_system_instruction(&mut messages, self.language_instructions.target.as_deref());
+        messages
+    }
+
+    fn target_conversation(&self, reasoning: Option<&str>) -> Vec<Message> {
+        let mut messages = self.get_messages_with_system();
+        let reasoning = match reasoning {
+            Some(reasoning) => reasoning,
+            None => return messages,
+        };
+
+        let mode = self.injection_mode.unwrap_or_default();
+        let injected = self
+            .injection_template
+            .as_deref()
+            .unwrap_or(mode.default_template())
+            .replace("{reasoning}", reasoning);
+
+        match mode {
+            InjectionMode::AssistantThinking => {
+                messages.push(Message {
+                    role: Role::Assistant,
+                    content: injected.into(),
+                    tool_calls: None,
+                    tool_call_id: None,
+                });
+            }
+            InjectionMode::SystemAppend => {
+                let system = match self.get_system_prompt() {
+                    Some(system) => format!("{}\n\n{}", system, injected),
+                    None => injected,
+                };
+                messages.retain(|msg| !msg.role.is_system());
+                messages.insert(0, Message {
+                    role: Role::System,
+                    content: system.into(),
+                    tool_calls: None,
+                    tool_call_id: None,
+                });
+            }
+            InjectionMode::UserContext => {
+                let position = messages
+                    .iter()
+                    .rposition(|msg| msg.role == Role::User)
+                    .unwrap_or(messages.len());
+                messages.insert(position, Message {
+                    role: Role::User,
+                    content: injected.into(),
+                    tool_calls: None,
+                    tool_call_id: None,
+                });
+            }
+        }
+
+        messages
+    }
+}
+
+/// Appends `instruction` to the leading system message of `messages`, adding
+/// one if the conversation has none.
+fn append_system_instruction(messages: &mut Vec<Message>, instruction: Option<&str>) {
+    let Some(instruction) = instruction else {
+        return;
+    };
+    match messages.first_mut() {
+        Some(first) if first.role.is_system() => {
+            let system = format!("{}\n\n{}", first.content.to_text(), instruction);
+            first.content = system.into();
+        }
+        _ => messages.insert(0, Message {
+            role: Role::System,
+            content: instruction.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(mode: Option<InjectionMode>, system: Option<&str>) -> ApiRequest {
+        let mut request: ApiRequest = serde_json::from_value(json!({
+            "system": system,
+            "messages": [
+                {"role": "user", "content": "What is the capital of France?"},
+                {"role": "assistant", "content": "Paris."},
+                {"role": "user", "content": "And of Italy?"},
+            ],
+        }))
+        .unwrap();
+        request.injection_mode = mode;
+        request
+    }
+
+    fn turns(messages: &[Message]) -> Vec<(Role, &str)> {
+        messages
+            .iter()
+            .map(|msg| match &msg.content {
+                MessageContent::Text(text) => (msg.role.clone(), text.as_str()),
+                MessageContent::Parts(parts) => panic!("expected text content, got {:?}", parts),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assistant_thinking_appends_a_think_block() {
+        let messages = request(None, Some("Be brief.")).build_target_messages(Some("Rome."));
+        assert_eq!(turns(&messages), vec![
+            (Role::System, "Be brief."),
+            (Role::User, "What is the capital of France?"),
+            (Role::Assistant, "Paris."),
+            (Role::User, "And of Italy?"),
+            (Role::Assistant, "<think>\nRome.\n</think>"),
+        ]);
+    }
+
+    #[test]
+    fn system_append_extends_the_system_prompt() {
+        let messages = request(Some(InjectionMode::SystemAppend), Some("Be brief.")).build_target_messages(Some("Rome."));
+        assert_eq!(turns(&messages), vec![
+            (Role::System, "Be brief.\n\nHere is an expert's analysis of the conversation:\nRome."),
+            (Role::User, "What is the capital of France?"),
+            (Role::Assistant, "Paris."),
+            (Role::User, "And of Italy?"),
+        ]);
+    }
+
+    #[test]
+    fn system_append_creates_a_system_prompt() {
+        let messages = request(Some(InjectionMode::SystemAppend), None).build_target_messages(Some("Rome."));
+        assert_eq!(turns(&messages), vec![
+            (Role::System, "Here is an expert's analysis of the conversation:\nRome."),
+            (Role::User, "What is the capital of France?"),
+            (Role::Assistant, "Paris."),
+            (Role::User, "And of Italy?"),
+        ]);
+    }
+
+    #[test]
+    fn user_context_precedes_the_final_user_turn() {
+        let messages = request(Some(InjectionMode::UserContext), Some("Be brief.")).build_target_messages(Some("Rome."));
+        assert_eq!(turns(&messages), vec![
+            (Role::System, "Be brief."),
+            (Role::User, "What is the capital of France?"),
+            (Role::Assistant, "Paris."),
+            (Role::User, "Here is an expert's analysis:\nRome."),
+            (Role::User, "And of Italy?"),
+        ]);
+    }
+
+    #[test]
+    fn custom_template_replaces_the_default() {
+        let mut request = request(Some(InjectionMode::UserContext), None);
+        request.injection_template = Some("Hint: {reasoning}".to_string());
+        let messages = request.build_target_messages(Some("Rome."));
+        assert_eq!(turns(&messages)[2], (Role::User, "Hint: Rome."));
+    }
+
+    #[test]
+    fn no_reasoning_leaves_the_conversation_unchanged() {
+        for mode in [InjectionMode::AssistantThinking, InjectionMode::SystemAppend, InjectionMode::UserContext] {
+            let messages = request(Some(mode), Some("Be brief.")).build_target_messages(None);
+            assert_eq!(turns(&messages), vec![
+                (Role::System, "Be brief."),
+                (Role::User, "What is the capital of France?"),
+                (Role::Assistant, "Paris."),
+                (Role::User, "And of Italy?"),
+            ]);
+        }
+    }
+
+    #[test]
+    fn system_prompts_round_trip_as_text_or_blocks() {
+        let text = json!("Be brief.");
+        let prompt: SystemPrompt = serde_json::from_value(text.clone()).unwrap();
+        assert_eq!(prompt, SystemPrompt::Text("Be brief.".to_string()));
+        assert_eq!(serde_json::to_value(&prompt).unwrap(), text);
+
+        let blocks = json!([
+            {"type": "text", "text": "Be brief."},
+            {"type": "text", "text": "Answer in French.", "cache_control": {"type": "ephemeral"}},
+        ]);
+        let prompt: SystemPrompt = serde_json::from_value(blocks.clone()).unwrap();
+        assert_eq!(prompt, SystemPrompt::Blocks(vec![
+            SystemBlock::text("Be brief."),
+            SystemBlock {
+                block_type: "text".to_string(),
+                text: "Answer in French.".to_string(),
+                cache_control: Some(json!({"type": "ephemeral"})),
+            },
+        ]));
+        assert_eq!(serde_json::to_value(&prompt).unwrap(), blocks);
+        assert_eq!(prompt.to_text(), "Be brief.\nAnswer in French.");
+    }
+
+    #[test]
+    fn empty_block_prompts_count_as_no_system_prompt() {
+        let mut request = request(None, None);
+        request.system = Some(SystemPrompt::Blocks(vec![SystemBlock::text("")]));
+        request.messages.insert(0, Message {
+            role: Role::System,
+            content: "From the messages.".into(),
+            tool_calls: None,
+            tool_call_id: None,
+        });
+        assert!(request.validate_system_prompt());
+        assert_eq!(request.get_system_prompt().as_deref(), Some("From the messages."));
+
+        request.system = Some(SystemPrompt::Blocks(vec![SystemBlock::text("Be brief.")]));
+        assert!(!request.validate_system_prompt());
+    }
+
+    #[test]
+    fn system_messages_move_to_the_front() {
+        let request: ApiRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "user", "content": "Capital of France?"},
+                {"role": "system", "content": "Answer in one word."},
+            ],
+        }))
+        .unwrap();
+        let messages = request.get_messages_with_system();
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].role.clone(), messages[0].content.to_text()), (Role::System, "Answer in one word.".to_string()));
+        assert_eq!(messages[1].role, Role::User);
+    }
+
+    #[test]
+    fn reasoner_sees_the_system_prompt_per_mode() {
+        let mut with_system = request(None, None);
+        with_system.messages.insert(0, Message {
+            role: Role::System,
+            content: "Answer in French.".into(),
+            tool_calls: None,
+            tool_call_id: None,
+        });
+        let conversation = [
+            (Role::User, "What is the capital of France?"),
+            (Role::Assistant, "Paris."),
+            (Role::User, "And of Italy?"),
+        ];
+
+        let messages = with_system.reasoner_messages(ReasonerSystemPrompt::None);
+        assert_eq!(turns(&messages), conversation.to_vec());
+
+        let messages = with_system.reasoner_messages(ReasonerSystemPrompt::AsSystem);
+        assert_eq!(turns(&messages)[0], (Role::System, "Answer in French."));
+        assert_eq!(turns(&messages)[1..], conversation);
+
+        let messages = with_system.reasoner_messages(ReasonerSystemPrompt::AsContext);
+        assert_eq!(turns(&messages)[0], (Role::User, "Task context:\nAnswer in French."));
+        assert_eq!(turns(&messages)[1..], conversation);
+
+        // 请求自己的设置优先于配置, 根级 system 同样适用
+        let mut native = request(None, Some("Be brief."));
+        native.reasoner_sees_system = Some(ReasonerSystemPrompt::AsSystem);
+        let messages = native.reasoner_messages(ReasonerSystemPrompt::None);
+        assert_eq!(turns(&messages)[0], (Role::System, "Be brief."));
+        assert_eq!(messages.len(), 4);
+
+        // 没有系统提示词时不添加任何消息
+        assert_eq!(request(None, None).reasoner_messages(ReasonerSystemPrompt::AsContext).len(), 3);
+    }
+
+    #[test]
+    fn developer_messages_are_system_messages() {
+        let request: ApiRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "developer", "content": "Be brief."},
+                {"role": "system", "content": "Answer in French."},
+                {"role": "user", "content": "Capital of Italy?"},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(request.messages[0].role, Role::Developer);
+        assert_eq!(serde_json::to_value(&request.messages[0].role).unwrap(), "developer");
+        assert_eq!(request.get_system_prompt().as_deref(), Some("Be brief.\n\nAnswer in French."));
+        assert_eq!(turns(&request.get_messages_with_system()), vec![
+            (Role::System, "Be brief.\n\nAnswer in French."),
+            (Role::User, "Capital of Italy?"),
+        ]);
+
+        // 根级 system 与 developer 消息同时出现视为重复
+        assert!(request.validate_system_prompt());
+        let duplicated: ApiRequest = serde_json::from_value(json!({
+            "system": "Be brief.",
+            "messages": [{"role": "developer", "content": "Answer in French."}],
+        }))
+        .unwrap();
+        assert!(!duplicated.validate_system_prompt());
+    }
+
+    #[test]
+    fn unknown_roles_are_rejected_with_the_accepted_ones() {
+        let error = serde_json::from_value::<Message>(json!({"role": "critic", "content": "Hi"})).unwrap_err();
+        assert!(
+            error.to_string().contains("unsupported message role 'critic'; accepted roles: system, developer, user, assistant, tool"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn messages_parse_leniently_and_serialize_canonically() {
+        let messages: Vec<Message> = serde_json::from_value(json!([
+            {"Role": "SYSTEM", "content": "Be brief."},
+            {"role": "User", "content": "Hi", "name": "alice"},
+            {"role": "ASSISTANT", "content": null, "function_call": null},
+        ]))
+        .unwrap();
+        let roles: Vec<&Role> = messages.iter().map(|message| &message.role).collect();
+        assert_eq!(roles, [&Role::System, &Role::User, &Role::Assistant]);
+        assert_eq!(messages[2].content.to_text(), "");
+
+        let serialized = serde_json::to_value(&messages).unwrap();
+        assert_eq!(serialized[0]["role"], "system");
+        assert_eq!(serialized[1]["role"], "user");
+        assert!(serialized[0].get("Role").is_none() && serialized[1].get("name").is_none());
+    }
+
+    #[test]
+    fn ignored_message_fields_are_listed_once() {
+        let body = json!({"messages": [
+            {"Role": "user", "content": "Hi", "name": "alice"},
+            {"role": "assistant", "content": null, "function_call": null, "name": "bot", "tool_calls": []},
+            {"role": "tool", "content": "42", "tool_call_id": "call_1"},
+        ]});
+        assert_eq!(unknown_message_fields(&body), ["function_call", "name"]);
+        assert!(unknown_message_fields(&json!({"messages": "not a list"})).is_empty());
+    }
+
+    #[test]
+    fn language_instructions_end_the_system_prompts_only_when_set() {
+        let mut with_system = request(None, Some("Be brief."));
+        let reasoner = with_system.reasoner_messages(ReasonerSystemPrompt::AsSystem);
+        assert_eq!(turns(&reasoner)[0], (Role::System, "Be brief."));
+        assert!(with_system.build_target_messages(Some("Rome.")).iter().all(|msg| !msg.content.to_text().contains("English")));
+
+        with_system.language_instructions = LanguageInstructions {
+            reasoner: Some("Reason in English.".to_string()),
+            target: Some("Answer in German.".to_string()),
+        };
+        let reasoner = with_system.reasoner_messages(ReasonerSystemPrompt::AsSystem);
+        assert_eq!(turns(&reasoner)[0], (Role::System, "Be brief.\n\nReason in English."));
+        let target = with_system.build_target_messages(Some("Rome."));
+        assert_eq!(turns(&target)[0], (Role::System, "Be brief.\n\nAnswer in German."));
+        assert!(target.iter().all(|msg| !msg.content.to_text().contains("Reason in English.")));
+
+        // 没有系统提示词时插入一条
+        let mut without_system = request(None, None);
+        without_system.language_instructions.reasoner = Some("Reason in English.".to_string());
+        let reasoner = without_system.reasoner_messages(ReasonerSystemPrompt::AsSystem);
+        assert_eq!(turns(&reasoner)[0], (Role::System, "Reason in English."));
+        assert_eq!(reasoner.len(), 4);
+        assert_eq!(without_system.build_target_messages(None).len(), 3);
     }
 }
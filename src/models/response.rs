@@ -3,6 +3,12 @@
 //! This module defines the structures used to represent API responses,
 //! including chat completions and usage statistics.
 
+use super::tools::ToolCall;
+use crate::connection::ConnectionTiming;
+use crate::error::ErrorDetails;
+use crate::history::TrimReport;
+use crate::trace::RequestTrace;
+use crate::warnings::Warning;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -15,8 +21,231 @@ use std::collections::HashMap;
 pub struct ApiResponse {
     pub created: DateTime<Utc>,
     pub content: Vec<ContentBlock>,
+    /// Every choice produced by the target; `content` mirrors the first one.
+    /// Only serialized when more than one choice was requested.
+    #[serde(skip_serializing_if = "has_single_choice")]
+    pub choices: Vec<ResponseChoice>,
+    /// Tool calls requested by the target; mirrors the first choice.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: UsageStats,
+    /// Raw reasoner response, included for verbose requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deepseek_response: Option<ExternalApiResponse>,
+    /// Raw target response, included for verbose requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_response: Option<ExternalApiResponse>,
+    /// Per-phase latency, included for verbose requests or when `include_timings` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// Reasoner model the request ran with; included for verbose requests or
+    /// when the request set `reasoner_model`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoner_model: Option<String>,
+    /// Reasoner replica the request was routed to, for mappings with `reasoner_endpoints`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoner_endpoint: Option<String>,
+    /// Set when the reasoner stopped at its `max_tokens`, so the injected
+    /// reasoning was incomplete.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reasoning_truncated: bool,
+    /// Set when the target returned an empty answer that was passed through
+    /// under the `pass` empty answer policy.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub empty_answer: bool,
+    /// Which answer was returned, for mappings with optimistic answering.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub optimistic_path: Option<OptimisticPath>,
+    /// Which model wrote the answer, for mappings with `use_reasoner_answer`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answered_by: Option<AnswerAuthor>,
+    /// Whether the injected reasoning was reused or fresh, when reasoning reuse is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_source: Option<ReasoningSource>,
+    /// Replacements the `[[redaction]]` rules made in the injected reasoning.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub redactions: u32,
+    /// What the mapping's `max_messages` trimmed from the conversation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trimmed: Option<TrimReport>,
+    /// Everything that went differently than the request asked.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    /// The request's `metadata`, echoed back.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// The request's trace, when one was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<RequestTrace>,
+    /// Per-phase latency, always measured for the request journal.
+    #[serde(skip)]
+    pub phase_timings: Timings,
+    /// Target provider that answered, for the request journal.
+    #[serde(skip)]
+    pub target_provider: String,
 }
 
+/// The answer an optimistic request returned.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimisticPath {
+    /// The draft answer on the raw conversation; the reasoner was too slow.
+    Draft,
+    /// The reasoning-primed answer; the draft was discarded.
+    Reasoned,
+}
+
+/// The upstream model that wrote a response's answer.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AnswerAuthor {
+    pub role: AnswerRole,
+    /// Upstream name of the model.
+    pub model: String,
+}
+
+/// The phase whose model wrote the answer.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerRole {
+    /// The reasoner's own answer after its reasoning; the target was not called.
+    Reasoner,
+    /// The target's answer.
+    Target,
+}
+
+impl AnswerRole {
+    /// Returns the role's name as serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnswerRole::Reasoner => "reasoner",
+            AnswerRole::Target => "target",
+        }
+    }
+}
+
+/// Where the reasoning injected into a turn came from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningSource {
+    /// The reasoner ran for this turn.
+    Fresh,
+    /// Reasoning from an earlier turn of the conversation was injected again.
+    Reused,
+}
+
+impl ReasoningSource {
+    /// Returns the source's name as serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasoningSource::Fresh => "fresh",
+            ReasoningSource::Reused => "reused",
+        }
+    }
+}
+
+impl OptimisticPath {
+    /// Returns the path's name as serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OptimisticPath::Draft => "draft",
+            OptimisticPath::Reasoned => "reasoned",
+        }
+    }
+}
+
+/// Per-phase latency of one request, in milliseconds.
+///
+/// Time-to-first-token is only known for streamed phases.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Timings {
+    /// Time between receiving the request and calling the reasoner.
+    pub queue_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoner_first_token_ms: Option<u64>,
+    pub reasoner_total_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_first_token_ms: Option<u64>,
+    pub target_total_ms: u64,
+    /// Time the target connection warm-up took off the target's critical path.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_warm_up_ms: Option<u64>,
+    /// Connection timing of each upstream call the request made.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<ConnectionTiming>,
+}
+
+impl Timings {
+    /// Returns the timings as `X-DeepThink-Timing-*` header names and values.
+    pub fn headers(&self) -> Vec<(&'static str, u64)> {
+        let mut headers = vec![("X-DeepThink-Timing-Queue-Ms", self.queue_ms)];
+        if let Some(ms) = self.reasoner_first_token_ms {
+            headers.push(("X-DeepThink-Timing-Reasoner-TTFT-Ms", ms));
+        }
+        headers.push(("X-DeepThink-Timing-Reasoner-Total-Ms", self.reasoner_total_ms));
+        if let Some(ms) = self.target_first_token_ms {
+            headers.push(("X-DeepThink-Timing-Target-TTFT-Ms", ms));
+        }
+        headers.push(("X-DeepThink-Timing-Target-Total-Ms", self.target_total_ms));
+        if let Some(ms) = self.target_warm_up_ms {
+            headers.push(("X-DeepThink-Timing-Target-Warm-Up-Ms", ms));
+        }
+        headers
+    }
+}
+
+/// One alternative answer in a multi-choice response.
+///
+/// The shared reasoning is repeated at the start of every choice's content.
+#[derive(Debug, Serialize, Clone)]
+pub struct ResponseChoice {
+    pub index: u32,
+    pub content: Vec<ContentBlock>,
+    /// Target-model log probabilities, when requested from an OpenAI-compatible target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<serde_json::Value>,
+    /// Why the target stopped, in OpenAI `finish_reason` terms (`stop`, `length`, ...).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// Tool calls requested by the target, in OpenAI form.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Token usage summed across all upstream calls made for a request.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UsageStats {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    /// Prompt tokens served from the provider's prompt cache, included in `prompt_tokens`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cached_prompt_tokens: u32,
+    /// Tokens of an OpenAI `prediction` that appeared in the answer, included in `completion_tokens`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub accepted_prediction_tokens: u32,
+    /// Tokens of an OpenAI `prediction` that did not appear in the answer.
+    /// OpenAI bills them as completion tokens and includes them in `completion_tokens`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rejected_prediction_tokens: u32,
+}
+
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+fn has_single_choice(choices: &[ResponseChoice]) -> bool {
+    choices.len() <= 1
+}
+
+/// Content block type of answer text.
+pub const TEXT_BLOCK_TYPE: &str = "text";
+
+/// Content block type of the reasoning when `thinking_format` asks for a structured form.
+pub const THINKING_BLOCK_TYPE: &str = "thinking";
+
+/// Content block type of the reasoner's own answer in non-streaming responses.
+pub const REASONER_ANSWER_BLOCK_TYPE: &str = "reasoner_answer";
+
 /// A block of content in a response.
 ///
 /// Represents a single piece of content in the response,
@@ -47,7 +276,8 @@ pub struct ExternalApiResponse {
 /// Represents different types of events that can occur
 /// during a streaming response, including content updates
 /// and usage statistics.
-#[derive(Debug, Serialize)]
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Default)]
 #[serde(tag = "type")]
 pub enum StreamEvent {
     #[serde(rename = "start")]
@@ -62,17 +292,73 @@ pub enum StreamEvent {
     Error {
         message: String,
         code: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorDetails>,
+        /// Pipeline phase that failed: `"reasoning"` or `"answering"`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phase: Option<String>,
+        /// Upstream provider of the failed phase.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        provider: Option<String>,
+        /// HTTP status of the upstream response, when it answered with an error.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        upstream_status: Option<u16>,
+        /// True if model output was already streamed before the failure.
+        partial: bool,
     },
+    #[serde(rename = "metadata")]
+    Metadata {
+        /// Unparseable upstream frames skipped, keyed by provider.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        dropped_frames: HashMap<String, u64>,
+        /// Real upstream model names, keyed by pipeline stage; only sent for verbose requests.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        upstream_models: HashMap<String, serde_json::Value>,
+        /// Reasoner replica the request was routed to, for mappings with `reasoner_endpoints`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reasoner_endpoint: Option<String>,
+        /// Per-phase latency; only sent for verbose requests or when `include_timings` is set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timings: Option<Timings>,
+        /// Set when the reasoning was cut off by the reasoning timeout or the reasoner's `max_tokens`.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        reasoning_truncated: bool,
+        /// Set when the target returned an empty answer under the `pass` empty answer policy.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        empty_answer: bool,
+        /// Token usage reported by the upstreams, summed across phases.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<UsageStats>,
+        /// Set when a failure cut the streamed output off.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        partial: bool,
+        /// Which answer was streamed, for mappings with optimistic answering.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        optimistic_path: Option<OptimisticPath>,
+        /// Which model wrote the answer, for mappings with `use_reasoner_answer`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        answered_by: Option<AnswerAuthor>,
+        /// Whether the injected reasoning was reused or fresh, when reasoning reuse is enabled.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reasoning_source: Option<ReasoningSource>,
+        /// Replacements the `[[redaction]]` rules made in the injected reasoning.
+        #[serde(skip_serializing_if = "is_zero")]
+        redactions: u32,
+        /// What the mapping's `max_messages` trimmed from the conversation.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trimmed: Option<TrimReport>,
+        /// The request's `metadata`, echoed back.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        metadata: HashMap<String, String>,
+    },
+    /// One of the request's warnings, sent before the metadata event.
+    #[serde(rename = "warning")]
+    Warning(Warning),
     #[serde(rename = "done")]
+    #[default]
     Done,
 }
 
-impl Default for StreamEvent {
-    fn default() -> Self {
-        StreamEvent::Done
-    }
-}
-
 impl ContentBlock {
     /// Creates a new text content block.
     ///
@@ -85,11 +371,19 @@ impl ContentBlock {
     /// A new `ContentBlock` with the type set to "text"
     pub fn text(text: impl Into<String>) -> Self {
         Self {
-            content_type: "text".to_string(),
+            content_type: TEXT_BLOCK_TYPE.to_string(),
             text: text.into(),
         }
     }
 
+    /// Creates a block carrying the reasoning in a structured thinking format.
+    pub fn thinking(reasoning: impl Into<String>) -> Self {
+        Self {
+            content_type: THINKING_BLOCK_TYPE.to_string(),
+            text: reasoning.into(),
+        }
+    }
+
     /// Converts an Anthropic content block to a generic content block.
     ///
     /// # Arguments
@@ -99,6 +393,8 @@ impl ContentBlock {
     /// # Returns
     ///
     /// A new `ContentBlock` with the same content type and text
+    #[cfg(feature = "anthropic")]
+    #[allow(dead_code)]
     pub fn from_anthropic(block: crate::clients::anthropic::ContentBlock) -> Self {
         Self {
             content_type: block.content_type,
@@ -107,24 +403,30 @@ impl ContentBlock {
     }
 }
 
-impl ApiResponse {
-    /// Creates a new API response with simple text content.
-    ///
-    /// # Arguments
-    ///
-    /// * `content` - The text content for the response
-    ///
-    /// # Returns
-    ///
-    /// A new `ApiResponse` with default values and the provided content
-    #[allow(dead_code)]
-    pub fn new(content: impl Into<String>) -> Self {
-        Self {
-            created: Utc::now(),
-            content: vec![ContentBlock::text(content)],
-            // deepseek_response: None,
-            // anthropic_response: None,
-        }
+impl UsageStats {
+    /// Adds the token counts of one upstream call.
+    pub fn add(&mut self, prompt_tokens: u32, completion_tokens: u32) {
+        self.prompt_tokens += prompt_tokens;
+        self.completion_tokens += completion_tokens;
+        self.total_tokens += prompt_tokens + completion_tokens;
+    }
+
+    /// Records prompt tokens, already counted by [`UsageStats::add`], that were read from a cache.
+    pub fn add_cached(&mut self, cached_prompt_tokens: u32) {
+        self.cached_prompt_tokens += cached_prompt_tokens;
+    }
+
+    /// Records predicted output tokens, already counted by [`UsageStats::add`].
+    pub fn add_prediction(&mut self, accepted: u32, rejected: u32) {
+        self.accepted_prediction_tokens += accepted;
+        self.rejected_prediction_tokens += rejected;
+    }
+
+    /// Adds the token counts of another usage, including its cached and predicted tokens.
+    pub fn merge(&mut self, other: &UsageStats) {
+        self.add(other.prompt_tokens, other.completion_tokens);
+        self.add_cached(other.cached_prompt_tokens);
+        self.add_prediction(other.accepted_prediction_tokens, other.rejected_prediction_tokens);
     }
 }
 
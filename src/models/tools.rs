@@ -0,0 +1,437 @@
+//! Tool calling in OpenAI and Anthropic form.
+//!
+//! Clients describe tools and tool turns the OpenAI way: `tools[].function`
+//! definitions, assistant `tool_calls` and `tool` role messages. Anthropic
+//! targets expect `tools[].input_schema`, `tool_use` blocks in the assistant
+//! turn and `tool_result` blocks in the user turn that follows. Requests are
+//! converted into the Anthropic form on the way in, and Anthropic `tool_use`
+//! output is converted back into OpenAI `tool_calls` on the way out.
+
+use super::content::{ContentPart, MessageContent};
+use super::request::{Message, Role};
+use crate::error::{ApiError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A tool invocation requested by the model, in OpenAI form.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+/// The function named by a tool call and its arguments.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON-encoded object.
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+impl ToolCall {
+    /// Creates a function tool call.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The call id clients echo back in the `tool` message
+    /// * `name` - The function name
+    /// * `arguments` - The JSON-encoded arguments
+    pub fn function(id: String, name: impl Into<String>, arguments: impl Into<String>) -> Self {
+        Self {
+            id,
+            call_type: function_type(),
+            function: FunctionCall {
+                name: name.into(),
+                arguments: arguments.into(),
+            },
+        }
+    }
+
+    /// Converts an Anthropic `tool_use` block into a tool call.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The call id to report to the client
+    /// * `name` - The tool name from the block
+    /// * `input` - The block's `input` object; a missing input becomes `{}`
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+    pub fn from_tool_use(id: String, name: impl Into<String>, input: Option<&serde_json::Value>) -> Self {
+        let arguments = input
+            .map(|input| input.to_string())
+            .unwrap_or_else(|| "{}".to_string());
+        Self::function(id, name, arguments)
+    }
+
+    /// Renders the call as text, for targets and reasoners without tool support.
+    pub fn to_text(&self) -> String {
+        format!("[tool_call {} {}]\n{}", self.id, self.function.name, self.function.arguments)
+    }
+}
+
+/// Converts OpenAI `tools` definitions into Anthropic `tools`.
+///
+/// Each `{"type": "function", "function": {name, description, parameters}}`
+/// becomes `{name, description, input_schema}`; a function without
+/// `parameters` gets an empty object schema.
+///
+/// # Arguments
+///
+/// * `tools` - The request's `tools` array
+///
+/// # Returns
+///
+/// * `Result<serde_json::Value>` - The Anthropic `tools` array
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if `tools` is not an array or an entry is
+/// not a named function tool.
+pub fn anthropic_tools(tools: &serde_json::Value) -> Result<serde_json::Value> {
+    let tools = tools.as_array().ok_or_else(|| ApiError::BadRequest {
+        message: "tools must be an array".to_string(),
+    })?;
+
+    tools
+        .iter()
+        .enumerate()
+        .map(|(index, tool)| {
+            let function = tool
+                .get("function")
+                .filter(|_| tool.get("type").and_then(|t| t.as_str()) == Some("function"))
+                .ok_or_else(|| ApiError::BadRequest {
+                    message: format!("tools[{}] is not a function tool", index),
+                })?;
+            let name = function.get("name").and_then(|n| n.as_str()).ok_or_else(|| ApiError::BadRequest {
+                message: format!("tools[{}].function has no name", index),
+            })?;
+
+            let mut converted = serde_json::json!({
+                "name": name,
+                "input_schema": function
+                    .get("parameters")
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!({"type": "object", "properties": {}})),
+            });
+            if let Some(description) = function.get("description") {
+                converted["description"] = description.clone();
+            }
+            Ok(converted)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(serde_json::Value::Array)
+}
+
+/// Converts an OpenAI `tool_choice` into the Anthropic form.
+///
+/// `auto`, `required` and `none` map to Anthropic's `auto`, `any` and `none`;
+/// a named function maps to `{"type": "tool", "name": ...}`.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for any other value.
+pub fn anthropic_tool_choice(tool_choice: &serde_json::Value) -> Result<serde_json::Value> {
+    let choice_type = match tool_choice.as_str() {
+        Some("auto") => "auto",
+        Some("required") => "any",
+        Some("none") => "none",
+        Some(other) => {
+            return Err(ApiError::BadRequest {
+                message: format!("Unsupported tool_choice '{}'", other),
+            })
+        }
+        None => {
+            let name = tool_choice
+                .pointer("/function/name")
+                .and_then(|n| n.as_str())
+                .ok_or_else(|| ApiError::BadRequest {
+                    message: "tool_choice must be a string or name a function".to_string(),
+                })?;
+            return Ok(serde_json::json!({"type": "tool", "name": name}));
+        }
+    };
+    Ok(serde_json::json!({"type": choice_type}))
+}
+
+/// Rewrites OpenAI tool turns into Anthropic content blocks.
+///
+/// An assistant message's `tool_calls` become `tool_use` blocks following
+/// its text, and each run of consecutive `tool` messages becomes a single
+/// user turn of `tool_result` blocks. Other messages are returned unchanged.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation in OpenAI form
+///
+/// # Returns
+///
+/// * `Result<Vec<Message>>` - The conversation with no `tool` roles or `tool_calls` left
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if a `tool` message has no `tool_call_id`
+/// or a call's arguments are not a JSON object.
+pub fn to_anthropic_messages(messages: &[Message]) -> Result<Vec<Message>> {
+    let mut converted: Vec<Message> = Vec::with_capacity(messages.len());
+    let mut in_tool_results = false;
+
+    for (index, message) in messages.iter().enumerate() {
+        if message.role == Role::Tool {
+            let tool_use_id = message.tool_call_id.clone().ok_or_else(|| ApiError::BadRequest {
+                message: format!("messages[{}] has role 'tool' but no tool_call_id", index),
+            })?;
+            let result = ContentPart::ToolResult {
+                tool_use_id,
+                content: serde_json::Value::String(message.content.to_text()),
+                is_error: None,
+            };
+            // 连续的工具结果合并到同一个 user 消息中
+            let previous = converted.last_mut().filter(|_| in_tool_results);
+            if let Some(MessageContent::Parts(parts)) = previous.map(|last| &mut last.content) {
+                parts.push(result);
+            } else {
+                converted.push(Message {
+                    role: Role::User,
+                    content: MessageContent::Parts(vec![result]),
+                    tool_calls: None,
+                    tool_call_id: None,
+                });
+            }
+            in_tool_results = true;
+            continue;
+        }
+        in_tool_results = false;
+
+        let tool_calls = match (&message.role, &message.tool_calls) {
+            (Role::Assistant, Some(tool_calls)) if !tool_calls.is_empty() => tool_calls,
+            _ => {
+                converted.push(message.clone());
+                continue;
+            }
+        };
+
+        let mut parts = match &message.content {
+            MessageContent::Text(text) if text.is_empty() => Vec::new(),
+            MessageContent::Text(text) => vec![ContentPart::Text { text: text.clone() }],
+            MessageContent::Parts(parts) => parts.clone(),
+        };
+        for call in tool_calls {
+            parts.push(ContentPart::ToolUse {
+                id: call.id.clone(),
+                name: call.function.name.clone(),
+                input: parse_arguments(index, call)?,
+            });
+        }
+        converted.push(Message {
+            role: Role::Assistant,
+            content: MessageContent::Parts(parts),
+            tool_calls: None,
+            tool_call_id: None,
+        });
+    }
+
+    Ok(converted)
+}
+
+/// Parses a tool call's arguments into the object Anthropic expects as `input`.
+fn parse_arguments(message_index: usize, call: &ToolCall) -> Result<serde_json::Value> {
+    if call.function.arguments.trim().is_empty() {
+        return Ok(serde_json::json!({}));
+    }
+    match serde_json::from_str::<serde_json::Value>(&call.function.arguments) {
+        Ok(input @ serde_json::Value::Object(_)) => Ok(input),
+        _ => Err(ApiError::BadRequest {
+            message: format!(
+                "messages[{}].tool_calls '{}' arguments must be a JSON object",
+                message_index, call.id
+            ),
+        }),
+    }
+}
+
+/// Accumulates streamed Anthropic `tool_use` blocks into complete tool calls.
+///
+/// Anthropic streams a tool's input as `input_json_delta` fragments between
+/// the block's start and stop events. The fragments are buffered per block
+/// and the call is released whole when its block stops, so clients receive
+/// one tool call delta with the complete arguments string.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+pub struct ToolCallAccumulator {
+    pending: HashMap<usize, PendingToolCall>,
+    completed: usize,
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+struct PendingToolCall {
+    name: String,
+    arguments: String,
+}
+
+#[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+impl ToolCallAccumulator {
+    /// Starts buffering the `tool_use` block at `block_index`.
+    pub fn start(&mut self, block_index: usize, name: impl Into<String>) {
+        self.pending.insert(
+            block_index,
+            PendingToolCall {
+                name: name.into(),
+                arguments: String::new(),
+            },
+        );
+    }
+
+    /// Appends an `input_json_delta` fragment to the block at `block_index`.
+    ///
+    /// Fragments for blocks that were never started are ignored.
+    pub fn push(&mut self, block_index: usize, partial_json: &str) {
+        if let Some(pending) = self.pending.get_mut(&block_index) {
+            pending.arguments.push_str(partial_json);
+        }
+    }
+
+    /// Completes the block at `block_index`.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_index` - The stopped content block
+    /// * `id` - Produces the call id to report to the client; only called
+    ///   for tool call blocks
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(usize, ToolCall)>` - The call's position among this
+    ///   response's tool calls and the call itself, or `None` if the block
+    ///   was not a tool call
+    pub fn finish(&mut self, block_index: usize, id: impl FnOnce() -> String) -> Option<(usize, ToolCall)> {
+        let pending = self.pending.remove(&block_index)?;
+        let arguments = if pending.arguments.trim().is_empty() {
+            "{}".to_string()
+        } else {
+            pending.arguments
+        };
+        let position = self.completed;
+        self.completed += 1;
+        Some((position, ToolCall::function(id(), pending.name, arguments)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(value: serde_json::Value) -> Message {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn converts_function_tools_to_input_schemas() {
+        let tools = json!([
+            {"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Current weather of a city",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"]}
+            }},
+            {"type": "function", "function": {"name": "now"}}
+        ]);
+        assert_eq!(
+            anthropic_tools(&tools).unwrap(),
+            json!([
+                {
+                    "name": "get_weather",
+                    "description": "Current weather of a city",
+                    "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"]}
+                },
+                {"name": "now", "input_schema": {"type": "object", "properties": {}}}
+            ])
+        );
+        assert!(anthropic_tools(&json!([{"type": "retrieval"}])).is_err());
+        assert!(anthropic_tools(&json!({})).is_err());
+    }
+
+    #[test]
+    fn converts_tool_choices() {
+        assert_eq!(anthropic_tool_choice(&json!("auto")).unwrap(), json!({"type": "auto"}));
+        assert_eq!(anthropic_tool_choice(&json!("required")).unwrap(), json!({"type": "any"}));
+        assert_eq!(
+            anthropic_tool_choice(&json!({"type": "function", "function": {"name": "now"}})).unwrap(),
+            json!({"type": "tool", "name": "now"})
+        );
+        assert!(anthropic_tool_choice(&json!("sometimes")).is_err());
+    }
+
+    #[test]
+    fn tool_turns_become_tool_use_and_grouped_tool_results() {
+        let messages = [
+            message(json!({"role": "user", "content": "Weather in Paris and Rome?"})),
+            message(json!({"role": "assistant", "content": "Checking.", "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}},
+                {"id": "call_2", "type": "function", "function": {"name": "get_weather", "arguments": ""}}
+            ]})),
+            message(json!({"role": "tool", "tool_call_id": "call_1", "content": "18C"})),
+            message(json!({"role": "tool", "tool_call_id": "call_2", "content": "24C"})),
+        ];
+        let converted = serde_json::to_value(to_anthropic_messages(&messages).unwrap()).unwrap();
+        assert_eq!(converted.as_array().unwrap().len(), 3);
+        assert_eq!(
+            converted[1]["content"],
+            json!([
+                {"type": "text", "text": "Checking."},
+                {"type": "tool_use", "id": "call_1", "name": "get_weather", "input": {"city": "Paris"}},
+                {"type": "tool_use", "id": "call_2", "name": "get_weather", "input": {}}
+            ])
+        );
+        assert_eq!(converted[2]["role"], "user");
+        assert_eq!(converted[2]["content"][0]["tool_use_id"], "call_1");
+        assert_eq!(converted[2]["content"][1]["tool_use_id"], "call_2");
+        assert!(converted[1].get("tool_calls").is_none());
+    }
+
+    #[test]
+    fn rejects_tool_turns_anthropic_cannot_represent() {
+        let orphan = [message(json!({"role": "tool", "content": "18C"}))];
+        assert!(to_anthropic_messages(&orphan).is_err());
+        let not_an_object = [message(json!({"role": "assistant", "tool_calls": [
+            {"id": "call_1", "type": "function", "function": {"name": "now", "arguments": "[1]"}}
+        ]}))];
+        assert!(to_anthropic_messages(&not_an_object).is_err());
+    }
+
+    #[test]
+    fn tool_use_round_trips_through_a_tool_call() {
+        let input = json!({"city": "Paris", "days": 2});
+        let call = ToolCall::from_tool_use("call_1".to_string(), "get_weather", Some(&input));
+        let assistant = Message {
+            role: Role::Assistant,
+            content: MessageContent::Text(String::new()),
+            tool_calls: Some(vec![call]),
+            tool_call_id: None,
+        };
+        let converted = serde_json::to_value(to_anthropic_messages(&[assistant]).unwrap()).unwrap();
+        assert_eq!(converted[0]["content"][0]["input"], input);
+    }
+
+    #[test]
+    fn streamed_input_fragments_are_released_whole() {
+        let mut calls = ToolCallAccumulator::default();
+        calls.start(1, "get_weather");
+        calls.push(1, "{\"ci");
+        calls.push(2, "ignored");
+        calls.push(1, "ty\":\"Paris\"}");
+        assert!(calls.finish(0, || unreachable!()).is_none());
+        let (position, call) = calls.finish(1, || "call_1".to_string()).unwrap();
+        assert_eq!(position, 0);
+        assert_eq!(call, ToolCall::function("call_1".to_string(), "get_weather", "{\"city\":\"Paris\"}"));
+
+        calls.start(3, "now");
+        assert_eq!(calls.finish(3, || "call_2".to_string()).unwrap(), (1, ToolCall::function("call_2".to_string(), "now", "{}")));
+    }
+}
@@ -0,0 +1,273 @@
+//! Plain-text transcripts of tool-using conversations for the reasoner.
+//!
+//! Reasoners often make little sense of raw `tool_calls` JSON and `tool`
+//! role messages. With `reasoner_transcript = "rendered"` the conversation
+//! sent to the reasoner is replaced by one readable transcript: the tool
+//! definitions summarized at the top, then every turn in order, with tool
+//! calls written as `Assistant called search({...})` and tool results as
+//! `Tool search returned: ...`. Only the reasoner sees the transcript; the
+//! target always gets the original structured messages.
+
+use super::content::{tool_result_text, ContentPart, MessageContent};
+use super::request::{Message, Role};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Longest tool call argument string kept in a transcript, in characters.
+const MAX_ARGUMENTS_CHARS: usize = 500;
+
+/// Longest tool result kept in a transcript, in characters.
+const MAX_RESULT_CHARS: usize = 2000;
+
+/// How the conversation is presented to the reasoner.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerTranscript {
+    /// The messages as the client sent them.
+    #[default]
+    Raw,
+    /// A single plain-text transcript of the conversation and its tool use.
+    Rendered,
+}
+
+/// How the caller's system prompt reaches the reasoner, which always gets
+/// DeepThink's own reasoning prompt as its first system message.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonerSystemPrompt {
+    /// The reasoner does not see the caller's system prompt.
+    #[default]
+    None,
+    /// A second system message after the reasoning prompt.
+    AsSystem,
+    /// A `Task context:` user message at the top of the conversation.
+    AsContext,
+}
+
+/// Prefix of the user message carrying the system prompt in `as_context` mode.
+pub const TASK_CONTEXT_PREFIX: &str = "Task context:";
+
+/// Renders a conversation as a plain-text transcript for the reasoner.
+///
+/// The system message, if any, is kept as is; everything else becomes one
+/// user message. Tool results are attributed to the tool whose call they
+/// answer, and long arguments and results are cut off.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation, system message first
+/// * `tools` - The request's tool definitions, in OpenAI or Anthropic form
+///
+/// # Returns
+///
+/// * `Vec<Message>` - The system message followed by the transcript
+pub fn render_transcript(messages: &[Message], tools: Option<&serde_json::Value>) -> Vec<Message> {
+    let mut rendered = Vec::new();
+    let mut sections = Vec::new();
+    if let Some(summary) = tools.and_then(summarize_tools) {
+        sections.push(summary);
+    }
+
+    // tool 消息只带 tool_call_id, 按调用 id 找回工具名
+    let mut tool_names: HashMap<&str, &str> = HashMap::new();
+    let mut turns = Vec::new();
+    for message in messages {
+        match message.role {
+            Role::System | Role::Developer => rendered.push(message.clone()),
+            Role::User => turns.extend(render_parts(&message.content, "User", &mut tool_names)),
+            Role::Assistant => {
+                turns.extend(render_parts(&message.content, "Assistant", &mut tool_names));
+                for call in message.tool_calls.iter().flatten() {
+                    tool_names.insert(&call.id, &call.function.name);
+                    turns.push(format!(
+                        "Assistant called {}({})",
+                        call.function.name,
+                        truncate(&call.function.arguments, MAX_ARGUMENTS_CHARS)
+                    ));
+                }
+            }
+            Role::Tool => {
+                let name = message
+                    .tool_call_id
+                    .as_deref()
+                    .and_then(|id| tool_names.get(id).copied())
+                    .unwrap_or("unknown");
+                turns.push(tool_result_line(name, &message.content.to_text()));
+            }
+        }
+    }
+    if !turns.is_empty() {
+        sections.push(format!("Conversation:\n{}", turns.join("\n\n")));
+    }
+
+    rendered.push(Message {
+        role: Role::User,
+        content: sections.join("\n\n").into(),
+        tool_calls: None,
+        tool_call_id: None,
+    });
+    rendered
+}
+
+/// Renders one message's content, writing Anthropic-style tool blocks the
+/// same way as OpenAI tool turns.
+fn render_parts<'a>(content: &'a MessageContent, speaker: &str, tool_names: &mut HashMap<&'a str, &'a str>) -> Vec<String> {
+    let parts = match content {
+        MessageContent::Text(text) if text.trim().is_empty() => return Vec::new(),
+        MessageContent::Text(text) => return vec![format!("{}: {}", speaker, text)],
+        MessageContent::Parts(parts) => parts,
+    };
+
+    let mut lines = Vec::new();
+    let mut text = Vec::new();
+    for part in parts {
+        match part {
+            ContentPart::ToolUse { id, name, input } => {
+                tool_names.insert(id, name);
+                lines.push(format!(
+                    "Assistant called {}({})",
+                    name,
+                    truncate(&input.to_string(), MAX_ARGUMENTS_CHARS)
+                ));
+            }
+            ContentPart::ToolResult { tool_use_id, content, .. } => {
+                let name = tool_names.get(tool_use_id.as_str()).copied().unwrap_or("unknown");
+                lines.push(tool_result_line(name, &tool_result_text(content)));
+            }
+            _ => text.push(part.to_text()),
+        }
+    }
+    let text = text.join("\n");
+    if !text.trim().is_empty() {
+        lines.insert(0, format!("{}: {}", speaker, text));
+    }
+    lines
+}
+
+fn tool_result_line(name: &str, result: &str) -> String {
+    format!("Tool {} returned: {}", name, truncate(result, MAX_RESULT_CHARS))
+}
+
+/// Summarizes tool definitions as one line per tool: its name, parameter
+/// names and description.
+fn summarize_tools(tools: &serde_json::Value) -> Option<String> {
+    let lines: Vec<String> = tools
+        .as_array()?
+        .iter()
+        .filter_map(|tool| {
+            // OpenAI 形式的定义在 function 下, Anthropic 形式直接在顶层
+            let function = tool.get("function").unwrap_or(tool);
+            let name = function.get("name")?.as_str()?;
+            let parameters = function
+                .get("parameters")
+                .or_else(|| function.get("input_schema"))
+                .and_then(|schema| schema.get("properties"))
+                .and_then(|properties| properties.as_object())
+                .map(|properties| properties.keys().cloned().collect::<Vec<_>>().join(", "))
+                .unwrap_or_default();
+            let line = match function.get("description").and_then(|d| d.as_str()) {
+                Some(description) => format!("- {}({}): {}", name, parameters, description),
+                None => format!("- {}({})", name, parameters),
+            };
+            Some(line)
+        })
+        .collect();
+    (!lines.is_empty()).then(|| format!("Available tools:\n{}", lines.join("\n")))
+}
+
+/// Cuts `text` off after `max_chars` characters, noting how much was dropped.
+fn truncate(text: &str, max_chars: usize) -> String {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max_chars).collect();
+    format!("{}… [{} more characters]", kept, total - max_chars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(value: serde_json::Value) -> Vec<Message> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn renders_openai_tool_turns_as_text() {
+        let conversation = messages(json!([
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1", "type": "function",
+                "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "18°C, cloudy"},
+            {"role": "tool", "tool_call_id": "call_9", "content": "orphan"}
+        ]));
+        let tools = json!([{"type": "function", "function": {
+            "name": "get_weather",
+            "description": "Current weather of a city",
+            "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+        }}]);
+
+        let rendered = render_transcript(&conversation, Some(&tools));
+        assert_eq!(rendered.len(), 2);
+        assert_eq!(rendered[0].role, Role::System);
+        assert_eq!(rendered[0].content.to_text(), "Be brief.");
+        assert_eq!(rendered[1].role, Role::User);
+        assert!(rendered[1].tool_calls.is_none() && rendered[1].tool_call_id.is_none());
+        assert_eq!(
+            rendered[1].content.to_text(),
+            "Available tools:\n- get_weather(city): Current weather of a city\n\n\
+             Conversation:\nUser: Weather in Paris?\n\n\
+             Assistant called get_weather({\"city\":\"Paris\"})\n\n\
+             Tool get_weather returned: 18°C, cloudy\n\n\
+             Tool unknown returned: orphan"
+        );
+    }
+
+    #[test]
+    fn renders_anthropic_tool_blocks_like_openai_turns() {
+        let conversation = messages(json!([
+            {"role": "assistant", "content": [
+                {"type": "text", "text": "Let me check."},
+                {"type": "tool_use", "id": "toolu_1", "name": "search", "input": {"q": "rust"}}
+            ]},
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "3 hits"}]},
+                {"type": "text", "text": "Summarize them."}
+            ]}
+        ]));
+        let tools = json!([{"name": "search", "input_schema": {"type": "object", "properties": {"q": {}}}}]);
+
+        let rendered = render_transcript(&conversation, Some(&tools));
+        assert_eq!(rendered.len(), 1);
+        assert_eq!(
+            rendered[0].content.to_text(),
+            "Available tools:\n- search(q)\n\n\
+             Conversation:\nAssistant: Let me check.\n\n\
+             Assistant called search({\"q\":\"rust\"})\n\n\
+             User: Summarize them.\n\n\
+             Tool search returned: 3 hits"
+        );
+    }
+
+    #[test]
+    fn truncates_long_arguments_and_results() {
+        let result = "x".repeat(MAX_RESULT_CHARS + 5);
+        let conversation = messages(json!([
+            {"role": "assistant", "content": "", "tool_calls": [{
+                "id": "call_1", "type": "function",
+                "function": {"name": "dump", "arguments": "a".repeat(MAX_ARGUMENTS_CHARS + 1)}
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": result}
+        ]));
+
+        let text = render_transcript(&conversation, None)[0].content.to_text();
+        assert!(!text.contains("Available tools"));
+        assert!(text.contains(&format!("dump({}… [1 more characters])", "a".repeat(MAX_ARGUMENTS_CHARS))), "{}", text);
+        assert!(text.ends_with(&format!("{}… [5 more characters]", "x".repeat(MAX_RESULT_CHARS))), "{}", text);
+    }
+}
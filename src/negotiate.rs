@@ -0,0 +1,525 @@
+//! Negotiation between a chat request's `stream` flag and its `Accept` header.
+//!
+//! A request whose `Accept` header names only one of `text/event-stream` and
+//! `application/json` states which response body it can parse. A missing
+//! `stream` flag follows that header. A flag that contradicts it is resolved
+//! by `streaming.accept_precedence`; by default the request is rejected with
+//! `406`, so the client does not get SSE bytes it will try to parse as JSON.
+//! SSE responses also get headers that stop proxies from caching or
+//! buffering them.
+//!
+//! Clients that cannot parse SSE can ask for the stream as chunked NDJSON,
+//! with `Accept: application/x-ndjson` or `"stream_transport": "ndjson"`.
+//! The pipeline still produces SSE events; [`stream_response`] rewrites the
+//! rendered frames into one JSON object per line, keep-alives into `{}`
+//! lines and the final `[DONE]` into `{"done":true}`.
+//!
+//! Frontends built on the Vercel AI SDK can ask `/v1/chat/completions` for
+//! its data stream protocol with `"stream_transport": "vercel-ai"`. The chunks
+//! are rewritten the same way: reasoning deltas into `g:` parts, answer deltas
+//! into `0:` parts, tool calls into `9:` parts, warnings into `8:` message
+//! annotations and errors into `3:` parts, and `[DONE]` into a `d:` finish
+//! message carrying the finish reason and usage.
+
+use crate::{
+    config::AcceptPrecedence,
+    error::{ApiError, Result},
+};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+
+const EVENT_STREAM: &str = "text/event-stream";
+const JSON: &str = "application/json";
+const NDJSON: &str = "application/x-ndjson";
+
+/// Content type of the Vercel AI data stream.
+const VERCEL_AI: &str = "text/plain; charset=utf-8";
+
+/// Header the Vercel AI SDK checks to recognize its data stream.
+const VERCEL_AI_HEADER: &str = "x-vercel-ai-data-stream";
+
+/// Line ending an NDJSON stream, in place of SSE's `[DONE]`.
+const NDJSON_DONE: &str = "{\"done\":true}\n";
+
+/// Line sent for an SSE keep-alive comment.
+const NDJSON_KEEP_ALIVE: &str = "{}\n";
+
+/// Wire format of a streamed chat response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamTransport {
+    /// Server-sent events.
+    #[default]
+    Sse,
+    /// One JSON chunk per line.
+    Ndjson,
+    /// The Vercel AI SDK data stream protocol; chat completions only.
+    #[serde(rename = "vercel-ai")]
+    VercelAi,
+}
+
+/// Decides whether a chat request is answered as a stream.
+///
+/// # Arguments
+///
+/// * `precedence` - Which side wins when the flag and the header conflict
+/// * `headers` - The request headers, consulted for `Accept`
+/// * `stream` - The body's `stream` flag, `None` when absent
+///
+/// # Returns
+///
+/// * `Result<bool>` - True to answer with SSE
+///
+/// # Errors
+///
+/// Returns `ApiError::NotAcceptable` if the flag contradicts the `Accept`
+/// header under the `reject` precedence.
+pub fn wants_stream(precedence: AcceptPrecedence, headers: &HeaderMap, stream: Option<bool>) -> Result<bool> {
+    let Some(accepts_stream) = accepted_format(headers) else {
+        return Ok(stream.unwrap_or(false));
+    };
+    match stream {
+        None => Ok(accepts_stream),
+        Some(stream) if stream == accepts_stream => Ok(stream),
+        Some(stream) => match precedence {
+            AcceptPrecedence::Reject => Err(ApiError::NotAcceptable {
+                accept: headers
+                    .get(header::ACCEPT)
+                    .and_then(|h| h.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                stream,
+            }),
+            AcceptPrecedence::Accept => Ok(accepts_stream),
+            AcceptPrecedence::Body => Ok(stream),
+        },
+    }
+}
+
+/// Returns `Some(true)` if `Accept` names only a stream format (the event
+/// stream or NDJSON), `Some(false)` if it names only JSON, and `None` if it
+/// names both, neither or is absent.
+fn accepted_format(headers: &HeaderMap) -> Option<bool> {
+    let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+    let mut event_stream = false;
+    let mut json = false;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or_default().trim();
+        // q=0 表示明确不接受该类型
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        if refused {
+            continue;
+        }
+        event_stream |= media_type.eq_ignore_ascii_case(EVENT_STREAM) || media_type.eq_ignore_ascii_case(NDJSON);
+        json |= media_type.eq_ignore_ascii_case(JSON);
+    }
+    match (event_stream, json) {
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Decides the wire format of a streamed response.
+///
+/// The request's `stream_transport` field wins; without it, an `Accept`
+/// header naming NDJSON but not the event stream selects NDJSON.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if `stream_transport` is not `"sse"`,
+/// `"ndjson"` or `"vercel-ai"`.
+pub fn stream_transport(headers: &HeaderMap, field: Option<&serde_json::Value>) -> Result<StreamTransport> {
+    if let Some(field) = field {
+        return serde_json::from_value(field.clone()).map_err(|_| ApiError::BadRequest {
+            message: format!("stream_transport must be \"sse\", \"ndjson\" or \"vercel-ai\", got {}", field),
+        });
+    }
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()) else {
+        return Ok(StreamTransport::Sse);
+    };
+    let names = |media_type: &str| {
+        accept
+            .split(',')
+            .any(|range| range.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(media_type))
+    };
+    Ok(if names(NDJSON) && !names(EVENT_STREAM) {
+        StreamTransport::Ndjson
+    } else {
+        StreamTransport::Sse
+    })
+}
+
+/// Rejects the Vercel AI data stream on routes whose events it cannot carry.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for [`StreamTransport::VercelAi`].
+pub fn chunk_transport(transport: StreamTransport) -> Result<StreamTransport> {
+    if transport == StreamTransport::VercelAi {
+        return Err(ApiError::BadRequest {
+            message: "stream_transport \"vercel-ai\" is only supported on /v1/chat/completions".to_string(),
+        });
+    }
+    Ok(transport)
+}
+
+/// Converts a streamed chat response into the requested transport.
+pub fn stream_response(transport: StreamTransport, sse: impl IntoResponse) -> Response {
+    match transport {
+        StreamTransport::Sse => sse_response(sse),
+        StreamTransport::Ndjson => ndjson_response(sse),
+        StreamTransport::VercelAi => vercel_ai_response(sse),
+    }
+}
+
+/// Converts an SSE body into a response that proxies neither cache nor buffer.
+///
+/// Sets `Cache-Control: no-store` and `X-Accel-Buffering: no` next to the
+/// `text/event-stream` content type, which nginx otherwise buffers until
+/// the stream ends.
+pub fn sse_response(sse: impl IntoResponse) -> Response {
+    let mut response = sse.into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(EVENT_STREAM));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert("X-Accel-Buffering", HeaderValue::from_static("no"));
+    response
+}
+
+/// Rewrites an SSE body into chunked NDJSON.
+///
+/// Each frame's `data` becomes one line; `id` and `event` fields are
+/// dropped, since every payload is self-describing JSON.
+fn ndjson_response(sse: impl IntoResponse) -> Response {
+    let (mut parts, body) = sse.into_response().into_parts();
+    let mut frames = body.into_data_stream();
+    let lines = async_stream::stream! {
+        let mut pending = String::new();
+        while let Some(Ok(chunk)) = frames.next().await {
+            pending.push_str(&String::from_utf8_lossy(&chunk));
+            while let Some(end) = pending.find("\n\n") {
+                let frame: String = pending.drain(..end + 2).collect();
+                if let Some(line) = ndjson_line(&frame) {
+                    yield Ok::<_, Infallible>(line);
+                }
+            }
+        }
+    };
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+    parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    parts.headers.insert("X-Accel-Buffering", HeaderValue::from_static("no"));
+    Response::from_parts(parts, Body::from_stream(lines))
+}
+
+/// Returns the NDJSON line of one SSE frame, or `None` for frames without data.
+fn ndjson_line(frame: &str) -> Option<String> {
+    let data: Vec<&str> = frame
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    if data.is_empty() {
+        // 只有注释的帧是心跳
+        return frame
+            .lines()
+            .any(|line| line.starts_with(':'))
+            .then(|| NDJSON_KEEP_ALIVE.to_string());
+    }
+    let data = data.join("\n");
+    if data == "[DONE]" {
+        return Some(NDJSON_DONE.to_string());
+    }
+    Some(format!("{}\n", data))
+}
+
+/// Rewrites an SSE body of chat completion chunks into the Vercel AI data stream.
+///
+/// Only the first choice is carried, as the SDK builds a single message.
+/// The chunks must carry reasoning in `delta.reasoning_content`.
+fn vercel_ai_response(sse: impl IntoResponse) -> Response {
+    let (mut parts, body) = sse.into_response().into_parts();
+    let mut frames = body.into_data_stream();
+    let lines = async_stream::stream! {
+        let mut pending = String::new();
+        let mut finish = VercelAiFinish::default();
+        while let Some(Ok(chunk)) = frames.next().await {
+            pending.push_str(&String::from_utf8_lossy(&chunk));
+            while let Some(end) = pending.find("\n\n") {
+                let frame: String = pending.drain(..end + 2).collect();
+                let lines = vercel_ai_lines(&frame, &mut finish);
+                if !lines.is_empty() {
+                    yield Ok::<_, Infallible>(lines);
+                }
+            }
+        }
+    };
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(VERCEL_AI));
+    parts.headers.insert(VERCEL_AI_HEADER, HeaderValue::from_static("v1"));
+    parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    parts.headers.insert("X-Accel-Buffering", HeaderValue::from_static("no"));
+    Response::from_parts(parts, Body::from_stream(lines))
+}
+
+/// What the `d:` finish message reports, collected from the chunks.
+#[derive(Debug, Default)]
+struct VercelAiFinish {
+    finish_reason: Option<String>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl VercelAiFinish {
+    fn record_usage(&mut self, usage: &serde_json::Value) {
+        let tokens = |field: &str| usage.get(field).and_then(|v| v.as_u64()).unwrap_or(0);
+        // 普通 chunk 带全零用量, 只记录真实上报的用量
+        if tokens("total_tokens") > 0 || tokens("prompt_tokens") > 0 || tokens("completion_tokens") > 0 {
+            self.prompt_tokens = tokens("prompt_tokens");
+            self.completion_tokens = tokens("completion_tokens");
+        }
+    }
+
+    fn line(&self) -> String {
+        // SDK 的 finishReason 用连字符, 如 tool-calls、content-filter
+        let reason = match self.finish_reason.as_deref() {
+            None | Some("stop") => "stop",
+            Some("length") => "length",
+            Some("tool_calls") | Some("function_call") => "tool-calls",
+            Some("content_filter") => "content-filter",
+            Some("error") => "error",
+            Some(_) => "other",
+        };
+        vercel_ai_part(
+            'd',
+            &serde_json::json!({
+                "finishReason": reason,
+                "usage": {"promptTokens": self.prompt_tokens, "completionTokens": self.completion_tokens}
+            }),
+        )
+    }
+}
+
+/// Returns the data stream lines of one SSE frame; empty for frames that map to nothing.
+fn vercel_ai_lines(frame: &str, finish: &mut VercelAiFinish) -> String {
+    let mut event = None;
+    let mut data = Vec::new();
+    for line in frame.lines() {
+        if let Some(name) = line.strip_prefix("event:") {
+            event = Some(name.trim());
+        } else if let Some(line) = line.strip_prefix("data:") {
+            data.push(line.strip_prefix(' ').unwrap_or(line));
+        }
+    }
+    // 心跳注释没有对应的 part
+    if data.is_empty() {
+        return String::new();
+    }
+    let data = data.join("\n");
+    if data == "[DONE]" {
+        return finish.line();
+    }
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(&data) else {
+        return String::new();
+    };
+    match (event, value.get("type").and_then(|t| t.as_str())) {
+        (Some("warning"), _) => return vercel_ai_part('8', &serde_json::json!([value])),
+        (Some("metadata"), _) => {
+            if let Some(usage) = value.get("usage") {
+                finish.record_usage(usage);
+            }
+            return String::new();
+        }
+        (_, Some("error")) => {
+            finish.finish_reason = Some("error".to_string());
+            let message = value.get("message").and_then(|m| m.as_str()).unwrap_or("upstream error");
+            return vercel_ai_part('3', &serde_json::json!(message));
+        }
+        _ => {}
+    }
+    if let Some(usage) = value.get("usage") {
+        finish.record_usage(usage);
+    }
+    let Some(choice) = value
+        .get("choices")
+        .and_then(|choices| choices.as_array())
+        .and_then(|choices| choices.iter().find(|choice| choice.get("index").and_then(|i| i.as_u64()).unwrap_or(0) == 0))
+    else {
+        return String::new();
+    };
+    let mut lines = String::new();
+    let delta = choice.get("delta").cloned().unwrap_or_default();
+    if let Some(reasoning) = delta.get("reasoning_content").and_then(|r| r.as_str()).filter(|r| !r.is_empty()) {
+        lines.push_str(&vercel_ai_part('g', &serde_json::json!(reasoning)));
+    }
+    if let Some(text) = delta.get("content").and_then(|c| c.as_str()).filter(|c| !c.is_empty()) {
+        lines.push_str(&vercel_ai_part('0', &serde_json::json!(text)));
+    }
+    for call in delta.get("tool_calls").and_then(|calls| calls.as_array()).into_iter().flatten() {
+        let function = call.get("function").cloned().unwrap_or_default();
+        // SDK 要求 args 是对象, 参数字符串无法解析时原样放入
+        let arguments = function.get("arguments").and_then(|a| a.as_str()).unwrap_or("{}");
+        let args = serde_json::from_str(arguments).unwrap_or_else(|_| serde_json::json!(arguments));
+        lines.push_str(&vercel_ai_part(
+            '9',
+            &serde_json::json!({"toolCallId": call.get("id"), "toolName": function.get("name"), "args": args}),
+        ));
+    }
+    if let Some(reason) = choice.get("finish_reason").and_then(|r| r.as_str()) {
+        finish.finish_reason = Some(reason.to_string());
+    }
+    lines
+}
+
+/// Formats one data stream part: its type code, a colon, and the JSON value on one line.
+fn vercel_ai_part(code: char, value: &serde_json::Value) -> String {
+    format!("{}:{}\n", code, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn a_missing_stream_flag_follows_the_accept_header() {
+        let reject = AcceptPrecedence::Reject;
+        assert!(wants_stream(reject, &accept("text/event-stream"), None).unwrap());
+        assert!(!wants_stream(reject, &accept("application/json"), None).unwrap());
+        assert!(!wants_stream(reject, &HeaderMap::new(), None).unwrap());
+        // 同时接受两种格式时不做判断
+        assert!(!wants_stream(reject, &accept("application/json, text/event-stream"), None).unwrap());
+        assert!(wants_stream(reject, &accept("*/*"), Some(true)).unwrap());
+    }
+
+    #[test]
+    fn refused_media_types_do_not_count() {
+        let headers = accept("text/event-stream, application/json;q=0");
+        assert!(wants_stream(AcceptPrecedence::Reject, &headers, None).unwrap());
+        let headers = accept("text/event-stream; q=0.0, application/json");
+        assert!(!wants_stream(AcceptPrecedence::Reject, &headers, None).unwrap());
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_the_precedence() {
+        let headers = accept("application/json");
+        let error = wants_stream(AcceptPrecedence::Reject, &headers, Some(true)).unwrap_err();
+        assert!(matches!(error, ApiError::NotAcceptable { ref accept, stream: true } if accept == "application/json"), "{:?}", error);
+        assert!(!wants_stream(AcceptPrecedence::Accept, &headers, Some(true)).unwrap());
+        assert!(wants_stream(AcceptPrecedence::Body, &headers, Some(true)).unwrap());
+    }
+
+    #[test]
+    fn sse_responses_disable_proxy_buffering() {
+        let response = sse_response("data: x\n\n");
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], EVENT_STREAM);
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert_eq!(headers["x-accel-buffering"], "no");
+    }
+
+    #[test]
+    fn the_transport_follows_the_field_then_the_accept_header() {
+        assert_eq!(stream_transport(&HeaderMap::new(), None).unwrap(), StreamTransport::Sse);
+        assert_eq!(stream_transport(&accept("application/x-ndjson"), None).unwrap(), StreamTransport::Ndjson);
+        assert_eq!(stream_transport(&accept("application/x-ndjson, text/event-stream"), None).unwrap(), StreamTransport::Sse);
+        let field = serde_json::json!("sse");
+        assert_eq!(stream_transport(&accept("application/x-ndjson"), Some(&field)).unwrap(), StreamTransport::Sse);
+        let field = serde_json::json!("ndjson");
+        assert_eq!(stream_transport(&HeaderMap::new(), Some(&field)).unwrap(), StreamTransport::Ndjson);
+        let field = serde_json::json!("websocket");
+        assert!(matches!(stream_transport(&HeaderMap::new(), Some(&field)), Err(ApiError::BadRequest { .. })));
+        // NDJSON 也算流式格式
+        assert!(wants_stream(AcceptPrecedence::Reject, &accept("application/x-ndjson"), None).unwrap());
+    }
+
+    #[tokio::test]
+    async fn sse_frames_become_ndjson_lines() {
+        // 帧跨越 chunk 边界, 且带有 id 和 event 字段
+        let chunks = [
+            "id: s1-0\nevent: message\ndata: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n: keep-",
+            "alive\n\ndata: {\"choices\":[{\"delta\":",
+            "{\"content\":\"!\"}}]}\n\ndata: [DONE]\n\n",
+        ];
+        let sse = Body::from_stream(futures::stream::iter(chunks.map(Ok::<_, Infallible>)));
+        let response = stream_response(StreamTransport::Ndjson, sse);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], NDJSON);
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert_eq!(headers["x-accel-buffering"], "no");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            concat!(
+                "{\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n",
+                "{}\n",
+                "{\"choices\":[{\"delta\":{\"content\":\"!\"}}]}\n",
+                "{\"done\":true}\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn sse_bodies_are_relayed_unchanged() {
+        let frames = "data: {\"a\":1}\n\n: keep-alive\n\ndata: [DONE]\n\n";
+        let response = stream_response(StreamTransport::Sse, frames);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], EVENT_STREAM);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, frames.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn chunks_are_rewritten_into_vercel_ai_parts() {
+        let frames = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"reasoning_content\":\"Think.\"},\"finish_reason\":null}]}\n\n",
+            ": keep-alive\n\n",
+            "data: {\"choices\":[{\"index\":1,\"delta\":{\"content\":\"Other choice\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Calling.\",\"tool_calls\":[{\"id\":\"call_1\",\"function\":{\"name\":\"lookup\",\"arguments\":\"{\\\"q\\\":1}\"}}]},\"finish_reason\":\"tool_calls\"}],\"usage\":{\"prompt_tokens\":0,\"completion_tokens\":0,\"total_tokens\":0}}\n\n",
+            "event: warning\ndata: {\"code\":\"reasoning_truncated\",\"message\":\"cut\"}\n\n",
+            "event: metadata\ndata: {\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":8,\"total_tokens\":20}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let response = stream_response(StreamTransport::VercelAi, frames);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], VERCEL_AI);
+        assert_eq!(response.headers()[VERCEL_AI_HEADER], "v1");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            concat!(
+                "g:\"Think.\"\n",
+                "0:\"Calling.\"\n",
+                "9:{\"args\":{\"q\":1},\"toolCallId\":\"call_1\",\"toolName\":\"lookup\"}\n",
+                "8:[{\"code\":\"reasoning_truncated\",\"message\":\"cut\"}]\n",
+                "d:{\"finishReason\":\"tool-calls\",\"usage\":{\"completionTokens\":8,\"promptTokens\":12}}\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_vercel_ai_streams_finish_with_an_error() {
+        let frames = "data: {\"type\":\"error\",\"message\":\"upstream timed out\"}\n\ndata: [DONE]\n\n";
+        let body = axum::body::to_bytes(stream_response(StreamTransport::VercelAi, frames).into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "3:\"upstream timed out\"\nd:{\"finishReason\":\"error\",\"usage\":{\"completionTokens\":0,\"promptTokens\":0}}\n"
+        );
+    }
+}
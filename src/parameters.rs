@@ -0,0 +1,170 @@
+//! Operator policy over the body parameters callers may set.
+//!
+//! `parameter_policy.reasoner` and `parameter_policy.target` each take an
+//! optional `allow` list and a `deny` list of body keys. Only parameters the
+//! caller supplied are checked: every key of a native request's
+//! `*_config.body`, and on the compat endpoints the request fields that are
+//! copied into a phase body, plus `model` for the reasoner when the request
+//! sets `reasoner_model`. Values the server fills in from model mappings are
+//! never affected. In `strict` mode a disallowed parameter rejects the
+//! request with a 400; in `lenient` mode it is dropped and reported in a
+//! warning header. Without lists every parameter is allowed.
+
+use crate::{
+    config::{ParameterPolicyConfig, ParameterPolicyMode, PhaseParameterPolicy},
+    error::{ApiError, Result},
+    models::ApiRequest,
+};
+
+/// Response header listing the parameters dropped under the lenient policy.
+pub const PARAMETER_WARNING_HEADER: &str = "X-DeepThink-Parameter-Warning";
+
+/// Applies the parameter policy to a request.
+///
+/// # Arguments
+///
+/// * `policy` - The configured policy
+/// * `request` - The request; dropped parameters are removed in place
+/// * `compat_fields` - The compat request's extra fields, which are the only
+///   parameters the caller supplied; `None` for native requests, whose bodies
+///   come entirely from the caller
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - A warning listing the dropped parameters, if any
+///
+/// # Errors
+///
+/// Returns `ApiError::ParameterNotAllowed` for the first disallowed parameter
+/// in strict mode.
+pub fn enforce(
+    policy: &ParameterPolicyConfig,
+    request: &mut ApiRequest,
+    compat_fields: Option<&serde_json::Value>,
+) -> Result<Option<String>> {
+    let supplied = |body: &serde_json::Value| -> Vec<String> {
+        let keys = body.as_object().into_iter().flat_map(|body| body.keys());
+        match compat_fields {
+            None => keys.cloned().collect(),
+            Some(fields) => keys.filter(|key| fields.get(key.as_str()).is_some()).cloned().collect(),
+        }
+    };
+
+    let mut dropped = Vec::new();
+    let mut check = |phase: &str, rules: &PhaseParameterPolicy, key: &str| -> Result<bool> {
+        if rules.permits(key) {
+            return Ok(true);
+        }
+        if policy.mode == ParameterPolicyMode::Strict {
+            return Err(ApiError::ParameterNotAllowed {
+                phase: phase.to_string(),
+                parameter: key.to_string(),
+            });
+        }
+        dropped.push(format!("{}.{}", phase, key));
+        Ok(false)
+    };
+
+    // reasoner_model 等同于由调用方设置推理阶段的 model
+    if request.reasoner_model.is_some() && !check("reasoner", &policy.reasoner, "model")? {
+        request.reasoner_model = None;
+    }
+    for key in supplied(&request.deepseek_config.body) {
+        if !check("reasoner", &policy.reasoner, &key)? {
+            remove_key(&mut request.deepseek_config.body, &key);
+        }
+    }
+    for body in [&mut request.openai_config.body, &mut request.anthropic_config.body] {
+        for key in supplied(body) {
+            if !check("target", &policy.target, &key)? {
+                remove_key(body, &key);
+            }
+        }
+    }
+
+    dropped.sort();
+    dropped.dedup();
+    Ok((!dropped.is_empty()).then(|| format!("dropped parameters not allowed by policy: {}", dropped.join(", "))))
+}
+
+fn remove_key(body: &mut serde_json::Value, key: &str) {
+    if let Some(body) = body.as_object_mut() {
+        body.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(body: serde_json::Value) -> ApiRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn policy(mode: ParameterPolicyMode, reasoner_deny: &[&str], target_allow: Option<&[&str]>) -> ParameterPolicyConfig {
+        let strings = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        ParameterPolicyConfig {
+            mode,
+            reasoner: PhaseParameterPolicy { allow: None, deny: strings(reasoner_deny) },
+            target: PhaseParameterPolicy { allow: target_allow.map(strings), deny: Vec::new() },
+        }
+    }
+
+    #[test]
+    fn an_empty_policy_allows_everything() {
+        let mut native = request(json!({
+            "messages": [],
+            "deepseek_config": {"body": {"model": "r1", "tools": []}},
+            "openai_config": {"body": {"model": "gpt-4o", "logit_bias": {}}}
+        }));
+        let before = serde_json::to_value(&native).unwrap();
+        assert_eq!(enforce(&ParameterPolicyConfig::default(), &mut native, None).unwrap(), None);
+        assert_eq!(serde_json::to_value(&native).unwrap(), before);
+    }
+
+    #[test]
+    fn strict_policies_reject_denied_and_unlisted_parameters() {
+        let policy = policy(ParameterPolicyMode::Strict, &["model"], Some(&["temperature"]));
+        let mut denied = request(json!({"messages": [], "deepseek_config": {"body": {"model": "r1"}}}));
+        let error = enforce(&policy, &mut denied, None).unwrap_err();
+        assert!(matches!(error, ApiError::ParameterNotAllowed { ref phase, ref parameter } if phase == "reasoner" && parameter == "model"));
+
+        let mut unlisted = request(json!({"messages": [], "openai_config": {"body": {"temperature": 0.2, "tools": []}}}));
+        let error = enforce(&policy, &mut unlisted, None).unwrap_err();
+        assert!(matches!(error, ApiError::ParameterNotAllowed { ref phase, ref parameter } if phase == "target" && parameter == "tools"));
+
+        let mut reasoner_model = request(json!({"messages": [], "reasoner_model": "r1-huge"}));
+        assert!(enforce(&policy, &mut reasoner_model, None).is_err());
+    }
+
+    #[test]
+    fn lenient_policies_drop_and_report_disallowed_parameters() {
+        let policy = policy(ParameterPolicyMode::Lenient, &["model"], Some(&["temperature"]));
+        let mut native = request(json!({
+            "messages": [],
+            "reasoner_model": "r1-huge",
+            "deepseek_config": {"body": {"model": "r1", "max_tokens": 64}},
+            "anthropic_config": {"body": {"temperature": 0.2, "tools": []}}
+        }));
+        let warning = enforce(&policy, &mut native, None).unwrap();
+        assert_eq!(warning.as_deref(), Some("dropped parameters not allowed by policy: reasoner.model, target.tools"));
+        assert_eq!(native.reasoner_model, None);
+        assert_eq!(native.deepseek_config.body, json!({"max_tokens": 64}));
+        assert_eq!(native.anthropic_config.body, json!({"temperature": 0.2}));
+    }
+
+    #[test]
+    fn compat_policies_only_check_the_fields_the_caller_sent() {
+        let policy = policy(ParameterPolicyMode::Strict, &["model"], Some(&["temperature"]));
+        // 映射填写的 model 不是调用方提供的参数
+        let mut compat = request(json!({
+            "messages": [],
+            "deepseek_config": {"body": {"model": "deepseek-r1:14b"}},
+            "openai_config": {"body": {"model": "qwen2.5:14b", "temperature": 0.2}}
+        }));
+        let fields = json!({"temperature": 0.2});
+        assert_eq!(enforce(&policy, &mut compat, Some(&fields)).unwrap(), None);
+        assert_eq!(compat.openai_config.body["model"], "qwen2.5:14b");
+    }
+}
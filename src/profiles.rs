@@ -0,0 +1,174 @@
+//! Route profiles: named bundles of defaults for the chat routes.
+//!
+//! Every `[profiles.<name>]` table mounts `/v1/chat/completions` again under
+//! its `route_prefix`, so one process can serve audiences with different
+//! needs, e.g. `/internal/v1/chat/completions` with inline reasoning and
+//! `/external/v1/chat/completions` without any. A profile is applied before
+//! the request's own options: its `thinking_format` and `default_mapping`
+//! are defaults a request may override, while `include_reasoning = false`,
+//! `allowed_models` and `requests_per_minute` are enforced. Responses of a
+//! profile with `requests_per_minute` report the window's headroom in
+//! OpenAI-style `x-ratelimit-*-requests` headers.
+
+use crate::config::ProfileConfig;
+use crate::error::{ApiError, Result};
+use crate::models::ApiRequest;
+use crate::ratelimit;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Length of one `requests_per_minute` window.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// The profile whose routes a request came in on, if any.
+///
+/// Extracted from the `Extension` the profile's routes are mounted with.
+#[derive(Debug, Clone, Default)]
+pub struct RouteProfile(pub Option<Arc<Profile>>);
+
+impl<S: Send + Sync> FromRequestParts<S> for RouteProfile {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self(parts.extensions.get::<Arc<Profile>>().cloned()))
+    }
+}
+
+/// A profile and its per-profile state.
+#[derive(Debug)]
+pub struct Profile {
+    pub name: String,
+    pub config: ProfileConfig,
+    /// Start and request count of the current rate limit window.
+    window: Mutex<(Instant, u32)>,
+}
+
+impl Profile {
+    /// Creates a profile with a fresh rate limit window.
+    pub fn new(name: String, config: ProfileConfig) -> Self {
+        Self {
+            name,
+            config,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Counts one request against the profile's `requests_per_minute`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<(&'static str, String)>>` - The rate limit headers of
+    ///   the window after this request; none if the profile is unlimited
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::RateLimited` once the current minute's requests are used up.
+    pub fn admit(&self) -> Result<Vec<(&'static str, String)>> {
+        let Some(limit) = self.config.requests_per_minute else {
+            return Ok(Vec::new());
+        };
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(window.0) >= RATE_WINDOW {
+            *window = (now, 0);
+        }
+        if window.1 >= limit {
+            return Err(ApiError::RateLimited {
+                scope: format!("profile {}", self.name),
+                limit,
+                retry_after_secs: RATE_WINDOW.saturating_sub(now.duration_since(window.0)).as_secs().max(1),
+            });
+        }
+        window.1 += 1;
+        let reset = RATE_WINDOW.saturating_sub(now.duration_since(window.0));
+        Ok(ratelimit::caller_headers(limit, limit - window.1, reset))
+    }
+
+    /// Checks that the profile serves `model`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if the model is not in `allowed_models`.
+    pub fn check_model(&self, model: &str) -> Result<()> {
+        match &self.config.allowed_models {
+            Some(allowed) if !allowed.iter().any(|m| m == model) => Err(ApiError::BadRequest {
+                message: format!(
+                    "Model '{}' is not available on this route; allowed models: {}",
+                    model,
+                    allowed.join(", ")
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the profile's defaults to a converted request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The internal request built from the client's request
+    /// * `thinking_format_set` - Whether the client chose a `thinking_format`,
+    ///   which then keeps its value
+    pub fn apply(&self, request: &mut ApiRequest, thinking_format_set: bool) {
+        if let Some(format) = self.config.thinking_format.filter(|_| !thinking_format_set) {
+            request.thinking_format = format;
+        }
+        if !self.config.include_reasoning {
+            request.include_reasoning = Some(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ThinkingFormat;
+    use serde_json::json;
+
+    fn profile(config: serde_json::Value) -> Profile {
+        Profile::new("external".to_string(), serde_json::from_value(config).unwrap())
+    }
+
+    #[test]
+    fn requests_per_minute_is_enforced() {
+        let limited = profile(json!({"route_prefix": "/external", "requests_per_minute": 2}));
+        assert!(limited.admit().is_ok());
+        assert!(limited.admit().is_ok());
+        match limited.admit() {
+            Err(ApiError::RateLimited { scope, limit, retry_after_secs }) => {
+                assert_eq!((scope.as_str(), limit), ("profile external", 2));
+                assert!((1..=60).contains(&retry_after_secs));
+            }
+            other => panic!("expected a rate limit error, got {:?}", other),
+        }
+
+        let unlimited = profile(json!({"route_prefix": "/internal"}));
+        assert!((0..100).all(|_| unlimited.admit().is_ok()));
+    }
+
+    #[test]
+    fn only_allowed_models_are_served() {
+        let restricted = profile(json!({"route_prefix": "/external", "allowed_models": ["deepthink"]}));
+        assert!(restricted.check_model("deepthink").is_ok());
+        assert!(matches!(restricted.check_model("gpt-4o"), Err(ApiError::BadRequest { .. })));
+        assert!(profile(json!({"route_prefix": "/internal"})).check_model("gpt-4o").is_ok());
+    }
+
+    #[test]
+    fn defaults_apply_unless_the_request_sets_them() {
+        let external = profile(json!({"route_prefix": "/external", "include_reasoning": false, "thinking_format": "reasoning_content"}));
+        let mut request: ApiRequest = serde_json::from_value(json!({"messages": []})).unwrap();
+        external.apply(&mut request, false);
+        assert_eq!(request.thinking_format, ThinkingFormat::ReasoningContent);
+        assert!(!request.includes_reasoning());
+
+        // 请求自带的 thinking_format 优先, include_reasoning = false 不能被请求打开
+        let mut request: ApiRequest = serde_json::from_value(json!({"messages": [], "include_reasoning": true})).unwrap();
+        external.apply(&mut request, true);
+        assert_eq!(request.thinking_format, ThinkingFormat::default());
+        assert!(!request.includes_reasoning());
+    }
+}
@@ -0,0 +1,409 @@
+//! Progress callbacks of non-streaming requests.
+//!
+//! A non-streaming request returns nothing until both phases finish, which
+//! can take longer than a load balancer waits. A request carrying a
+//! `progress_callback_url` (a `deepthink` option on the compat routes) gets
+//! [`ProgressEvent`]s posted to that URL while it runs: one at each phase
+//! transition, and one every `progress_callbacks.interval_secs` while the
+//! phase does not change. The last event has the phase `completed` or
+//! `failed`; the result itself is still returned on the original request.
+//!
+//! Only HTTPS URLs on `progress_callbacks.allowed_hosts` are accepted, and
+//! the bodies are signed like usage webhook events, in the
+//! `X-DeepThink-Signature` and `X-DeepThink-Timestamp` headers. Events are
+//! posted in order by a background task, once each; a failed delivery is
+//! logged and never affects the request.
+
+use crate::{
+    config::ProgressCallbackConfig,
+    error::{ApiError, Result},
+    signing::{HmacSigner, RequestSigner},
+    webhooks::{SIGNATURE_HEADER, TIMESTAMP_HEADER},
+};
+use reqwest::{header::HeaderMap, Method, Url};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::Instant};
+
+/// Phase of a request, as reported in its progress events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    /// Waiting in the admission queue.
+    Queued,
+    Reasoning,
+    Answering,
+    Completed,
+    Failed,
+}
+
+/// One progress update of a request.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressEvent {
+    /// Id of the request, as in `X-DeepThink-Request-Id`.
+    pub request_id: Option<String>,
+    /// Position of the event among the request's events, from 0.
+    pub sequence: u64,
+    pub phase: ProgressPhase,
+    /// Completion tokens of the reasoner, once the reasoning phase is over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_tokens: Option<u32>,
+    /// Time since the request was received.
+    pub elapsed_ms: u64,
+}
+
+/// Creates the progress reporters of requests.
+///
+/// The default value accepts no callback URLs.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressCallbacks {
+    settings: Option<Arc<Settings>>,
+}
+
+#[derive(Debug)]
+struct Settings {
+    allowed_hosts: Vec<String>,
+    signer: HmacSigner,
+    http: reqwest::Client,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl ProgressCallbacks {
+    /// Checks a `[progress_callbacks]` section.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if hosts are allowed but the
+    /// secret variable is unset.
+    pub fn check(config: &ProgressCallbackConfig) -> std::result::Result<(), String> {
+        if config.allowed_hosts.is_empty() {
+            return Ok(());
+        }
+        signer(config).map(drop)
+    }
+
+    /// Builds the reporters' settings from a `[progress_callbacks]` section.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the section cannot be used, as in [`ProgressCallbacks::check`].
+    pub fn new(config: &ProgressCallbackConfig, http: reqwest::Client) -> std::result::Result<Self, String> {
+        if config.allowed_hosts.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self {
+            settings: Some(Arc::new(Settings {
+                allowed_hosts: config.allowed_hosts.clone(),
+                signer: signer(config)?,
+                http,
+                interval: Duration::from_secs(config.interval_secs.max(1)),
+                timeout: Duration::from_secs(config.timeout_secs),
+            })),
+        })
+    }
+
+    /// Starts reporting the progress of a request to `url`.
+    ///
+    /// Must be called inside the Tokio runtime. Without a URL, returns a
+    /// reporter that discards every update.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The request's `progress_callback_url`
+    /// * `request_id` - Id of the request, as in `X-DeepThink-Request-Id`
+    /// * `received` - When the request was received
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if the URL is not an HTTPS URL on
+    /// `progress_callbacks.allowed_hosts`.
+    pub fn start(&self, url: Option<&str>, request_id: Option<String>, received: Instant) -> Result<Progress> {
+        let Some(url) = url else {
+            return Ok(Progress::default());
+        };
+        let Some(settings) = &self.settings else {
+            return Err(rejected("progress callbacks are disabled on this server"));
+        };
+        let parsed = Url::parse(url).map_err(|e| rejected(&format!("invalid URL: {}", e)))?;
+        if parsed.scheme() != "https" {
+            return Err(rejected("the URL must be an HTTPS URL"));
+        }
+        let host = parsed.host_str().unwrap_or_default();
+        // 允许列表的条目可以是主机名, 也可以是 主机:端口
+        let host_port = parsed.port_or_known_default().map(|port| format!("{}:{}", host, port));
+        let listed = settings.allowed_hosts.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(host) || host_port.as_deref().is_some_and(|host_port| allowed.eq_ignore_ascii_case(host_port))
+        });
+        if !listed {
+            return Err(rejected(&format!("host '{}' is not in progress_callbacks.allowed_hosts", host)));
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reporter = Reporter {
+            settings: settings.clone(),
+            url: url.to_string(),
+            request_id,
+            received,
+        };
+        tokio::spawn(reporter.run(receiver));
+        Ok(Progress { sender: Some(sender) })
+    }
+}
+
+/// Builds the signer of a section.
+fn signer(config: &ProgressCallbackConfig) -> std::result::Result<HmacSigner, String> {
+    let secret = std::env::var(&config.secret_env)
+        .map_err(|_| format!("progress_callbacks: the secret variable {} is not set", config.secret_env))?;
+    HmacSigner::new(secret, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0).map_err(|e| format!("progress_callbacks: {}", e))
+}
+
+fn rejected(reason: &str) -> ApiError {
+    ApiError::BadRequest {
+        message: format!("Invalid progress_callback_url: {}", reason),
+    }
+}
+
+/// A change of phase handed to the reporter task.
+#[derive(Debug, Clone, Copy)]
+struct Update {
+    phase: ProgressPhase,
+    reasoning_tokens: Option<u32>,
+}
+
+/// Reports the progress of one request.
+///
+/// The default reporter discards every update.
+#[derive(Debug, Default)]
+pub struct Progress {
+    sender: Option<mpsc::UnboundedSender<Update>>,
+}
+
+impl Progress {
+    /// Reports that the request entered `phase`.
+    pub fn phase(&self, phase: ProgressPhase) {
+        self.send(Update {
+            phase,
+            reasoning_tokens: None,
+        });
+    }
+
+    /// Reports that the reasoning is over and the answering phase started.
+    pub fn reasoned(&self, reasoning_tokens: u32) {
+        self.send(Update {
+            phase: ProgressPhase::Answering,
+            reasoning_tokens: Some(reasoning_tokens),
+        });
+    }
+
+    /// Reports the outcome of the request and stops the updates.
+    pub fn finish(self, succeeded: bool) {
+        self.phase(if succeeded { ProgressPhase::Completed } else { ProgressPhase::Failed });
+    }
+
+    fn send(&self, update: Update) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(update);
+        }
+    }
+}
+
+/// Background task posting the events of one request.
+struct Reporter {
+    settings: Arc<Settings>,
+    url: String,
+    request_id: Option<String>,
+    received: Instant,
+}
+
+impl Reporter {
+    async fn run(self, mut receiver: mpsc::UnboundedReceiver<Update>) {
+        let mut current = Update {
+            phase: ProgressPhase::Queued,
+            reasoning_tokens: None,
+        };
+        let mut sequence = 0;
+        let interval = self.settings.interval;
+        let mut heartbeat = tokio::time::interval_at(Instant::now() + interval, interval);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            self.post(ProgressEvent {
+                request_id: self.request_id.clone(),
+                sequence,
+                phase: current.phase,
+                reasoning_tokens: current.reasoning_tokens,
+                elapsed_ms: self.received.elapsed().as_millis() as u64,
+            })
+            .await;
+            sequence += 1;
+            if matches!(current.phase, ProgressPhase::Completed | ProgressPhase::Failed) {
+                break;
+            }
+            tokio::select! {
+                update = receiver.recv() => match update {
+                    Some(update) => {
+                        // 推理 token 数在之后的事件中保留
+                        let reasoning_tokens = update.reasoning_tokens.or(current.reasoning_tokens);
+                        current = Update { reasoning_tokens, ..update };
+                        heartbeat.reset();
+                    }
+                    // 请求没有报告结果就结束了, 例如被客户端取消
+                    None => break,
+                },
+                _ = heartbeat.tick() => {}
+            }
+        }
+    }
+
+    /// Posts one event, logging a failed delivery.
+    async fn post(&self, event: ProgressEvent) {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!("Cannot serialize the progress event of {:?}: {}", event.request_id, e);
+                return;
+            }
+        };
+        let headers = match self.settings.signer.sign(&Method::POST, &self.url, &HeaderMap::new(), &body) {
+            Ok(headers) => headers,
+            Err(e) => {
+                tracing::warn!("Cannot sign the progress event of {:?}: {}", event.request_id, e);
+                return;
+            }
+        };
+        let mut request = self
+            .settings
+            .http
+            .post(&self.url)
+            .timeout(self.settings.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => tracing::debug!("Progress callback {} answered with status {}", self.url, response.status()),
+            Err(e) => tracing::debug!("Progress callback {} failed: {}", self.url, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    const SECRET: &str = "progress-secret";
+
+    fn settings(allowed_hosts: &[&str], interval: Duration) -> Arc<Settings> {
+        Arc::new(Settings {
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            signer: HmacSigner::new(SECRET, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0).unwrap(),
+            http: reqwest::Client::new(),
+            interval,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Builds a reporter for the plain HTTP mock receiver, which
+    /// [`ProgressCallbacks::start`] would refuse.
+    fn reporter(receiver: &MockServer, interval: Duration) -> Reporter {
+        Reporter {
+            settings: settings(&[], interval),
+            url: format!("{}/progress", receiver.uri()),
+            request_id: Some("req-1".to_string()),
+            received: Instant::now(),
+        }
+    }
+
+    async fn events(receiver: &MockServer) -> Vec<serde_json::Value> {
+        let requests = receiver.received_requests().await.unwrap();
+        requests.iter().map(|request| serde_json::from_slice(&request.body).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_phases_in_order_and_are_signed() {
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let (sender, updates) = mpsc::unbounded_channel();
+        let progress = Progress { sender: Some(sender) };
+        progress.phase(ProgressPhase::Reasoning);
+        progress.reasoned(812);
+        progress.finish(true);
+
+        reporter(&receiver, Duration::from_secs(60)).run(updates).await;
+
+        let events = events(&receiver).await;
+        let phases: Vec<&str> = events.iter().map(|event| event["phase"].as_str().unwrap()).collect();
+        assert_eq!(phases, ["queued", "reasoning", "answering", "completed"]);
+        let sequences: Vec<u64> = events.iter().map(|event| event["sequence"].as_u64().unwrap()).collect();
+        assert_eq!(sequences, [0, 1, 2, 3]);
+        assert!(events[1].get("reasoning_tokens").is_none());
+        // 推理结束后的事件都带有推理 token 数
+        assert_eq!(events[2]["reasoning_tokens"], 812);
+        assert_eq!(events[3]["reasoning_tokens"], 812);
+        assert_eq!(events[3]["request_id"], "req-1");
+
+        let delivered = &receiver.received_requests().await.unwrap()[3];
+        let timestamp: i64 = delivered.headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        let expected = HmacSigner::new(SECRET, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0)
+            .unwrap()
+            .signature(timestamp, &Method::POST, &format!("{}/progress", receiver.uri()), &delivered.body)
+            .unwrap();
+        assert_eq!(delivered.headers[SIGNATURE_HEADER], expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn heartbeats_repeat_the_phase_until_it_changes() {
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let (sender, updates) = mpsc::unbounded_channel();
+        let progress = Progress { sender: Some(sender) };
+        let task = tokio::spawn(reporter(&receiver, Duration::from_millis(50)).run(updates));
+        tokio::time::sleep(Duration::from_millis(180)).await;
+        progress.finish(false);
+        task.await.unwrap();
+
+        let events = events(&receiver).await;
+        let (last, heartbeats) = events.split_last().unwrap();
+        assert_eq!(last["phase"], "failed");
+        assert!(heartbeats.len() >= 3, "{:?}", events);
+        assert!(heartbeats.iter().all(|event| event["phase"] == "queued"), "{:?}", events);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_do_not_stop_the_updates() {
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)).mount(&receiver).await;
+        let (sender, updates) = mpsc::unbounded_channel();
+        let progress = Progress { sender: Some(sender) };
+        progress.reasoned(5);
+        progress.finish(true);
+
+        reporter(&receiver, Duration::from_secs(60)).run(updates).await;
+
+        // 每个事件只投递一次, 失败不重试
+        let phases: Vec<String> = events(&receiver).await.iter().map(|event| event["phase"].as_str().unwrap().to_string()).collect();
+        assert_eq!(phases, ["queued", "answering", "completed"]);
+    }
+
+    #[tokio::test]
+    async fn only_https_urls_on_the_allowed_hosts_are_accepted() {
+        let callbacks = ProgressCallbacks {
+            settings: Some(settings(&["hooks.example.com", "10.0.0.2:8443"], Duration::from_secs(60))),
+        };
+        let start = |url: &str| callbacks.start(Some(url), None, Instant::now()).map(drop);
+        start("https://hooks.example.com/progress").unwrap();
+        start("https://HOOKS.example.com:443/progress").unwrap();
+        start("https://10.0.0.2:8443/progress").unwrap();
+        for url in ["http://hooks.example.com/progress", "https://10.0.0.2/progress", "https://other.example.com/", "not a url"] {
+            let error = start(url).unwrap_err().to_string();
+            assert!(error.contains("Invalid progress_callback_url"), "{}: {}", url, error);
+        }
+
+        // 没有 URL 的请求不报告进度; 未配置 allowed_hosts 时拒绝带 URL 的请求
+        assert!(ProgressCallbacks::default().start(None, None, Instant::now()).unwrap().sender.is_none());
+        assert!(ProgressCallbacks::default().start(Some("https://hooks.example.com/"), None, Instant::now()).is_err());
+    }
+}
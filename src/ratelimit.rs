@@ -0,0 +1,301 @@
+//! Rate limit headers: the upstreams' and our own.
+//!
+//! OpenAI-compatible upstreams report their remaining headroom with every
+//! response as `x-ratelimit-{limit,remaining,reset}-{requests,tokens}`, and
+//! Anthropic as `anthropic-ratelimit-{requests,tokens}-{limit,remaining,reset}`.
+//! The clients hand those headers to [`UpstreamRateLimits`], which keeps the
+//! latest values per upstream for the `/metrics` gauges. A response that
+//! reports an exhausted window backs the upstream's circuit off until the
+//! advertised reset, before a request runs into the `429`.
+//!
+//! Responses of routes with a `requests_per_minute` limit carry the same
+//! OpenAI-style headers for the caller's window, so client-side throttlers
+//! written for the OpenAI API work against the proxy unmodified.
+
+use crate::circuit::CircuitBreakers;
+use chrono::Utc;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Requests allowed in the caller's current window.
+pub const LIMIT_REQUESTS_HEADER: &str = "x-ratelimit-limit-requests";
+
+/// Requests left in the caller's current window.
+pub const REMAINING_REQUESTS_HEADER: &str = "x-ratelimit-remaining-requests";
+
+/// Time until the caller's window resets, e.g. `42s`.
+pub const RESET_REQUESTS_HEADER: &str = "x-ratelimit-reset-requests";
+
+/// One window of an upstream rate limit, requests or tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateWindow {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    /// Time until the window resets, as of the response.
+    pub reset: Option<Duration>,
+}
+
+impl RateWindow {
+    fn is_empty(&self) -> bool {
+        self.limit.is_none() && self.remaining.is_none() && self.reset.is_none()
+    }
+
+    /// Returns the time until reset if no headroom is left.
+    fn exhausted_for(&self) -> Option<Duration> {
+        self.reset.filter(|_| self.remaining == Some(0))
+    }
+}
+
+/// The rate limit an upstream advertised in its response headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UpstreamRateLimit {
+    pub requests: RateWindow,
+    pub tokens: RateWindow,
+}
+
+impl UpstreamRateLimit {
+    /// Reads the OpenAI-style or Anthropic-style rate limit headers.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Self>` - The advertised limits, or `None` if the response
+    ///   carried no rate limit header
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let limit = Self {
+            requests: read_window(headers, "requests"),
+            tokens: read_window(headers, "tokens"),
+        };
+        (!limit.requests.is_empty() || !limit.tokens.is_empty()).then_some(limit)
+    }
+
+    /// Returns how long until the upstream accepts requests again, if a
+    /// window has no headroom left.
+    pub fn exhausted_for(&self) -> Option<Duration> {
+        self.requests.exhausted_for().max(self.tokens.exhausted_for())
+    }
+
+    /// Returns the later of the two windows' resets.
+    pub fn latest_reset(&self) -> Option<Duration> {
+        self.requests.reset.max(self.tokens.reset)
+    }
+}
+
+fn read_window(headers: &HeaderMap, kind: &str) -> RateWindow {
+    let header = |name: String| headers.get(name).and_then(|h| h.to_str().ok()).map(str::trim);
+    let number = |name: String| header(name).and_then(|value| value.parse::<u64>().ok());
+    let openai = RateWindow {
+        limit: number(format!("x-ratelimit-limit-{}", kind)),
+        remaining: number(format!("x-ratelimit-remaining-{}", kind)),
+        reset: header(format!("x-ratelimit-reset-{}", kind)).and_then(parse_duration),
+    };
+    if !openai.is_empty() {
+        return openai;
+    }
+    RateWindow {
+        limit: number(format!("anthropic-ratelimit-{}-limit", kind)),
+        remaining: number(format!("anthropic-ratelimit-{}-remaining", kind)),
+        reset: header(format!("anthropic-ratelimit-{}-reset", kind)).and_then(parse_timestamp),
+    }
+}
+
+/// Parses a duration such as `1h2m3.5s`, `7.66s` or `450ms`.
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+        let unit_end = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let scale = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        total += number * scale;
+        rest = &rest[unit_end..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+/// Parses an RFC 3339 reset time into the time left until it.
+fn parse_timestamp(value: &str) -> Option<Duration> {
+    let at = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+    // 已经过去的重置时间视为立即重置
+    Some((at.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Formats a reset time the way OpenAI does, e.g. `1m30s` or `450ms`.
+pub fn format_reset(reset: Duration) -> String {
+    let secs = reset.as_secs();
+    match (secs / 60, secs % 60) {
+        (0, 0) => format!("{}ms", reset.as_millis()),
+        (0, secs) => format!("{}s", secs),
+        (minutes, secs) => format!("{}m{}s", minutes, secs),
+    }
+}
+
+/// Builds the rate limit headers of a caller's window.
+///
+/// # Arguments
+///
+/// * `limit` - Requests allowed per window
+/// * `remaining` - Requests left in the current window
+/// * `reset` - Time until the window resets
+pub fn caller_headers(limit: u32, remaining: u32, reset: Duration) -> Vec<(&'static str, String)> {
+    vec![
+        (LIMIT_REQUESTS_HEADER, limit.to_string()),
+        (REMAINING_REQUESTS_HEADER, remaining.to_string()),
+        (RESET_REQUESTS_HEADER, format_reset(reset)),
+    ]
+}
+
+/// The latest advertised rate limit of every upstream, by provider and base URL.
+#[derive(Debug)]
+pub struct UpstreamRateLimits {
+    latest: Mutex<HashMap<(String, String), (UpstreamRateLimit, Instant)>>,
+    circuits: Arc<CircuitBreakers>,
+}
+
+/// Point-in-time rate limit of one upstream, as reported by `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitGauge {
+    pub provider: String,
+    pub base_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_requests: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_requests: Option<u64>,
+    /// Seconds until the requests window resets, counted from now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_requests_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_tokens: Option<u64>,
+    /// Seconds until the tokens window resets, counted from now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_tokens_secs: Option<f64>,
+    /// Seconds since the upstream last reported these values.
+    pub age_secs: u64,
+}
+
+impl UpstreamRateLimits {
+    /// Creates an empty registry that backs off `circuits` on exhausted windows.
+    pub fn new(circuits: Arc<CircuitBreakers>) -> Self {
+        Self {
+            latest: Mutex::new(HashMap::new()),
+            circuits,
+        }
+    }
+
+    /// Records the rate limit headers of a response from `base_url`.
+    ///
+    /// A window without headroom opens the upstream's circuit until it resets.
+    pub fn observe(&self, provider: &str, base_url: &str, headers: &HeaderMap) {
+        let Some(limit) = UpstreamRateLimit::from_headers(headers) else {
+            return;
+        };
+        if let Some(delay) = limit.exhausted_for() {
+            self.circuits.back_off(provider, base_url, Some(delay));
+        }
+        self.latest
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((provider.to_string(), base_url.to_string()), (limit, Instant::now()));
+    }
+
+    /// Returns the latest rate limit of every upstream that advertised one.
+    pub fn gauges(&self) -> Vec<RateLimitGauge> {
+        let latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        let mut gauges: Vec<RateLimitGauge> = latest
+            .iter()
+            .map(|((provider, base_url), (limit, observed_at))| {
+                let age = observed_at.elapsed();
+                let reset_secs = |window: &RateWindow| window.reset.map(|reset| reset.saturating_sub(age).as_secs_f64());
+                RateLimitGauge {
+                    provider: provider.clone(),
+                    base_url: base_url.clone(),
+                    limit_requests: limit.requests.limit,
+                    remaining_requests: limit.requests.remaining,
+                    reset_requests_secs: reset_secs(&limit.requests),
+                    limit_tokens: limit.tokens.limit,
+                    remaining_tokens: limit.tokens.remaining,
+                    reset_tokens_secs: reset_secs(&limit.tokens),
+                    age_secs: age.as_secs(),
+                }
+            })
+            .collect();
+        gauges.sort_by(|a, b| (&a.provider, &a.base_url).cmp(&(&b.provider, &b.base_url)));
+        gauges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn openai_and_anthropic_headers_are_read() {
+        let openai = headers(&[
+            ("x-ratelimit-limit-requests", "60"),
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-reset-requests", "1m30s"),
+            ("x-ratelimit-reset-tokens", "450ms"),
+        ]);
+        let limit = UpstreamRateLimit::from_headers(&openai).unwrap();
+        assert_eq!(limit.requests, RateWindow { limit: Some(60), remaining: Some(0), reset: Some(Duration::from_secs(90)) });
+        assert_eq!(limit.tokens.reset, Some(Duration::from_millis(450)));
+        assert_eq!(limit.exhausted_for(), Some(Duration::from_secs(90)));
+
+        let reset = (Utc::now() + chrono::Duration::seconds(30)).to_rfc3339();
+        let anthropic = headers(&[("anthropic-ratelimit-tokens-remaining", "12"), ("anthropic-ratelimit-tokens-reset", &reset)]);
+        let limit = UpstreamRateLimit::from_headers(&anthropic).unwrap();
+        assert_eq!(limit.tokens.remaining, Some(12));
+        assert!(limit.latest_reset().is_some_and(|reset| reset > Duration::from_secs(28) && reset <= Duration::from_secs(30)));
+        assert_eq!(limit.exhausted_for(), None);
+
+        assert!(UpstreamRateLimit::from_headers(&headers(&[("retry-after", "5")])).is_none());
+    }
+
+    #[test]
+    fn resets_are_formatted_like_openai() {
+        assert_eq!(format_reset(Duration::from_millis(450)), "450ms");
+        assert_eq!(format_reset(Duration::from_secs(42)), "42s");
+        assert_eq!(format_reset(Duration::from_secs(90)), "1m30s");
+        assert_eq!(parse_duration(&format_reset(Duration::from_secs(90))), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn exhausted_windows_back_the_upstream_off() {
+        let circuits = Arc::new(CircuitBreakers::new(5, Duration::from_secs(60)));
+        let limits = UpstreamRateLimits::new(circuits.clone());
+        limits.observe("openai", "http://a", &headers(&[("x-ratelimit-remaining-requests", "3"), ("x-ratelimit-reset-requests", "5s")]));
+        assert!(circuits.check("openai", "http://a").is_ok());
+        limits.observe("openai", "http://b", &headers(&[("x-ratelimit-remaining-tokens", "0"), ("x-ratelimit-reset-tokens", "5s")]));
+        assert!(circuits.check("openai", "http://b").is_err());
+
+        let gauges = limits.gauges();
+        assert_eq!(gauges.iter().map(|gauge| gauge.base_url.as_str()).collect::<Vec<_>>(), ["http://a", "http://b"]);
+        assert_eq!(gauges[0].remaining_requests, Some(3));
+        assert_eq!(gauges[1].remaining_tokens, Some(0));
+    }
+}
@@ -0,0 +1,98 @@
+//! Redaction of the reasoning before it reaches the target.
+//!
+//! The reasoner sometimes echoes secrets, emails or internal hostnames from
+//! the conversation into its reasoning, and that reasoning is then sent to a
+//! second provider. The `[[redaction]]` rules of `config.toml` are applied in
+//! order to the reasoning injected into the target prompt; under
+//! `reasoning.redaction_scope = "all"` the reasoning returned to the client
+//! is redacted as well. Unlike [`crate::redact`], which only changes what is
+//! logged, these rules change what the upstreams and clients see.
+
+use crate::config::RedactionRule;
+use regex::Regex;
+
+/// The compiled `[[redaction]]` rules.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Redactor {
+    /// Compiles `rules`, keeping their order.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message naming the first rule whose pattern is not a valid regex.
+    pub fn compile(rules: &[RedactionRule]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                Regex::new(&rule.pattern)
+                    .map(|regex| (regex, rule.replacement.clone()))
+                    .map_err(|e| format!("redaction rule {} has an invalid pattern {:?}: {}", index + 1, rule.pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies every rule to `text` in order.
+    ///
+    /// # Returns
+    ///
+    /// * `(String, u32)` - The redacted text and the number of replacements made
+    pub fn apply(&self, text: &str) -> (String, u32) {
+        let mut redacted = text.to_string();
+        let mut count = 0;
+        for (regex, replacement) in &self.rules {
+            let matches = regex.find_iter(&redacted).count() as u32;
+            if matches > 0 {
+                redacted = regex.replace_all(&redacted, replacement.as_str()).into_owned();
+                count += matches;
+            }
+        }
+        (redacted, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, replacement: &str) -> RedactionRule {
+        RedactionRule { pattern: pattern.to_string(), replacement: replacement.to_string() }
+    }
+
+    #[test]
+    fn rules_are_applied_in_order_and_counted() {
+        let redactor = Redactor::compile(&[
+            rule(r"[\w.+-]+@[\w-]+\.[\w.]+", "[email]"),
+            rule(r"\+?\d[\d -]{7,}\d", "[phone]"),
+            // 前一条规则的替换结果也会被后面的规则匹配
+            rule(r"\[email\]", "[contact]"),
+        ])
+        .unwrap();
+        let (redacted, count) = redactor.apply("Mail ann@example.com or bob@example.org, call +1 555 123 4567.");
+        assert_eq!(redacted, "Mail [contact] or [contact], call [phone].");
+        assert_eq!(count, 5);
+        assert_eq!(redactor.apply("Nothing to hide."), ("Nothing to hide.".to_string(), 0));
+    }
+
+    #[test]
+    fn replacements_may_refer_to_capture_groups() {
+        let redactor = Redactor::compile(&[rule(r"(?P<user>\w+)@example\.com", "${user}@[host]")]).unwrap();
+        assert_eq!(redactor.apply("ann@example.com").0, "ann@[host]");
+    }
+
+    #[test]
+    fn invalid_patterns_name_their_rule() {
+        let error = Redactor::compile(&[rule("sk-\\w+", "[key]"), rule("(unclosed", "x")]).unwrap_err();
+        assert!(error.starts_with("redaction rule 2 has an invalid pattern \"(unclosed\""), "{}", error);
+        assert!(Redactor::compile(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+//! Log privacy for end-user content.
+//!
+//! Request and response bodies are logged through [`Loggable`], and bare
+//! content strings through [`text`] or [`json`], instead of their raw
+//! `Debug`/`Display` output. Under `logging.log_content = "full"` both print
+//! the value unchanged. Under `"hash"` every content string is replaced by
+//! the prefix of its SHA-256 and its length, and under `"length_only"` by
+//! just its length, while roles, models, ids and the shape of the value are
+//! kept so a logged body can still be read for structure.
+
+use crate::config::LogContent;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Object keys whose values are end-user or model content.
+const CONTENT_KEYS: &[&str] = &[
+    "content",
+    "text",
+    "reasoning_content",
+    "thinking",
+    "system",
+    "arguments",
+    "input",
+    "partial_json",
+];
+
+static LOG_CONTENT: OnceCell<LogContent> = OnceCell::new();
+
+/// Sets the process-wide log privacy mode; later calls are ignored.
+pub fn init(mode: LogContent) {
+    let _ = LOG_CONTENT.set(mode);
+}
+
+/// Returns the log privacy mode, `Full` until [`init`] is called.
+pub fn mode() -> LogContent {
+    LOG_CONTENT.get().copied().unwrap_or_default()
+}
+
+/// Formats a serializable value for logging under the privacy mode.
+///
+/// `{:?}` and `{:#?}` print the value's own `Debug` output in full mode, and
+/// its JSON form with content strings redacted otherwise.
+pub struct Loggable<'a, T: ?Sized>(pub &'a T);
+
+impl<T: fmt::Debug + Serialize + ?Sized> fmt::Debug for Loggable<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = mode();
+        if mode == LogContent::Full {
+            return self.0.fmt(f);
+        }
+        match serde_json::to_value(self.0) {
+            Ok(mut value) => {
+                redact_value(&mut value, false, mode);
+                if f.alternate() {
+                    write!(f, "{}", serde_json::to_string_pretty(&value).unwrap_or_default())
+                } else {
+                    write!(f, "{}", value)
+                }
+            }
+            Err(_) => write!(f, "<unserializable {}>", std::any::type_name::<T>()),
+        }
+    }
+}
+
+/// Returns a content string as it may be logged.
+pub fn text(content: &str) -> String {
+    redact_str(content, mode())
+}
+
+/// Returns a raw JSON payload as it may be logged.
+///
+/// Payloads that are not JSON are treated as a single content string.
+pub fn json(payload: &str) -> String {
+    let mode = mode();
+    if mode == LogContent::Full {
+        return payload.to_string();
+    }
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(mut value) => {
+            redact_value(&mut value, false, mode);
+            value.to_string()
+        }
+        Err(_) => redact_str(payload, mode),
+    }
+}
+
+/// Redacts the content strings of a JSON value under the privacy mode, in place.
+pub fn value(value: &mut serde_json::Value) {
+    let mode = mode();
+    if mode != LogContent::Full {
+        redact_value(value, false, mode);
+    }
+}
+
+/// Replaces the content strings in `value`.
+///
+/// Every string below a content key is redacted, except the `type` tags of
+/// content parts, which describe structure.
+fn redact_value(value: &mut serde_json::Value, in_content: bool, mode: LogContent) {
+    match value {
+        serde_json::Value::String(s) if in_content => *s = redact_str(s, mode),
+        serde_json::Value::Array(items) => {
+            for item in items {
+                redact_value(item, in_content, mode);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                if in_content && key == "type" {
+                    continue;
+                }
+                redact_value(item, in_content || CONTENT_KEYS.contains(&key.as_str()), mode);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_str(content: &str, mode: LogContent) -> String {
+    let length = content.chars().count();
+    match mode {
+        LogContent::Full => content.to_string(),
+        LogContent::Hash => {
+            let digest = Sha256::digest(content.as_bytes());
+            let prefix: String = digest.iter().take(6).map(|b| format!("{:02x}", b)).collect();
+            format!("<sha256:{} len={}>", prefix, length)
+        }
+        LogContent::LengthOnly => format!("<len={}>", length),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &str = "my card number is 4111 1111 1111 1111";
+
+    fn request() -> serde_json::Value {
+        json!({
+            "model": "deepseek-reasoner",
+            "messages": [
+                {"role": "system", "content": SECRET},
+                {"role": "user", "content": [{"type": "text", "text": SECRET}]},
+                {"role": "assistant", "tool_calls": [{"id": "call_1", "function": {"name": "lookup", "arguments": SECRET}}]}
+            ]
+        })
+    }
+
+    #[test]
+    fn restricted_modes_keep_structure_but_no_content() {
+        for mode in [LogContent::Hash, LogContent::LengthOnly] {
+            let mut value = request();
+            redact_value(&mut value, false, mode);
+            let logged = value.to_string();
+            assert!(!logged.contains("4111"), "{:?} leaked content: {}", mode, logged);
+            assert_eq!(value["model"], "deepseek-reasoner");
+            assert_eq!(value["messages"][1]["role"], "user");
+            assert_eq!(value["messages"][1]["content"][0]["type"], "text");
+            assert_eq!(value["messages"][2]["tool_calls"][0]["function"]["name"], "lookup");
+        }
+    }
+
+    #[test]
+    fn hash_mode_replaces_content_with_its_digest_and_length() {
+        assert_eq!(redact_str(SECRET, LogContent::LengthOnly), "<len=37>");
+        let hashed = redact_str(SECRET, LogContent::Hash);
+        assert!(hashed.starts_with("<sha256:") && hashed.ends_with(" len=37>"));
+        assert_eq!(hashed, redact_str(SECRET, LogContent::Hash));
+        assert_eq!(redact_str(SECRET, LogContent::Full), SECRET);
+    }
+}
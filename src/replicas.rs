@@ -0,0 +1,206 @@
+//! Routing across the reasoner replicas of a mapping.
+//!
+//! A mapping may list several `reasoner_endpoints` serving the same model,
+//! such as vLLM replicas. Each request takes a [`ReplicaLease`] on one of
+//! them, picked by the mapping's `reasoner_routing`, and holds it until the
+//! request is done, so the router knows how many requests each replica has
+//! in flight. Replicas whose circuit is open are skipped while another one
+//! is available.
+//!
+//! Sticky routing uses rendezvous hashing: a conversation goes to the replica
+//! with the highest hash of its key and the replica's URL, so a replica
+//! dropping out only moves the conversations it held.
+
+use crate::{
+    config::ReasonerRouting,
+    models::{ApiRequest, Role},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Requests in flight on one reasoner replica, as reported by `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicaGauge {
+    pub endpoint: String,
+    pub in_flight: usize,
+}
+
+/// Picks reasoner replicas and counts the requests in flight on each.
+#[derive(Debug, Default)]
+pub struct ReplicaRouter {
+    /// Next round-robin position, by mapping.
+    next: Mutex<HashMap<String, usize>>,
+    /// Requests in flight, by replica URL.
+    in_flight: Mutex<BTreeMap<String, Arc<AtomicUsize>>>,
+}
+
+/// A request's claim on the replica it was routed to.
+///
+/// Counts as in flight on the replica until dropped.
+#[derive(Debug)]
+pub struct ReplicaLease {
+    endpoint: String,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ReplicaLease {
+    /// Returns the URL of the replica.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
+
+impl Drop for ReplicaLease {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ReplicaRouter {
+    /// Picks one of `endpoints` for a request of `mapping`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mapping` - Name of the model mapping, which keys the round-robin position
+    /// * `endpoints` - The mapping's reasoner replicas
+    /// * `routing` - How to pick among them
+    /// * `affinity` - Key of the conversation, for sticky routing
+    /// * `is_open` - Whether a replica's circuit is open
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ReplicaLease>` - The lease on the picked replica, or `None`
+    ///   if `endpoints` is empty
+    pub fn select(
+        &self,
+        mapping: &str,
+        endpoints: &[String],
+        routing: ReasonerRouting,
+        affinity: Option<&str>,
+        is_open: impl Fn(&str) -> bool,
+    ) -> Option<ReplicaLease> {
+        // 全部副本熔断时仍按策略选择, 由熔断检查让请求快速失败
+        let healthy: Vec<&String> = endpoints.iter().filter(|endpoint| !is_open(endpoint)).collect();
+        let candidates = if healthy.is_empty() { endpoints.iter().collect() } else { healthy };
+        let endpoint = match (routing, affinity) {
+            (ReasonerRouting::Sticky, Some(key)) => candidates.into_iter().max_by_key(|endpoint| rendezvous(key, endpoint)),
+            (ReasonerRouting::LeastBusy, _) => candidates.into_iter().min_by_key(|endpoint| self.counter(endpoint).load(Ordering::Relaxed)),
+            // 没有会话标识的 sticky 请求退化为轮询
+            _ => {
+                let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+                let position = next.entry(mapping.to_string()).or_default();
+                let endpoint = candidates.get(*position % candidates.len().max(1)).copied();
+                *position = position.wrapping_add(1);
+                endpoint
+            }
+        }?;
+        let in_flight = self.counter(endpoint);
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(ReplicaLease {
+            endpoint: endpoint.clone(),
+            in_flight,
+        })
+    }
+
+    /// Returns the requests in flight on every replica that has been routed to, ordered by URL.
+    pub fn gauges(&self) -> Vec<ReplicaGauge> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(endpoint, in_flight)| ReplicaGauge {
+                endpoint: endpoint.clone(),
+                in_flight: in_flight.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn counter(&self, endpoint: &str) -> Arc<AtomicUsize> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(endpoint.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+/// Returns the key that keeps a conversation on one replica: its
+/// `conversation_id`, or else its first user message.
+pub fn affinity_key(request: &ApiRequest) -> Option<String> {
+    request.conversation_id.clone().or_else(|| {
+        request
+            .messages
+            .iter()
+            .find(|message| message.role == Role::User)
+            .map(|message| message.content.to_text())
+    })
+}
+
+fn rendezvous(key: &str, endpoint: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (key, endpoint).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints() -> Vec<String> {
+        ["http://r0", "http://r1", "http://r2"].map(String::from).to_vec()
+    }
+
+    fn pick(router: &ReplicaRouter, routing: ReasonerRouting, affinity: Option<&str>, open: &[&str]) -> ReplicaLease {
+        router.select("deepthink", &endpoints(), routing, affinity, |endpoint| open.contains(&endpoint)).unwrap()
+    }
+
+    #[test]
+    fn round_robin_takes_the_healthy_replicas_in_turn() {
+        let router = ReplicaRouter::default();
+        let picked: Vec<String> = (0..4).map(|_| pick(&router, ReasonerRouting::RoundRobin, None, &[]).endpoint().to_string()).collect();
+        assert_eq!(picked, ["http://r0", "http://r1", "http://r2", "http://r0"]);
+        let lease = pick(&router, ReasonerRouting::RoundRobin, None, &["http://r1"]);
+        assert_ne!(lease.endpoint(), "http://r1");
+        assert!(router.select("deepthink", &[], ReasonerRouting::RoundRobin, None, |_| false).is_none());
+    }
+
+    #[test]
+    fn least_busy_counts_the_leases_in_flight() {
+        let router = ReplicaRouter::default();
+        let first = pick(&router, ReasonerRouting::LeastBusy, None, &[]);
+        let second = pick(&router, ReasonerRouting::LeastBusy, None, &[]);
+        assert_ne!(first.endpoint(), second.endpoint());
+        let freed = second.endpoint().to_string();
+        drop(second);
+        let gauge = |endpoint: &str| router.gauges().into_iter().find(|gauge| gauge.endpoint == endpoint).unwrap().in_flight;
+        assert_eq!(gauge(first.endpoint()), 1);
+        assert_eq!(gauge(&freed), 0);
+        let third = pick(&router, ReasonerRouting::LeastBusy, None, &[]);
+        assert_ne!(third.endpoint(), first.endpoint());
+    }
+
+    #[test]
+    fn sticky_conversations_move_only_when_their_replica_is_open() {
+        let router = ReplicaRouter::default();
+        let home = pick(&router, ReasonerRouting::Sticky, Some("conversation"), &[]).endpoint().to_string();
+        for _ in 0..5 {
+            assert_eq!(pick(&router, ReasonerRouting::Sticky, Some("conversation"), &[]).endpoint(), home);
+        }
+        // 其他副本熔断不影响该会话
+        let others: Vec<&str> = ["http://r0", "http://r1", "http://r2"].into_iter().filter(|endpoint| *endpoint != home).collect();
+        assert_eq!(pick(&router, ReasonerRouting::Sticky, Some("conversation"), &others[..1]).endpoint(), home);
+
+        let moved = pick(&router, ReasonerRouting::Sticky, Some("conversation"), &[home.as_str()]).endpoint().to_string();
+        assert_ne!(moved, home);
+        // 全部熔断时仍选择原副本, 由熔断检查决定是否失败
+        assert_eq!(pick(&router, ReasonerRouting::Sticky, Some("conversation"), &["http://r0", "http://r1", "http://r2"]).endpoint(), home);
+    }
+}
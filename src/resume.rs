@@ -0,0 +1,296 @@
+//! Replay buffers for resumable streams.
+//!
+//! A stream requested with `resumable: true` records every frame it emits in
+//! a [`StreamBuffer`] registered under its stream id, numbering frames with
+//! the SSE `id` field as `{stream id}:{index}`. The producer keeps running
+//! when its client goes away, so the client can reconnect with
+//! `GET /v1/streams/{id}?from={index}`, or resend its request with the
+//! `Last-Event-ID` header as `EventSource` does, to replay the frames it
+//! missed and then follow the live stream. Buffers hold at most `streaming.resume_buffer_frames` frames
+//! and are dropped `streaming.resume_ttl_secs` after the stream ends, or
+//! earlier through `DELETE /v1/streams/{id}`.
+//!
+//! Each buffer belongs to the caller that created it, identified by a
+//! fingerprint of its credentials; other callers see no buffer under its id.
+
+use crate::error::SseResult;
+use axum::response::sse::Event;
+use futures::Stream;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// Buffers of the resumable streams, by stream id.
+#[derive(Debug)]
+pub struct StreamRegistry {
+    streams: Mutex<HashMap<String, Arc<StreamBuffer>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+/// The recorded frames of one stream.
+#[derive(Debug)]
+pub struct StreamBuffer {
+    /// Caller that created the stream; `None` for a caller without credentials,
+    /// whose stream cannot be resumed.
+    owner: Option<String>,
+    id: String,
+    state: Mutex<BufferState>,
+    capacity: usize,
+    /// Publishes the index of the next frame, waking replaying readers.
+    progress: watch::Sender<u64>,
+}
+
+#[derive(Debug)]
+struct BufferState {
+    frames: VecDeque<Event>,
+    /// Index of the oldest frame still buffered.
+    first_index: u64,
+    next_index: u64,
+    finished_at: Option<Instant>,
+}
+
+impl StreamRegistry {
+    /// Creates an empty registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Most frames kept per stream; older frames are dropped first
+    /// * `ttl` - How long a finished stream stays available for replay
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            streams: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    /// Registers a new buffer for the stream `id`, owned by `owner`.
+    pub fn create(&self, id: &str, owner: Option<String>) -> Arc<StreamBuffer> {
+        let (progress, _) = watch::channel(0);
+        let buffer = Arc::new(StreamBuffer {
+            owner,
+            id: id.to_string(),
+            state: Mutex::new(BufferState {
+                frames: VecDeque::new(),
+                first_index: 0,
+                next_index: 0,
+                finished_at: None,
+            }),
+            capacity: self.capacity,
+            progress,
+        });
+        let mut streams = self.lock();
+        self.purge_expired(&mut streams);
+        streams.insert(id.to_string(), buffer.clone());
+        buffer
+    }
+
+    /// Returns the buffer of the stream `id`, if it is still available and belongs to `owner`.
+    pub fn get(&self, id: &str, owner: &str) -> Option<Arc<StreamBuffer>> {
+        let mut streams = self.lock();
+        self.purge_expired(&mut streams);
+        streams.get(id).filter(|buffer| buffer.owned_by(owner)).cloned()
+    }
+
+    /// Drops the buffer of the stream `id`; returns `false` if there was
+    /// none belonging to `owner`.
+    ///
+    /// A stream that is still running keeps producing, but can no longer be resumed.
+    pub fn remove(&self, id: &str, owner: &str) -> bool {
+        let mut streams = self.lock();
+        if !streams.get(id).is_some_and(|buffer| buffer.owned_by(owner)) {
+            return false;
+        }
+        streams.remove(id).is_some()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<StreamBuffer>>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn purge_expired(&self, streams: &mut HashMap<String, Arc<StreamBuffer>>) {
+        let ttl = self.ttl;
+        streams.retain(|_, buffer| {
+            buffer
+                .lock()
+                .finished_at
+                .is_none_or(|finished_at| finished_at.elapsed() < ttl)
+        });
+    }
+}
+
+impl StreamBuffer {
+    fn owned_by(&self, owner: &str) -> bool {
+        self.owner.as_deref() == Some(owner)
+    }
+
+    /// Records a frame, dropping the oldest ones beyond the buffer's capacity.
+    ///
+    /// # Returns
+    ///
+    /// * `Event` - The frame with its stream id and index set as the SSE `id`
+    pub fn push(&self, event: Event) -> Event {
+        let mut state = self.lock();
+        let event = event.id(format!("{}:{}", self.id, state.next_index));
+        state.frames.push_back(event.clone());
+        state.next_index += 1;
+        while state.frames.len() > self.capacity {
+            state.frames.pop_front();
+            state.first_index += 1;
+        }
+        self.progress.send_replace(state.next_index);
+        event
+    }
+
+    /// Marks the stream as complete; replaying readers end after the last frame.
+    pub fn finish(&self) {
+        let mut state = self.lock();
+        if state.finished_at.is_none() {
+            state.finished_at = Some(Instant::now());
+        }
+        self.progress.send_replace(state.next_index);
+    }
+
+    /// Replays the frames from index `from`, then follows the stream until it ends.
+    ///
+    /// Frames already dropped from the buffer are skipped; replay starts at
+    /// the oldest frame still held.
+    pub fn replay(self: Arc<Self>, from: u64) -> impl Stream<Item = SseResult> {
+        let mut progress = self.progress.subscribe();
+        async_stream::stream! {
+            let mut next = from;
+            loop {
+                let (frames, finished) = {
+                    let state = self.lock();
+                    let start = next.max(state.first_index);
+                    let frames: Vec<Event> = state
+                        .frames
+                        .iter()
+                        .skip((start - state.first_index) as usize)
+                        .cloned()
+                        .collect();
+                    next = state.next_index.max(next);
+                    (frames, state.finished_at.is_some())
+                };
+                for frame in frames {
+                    yield Ok(frame);
+                }
+                if finished {
+                    break;
+                }
+                if progress.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BufferState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Splits an SSE event id into the stream id of a resumable stream, if it
+/// names one, and the frame index.
+///
+/// Frames of resumable streams carry `{stream id}:{index}`, all other
+/// frames only their index.
+pub fn parse_event_id(id: &str) -> Option<(Option<&str>, u64)> {
+    let id = id.trim();
+    match id.rsplit_once(':') {
+        Some((stream_id, index)) => Some((Some(stream_id), index.parse().ok()?)),
+        None => Some((None, id.parse().ok()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn frames(count: u64, buffer: &StreamBuffer) {
+        for n in 0..count {
+            let _ = buffer.push(Event::default().data(n.to_string()));
+        }
+    }
+
+    async fn replayed(buffer: Arc<StreamBuffer>, from: u64) -> Vec<String> {
+        buffer
+            .replay(from)
+            .map(|event| format!("{:?}", event.unwrap()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn replays_from_the_requested_frame_to_the_end() {
+        let registry = StreamRegistry::new(16, Duration::from_secs(60));
+        let buffer = registry.create("s1", Some("owner".to_string()));
+        frames(4, &buffer);
+        buffer.finish();
+
+        let replayed = replayed(buffer, 2).await;
+        assert_eq!(replayed.len(), 2);
+        assert!(replayed[0].contains("id: s1:2") && replayed[0].contains("data: 2"), "{}", replayed[0]);
+        assert!(replayed[1].contains("id: s1:3"));
+    }
+
+    #[tokio::test]
+    async fn replay_follows_the_live_stream_until_it_finishes() {
+        let registry = StreamRegistry::new(16, Duration::from_secs(60));
+        let buffer = registry.create("s1", Some("owner".to_string()));
+        frames(1, &buffer);
+        let reader = tokio::spawn(replayed(buffer.clone(), 0));
+        tokio::task::yield_now().await;
+        frames(2, &buffer);
+        buffer.finish();
+        assert_eq!(reader.await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn frames_beyond_the_capacity_are_dropped_oldest_first() {
+        let registry = StreamRegistry::new(2, Duration::from_secs(60));
+        let buffer = registry.create("s1", Some("owner".to_string()));
+        frames(5, &buffer);
+        buffer.finish();
+        let replayed = replayed(buffer, 0).await;
+        assert_eq!(replayed.len(), 2);
+        assert!(replayed[0].contains("id: s1:3"));
+    }
+
+    #[test]
+    fn buffers_are_only_visible_to_their_owner() {
+        let registry = StreamRegistry::new(16, Duration::from_secs(60));
+        registry.create("s1", Some("owner".to_string()));
+        registry.create("s2", None);
+        assert!(registry.get("s1", "owner").is_some());
+        assert!(registry.get("s1", "other").is_none());
+        assert!(registry.get("s2", "").is_none());
+        assert!(!registry.remove("s1", "other"));
+        assert!(registry.remove("s1", "owner"));
+        assert!(registry.get("s1", "owner").is_none());
+    }
+
+    #[test]
+    fn finished_streams_expire_after_the_ttl() {
+        let registry = StreamRegistry::new(16, Duration::ZERO);
+        let running = registry.create("running", Some("owner".to_string()));
+        let finished = registry.create("finished", Some("owner".to_string()));
+        finished.finish();
+        assert!(registry.get("finished", "owner").is_none());
+        assert!(registry.get("running", "owner").is_some());
+        drop(running);
+    }
+
+    #[test]
+    fn event_ids_name_the_stream_and_the_frame() {
+        assert_eq!(parse_event_id("chatcmpl-1:42"), Some((Some("chatcmpl-1"), 42)));
+        assert_eq!(parse_event_id(" 7 "), Some((None, 7)));
+        assert_eq!(parse_event_id("chatcmpl-1:x"), None);
+        assert_eq!(parse_event_id(""), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Reasoning reuse across the turns of a tool-calling loop.
+//!
+//! In an agent loop the client sends the conversation back once per tool
+//! iteration: the assistant's `tool_calls`, then the `tool` results, with no
+//! new user message. The plan the reasoner made for the user's request
+//! usually still holds, so with `reasoning.reasoning_reuse = "tool_loop"`
+//! those continuation turns skip the reasoner and inject the reasoning of
+//! the turn that started the loop. It is taken from the [`ReasoningStore`]
+//! entry of the request's `conversation_id`, or else from the thinking block
+//! leading the assistant turn that requested the tools. A new user message
+//! always gets fresh reasoning, which replaces the stored entry. With
+//! `"always"` any turn that finds reasoning to reuse skips the reasoner.
+
+use crate::{
+    config::ReasoningReuse,
+    models::{Message, Role},
+    think,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Latest fresh reasoning by conversation id.
+#[derive(Debug)]
+pub struct ReasoningStore {
+    entries: Mutex<HashMap<String, StoredReasoning>>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct StoredReasoning {
+    reasoning: String,
+    created: Instant,
+}
+
+impl ReasoningStore {
+    /// Creates an empty store whose entries stay usable for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Stores a conversation's fresh reasoning, replacing any earlier entry
+    /// and dropping expired ones.
+    pub fn store(&self, conversation_id: &str, reasoning: &str) {
+        let mut entries = self.lock();
+        entries.retain(|_, entry| entry.created.elapsed() < self.ttl);
+        entries.insert(
+            conversation_id.to_string(),
+            StoredReasoning {
+                reasoning: reasoning.to_string(),
+                created: Instant::now(),
+            },
+        );
+    }
+
+    /// Returns a conversation's stored reasoning unless it has expired.
+    pub fn get(&self, conversation_id: &str) -> Option<String> {
+        self.lock()
+            .get(conversation_id)
+            .filter(|entry| entry.created.elapsed() < self.ttl)
+            .map(|entry| entry.reasoning.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, StoredReasoning>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns true if `messages` continue a tool loop: the last message is a
+/// tool result, and an assistant turn requested tools after the last user
+/// message.
+pub fn is_tool_loop_continuation(messages: &[Message]) -> bool {
+    if messages.last().map(|message| &message.role) != Some(&Role::Tool) {
+        return false;
+    }
+    messages
+        .iter()
+        .rev()
+        .take_while(|message| message.role != Role::User)
+        .any(|message| message.role == Role::Assistant && message.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty()))
+}
+
+/// Finds reasoning the request may reuse instead of running the reasoner.
+///
+/// # Arguments
+///
+/// * `policy` - The configured `reasoning.reasoning_reuse`
+/// * `store` - Fresh reasoning by conversation id
+/// * `conversation_id` - The request's conversation, if it named one
+/// * `messages` - The request's messages
+///
+/// # Returns
+///
+/// * `Option<String>` - The reasoning to inject, or `None` to reason afresh
+pub fn reusable_reasoning(
+    policy: ReasoningReuse,
+    store: &ReasoningStore,
+    conversation_id: Option<&str>,
+    messages: &[Message],
+) -> Option<String> {
+    match policy {
+        ReasoningReuse::Never => return None,
+        ReasoningReuse::ToolLoop if !is_tool_loop_continuation(messages) => return None,
+        ReasoningReuse::ToolLoop | ReasoningReuse::Always => {}
+    }
+    conversation_id
+        .and_then(|conversation_id| store.get(conversation_id))
+        .or_else(|| history_reasoning(messages))
+}
+
+/// Returns the thinking block leading the latest assistant turn since the
+/// last user message.
+fn history_reasoning(messages: &[Message]) -> Option<String> {
+    messages
+        .iter()
+        .rev()
+        .take_while(|message| message.role != Role::User)
+        .filter(|message| message.role == Role::Assistant)
+        .find_map(|message| think::leading_block(&message.content.to_text()))
+        .filter(|reasoning| !reasoning.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{FunctionCall, ToolCall};
+
+    fn message(role: Role, text: &str) -> Message {
+        Message { role, content: text.into(), tool_calls: None, tool_call_id: None }
+    }
+
+    fn tool_request(thinking: &str) -> Message {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall { name: "weather".to_string(), arguments: "{}".to_string() },
+        };
+        Message { tool_calls: Some(vec![call]), ..message(Role::Assistant, thinking) }
+    }
+
+    #[test]
+    fn only_tool_results_after_a_tool_request_continue_a_loop() {
+        let user = message(Role::User, "Weather?");
+        let tool = message(Role::Tool, "Sunny");
+        assert!(is_tool_loop_continuation(&[user.clone(), tool_request(""), tool.clone()]));
+        assert!(!is_tool_loop_continuation(&[user.clone(), tool_request(""), tool.clone(), user.clone()]));
+        assert!(!is_tool_loop_continuation(&[tool_request(""), user.clone(), tool]));
+        assert!(!is_tool_loop_continuation(&[user]));
+    }
+
+    #[test]
+    fn policies_choose_which_turns_reuse_reasoning() {
+        let store = ReasoningStore::new(Duration::from_secs(60));
+        store.store("conv-1", "stored plan");
+        let continuation = [message(Role::User, "Weather?"), tool_request("<think>history plan</think>"), message(Role::Tool, "Sunny")];
+        let new_turn = [message(Role::User, "Weather?")];
+
+        let reuse = |policy, conversation_id, messages: &[Message]| reusable_reasoning(policy, &store, conversation_id, messages);
+        assert_eq!(reuse(ReasoningReuse::ToolLoop, Some("conv-1"), &continuation).as_deref(), Some("stored plan"));
+        assert_eq!(reuse(ReasoningReuse::ToolLoop, Some("conv-2"), &continuation).as_deref(), Some("history plan"));
+        assert_eq!(reuse(ReasoningReuse::ToolLoop, Some("conv-1"), &new_turn), None);
+        assert_eq!(reuse(ReasoningReuse::Always, Some("conv-1"), &new_turn).as_deref(), Some("stored plan"));
+        assert_eq!(reuse(ReasoningReuse::Never, Some("conv-1"), &continuation), None);
+    }
+
+    #[test]
+    fn stored_reasoning_expires() {
+        let store = ReasoningStore::new(Duration::from_millis(20));
+        store.store("conv-1", "plan");
+        assert_eq!(store.get("conv-1").as_deref(), Some("plan"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("conv-1"), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Validation of target answers against a requested JSON schema.
+//!
+//! Used when a request sets `validate_json_schema`: the target's final answer
+//! must parse as JSON and satisfy the `response_format.json_schema.schema`
+//! the client supplied. Models primed with a thinking block often wrap the
+//! JSON in prose or code fences, so the answer is unwrapped before giving up.
+
+use crate::error::{ApiError, Result};
+use jsonschema::Validator;
+
+/// Compiles the schema from an OpenAI `response_format` value.
+///
+/// # Arguments
+///
+/// * `response_format` - The request's `response_format`, if any
+///
+/// # Returns
+///
+/// * `Result<Option<Validator>>` - The compiled schema, or `None` when the
+///   response format carries no `json_schema`
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the schema itself is invalid.
+pub fn compile(response_format: Option<&serde_json::Value>) -> Result<Option<Validator>> {
+    let schema = match response_format.and_then(|format| format.pointer("/json_schema/schema")) {
+        Some(schema) => schema,
+        None => return Ok(None),
+    };
+    jsonschema::validator_for(schema)
+        .map(Some)
+        .map_err(|e| ApiError::BadRequest {
+            message: format!("Invalid response_format json_schema: {}", e),
+        })
+}
+
+/// Checks an answer against the schema, unwrapping it from prose if needed.
+///
+/// # Arguments
+///
+/// * `validator` - The compiled schema
+/// * `answer` - The target's answer text
+///
+/// # Returns
+///
+/// * `std::result::Result<String, Vec<String>>` - The conforming JSON text
+///   (possibly stripped of surrounding fences or prose), or the validation
+///   errors of the answer as given
+pub fn conform(validator: &Validator, answer: &str) -> std::result::Result<String, Vec<String>> {
+    let errors = match check(validator, answer.trim()) {
+        Ok(()) => return Ok(answer.trim().to_string()),
+        Err(errors) => errors,
+    };
+
+    match unwrap_json(answer) {
+        Some(inner) if check(validator, inner).is_ok() => Ok(inner.to_string()),
+        _ => Err(errors),
+    }
+}
+
+/// Validates `text` as a JSON document against `validator`.
+fn check(validator: &Validator, text: &str) -> std::result::Result<(), Vec<String>> {
+    let instance: serde_json::Value =
+        serde_json::from_str(text).map_err(|e| vec![format!("answer is not valid JSON: {}", e)])?;
+    let errors: Vec<String> = validator
+        .iter_errors(&instance)
+        .map(|e| format!("{}: {}", e.instance_path, e))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Extracts the JSON document from an answer wrapped in code fences or prose.
+fn unwrap_json(answer: &str) -> Option<&str> {
+    // ```json ... ``` 代码块优先
+    if let Some(start) = answer.find("```") {
+        let body = &answer[start + 3..];
+        let body = body.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+        if let Some(end) = body.find("```") {
+            return Some(body[..end].trim());
+        }
+    }
+
+    // 否则取第一个 { / [ 到最后一个 } / ] 之间的内容
+    let start = answer.find(['{', '['])?;
+    let end = answer.rfind(['}', ']'])?;
+    (start < end).then(|| &answer[start..=end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validator() -> Validator {
+        let format = json!({"type": "json_schema", "json_schema": {"name": "city", "schema": {
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"],
+        }}});
+        compile(Some(&format)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn formats_without_a_schema_compile_to_nothing() {
+        assert!(compile(None).unwrap().is_none());
+        assert!(compile(Some(&json!({"type": "json_object"}))).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_schemas_are_bad_requests() {
+        let format = json!({"json_schema": {"schema": {"type": "no-such-type"}}});
+        assert!(matches!(compile(Some(&format)), Err(ApiError::BadRequest { .. })));
+    }
+
+    #[test]
+    fn conforming_answers_are_trimmed() {
+        assert_eq!(conform(&validator(), " {\"city\": \"Paris\"}\n"), Ok("{\"city\": \"Paris\"}".to_string()));
+    }
+
+    #[test]
+    fn answers_are_unwrapped_from_fences_and_prose() {
+        let fenced = "Here you go:\n```json\n{\"city\": \"Paris\"}\n```";
+        assert_eq!(conform(&validator(), fenced), Ok("{\"city\": \"Paris\"}".to_string()));
+        let prose = "The answer is {\"city\": \"Paris\"} as requested.";
+        assert_eq!(conform(&validator(), prose), Ok("{\"city\": \"Paris\"}".to_string()));
+    }
+
+    #[test]
+    fn nonconforming_answers_report_their_errors() {
+        let errors = conform(&validator(), "{\"town\": \"Paris\"}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("city"), "{:?}", errors);
+
+        let errors = conform(&validator(), "Paris").unwrap_err();
+        assert!(errors[0].starts_with("answer is not valid JSON"), "{:?}", errors);
+    }
+}
@@ -0,0 +1,257 @@
+//! Request signing for upstreams behind an API gateway.
+//!
+//! Some deployments put the providers behind a gateway that authenticates
+//! every call by a signature over the request, on top of (or instead of)
+//! the provider token. Each client can carry a [`RequestSigner`], which is
+//! handed the final method, URL, headers and body just before the request is
+//! sent and returns the headers to add. The proxy builds an [`HmacSigner`]
+//! for every provider configured under `[signing.<provider>]`; library users
+//! can pass their own signer to `ClientBuilder::signer`.
+
+use crate::{
+    config::{HmacSigningConfig, SigningConfig},
+    error::{ApiError, Result},
+};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method,
+};
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Signs the requests a client sends.
+pub trait RequestSigner: Send + Sync + std::fmt::Debug {
+    /// Returns the headers to add to a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The request method
+    /// * `url` - The URL the request is sent to
+    /// * `headers` - The request's headers, without the ones returned here
+    /// * `body` - The exact bytes of the request body
+    ///
+    /// # Errors
+    ///
+    /// An error aborts the request before it is sent.
+    fn sign(
+        &self,
+        method: &Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Vec<(HeaderName, HeaderValue)>>;
+}
+
+/// Signs requests with an HMAC-SHA256 over the timestamp, method, URL and body.
+///
+/// The signed string is `{timestamp}\n{METHOD}\n{url}\n{body}`, where the
+/// timestamp is the Unix time in seconds shifted by `timestamp_skew_secs`.
+/// The lowercase hex signature and the timestamp are sent in their own headers.
+pub struct HmacSigner {
+    key: Vec<u8>,
+    signature_header: HeaderName,
+    timestamp_header: HeaderName,
+    timestamp_skew_secs: i64,
+}
+
+impl std::fmt::Debug for HmacSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 不输出密钥
+        f.debug_struct("HmacSigner")
+            .field("signature_header", &self.signature_header)
+            .field("timestamp_header", &self.timestamp_header)
+            .field("timestamp_skew_secs", &self.timestamp_skew_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HmacSigner {
+    /// Creates a signer.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the key is empty or a header name is invalid.
+    pub fn new(
+        key: impl Into<Vec<u8>>,
+        signature_header: &str,
+        timestamp_header: &str,
+        timestamp_skew_secs: i64,
+    ) -> std::result::Result<Self, String> {
+        let key = key.into();
+        if key.is_empty() {
+            return Err("The signing key is empty".to_string());
+        }
+        let header = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("Invalid signing header name {}: {}", name, e))
+        };
+        Ok(Self {
+            key,
+            signature_header: header(signature_header)?,
+            timestamp_header: header(timestamp_header)?,
+            timestamp_skew_secs,
+        })
+    }
+
+    /// Creates the signer of a `[signing.<provider>]` section, reading the key from its environment variable.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the variable is unset or the section is invalid.
+    pub fn from_config(config: &HmacSigningConfig) -> std::result::Result<Self, String> {
+        let key = std::env::var(&config.key_env)
+            .map_err(|_| format!("The signing key variable {} is not set", config.key_env))?;
+        Self::new(key, &config.signature_header, &config.timestamp_header, config.timestamp_skew_secs)
+    }
+
+    /// Returns the lowercase hex signature of a request signed at `timestamp`.
+    pub(crate) fn signature(&self, timestamp: i64, method: &Method, url: &str, body: &[u8]) -> Result<String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).map_err(|e| ApiError::Internal {
+            message: format!("Invalid signing key: {}", e),
+        })?;
+        mac.update(format!("{}\n{}\n{}\n", timestamp, method.as_str(), url).as_bytes());
+        mac.update(body);
+        Ok(mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+impl RequestSigner for HmacSigner {
+    fn sign(
+        &self,
+        method: &Method,
+        url: &str,
+        _headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Vec<(HeaderName, HeaderValue)>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let timestamp = now.saturating_add(self.timestamp_skew_secs);
+        let signature = self.signature(timestamp, method, url, body)?;
+        Ok(vec![
+            (self.timestamp_header.clone(), HeaderValue::from(timestamp)),
+            (self.signature_header.clone(), HeaderValue::from_str(&signature).map_err(|e| ApiError::Internal {
+                message: format!("Invalid signature header value: {}", e),
+            })?),
+        ])
+    }
+}
+
+/// The signer of each provider, if any.
+#[derive(Debug, Clone, Default)]
+pub struct Signers {
+    pub deepseek: Option<Arc<dyn RequestSigner>>,
+    pub openai: Option<Arc<dyn RequestSigner>>,
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+    pub anthropic: Option<Arc<dyn RequestSigner>>,
+}
+
+impl Signers {
+    /// Builds the signers of the `[signing]` sections.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first section that cannot be used.
+    pub fn from_config(config: &SigningConfig) -> std::result::Result<Self, String> {
+        let signer = |provider: &str, section: &Option<HmacSigningConfig>| {
+            section
+                .as_ref()
+                .map(|section| {
+                    HmacSigner::from_config(section)
+                        .map(|signer| Arc::new(signer) as Arc<dyn RequestSigner>)
+                        .map_err(|e| format!("signing.{}: {}", provider, e))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            deepseek: signer("deepseek", &config.deepseek)?,
+            openai: signer("openai", &config.openai)?,
+            anthropic: signer("anthropic", &config.anthropic)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::signed_body;
+    use std::sync::Mutex;
+
+    const URL: &str = "https://gateway.internal/v1/chat/completions";
+    const BODY: &[u8] = br#"{"model":"deepseek-reasoner","stream":false}"#;
+
+    fn signer(skew: i64) -> HmacSigner {
+        HmacSigner::new("gateway-secret", "X-Gateway-Signature", "X-Gateway-Timestamp", skew).unwrap()
+    }
+
+    #[test]
+    fn signs_a_fixed_request_with_the_reference_hmac() {
+        let signature = signer(0).signature(1_700_000_000, &Method::POST, URL, BODY).unwrap();
+        assert_eq!(signature, "56b0cad0fe17766b977d85a08a7243c23424c67a5921773a07366de59539c1d2");
+    }
+
+    #[test]
+    fn sends_the_skewed_timestamp_it_signed() {
+        let signer = signer(-30);
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let headers: HeaderMap = signer.sign(&Method::POST, URL, &HeaderMap::new(), BODY).unwrap().into_iter().collect();
+        let timestamp: i64 = headers["x-gateway-timestamp"].to_str().unwrap().parse().unwrap();
+        assert!((before - 30..=before - 29).contains(&timestamp));
+        let expected = signer.signature(timestamp, &Method::POST, URL, BODY).unwrap();
+        assert_eq!(headers["x-gateway-signature"], expected.as_str());
+    }
+
+    #[test]
+    fn rejects_empty_keys_and_invalid_header_names() {
+        assert!(HmacSigner::new("", "X-Signature", "X-Timestamp", 0).is_err());
+        assert!(HmacSigner::new("key", "X Signature", "X-Timestamp", 0).is_err());
+    }
+
+    /// Records the headers and body it was asked to sign.
+    #[derive(Debug, Default)]
+    struct RecordingSigner(Mutex<Option<(HeaderMap, Vec<u8>)>>);
+
+    impl RequestSigner for RecordingSigner {
+        fn sign(&self, _method: &Method, _url: &str, headers: &HeaderMap, body: &[u8]) -> Result<Vec<(HeaderName, HeaderValue)>> {
+            *self.0.lock().unwrap() = Some((headers.clone(), body.to_vec()));
+            Ok(vec![(HeaderName::from_static("x-signed"), HeaderValue::from_static("yes"))])
+        }
+    }
+
+    #[test]
+    fn signer_sees_the_final_body_and_headers() {
+        let signer = RecordingSigner::default();
+        let mut headers = HeaderMap::new();
+        let body = signed_body(Some(&signer), URL, &mut headers, &serde_json::json!({"model": "deepseek-reasoner"})).unwrap();
+        let (signed_headers, signed_body) = signer.0.lock().unwrap().take().unwrap();
+        assert_eq!(signed_body, body);
+        assert_eq!(signed_headers["content-type"], "application/json");
+        assert_eq!(headers["x-signed"], "yes");
+    }
+
+    #[tokio::test]
+    async fn clients_sign_chat_and_stream_requests_as_sent() {
+        use crate::{clients::DeepSeekClient, models::{ApiConfig, Message, Role}, testing};
+        use futures::StreamExt;
+        use wiremock::MockServer;
+
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        let url = format!("{}{}", upstream.uri(), testing::REASONER_PATH);
+        let signer = Arc::new(signer(0));
+        let client = DeepSeekClient::builder().api_token("token").base_url(&url).signer(signer.clone()).build().unwrap();
+        let messages = || vec![Message { role: Role::User, content: "Hi".into(), tool_calls: None, tool_call_id: None }];
+        let config = ApiConfig { headers: Default::default(), body: serde_json::json!({"model": "deepseek-reasoner"}) };
+
+        client.chat(messages(), &config).await.unwrap();
+        let _ = client.chat_stream(messages(), &config).collect::<Vec<_>>().await;
+        let received = upstream.received_requests().await.unwrap();
+        assert_eq!(received.len(), 2);
+        for request in received {
+            let timestamp: i64 = request.headers["x-gateway-timestamp"].to_str().unwrap().parse().unwrap();
+            let expected = signer.signature(timestamp, &Method::POST, &url, &request.body).unwrap();
+            assert_eq!(request.headers["x-gateway-signature"], expected.as_str());
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Sending half of the SSE pipeline.
+//!
+//! The streaming handler writes every event through an [`EventSink`], which
+//! applies the configured overflow policy and reports when the client has
+//! gone away so the upstream readers can be dropped instead of wasting tokens.
+//! A resumable stream also records each event in its replay buffer and keeps
+//! producing after the client disconnects, so the client can reconnect.
+//!
+//! Some reasoners stream one token per frame, so the chunk envelope dwarfs
+//! the text. With coalescing configured, reasoning deltas are held until
+//! enough bytes are pending or the oldest has waited long enough, then sent
+//! as one chunk. The delay is checked as deltas arrive; the end of the
+//! reasoning phase always flushes what is left.
+//!
+//! Every event carries an SSE `id` with its frame index, so `EventSource`
+//! clients reconnect with a `Last-Event-ID`, and the first event carries the
+//! configured `retry` delay.
+//!
+//! An optimistic request holds every event while the reasoner races a draft
+//! answer, and either releases them once the reasoner wins or discards them.
+
+use crate::{config::StreamOverflowPolicy, error::SseResult, metrics::Metrics, resume::StreamBuffer};
+use axum::response::sse::Event;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+/// Wraps the SSE channel with backpressure and disconnect handling.
+pub struct EventSink {
+    tx: Sender<SseResult>,
+    overflow: StreamOverflowPolicy,
+    metrics: Arc<Metrics>,
+    pending_reasoning: String,
+    coalesce: Option<Coalescing>,
+    resume: Option<Arc<StreamBuffer>>,
+    detached: AtomicBool,
+    held: Mutex<Option<Vec<Event>>>,
+    /// Index of the next frame, sent as its SSE `id`.
+    next_id: AtomicU64,
+    retry: Option<Duration>,
+}
+
+/// Reasoning deltas held back to be sent as one chunk.
+struct Coalescing {
+    max_bytes: usize,
+    max_delay: Duration,
+    held: String,
+    since: Instant,
+}
+
+impl EventSink {
+    /// Creates a sink writing into `tx`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - Channel feeding the client's SSE stream
+    /// * `overflow` - Policy applied to reasoning deltas when the channel is full
+    /// * `metrics` - Counters updated on coalesced frames and disconnects
+    pub fn new(tx: Sender<SseResult>, overflow: StreamOverflowPolicy, metrics: Arc<Metrics>) -> Self {
+        Self {
+            tx,
+            overflow,
+            metrics,
+            pending_reasoning: String::new(),
+            coalesce: None,
+            resume: None,
+            detached: AtomicBool::new(false),
+            held: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            retry: None,
+        }
+    }
+
+    /// Coalesces reasoning deltas into chunks of up to `max_bytes`, holding
+    /// none for longer than `max_delay`. A `max_bytes` of 0 leaves every delta
+    /// in its own chunk.
+    pub fn with_coalescing(mut self, max_bytes: usize, max_delay: Duration) -> Self {
+        self.coalesce = (max_bytes > 0).then(|| Coalescing {
+            max_bytes,
+            max_delay,
+            held: String::new(),
+            since: Instant::now(),
+        });
+        self
+    }
+
+    /// Records every event in `buffer` and keeps the stream running after a disconnect.
+    ///
+    /// Reasoning deltas are then never coalesced, since the buffer keeps them all.
+    pub fn with_resume(mut self, buffer: Arc<StreamBuffer>) -> Self {
+        self.resume = Some(buffer);
+        self
+    }
+
+    /// Sends `retry` as the reconnection delay with the first event.
+    pub fn with_retry(mut self, retry: Option<Duration>) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Adds the reconnection delay to the stream's first frame.
+    fn hint_retry(&self, event: Event, id: u64) -> Event {
+        match self.retry {
+            Some(retry) if id == 0 => event.retry(retry),
+            _ => event,
+        }
+    }
+
+    /// Holds every event sent from now on until [`EventSink::release`] or
+    /// [`EventSink::discard_held`].
+    pub fn hold(&self) {
+        *self.lock_held() = Some(Vec::new());
+    }
+
+    /// Sends the held events and stops holding.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the consumer has disconnected and the stream should stop
+    pub async fn release(&self) -> bool {
+        let held = self.lock_held().take().unwrap_or_default();
+        for event in held {
+            if !self.send(event).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Drops the held events and stops holding.
+    pub fn discard_held(&self) {
+        self.lock_held().take();
+    }
+
+    fn lock_held(&self) -> std::sync::MutexGuard<'_, Option<Vec<Event>>> {
+        self.held.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sends an event, waiting for channel capacity.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the consumer has disconnected and the stream should stop
+    pub async fn send(&self, event: Event) -> bool {
+        if let Some(held) = self.lock_held().as_mut() {
+            held.push(event);
+            return true;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let event = self.hint_retry(event, id);
+        let event = match &self.resume {
+            Some(buffer) => buffer.push(event),
+            None => event.id(id.to_string()),
+        };
+        if self.detached.load(Ordering::Relaxed) {
+            return true;
+        }
+        if self.tx.send(Ok(event)).await.is_ok() {
+            return true;
+        }
+        self.disconnected()
+    }
+
+    /// Sends a reasoning delta according to the coalescing settings and the
+    /// overflow policy.
+    ///
+    /// With coalescing, the delta is held until enough reasoning is pending.
+    /// Under `drop_reasoning`, a delta that does not fit into the channel is
+    /// held back and merged into the next one instead of blocking the reader.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the consumer has disconnected and the stream should stop
+    pub async fn send_reasoning<F>(&mut self, content: &str, make_event: F) -> bool
+    where
+        F: Fn(&str) -> Event,
+    {
+        let Some(coalesce) = &mut self.coalesce else {
+            return self.forward_reasoning(content, make_event).await;
+        };
+        if coalesce.held.is_empty() {
+            coalesce.since = Instant::now();
+        }
+        coalesce.held.push_str(content);
+        if coalesce.held.len() < coalesce.max_bytes && coalesce.since.elapsed() < coalesce.max_delay {
+            return true;
+        }
+        let held = std::mem::take(&mut coalesce.held);
+        self.forward_reasoning(&held, make_event).await
+    }
+
+    /// Sends reasoning according to the overflow policy.
+    async fn forward_reasoning<F>(&mut self, content: &str, make_event: F) -> bool
+    where
+        F: Fn(&str) -> Event,
+    {
+        if self.overflow == StreamOverflowPolicy::Block || self.resume.is_some() || self.lock_held().is_some() {
+            return self.send(make_event(content)).await;
+        }
+
+        self.pending_reasoning.push_str(content);
+        // 丢弃的帧不占用 id, 保证客户端看到的 id 连续
+        let id = self.next_id.load(Ordering::Relaxed);
+        let event = self.hint_retry(make_event(&self.pending_reasoning), id).id(id.to_string());
+        match self.tx.try_send(Ok(event)) {
+            Ok(()) => {
+                self.next_id.store(id + 1, Ordering::Relaxed);
+                self.pending_reasoning.clear();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.metrics.record_coalesced_frame();
+                true
+            }
+            Err(TrySendError::Closed(_)) => self.disconnected(),
+        }
+    }
+
+    /// Sends any reasoning held back by [`EventSink::send_reasoning`], waiting for capacity.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the consumer has disconnected and the stream should stop
+    pub async fn flush_reasoning<F>(&mut self, make_event: F) -> bool
+    where
+        F: Fn(&str) -> Event,
+    {
+        let mut pending = std::mem::take(&mut self.pending_reasoning);
+        if let Some(coalesce) = &mut self.coalesce {
+            pending.push_str(&std::mem::take(&mut coalesce.held));
+        }
+        if pending.is_empty() {
+            return true;
+        }
+        self.send(make_event(&pending)).await
+    }
+
+    /// Handles a closed channel: a resumable stream detaches and continues, others stop.
+    fn disconnected(&self) -> bool {
+        self.metrics.record_consumer_disconnect();
+        if self.resume.is_some() {
+            self.detached.store(true, Ordering::Relaxed);
+            tracing::info!("SSE consumer disconnected, buffering stream for resumption");
+            return true;
+        }
+        tracing::info!("SSE consumer disconnected, cancelling stream");
+        false
+    }
+}
+
+impl Drop for EventSink {
+    fn drop(&mut self) {
+        if let Some(buffer) = &self.resume {
+            buffer.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn sink(capacity: usize, overflow: StreamOverflowPolicy) -> (EventSink, mpsc::Receiver<SseResult>, Arc<Metrics>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let metrics = Arc::new(Metrics::default());
+        (EventSink::new(tx, overflow, metrics.clone()), rx, metrics)
+    }
+
+    fn data(piece: &str) -> Event {
+        Event::default().data(piece)
+    }
+
+    /// Renders an event the way it goes out on the wire.
+    fn wire(event: SseResult) -> String {
+        format!("{:?}", event.unwrap())
+    }
+
+    #[tokio::test]
+    async fn sends_report_a_disconnected_consumer() {
+        let (mut sink, rx, metrics) = sink(4, StreamOverflowPolicy::DropReasoning);
+        drop(rx);
+
+        assert!(!sink.send(data("answer")).await);
+        assert!(!sink.send_reasoning("thought", data).await);
+        assert_eq!(metrics.snapshot().consumer_disconnects, 2);
+    }
+
+    #[tokio::test]
+    async fn reasoning_is_coalesced_while_the_channel_is_full() {
+        let (mut sink, mut rx, metrics) = sink(1, StreamOverflowPolicy::DropReasoning);
+
+        assert!(sink.send_reasoning("one ", data).await);
+        assert!(sink.send_reasoning("two ", data).await);
+        assert!(sink.send_reasoning("three", data).await);
+        assert_eq!(metrics.snapshot().coalesced_reasoning_frames, 2);
+
+        assert!(wire(rx.recv().await.unwrap()).contains("one "));
+        assert!(sink.flush_reasoning(data).await);
+        assert!(wire(rx.recv().await.unwrap()).contains("two three"));
+        assert!(sink.flush_reasoning(data).await);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn blocking_sinks_wait_for_capacity() {
+        let (mut sink, mut rx, metrics) = sink(1, StreamOverflowPolicy::Block);
+
+        assert!(sink.send_reasoning("one", data).await);
+        let second = tokio::spawn(async move { sink.send_reasoning("two", data).await });
+        tokio::task::yield_now().await;
+        assert!(!second.is_finished());
+
+        assert!(wire(rx.recv().await.unwrap()).contains("one"));
+        assert!(second.await.unwrap());
+        assert!(wire(rx.recv().await.unwrap()).contains("two"));
+        assert_eq!(metrics.snapshot().coalesced_reasoning_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn resumable_streams_keep_producing_after_a_disconnect() {
+        let (sink, rx, metrics) = sink(4, StreamOverflowPolicy::DropReasoning);
+        let registry = crate::resume::StreamRegistry::new(16, std::time::Duration::from_secs(60));
+        let buffer = registry.create("s1", Some("owner".to_string()));
+        let mut sink = sink.with_resume(buffer.clone());
+        drop(rx);
+
+        assert!(sink.send(data("answer")).await);
+        assert!(sink.send_reasoning("thought", data).await);
+        assert!(sink.send(data("more")).await);
+        // 断开只记录一次, 之后的事件只写入缓冲
+        assert_eq!(metrics.snapshot().consumer_disconnects, 1);
+        drop(sink);
+        assert!(registry.get("s1", "owner").is_some());
+    }
+
+    #[tokio::test]
+    async fn reasoning_is_coalesced_up_to_the_byte_limit() {
+        let (sink, mut rx, _) = sink(16, StreamOverflowPolicy::Block);
+        let mut sink = sink.with_coalescing(8, Duration::from_secs(60));
+
+        for piece in ["ab", "cd", "ef"] {
+            assert!(sink.send_reasoning(piece, data).await);
+        }
+        assert!(rx.try_recv().is_err());
+        assert!(sink.send_reasoning("gh", data).await);
+        assert!(wire(rx.recv().await.unwrap()).contains("abcdefgh"));
+
+        // 推理结束时发出剩余内容
+        assert!(sink.send_reasoning("ij", data).await);
+        assert!(rx.try_recv().is_err());
+        assert!(sink.flush_reasoning(data).await);
+        assert!(wire(rx.recv().await.unwrap()).contains("ij"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn coalesced_reasoning_is_held_no_longer_than_the_delay() {
+        let (sink, mut rx, _) = sink(16, StreamOverflowPolicy::Block);
+        let mut sink = sink.with_coalescing(1024, Duration::from_millis(20));
+
+        assert!(sink.send_reasoning("slow ", data).await);
+        assert!(rx.try_recv().is_err());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(sink.send_reasoning("token", data).await);
+        assert!(wire(rx.recv().await.unwrap()).contains("slow token"));
+    }
+
+    #[tokio::test]
+    async fn zero_bytes_disables_coalescing() {
+        let (sink, mut rx, _) = sink(16, StreamOverflowPolicy::Block);
+        let mut sink = sink.with_coalescing(0, Duration::from_secs(60));
+
+        assert!(sink.send_reasoning("one", data).await);
+        assert!(wire(rx.recv().await.unwrap()).contains("one"));
+    }
+}
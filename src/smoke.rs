@@ -0,0 +1,321 @@
+//! The `deepthink smoke` subcommand: an end-to-end check of a deployment.
+//!
+//! Sends a tiny prompt through the whole pipeline and prints the reasoning,
+//! the answer and the phase timings, exiting nonzero if any of them is
+//! missing. Without `--url` the proxy is started in-process from the loaded
+//! `config.toml` on an ephemeral port, so the check exercises the configured
+//! endpoints and tokens without touching a running server. With `--mock` the
+//! upstreams are replaced by an in-process mock reasoner and target, which
+//! checks the proxy itself without network access or API tokens.
+//!
+//! ```text
+//! deepthink smoke [--mock] [--url <base url>] [--token <token>] [--model <model>] [--prompt <text>]
+//! ```
+
+use crate::config::Config;
+use anyhow::{bail, Context};
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, time::Duration};
+
+const DEFAULT_PROMPT: &str = "What is 2 + 2? Answer with the number only.";
+const DEFAULT_MODEL: &str = "deepthink";
+const DEFAULT_TOKEN: &str = "smoke";
+
+/// Longest the smoke request may take, reasoning included.
+const SMOKE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Prefix of the response headers carrying the phase timings.
+const TIMING_HEADER_PREFIX: &str = "x-deepthink-timing-";
+
+const MOCK_REASONING: &str = "The user asks for 2 + 2. Two plus two is four, so the answer is 4.";
+const MOCK_ANSWER: &str = "4";
+
+/// Options of the `smoke` subcommand.
+#[derive(Debug)]
+struct SmokeOptions {
+    mock: bool,
+    url: Option<String>,
+    token: String,
+    model: String,
+    prompt: String,
+}
+
+impl SmokeOptions {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self {
+            mock: false,
+            url: None,
+            token: DEFAULT_TOKEN.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            prompt: DEFAULT_PROMPT.to_string(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{} needs a value", arg)).cloned();
+            match arg.as_str() {
+                "--mock" => options.mock = true,
+                "--url" => options.url = Some(value()?),
+                "--token" => options.token = value()?,
+                "--model" => options.model = value()?,
+                "--prompt" => options.prompt = value()?,
+                other => bail!("Unknown smoke option {}", other),
+            }
+        }
+        if options.mock && options.url.is_some() {
+            bail!("--mock starts its own proxy and cannot be combined with --url");
+        }
+        Ok(options)
+    }
+}
+
+/// Runs the smoke test.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration, used for the in-process proxy
+/// * `args` - The arguments after `smoke`
+///
+/// # Errors
+///
+/// Returns an error, which makes the process exit nonzero, if the options
+/// are invalid, the request fails, or the response lacks reasoning or an answer.
+pub async fn run(mut config: Config, args: &[String]) -> anyhow::Result<()> {
+    let options = SmokeOptions::parse(args)?;
+    if options.mock {
+        let upstream = serve(mock_upstream()).await?;
+        use_mock_upstream(&mut config, upstream);
+    }
+    let base_url = match &options.url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let (app, _) = crate::routers(&config, crate::app_state(&config));
+            format!("http://{}", serve(app).await?)
+        }
+    };
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/v1/chat/completions", base_url))
+        .bearer_auth(&options.token)
+        .timeout(SMOKE_TIMEOUT)
+        .json(&json!({
+            "model": options.model,
+            "messages": [{"role": "user", "content": options.prompt}],
+            "stream": false,
+            "thinking_format": "reasoning_content",
+        }))
+        .send()
+        .await
+        .with_context(|| format!("Request to {} failed", base_url))?;
+    let status = response.status();
+    let timings: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let phase = name.as_str().strip_prefix(TIMING_HEADER_PREFIX)?;
+            Some((phase.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect();
+    let body = response.text().await.context("Failed to read the response")?;
+    if !status.is_success() {
+        bail!("Proxy answered HTTP {}: {}", status, body);
+    }
+    let body: Value = serde_json::from_str(&body).with_context(|| format!("Response is not JSON: {}", body))?;
+    let message = &body["choices"][0]["message"];
+    let reasoning = message["reasoning_content"].as_str().unwrap_or_default().trim();
+    let answer = message["content"].as_str().unwrap_or_default().trim();
+
+    println!("model:     {}", body["model"].as_str().unwrap_or_default());
+    println!("reasoning: {}", reasoning);
+    println!("answer:    {}", answer);
+    for (phase, ms) in &timings {
+        println!("timing:    {} = {}", phase, ms);
+    }
+
+    if reasoning.is_empty() {
+        bail!("The response carries no reasoning");
+    }
+    if answer.is_empty() {
+        bail!("The response carries no answer");
+    }
+    println!("smoke test passed");
+    Ok(())
+}
+
+/// Serves `router` on an ephemeral local port in the background.
+async fn serve(router: Router) -> anyhow::Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router.into_make_service()).await {
+            tracing::warn!("Smoke test server stopped: {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+/// Points the reasoner and the OpenAI target at the mock upstream.
+fn use_mock_upstream(config: &mut Config, upstream: SocketAddr) {
+    config.endpoints.deepseek = format!("http://{}/reasoner/chat/completions", upstream);
+    config.endpoints.openai = format!("http://{}/target/v1/chat/completions", upstream);
+    config.auth.token_mappings.clear();
+    config.auth.default_tokens.deepseek_token = "mock".to_string();
+    config.auth.default_tokens.openai_token = "mock".to_string();
+}
+
+/// A reasoner and an OpenAI target that always give the same reasoning and answer.
+fn mock_upstream() -> Router {
+    Router::new()
+        .route("/reasoner/chat/completions", post(mock_reasoner))
+        .route("/target/v1/chat/completions", post(mock_target))
+}
+
+async fn mock_reasoner(Json(request): Json<Value>) -> Response {
+    let usage = json!({"prompt_tokens": 12, "completion_tokens": 16, "total_tokens": 28});
+    if !wants_stream(&request) {
+        return Json(json!({
+            "id": "mock-reasoning",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "mock-reasoner",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "", "reasoning_content": MOCK_REASONING},
+                "logprobs": null,
+                "finish_reason": "stop",
+            }],
+            "usage": usage,
+            "system_fingerprint": "fp_mock",
+        }))
+        .into_response();
+    }
+    let chunk = |delta: Value, finish_reason: Value, usage: Value| {
+        json!({
+            "id": "mock-reasoning",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "mock-reasoner",
+            "choices": [{"index": 0, "delta": delta, "logprobs": null, "finish_reason": finish_reason}],
+            "usage": usage,
+            "system_fingerprint": "fp_mock",
+        })
+    };
+    let mut chunks: Vec<Value> = MOCK_REASONING
+        .split_inclusive(' ')
+        .map(|word| chunk(json!({"role": "assistant", "reasoning_content": word}), Value::Null, Value::Null))
+        .collect();
+    chunks.push(chunk(json!({}), json!("stop"), usage));
+    event_stream(chunks)
+}
+
+async fn mock_target(Json(request): Json<Value>) -> Response {
+    let usage = json!({"prompt_tokens": 40, "completion_tokens": 1, "total_tokens": 41});
+    if !wants_stream(&request) {
+        return Json(json!({
+            "id": "mock-answer",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "mock-target",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": MOCK_ANSWER},
+                "finish_reason": "stop",
+            }],
+            "usage": usage,
+        }))
+        .into_response();
+    }
+    let chunk = |delta: Value, finish_reason: Value, usage: Value| {
+        json!({
+            "id": "mock-answer",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "mock-target",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+            "usage": usage,
+        })
+    };
+    event_stream(vec![
+        chunk(json!({"role": "assistant", "content": MOCK_ANSWER}), Value::Null, Value::Null),
+        chunk(json!({}), json!("stop"), usage),
+    ])
+}
+
+fn wants_stream(request: &Value) -> bool {
+    request.get("stream").and_then(Value::as_bool).unwrap_or(false)
+}
+
+/// Writes `chunks` as an SSE body ending in `[DONE]`.
+fn event_stream(chunks: Vec<Value>) -> Response {
+    let mut body: String = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect();
+    body.push_str("data: [DONE]\n\n");
+    ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, OPENAI_PATH, REASONER_PATH};
+    use wiremock::{matchers::{method, path}, Mock, MockServer, ResponseTemplate};
+
+    async fn mock_answer(upstream: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path(OPENAI_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::openai_completion(json!({"role": "assistant", "content": "4"}), "stop")))
+            .mount(upstream)
+            .await;
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_pass_against_the_configured_upstreams() {
+        let upstream = MockServer::start().await;
+        testing::mock_reasoner(&upstream).await;
+        mock_answer(&upstream).await;
+        run(testing::config(&upstream), &args(&["--prompt", "2 + 2?"])).await.unwrap();
+
+        let reasoner = testing::received(&upstream, REASONER_PATH).await;
+        assert_eq!(reasoner.len(), 1);
+        assert!(reasoner[0]["messages"].to_string().contains("2 + 2?"));
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_fail_without_reasoning() {
+        let upstream = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(REASONER_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(testing::reasoner_completion("")))
+            .mount(&upstream)
+            .await;
+        mock_answer(&upstream).await;
+        let mut config = testing::config(&upstream);
+        config.reasoning.empty_policy = crate::config::EmptyReasoningPolicy::Skip;
+        let error = run(config, &[]).await.unwrap_err();
+        assert_eq!(error.to_string(), "The response carries no reasoning");
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_pass_against_the_mock_upstream() {
+        let upstream = MockServer::start().await;
+        // --mock 替换配置的上游, 配置中的地址不会被请求
+        run(testing::config(&upstream), &args(&["--mock"])).await.unwrap();
+        assert!(upstream.received_requests().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn options_are_validated() {
+        let options = SmokeOptions::parse(&args(&["--model", "m", "--token", "t"])).unwrap();
+        assert_eq!((options.model.as_str(), options.token.as_str(), options.mock), ("m", "t", false));
+        assert!(SmokeOptions::parse(&args(&["--mock", "--url", "http://localhost:3000"])).is_err());
+        assert!(SmokeOptions::parse(&args(&["--model"])).is_err());
+        assert!(SmokeOptions::parse(&args(&["--verbose"])).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Speculative reasoning for conversation continuations.
+//!
+//! In chat UIs the next user turn usually arrives while the user is still
+//! reading the previous answer. With `speculation.speculative_reasoning`
+//! enabled, a completed non-streaming response to a request carrying a
+//! `conversation_id` starts a background reasoner run on the updated
+//! history, asked to anticipate likely follow-ups. The reasoning is cached
+//! under the conversation id together with a hash of that history.
+//!
+//! The next request for the conversation only uses the entry if its
+//! messages, minus the new user turn, hash to the same history; anything
+//! else is a miss and drops the entry, so a conversation that was edited or
+//! regenerated never sees stale reasoning. Assistant turns are compared by
+//! position only, since clients send answers back in varying forms (with or
+//! without the inline reasoning). On a hit, `speculation.reuse` decides
+//! whether the reasoning is injected as is or topped up by a short reasoner
+//! run on the real question.
+
+use crate::models::{Message, Role};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Cached speculative reasoning by conversation id.
+#[derive(Debug)]
+pub struct SpeculationCache {
+    entries: Mutex<HashMap<String, Speculation>>,
+    running: AtomicUsize,
+    max_concurrent: usize,
+    ttl: Duration,
+    runs: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Speculation {
+    /// Hash of the history the reasoning anticipates a continuation of.
+    history: u64,
+    reasoning: String,
+    created: Instant,
+}
+
+/// A running speculation's slot, released when dropped.
+#[derive(Debug)]
+pub struct SpeculationSlot {
+    cache: Arc<SpeculationCache>,
+}
+
+impl Drop for SpeculationSlot {
+    fn drop(&mut self) {
+        self.cache.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time speculation counters, as reported by `/metrics`.
+#[derive(Debug, Default, Serialize)]
+pub struct SpeculationStats {
+    /// Speculative reasoner runs started.
+    pub runs: u64,
+    /// Requests that used cached speculative reasoning.
+    pub hits: u64,
+    /// Requests whose conversation had speculative reasoning for a different history.
+    pub misses: u64,
+    /// Speculative reasoner runs in progress.
+    pub running: usize,
+    /// Conversations with cached speculative reasoning.
+    pub cached: usize,
+}
+
+impl SpeculationCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_concurrent` - Speculative runs allowed at once across all conversations
+    /// * `ttl` - How long cached reasoning stays usable
+    pub fn new(max_concurrent: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            running: AtomicUsize::new(0),
+            max_concurrent,
+            ttl,
+            runs: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Claims a slot for a speculative run, or returns `None` when
+    /// `max_concurrent` runs are already in progress.
+    pub fn try_start(self: &Arc<Self>) -> Option<SpeculationSlot> {
+        self.running
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |running| {
+                (running < self.max_concurrent).then_some(running + 1)
+            })
+            .ok()?;
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        Some(SpeculationSlot { cache: self.clone() })
+    }
+
+    /// Caches speculative reasoning for a conversation, replacing any
+    /// earlier entry and dropping expired ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `conversation_id` - The conversation the reasoning belongs to
+    /// * `history` - The conversation including the answer just returned
+    /// * `reasoning` - The speculative reasoning
+    pub fn store(&self, conversation_id: &str, history: &[Message], reasoning: String) {
+        let mut entries = self.lock();
+        entries.retain(|_, entry| entry.created.elapsed() < self.ttl);
+        entries.insert(
+            conversation_id.to_string(),
+            Speculation {
+                history: conversation_hash(history),
+                reasoning,
+                created: Instant::now(),
+            },
+        );
+    }
+
+    /// Takes a conversation's cached reasoning if it anticipated `messages`.
+    ///
+    /// The entry is removed whether or not it matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `conversation_id` - The conversation of the request
+    /// * `messages` - The request's messages, ending with the new user turn
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The speculative reasoning on a hit
+    pub fn take(&self, conversation_id: &str, messages: &[Message]) -> Option<String> {
+        let entry = self.lock().remove(conversation_id)?;
+        let history = match messages.split_last() {
+            Some((last, history)) if last.role == Role::User => history,
+            _ => &[],
+        };
+        if entry.created.elapsed() < self.ttl && !history.is_empty() && entry.history == conversation_hash(history) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Some(entry.reasoning)
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    /// Returns the current counters.
+    pub fn stats(&self) -> SpeculationStats {
+        SpeculationStats {
+            runs: self.runs.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+            cached: self.lock().len(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Speculation>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Hashes the parts of a conversation that identify its state: every turn's
+/// role and, except for assistant turns, its content.
+fn conversation_hash(messages: &[Message]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for message in messages {
+        serde_json::to_string(&message.role).unwrap_or_default().hash(&mut hasher);
+        if message.role != Role::Assistant {
+            message.content.to_text().hash(&mut hasher);
+            message.tool_call_id.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(value: serde_json::Value) -> Vec<Message> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn history() -> Vec<Message> {
+        messages(json!([
+            {"role": "user", "content": "Capital of France?"},
+            {"role": "assistant", "content": "Paris."}
+        ]))
+    }
+
+    #[test]
+    fn matching_continuations_hit_once() {
+        let cache = SpeculationCache::new(1, Duration::from_secs(60));
+        cache.store("conv-1", &history(), "Follow-ups are about Paris.".to_string());
+
+        // 客户端回传的回答可能带着推理, 助手消息只比较位置
+        let next = messages(json!([
+            {"role": "user", "content": "Capital of France?"},
+            {"role": "assistant", "content": "<think>...</think>Paris."},
+            {"role": "user", "content": "And its population?"}
+        ]));
+        assert_eq!(cache.take("conv-1", &next).as_deref(), Some("Follow-ups are about Paris."));
+        assert_eq!(cache.take("conv-1", &next), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.cached), (1, 0, 0));
+    }
+
+    #[test]
+    fn edited_or_expired_histories_miss_and_drop_the_entry() {
+        let cache = SpeculationCache::new(1, Duration::from_secs(60));
+        cache.store("conv-1", &history(), "reasoning".to_string());
+        let edited = messages(json!([
+            {"role": "user", "content": "Capital of Spain?"},
+            {"role": "assistant", "content": "Madrid."},
+            {"role": "user", "content": "And its population?"}
+        ]));
+        assert_eq!(cache.take("conv-1", &edited), None);
+        assert_eq!(cache.stats().cached, 0);
+
+        // 最后一条不是用户消息时不算命中
+        cache.store("conv-1", &history(), "reasoning".to_string());
+        assert_eq!(cache.take("conv-1", &history()), None);
+
+        let expiring = SpeculationCache::new(1, Duration::ZERO);
+        expiring.store("conv-1", &history(), "reasoning".to_string());
+        let mut next = history();
+        next.extend(messages(json!([{"role": "user", "content": "More?"}])));
+        assert_eq!(expiring.take("conv-1", &next), None);
+        assert_eq!(cache.stats().misses + expiring.stats().misses, 3);
+    }
+
+    #[test]
+    fn concurrent_runs_are_capped() {
+        let cache = Arc::new(SpeculationCache::new(2, Duration::from_secs(60)));
+        let first = cache.try_start().unwrap();
+        let _second = cache.try_start().unwrap();
+        assert!(cache.try_start().is_none());
+        assert_eq!(cache.stats().running, 2);
+
+        drop(first);
+        assert!(cache.try_start().is_some());
+        assert_eq!(cache.stats().runs, 3);
+    }
+}
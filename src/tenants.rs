@@ -0,0 +1,362 @@
+//! Tenant namespaces sharing one process.
+//!
+//! Each `[tenants.<name>]` table may bring its own `models`, `endpoints` and
+//! `auth` sections, so teams with different model catalogs and upstreams
+//! can share a deployment. A request belongs to the tenant its auth token
+//! is mapped to, either through the token's `tenant` field in the global
+//! `auth.token_mappings` or by being listed in the tenant's own
+//! `auth.token_mappings`; other requests use the global sections. Admins
+//! may pick a tenant with the `X-DeepThink-Tenant` header, which requires
+//! `server.admin_token` in `X-DeepThink-Admin-Token`.
+//!
+//! A tenant's sections replace the global ones, except that model mappings
+//! the tenant does not define fall back to the global mappings, and the
+//! endpoint override policy is inherited unless the tenant sets it. Mappings of
+//! one tenant are never visible to another. Spend budgets are kept per
+//! tenant, so the callers without a token mapping of each tenant share a
+//! budget only with each other.
+
+use crate::{
+    config::{AuthConfig, Config, EndpointConfig, ModelConfig, TenantConfig, TokenConfig},
+    error::{ApiError, Result},
+    trace::ADMIN_TOKEN_HEADER,
+};
+use axum::http::HeaderMap;
+use std::{collections::HashMap, sync::Arc};
+
+/// Request header naming the tenant of an admin's request.
+pub const TENANT_HEADER: &str = "X-DeepThink-Tenant";
+
+/// The model, endpoint and auth settings a request resolves against.
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    /// Name of the tenant; `None` for the global sections.
+    pub tenant: Option<String>,
+    pub models: ModelConfig,
+    pub endpoints: EndpointConfig,
+    pub auth: AuthConfig,
+}
+
+impl Namespace {
+    /// Resolves the caller's token configuration from its `Authorization` token.
+    ///
+    /// # Returns
+    ///
+    /// * `(&str, &TokenConfig)` - The mapped auth token and its configuration, or
+    ///   an empty key and the default tokens for callers without a mapping
+    pub fn caller_tokens(&self, headers: &HeaderMap) -> (&str, &TokenConfig) {
+        bearer_token(headers)
+            .and_then(|token| self.auth.token_mappings.get_key_value(token))
+            .map(|(token, tokens)| (token.as_str(), tokens))
+            .unwrap_or(("", &self.auth.default_tokens))
+    }
+
+    /// Returns the key the caller's spend is recorded under.
+    ///
+    /// Inside a tenant the key is prefixed with the tenant's name, so the
+    /// callers without a token mapping of different tenants do not share a budget.
+    pub fn spend_key(&self, headers: &HeaderMap) -> String {
+        let caller = self.caller_tokens(headers).0;
+        match &self.tenant {
+            Some(tenant) => format!("{}/{}", tenant, caller),
+            None => caller.to_string(),
+        }
+    }
+}
+
+/// The global namespace and the namespace of every tenant.
+#[derive(Debug)]
+pub struct Tenants {
+    global: Arc<Namespace>,
+    tenants: HashMap<String, Arc<Namespace>>,
+    /// Tenant of each token listed in a tenant's own `auth.token_mappings`.
+    token_tenants: HashMap<String, String>,
+    admin_token: Option<String>,
+}
+
+impl Tenants {
+    /// Builds the namespaces of `config`.
+    pub fn new(config: &Config) -> Self {
+        let tenants: HashMap<String, Arc<Namespace>> = config
+            .tenants
+            .iter()
+            .map(|(name, tenant)| (name.clone(), Arc::new(namespace(config, name, tenant))))
+            .collect();
+        let token_tenants = config
+            .tenants
+            .iter()
+            .flat_map(|(name, tenant)| {
+                tenant
+                    .auth
+                    .iter()
+                    .flat_map(|auth| auth.token_mappings.keys())
+                    .map(move |token| (token.clone(), name.clone()))
+            })
+            .collect();
+        Self {
+            global: Arc::new(Namespace {
+                tenant: None,
+                models: config.models.clone(),
+                endpoints: config.endpoints.clone(),
+                auth: config.auth.clone(),
+            }),
+            tenants,
+            token_tenants,
+            admin_token: config.server.admin_token.clone(),
+        }
+    }
+
+    /// Checks that every tenant a token names exists and no token belongs to two tenants.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first problem found.
+    pub fn check(config: &Config) -> std::result::Result<(), String> {
+        for (token, tokens) in &config.auth.token_mappings {
+            if let Some(tenant) = &tokens.tenant {
+                if !config.tenants.contains_key(tenant) {
+                    return Err(format!("auth.token_mappings: a token names unknown tenant '{}'", tenant));
+                }
+                let listed_elsewhere = config.tenants.iter().any(|(name, other)| {
+                    name != tenant && other.auth.as_ref().is_some_and(|auth| auth.token_mappings.contains_key(token))
+                });
+                if listed_elsewhere {
+                    return Err(format!("auth.token_mappings: a token of tenant '{}' is also mapped by another tenant", tenant));
+                }
+            }
+        }
+        let mut owners: HashMap<&str, &str> = HashMap::new();
+        for (name, tenant) in &config.tenants {
+            for token in tenant.auth.iter().flat_map(|auth| auth.token_mappings.keys()) {
+                if let Some(owner) = owners.insert(token, name) {
+                    return Err(format!("tenants.{}.auth: a token is also mapped by tenant '{}'", name, owner));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the namespace of a request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::TenantNotAllowed` if the request names a tenant in
+    /// `X-DeepThink-Tenant` without the admin token, or a tenant that does not exist.
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<Arc<Namespace>> {
+        if let Some(requested) = headers.get(TENANT_HEADER) {
+            let requested = requested.to_str().map_err(|_| not_allowed("the header is not valid ASCII"))?;
+            let supplied = headers.get(ADMIN_TOKEN_HEADER).and_then(|h| h.to_str().ok());
+            if self.admin_token.is_none() || supplied != self.admin_token.as_deref() {
+                return Err(not_allowed(&format!("{} requires server.admin_token in {}", TENANT_HEADER, ADMIN_TOKEN_HEADER)));
+            }
+            return self
+                .tenants
+                .get(requested)
+                .cloned()
+                .ok_or_else(|| not_allowed(&format!("tenant '{}' does not exist", requested)));
+        }
+        let Some(token) = bearer_token(headers) else {
+            return Ok(self.global.clone());
+        };
+        // 全局映射中的 tenant 字段优先, 其次是租户自己的 token 映射
+        let tenant = self
+            .global
+            .auth
+            .token_mappings
+            .get(token)
+            .and_then(|tokens| tokens.tenant.as_ref())
+            .or_else(|| self.token_tenants.get(token));
+        Ok(tenant
+            .and_then(|tenant| self.tenants.get(tenant))
+            .unwrap_or(&self.global)
+            .clone())
+    }
+
+    /// Returns the namespace of a request already checked by [`Tenants::resolve`].
+    ///
+    /// Falls back to the global namespace for a request that fails the check.
+    pub fn of(&self, headers: &HeaderMap) -> Arc<Namespace> {
+        self.resolve(headers).unwrap_or_else(|_| self.global.clone())
+    }
+}
+
+/// Builds the namespace of tenant `name` on top of the global sections.
+fn namespace(config: &Config, name: &str, tenant: &TenantConfig) -> Namespace {
+    let mut models = tenant.models.clone().unwrap_or_else(|| config.models.clone());
+    // 租户没有定义的映射回退到全局映射
+    for (model, mapping) in &config.models.model_mappings {
+        models.model_mappings.entry(model.clone()).or_insert_with(|| mapping.clone());
+    }
+    let mut auth = tenant.auth.clone().unwrap_or_else(|| AuthConfig {
+        default_tokens: config.auth.default_tokens.clone(),
+        token_mappings: HashMap::new(),
+    });
+    // 全局映射中指向本租户的 token 也属于本租户
+    for (token, tokens) in &config.auth.token_mappings {
+        if tokens.tenant.as_deref() == Some(name) {
+            auth.token_mappings.entry(token.clone()).or_insert_with(|| tokens.clone());
+        }
+    }
+    Namespace {
+        tenant: Some(name.to_string()),
+        models,
+        // 租户没有设置的覆盖策略 (allow_override, allowed_hosts) 沿用全局配置
+        endpoints: tenant
+            .endpoints
+            .as_ref()
+            .map(|endpoints| endpoints.resolve(&config.endpoints))
+            .unwrap_or_else(|| config.endpoints.clone()),
+        auth,
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+}
+
+fn not_allowed(reason: &str) -> ApiError {
+    ApiError::TenantNotAllowed {
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelMapping, TenantEndpointConfig};
+
+    fn tokens(tenant: Option<&str>) -> TokenConfig {
+        TokenConfig {
+            tenant: tenant.map(str::to_string),
+            ..Config::default().auth.default_tokens
+        }
+    }
+
+    fn tenant_endpoints() -> TenantEndpointConfig {
+        TenantEndpointConfig {
+            deepseek: "http://10.0.0.2:11434/v1/chat/completions".to_string(),
+            anthropic: "https://api.anthropic.com/v1/messages".to_string(),
+            openai: "http://10.0.0.2:11434/v1/chat/completions".to_string(),
+            allow_override: None,
+            allowed_hosts: None,
+            completion_paths: None,
+        }
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.endpoints.allow_override = false;
+        config.endpoints.allowed_hosts = vec!["api.openai.com".to_string()];
+        config.server.admin_token = Some("admin".to_string());
+        config.auth.token_mappings.insert("sk-global-a".to_string(), tokens(Some("team_a")));
+        config.models.model_mappings.insert("shared".to_string(), mapping("shared-target"));
+
+        let mut team_a = TenantConfig {
+            endpoints: Some(tenant_endpoints()),
+            models: Some(ModelConfig::default()),
+            ..TenantConfig::default()
+        };
+        team_a.models.as_mut().unwrap().model_mappings.insert("a-only".to_string(), mapping("a-target"));
+        let mut team_b = TenantConfig {
+            auth: Some(AuthConfig::default()),
+            ..TenantConfig::default()
+        };
+        team_b.auth.as_mut().unwrap().token_mappings.insert("sk-b".to_string(), tokens(None));
+        config.tenants.insert("team_a".to_string(), team_a);
+        config.tenants.insert("team_b".to_string(), team_b);
+        config
+    }
+
+    fn mapping(target: &str) -> ModelMapping {
+        serde_json::from_value(serde_json::json!({
+            "deepseek_model": "deepseek-r1",
+            "target_model": target,
+            "parameters": {},
+        }))
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn tenant_endpoints_inherit_the_override_policy() {
+        let tenants = Tenants::new(&config());
+        let namespace = tenants.resolve(&headers(&[("Authorization", "Bearer sk-global-a")])).unwrap();
+        assert_eq!(namespace.tenant.as_deref(), Some("team_a"));
+        assert_eq!(namespace.endpoints.deepseek, "http://10.0.0.2:11434/v1/chat/completions");
+        assert!(!namespace.endpoints.allow_override);
+        assert_eq!(namespace.endpoints.allowed_hosts, vec!["api.openai.com".to_string()]);
+    }
+
+    #[test]
+    fn tenant_endpoints_may_set_their_own_policy() {
+        let mut config = config();
+        let endpoints = config.tenants.get_mut("team_a").unwrap().endpoints.as_mut().unwrap();
+        endpoints.allow_override = Some(true);
+        endpoints.allowed_hosts = Some(vec!["10.0.0.2".to_string()]);
+        let namespace = Tenants::new(&config).resolve(&headers(&[("Authorization", "Bearer sk-global-a")])).unwrap();
+        assert!(namespace.endpoints.allow_override);
+        assert_eq!(namespace.endpoints.allowed_hosts, vec!["10.0.0.2".to_string()]);
+    }
+
+    #[test]
+    fn tokens_resolve_to_their_tenant() {
+        let tenants = Tenants::new(&config());
+        let resolve = |token: &str| tenants.resolve(&headers(&[("Authorization", token)])).unwrap().tenant.clone();
+        assert_eq!(resolve("Bearer sk-global-a").as_deref(), Some("team_a"));
+        assert_eq!(resolve("Bearer sk-b").as_deref(), Some("team_b"));
+        assert_eq!(resolve("Bearer sk-unknown"), None);
+        assert_eq!(tenants.resolve(&HeaderMap::new()).unwrap().tenant, None);
+    }
+
+    #[test]
+    fn mappings_do_not_leak_between_tenants() {
+        let tenants = Tenants::new(&config());
+        let team_a = tenants.resolve(&headers(&[("Authorization", "Bearer sk-global-a")])).unwrap();
+        let team_b = tenants.resolve(&headers(&[("Authorization", "Bearer sk-b")])).unwrap();
+        let global = tenants.resolve(&HeaderMap::new()).unwrap();
+        assert!(team_a.models.model_mappings.contains_key("a-only"));
+        assert!(team_a.models.model_mappings.contains_key("shared"));
+        assert!(!team_b.models.model_mappings.contains_key("a-only"));
+        assert!(!global.models.model_mappings.contains_key("a-only"));
+        // 租户的 token 不属于其他租户
+        assert!(!team_b.auth.token_mappings.contains_key("sk-global-a"));
+        assert!(!global.auth.token_mappings.contains_key("sk-b"));
+    }
+
+    #[test]
+    fn spend_keys_are_prefixed_with_the_tenant() {
+        let tenants = Tenants::new(&config());
+        let request = headers(&[("Authorization", "Bearer sk-unmapped"), (TENANT_HEADER, "team_b"), (ADMIN_TOKEN_HEADER, "admin")]);
+        let namespace = tenants.resolve(&request).unwrap();
+        assert_eq!(namespace.spend_key(&request), "team_b/");
+        assert_eq!(tenants.resolve(&HeaderMap::new()).unwrap().spend_key(&HeaderMap::new()), "");
+    }
+
+    #[test]
+    fn tenant_header_requires_the_admin_token() {
+        let tenants = Tenants::new(&config());
+        assert!(tenants.resolve(&headers(&[(TENANT_HEADER, "team_a")])).is_err());
+        assert!(tenants.resolve(&headers(&[(TENANT_HEADER, "team_a"), (ADMIN_TOKEN_HEADER, "wrong")])).is_err());
+        assert!(tenants.resolve(&headers(&[(TENANT_HEADER, "missing"), (ADMIN_TOKEN_HEADER, "admin")])).is_err());
+        let namespace = tenants.resolve(&headers(&[(TENANT_HEADER, "team_a"), (ADMIN_TOKEN_HEADER, "admin")])).unwrap();
+        assert_eq!(namespace.tenant.as_deref(), Some("team_a"));
+    }
+
+    #[test]
+    fn check_rejects_tokens_of_two_tenants() {
+        let mut config = config();
+        assert!(Tenants::check(&config).is_ok());
+        config.tenants.get_mut("team_b").unwrap().auth.as_mut().unwrap().token_mappings.insert("sk-global-a".to_string(), tokens(None));
+        assert!(Tenants::check(&config).is_err());
+    }
+}
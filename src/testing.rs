@@ -0,0 +1,306 @@
+//! Mocked upstreams for the route tests.
+//!
+//! The tests serve the real routes in process and point every provider at a
+//! [`MockServer`]: the reasoner under `/reasoner`, the OpenAI target under
+//! `/openai` and the Anthropic target under `/anthropic`.
+
+use crate::{app_state, config::Config, handlers::AppState, routers};
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request, StatusCode},
+    Router,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tower::ServiceExt;
+use wiremock::{
+    matchers::{body_partial_json, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Path of the mocked reasoner.
+pub const REASONER_PATH: &str = "/reasoner/chat/completions";
+
+/// Path of the mocked OpenAI target.
+pub const OPENAI_PATH: &str = "/openai/v1/chat/completions";
+
+/// Path of the mocked Anthropic target.
+pub const ANTHROPIC_PATH: &str = "/anthropic/v1/messages";
+
+/// The reasoning every mocked reasoner call returns.
+pub const REASONING: &str = "The user wants a short answer.";
+
+/// Returns the configuration pointing every provider at `upstream`.
+pub fn config(upstream: &MockServer) -> Config {
+    let mut config = Config::default();
+    config.endpoints.deepseek = format!("{}{}", upstream.uri(), REASONER_PATH);
+    config.endpoints.openai = format!("{}{}", upstream.uri(), OPENAI_PATH);
+    config.endpoints.anthropic = format!("{}{}", upstream.uri(), ANTHROPIC_PATH);
+    config
+}
+
+/// Serves the routes of `config`.
+pub fn app(config: &Config) -> (Router, Arc<AppState>) {
+    let state = app_state(config);
+    (routers(config, state.clone()).0, state)
+}
+
+/// Returns a non-streaming reasoner response carrying `reasoning`.
+pub fn reasoner_completion(reasoning: &str) -> Value {
+    json!({
+        "id": "reasoning-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "deepseek-reasoner",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": "", "reasoning_content": reasoning},
+            "finish_reason": "stop",
+        }],
+        "usage": {"prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20},
+        "system_fingerprint": "fp_mock",
+    })
+}
+
+/// Mounts a reasoner answering every non-streaming call with [`REASONING`].
+pub async fn mock_reasoner(upstream: &MockServer) {
+    Mock::given(method("POST"))
+        .and(path(REASONER_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(reasoner_completion(REASONING)))
+        .mount(upstream)
+        .await;
+}
+
+/// Mounts a reasoner streaming [`REASONING`] to every streaming call.
+pub async fn mock_streaming_reasoner(upstream: &MockServer) {
+    Mock::given(method("POST"))
+        .and(path(REASONER_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(reasoner_stream(REASONING), "text/event-stream"))
+        .mount(upstream)
+        .await;
+}
+
+/// Returns a streaming reasoner response carrying `reasoning` in two deltas.
+pub fn reasoner_stream(reasoning: &str) -> String {
+    let chunk = |delta: Value, finish_reason: Value, usage: Value| {
+        json!({
+            "id": "reasoning-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "deepseek-reasoner",
+            "choices": [{"index": 0, "delta": delta, "logprobs": null, "finish_reason": finish_reason}],
+            "usage": usage,
+            "system_fingerprint": "fp_mock",
+        })
+    };
+    let (head, tail) = reasoning.split_at(reasoning.len() / 2);
+    sse(&[
+        chunk(json!({"role": "assistant", "reasoning_content": head}), Value::Null, Value::Null),
+        chunk(json!({"reasoning_content": tail}), Value::Null, Value::Null),
+        chunk(
+            json!({"content": ""}),
+            json!("stop"),
+            json!({"prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20}),
+        ),
+    ])
+}
+
+/// Mounts an OpenAI target streaming `deltas` as the answer.
+pub async fn mock_streaming_openai(upstream: &MockServer, deltas: &[&str]) {
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+            "usage": null,
+        })
+    };
+    let mut chunks = vec![chunk(json!({"role": "assistant"}), Value::Null)];
+    chunks.extend(deltas.iter().map(|content| chunk(json!({"content": content}), Value::Null)));
+    chunks.push(chunk(json!({}), json!("stop")));
+    Mock::given(method("POST"))
+        .and(path(OPENAI_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(sse(&chunks), "text/event-stream"))
+        .mount(upstream)
+        .await;
+}
+
+/// Serves a reasoner that streams the first half of `reasoning` and then
+/// stalls without closing the stream.
+///
+/// Returns the endpoint URL to configure as the reasoner.
+pub async fn mock_stalled_reasoner(reasoning: &str) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let first_frame = reasoner_stream(reasoning).lines().next().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let first_frame = first_frame.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 8192];
+                let _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n{}\n\n",
+                    first_frame
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                // 保持连接但不再发送任何数据
+                std::future::pending::<()>().await;
+            });
+        }
+    });
+    format!("http://{}{}", address, REASONER_PATH)
+}
+
+/// Mounts an OpenAI target streaming `deltas` to streaming calls only, so a
+/// non-streaming mock mounted after it answers the other calls.
+pub async fn mock_streaming_openai_when_streamed(upstream: &MockServer, deltas: &[&str]) {
+    let chunks: Vec<Value> = deltas
+        .iter()
+        .map(|content| {
+            json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}],
+            })
+        })
+        .collect();
+    Mock::given(method("POST"))
+        .and(path(OPENAI_PATH))
+        .and(body_partial_json(json!({"stream": true})))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(sse(&chunks), "text/event-stream"))
+        .mount(upstream)
+        .await;
+}
+
+/// Serves an OpenAI target that streams `deltas` and then drops the
+/// connection before finishing the answer.
+///
+/// Returns the endpoint URL to configure as the OpenAI target.
+pub async fn mock_dying_openai(deltas: &[&str]) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let frames: String = deltas
+        .iter()
+        .map(|content| {
+            let chunk = json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}],
+            });
+            format!("data: {}\n\n", chunk)
+        })
+        .collect();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let frames = frames.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 8192];
+                let _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                    frames.len(),
+                    frames
+                );
+                // 发送部分回答后不发送结束块直接断开
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    format!("http://{}{}", address, OPENAI_PATH)
+}
+
+/// Encodes `chunks` as an SSE body ending in `[DONE]`.
+pub fn sse(chunks: &[Value]) -> String {
+    let mut body: String = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect();
+    body.push_str("data: [DONE]\n\n");
+    body
+}
+
+/// Returns the `delta` of choice 0 of every chunk of an SSE response body.
+///
+/// Ids and timestamps vary between runs, so the deltas are what the golden
+/// snapshots of streamed responses compare.
+pub fn stream_deltas(body: &str) -> Vec<Value> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .filter_map(|chunk| chunk.pointer("/choices/0/delta").cloned())
+        .collect()
+}
+
+/// Returns an OpenAI chat completion with one choice.
+pub fn openai_completion(message: Value, finish_reason: &str) -> Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{"index": 0, "message": message, "finish_reason": finish_reason}],
+        "usage": {"prompt_tokens": 30, "completion_tokens": 5, "total_tokens": 35},
+    })
+}
+
+/// Returns an Anthropic message with the given content blocks.
+#[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+pub fn anthropic_message(content: Value, stop_reason: &str) -> Value {
+    json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-test",
+        "content": content,
+        "stop_reason": stop_reason,
+        "stop_sequence": null,
+        "usage": {"input_tokens": 30, "output_tokens": 5},
+    })
+}
+
+/// Posts a JSON body to `uri` and returns the status, headers and body.
+pub async fn post(app: &Router, uri: &str, headers: &[(&str, &str)], body: Value) -> (StatusCode, HeaderMap, String) {
+    let mut request = Request::post(uri).header("content-type", "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    (parts.status, parts.headers, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Sends a GET request to `uri` and returns the status and body.
+pub async fn get(app: &Router, uri: &str, headers: &[(&str, &str)]) -> (StatusCode, String) {
+    let mut request = Request::get(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Returns the JSON bodies the upstream received on `path`, oldest first.
+pub async fn received(upstream: &MockServer, path: &str) -> Vec<Value> {
+    upstream
+        .received_requests()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|request| request.url.path() == path)
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect()
+}
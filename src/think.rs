@@ -0,0 +1,344 @@
+//! Think tags in reasoner output and in client messages.
+//!
+//! Ollama reasoners write their reasoning inline as `<think>...</think>` at
+//! the start of their output, and tag-format responses wrap the reasoning in
+//! `<thinking>` tags. Only output the proxy itself produces is parsed for
+//! these tags, and a `<think>` only opens the reasoning at the very start of
+//! the reasoner's output. Tags written by clients are never parsed: in user
+//! messages they are escaped before the reasoner sees them unless
+//! `reasoning.user_think_tags = "pass"`, so a reasoner quoting the user
+//! cannot end its own reasoning early, and thinking blocks a client sends
+//! back at the start of assistant turns are dropped when
+//! `reasoning.strip_history_thinking` is set. Tags the target writes in its
+//! answer are escaped or removed according to `target.answer_think_tags`.
+
+use crate::{
+    config::AnswerThinkTags,
+    models::{ContentPart, Message, MessageContent, Role},
+};
+use std::collections::HashMap;
+
+/// Tag opening an ollama reasoner's inline reasoning.
+pub const THINK_OPEN: &str = "<think>";
+
+/// Tag closing an ollama reasoner's inline reasoning.
+pub const THINK_CLOSE: &str = "</think>";
+
+/// Names of the tags the pipeline treats as reasoning markup.
+const TAG_NAMES: &[&str] = &["think", "thinking"];
+
+/// Returns true if reasoner output opens with a `<think>` tag.
+pub fn opens(output: &str) -> bool {
+    output.trim_start().starts_with(THINK_OPEN)
+}
+
+/// Splits reasoner output into the reasoning of its leading `<think>` block
+/// and the text around it.
+///
+/// # Returns
+///
+/// * `Option<(String, String)>` - The trimmed reasoning and the trimmed rest,
+///   or `None` unless the output opens with `<think>` and the block is closed
+pub fn split(output: &str) -> Option<(String, String)> {
+    let body = output.trim_start().strip_prefix(THINK_OPEN)?;
+    let end = body.find(THINK_CLOSE)?;
+    let reasoning = body[..end].trim().to_string();
+    let rest = body[end + THINK_CLOSE.len()..].trim().to_string();
+    Some((reasoning, rest))
+}
+
+/// Escapes the think tags in user messages, so no phase reads them as markup.
+pub fn escape_user_tags(messages: &mut [Message]) {
+    for message in messages.iter_mut().filter(|message| message.role == Role::User) {
+        for_each_text(&mut message.content, |text| *text = escape(text));
+    }
+}
+
+/// Drops the thinking block a client sent back at the start of assistant turns.
+///
+/// Only a closed `<think>` or `<thinking>` block leading the turn is
+/// removed; tags anywhere else are left as written.
+pub fn strip_history_thinking(messages: &mut [Message]) {
+    for message in messages.iter_mut().filter(|message| message.role == Role::Assistant) {
+        for_each_text(&mut message.content, |text| {
+            if let Some(rest) = strip_leading_block(text) {
+                *text = rest;
+            }
+        });
+    }
+}
+
+/// Returns the trimmed reasoning of a closed `<think>` or `<thinking>` block
+/// leading `text`.
+pub fn leading_block(text: &str) -> Option<String> {
+    let trimmed = text.trim_start();
+    TAG_NAMES.iter().find_map(|name| {
+        let body = trimmed.strip_prefix(&format!("<{}>", name))?;
+        let end = body.find(&format!("</{}>", name))?;
+        Some(body[..end].trim().to_string())
+    })
+}
+
+/// Applies `mode` to the think tags in an answer.
+///
+/// # Returns
+///
+/// * `(String, u32)` - The sanitized answer and the number of tags it had;
+///   the count is 0 under [`AnswerThinkTags::Pass`]
+pub fn sanitize_answer(text: &str, mode: AnswerThinkTags) -> (String, u32) {
+    if mode == AnswerThinkTags::Pass {
+        return (text.to_string(), 0);
+    }
+    let mut sanitized = text.to_string();
+    let mut count = 0;
+    for tag in tags() {
+        let matches = sanitized.matches(tag.as_str()).count() as u32;
+        if matches > 0 {
+            let replacement = match mode {
+                AnswerThinkTags::Strip => String::new(),
+                _ => tag.replace('<', "&lt;").replace('>', "&gt;"),
+            };
+            sanitized = sanitized.replace(tag.as_str(), &replacement);
+            count += matches;
+        }
+    }
+    (sanitized, count)
+}
+
+/// Applies [`sanitize_answer`] to a streamed answer, per choice.
+///
+/// Tags can be split across deltas, so a delta ending in what may be the
+/// start of a tag has that part held back until the next delta shows
+/// whether it is one.
+#[derive(Debug)]
+pub struct AnswerTagFilter {
+    mode: AnswerThinkTags,
+    held: HashMap<u32, String>,
+    count: u32,
+}
+
+impl AnswerTagFilter {
+    pub fn new(mode: AnswerThinkTags) -> Self {
+        Self {
+            mode,
+            held: HashMap::new(),
+            count: 0,
+        }
+    }
+
+    /// Returns the part of choice `index`'s delta that can be sent now, sanitized.
+    pub fn push(&mut self, index: u32, delta: &str) -> String {
+        if self.mode == AnswerThinkTags::Pass {
+            return delta.to_string();
+        }
+        let mut pending = self.held.remove(&index).unwrap_or_default();
+        pending.push_str(delta);
+        let split = partial_tag_start(&pending).unwrap_or(pending.len());
+        let held = pending.split_off(split);
+        if !held.is_empty() {
+            self.held.insert(index, held);
+        }
+        let (sanitized, count) = sanitize_answer(&pending, self.mode);
+        self.count += count;
+        sanitized
+    }
+
+    /// Returns the text still held back per choice, once the answer is complete.
+    ///
+    /// Held text is never a whole tag, so it is returned unchanged.
+    pub fn finish(&mut self) -> Vec<(u32, String)> {
+        let mut held: Vec<(u32, String)> = self.held.drain().collect();
+        held.sort_by_key(|(index, _)| *index);
+        held
+    }
+
+    /// Returns the number of tags sanitized so far.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+/// Returns every opening and closing think tag.
+fn tags() -> Vec<String> {
+    TAG_NAMES
+        .iter()
+        .flat_map(|name| [format!("<{}>", name), format!("</{}>", name)])
+        .collect()
+}
+
+/// Returns where the longest suffix of `text` that is the start of a tag,
+/// but not a whole one, begins.
+fn partial_tag_start(text: &str) -> Option<usize> {
+    let tags = tags();
+    let longest = tags.iter().map(String::len).max().unwrap_or(0);
+    // 标签只含 ASCII, '<' 的位置总是字符边界
+    text.char_indices()
+        .filter(|(position, c)| *c == '<' && text.len() - position < longest)
+        .map(|(position, _)| position)
+        .find(|&position| {
+            let suffix = &text[position..];
+            tags.iter().any(|tag| tag.len() > suffix.len() && tag.starts_with(suffix))
+        })
+}
+
+fn escape(text: &str) -> String {
+    TAG_NAMES.iter().fold(text.to_string(), |text, name| {
+        text.replace(&format!("<{}>", name), &format!("&lt;{}&gt;", name))
+            .replace(&format!("</{}>", name), &format!("&lt;/{}&gt;", name))
+    })
+}
+
+fn strip_leading_block(text: &str) -> Option<String> {
+    let trimmed = text.trim_start();
+    TAG_NAMES.iter().find_map(|name| {
+        let body = trimmed.strip_prefix(&format!("<{}>", name))?;
+        let close = format!("</{}>", name);
+        let end = body.find(&close)?;
+        Some(body[end + close.len()..].trim_start().to_string())
+    })
+}
+
+/// Applies `edit` to the message's text and to each of its text parts.
+fn for_each_text(content: &mut MessageContent, mut edit: impl FnMut(&mut String)) {
+    match content {
+        MessageContent::Text(text) => edit(text),
+        MessageContent::Parts(parts) => {
+            for part in parts {
+                if let ContentPart::Text { text } = part {
+                    edit(text);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, text: &str) -> Message {
+        Message { role, content: text.into(), tool_calls: None, tool_call_id: None }
+    }
+
+    fn text(message: &Message) -> &str {
+        match &message.content {
+            MessageContent::Text(text) => text,
+            MessageContent::Parts(_) => panic!("expected text content"),
+        }
+    }
+
+    #[test]
+    fn only_a_leading_think_tag_opens_the_reasoning() {
+        assert!(opens("  <think>plan"));
+        assert!(!opens("The user wrote <think> here"));
+        assert_eq!(split("\n<think> plan </think>\nanswer"), Some(("plan".to_string(), "answer".to_string())));
+        assert_eq!(split("answer quoting <think>x</think>"), None);
+        assert_eq!(split("<think>never closed"), None);
+    }
+
+    #[test]
+    fn the_reasoning_ends_at_the_first_close_tag() {
+        let (reasoning, rest) = split("<think>a</think>b </think> c").unwrap();
+        assert_eq!(reasoning, "a");
+        assert_eq!(rest, "b </think> c");
+    }
+
+    #[test]
+    fn user_tags_are_escaped_and_other_roles_are_left_alone() {
+        let mut messages = vec![
+            message(Role::System, "<think>system</think>"),
+            message(Role::User, "Why does </think> end <thinking>it</thinking>?"),
+            message(Role::Assistant, "<think>kept</think>"),
+        ];
+        escape_user_tags(&mut messages);
+        assert_eq!(text(&messages[0]), "<think>system</think>");
+        assert_eq!(text(&messages[1]), "Why does &lt;/think&gt; end &lt;thinking&gt;it&lt;/thinking&gt;?");
+        assert_eq!(text(&messages[2]), "<think>kept</think>");
+    }
+
+    #[test]
+    fn escaping_covers_every_text_part() {
+        let mut messages = vec![Message {
+            role: Role::User,
+            content: MessageContent::Parts(vec![
+                ContentPart::Text { text: "a <think>".to_string() },
+                ContentPart::Text { text: "</think> b".to_string() },
+            ]),
+            tool_calls: None,
+            tool_call_id: None,
+        }];
+        escape_user_tags(&mut messages);
+        let MessageContent::Parts(parts) = &messages[0].content else { panic!("expected parts") };
+        let texts: Vec<_> = parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => text.as_str(),
+                _ => panic!("expected text parts"),
+            })
+            .collect();
+        assert_eq!(texts, ["a &lt;think&gt;", "&lt;/think&gt; b"]);
+    }
+
+    #[test]
+    fn only_leading_assistant_thinking_is_stripped_from_history() {
+        let mut messages = vec![
+            message(Role::User, "<think>user</think> question"),
+            message(Role::Assistant, " <thinking>old plan</thinking>\nAnswer"),
+            message(Role::Assistant, "<think>plan</think>Answer"),
+            message(Role::Assistant, "Answer mentioning <think>x</think>"),
+            message(Role::Assistant, "<think>unclosed"),
+        ];
+        strip_history_thinking(&mut messages);
+        assert_eq!(text(&messages[0]), "<think>user</think> question");
+        assert_eq!(text(&messages[1]), "Answer");
+        assert_eq!(text(&messages[2]), "Answer");
+        assert_eq!(text(&messages[3]), "Answer mentioning <think>x</think>");
+        assert_eq!(text(&messages[4]), "<think>unclosed");
+    }
+
+    #[test]
+    fn leading_blocks_are_only_found_at_the_start() {
+        assert_eq!(leading_block("  <think> plan </think>Answer").as_deref(), Some("plan"));
+        assert_eq!(leading_block("<thinking>plan</thinking>").as_deref(), Some("plan"));
+        assert_eq!(leading_block("Answer <think>plan</think>"), None);
+        assert_eq!(leading_block("<think>never closed"), None);
+    }
+
+    #[test]
+    fn answer_tags_are_escaped_stripped_or_passed() {
+        let answer = "Plan: <think>echo</think> then </thinking>done";
+        assert_eq!(sanitize_answer(answer, AnswerThinkTags::Pass), (answer.to_string(), 0));
+        assert_eq!(sanitize_answer(answer, AnswerThinkTags::Strip), ("Plan: echo then done".to_string(), 3));
+        assert_eq!(
+            sanitize_answer(answer, AnswerThinkTags::Escape),
+            ("Plan: &lt;think&gt;echo&lt;/think&gt; then &lt;/thinking&gt;done".to_string(), 3)
+        );
+        assert_eq!(sanitize_answer("a < b and <thin", AnswerThinkTags::Strip), ("a < b and <thin".to_string(), 0));
+    }
+
+    #[test]
+    fn tags_split_across_deltas_are_caught() {
+        let mut filter = AnswerTagFilter::new(AnswerThinkTags::Strip);
+        let deltas = ["Sure <thi", "nk>echo</th", "ink", "> x < y <", "/thinking>", " end <"];
+        let sent: String = deltas.iter().map(|delta| filter.push(0, delta)).collect();
+        assert_eq!(sent, "Sure echo x < y  end ");
+        assert_eq!(filter.count(), 3);
+        // 结束时仍保留的文本不是完整标签, 原样发送
+        assert_eq!(filter.finish(), [(0, "<".to_string())]);
+        assert!(filter.finish().is_empty());
+    }
+
+    #[test]
+    fn choices_hold_their_partial_tags_apart() {
+        let mut filter = AnswerTagFilter::new(AnswerThinkTags::Escape);
+        assert_eq!(filter.push(0, "a<th"), "a");
+        assert_eq!(filter.push(1, "b<think"), "b");
+        assert_eq!(filter.push(1, ">"), "&lt;think&gt;");
+        assert_eq!(filter.push(0, "e"), "<the");
+        assert_eq!(filter.count(), 1);
+
+        let mut passthrough = AnswerTagFilter::new(AnswerThinkTags::Pass);
+        assert_eq!(passthrough.push(0, "<thi"), "<thi");
+        assert!(passthrough.finish().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+//! Output rate limiting for streamed responses.
+//!
+//! This module provides a character-rate throttle used by the streaming
+//! handler to pace content sent to the client. Content is split into
+//! small pieces and released on a fixed tick, so a fast upstream is
+//! smoothed into a steady output rate.
+
+use crate::sink::EventSink;
+use axum::response::sse::Event;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// Upper bound on how many frames per second a throttle emits.
+const MAX_TICKS_PER_SECOND: u64 = 20;
+
+/// Paces streamed content at a fixed characters-per-second rate.
+///
+/// Rates that are not a multiple of the tick rate are met exactly: the
+/// fraction of a character left over after each tick is carried into the
+/// next, so 25 characters per second releases 1, 1, 1 and then 2 characters
+/// per tick rather than rounding down to 20.
+pub struct OutputThrottle {
+    interval: Interval,
+    chars_per_second: u64,
+    ticks_per_second: u64,
+    // 上一拍未用完的额度, 单位为 1/ticks_per_second 个字符
+    carry: u64,
+}
+
+impl OutputThrottle {
+    /// Creates a throttle releasing at most `chars_per_second` characters per second.
+    ///
+    /// # Arguments
+    ///
+    /// * `chars_per_second` - Target output rate; values below 1 are treated as 1
+    ///
+    /// # Returns
+    ///
+    /// A new `OutputThrottle` whose first piece is released immediately
+    pub fn new(chars_per_second: u32) -> Self {
+        let chars_per_second = u64::from(chars_per_second.max(1));
+        let ticks_per_second = chars_per_second.min(MAX_TICKS_PER_SECOND);
+        let mut interval = tokio::time::interval(Duration::from_nanos(1_000_000_000 / ticks_per_second));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            interval,
+            chars_per_second,
+            ticks_per_second,
+            carry: 0,
+        }
+    }
+
+    /// Returns how many characters the next tick may release, carrying the remainder.
+    fn next_piece_len(&mut self) -> usize {
+        let budget = self.carry + self.chars_per_second;
+        self.carry = budget % self.ticks_per_second;
+        (budget / self.ticks_per_second) as usize
+    }
+
+    /// Sends `content` in paced pieces, waiting for the next tick before each one.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - Sink feeding the client's SSE stream
+    /// * `content` - The text to send
+    /// * `make_event` - Builds the SSE event carrying one piece of text
+    ///
+    /// # Returns
+    ///
+    /// `false` if the consumer disconnected before all pieces were sent
+    pub async fn send<F>(&mut self, sink: &EventSink, content: &str, make_event: F) -> bool
+    where
+        F: Fn(&str) -> Event,
+    {
+        let chars: Vec<char> = content.chars().collect();
+        let mut rest = chars.as_slice();
+        while !rest.is_empty() {
+            self.interval.tick().await;
+            let (piece, tail) = rest.split_at(self.next_piece_len().min(rest.len()));
+            rest = tail;
+            let piece: String = piece.iter().collect();
+            if !sink.send(make_event(&piece)).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Sends `content` to the client, pacing it through `throttle` when one is set.
+///
+/// Without a throttle the content is sent as a single event, so unthrottled
+/// streams pay no extra cost. Returns `false` once the consumer has disconnected.
+pub async fn send_paced<F>(
+    sink: &EventSink,
+    throttle: &mut Option<OutputThrottle>,
+    content: &str,
+    make_event: F,
+) -> bool
+where
+    F: Fn(&str) -> Event,
+{
+    match throttle {
+        Some(throttle) => throttle.send(sink, content, make_event).await,
+        None => sink.send(make_event(content)).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::StreamOverflowPolicy, metrics::Metrics};
+    use std::sync::Arc;
+    use tokio::{sync::mpsc, time::Instant};
+
+    /// Sends `chars` characters through a throttle and returns the virtual
+    /// time taken and the number of events the client received.
+    async fn pace(chars_per_second: u32, chars: usize) -> (Duration, usize) {
+        let (tx, mut rx) = mpsc::channel(1024);
+        let sink = EventSink::new(tx, StreamOverflowPolicy::Block, Arc::new(Metrics::default()));
+        let mut throttle = OutputThrottle::new(chars_per_second);
+        let start = Instant::now();
+        assert!(throttle.send(&sink, &"x".repeat(chars), |piece| Event::default().data(piece)).await);
+        let elapsed = start.elapsed();
+        drop(sink);
+
+        let mut events = 0;
+        while rx.recv().await.is_some() {
+            events += 1;
+        }
+        (elapsed, events)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_below_the_tick_cap_send_one_character_per_tick() {
+        // 首个字符立即发送, 其余 9 个各等待 200ms
+        assert_eq!(pace(5, 10).await, (Duration::from_millis(1800), 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_that_are_not_multiples_of_the_tick_rate_are_met() {
+        // 25 字符/秒: 每 20 拍释放 25 个字符, 100 个字符需要 80 拍
+        assert_eq!(pace(25, 100).await, (Duration::from_millis(79 * 50), 80));
+        // 39 字符/秒: 每 20 拍释放 39 个字符, 390 个字符需要 200 拍
+        assert_eq!(pace(39, 390).await, (Duration::from_millis(199 * 50), 200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn piece_lengths_carry_the_remainder() {
+        let mut throttle = OutputThrottle::new(25);
+        let pieces: Vec<usize> = (0..8).map(|_| throttle.next_piece_len()).collect();
+        assert_eq!(pieces, vec![1, 1, 1, 2, 1, 1, 1, 2]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Per-phase latency measurement.
+//!
+//! Handlers wrap each upstream phase in a [`PhaseTimer`] and combine them
+//! into the [`Timings`] reported to clients, so a slow request can be
+//! attributed to queueing, the reasoner or the target without reading logs.
+
+use crate::{connection, models::Timings};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Measures one phase: its start, first token and end.
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseTimer {
+    started: Instant,
+    first_token: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl PhaseTimer {
+    /// Starts timing a phase now.
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            first_token: None,
+            total: None,
+        }
+    }
+
+    /// Records the arrival of the first token; later calls are ignored.
+    pub fn first_token(&mut self) {
+        if self.first_token.is_none() {
+            self.first_token = Some(self.started.elapsed());
+        }
+    }
+
+    /// Returns true once the phase has produced its first token.
+    pub fn has_output(&self) -> bool {
+        self.first_token.is_some()
+    }
+
+    /// Stops the timer; later calls are ignored.
+    pub fn finish(&mut self) {
+        if self.total.is_none() {
+            self.total = Some(self.started.elapsed());
+        }
+    }
+
+    /// Returns the phase duration, up to now if it has not finished.
+    fn total(&self) -> Duration {
+        self.total.unwrap_or_else(|| self.started.elapsed())
+    }
+}
+
+/// Combines the phase timers of one request into reported timings.
+///
+/// # Arguments
+///
+/// * `received` - When the handler received the request
+/// * `reasoner` - The reasoner phase; its start ends the queueing time
+/// * `target` - The target phase
+/// * `target_warm_up` - How long the target connection warm-up took, if one ran
+///
+/// # Returns
+///
+/// * `Timings` - All durations in milliseconds, with the upstream calls
+///   recorded so far in the current [`connection::scope`]
+pub fn timings(received: Instant, reasoner: &PhaseTimer, target: &PhaseTimer, target_warm_up: Option<Duration>) -> Timings {
+    Timings {
+        queue_ms: millis(reasoner.started.saturating_duration_since(received)),
+        reasoner_first_token_ms: reasoner.first_token.map(millis),
+        reasoner_total_ms: millis(reasoner.total()),
+        target_first_token_ms: target.first_token.map(millis),
+        target_total_ms: millis(target.total()),
+        target_warm_up_ms: target_warm_up.map(millis),
+        connections: connection::calls(),
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn phases_are_measured_from_the_request() {
+        let received = Instant::now();
+        advance(Duration::from_millis(5)).await;
+        let mut reasoner = PhaseTimer::start();
+        advance(Duration::from_millis(20)).await;
+        reasoner.first_token();
+        advance(Duration::from_millis(30)).await;
+        reasoner.first_token();
+        reasoner.finish();
+        let mut target = PhaseTimer::start();
+        advance(Duration::from_millis(40)).await;
+        target.finish();
+        advance(Duration::from_millis(100)).await;
+        target.finish();
+
+        assert_eq!(
+            timings(received, &reasoner, &target, Some(Duration::from_millis(12))),
+            Timings {
+                queue_ms: 5,
+                reasoner_first_token_ms: Some(20),
+                reasoner_total_ms: 50,
+                target_first_token_ms: None,
+                target_total_ms: 40,
+                target_warm_up_ms: Some(12),
+                connections: Vec::new(),
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unfinished_phases_run_until_now() {
+        let received = Instant::now();
+        let reasoner = PhaseTimer::start();
+        let target = PhaseTimer::start();
+        advance(Duration::from_millis(7)).await;
+        let timings = timings(received, &reasoner, &target, None);
+        assert_eq!((timings.reasoner_total_ms, timings.target_total_ms), (7, 7));
+    }
+}
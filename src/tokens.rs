@@ -0,0 +1,191 @@
+//! Prompt token counting.
+//!
+//! OpenAI-family models known to tiktoken are counted with their own BPE
+//! (`o200k_base`, `cl100k_base`, ...) and the message framing OpenAI
+//! documents. Other models are estimated: text outside CJK scripts is
+//! counted at roughly four characters per token, CJK characters one token
+//! each, plus the per-message framing chat models add around every turn.
+//! Anthropic targets can be counted exactly by the Anthropic client's
+//! `count_tokens` call, which the handler makes when
+//! `compat.anthropic_token_counting` is set.
+
+use crate::models::{Message, Role};
+use serde::Serialize;
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, p50k_edit_singleton, r50k_base_singleton,
+    tokenizer::{get_tokenizer, Tokenizer},
+    CoreBPE,
+};
+
+/// Tokens added around each message for its role and delimiters.
+const MESSAGE_OVERHEAD: u32 = 4;
+
+/// Tokens that prime the assistant's reply after the last message.
+const REPLY_PRIMING: u32 = 3;
+
+/// Tokens OpenAI's chat format adds around each message besides its role.
+const TIKTOKEN_MESSAGE_OVERHEAD: u32 = 3;
+
+/// How a token count was produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Counting {
+    /// Counted with the model's tiktoken BPE.
+    Tiktoken,
+    /// Counted by Anthropic's `count_tokens` endpoint.
+    #[cfg_attr(not(feature = "anthropic"), allow(dead_code))]
+    Anthropic,
+    /// Estimated from the characters of the text.
+    Estimate,
+}
+
+/// Counts the prompt tokens of a conversation sent to `model`.
+///
+/// # Arguments
+///
+/// * `model` - The upstream model name, which selects the tokenizer
+/// * `messages` - The messages as sent upstream, system prompt included
+///
+/// # Returns
+///
+/// * `(u32, Counting)` - The prompt tokens, including per-message framing,
+///   and whether they were counted with tiktoken or estimated
+pub fn count_messages(model: &str, messages: &[Message]) -> (u32, Counting) {
+    let Some(tokenizer) = get_tokenizer(model) else {
+        return (estimate_messages(messages), Counting::Estimate);
+    };
+    let bpe = match tokenizer {
+        Tokenizer::O200kBase => o200k_base_singleton(),
+        Tokenizer::Cl100kBase => cl100k_base_singleton(),
+        Tokenizer::P50kBase => p50k_base_singleton(),
+        Tokenizer::P50kEdit => p50k_edit_singleton(),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => r50k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    let count = messages
+        .iter()
+        .map(|message| {
+            let tool_calls: u32 = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| bpe_count(&bpe, &call.to_text()))
+                .sum();
+            TIKTOKEN_MESSAGE_OVERHEAD
+                + bpe_count(&bpe, role_name(&message.role))
+                + bpe_count(&bpe, &message.content.to_text())
+                + tool_calls
+        })
+        .sum::<u32>()
+        + REPLY_PRIMING;
+    (count, Counting::Tiktoken)
+}
+
+fn bpe_count(bpe: &CoreBPE, text: &str) -> u32 {
+    bpe.encode_ordinary(text).len() as u32
+}
+
+fn role_name(role: &Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::Developer => "developer",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+    }
+}
+
+/// Estimates the tokens in a piece of text.
+pub fn estimate_text(text: &str) -> u32 {
+    let mut cjk = 0u32;
+    let mut other = 0u32;
+    for c in text.chars() {
+        if is_cjk(c) {
+            cjk += 1;
+        } else {
+            other += 1;
+        }
+    }
+    cjk + other.div_ceil(4)
+}
+
+/// Estimates the prompt tokens of a conversation.
+///
+/// # Arguments
+///
+/// * `messages` - The messages as sent upstream, system prompt included
+///
+/// # Returns
+///
+/// * `u32` - The estimated prompt tokens, including per-message framing
+pub fn estimate_messages(messages: &[Message]) -> u32 {
+    messages
+        .iter()
+        .map(|message| {
+            let tool_calls: u32 = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| estimate_text(&call.to_text()))
+                .sum();
+            MESSAGE_OVERHEAD + estimate_text(&message.content.to_text()) + tool_calls
+        })
+        .sum::<u32>()
+        + REPLY_PRIMING
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ff00}'..='\u{ffef}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<Message> {
+        serde_json::from_value(serde_json::json!([
+            {"role": "system", "content": "You are a helpful assistant."},
+            {"role": "user", "content": "tiktoken is great!"},
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn openai_models_are_counted_with_their_bpe() {
+        // "tiktoken is great!" 在 cl100k_base 和 o200k_base 中都是 6 个 token,
+        // "You are a helpful assistant." 是 6 个, 角色名各 1 个
+        assert_eq!(count_messages("gpt-4o", &conversation()), (3 + 1 + 6 + 3 + 1 + 6 + 3, Counting::Tiktoken));
+        assert_eq!(count_messages("gpt-4-0613", &conversation()), (23, Counting::Tiktoken));
+    }
+
+    #[test]
+    fn counts_match_tiktoken_rs() {
+        let messages: Vec<tiktoken_rs::ChatCompletionRequestMessage> = conversation()
+            .iter()
+            .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+                role: role_name(&message.role).to_string(),
+                content: Some(message.content.to_text()),
+                ..Default::default()
+            })
+            .collect();
+        let expected = tiktoken_rs::num_tokens_from_messages("gpt-4o", &messages).unwrap() as u32;
+        assert_eq!(count_messages("gpt-4o", &conversation()).0, expected);
+    }
+
+    #[test]
+    fn other_models_are_estimated() {
+        assert_eq!(estimate_text("abcdefgh"), 2);
+        assert_eq!(estimate_text("你好"), 2);
+        let (count, counting) = count_messages("deepseek-reasoner", &conversation());
+        assert_eq!(counting, Counting::Estimate);
+        assert_eq!(count, estimate_messages(&conversation()));
+        // 7 + 5 个字符估算 + 每条消息 4 个 + 回复 3 个
+        assert_eq!(count, 7 + 5 + 4 * 2 + 3);
+    }
+}
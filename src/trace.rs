@@ -0,0 +1,318 @@
+//! Replayable traces of single requests.
+//!
+//! Debugging a bad answer means piecing together what the client sent, which
+//! mapping and parameters the proxy resolved, and what each upstream was
+//! sent and returned. A request with `trace: true` gets a [`RequestTrace`]
+//! holding all of it: the inbound body, the resolved mapping, the merged
+//! per-provider parameters, the exact body of every upstream call with its
+//! response or stream frames, and the request's warnings, timings and usage.
+//! Content is redacted under `logging.log_content` like the logs are.
+//!
+//! The clients report their calls to the [`TraceRecorder`] of the request
+//! running inside [`scope`], much like connection timings. Non-streaming
+//! responses return the trace in a `trace` field; every trace is also kept
+//! in a [`TraceStore`] of `server.trace_store_size` entries, read through
+//! `GET /admin/traces/{id}` with the request's `X-DeepThink-Request-Id`.
+
+use crate::{
+    config::ModelMapping,
+    models::{ApiRequest, Timings, UsageStats},
+    redact,
+    warnings::Warning,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+/// Request header carrying `server.admin_token`, on the admin routes and on
+/// chat requests that ask for a trace.
+pub const ADMIN_TOKEN_HEADER: &str = "X-DeepThink-Admin-Token";
+
+/// Version of the trace schema, raised on incompatible changes.
+const TRACE_VERSION: u32 = 1;
+
+tokio::task_local! {
+    static CURRENT: Option<TraceRecorder>;
+}
+
+/// The trace of one request.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestTrace {
+    pub version: u32,
+    /// Id of the request, as in `X-DeepThink-Request-Id`.
+    pub request_id: String,
+    pub received_at: DateTime<Utc>,
+    /// The inbound request body.
+    pub request: serde_json::Value,
+    /// The model mapping the request resolved to, for the OpenAI-compatible routes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<serde_json::Value>,
+    /// Each provider's body parameters after parameter policy and mapping defaults.
+    pub parameters: TraceParameters,
+    /// Every upstream call, in the order they were sent.
+    pub upstream_calls: Vec<UpstreamCall>,
+    pub warnings: Vec<Warning>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageStats>,
+    /// Set once the response is complete; a running stream's trace is partial.
+    pub complete: bool,
+}
+
+/// Body parameters of each provider's requests.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TraceParameters {
+    pub deepseek: serde_json::Value,
+    pub openai: serde_json::Value,
+    pub anthropic: serde_json::Value,
+}
+
+/// One call to an upstream provider.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamCall {
+    /// `reasoner` for the reasoner, `target` for the answering model.
+    pub role: &'static str,
+    pub provider: String,
+    pub url: String,
+    /// The exact request body sent.
+    pub request: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// The response body of a non-streaming call or a failed stream.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<serde_json::Value>,
+    /// The data frames of a streaming call, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stream: Vec<serde_json::Value>,
+}
+
+/// Collects the trace of a request while it runs.
+///
+/// Clones share the same trace, so the one in the [`TraceStore`] sees what
+/// the request's task records.
+#[derive(Debug, Clone)]
+pub struct TraceRecorder(Arc<Mutex<RequestTrace>>);
+
+impl TraceRecorder {
+    /// Starts the trace of request `request_id` with its inbound body.
+    pub fn new(request_id: String, received_at: DateTime<Utc>, inbound: &serde_json::Value) -> Self {
+        Self(Arc::new(Mutex::new(RequestTrace {
+            version: TRACE_VERSION,
+            request_id,
+            received_at,
+            request: redacted(inbound.clone()),
+            mapping: None,
+            parameters: TraceParameters::default(),
+            upstream_calls: Vec::new(),
+            warnings: Vec::new(),
+            timings: None,
+            usage: None,
+            complete: false,
+        })))
+    }
+
+    /// Returns the id of the traced request.
+    pub fn request_id(&self) -> String {
+        self.lock().request_id.clone()
+    }
+
+    /// Records the resolved mapping and the parameters the request will be sent with.
+    pub fn set_request(&self, mapping: Option<&ModelMapping>, request: &ApiRequest) {
+        let mut trace = self.lock();
+        trace.mapping = mapping.and_then(|mapping| serde_json::to_value(mapping).ok());
+        trace.parameters = TraceParameters {
+            deepseek: request.deepseek_config.body.clone(),
+            openai: request.openai_config.body.clone(),
+            anthropic: request.anthropic_config.body.clone(),
+        };
+    }
+
+    /// Records the outcome of the request and marks the trace complete.
+    pub fn finish(&self, warnings: Vec<Warning>, timings: Option<Timings>, usage: &UsageStats) {
+        let mut trace = self.lock();
+        trace.warnings = warnings;
+        trace.timings = timings;
+        trace.usage = Some(usage.clone());
+        trace.complete = true;
+    }
+
+    /// Returns the trace as recorded so far.
+    pub fn snapshot(&self) -> RequestTrace {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RequestTrace> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs `future` with `recorder` receiving the upstream calls made by the clients.
+pub fn scope<F: Future>(recorder: Option<TraceRecorder>, future: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(recorder, future)
+}
+
+/// Records a call about to be sent, returning its handle for [`record_response`] and [`record_frame`].
+///
+/// Returns `None` when the request running in the current task is not traced.
+pub fn record_request(provider: &str, url: &str, body: &[u8]) -> Option<usize> {
+    with_current(|trace| {
+        trace.upstream_calls.push(UpstreamCall {
+            role: if provider == "deepseek" { "reasoner" } else { "target" },
+            provider: provider.to_string(),
+            url: url.to_string(),
+            request: redacted(serde_json::from_slice(body).unwrap_or_default()),
+            status: None,
+            response: None,
+            stream: Vec::new(),
+        });
+        trace.upstream_calls.len() - 1
+    })
+}
+
+/// Records the status and body of the response to `call`.
+pub fn record_response(call: Option<usize>, status: u16, body: &str) {
+    with_call(call, |call| {
+        call.status = Some(status);
+        call.response = Some(parsed(body));
+    });
+}
+
+/// Records the status of a streaming response to `call`.
+pub fn record_status(call: Option<usize>, status: u16) {
+    with_call(call, |call| call.status = Some(status));
+}
+
+/// Records one data frame of the streaming response to `call`.
+pub fn record_frame(call: Option<usize>, data: &str) {
+    with_call(call, |call| call.stream.push(parsed(data)));
+}
+
+/// Records the outcome of the request running in the current task, if it is traced.
+pub fn finish(warnings: Vec<Warning>, timings: Option<Timings>, usage: &UsageStats) {
+    let _ = CURRENT.try_with(|recorder| {
+        if let Some(recorder) = recorder {
+            recorder.finish(warnings, timings, usage);
+        }
+    });
+}
+
+fn with_current<T>(f: impl FnOnce(&mut RequestTrace) -> T) -> Option<T> {
+    CURRENT
+        .try_with(|recorder| recorder.as_ref().map(|recorder| f(&mut recorder.lock())))
+        .ok()
+        .flatten()
+}
+
+fn with_call(call: Option<usize>, f: impl FnOnce(&mut UpstreamCall)) {
+    if let Some(index) = call {
+        with_current(|trace| trace.upstream_calls.get_mut(index).map(f));
+    }
+}
+
+/// Parses a payload as JSON, keeping one that is not as a string, and redacts it.
+fn parsed(payload: &str) -> serde_json::Value {
+    redacted(serde_json::from_str(payload).unwrap_or_else(|_| serde_json::json!(payload)))
+}
+
+fn redacted(mut value: serde_json::Value) -> serde_json::Value {
+    redact::value(&mut value);
+    value
+}
+
+/// Bounded store of the latest traces.
+#[derive(Debug)]
+pub struct TraceStore {
+    traces: Mutex<VecDeque<TraceRecorder>>,
+    capacity: usize,
+}
+
+impl TraceStore {
+    /// Creates a store keeping the latest `capacity` traces; 0 keeps none.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            traces: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Adds a trace, evicting the oldest once the store is full.
+    pub fn insert(&self, recorder: TraceRecorder) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut traces = self.lock();
+        if traces.len() == self.capacity {
+            traces.pop_front();
+        }
+        traces.push_back(recorder);
+    }
+
+    /// Returns the trace of request `id`, if it is still in the store.
+    pub fn get(&self, id: &str) -> Option<RequestTrace> {
+        self.lock()
+            .iter()
+            .find(|recorder| recorder.request_id() == id)
+            .map(TraceRecorder::snapshot)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<TraceRecorder>> {
+        self.traces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recorder(id: &str) -> TraceRecorder {
+        TraceRecorder::new(id.to_string(), Utc::now(), &json!({"model": "deepthink"}))
+    }
+
+    #[tokio::test]
+    async fn calls_are_recorded_only_inside_a_traced_scope() {
+        assert_eq!(record_request("deepseek", "http://reasoner", b"{}"), None);
+
+        let trace = recorder("traced");
+        scope(Some(trace.clone()), async {
+            let reasoner = record_request("deepseek", "http://reasoner", br#"{"stream":true}"#);
+            record_status(reasoner, 200);
+            record_frame(reasoner, r#"{"delta":"a"}"#);
+            record_frame(reasoner, "[DONE]");
+            let target = record_request("openai", "http://target", br#"{"model":"gpt-4o"}"#);
+            record_response(target, 200, r#"{"id":"chatcmpl-1"}"#);
+            finish(Vec::new(), None, &UsageStats::default());
+        })
+        .await;
+        scope(None, async { assert_eq!(record_request("openai", "http://target", b"{}"), None) }).await;
+
+        let trace = trace.snapshot();
+        assert!(trace.complete);
+        let calls = serde_json::to_value(&trace.upstream_calls).unwrap();
+        assert_eq!(calls[0]["role"], "reasoner");
+        assert_eq!(calls[0]["stream"], json!([{"delta": "a"}, "[DONE]"]));
+        assert_eq!(calls[1]["role"], "target");
+        assert_eq!(calls[1]["response"]["id"], "chatcmpl-1");
+        assert!(calls[1].get("stream").is_none());
+    }
+
+    #[test]
+    fn the_store_keeps_the_latest_traces() {
+        let store = TraceStore::new(2);
+        for id in ["a", "b", "c"] {
+            store.insert(recorder(id));
+        }
+        assert!(store.get("a").is_none());
+        assert_eq!(store.get("c").unwrap().request_id, "c");
+        assert!(!store.get("b").unwrap().complete);
+
+        let disabled = TraceStore::new(0);
+        disabled.insert(recorder("a"));
+        assert!(disabled.get("a").is_none());
+    }
+}
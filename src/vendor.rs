@@ -0,0 +1,189 @@
+//! The `deepthink` vendor namespace of compat requests.
+//!
+//! OpenAI SDKs and LiteLLM pass provider-specific options through
+//! `extra_body`, which clients either merge into the request body or send
+//! as an `extra_body` object. DeepThink reads its own options from a
+//! `deepthink` object in either place, e.g.
+//! `{"extra_body": {"deepthink": {"skip_reasoning": true}}}`; when both are
+//! present, keys in `extra_body.deepthink` win. The namespace is never sent
+//! upstream. Unknown keys inside it are ignored and reported in a warning
+//! header, so a typo does not fail requests that other providers would accept.
+
+use crate::{
+    error::{ApiError, Result},
+    models::ThinkingFormat,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Name of the vendor namespace object.
+pub const NAMESPACE: &str = "deepthink";
+
+/// Response header listing the ignored keys of the vendor namespace.
+pub const VENDOR_WARNING_HEADER: &str = "X-DeepThink-Vendor-Warning";
+
+/// Keys understood inside the vendor namespace.
+const KNOWN_KEYS: &[&str] = &[
+    "skip_reasoning",
+    "include_reasoning",
+    "reasoner_model",
+    "reasoning_effort",
+    "thinking_format",
+    "metadata",
+    "no_cache",
+    "trace",
+    "progress_callback_url",
+];
+
+/// Options read from the vendor namespace. Each one overrides the top-level
+/// request field of the same name.
+#[derive(Debug, Default, Deserialize)]
+pub struct VendorOptions {
+    /// Answer with the target alone, without a reasoner call.
+    #[serde(default)]
+    pub skip_reasoning: bool,
+    pub include_reasoning: Option<bool>,
+    pub reasoner_model: Option<String>,
+    /// Sent to the reasoner as its `reasoning_effort` body parameter.
+    pub reasoning_effort: Option<String>,
+    pub thinking_format: Option<ThinkingFormat>,
+    pub metadata: Option<HashMap<String, String>>,
+    /// Bypass the idempotency response cache.
+    #[serde(default)]
+    pub no_cache: bool,
+    /// Assemble a trace of the request, when `server.request_traces` allows it.
+    #[serde(default)]
+    pub trace: bool,
+    /// Post progress updates of a non-streaming request to this URL.
+    pub progress_callback_url: Option<String>,
+}
+
+impl VendorOptions {
+    /// Returns the request's extra fields plus the options that end up as body
+    /// parameters, so `parameter_policy` treats them as supplied by the caller.
+    pub fn supplied_fields(&self, extra: &serde_json::Value) -> serde_json::Value {
+        let mut fields = extra.clone();
+        if let (Some(effort), Some(object)) = (&self.reasoning_effort, fields.as_object_mut()) {
+            object.insert("reasoning_effort".to_string(), serde_json::json!(effort));
+        }
+        fields
+    }
+}
+
+/// Reads the vendor namespace from a compat request's extra fields.
+///
+/// # Arguments
+///
+/// * `extra` - The request's fields other than `model`, `messages` and `stream`
+///
+/// # Returns
+///
+/// * `Result<(VendorOptions, Option<String>)>` - The options and a warning
+///   listing the ignored keys, if any
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the namespace is not an object or a
+/// known key has a value of the wrong type.
+pub fn parse(extra: &serde_json::Value) -> Result<(VendorOptions, Option<String>)> {
+    let mut options = serde_json::Map::new();
+    for (path, namespace) in [
+        (NAMESPACE.to_string(), extra.get(NAMESPACE)),
+        (format!("extra_body.{}", NAMESPACE), extra.get("extra_body").and_then(|body| body.get(NAMESPACE))),
+    ] {
+        match namespace {
+            None => {}
+            Some(serde_json::Value::Object(namespace)) => options.extend(namespace.clone()),
+            Some(other) => {
+                return Err(ApiError::BadRequest {
+                    message: format!("Invalid {}: expected an object, got {}", path, other),
+                })
+            }
+        }
+    }
+
+    let mut ignored: Vec<String> = options
+        .keys()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    options.retain(|key, _| KNOWN_KEYS.contains(&key.as_str()));
+    let options = serde_json::from_value(serde_json::Value::Object(options)).map_err(|e| ApiError::BadRequest {
+        message: format!("Invalid {} options: {}", NAMESPACE, e),
+    })?;
+
+    ignored.sort();
+    let warning = (!ignored.is_empty())
+        .then(|| format!("ignored unknown {} options: {}", NAMESPACE, ignored.join(", ")));
+    Ok((options, warning))
+}
+
+/// Removes the vendor namespace from a request body before it is forwarded
+/// as is, dropping `extra_body` if nothing else is left in it.
+pub fn strip(body: &mut serde_json::Value) {
+    let Some(body) = body.as_object_mut() else {
+        return;
+    };
+    body.remove(NAMESPACE);
+    if let Some(serde_json::Value::Object(extra_body)) = body.get_mut("extra_body") {
+        extra_body.remove(NAMESPACE);
+        if extra_body.is_empty() {
+            body.remove("extra_body");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn options_are_read_from_both_places_and_extra_body_wins() {
+        let extra = json!({
+            "deepthink": {"skip_reasoning": true, "reasoner_model": "deepseek-r1:14b"},
+            "extra_body": {"deepthink": {"reasoner_model": "deepseek-r1:32b", "no_cache": true}},
+        });
+        let (options, warning) = parse(&extra).unwrap();
+        assert!(options.skip_reasoning);
+        assert!(options.no_cache);
+        assert_eq!(options.reasoner_model.as_deref(), Some("deepseek-r1:32b"));
+        assert_eq!(warning, None);
+
+        let (options, _) = parse(&json!({})).unwrap();
+        assert!(!options.skip_reasoning && options.reasoner_model.is_none());
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored_with_a_warning() {
+        let extra = json!({"deepthink": {"skip_reasonning": true, "include_reasoning": false}, "extra_body": {"deepthink": {"color": "red"}}});
+        let (options, warning) = parse(&extra).unwrap();
+        assert!(!options.skip_reasoning);
+        assert_eq!(options.include_reasoning, Some(false));
+        assert_eq!(warning.as_deref(), Some("ignored unknown deepthink options: color, skip_reasonning"));
+    }
+
+    #[test]
+    fn malformed_namespaces_are_rejected() {
+        assert!(matches!(parse(&json!({"deepthink": true})), Err(ApiError::BadRequest { .. })));
+        assert!(matches!(parse(&json!({"extra_body": {"deepthink": []}})), Err(ApiError::BadRequest { .. })));
+        assert!(matches!(parse(&json!({"deepthink": {"no_cache": "yes"}})), Err(ApiError::BadRequest { .. })));
+    }
+
+    #[test]
+    fn strip_removes_the_namespace_and_an_emptied_extra_body() {
+        let mut body = json!({"model": "m", "deepthink": {}, "extra_body": {"deepthink": {}}});
+        strip(&mut body);
+        assert_eq!(body, json!({"model": "m"}));
+
+        let mut body = json!({"extra_body": {"deepthink": {}, "top_k": 5}});
+        strip(&mut body);
+        assert_eq!(body, json!({"extra_body": {"top_k": 5}}));
+    }
+
+    #[test]
+    fn reasoning_effort_counts_as_a_supplied_parameter() {
+        let (options, _) = parse(&json!({"deepthink": {"reasoning_effort": "high"}})).unwrap();
+        assert_eq!(options.supplied_fields(&json!({"seed": 1})), json!({"seed": 1, "reasoning_effort": "high"}));
+    }
+}
@@ -0,0 +1,39 @@
+//! Build metadata embedded at compile time by `build.rs`.
+//!
+//! The version is reported by `GET /version`, the `/health` payload, the
+//! `X-DeepThink-Version` header on every response, the startup log line and
+//! the User-Agent sent to upstream providers, so every instance can be told
+//! apart by the build it serves.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Response header carrying [`FULL_VERSION`].
+pub const VERSION_HEADER: &str = "X-DeepThink-Version";
+
+/// Crate version and git commit, as `<version>+<sha>`.
+pub const FULL_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("DEEPTHINK_GIT_SHA"));
+
+/// Body of the `/version` endpoint.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// Build time in RFC 3339, or `None` if the build clock was unavailable.
+    pub build_timestamp: Option<String>,
+}
+
+/// Returns the metadata of the running build.
+pub fn info() -> VersionInfo {
+    let build_timestamp = env!("DEEPTHINK_BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .filter(|secs| *secs > 0)
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339());
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("DEEPTHINK_GIT_SHA"),
+        build_timestamp,
+    }
+}
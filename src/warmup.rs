@@ -0,0 +1,77 @@
+//! Target connection warm-up during the reasoning phase.
+//!
+//! The target is only called once reasoning is done, so without warm-up its
+//! first token also waits for a fresh TCP and TLS handshake. With
+//! `target.warm_up` set for a provider, the pipeline sends a cheap request to
+//! the target endpoint as soon as it starts, on the connection pool the
+//! target clients share, and the real call picks up the hot connection. The
+//! warm-up belongs to its request: it is cancelled when the request ends
+//! early, and the target call waits for it to finish instead of racing it
+//! with a second handshake.
+
+use crate::config::WarmUpMethod;
+use reqwest::{Client, Method};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Longest a warm-up request may take before it is given up.
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running warm-up request, cancelled when dropped.
+#[derive(Debug)]
+pub struct WarmUp {
+    task: JoinHandle<Option<Duration>>,
+}
+
+impl WarmUp {
+    /// Starts warming up a connection to `url`.
+    ///
+    /// # Arguments
+    ///
+    /// * `http` - The connection pool the target call will use
+    /// * `method` - The configured warm-up request for the target provider
+    /// * `url` - The target endpoint
+    ///
+    /// # Returns
+    ///
+    /// * `Option<WarmUp>` - The running warm-up, or `None` if it is disabled
+    pub fn start(http: &Client, method: WarmUpMethod, url: &str) -> Option<Self> {
+        let request = match method {
+            WarmUpMethod::Off => return None,
+            WarmUpMethod::Head => http.head(url),
+            WarmUpMethod::Options => http.request(Method::OPTIONS, url),
+        };
+        let url = url.to_string();
+        let task = tokio::spawn(async move {
+            let started = Instant::now();
+            // 任何状态码都说明连接已建立; 读完响应体后连接才会回到连接池
+            let result = match request.timeout(WARM_UP_TIMEOUT).send().await {
+                Ok(response) => response.bytes().await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => Some(started.elapsed()),
+                Err(e) => {
+                    tracing::debug!("Target warm-up to {} failed: {}", url, e);
+                    None
+                }
+            }
+        });
+        Some(Self { task })
+    }
+
+    /// Waits for the warm-up so the target call can reuse its connection.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Duration>` - How long the warm-up took, or `None` if it failed
+    pub async fn finish(mut self) -> Option<Duration> {
+        (&mut self.task).await.ok().flatten()
+    }
+}
+
+impl Drop for WarmUp {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,117 @@
+//! Machine-readable warnings of a request.
+//!
+//! Each kind of problem the proxy works around used to get its own
+//! `X-DeepThink-*` response header, which clients had to know about and
+//! parse one by one. Every stage of a request now pushes a [`Warning`] with a
+//! stable `code` into the request's [`WarningCollector`]. When the response
+//! is ready the collected warnings are returned as a `warnings` array in the
+//! body, or as `warning` events at the end of a stream, and the kinds that
+//! had a header still set it from the same warning.
+
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// One thing that went differently than the request asked.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Warning {
+    /// Stable identifier of the kind of warning, e.g. `parameters_dropped`.
+    pub code: String,
+    /// Human-readable description.
+    pub message: String,
+    /// Structured details, depending on the code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<serde_json::Value>,
+    /// Response header carrying the warning for clients that predate the array.
+    #[serde(skip)]
+    pub header: Option<(&'static str, String)>,
+}
+
+impl Warning {
+    /// Creates a warning without details or header.
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.into(),
+            detail: None,
+            header: None,
+        }
+    }
+
+    /// Creates a warning also reported in response header `name`, with the same message.
+    pub fn in_header(code: &str, name: &'static str, message: String) -> Self {
+        Self::new(code, message.clone()).with_header(name, message)
+    }
+
+    /// Attaches structured details.
+    pub fn with_detail(mut self, detail: serde_json::Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Also reports the warning in response header `name`.
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.header = Some((name, value.into()));
+        self
+    }
+}
+
+/// Warnings pushed by the stages of one request.
+///
+/// Carried in [`crate::models::ApiRequest`]; clones share the same list, so
+/// the handler keeps a clone to read what the pipeline pushed.
+#[derive(Debug, Clone, Default)]
+pub struct WarningCollector(Arc<Mutex<Vec<Warning>>>);
+
+impl WarningCollector {
+    /// Adds a warning.
+    pub fn push(&self, warning: Warning) {
+        self.lock().push(warning);
+    }
+
+    /// Adds every warning of `warnings`, e.g. an `Option<Warning>`.
+    pub fn extend(&self, warnings: impl IntoIterator<Item = Warning>) {
+        self.lock().extend(warnings);
+    }
+
+    /// Returns the warnings pushed so far, in order.
+    pub fn list(&self) -> Vec<Warning> {
+        self.lock().clone()
+    }
+
+    /// Returns the response headers of the warnings pushed so far.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        self.lock().iter().filter_map(|warning| warning.header.clone()).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Warning>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stages_pushing_into_clones_share_one_list() {
+        let collector = WarningCollector::default();
+        let stage = collector.clone();
+        collector.push(Warning::new("first", "one"));
+        stage.extend(Some(Warning::in_header("second", "x-second", "two".to_string())));
+        stage.extend(None);
+        collector.push(Warning::new("third", "three").with_header("x-third", "3"));
+
+        let codes: Vec<String> = collector.list().into_iter().map(|warning| warning.code).collect();
+        assert_eq!(codes, ["first", "second", "third"]);
+        assert_eq!(collector.headers(), [("x-second", "two".to_string()), ("x-third", "3".to_string())]);
+    }
+
+    #[test]
+    fn warnings_serialize_without_their_header() {
+        let plain = serde_json::to_value(Warning::in_header("code", "x-code", "message".to_string())).unwrap();
+        assert_eq!(plain, json!({"code": "code", "message": "message"}));
+        let detailed = serde_json::to_value(Warning::new("code", "message").with_detail(json!({"n": 1}))).unwrap();
+        assert_eq!(detailed, json!({"code": "code", "message": "message", "detail": {"n": 1}}));
+    }
+}
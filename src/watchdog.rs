@@ -0,0 +1,309 @@
+//! Watchdog for streaming tasks.
+//!
+//! Every streamed request runs in a spawned task that holds its admission
+//! permit until it ends. The idle and reasoning timeouts cover upstreams that
+//! go quiet, but a connection can hang in states none of them see, such as a
+//! half-open TLS session, and then the task, the client connection and the
+//! permit would be held forever. [`StreamWatchdog`] supervises each task: past
+//! `streaming.max_stream_secs` of wall-clock time, or when an operator kills
+//! it through `DELETE /admin/streams/{id}`, the task is aborted, which drops
+//! its permit, and the client gets an error frame and `[DONE]` if it is
+//! still connected. The phase the stream was stuck in is logged and counted.
+
+use crate::{
+    error::{ApiError, SseResult, StreamError},
+    metrics::Metrics,
+    resume::StreamBuffer,
+};
+use axum::response::sse::Event;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc::Sender, Notify},
+    task::JoinHandle,
+};
+
+/// Pipeline phase of a running stream.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamPhase {
+    Reasoning,
+    Answering,
+}
+
+impl StreamPhase {
+    /// Returns the phase's name as reported in error frames.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamPhase::Reasoning => "reasoning",
+            StreamPhase::Answering => "answering",
+        }
+    }
+}
+
+/// The current phase of one stream, updated by its task.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseCell(Arc<AtomicU8>);
+
+impl PhaseCell {
+    pub fn set(&self, phase: StreamPhase) {
+        self.0.store(phase as u8, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> StreamPhase {
+        match self.0.load(Ordering::Relaxed) {
+            0 => StreamPhase::Reasoning,
+            _ => StreamPhase::Answering,
+        }
+    }
+}
+
+/// A running stream, as listed by `GET /admin/streams`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveStream {
+    pub id: String,
+    pub phase: StreamPhase,
+    /// Seconds since the stream's task started.
+    pub age_secs: u64,
+}
+
+#[derive(Debug)]
+struct WatchedStream {
+    started: Instant,
+    phase: PhaseCell,
+    kill: Arc<Notify>,
+}
+
+/// Why the watchdog ended a stream.
+enum Termination {
+    Deadline,
+    Killed,
+}
+
+/// Registry of the running stream tasks.
+#[derive(Debug)]
+pub struct StreamWatchdog {
+    streams: Arc<Mutex<HashMap<String, WatchedStream>>>,
+    max_duration: Option<Duration>,
+    metrics: Arc<Metrics>,
+}
+
+/// Where the watchdog writes the frames ending a stream it aborted.
+pub struct StreamOutput {
+    pub tx: Sender<SseResult>,
+    /// The replay buffer of a resumable stream.
+    pub resume: Option<Arc<StreamBuffer>>,
+    /// Target provider, reported for streams stuck answering.
+    pub target_provider: &'static str,
+}
+
+impl StreamWatchdog {
+    /// Creates a watchdog that aborts streams running longer than `max_duration`.
+    pub fn new(max_duration: Option<Duration>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            max_duration,
+            metrics,
+        }
+    }
+
+    /// Supervises the task of stream `id` until it ends.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The stream id, also used to kill the stream
+    /// * `phase` - The phase cell the task updates
+    /// * `task` - The stream's task
+    /// * `output` - Where to send the error frame if the task is aborted
+    pub fn watch(&self, id: String, phase: PhaseCell, mut task: JoinHandle<()>, output: StreamOutput) {
+        let kill = Arc::new(Notify::new());
+        let started = Instant::now();
+        self.lock().insert(
+            id.clone(),
+            WatchedStream {
+                started,
+                phase: phase.clone(),
+                kill: kill.clone(),
+            },
+        );
+        let streams = self.streams.clone();
+        let metrics = self.metrics.clone();
+        let max_duration = self.max_duration;
+        tokio::spawn(async move {
+            let deadline = async {
+                match max_duration {
+                    Some(max_duration) => tokio::time::sleep(max_duration).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let termination = tokio::select! {
+                _ = &mut task => None,
+                _ = deadline => Some(Termination::Deadline),
+                _ = kill.notified() => Some(Termination::Killed),
+            };
+            streams.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+            let Some(termination) = termination else {
+                return;
+            };
+
+            // 中止任务会释放它持有的并发许可
+            task.abort();
+            let phase = phase.get();
+            let elapsed = started.elapsed();
+            let error = match termination {
+                Termination::Deadline => ApiError::StreamDeadlineExceeded {
+                    max_stream_secs: max_duration.unwrap_or_default().as_secs(),
+                },
+                Termination::Killed => ApiError::StreamKilled { id: id.clone() },
+            };
+            metrics.record_aborted_stream();
+            tracing::error!(
+                stream_id = %id,
+                phase = phase.as_str(),
+                elapsed_secs = elapsed.as_secs(),
+                "Aborted stream task: {}",
+                error
+            );
+            let provider = match phase {
+                StreamPhase::Reasoning => "deepseek",
+                StreamPhase::Answering => output.target_provider,
+            };
+            // 回答阶段可能已经输出了部分内容, 按部分输出上报
+            let failure = StreamError {
+                error,
+                phase: phase.as_str(),
+                provider,
+                partial: phase == StreamPhase::Answering,
+            };
+            for event in [failure.error_event(), Event::default().data("[DONE]")] {
+                let event = match &output.resume {
+                    Some(buffer) => buffer.push(event),
+                    None => event,
+                };
+                // 客户端已断开时不再发送
+                if output.tx.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+            if let Some(buffer) = &output.resume {
+                buffer.finish();
+            }
+        });
+    }
+
+    /// Returns the running streams, oldest first.
+    pub fn active(&self) -> Vec<ActiveStream> {
+        let mut active: Vec<ActiveStream> = self
+            .lock()
+            .iter()
+            .map(|(id, stream)| ActiveStream {
+                id: id.clone(),
+                phase: stream.phase.get(),
+                age_secs: stream.started.elapsed().as_secs(),
+            })
+            .collect();
+        active.sort_by(|a, b| b.age_secs.cmp(&a.age_secs).then_with(|| a.id.cmp(&b.id)));
+        active
+    }
+
+    /// Aborts the running stream `id`.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - False if no stream with that id is running
+    pub fn kill(&self, id: &str) -> bool {
+        match self.lock().get(id) {
+            Some(stream) => {
+                stream.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, WatchedStream>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::{mpsc, Semaphore};
+
+    fn output() -> (StreamOutput, mpsc::Receiver<SseResult>) {
+        let (tx, rx) = mpsc::channel(8);
+        (StreamOutput { tx, resume: None, target_provider: "openai" }, rx)
+    }
+
+    /// Counts the frames received until every sender is dropped.
+    async fn frame_count(mut rx: mpsc::Receiver<SseResult>) -> usize {
+        let mut count = 0;
+        while rx.recv().await.is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn deadlines_abort_the_task_and_release_its_permit() {
+        let metrics = Arc::new(Metrics::default());
+        let watchdog = StreamWatchdog::new(Some(Duration::from_millis(50)), metrics.clone());
+        let permits = Arc::new(Semaphore::new(1));
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let task = tokio::spawn(async move {
+            let _permit = permit;
+            std::future::pending::<()>().await;
+        });
+        let (output, rx) = output();
+        let phase = PhaseCell::default();
+        phase.set(StreamPhase::Answering);
+        watchdog.watch("s1".to_string(), phase, task, output);
+        assert_eq!(watchdog.active()[0].phase, StreamPhase::Answering);
+
+        // 错误帧和 [DONE] 之后发送端被丢弃
+        assert_eq!(frame_count(rx).await, 2);
+        assert_eq!(permits.available_permits(), 1);
+        assert!(watchdog.active().is_empty());
+        assert_eq!(metrics.snapshot().aborted_streams, 1);
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_forgotten_without_frames() {
+        let metrics = Arc::new(Metrics::default());
+        let watchdog = StreamWatchdog::new(None, metrics.clone());
+        let (output, rx) = output();
+        watchdog.watch("s1".to_string(), PhaseCell::default(), tokio::spawn(async {}), output);
+
+        assert_eq!(frame_count(rx).await, 0);
+        assert!(watchdog.active().is_empty());
+        assert!(!watchdog.kill("s1"));
+        assert_eq!(metrics.snapshot().aborted_streams, 0);
+    }
+
+    #[tokio::test]
+    async fn killed_streams_are_aborted_and_listed_until_then() {
+        let watchdog = StreamWatchdog::new(None, Arc::new(Metrics::default()));
+        for id in ["s1", "s2"] {
+            let (output, _) = output();
+            watchdog.watch(id.to_string(), PhaseCell::default(), tokio::spawn(std::future::pending()), output);
+        }
+        let (output, rx) = output();
+        let task = tokio::spawn(std::future::pending());
+        watchdog.watch("s3".to_string(), PhaseCell::default(), task, output);
+        let ids: Vec<String> = watchdog.active().into_iter().map(|stream| stream.id).collect();
+        assert_eq!(ids, ["s1", "s2", "s3"]);
+
+        assert!(watchdog.kill("s3"));
+        assert_eq!(frame_count(rx).await, 2);
+        let ids: Vec<String> = watchdog.active().into_iter().map(|stream| stream.id).collect();
+        assert_eq!(ids, ["s1", "s2"]);
+    }
+}
@@ -0,0 +1,649 @@
+//! Usage events for external billing systems.
+//!
+//! After each chat request completes or fails, and after each stream once it
+//! ends, the proxy posts a [`UsageEvent`] to every `[usage_webhooks]`
+//! endpoint. A failed request reports the status of its error; a stream that
+//! fails after its response started reports the status of the error that
+//! aborted it, or 499 if its client went away.
+//! Events are handed to a background dispatcher, so a slow or failing
+//! receiver never holds up a request. Each delivery is retried with
+//! exponential backoff; an event that still fails is appended to an on-disk
+//! queue, which the dispatcher replays periodically and after a restart
+//! until the endpoint accepts it.
+//!
+//! Bodies are signed with an [`HmacSigner`] over the shared secret, in the
+//! `X-DeepThink-Signature` and `X-DeepThink-Timestamp` headers.
+
+use crate::{
+    config::UsageWebhookConfig,
+    models::{ApiRequest, UsageStats},
+    signing::{HmacSigner, RequestSigner},
+};
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderMap, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Header carrying the hex HMAC-SHA256 of an event.
+pub const SIGNATURE_HEADER: &str = "X-DeepThink-Signature";
+
+/// Header carrying the signed Unix timestamp of an event.
+pub const TIMESTAMP_HEADER: &str = "X-DeepThink-Timestamp";
+
+/// How often the on-disk queue is replayed.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest wait between two delivery attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Usage of one completed or failed request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageEvent {
+    /// Id of the request, as in `X-DeepThink-Request-Id`.
+    pub request_id: Option<String>,
+    /// First 16 hex digits of the SHA-256 of the caller's auth token, absent
+    /// for callers without a token mapping.
+    pub token_fingerprint: Option<String>,
+    /// The model mapping the compat client asked for.
+    pub mapping: Option<String>,
+    pub stream: bool,
+    /// HTTP status the request was answered with, or the status of the error
+    /// that aborted a stream.
+    pub status: u16,
+    pub reasoner_usage: UsageStats,
+    pub target_usage: UsageStats,
+    /// Cost of both phases at the `[budget]` prices, in USD.
+    pub cost_usd: f64,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+impl UsageEvent {
+    /// Starts the event of `request`, filled in by [`UsageEvent::completed`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request being answered
+    /// * `caller` - The caller's mapped auth token, empty for callers without one
+    /// * `started_at` - When the request was received
+    pub fn start(request: &ApiRequest, caller: &str, started_at: DateTime<Utc>) -> Self {
+        Self {
+            request_id: request.request_id.clone(),
+            token_fingerprint: (!caller.is_empty()).then(|| fingerprint(caller)),
+            mapping: request.model.clone(),
+            stream: request.stream,
+            status: StatusCode::OK.as_u16(),
+            reasoner_usage: UsageStats::default(),
+            target_usage: UsageStats::default(),
+            cost_usd: 0.0,
+            started_at,
+            completed_at: started_at,
+        }
+    }
+
+    /// Returns the event with the usage and cost of the completed request.
+    pub fn completed(
+        &self,
+        reasoner_usage: &UsageStats,
+        target_usage: &UsageStats,
+        cost_usd: f64,
+        completed_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            reasoner_usage: reasoner_usage.clone(),
+            target_usage: target_usage.clone(),
+            cost_usd,
+            completed_at,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the event of a stream aborted with `status`, with the usage recorded so far.
+    pub fn failed(&self, status: u16, completed_at: DateTime<Utc>) -> Self {
+        Self {
+            status,
+            completed_at,
+            ..self.clone()
+        }
+    }
+
+    /// Starts the event of a request relayed to the target unparsed, filled
+    /// in by [`UsageEvent::completed`] with the usage the upstream reported.
+    pub fn relayed(request_id: Option<String>, caller: &str, mapping: Option<String>, stream: bool, started_at: DateTime<Utc>) -> Self {
+        Self::rejected(request_id, caller, mapping, stream, StatusCode::OK.as_u16(), started_at, started_at)
+    }
+
+    /// Builds the event of a request answered with error `status`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - Id of the request, as in `X-DeepThink-Request-Id`
+    /// * `caller` - The caller's mapped auth token, empty for callers without one
+    /// * `mapping` - The model the request asked for, if it was read
+    /// * `stream` - Whether the request asked for a stream
+    /// * `status` - The status of the error response
+    /// * `started_at` - When the request was received
+    /// * `completed_at` - When the error was returned
+    #[allow(clippy::too_many_arguments)]
+    pub fn rejected(
+        request_id: Option<String>,
+        caller: &str,
+        mapping: Option<String>,
+        stream: bool,
+        status: u16,
+        started_at: DateTime<Utc>,
+        completed_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            request_id,
+            token_fingerprint: (!caller.is_empty()).then(|| fingerprint(caller)),
+            mapping,
+            stream,
+            status,
+            reasoner_usage: UsageStats::default(),
+            target_usage: UsageStats::default(),
+            cost_usd: 0.0,
+            started_at,
+            completed_at,
+        }
+    }
+}
+
+/// Returns the first 16 hex digits of the SHA-256 of `token`, to name a token without revealing it.
+pub fn fingerprint(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Point-in-time webhook counters, as reported by `/metrics`.
+#[derive(Debug, Default, Serialize)]
+pub struct WebhookStats {
+    /// Deliveries the endpoints accepted, replays included.
+    pub delivered: u64,
+    /// Delivery attempts that failed and were retried or queued.
+    pub failed_attempts: u64,
+    /// Deliveries an endpoint rejected with a client error, which are not retried.
+    pub rejected: u64,
+    /// Deliveries written to the on-disk queue.
+    pub queued: u64,
+    /// Queued deliveries that were later accepted.
+    pub replayed: u64,
+    /// Deliveries lost because the queue file could not be written.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    delivered: AtomicU64,
+    failed_attempts: AtomicU64,
+    rejected: AtomicU64,
+    queued: AtomicU64,
+    replayed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Handle on the usage webhook dispatcher.
+///
+/// The default handle has no endpoints and discards every event.
+#[derive(Debug, Clone, Default)]
+pub struct UsageWebhooks {
+    sender: Option<mpsc::Sender<UsageEvent>>,
+    shared: Option<Arc<Shared>>,
+}
+
+/// State shared by the handle and the dispatcher.
+#[derive(Debug)]
+struct Shared {
+    endpoints: Vec<String>,
+    queue: DiskQueue,
+    counters: Counters,
+}
+
+impl UsageWebhooks {
+    /// Checks a `[usage_webhooks]` section.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if an endpoint is not an HTTPS
+    /// URL or the secret variable is unset.
+    pub fn check(config: &UsageWebhookConfig) -> std::result::Result<(), String> {
+        if config.endpoints.is_empty() {
+            return Ok(());
+        }
+        signer(config).map(drop)
+    }
+
+    /// Starts the dispatcher of the configured endpoints.
+    ///
+    /// Must be called inside the Tokio runtime. Without endpoints, returns a
+    /// handle that discards every event.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the section cannot be used, as in [`UsageWebhooks::check`].
+    pub fn start(config: &UsageWebhookConfig, http: reqwest::Client) -> std::result::Result<Self, String> {
+        if config.endpoints.is_empty() {
+            return Ok(Self::default());
+        }
+        let shared = Arc::new(Shared {
+            endpoints: config.endpoints.clone(),
+            queue: DiskQueue::new(PathBuf::from(&config.queue_path)),
+            counters: Counters::default(),
+        });
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        let dispatcher = Dispatcher {
+            shared: shared.clone(),
+            signer: signer(config)?,
+            http,
+            timeout: Duration::from_secs(config.timeout_secs),
+            max_attempts: config.max_attempts.max(1),
+            retry_base: Duration::from_millis(config.retry_base_ms),
+        };
+        tokio::spawn(dispatcher.run(receiver));
+        Ok(Self {
+            sender: Some(sender),
+            shared: Some(shared),
+        })
+    }
+
+    /// Queues `event` for delivery to every endpoint without waiting.
+    pub fn send(&self, event: UsageEvent) {
+        let (Some(sender), Some(shared)) = (&self.sender, &self.shared) else {
+            return;
+        };
+        // 分发任务积压时直接写入磁盘队列, 稍后重放
+        if let Err(mpsc::error::TrySendError::Full(event) | mpsc::error::TrySendError::Closed(event)) = sender.try_send(event) {
+            for endpoint in &shared.endpoints {
+                shared.enqueue(endpoint, &event);
+            }
+        }
+    }
+
+    /// Returns the current value of every counter.
+    pub fn stats(&self) -> WebhookStats {
+        let Some(shared) = &self.shared else {
+            return WebhookStats::default();
+        };
+        let counters = &shared.counters;
+        WebhookStats {
+            delivered: counters.delivered.load(Ordering::Relaxed),
+            failed_attempts: counters.failed_attempts.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+            queued: counters.queued.load(Ordering::Relaxed),
+            replayed: counters.replayed.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Shared {
+    fn enqueue(&self, endpoint: &str, event: &UsageEvent) {
+        let entry = QueuedEvent {
+            endpoint: endpoint.to_string(),
+            event: event.clone(),
+        };
+        match self.queue.append(&entry) {
+            Ok(()) => self.counters.queued.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                tracing::warn!("Dropping the usage event of {:?} for {}: {}", event.request_id, endpoint, e);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+    }
+}
+
+/// Builds the signer of a section after checking its endpoints.
+fn signer(config: &UsageWebhookConfig) -> std::result::Result<HmacSigner, String> {
+    for endpoint in &config.endpoints {
+        let url = Url::parse(endpoint).map_err(|e| format!("usage_webhooks: invalid endpoint {}: {}", endpoint, e))?;
+        if url.scheme() != "https" {
+            return Err(format!("usage_webhooks: endpoint {} is not an HTTPS URL", endpoint));
+        }
+    }
+    let secret = std::env::var(&config.secret_env)
+        .map_err(|_| format!("usage_webhooks: the secret variable {} is not set", config.secret_env))?;
+    HmacSigner::new(secret, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0).map_err(|e| format!("usage_webhooks: {}", e))
+}
+
+/// Outcome of one delivery attempt.
+enum Delivery {
+    Accepted,
+    /// The endpoint answered with a client error; retrying will not help.
+    Rejected(StatusCode),
+    Failed(String),
+}
+
+/// Background task delivering the events.
+struct Dispatcher {
+    shared: Arc<Shared>,
+    signer: HmacSigner,
+    http: reqwest::Client,
+    timeout: Duration,
+    max_attempts: u32,
+    retry_base: Duration,
+}
+
+impl Dispatcher {
+    async fn run(self, mut receiver: mpsc::Receiver<UsageEvent>) {
+        // 首次 tick 立即触发, 启动时先重放上次运行留下的队列
+        let mut replay = tokio::time::interval(REPLAY_INTERVAL);
+        replay.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Some(event) => self.dispatch(&event).await,
+                    None => break,
+                },
+                _ = replay.tick() => self.replay().await,
+            }
+        }
+    }
+
+    /// Delivers a fresh event to every endpoint, queueing it for those that keep failing.
+    async fn dispatch(&self, event: &UsageEvent) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!("Cannot serialize the usage event of {:?}: {}", event.request_id, e);
+                return;
+            }
+        };
+        for endpoint in &self.shared.endpoints {
+            let mut attempt = 0;
+            loop {
+                match self.deliver(endpoint, &body).await {
+                    Delivery::Accepted | Delivery::Rejected(_) => break,
+                    Delivery::Failed(_) if attempt + 1 < self.max_attempts => {
+                        let delay = self.retry_base.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY_DELAY);
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Delivery::Failed(reason) => {
+                        tracing::warn!("Queueing the usage event of {:?} for {}: {}", event.request_id, endpoint, reason);
+                        self.shared.enqueue(endpoint, event);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the queued events again, once each, keeping the ones still failing.
+    async fn replay(&self) {
+        let entries = match self.shared.queue.read() {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Cannot read the usage event queue: {}", e);
+                return;
+            }
+        };
+        if entries.is_empty() {
+            return;
+        }
+        let mut done = HashSet::new();
+        // 某个端点失败后, 本轮不再向它重放, 避免对不可用的端点逐条超时
+        let mut unavailable = HashSet::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let Some(entry) = entry else {
+                // 无法解析的行直接丢弃
+                done.insert(index);
+                continue;
+            };
+            if unavailable.contains(&entry.endpoint) {
+                continue;
+            }
+            let Ok(body) = serde_json::to_vec(&entry.event) else {
+                done.insert(index);
+                continue;
+            };
+            match self.deliver(&entry.endpoint, &body).await {
+                Delivery::Accepted => {
+                    self.shared.counters.replayed.fetch_add(1, Ordering::Relaxed);
+                    done.insert(index);
+                }
+                Delivery::Rejected(_) => {
+                    done.insert(index);
+                }
+                Delivery::Failed(_) => {
+                    unavailable.insert(entry.endpoint.clone());
+                }
+            }
+        }
+        if let Err(e) = self.shared.queue.remove(entries.len(), &done) {
+            tracing::warn!("Cannot rewrite the usage event queue: {}", e);
+        }
+    }
+
+    async fn deliver(&self, endpoint: &str, body: &[u8]) -> Delivery {
+        let counters = &self.shared.counters;
+        let headers = match self.signer.sign(&Method::POST, endpoint, &HeaderMap::new(), body) {
+            Ok(headers) => headers,
+            Err(e) => return Delivery::Failed(e.to_string()),
+        };
+        let mut request = self
+            .http
+            .post(endpoint)
+            .timeout(self.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let outcome = match request.send().await {
+            Ok(response) if response.status().is_success() => Delivery::Accepted,
+            // 超时和限流之外的客户端错误说明事件本身被拒绝, 重试无益
+            Ok(response)
+                if response.status().is_client_error()
+                    && !matches!(response.status(), StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) =>
+            {
+                Delivery::Rejected(response.status())
+            }
+            Ok(response) => Delivery::Failed(format!("status {}", response.status())),
+            Err(e) => Delivery::Failed(e.to_string()),
+        };
+        match &outcome {
+            Delivery::Accepted => counters.delivered.fetch_add(1, Ordering::Relaxed),
+            Delivery::Rejected(status) => {
+                tracing::warn!("Usage webhook {} rejected an event with status {}", endpoint, status);
+                counters.rejected.fetch_add(1, Ordering::Relaxed)
+            }
+            Delivery::Failed(_) => counters.failed_attempts.fetch_add(1, Ordering::Relaxed),
+        };
+        outcome
+    }
+}
+
+/// One undelivered event in the on-disk queue.
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedEvent {
+    endpoint: String,
+    event: UsageEvent,
+}
+
+/// Append-only JSON lines file of undelivered events.
+///
+/// Request handlers only append; the dispatcher alone removes the entries
+/// it replayed, which are always at the start of the file.
+#[derive(Debug)]
+struct DiskQueue {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl DiskQueue {
+    fn new(path: PathBuf) -> Self {
+        Self { path, lock: Mutex::new(()) }
+    }
+
+    fn append(&self, entry: &QueuedEvent) -> std::io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let _guard = self.lock();
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)
+    }
+
+    /// Returns every entry, `None` for lines that do not parse.
+    fn read(&self) -> std::io::Result<Vec<Option<QueuedEvent>>> {
+        let _guard = self.lock();
+        Ok(self.lines()?.iter().map(|line| serde_json::from_str(line).ok()).collect())
+    }
+
+    /// Removes the entries at `done` among the first `read` lines.
+    fn remove(&self, read: usize, done: &HashSet<usize>) -> std::io::Result<()> {
+        if done.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.lock();
+        let kept: Vec<String> = self
+            .lines()?
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| *index >= read || !done.contains(index))
+            .map(|(_, line)| line)
+            .collect();
+        // 先写临时文件再替换, 中途退出不会丢失队列
+        let temporary = self.path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temporary)?;
+        for line in &kept {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)
+    }
+
+    fn lines(&self) -> std::io::Result<Vec<String>> {
+        match std::fs::File::open(&self.path) {
+            Ok(file) => std::io::BufReader::new(file)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    const SECRET: &str = "billing-secret";
+
+    /// Builds a dispatcher for the plain HTTP mock receiver, which
+    /// [`UsageWebhooks::start`] would refuse.
+    fn dispatcher(endpoint: String, queue: PathBuf) -> Dispatcher {
+        Dispatcher {
+            shared: Arc::new(Shared {
+                endpoints: vec![endpoint],
+                queue: DiskQueue::new(queue),
+                counters: Counters::default(),
+            }),
+            signer: HmacSigner::new(SECRET, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0).unwrap(),
+            http: reqwest::Client::new(),
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            retry_base: Duration::from_millis(1),
+        }
+    }
+
+    fn queue_path() -> PathBuf {
+        std::env::temp_dir().join(format!("deepthink-webhooks-{}.jsonl", uuid::Uuid::new_v4()))
+    }
+
+    fn event() -> UsageEvent {
+        let now = Utc::now();
+        UsageEvent::rejected(Some("req-1".to_string()), "sk-test", Some("deepthink".to_string()), false, 200, now, now)
+    }
+
+    #[tokio::test]
+    async fn events_are_signed_and_retried_after_server_errors() {
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)).up_to_n_times(1).with_priority(1).mount(&receiver).await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let queue = queue_path();
+        let dispatcher = dispatcher(format!("{}/usage", receiver.uri()), queue.clone());
+
+        dispatcher.dispatch(&event()).await;
+
+        let requests = receiver.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let delivered = &requests[1];
+        let timestamp: i64 = delivered.headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        let expected = HmacSigner::new(SECRET, SIGNATURE_HEADER, TIMESTAMP_HEADER, 0)
+            .unwrap()
+            .signature(timestamp, &Method::POST, &format!("{}/usage", receiver.uri()), &delivered.body)
+            .unwrap();
+        assert_eq!(delivered.headers[SIGNATURE_HEADER], expected.as_str());
+        let body: UsageEvent = serde_json::from_slice(&delivered.body).unwrap();
+        assert_eq!(body.request_id.as_deref(), Some("req-1"));
+        let counters = &dispatcher.shared.counters;
+        assert_eq!(counters.failed_attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.delivered.load(Ordering::Relaxed), 1);
+        assert!(!queue.exists());
+    }
+
+    #[tokio::test]
+    async fn queued_events_drain_once_the_receiver_recovers() {
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(503)).mount(&receiver).await;
+        let queue = queue_path();
+        let dispatcher = dispatcher(format!("{}/usage", receiver.uri()), queue.clone());
+
+        dispatcher.dispatch(&event()).await;
+        assert_eq!(receiver.received_requests().await.unwrap().len(), 3);
+        assert_eq!(dispatcher.shared.counters.queued.load(Ordering::Relaxed), 1);
+        dispatcher.replay().await;
+        assert_eq!(dispatcher.shared.queue.read().unwrap().len(), 1);
+
+        receiver.reset().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        dispatcher.replay().await;
+        assert_eq!(receiver.received_requests().await.unwrap().len(), 1);
+        assert_eq!(dispatcher.shared.counters.replayed.load(Ordering::Relaxed), 1);
+        assert!(dispatcher.shared.queue.read().unwrap().is_empty());
+        std::fs::remove_file(queue).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_are_the_sha256_prefix() {
+        assert_eq!(fingerprint("sk-test"), "f3abf2a6cc4f0098");
+    }
+
+    #[test]
+    fn rejected_requests_report_their_status() {
+        let now = Utc::now();
+        let event = UsageEvent::rejected(Some("req-1".to_string()), "sk-test", Some("deepthink".to_string()), true, 429, now, now);
+        assert_eq!(event.status, 429);
+        assert_eq!(event.token_fingerprint, Some(fingerprint("sk-test")));
+        assert_eq!(event.cost_usd, 0.0);
+        let anonymous = UsageEvent::rejected(None, "", None, false, 400, now, now);
+        assert_eq!(anonymous.token_fingerprint, None);
+    }
+
+    #[test]
+    fn failed_streams_keep_the_usage_recorded_so_far() {
+        let now = Utc::now();
+        let mut usage = UsageStats::default();
+        usage.add(10, 5);
+        let started = UsageEvent::rejected(None, "", None, true, 200, now, now);
+        let event = started.completed(&usage, &UsageStats::default(), 0.5, now).failed(502, now);
+        assert_eq!(event.status, 502);
+        assert_eq!(event.reasoner_usage.total_tokens, 15);
+        assert_eq!(event.cost_usd, 0.5);
+    }
+}